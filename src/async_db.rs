@@ -0,0 +1,288 @@
+use std::ops::{Bound, Deref, RangeBounds};
+
+use crate::{tree::CompareAndSwapResult, *};
+
+/// Runs a potentially-blocking closure on sled's internal
+/// threadpool, so that async callers never block their
+/// executor's worker threads on IO.
+async fn spawn_blocking<F, R>(work: F) -> Result<R>
+where
+    F: FnOnce() -> Result<R> + Send + 'static,
+    R: Send + 'static,
+{
+    if let Some(result) = threadpool::spawn(work).await {
+        result
+    } else {
+        Err(Error::ReportableBug(
+            "threadpool failed to complete \
+            action before shutdown"
+                .to_string(),
+        ))
+    }
+}
+
+fn owned_bound<K: AsRef<[u8]>>(bound: Bound<&K>) -> Bound<IVec> {
+    match bound {
+        Bound::Included(b) => Bound::Included(IVec::from(b.as_ref())),
+        Bound::Excluded(b) => Bound::Excluded(IVec::from(b.as_ref())),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// An async handle to a `Db`. All operations that may block
+/// on IO are performed on sled's internal threadpool rather
+/// than on the calling task's executor.
+///
+/// Implements `Deref<Target = AsyncTree>` to refer to the
+/// default keyspace, in the same way that `Db` acts like
+/// the default `Tree`.
+///
+/// # Examples
+///
+/// ```
+/// # async fn example() -> sled::Result<()> {
+/// let config = sled::Config::new().temporary(true);
+/// let db: sled::AsyncDb = config.open_async().await?;
+///
+/// let tree = db.open_tree("tree").await?;
+/// tree.insert("k", "v").await?;
+/// assert_eq!(tree.get("k").await?, Some(sled::IVec::from("v")));
+///
+/// db.flush().await?;
+/// # Ok(()) }
+/// ```
+#[derive(Clone)]
+pub struct AsyncDb {
+    db: Db,
+    default: AsyncTree,
+}
+
+impl Deref for AsyncDb {
+    type Target = AsyncTree;
+
+    fn deref(&self) -> &AsyncTree {
+        &self.default
+    }
+}
+
+impl From<Db> for AsyncDb {
+    fn from(db: Db) -> AsyncDb {
+        let default = AsyncTree::from(db.default.clone());
+        AsyncDb { db, default }
+    }
+}
+
+impl Debug for AsyncDb {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> std::result::Result<(), fmt::Error> {
+        write!(f, "AsyncDb({:?})", self.db)
+    }
+}
+
+impl AsyncDb {
+    /// Opens an `AsyncDb` based on the provided config
+    /// without blocking the calling task.
+    pub async fn open(config: Config) -> Result<AsyncDb> {
+        spawn_blocking(move || config.open()).await.map(AsyncDb::from)
+    }
+
+    /// Open or create a new disk-backed Tree with its own keyspace,
+    /// accessible from the `AsyncDb` via the provided identifier.
+    pub async fn open_tree<V: AsRef<[u8]>>(
+        &self,
+        name: V,
+    ) -> Result<AsyncTree> {
+        let db = self.db.clone();
+        let name = IVec::from(name.as_ref());
+        spawn_blocking(move || db.open_tree(name))
+            .await
+            .map(AsyncTree::from)
+    }
+
+    /// Remove a disk-backed collection.
+    pub async fn drop_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<bool> {
+        let db = self.db.clone();
+        let name = IVec::from(name.as_ref());
+        spawn_blocking(move || db.drop_tree(name)).await
+    }
+
+    /// Returns the trees names saved in this Db.
+    pub fn tree_names(&self) -> Vec<IVec> {
+        self.db.tree_names()
+    }
+
+    /// Generate a monotonic ID, see `Db::generate_id`.
+    pub async fn generate_id(&self) -> Result<u64> {
+        let db = self.db.clone();
+        spawn_blocking(move || db.generate_id()).await
+    }
+
+    /// Waits for the log to be durable up to `lsn`, see
+    /// `Db::wait_for_durability`.
+    pub async fn wait_for_durability(&self, lsn: u64) -> Result<()> {
+        self.db.wait_for_durability_async(lsn).await
+    }
+
+    /// Returns the underlying blocking `Db`.
+    pub fn blocking(&self) -> &Db {
+        &self.db
+    }
+}
+
+/// An async handle to a `Tree`. All operations that may block
+/// on IO are performed on sled's internal threadpool rather
+/// than on the calling task's executor.
+///
+/// Iterators and subscribers may be accessed through the
+/// underlying `Tree` via `AsyncTree::blocking`. A `Subscriber`
+/// already implements `Future`.
+#[derive(Clone)]
+pub struct AsyncTree {
+    tree: Tree,
+}
+
+impl From<Tree> for AsyncTree {
+    fn from(tree: Tree) -> AsyncTree {
+        AsyncTree { tree }
+    }
+}
+
+impl Debug for AsyncTree {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> std::result::Result<(), fmt::Error> {
+        write!(f, "AsyncTree({:?})", self.tree)
+    }
+}
+
+impl AsyncTree {
+    /// Insert a key to a new value, returning the last value if it
+    /// was set.
+    pub async fn insert<K, V>(
+        &self,
+        key: K,
+        value: V,
+    ) -> Result<Option<IVec>>
+    where
+        K: AsRef<[u8]>,
+        V: Into<IVec>,
+    {
+        let tree = self.tree.clone();
+        let key = IVec::from(key.as_ref());
+        let value = value.into();
+        spawn_blocking(move || tree.insert(key, value)).await
+    }
+
+    /// Retrieve a value from the `Tree` if it exists.
+    pub async fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>> {
+        let tree = self.tree.clone();
+        let key = IVec::from(key.as_ref());
+        spawn_blocking(move || tree.get(key)).await
+    }
+
+    /// Retrieve the values for several keys at once,
+    /// see `Tree::get_many`.
+    pub async fn get_many<K, I>(&self, keys: I) -> Result<Vec<Option<IVec>>>
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = K>,
+    {
+        let tree = self.tree.clone();
+        let keys: Vec<IVec> =
+            keys.into_iter().map(|k| IVec::from(k.as_ref())).collect();
+        spawn_blocking(move || tree.get_many(keys)).await
+    }
+
+    /// Returns `true` if the `Tree` contains a value for
+    /// the specified key.
+    pub async fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+        let tree = self.tree.clone();
+        let key = IVec::from(key.as_ref());
+        spawn_blocking(move || tree.contains_key(key)).await
+    }
+
+    /// Delete a value, returning the old value if it existed.
+    pub async fn remove<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+    ) -> Result<Option<IVec>> {
+        let tree = self.tree.clone();
+        let key = IVec::from(key.as_ref());
+        spawn_blocking(move || tree.remove(key)).await
+    }
+
+    /// Removes all keys that fall within the specified
+    /// range, see `Tree::remove_range`.
+    pub async fn remove_range<K, R>(&self, range: R) -> Result<usize>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let tree = self.tree.clone();
+        let start = owned_bound(range.start_bound());
+        let end = owned_bound(range.end_bound());
+        spawn_blocking(move || tree.remove_range((start, end))).await
+    }
+
+    /// Compare and swap, see `Tree::compare_and_swap`.
+    pub async fn compare_and_swap<K, OV, NV>(
+        &self,
+        key: K,
+        old: Option<OV>,
+        new: Option<NV>,
+    ) -> CompareAndSwapResult
+    where
+        K: AsRef<[u8]>,
+        OV: AsRef<[u8]>,
+        NV: Into<IVec>,
+    {
+        let tree = self.tree.clone();
+        let key = IVec::from(key.as_ref());
+        let old = old.map(|o| IVec::from(o.as_ref()));
+        let new = new.map(Into::into);
+        spawn_blocking(move || tree.compare_and_swap(key, old, new)).await
+    }
+
+    /// Create a new batched update that is applied
+    /// atomically, see `Tree::apply_batch`.
+    pub async fn apply_batch(&self, batch: Batch) -> Result<()> {
+        let tree = self.tree.clone();
+        spawn_blocking(move || tree.apply_batch(batch)).await
+    }
+
+    /// Perform a multi-key serializable transaction with an async
+    /// body, see `Tree::transaction_async`.
+    pub async fn transaction<F, Fut, A, E>(
+        &self,
+        f: F,
+    ) -> transaction::TransactionResult<A, E>
+    where
+        F: FnMut(transaction::AsyncTransactionalTree) -> Fut,
+        Fut: std::future::Future<
+            Output = transaction::ConflictableTransactionResult<A, E>,
+        >,
+    {
+        self.tree.transaction_async(f).await
+    }
+
+    /// Atomically removes the minimum item, waiting for one to
+    /// be inserted if the `Tree` is empty, see
+    /// `Tree::pop_min_wait_async`.
+    pub async fn pop_min_wait(&self) -> Result<Option<(IVec, IVec)>> {
+        self.tree.pop_min_wait_async().await
+    }
+
+    /// Asynchronously flushes all dirty IO buffers and calls
+    /// fsync, see `Tree::flush_async`.
+    pub async fn flush(&self) -> Result<usize> {
+        self.tree.flush_async().await
+    }
+
+    /// Returns the underlying blocking `Tree`.
+    pub fn blocking(&self) -> &Tree {
+        &self.tree
+    }
+}
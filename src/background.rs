@@ -0,0 +1,96 @@
+//! The threads that a `Db` starts for itself, see
+//! `Config::background_threads`.
+//!
+//! The flush thread, the threads that only clean segments, the
+//! scrubber and the expiration sweeper are all spawned here, so
+//! that they are named after `Config::background_thread_name`
+//! and take on the niceness and the cores of the config before
+//! doing any work. The threads that write the log and take
+//! snapshots are shared by every `Db` of the process, so the
+//! config of any one of them doesn't apply to them.
+#![allow(unsafe_code)]
+
+use std::{io, thread};
+
+#[cfg(target_os = "linux")]
+use std::mem::{size_of, zeroed};
+
+use crate::*;
+
+/// Spawns a thread named after its `role` that runs `work`.
+pub(crate) fn spawn<F, T>(
+    config: &RunningConfig,
+    role: &str,
+    work: F,
+) -> io::Result<thread::JoinHandle<T>>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let name = format!("{}-{}", config.background_thread_name, role);
+    let niceness = config.background_thread_nice;
+    let pinned = config.background_thread_cores.clone();
+
+    thread::Builder::new().name(name).spawn(move || {
+        if let Some(nice) = niceness {
+            if let Err(e) = set_nice(nice) {
+                warn!("failed to set background thread niceness: {}", e);
+            }
+        }
+        if let Some(ref cores) = pinned {
+            if let Err(e) = pin_to_cores(cores) {
+                warn!("failed to pin background thread to cores: {}", e);
+            }
+        }
+        work()
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn set_nice(nice: i32) -> io::Result<()> {
+    // linux keeps a niceness for every thread, which setpriority
+    // changes when it is given the id of the thread
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+    let ret = unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, nice) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_to_cores(cores: &[usize]) -> io::Result<()> {
+    #[allow(clippy::cast_sign_loss)]
+    let max_cores = libc::CPU_SETSIZE as usize;
+    let mut set: libc::cpu_set_t = unsafe { zeroed() };
+    for &core in cores {
+        if core >= max_cores {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("only cores below {} can be pinned", max_cores),
+            ));
+        }
+        unsafe { libc::CPU_SET(core, &mut set) };
+    }
+
+    let size = size_of::<libc::cpu_set_t>();
+    let ret = unsafe { libc::sched_setaffinity(0, size, &set) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// `Config::validate` refuses both options on other platforms
+#[cfg(not(target_os = "linux"))]
+fn set_nice(_: i32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_cores(_: &[usize]) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,301 @@
+//! The stream format written by `Db::backup_to` and
+//! `Db::backup_since`, and read by `Db::restore_from`.
+//!
+//! A backup begins with `MAGIC`, a version byte, and a `Header`
+//! made up of an incremental flag byte, the `since` and `lsn`
+//! fields, and the timestamp.
+//! It is followed by a sequence of records, each starting with
+//! a tag byte:
+//!
+//! * `TREE` ++ len ++ name, which begins the contents of a tree
+//! * `RANGE` ++ len ++ lo ++ len ++ hi, which only appears in
+//!   incremental backups, and replaces the contents of the keys
+//!   from `lo` up to, but not including, `hi` in the current tree
+//!   with the `KV` records that follow it. An empty `hi` means
+//!   the range is unbounded.
+//! * `KV` ++ len ++ key ++ len ++ value, which belongs to the
+//!   most recently started tree or range
+//! * `END` ++ crc32, which ends the stream. The checksum covers
+//!   every byte that precedes it.
+//!
+//! All integers are little-endian.
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use crate::*;
+
+const MAGIC: &[u8; 8] = b"sledback";
+const VERSION: u8 = 1;
+
+const END: u8 = 0;
+const TREE: u8 = 1;
+const KV: u8 = 2;
+const RANGE: u8 = 3;
+
+/// Describes the changes contained in a backup stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Header {
+    /// The `Lsn` of the previous backup that an incremental
+    /// backup builds upon, or `None` for a full backup.
+    pub since: Option<Lsn>,
+    /// The `Lsn` that the backup is consistent with.
+    pub lsn: Lsn,
+    /// The number of milliseconds since the unix epoch at
+    /// the moment the backup was consistent with.
+    pub timestamp: u64,
+}
+
+/// A record read from a backup stream.
+pub(crate) enum Record {
+    Tree(IVec),
+    Range(IVec, Option<IVec>),
+    Kv(IVec, IVec),
+}
+
+pub(crate) struct BackupWriter<W: Write> {
+    writer: BufWriter<W>,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> BackupWriter<W> {
+    pub(crate) fn new(writer: W, header: Header) -> Result<BackupWriter<W>> {
+        let mut ret = BackupWriter {
+            writer: BufWriter::new(writer),
+            hasher: crc32fast::Hasher::new(),
+        };
+        ret.write(MAGIC)?;
+        ret.write(&[VERSION, u8::from(header.since.is_some())])?;
+        ret.write(&header.since.unwrap_or(0).to_le_bytes())?;
+        ret.write(&header.lsn.to_le_bytes())?;
+        ret.write(&header.timestamp.to_le_bytes())?;
+        Ok(ret)
+    }
+
+    pub(crate) fn tree(&mut self, name: &[u8]) -> Result<()> {
+        self.write(&[TREE])?;
+        self.write_bytes(name)
+    }
+
+    pub(crate) fn range(
+        &mut self,
+        lo: &[u8],
+        hi: Option<&[u8]>,
+    ) -> Result<()> {
+        self.write(&[RANGE])?;
+        self.write_bytes(lo)?;
+        self.write_bytes(hi.unwrap_or(&[]))
+    }
+
+    pub(crate) fn kv(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.write(&[KV])?;
+        self.write_bytes(key)?;
+        self.write_bytes(value)
+    }
+
+    /// Writes every item of a snapshot, which skips the ones that
+    /// have expired, under the stored form of its key.
+    pub(crate) fn kvs(
+        &mut self,
+        tree: &Tree,
+        iter: snapshot::SnapshotIter,
+    ) -> Result<()> {
+        for kv_res in iter {
+            let (k, v) = kv_res?;
+            self.kv(&tree.order.encode(&k), &v)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> Result<()> {
+        self.write(&[END])?;
+        let crc = self.hasher.clone().finalize();
+        self.writer.write_all(&crc.to_le_bytes())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.write(&(bytes.len() as u64).to_le_bytes())?;
+        self.write(bytes)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.hasher.update(bytes);
+        self.writer.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+pub(crate) struct BackupReader<R: Read> {
+    pub(crate) header: Header,
+    reader: BufReader<R>,
+    hasher: crc32fast::Hasher,
+    done: bool,
+}
+
+impl<R: Read> BackupReader<R> {
+    pub(crate) fn new(reader: R) -> Result<BackupReader<R>> {
+        let mut ret = BackupReader {
+            header: Header { since: None, lsn: 0, timestamp: 0 },
+            reader: BufReader::new(reader),
+            hasher: crc32fast::Hasher::new(),
+            done: false,
+        };
+
+        let mut prelude = [0; 10];
+        ret.read(&mut prelude)?;
+        if &prelude[..8] != MAGIC {
+            return Err(Error::Unsupported(
+                "stream is not a sled backup".into(),
+            ));
+        }
+        if prelude[8] != VERSION {
+            return Err(Error::Unsupported(format!(
+                "unsupported backup version {}",
+                prelude[8]
+            )));
+        }
+
+        let since = ret.read_lsn()?;
+        ret.header.since = if prelude[9] == 0 { None } else { Some(since) };
+        ret.header.lsn = ret.read_lsn()?;
+
+        let mut timestamp = [0; 8];
+        ret.read(&mut timestamp)?;
+        ret.header.timestamp = u64::from_le_bytes(timestamp);
+
+        Ok(ret)
+    }
+
+    /// Returns the next record, or `None` once the end of
+    /// the stream has been reached and its checksum has
+    /// been verified.
+    pub(crate) fn next(&mut self) -> Result<Option<Record>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let mut tag = [0];
+        self.read(&mut tag)?;
+
+        match tag[0] {
+            END => {
+                let expected = self.hasher.clone().finalize();
+                let mut crc = [0; 4];
+                self.reader.read_exact(&mut crc)?;
+                if u32::from_le_bytes(crc) != expected {
+                    return Err(Error::corruption(None));
+                }
+                self.done = true;
+                Ok(None)
+            }
+            TREE => Ok(Some(Record::Tree(self.read_bytes()?))),
+            RANGE => {
+                let lo = self.read_bytes()?;
+                let hi = self.read_bytes()?;
+                let hi = if hi.is_empty() { None } else { Some(hi) };
+                Ok(Some(Record::Range(lo, hi)))
+            }
+            KV => {
+                let key = self.read_bytes()?;
+                let value = self.read_bytes()?;
+                Ok(Some(Record::Kv(key, value)))
+            }
+            _ => Err(Error::corruption(None)),
+        }
+    }
+
+    /// Reads the rest of the stream into a temporary file in
+    /// `dir`, verifying its checksum, and returns a reader of
+    /// the copy, so that nothing is restored from a stream
+    /// that turns out to be corrupt.
+    pub(crate) fn verified(
+        mut self,
+        dir: &Path,
+    ) -> Result<BackupReader<Spooled>> {
+        let path = dir.join(format!(
+            "backup-spool-{}-{}",
+            std::process::id(),
+            SPOOL_ID.fetch_add(1, SeqCst)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let mut spooled = Spooled { path, file };
+
+        let mut writer = BackupWriter::new(&spooled.file, self.header)?;
+        while let Some(record) = self.next()? {
+            match record {
+                Record::Tree(name) => writer.tree(&name)?,
+                Record::Range(lo, hi) => writer.range(&lo, hi.as_deref())?,
+                Record::Kv(key, value) => writer.kv(&key, &value)?,
+            }
+        }
+        writer.finish()?;
+
+        let _ = spooled.file.seek(SeekFrom::Start(0))?;
+        BackupReader::new(spooled)
+    }
+
+    fn read_bytes(&mut self) -> Result<IVec> {
+        let mut len = [0; 8];
+        self.read(&mut len)?;
+        let len = u64::from_le_bytes(len);
+
+        // read through `take` rather than allocating `len`
+        // bytes up front, so a corrupt length can't cause
+        // a huge allocation.
+        let mut buf = vec![];
+        let read = (&mut self.reader).take(len).read_to_end(&mut buf)?;
+        if read as u64 != len {
+            return Err(Error::corruption(None));
+        }
+        self.hasher.update(&buf);
+
+        Ok(buf.into())
+    }
+
+    fn read_lsn(&mut self) -> Result<Lsn> {
+        let mut buf = [0; 8];
+        self.read(&mut buf)?;
+        let lsn = Lsn::from_le_bytes(buf);
+        if lsn < 0 {
+            return Err(Error::corruption(None));
+        }
+        Ok(lsn)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.reader.read_exact(buf)?;
+        self.hasher.update(buf);
+        Ok(())
+    }
+}
+
+static SPOOL_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A verified copy of a backup stream, which is removed
+/// when it is dropped.
+pub(crate) struct Spooled {
+    path: PathBuf,
+    file: File,
+}
+
+impl Read for Spooled {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Drop for Spooled {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("failed to remove spooled backup {:?}: {:?}", self.path, e);
+        }
+    }
+}
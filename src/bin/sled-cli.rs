@@ -0,0 +1,188 @@
+//! Inspects and repairs the database in a directory, without
+//! having to write a program for it. Every command except
+//! `recover` opens the database with `Config::read_only`, so it
+//! may be run while another process has the database open.
+use std::process::exit;
+
+use sled::{dump::Encoding, Config, Db, Result};
+
+const USAGE: &str = "
+Usage: sled-cli <path> [--keys=<e>] [--values=<e>] <command> [<args>]
+
+Commands:
+    trees                 List the trees and how many keys they hold.
+    get <tree> <key>      Print the value of a key.
+    scan <tree> [<pre>]   Print the keys and values of a tree, or only
+                          those with keys that begin with <pre>.
+    space                 Show the size of the files, and an estimate
+                          of the size of every tree.
+    verify                Read every key and value, failing on the
+                          first that can't be read, print the checksum
+                          of the database, and check the structure of
+                          every tree with Db::verify_integrity.
+    recover               Open the database for writing, which recovers
+                          it from its log and truncates a torn or
+                          corrupt end of it, and make that durable.
+
+Options:
+    --keys=<e>    The encoding of tree names and keys, in arguments
+                  and output: utf8, hex or base64 [default: utf8].
+    --values=<e>  The encoding of values in output [default: hex].
+";
+
+struct Args {
+    path: String,
+    keys: Encoding,
+    values: Encoding,
+    command: String,
+    rest: Vec<String>,
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(2)
+}
+
+fn parse_encoding(name: &str) -> Encoding {
+    match name {
+        "utf8" => Encoding::Utf8,
+        "hex" => Encoding::Hex,
+        "base64" => Encoding::Base64,
+        _ => usage(),
+    }
+}
+
+impl Args {
+    fn parse() -> Args {
+        let mut keys = Encoding::Utf8;
+        let mut values = Encoding::Hex;
+        let mut positional = vec![];
+        for raw_arg in std::env::args().skip(1) {
+            if raw_arg.starts_with("--") {
+                let mut splits = raw_arg[2..].splitn(2, '=');
+                let name = splits.next().unwrap();
+                let value = splits.next().unwrap_or_else(|| usage());
+                match name {
+                    "keys" => keys = parse_encoding(value),
+                    "values" => values = parse_encoding(value),
+                    _ => usage(),
+                }
+            } else {
+                positional.push(raw_arg);
+            }
+        }
+        if positional.len() < 2 {
+            usage();
+        }
+        let rest = positional.split_off(2);
+        let command = positional.pop().unwrap();
+        let path = positional.pop().unwrap();
+        Args { path, keys, values, command, rest }
+    }
+
+    fn open(&self, read_only: bool) -> Result<Db> {
+        if !std::path::Path::new(&self.path).is_dir() {
+            eprintln!("no database directory at {}", self.path);
+            exit(1);
+        }
+        Config::new().path(&self.path).read_only(read_only).open()
+    }
+
+    fn pair(&self, key: &[u8], value: &[u8]) -> Result<String> {
+        Ok(format!(
+            "{}\t{}",
+            self.keys.encode(key)?,
+            self.values.encode(value)?
+        ))
+    }
+}
+
+fn run(args: &Args) -> Result<()> {
+    match (&*args.command, args.rest.len()) {
+        ("trees", 0) => {
+            let db = args.open(true)?;
+            for name in db.tree_names() {
+                let tree = db.open_tree(&name)?;
+                println!("{}\t{}", args.keys.encode(&name)?, tree.len());
+            }
+        }
+        ("get", 2) => {
+            let db = args.open(true)?;
+            let tree = db.open_tree(args.keys.decode(&args.rest[0])?)?;
+            let key = args.keys.decode(&args.rest[1])?;
+            match tree.get(&key)? {
+                Some(value) => println!("{}", args.values.encode(&value)?),
+                None => {
+                    eprintln!("key not found");
+                    exit(1);
+                }
+            }
+        }
+        ("scan", 1) | ("scan", 2) => {
+            let db = args.open(true)?;
+            let tree = db.open_tree(args.keys.decode(&args.rest[0])?)?;
+            let prefix = match args.rest.get(1) {
+                Some(prefix) => args.keys.decode(prefix)?,
+                None => vec![],
+            };
+            for kv_res in tree.scan_prefix(prefix) {
+                let (k, v) = kv_res?;
+                println!("{}", args.pair(&k, &v)?);
+            }
+        }
+        ("space", 0) => {
+            let db = args.open(true)?;
+            println!("size on disk\t{}", db.size_on_disk()?);
+            for name in db.tree_names() {
+                let tree = db.open_tree(&name)?;
+                let (bytes, keys) = tree.size_of_range::<&[u8], _>(..)?;
+                println!(
+                    "{}\t~{} bytes\t~{} keys",
+                    args.keys.encode(&name)?,
+                    bytes,
+                    keys
+                );
+            }
+        }
+        ("verify", 0) => {
+            let db = args.open(true)?;
+            for name in db.tree_names() {
+                let tree = db.open_tree(&name)?;
+                let mut read = 0_u64;
+                for kv_res in &tree {
+                    let _ = kv_res?;
+                    read += 1;
+                }
+                println!("{}\t{} keys ok", args.keys.encode(&name)?, read);
+            }
+            println!("checksum\t{:08x}", db.checksum()?);
+            let report = db.verify_integrity()?;
+            for problem in &report.problems {
+                println!("problem\t{}", problem);
+            }
+            if !report.is_healthy() {
+                exit(1);
+            }
+        }
+        ("recover", 0) => {
+            let db = args.open(false)?;
+            let flushed = db.flush()?;
+            println!(
+                "recovered {} trees, flushed {} bytes, {} bytes on disk",
+                db.tree_names().len(),
+                flushed,
+                db.size_on_disk()?
+            );
+        }
+        _ => usage(),
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(&args) {
+        eprintln!("error: {}", e);
+        exit(1);
+    }
+}
@@ -0,0 +1,235 @@
+//! Bloom filters that let point reads skip the leaves which
+//! can't contain their key, see `Config::bloom_bits_per_key`.
+//!
+//! A filter is built in memory for a leaf the first time that
+//! a key is read from it, and is kept while the leaf is paged
+//! out, so that reads of keys that don't exist don't have to
+//! page in the leaves that would hold them. Filters are never
+//! written to disk, so after a restart they are built again as
+//! leaves are read.
+//!
+//! Every key that is written to a leaf is added to its filter
+//! before the write is linked, while holding a shared lock that
+//! filters are only built under exclusively, so a filter never
+//! misses a key of its leaf. Filters also record the bounds of
+//! their leaf, and are removed before it is split or merged
+//! into its left sibling, so that they are never used for keys
+//! that the leaf no longer holds.
+use std::{hash::Hasher, sync::atomic::Ordering::SeqCst};
+
+use parking_lot::{RwLockReadGuard, RwLockWriteGuard};
+
+use crate::*;
+
+/// The filters of the leaves of a `Tree`.
+pub(crate) struct Blooms {
+    bits_per_key: Option<usize>,
+    filters: RwLock<FastMap8<PageId, Filter>>,
+}
+
+/// Held while a write that a filter must contain is linked.
+pub(crate) type LinkGuard<'a> =
+    RwLockReadGuard<'a, FastMap8<PageId, Filter>>;
+
+impl Blooms {
+    pub(crate) fn new(bits_per_key: Option<usize>) -> Blooms {
+        Blooms { bits_per_key, filters: RwLock::new(FastMap8::default()) }
+    }
+}
+
+/// The filter of a leaf.
+pub(crate) struct Filter {
+    lo: IVec,
+    hi: Option<IVec>,
+    hashes: u64,
+    bits: Vec<AtomicU64>,
+}
+
+impl Filter {
+    fn new(
+        lo: IVec,
+        hi: Option<IVec>,
+        bits_per_key: usize,
+        keys: usize,
+    ) -> Filter {
+        let bits = keys.saturating_add(1).saturating_mul(bits_per_key);
+        let words = bits / 64 + 1;
+        // `ln(2) * bits_per_key` hashes minimize false positives
+        let hashes = match bits_per_key * 69 / 100 {
+            0 => 1,
+            hashes => hashes.min(30),
+        };
+        Filter {
+            lo,
+            hi,
+            hashes: u64::try_from(hashes).unwrap(),
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    fn bit_indices(&self, key: &[u8]) -> impl Iterator<Item = u64> {
+        let mut hasher = fnv::Hasher::default();
+        hasher.write(key);
+        let mut hash = hasher.finish();
+        // fnv mixes the high bits of its hash poorly
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+
+        let len = u64::try_from(self.bits.len()).unwrap() * 64;
+        let (first, second) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        (0..self.hashes).map(move |i| {
+            first.wrapping_add(i.wrapping_mul(second)) % len
+        })
+    }
+
+    fn add(&self, key: &[u8]) {
+        for bit in self.bit_indices(key) {
+            let word = &self.bits[usize::try_from(bit / 64).unwrap()];
+            let _ = word.fetch_or(1 << (bit % 64), SeqCst);
+        }
+    }
+
+    fn may_contain(&self, key: &[u8]) -> bool {
+        self.bit_indices(key).all(|bit| {
+            let word = &self.bits[usize::try_from(bit / 64).unwrap()];
+            word.load(SeqCst) & (1 << (bit % 64)) != 0
+        })
+    }
+
+    fn covers(&self, key: &[u8]) -> bool {
+        match self.hi {
+            Some(ref hi) => key >= &*self.lo && key < &**hi,
+            None => key >= &*self.lo,
+        }
+    }
+}
+
+/// Returns `true` if the leaf that would hold a key has a filter
+/// that shows that it doesn't, reading only the index nodes
+/// above it.
+pub(crate) fn excludes(tree: &Tree, key: &[u8], guard: &Guard) -> Result<bool> {
+    if tree.blooms.bits_per_key.is_none() {
+        return Ok(false);
+    }
+
+    let mut cursor = tree.root.load(Acquire);
+    loop {
+        if cursor == u64::max_value() {
+            return Ok(false);
+        }
+        let pagecache = &tree.context.pagecache;
+        let node_view = if let Some(node_view) = pagecache.get(cursor, guard)? {
+            node_view
+        } else {
+            return Ok(false);
+        };
+
+        // anything unusual is left to the regular traversal
+        let undershot = match node_view.hi() {
+            Some(hi) => key >= hi,
+            None => false,
+        };
+        if !node_view.is_index
+            || node_view.merging_child.is_some()
+            || key < node_view.lo()
+            || undershot
+        {
+            return Ok(false);
+        }
+
+        let child = node_view.index_next_node(key).1;
+        if let Some(filter) = tree.blooms.filters.read().get(&child) {
+            return Ok(filter.covers(key) && !filter.may_contain(key));
+        }
+        cursor = child;
+    }
+}
+
+/// Builds the filter of a leaf if it doesn't have one yet.
+pub(crate) fn build(tree: &Tree, pid: PageId, guard: &Guard) -> Result<()> {
+    let bits_per_key = if let Some(bits_per_key) = tree.blooms.bits_per_key {
+        bits_per_key
+    } else {
+        return Ok(());
+    };
+
+    if tree.blooms.filters.read().contains_key(&pid) {
+        return Ok(());
+    }
+
+    let mut filters = tree.blooms.filters.write();
+
+    // the leaf is read again while no writes can be linked,
+    // because the ones that were linked since it was read may
+    // not have been added to any filter.
+    let node_view =
+        if let Some(node_view) = tree.context.pagecache.get(pid, guard)? {
+            node_view
+        } else {
+            return Ok(());
+        };
+    if node_view.is_index || node_view.merging || filters.contains_key(&pid) {
+        return Ok(());
+    }
+
+    let keys: Vec<IVec> = node_view.decoded_keys().collect();
+    let filter = Filter::new(
+        node_view.lo().into(),
+        node_view.hi().map(IVec::from),
+        bits_per_key,
+        keys.len(),
+    );
+    for key in &keys {
+        filter.add(key);
+    }
+    let _ = filters.insert(pid, filter);
+
+    Ok(())
+}
+
+/// Adds a key to the filter of the leaf that it is written to,
+/// returning a guard that must be held until the write is
+/// linked.
+pub(crate) fn write<'a>(
+    tree: &'a Tree,
+    pid: PageId,
+    key: &[u8],
+) -> Option<LinkGuard<'a>> {
+    tree.blooms.bits_per_key?;
+    let filters = tree.blooms.filters.read();
+    if let Some(filter) = filters.get(&pid) {
+        filter.add(key);
+    }
+    Some(filters)
+}
+
+/// Removes the filter of a leaf that is about to be split or
+/// merged, returning a guard that must be held until that is
+/// linked.
+pub(crate) fn remove(tree: &Tree, pid: PageId) -> Option<LinkGuard<'_>> {
+    tree.blooms.bits_per_key?;
+    let mut filters = tree.blooms.filters.write();
+    let _ = filters.remove(&pid);
+    Some(RwLockWriteGuard::downgrade(filters))
+}
+
+/// Removes the filters of every leaf, after the nodes of a tree
+/// were replaced by `Tree::bulk_load`.
+pub(crate) fn clear(tree: &Tree) {
+    tree.blooms.filters.write().clear();
+}
+
+#[test]
+fn filters_have_no_false_negatives() {
+    let filter = Filter::new(IVec::default(), None, 10, 1000);
+    for i in 0_u32..1000 {
+        filter.add(&i.to_be_bytes());
+    }
+    assert!((0_u32..1000).all(|i| filter.may_contain(&i.to_be_bytes())));
+
+    let false_positives = (1000_u32..11_000)
+        .filter(|i| filter.may_contain(&i.to_be_bytes()))
+        .count();
+    assert!(false_positives < 300, "{} false positives", false_positives);
+}
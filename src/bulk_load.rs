@@ -0,0 +1,203 @@
+//! Building the nodes of an empty `Tree` directly from sorted
+//! keys, see `Tree::bulk_load`.
+//!
+//! Nodes are filled from left to right on every level at once.
+//! When a node is full it is written, and its low key and page
+//! are added to the node that is being filled on the level
+//! above, so only one node per level is kept in memory. Every
+//! node points to its right sibling, whose page is allocated
+//! as an empty placeholder before the node is written, and
+//! is replaced once the sibling is full.
+//!
+//! The new nodes are unreachable until the root of the tree is
+//! swapped for the new one, while writes are blocked, so that a
+//! failed load leaves the tree as it was.
+use std::num::NonZeroU64;
+
+use crate::*;
+
+/// The number of bytes of keys and values that nodes are filled
+/// with, which is about half of the size at which they are split,
+/// leaving room for later writes.
+const NODE_BYTES: usize = 512;
+
+/// The node that is being filled on one level of the tree.
+struct Level {
+    is_index: bool,
+    lo: IVec,
+    items: Vec<(IVec, IVec)>,
+    bytes: usize,
+    // the placeholder page of the node
+    pid: PageId,
+    // the page of the leftmost node of the level
+    first: PageId,
+    written: usize,
+}
+
+pub(crate) struct Loader<'a> {
+    tree: &'a Tree,
+    codec: Option<Codec>,
+    dictionary: u32,
+    cache_priority: CachePriority,
+    expected_value_size: Option<usize>,
+    // the number of bytes that leaves are filled with, which is
+    // raised for trees with large expected values
+    leaf_bytes: usize,
+    levels: Vec<Level>,
+}
+
+impl<'a> Loader<'a> {
+    pub(crate) fn new(tree: &'a Tree) -> Result<Loader<'a>> {
+        let (codec, dictionary) = tree.codec()?;
+        let (cache_priority, expected_value_size) = tree.tree_hints()?;
+        let mut ret = Loader {
+            tree,
+            codec,
+            dictionary,
+            cache_priority,
+            expected_value_size,
+            leaf_bytes: NODE_BYTES,
+            levels: vec![],
+        };
+        let leaf = ret.configure(Node::new_empty_leaf());
+        ret.leaf_bytes = NODE_BYTES.max(leaf.split_size() / 2);
+        ret.add_level(false, IVec::default())?;
+        Ok(ret)
+    }
+
+    fn add_level(&mut self, is_index: bool, lo: IVec) -> Result<()> {
+        let pid = self.placeholder()?;
+        self.levels.push(Level {
+            is_index,
+            lo,
+            items: vec![],
+            bytes: 0,
+            pid,
+            first: pid,
+            written: 0,
+        });
+        Ok(())
+    }
+
+    fn placeholder(&self) -> Result<PageId> {
+        let guard = pin();
+        let node = self.configure(Node::new_empty_leaf());
+        let (pid, _) = self.tree.context.pagecache.allocate(node, &guard)?;
+        Ok(pid)
+    }
+
+    fn configure(&self, mut node: Node) -> Node {
+        node.set_codec(self.codec, self.dictionary);
+        node.set_key_order(self.tree.order);
+        node.set_tree_hints(self.cache_priority, self.expected_value_size);
+        node
+    }
+
+    /// Adds a stored key, which must be greater than the last
+    /// one, and its stored value to the leaves.
+    pub(crate) fn push(&mut self, key: IVec, value: IVec) -> Result<()> {
+        self.push_to(0, key, value)
+    }
+
+    fn push_to(&mut self, depth: usize, key: IVec, value: IVec) -> Result<()> {
+        if depth == self.levels.len() {
+            self.add_level(true, key.clone())?;
+        }
+
+        let bytes = key.len() + value.len();
+        let full = {
+            let level = &self.levels[depth];
+            let node_bytes =
+                if level.is_index { NODE_BYTES } else { self.leaf_bytes };
+            !level.items.is_empty() && level.bytes + bytes > node_bytes
+        };
+        if full {
+            let (lo, pid) = self.write(depth, Some(key.clone()))?;
+            self.push_to(depth + 1, lo, IVec::from(&pid.to_le_bytes()))?;
+        }
+
+        let level = &mut self.levels[depth];
+        level.bytes += bytes;
+        level.items.push((key, value));
+        Ok(())
+    }
+
+    // writes the node of a level, which either ends where the
+    // next one starts or at the end of the tree, returning its
+    // low key and page
+    fn write(
+        &mut self,
+        depth: usize,
+        hi: Option<IVec>,
+    ) -> Result<(IVec, PageId)> {
+        let next = if hi.is_some() { Some(self.placeholder()?) } else { None };
+
+        let (mut node, pid) = {
+            let level = &self.levels[depth];
+            let node = Node::new_sorted(
+                &level.lo,
+                hi.as_deref(),
+                level.is_index,
+                &level.items,
+            );
+            (self.configure(node), level.pid)
+        };
+        node.set_next(next.map(|next_pid| NonZeroU64::new(next_pid).unwrap()));
+
+        let guard = pin();
+        loop {
+            let view = self
+                .tree
+                .view_for_pid(pid, &guard)?
+                .expect("bulk loaded pages can't be freed by others");
+            // the placeholder may have been moved by segment cleaning
+            if self
+                .tree
+                .context
+                .pagecache
+                .replace(pid, view.node_view.0, &node, &guard)?
+                .is_ok()
+            {
+                break;
+            }
+        }
+
+        let level = &mut self.levels[depth];
+        let lo = std::mem::replace(&mut level.lo, hi.unwrap_or_default());
+        level.items.clear();
+        level.bytes = 0;
+        level.written += 1;
+        if let Some(next_pid) = next {
+            level.pid = next_pid;
+        }
+
+        Ok((lo, pid))
+    }
+
+    /// Writes the nodes that are still being filled, returning
+    /// the page of the new root.
+    pub(crate) fn finish(&mut self) -> Result<PageId> {
+        let mut depth = 0;
+        loop {
+            let is_root = {
+                let level = &self.levels[depth];
+                level.is_index
+                    && level.written == 0
+                    && depth + 1 == self.levels.len()
+            };
+
+            let (lo, pid) = self.write(depth, None)?;
+            if is_root {
+                return Ok(pid);
+            }
+            self.push_to(depth + 1, lo, IVec::from(&pid.to_le_bytes()))?;
+            depth += 1;
+        }
+    }
+
+    /// Frees the pages of the nodes that were written so far.
+    pub(crate) fn abort(self) -> Result<()> {
+        let chain = self.levels.iter().map(|level| level.first).collect();
+        self.tree.gc_pages(chain)
+    }
+}
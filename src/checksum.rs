@@ -0,0 +1,161 @@
+//! The checksums of the messages of the log and of the slots of
+//! the heap files, see `Config::checksum`.
+//!
+//! CRC32 is computed by `crc32fast`, which uses the carry-less
+//! multiplication instructions of the cpu where it can. CRC32C
+//! is computed with the `crc32` instruction of SSE 4.2 on
+//! `x86_64` cpus that have it, and with a table everywhere else.
+#![allow(unsafe_code)]
+
+use crate::Lazy;
+
+// CRC32C in reversed bit order
+const CASTAGNOLI: u32 = 0x82F6_3B78;
+
+static CRC32C_TABLE: Lazy<[u32; 256], fn() -> [u32; 256]> =
+    Lazy::new(crc32c_table);
+
+/// The function that checksums what is written to the log and
+/// to the heap files that large messages go to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    /// CRC32 with the IEEE polynomial, which every database used
+    /// before the checksum could be chosen.
+    Crc32,
+    /// CRC32 with the Castagnoli polynomial, which is computed in
+    /// hardware on `x86_64` cpus with SSE 4.2.
+    Crc32c,
+}
+
+impl Checksum {
+    pub(crate) fn hasher(self) -> Hasher {
+        match self {
+            Checksum::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+            Checksum::Crc32c => Hasher::Crc32c(!0),
+        }
+    }
+
+    // the name of the checksum in the config file
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Checksum::Crc32 => "crc32",
+            Checksum::Crc32c => "crc32c",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<Checksum> {
+        match name {
+            "crc32" => Some(Checksum::Crc32),
+            "crc32c" => Some(Checksum::Crc32c),
+            _ => None,
+        }
+    }
+}
+
+/// Computes a checksum over several buffers.
+pub(crate) enum Hasher {
+    Crc32(crc32fast::Hasher),
+    Crc32c(u32),
+}
+
+impl Hasher {
+    pub(crate) fn update(&mut self, buf: &[u8]) {
+        match self {
+            Hasher::Crc32(hasher) => hasher.update(buf),
+            Hasher::Crc32c(crc) => *crc = crc32c_update(*crc, buf),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> u32 {
+        match self {
+            Hasher::Crc32(hasher) => hasher.finalize(),
+            Hasher::Crc32c(crc) => !crc,
+        }
+    }
+}
+
+fn crc32c_update(crc: u32, buf: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("sse4.2") {
+            return unsafe { crc32c_sse42(crc, buf) };
+        }
+    }
+
+    crc32c_by_table(crc, buf)
+}
+
+fn crc32c_by_table(crc: u32, buf: &[u8]) -> u32 {
+    let table = &*CRC32C_TABLE;
+    buf.iter().fold(crc, |acc, byte| {
+        table[((acc ^ u32::from(*byte)) & 0xFF) as usize] ^ (acc >> 8)
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+unsafe fn crc32c_sse42(crc: u32, buf: &[u8]) -> u32 {
+    use std::{
+        arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8},
+        convert::TryFrom,
+    };
+
+    let mut chunks = buf.chunks_exact(8);
+    let mut crc64 = u64::from(crc);
+    for chunk in &mut chunks {
+        let word = u64::from_le_bytes(<[u8; 8]>::try_from(chunk).unwrap());
+        crc64 = _mm_crc32_u64(crc64, word);
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    let mut crc32 = crc64 as u32;
+    for byte in chunks.remainder() {
+        crc32 = _mm_crc32_u8(crc32, *byte);
+    }
+    crc32
+}
+
+fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    for (i, entry) in (0..256_u32).zip(table.iter_mut()) {
+        let mut crc = i;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CASTAGNOLI
+            } else {
+                crc >> 1
+            };
+        }
+        *entry = crc;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_values() {
+        // the check values of both crcs, from the catalogue of
+        // parametrised crc algorithms
+        let of = |checksum: Checksum, buf: &[u8]| {
+            let mut hasher = checksum.hasher();
+            hasher.update(buf);
+            hasher.finalize()
+        };
+        assert_eq!(of(Checksum::Crc32, b"123456789"), 0xCBF4_3926);
+        assert_eq!(of(Checksum::Crc32c, b"123456789"), 0xE306_9283);
+
+        assert_eq!(!crc32c_by_table(!0, b"123456789"), 0xE306_9283);
+
+        // split so that the bytes after the last whole word of
+        // each part are hashed on their own
+        let long: Vec<u8> = (0..1000_u32).map(|i| i as u8).collect();
+        let mut hasher = Checksum::Crc32c.hasher();
+        hasher.update(&long[..333]);
+        hasher.update(&long[333..]);
+        assert_eq!(hasher.finalize(), !crc32c_by_table(!0, &long));
+        assert_eq!(of(Checksum::Crc32c, &long), !crc32c_by_table(!0, &long));
+    }
+}
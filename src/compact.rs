@@ -0,0 +1,242 @@
+//! Compaction of the log on demand, see `Db::compact`.
+//!
+//! Every segment of the log that is no fuller than the threshold
+//! is handed to the segment cleaner at once, as the background
+//! garbage collector does with those that are at most half full,
+//! and the calling thread then moves their pages to the end of
+//! the log until none are left, which frees the segments. When
+//! the compaction is limited to a tree, only the leaves of the
+//! tree, or of the range of its keys, that have a fragment in
+//! such a segment are moved, and the segments are only freed
+//! once the garbage collector gets to the rest of their pages.
+use std::{
+    fmt,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::*;
+
+// the number of pages that are moved between progress reports
+const PROGRESS_EVERY: u64 = 64;
+
+type ProgressFn = Arc<dyn Fn(&CompactProgress) + Send + Sync>;
+
+/// Options for `Db::compact`.
+#[derive(Clone)]
+pub struct CompactOptions {
+    tree: Option<IVec>,
+    range: (Bound<IVec>, Bound<IVec>),
+    live_threshold: u8,
+    on_progress: Option<ProgressFn>,
+}
+
+impl Debug for CompactOptions {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> std::result::Result<(), fmt::Error> {
+        f.debug_struct("CompactOptions")
+            .field("tree", &self.tree)
+            .field("range", &self.range)
+            .field("live_threshold", &self.live_threshold)
+            .finish()
+    }
+}
+
+impl Default for CompactOptions {
+    fn default() -> CompactOptions {
+        CompactOptions {
+            tree: None,
+            range: (Bound::Unbounded, Bound::Unbounded),
+            live_threshold: 90,
+            on_progress: None,
+        }
+    }
+}
+
+impl CompactOptions {
+    /// Returns the default options, which compact every segment
+    /// that is at most 90% live.
+    pub fn new() -> CompactOptions {
+        CompactOptions::default()
+    }
+
+    /// Only moves the nodes of the `Tree` with this name.
+    pub fn tree<N: AsRef<[u8]>>(mut self, name: N) -> CompactOptions {
+        self.tree = Some(IVec::from(name.as_ref()));
+        self
+    }
+
+    /// Only moves the nodes that hold keys in this range, which
+    /// requires `CompactOptions::tree`.
+    pub fn range<K, R>(mut self, range: R) -> CompactOptions
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let to_ivec = |bound: Bound<&K>| match bound {
+            Bound::Included(key) => Bound::Included(IVec::from(key.as_ref())),
+            Bound::Excluded(key) => Bound::Excluded(IVec::from(key.as_ref())),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        self.range = (to_ivec(range.start_bound()), to_ivec(range.end_bound()));
+        self
+    }
+
+    /// Compacts the segments whose live bytes are at most this
+    /// percentage of their size. The background garbage
+    /// collector only compacts those that are at most 50% live.
+    /// 100 compacts every segment that isn't being written to.
+    pub fn live_threshold(mut self, percent: u8) -> CompactOptions {
+        self.live_threshold = percent;
+        self
+    }
+
+    /// Calls `on_progress` from the compacting thread once the
+    /// pages to move are known, every few pages as they are
+    /// moved, and when the compaction is done.
+    pub fn on_progress<F>(mut self, on_progress: F) -> CompactOptions
+    where
+        F: Fn(&CompactProgress) + Send + Sync + 'static,
+    {
+        self.on_progress = Some(Arc::new(on_progress));
+        self
+    }
+
+    fn report(&self, progress: &CompactProgress) {
+        if let Some(ref on_progress) = self.on_progress {
+            on_progress(progress);
+        }
+    }
+}
+
+/// How far along `Db::compact` is, which it also returns once
+/// it is done.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompactProgress {
+    /// The number of segments that were found to be at most as
+    /// live as the threshold.
+    pub segments: u64,
+    /// The number of pages that were moved so far.
+    pub moved: u64,
+    /// The number of pages that are left to move.
+    pub remaining: u64,
+}
+
+/// Compacts the segments that the options select, see
+/// `Db::compact`.
+pub(crate) fn compact(
+    context: &Context,
+    tenants: &FastMap8<IVec, Tree>,
+    options: &CompactOptions,
+) -> Result<CompactProgress> {
+    if context.read_only {
+        return Err(Error::Unsupported(
+            "the database was opened in read-only mode".into(),
+        ));
+    }
+    let ranged = options.range != (Bound::Unbounded, Bound::Unbounded);
+    if ranged && options.tree.is_none() {
+        return Err(Error::Unsupported(
+            "compacting a range of keys requires a tree".into(),
+        ));
+    }
+
+    let pagecache = &context.pagecache;
+    let threshold = usize::from(options.live_threshold);
+    let mut progress = CompactProgress::default();
+
+    if let Some(ref name) = options.tree {
+        let tree = tenants
+            .get(name)
+            .ok_or_else(|| Error::CollectionNotFound(name.clone()))?;
+
+        let segment_size = context.segment_size as u64;
+        let fragmented: FastSet8<LogOffset> = pagecache
+            .segment_space()
+            .into_iter()
+            .filter(|segment| {
+                segment.state == SegmentState::Inactive
+                    && segment.live * 100 <= threshold as u64 * segment_size
+            })
+            .map(|segment| segment.offset)
+            .collect();
+        progress.segments = fragmented.len() as u64;
+
+        let (lo, hi) = tree.encode_range(&options.range);
+        let guard = pin();
+        let pids: Vec<PageId> = leaves(tree, &lo, &hi)?
+            .into_iter()
+            .filter(|pid| {
+                pagecache.page_lids(*pid, &guard).iter().any(|lid| {
+                    fragmented.contains(&(lid / segment_size * segment_size))
+                })
+            })
+            .collect();
+        drop(guard);
+
+        progress.remaining = pids.len() as u64;
+        options.report(&progress);
+        for pid in pids {
+            pagecache.rewrite(pid)?;
+            progress.moved += 1;
+            progress.remaining -= 1;
+            if progress.moved % PROGRESS_EVERY == 0 {
+                options.report(&progress);
+            }
+        }
+    } else {
+        progress.segments = pagecache.drain_fragmented(threshold)?;
+        progress.remaining = pagecache.pages_to_clean();
+        options.report(&progress);
+
+        // the background garbage collector moves pages from the
+        // same segments, so they are counted here as they leave
+        while progress.remaining > 0 {
+            if pagecache.attempt_gc()?.is_some() {
+                progress.moved += 1;
+                if progress.moved % PROGRESS_EVERY == 0 {
+                    options.report(&progress);
+                }
+            }
+            progress.remaining = pagecache.pages_to_clean();
+        }
+    }
+
+    options.report(&progress);
+    Ok(progress)
+}
+
+// the leaves that hold keys from `lo` to `hi`
+fn leaves(
+    tree: &Tree,
+    lo: &Bound<IVec>,
+    hi: &Bound<IVec>,
+) -> Result<Vec<PageId>> {
+    let guard = pin();
+    let mut ret = vec![];
+    let mut key = match lo {
+        Bound::Included(start) | Bound::Excluded(start) => start.clone(),
+        Bound::Unbounded => IVec::default(),
+    };
+
+    loop {
+        let view = tree.view_for_key(&key, &guard)?;
+        ret.push(view.pid);
+
+        let node_hi = if let Some(node_hi) = view.hi() {
+            IVec::from(node_hi)
+        } else {
+            return Ok(ret);
+        };
+        let past_hi = match hi {
+            Bound::Included(end) => node_hi > *end,
+            Bound::Excluded(end) => node_hi >= *end,
+            Bound::Unbounded => false,
+        };
+        if past_hi {
+            return Ok(ret);
+        }
+        key = node_hi;
+    }
+}
@@ -5,10 +5,17 @@ use std::{
     io::{BufRead, BufReader, ErrorKind, Read, Seek, Write},
     ops::Deref,
     path::{Path, PathBuf},
-    sync::atomic::AtomicUsize,
+    sync::atomic::{AtomicBool, AtomicUsize},
+    time::Duration,
 };
 
-use crate::pagecache::{arr_to_u32, u32_to_arr, Heap};
+use crate::{
+    encryption::Encryption, fault::FaultHandler, slow_op::SlowOpHandler,
+};
+use crate::pagecache::{
+    arr_to_u32, direct_io, u32_to_arr, BufferPool, Dictionaries, Heap, Mmaps,
+    Readers,
+};
 use crate::*;
 
 const DEFAULT_PATH: &str = "default.sled";
@@ -30,12 +37,184 @@ pub enum Mode {
     HighThroughput,
 }
 
+/// How recovery treats parts of the storage files that can't be
+/// read, see `Config::recovery_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryMode {
+    /// Refuse to open a database with a corrupt snapshot, and
+    /// return `Error::Corruption` from operations that read
+    /// nodes that can't be read.
+    Strict,
+    /// Rebuild a corrupt snapshot from the log, and rebuild every
+    /// tree that has nodes that can't be read from the nodes that
+    /// can, reporting the key ranges that were lost in
+    /// `Db::lost_ranges`.
+    Salvage,
+}
+
+/// When the writes to the log are synced to the storage device
+/// without an explicit `Db::flush`, see `Config::sync_policy`
+/// and `Db::set_sync_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Every write is synced before it returns, which is the
+    /// most durable and the slowest policy.
+    EveryWrite,
+    /// A background thread syncs the log every this many
+    /// milliseconds.
+    EveryNMillis(u64),
+    /// The write that takes the unsynced part of the log to at
+    /// least this many bytes syncs it before it returns.
+    EveryNBytes(u64),
+    /// Nothing is synced until a buffer of the log fills up or
+    /// `Db::flush` is called, and no background thread flushes
+    /// the log or cleans its segments.
+    OsManaged,
+}
+
+impl SyncPolicy {
+    pub(crate) fn check(self) -> Result<()> {
+        match self {
+            SyncPolicy::EveryNMillis(0) | SyncPolicy::EveryNBytes(0) => {
+                Err(Error::Unsupported(
+                    "the interval of a sync policy must be above 0".into(),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What a write does when the queue of a subscriber that it has an
+/// event for is full, see `Config::subscriber_overflow`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriberOverflow {
+    /// The write waits until the subscriber takes an event, so
+    /// a slow subscriber slows down the writers of the keys that
+    /// it watches.
+    Block,
+    /// The oldest event in the queue is dropped, and the next one
+    /// that the subscriber receives counts it in `Event::missed`.
+    DropOldest,
+    /// The subscriber is disconnected, and ends once it has
+    /// received the events that are already in its queue.
+    Disconnect,
+}
+
+/// The compression applied to the pages of a `Tree`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// Pages are stored without compression, which keeps
+    /// reads and writes as fast as possible.
+    None,
+    /// Pages are compressed with zstd at the given level, which
+    /// ranges from 1 up to 22. Levels >= 20 are 'ultra'.
+    /// Requires the `compression` feature.
+    Zstd(i32),
+}
+
+/// How long the pages of a `Tree` are kept in the cache, relative
+/// to the pages of the other trees, see `TreeConfig::cache_priority`
+/// and `Tree::set_cache_priority`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CachePriority {
+    /// Pages are evicted before the pages of every other tree,
+    /// which suits large trees that are rarely read.
+    Low,
+    /// Pages are evicted in the order that they were last used.
+    Normal,
+    /// Pages are only evicted once the pages of every other tree
+    /// are, which suits small trees that are read all the time.
+    High,
+    /// Pages are never evicted once they have been read, which
+    /// suits small trees that every request depends on, like
+    /// routing metadata. They count against the capacity of the
+    /// cache like any other pages, but are kept even if they
+    /// don't fit in it on their own.
+    Pinned,
+}
+
+/// How the page cache chooses the pages that it keeps once it is
+/// full, see `Config::cache_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Every page that is used is cached, evicting the pages that
+    /// were used least recently, so a large scan replaces the
+    /// pages that are read all the time.
+    Lru,
+    /// A page that isn't cached is only admitted into a full cache
+    /// if it was used more often than the page that it would evict.
+    /// How often pages are used is estimated by a small sketch in
+    /// every shard of the cache, whose counts are halved as it
+    /// fills up so that pages that stop being used lose their
+    /// place. This keeps the working set cached through scans, at
+    /// the price of reading pages that were used for the first
+    /// time again the next time they are used.
+    TinyLfu,
+}
+
+/// Options for a `Tree` opened with `Db::open_tree_with`,
+/// which are fixed once the `Tree` has been created, apart
+/// from `merge_operator` and `cache_priority`.
+///
+/// # Examples
+///
+/// ```
+/// use sled::{CachePriority, TreeConfig};
+///
+/// let hot_index = TreeConfig {
+///     cache_priority: CachePriority::High,
+///     ..TreeConfig::default()
+/// };
+/// let cold_blobs = TreeConfig {
+///     cache_priority: CachePriority::Low,
+///     expected_value_size: Some(64 * 1024),
+///     ..TreeConfig::default()
+/// };
+/// # let _ = (hot_index, cold_blobs);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TreeConfig {
+    /// The compression applied to the pages of the `Tree`,
+    /// in place of the one configured for the `Db` with
+    /// `Config::use_compression`.
+    pub compression: Codec,
+    /// The order that the keys of the `Tree` are sorted in.
+    pub order: KeyOrder,
+    /// How long the pages of the `Tree` are kept in the cache,
+    /// which `Tree::set_cache_priority` changes later on.
+    pub cache_priority: CachePriority,
+    /// The typical size of the values of the `Tree`, which its
+    /// pages are sized for, so that a page holds several values
+    /// instead of one. It is rounded up to a power of two.
+    pub expected_value_size: Option<usize>,
+    /// The name of a merge operator registered with
+    /// `Db::register_merge_operator` that the `Tree` uses, as if
+    /// `Tree::use_merge_operator` was called once it's opened.
+    pub merge_operator: Option<&'static str>,
+}
+
+impl Default for TreeConfig {
+    fn default() -> TreeConfig {
+        TreeConfig {
+            compression: Codec::None,
+            order: KeyOrder::Lexicographic,
+            cache_priority: CachePriority::Normal,
+            expected_value_size: None,
+            merge_operator: None,
+        }
+    }
+}
+
 /// A persisted configuration about high-level
 /// storage file information
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 struct StorageParameters {
     pub segment_size: usize,
     pub use_compression: bool,
+    pub use_encryption: bool,
+    pub use_value_log: bool,
+    pub checksum: Checksum,
     pub version: (usize, usize),
 }
 
@@ -46,6 +225,15 @@ impl StorageParameters {
         writeln!(&mut out, "segment_size: {}", self.segment_size).unwrap();
         writeln!(&mut out, "use_compression: {}", self.use_compression)
             .unwrap();
+        if self.use_encryption {
+            writeln!(&mut out, "use_encryption: true").unwrap();
+        }
+        if self.use_value_log {
+            writeln!(&mut out, "use_value_log: true").unwrap();
+        }
+        if self.checksum != Checksum::Crc32 {
+            writeln!(&mut out, "checksum: {}", self.checksum.name()).unwrap();
+        }
         writeln!(&mut out, "version: {}.{}", self.version.0, self.version.1)
             .unwrap();
 
@@ -118,6 +306,46 @@ impl StorageParameters {
             return Err(Error::corruption(None));
         };
 
+        // only written for encrypted databases, so that older
+        // versions refuse to open them
+        let use_encryption: bool = if let Some(raw) =
+            lines.get("use_encryption")
+        {
+            if let Ok(parsed) = raw.parse() {
+                parsed
+            } else {
+                error!("failed to parse use_encryption value: {}", raw);
+                return Err(Error::corruption(None));
+            }
+        } else {
+            false
+        };
+
+        // only written for databases that separate large values
+        let use_value_log: bool = if let Some(raw) = lines.get("use_value_log")
+        {
+            if let Ok(parsed) = raw.parse() {
+                parsed
+            } else {
+                error!("failed to parse use_value_log value: {}", raw);
+                return Err(Error::corruption(None));
+            }
+        } else {
+            false
+        };
+
+        // only written for databases that don't use crc32
+        let checksum = if let Some(raw) = lines.get("checksum") {
+            if let Some(parsed) = Checksum::from_name(raw) {
+                parsed
+            } else {
+                error!("failed to parse checksum value: {}", raw);
+                return Err(Error::corruption(None));
+            }
+        } else {
+            Checksum::Crc32
+        };
+
         let version: (usize, usize) = if let Some(raw) = lines.get("version") {
             let mut split = raw.split('.');
             let major = if let Some(raw_major) = split.next() {
@@ -158,7 +386,14 @@ impl StorageParameters {
             return Err(Error::corruption(None));
         };
 
-        Ok(StorageParameters { segment_size, use_compression, version })
+        Ok(StorageParameters {
+            segment_size,
+            use_compression,
+            use_encryption,
+            use_value_log,
+            checksum,
+            version,
+        })
     }
 }
 
@@ -170,7 +405,7 @@ impl StorageParameters {
 /// let _config = sled::Config::default()
 ///     .path("/path/to/data".to_owned())
 ///     .cache_capacity(10_000_000_000)
-///     .flush_every_ms(Some(1000));
+///     .sync_policy(sled::SyncPolicy::EveryNMillis(1000));
 /// ```
 #[derive(Default, Debug, Clone)]
 pub struct Config(Arc<Inner>);
@@ -189,7 +424,23 @@ pub struct Inner {
     #[doc(hidden)]
     pub cache_capacity: usize,
     #[doc(hidden)]
-    pub flush_every_ms: Option<u64>,
+    pub cache_policy: CachePolicy,
+    #[doc(hidden)]
+    pub shared_cache_priority: CachePriority,
+    #[doc(hidden)]
+    pub compressed_cache_fraction: Option<f64>,
+    #[doc(hidden)]
+    pub secondary_cache_path: Option<PathBuf>,
+    #[doc(hidden)]
+    pub secondary_cache_capacity: u64,
+    #[doc(hidden)]
+    pub sync_policy: SyncPolicy,
+    #[doc(hidden)]
+    pub subscriber_capacity: usize,
+    #[doc(hidden)]
+    pub subscriber_overflow: SubscriberOverflow,
+    #[doc(hidden)]
+    pub memory_limit: Option<u64>,
     #[doc(hidden)]
     pub segment_size: usize,
     #[doc(hidden)]
@@ -210,6 +461,50 @@ pub struct Inner {
     pub snapshot_after_ops: u64,
     #[doc(hidden)]
     pub version: (usize, usize),
+    #[doc(hidden)]
+    pub expiration_sweep_every_ms: Option<u64>,
+    #[doc(hidden)]
+    pub read_only: bool,
+    #[doc(hidden)]
+    pub value_log_threshold: Option<usize>,
+    #[doc(hidden)]
+    pub bloom_bits_per_key: Option<usize>,
+    #[doc(hidden)]
+    pub recovery_mode: RecoveryMode,
+    #[doc(hidden)]
+    pub scrub_every_ms: Option<u64>,
+    #[doc(hidden)]
+    pub background_io_limit: Option<u64>,
+    #[doc(hidden)]
+    pub background_cpu_limit: Option<u8>,
+    #[doc(hidden)]
+    pub background_threads: usize,
+    #[doc(hidden)]
+    pub background_thread_name: String,
+    #[doc(hidden)]
+    pub background_thread_nice: Option<i32>,
+    #[doc(hidden)]
+    pub background_thread_cores: Option<Vec<usize>>,
+    #[doc(hidden)]
+    pub direct_io: bool,
+    #[doc(hidden)]
+    pub mmap_reads: bool,
+    #[doc(hidden)]
+    pub checksum: Checksum,
+    #[doc(hidden)]
+    pub group_commit_bytes: Option<usize>,
+    #[doc(hidden)]
+    pub group_commit_latency_us: Option<u64>,
+    #[doc(hidden)]
+    pub max_concurrent_reservations: usize,
+    #[doc(hidden)]
+    pub event_history: Option<usize>,
+    #[doc(hidden)]
+    pub version_retention_ms: Option<u64>,
+    pub(crate) encryption: Option<Encryption>,
+    pub(crate) on_fault: Option<FaultHandler>,
+    pub(crate) on_slow_op: Option<SlowOpHandler>,
+    pub(crate) shared_cache: Option<SharedCache>,
     tmp_path: PathBuf,
     pub(crate) global_error: Arc<Atomic<Error>>,
     #[cfg(feature = "event_log")]
@@ -225,6 +520,11 @@ impl Default for Inner {
             tmp_path: Config::gen_temp_path(),
             create_new: false,
             cache_capacity: 1024 * 1024 * 1024, // 1gb
+            cache_policy: CachePolicy::Lru,
+            shared_cache_priority: CachePriority::Normal,
+            compressed_cache_fraction: None,
+            secondary_cache_path: None,
+            secondary_cache_capacity: 16 * 1024 * 1024 * 1024, // 16gb
             mode: Mode::LowSpace,
             use_compression: false,
             compression_factor: 5,
@@ -233,13 +533,40 @@ impl Default for Inner {
 
             // useful in testing
             segment_size: 512 * 1024, // 512kb in bytes
-            flush_every_ms: Some(500),
+            sync_policy: SyncPolicy::EveryNMillis(500),
             idgen_persist_interval: 1_000_000,
             snapshot_after_ops: if cfg!(feature = "testing") {
                 10
             } else {
                 1_000_000
             },
+            expiration_sweep_every_ms: Some(1000),
+            read_only: false,
+            value_log_threshold: None,
+            bloom_bits_per_key: None,
+            recovery_mode: RecoveryMode::Strict,
+            scrub_every_ms: None,
+            background_io_limit: None,
+            background_cpu_limit: None,
+            background_threads: 1,
+            background_thread_name: "sled".to_owned(),
+            background_thread_nice: None,
+            background_thread_cores: None,
+            direct_io: false,
+            mmap_reads: false,
+            checksum: Checksum::Crc32,
+            group_commit_bytes: None,
+            group_commit_latency_us: None,
+            max_concurrent_reservations: 127,
+            event_history: None,
+            version_retention_ms: None,
+            subscriber_capacity: 1024,
+            subscriber_overflow: SubscriberOverflow::Block,
+            memory_limit: None,
+            encryption: None,
+            on_fault: None,
+            on_slow_op: None,
+            shared_cache: None,
             global_error: Arc::new(Atomic::default()),
             #[cfg(feature = "event_log")]
             event_log: Arc::new(crate::event_log::EventLog::default()),
@@ -248,6 +575,20 @@ impl Default for Inner {
 }
 
 impl Inner {
+    /// The bytes of `cache_capacity` that are set aside for the
+    /// compressed tier of the cache.
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    #[allow(clippy::float_arithmetic)]
+    pub(crate) fn compressed_cache_capacity(
+        &self,
+        cache_capacity: usize,
+    ) -> usize {
+        self.compressed_cache_fraction
+            .map_or(0, |fraction| (cache_capacity as f64 * fraction) as usize)
+    }
+
     // Get the path of the database
     #[doc(hidden)]
     pub fn get_path(&self) -> PathBuf {
@@ -258,11 +599,11 @@ impl Inner {
         }
     }
 
-    fn db_path(&self) -> PathBuf {
+    pub(crate) fn db_path(&self) -> PathBuf {
         self.get_path().join("db")
     }
 
-    fn config_path(&self) -> PathBuf {
+    pub(crate) fn config_path(&self) -> PathBuf {
         self.get_path().join("conf")
     }
 
@@ -320,6 +661,121 @@ impl Config {
         self
     }
 
+    /// Encrypt everything that is written to the storage files
+    /// using keys from the provided `KeyProvider`. A database
+    /// that was created with encryption can only be opened
+    /// with it, and vice versa.
+    pub fn encryption<K: KeyProvider + 'static>(
+        mut self,
+        provider: K,
+    ) -> Config {
+        if Arc::strong_count(&self.0) != 1 {
+            error!(
+                "config has already been used to start \
+                 the system and probably should not be \
+                 mutated",
+            );
+        }
+        let m = Arc::make_mut(&mut self.0);
+        m.encryption = Some(Encryption::new(provider));
+        self
+    }
+
+    /// Keeps the pages of the `Db` in `cache`, along with the pages
+    /// of the other databases that use it, instead of in a cache of
+    /// its own of `cache_capacity` bytes, see `SharedCache`. The
+    /// `cache_policy` of the `SharedCache` is used rather than
+    /// this one.
+    pub fn shared_cache(mut self, cache: SharedCache) -> Config {
+        if Arc::strong_count(&self.0) != 1 {
+            error!(
+                "config has already been used to start \
+                 the system and probably should not be \
+                 mutated",
+            );
+        }
+        let m = Arc::make_mut(&mut self.0);
+        m.shared_cache = Some(cache);
+        self
+    }
+
+    /// Calls `handler` with every `Fault` that the `Db` runs into
+    /// while reading or writing its files, such as checksum
+    /// mismatches, short reads and IO errors, so that they can
+    /// be acted on as they happen rather than found in the logs.
+    /// The handler is called from the thread that ran into the
+    /// fault, which may be holding locks, so it should return
+    /// quickly and must not use the `Db`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new()
+    ///     .temporary(true)
+    ///     .on_fault(|fault| {
+    ///         if fault.fatal {
+    ///             eprintln!("paging a human: {}", fault);
+    ///         }
+    ///     })
+    ///     .open()?;
+    /// # Ok(()) }
+    /// ```
+    pub fn on_fault<F>(mut self, handler: F) -> Config
+    where
+        F: Fn(&Fault) + Send + Sync + 'static,
+    {
+        if Arc::strong_count(&self.0) != 1 {
+            error!(
+                "config has already been used to start \
+                 the system and probably should not be \
+                 mutated",
+            );
+        }
+        let m = Arc::make_mut(&mut self.0);
+        m.on_fault = Some(FaultHandler::new(handler));
+        self
+    }
+
+    /// Calls `handler` with every get, insert, remove, flush and
+    /// transaction that takes `threshold` or longer, along with
+    /// the length of its key, the pages that it read and the time
+    /// that it spent in each of its phases, like waiting for a
+    /// transaction to commit or linking its update into the tree.
+    /// The phases are only timed while a handler is set. The
+    /// handler is called from the thread that ran the operation,
+    /// once it's done, so it should return quickly.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::time::Duration;
+    ///
+    /// let db = sled::Config::new()
+    ///     .temporary(true)
+    ///     .on_slow_op(Duration::from_millis(100), |slow_op| {
+    ///         eprintln!("{}", slow_op);
+    ///     })
+    ///     .open()?;
+    /// # Ok(()) }
+    /// ```
+    pub fn on_slow_op<F>(mut self, threshold: Duration, handler: F) -> Config
+    where
+        F: Fn(&SlowOp) + Send + Sync + 'static,
+    {
+        if Arc::strong_count(&self.0) != 1 {
+            error!(
+                "config has already been used to start \
+                 the system and probably should not be \
+                 mutated",
+            );
+        }
+        let m = Arc::make_mut(&mut self.0);
+        m.on_slow_op = Some(SlowOpHandler::new(threshold, handler));
+        self
+    }
+
     /// A testing-only method for reducing the io-buffer size
     /// to trigger correctness-critical behavior more often
     /// by shrinking the buffer size. Don't rely on this.
@@ -346,21 +802,65 @@ impl Config {
         config.limit_cache_max_memory();
 
         let file = config.open_file()?;
+        let direct_file = config.open_direct_file();
+        let mmaps = if config.mmap_reads {
+            Some(Arc::new(Mmaps::new(config.segment_size)))
+        } else {
+            None
+        };
+
+        let readers = Readers::new(&config.get_path(), config.segment_size);
+        if config.read_only {
+            readers.register()?;
+        }
 
         let heap_path = config.get_path().join("heap");
-        let heap = Heap::start(&heap_path)?;
-        maybe_fsync_directory(heap_path)?;
+        let heap = Heap::start(&heap_path, config.read_only, config.checksum)?;
+        if !config.read_only {
+            maybe_fsync_directory(heap_path)?;
+        }
+
+        let dictionaries = Dictionaries::start(
+            &config.get_path(),
+            config.read_only,
+            config.encryption.clone(),
+        )?;
+
+        let value_log = ValueLog::start(
+            &config.get_path(),
+            config.read_only,
+            config.encryption.clone(),
+        )?;
 
         // seal config in a Config
         let config = RunningConfig {
             inner: config,
             file: Arc::new(file),
+            direct_file,
+            mmaps,
             heap: Arc::new(heap),
+            io_barrier: Arc::new(RwLock::new(())),
+            no_space: Arc::new(AtomicBool::new(false)),
+            runtime_sync_policy: Arc::new(RwLock::new(self.sync_policy)),
+            readers: Arc::new(readers),
+            dictionaries: Arc::new(dictionaries),
+            value_log: Arc::new(value_log),
+            metrics: Arc::new(db_metrics::Counters::default()),
+            latency: Arc::new(latency::Histograms::default()),
+            memory: Arc::new(memory::Gauges::default()),
+            buffers: Arc::new(BufferPool::default()),
         };
 
         Db::start_inner(config)
     }
 
+    /// Opens an `AsyncDb` based on the provided config,
+    /// performing the blocking recovery process on a
+    /// background thread rather than on the calling task.
+    pub async fn open_async(&self) -> Result<AsyncDb> {
+        AsyncDb::open(self.clone()).await
+    }
+
     #[doc(hidden)]
     pub fn flush_every_ms(mut self, every_ms: Option<u64>) -> Self {
         if Arc::strong_count(&self.0) != 1 {
@@ -371,7 +871,10 @@ impl Config {
             );
         }
         let m = Arc::make_mut(&mut self.0);
-        m.flush_every_ms = every_ms;
+        m.sync_policy = match every_ms {
+            Some(ms) => SyncPolicy::EveryNMillis(ms),
+            None => SyncPolicy::OsManaged,
+        };
         self
     }
 
@@ -438,6 +941,31 @@ impl Config {
             usize,
             "maximum size in bytes for the system page cache"
         ),
+        (
+            cache_policy,
+            CachePolicy,
+            "how the page cache chooses the pages that it keeps once it is full. `CachePolicy::TinyLfu` keeps pages that are used often cached through large scans. defaults to `CachePolicy::Lru`"
+        ),
+        (
+            shared_cache_priority,
+            CachePriority,
+            "how long the pages of the database are kept in a `SharedCache`, relative to the pages of the other databases that use it. `CachePriority::High` moves the `TreeConfig::cache_priority` of every tree up by one, and `CachePriority::Low` moves it down by one, but the pages of pinned trees stay pinned. can't be `CachePriority::Pinned`. defaults to `CachePriority::Normal`"
+        ),
+        (
+            compressed_cache_fraction,
+            Option<f64>,
+            "the fraction of `cache_capacity`, between 0 and 1, that is set aside for a second tier of the cache, which keeps the pages that are evicted from the first one compressed with zstd, so that reading them again costs decompressing them instead of reading them from the storage files. pages that compress 4:1 take a quarter of the memory there. requires the `compression` feature, and can't be combined with `shared_cache`. None, the default, drops the pages that are evicted"
+        ),
+        (
+            secondary_cache_path,
+            Option<PathBuf>,
+            "a file on a fast local device, like an NVMe drive, that the pages that are evicted from the cache are written to, so that reading them again is served from it instead of from the storage files, which may be on slower network storage. the file is emptied when the database is opened and removed when it's closed, and may not be used by two databases at once. its size is bounded by `secondary_cache_capacity`, and the oldest pages in it are overwritten first. None, the default, reads evicted pages from the storage files"
+        ),
+        (
+            secondary_cache_capacity,
+            u64,
+            "the most bytes that the file of `secondary_cache_path` takes. defaults to 16gb"
+        ),
         (
             mode,
             Mode,
@@ -463,6 +991,126 @@ impl Config {
             snapshot_after_ops,
             u64,
             "take a fuzzy snapshot of pagecache metadata after this many ops"
+        ),
+        (
+            expiration_sweep_every_ms,
+            Option<u64>,
+            "how often to remove keys written with `Tree::insert_with_ttl` after they expire. None disables background sweeping"
+        ),
+        (
+            read_only,
+            bool,
+            "opens an existing database without ever writing to its files, even while another process writes to it. all mutations return `Error::Unsupported`, and `Db::refresh` observes later writes"
+        ),
+        (
+            value_log_threshold,
+            Option<usize>,
+            "stores values of at least this many bytes in separate value log files, and only a pointer to them in the tree, so that they are not rewritten along with their nodes. None stores every value in the tree. can't be turned on or off after the database is created, but the threshold may change. see `Db::collect_value_log_garbage`"
+        ),
+        (
+            bloom_bits_per_key,
+            Option<usize>,
+            "keeps a bloom filter with this many bits per key in memory for every leaf that has been read, so that reads of keys that don't exist can skip paging in the leaves that would hold them. 10 bits per key give about 1% false positives. None disables the filters"
+        ),
+        (
+            recovery_mode,
+            RecoveryMode,
+            "whether to refuse to open a database with a corrupt snapshot and fail reads of nodes that can't be read, or to salvage everything that is readable when opening it. `RecoveryMode::Salvage` reads every node at startup, and can't be used in read-only mode"
+        ),
+        (
+            scrub_every_ms,
+            Option<u64>,
+            "how often to read a few pages back from the storage files in the background and check them, so that damage to data that is rarely read is found before it is needed. pages that fail the check are sent to the receivers returned by `Db::scrub_failures`. None disables scrubbing"
+        ),
+        (
+            background_io_limit,
+            Option<u64>,
+            "caps the bytes per second that the background threads write while flushing buffered writes and moving pages out of fragmented segments, pausing them once they get ahead. the limit is split evenly between the `background_threads`. foreground writes and `Db::compact` are not limited. None lets them write as fast as they can"
+        ),
+        (
+            background_cpu_limit,
+            Option<u8>,
+            "caps the percentage of the time of one core that each background thread spends flushing and moving pages out of fragmented segments, from 1 to 100. None lets the flush thread run for up to half of every `SyncPolicy::EveryNMillis` interval, and the other threads for all of it"
+        ),
+        (
+            background_threads,
+            usize,
+            "the number of background threads that move pages out of fragmented segments of the log, starting with the thread that flushes according to `SyncPolicy::EveryNMillis`, which cleans between flushes. the others only clean, and wake up as often as the flush thread does. under the policies that sync from the writes, every thread only cleans, twice a second, and none of them are started under `SyncPolicy::OsManaged`. must be at least 1"
+        ),
+        (
+            background_thread_name,
+            String,
+            "the prefix of the names of the threads that the database starts for itself, which are named after what they do, like `sled-flusher`, `sled-cleaner-1` or `sled-scrubber`. the threads that write the log and take snapshots are shared by every database of the process, and are not renamed"
+        ),
+        (
+            background_thread_nice,
+            Option<i32>,
+            "the niceness that the threads that the database starts for itself run at, from -20 to 19, where higher values yield more of the cpu to other threads. only lowering their priority is allowed without privileges. linux only. None keeps the niceness of the thread that opened the database"
+        ),
+        (
+            background_thread_cores,
+            Option<Vec<usize>>,
+            "pins the threads that the database starts for itself to these cores, so that they can't take time from the cores that serve requests. linux only. None lets them run on every core"
+        ),
+        (
+            direct_io,
+            bool,
+            "whether to read and write the log with `O_DIRECT`, in whole blocks of 4kb, so that it bypasses the page cache of the OS and only the cache of the database holds its pages. writes that share a block with the one before them read that block back first, and are serialized. `io_uring` isn't used for the log in this mode, and the heap files and value log still go through the page cache. filesystems that don't support it, like tmpfs, fall back to the page cache with a warning. linux only"
+        ),
+        (
+            mmap_reads,
+            bool,
+            "whether to read pages from memory maps of the segments of the log instead of with a read syscall each, which is faster for data that is read far more than it is written and fits in the page cache of the OS. segments are mapped once the log grows past them, so the end of the log is still read from the file. the log must not be shortened by anything but the database. unix only, and can't be combined with `direct_io` or `read_only`"
+        ),
+        (
+            checksum,
+            Checksum,
+            "the checksum of the messages of the log and of the large messages in the heap files. `Checksum::Crc32c` is computed in hardware on `x86_64` cpus with SSE 4.2. the checksum is recorded when the database is created, and must be the same every time that it is opened. the snapshot, the value log and the other metadata files always use crc32"
+        ),
+        (
+            sync_policy,
+            SyncPolicy,
+            "when writes to the log are synced to the storage device without an explicit `Db::flush`. can be changed while the database is open with `Db::set_sync_policy`. `flush_every_ms` sets `SyncPolicy::EveryNMillis`, or `SyncPolicy::OsManaged` when given None. defaults to `SyncPolicy::EveryNMillis(500)`"
+        ),
+        (
+            group_commit_bytes,
+            Option<usize>,
+            "writes a buffer of the log as soon as this many bytes are reserved in it, so that writers that don't flush still reach the disk in batches of about this size. None only writes a buffer once its segment is full or it is flushed"
+        ),
+        (
+            group_commit_latency_us,
+            Option<u64>,
+            "how long a flush waits for more writers to join the buffer of the log that it is about to write, so that their writes share its sync. the wait ends early once the buffer is written for another reason, like reaching `group_commit_bytes`. None writes it right away"
+        ),
+        (
+            max_concurrent_reservations,
+            usize,
+            "the most writers that may serialize their messages into a buffer of the log at the same time, from 1 to 127. further writers wait for one of them to finish"
+        ),
+        (
+            event_history,
+            Option<usize>,
+            "the number of the most recent events that every tree retains, so that `Tree::watch_prefix_since` can replay them to a subscriber that was not running when they happened. the history that was retained is discarded when the database is opened with None, which is the default"
+        ),
+        (
+            version_retention_ms,
+            Option<u64>,
+            "how long the values that keys held before they were overwritten or removed are retained, so that `Tree::get_at` and `Tree::range_at` can read a tree as of a point within that window. the versions that were retained are discarded when the database is opened with None, which is the default"
+        ),
+        (
+            subscriber_capacity,
+            usize,
+            "the number of events that may be queued for a subscriber that hasn't received them yet, including those of writes that are still being made. must be at least 1, and defaults to 1024"
+        ),
+        (
+            subscriber_overflow,
+            SubscriberOverflow,
+            "what a write does when the queue of a subscriber is full, see `SubscriberOverflow`. defaults to `SubscriberOverflow::Block`"
+        ),
+        (
+            memory_limit,
+            Option<u64>,
+            "the most bytes that the page cache, the buffers of the log, the events that are queued for subscribers and the writes that `Txn`s hold take together, see `Db::memory_usage`. writes that find them above it flush the log and wait for up to a second for them to fall below it, instead of letting them grow. must leave room for `cache_capacity`, or the capacity of a `SharedCache`, and for a buffer of `segment_size` bytes. None, the default, doesn't limit them"
         )
     );
 
@@ -486,6 +1134,37 @@ impl Config {
                 "the 'compression' feature must be enabled"
             );
         }
+        if let Some(fraction) = self.compressed_cache_fraction {
+            supported!(
+                fraction > 0. && fraction < 1.,
+                "compressed_cache_fraction must be between 0 and 1"
+            );
+            supported!(
+                cfg!(feature = "compression"),
+                "compressed_cache_fraction requires the 'compression' feature"
+            );
+            supported!(
+                self.shared_cache.is_none(),
+                "compressed_cache_fraction can't be combined with shared_cache"
+            );
+            supported!(
+                self.cache_capacity
+                    - self.compressed_cache_capacity(self.cache_capacity)
+                    >= 256,
+                "the cache capacity must leave at least 256 bytes \
+                 beside its compressed tier"
+            );
+        }
+        supported!(
+            self.shared_cache_priority != CachePriority::Pinned,
+            "shared_cache_priority can't be CachePriority::Pinned"
+        );
+        if self.secondary_cache_path.is_some() {
+            supported!(
+                self.secondary_cache_capacity > 0,
+                "secondary_cache_capacity must be above 0"
+            );
+        }
         supported!(
             self.compression_factor >= 1,
             "compression_factor must be >= 1"
@@ -498,10 +1177,111 @@ impl Config {
             self.idgen_persist_interval > 0,
             "idgen_persist_interval must be above 0"
         );
+        supported!(
+            !(self.read_only && self.temporary),
+            "a temporary database can't be opened in read-only mode"
+        );
+        supported!(
+            !(self.read_only && self.create_new),
+            "a new database can't be created in read-only mode"
+        );
+        supported!(
+            !(self.read_only && self.recovery_mode == RecoveryMode::Salvage),
+            "a database can't be salvaged in read-only mode"
+        );
+        supported!(
+            self.background_io_limit != Some(0),
+            "background_io_limit must be above 0"
+        );
+        supported!(
+            self.background_threads >= 1,
+            "background_threads must be at least 1"
+        );
+        supported!(
+            cfg!(target_os = "linux")
+                || (self.background_thread_nice.is_none()
+                    && self.background_thread_cores.is_none()),
+            "background_thread_nice and background_thread_cores \
+             are only supported on linux"
+        );
+        if let Some(nice) = self.background_thread_nice {
+            supported!(
+                (-20..=19).contains(&nice),
+                "background_thread_nice must be from -20 to 19"
+            );
+        }
+        if let Some(ref cores) = self.background_thread_cores {
+            supported!(
+                !cores.is_empty(),
+                "background_thread_cores must name at least one core"
+            );
+        }
+        supported!(
+            cfg!(target_os = "linux") || !self.direct_io,
+            "direct_io is only supported on linux"
+        );
+        if self.mmap_reads {
+            supported!(
+                cfg!(all(unix, not(miri))),
+                "mmap_reads is only supported on unix"
+            );
+            supported!(
+                !self.direct_io,
+                "mmap_reads can't be combined with direct_io"
+            );
+            // a writer may truncate the log under the maps
+            supported!(
+                !self.read_only,
+                "mmap_reads can't be used in read-only mode"
+            );
+        }
+        self.sync_policy.check()?;
+        supported!(
+            self.group_commit_bytes != Some(0),
+            "group_commit_bytes must be above 0"
+        );
+        supported!(
+            self.event_history != Some(0),
+            "event_history must be above 0"
+        );
+        supported!(
+            self.version_retention_ms != Some(0),
+            "version_retention_ms must be above 0"
+        );
+        supported!(
+            self.subscriber_capacity > 0,
+            "subscriber_capacity must be above 0"
+        );
+        if let Some(limit) = self.memory_limit {
+            let cache_capacity = self
+                .shared_cache
+                .as_ref()
+                .map_or(self.cache_capacity, SharedCache::capacity);
+            supported!(
+                limit > (cache_capacity + self.segment_size) as u64,
+                "memory_limit must leave room for the cache and for \
+                 a buffer of segment_size bytes beside it"
+            );
+        }
+        // the count of writers in the header of a buffer has 7 bits
+        supported!(
+            (1..=127).contains(&self.max_concurrent_reservations),
+            "max_concurrent_reservations must be from 1 to 127"
+        );
+        if let Some(cpu_limit) = self.background_cpu_limit {
+            supported!(
+                (1..=100).contains(&cpu_limit),
+                "background_cpu_limit must be from 1 to 100"
+            );
+        }
         Ok(())
     }
 
     fn open_file(&self) -> Result<File> {
+        if self.read_only {
+            return self.open_file_read_only();
+        }
+
         let heap_dir: PathBuf = self.get_path().join("heap");
 
         if !heap_dir.exists() {
@@ -530,6 +1310,53 @@ impl Config {
         Ok(file)
     }
 
+    fn open_file_read_only(&self) -> Result<File> {
+        supported!(
+            self.db_path().exists(),
+            format!(
+                "no database exists at {:?} to open in read-only mode",
+                self.get_path()
+            )
+        );
+
+        match self.read_config()? {
+            Some(_) => self.verify_config()?,
+            None => {
+                return Err(Error::Unsupported(
+                    "can't open a database without a config file \
+                     in read-only mode"
+                        .into(),
+                ))
+            }
+        }
+
+        // readers don't lock the file, because a writer may
+        // be running. They register themselves in the
+        // `readers` directory instead, see `Readers`.
+        let file = fs::OpenOptions::new().read(true).open(&self.db_path())?;
+
+        Ok(file)
+    }
+
+    // a second handle to the log that bypasses the page cache,
+    // which is `None` unless `direct_io` is set and works here
+    fn open_direct_file(&self) -> Option<Arc<File>> {
+        if !self.direct_io {
+            return None;
+        }
+        match direct_io::open(&self.db_path(), self.read_only) {
+            Ok(file) => Some(Arc::new(file)),
+            Err(e) => {
+                warn!(
+                    "direct IO is unavailable, falling back to \
+                     the page cache for the log: {}",
+                    e
+                );
+                None
+            }
+        }
+    }
+
     fn try_lock(&self, file: File) -> Result<File> {
         #[cfg(all(
             not(miri),
@@ -577,6 +1404,37 @@ impl Config {
                     )
                 );
 
+                supported!(
+                    self.encryption.is_some() == old.use_encryption,
+                    if old.use_encryption {
+                        "this database is encrypted, and must be opened \
+                         with `Config::encryption`"
+                    } else {
+                        "this database is not encrypted, and can't be \
+                         opened with `Config::encryption`"
+                    }
+                );
+
+                supported!(
+                    self.value_log_threshold.is_some() == old.use_value_log,
+                    if old.use_value_log {
+                        "this database separates large values, and must \
+                         be opened with `Config::value_log_threshold`"
+                    } else {
+                        "this database does not separate large values, and \
+                         can't be opened with `Config::value_log_threshold`"
+                    }
+                );
+
+                supported!(
+                    self.checksum == old.checksum,
+                    format!(
+                        "cannot change the checksum across restarts. \
+                         please change it back to {:?}",
+                        old.checksum
+                    )
+                );
+
                 supported!(
                     self.segment_size == old.segment_size,
                     format!(
@@ -612,6 +1470,9 @@ impl Config {
             version: self.version,
             segment_size: self.segment_size,
             use_compression: self.use_compression,
+            use_encryption: self.encryption.is_some(),
+            use_value_log: self.value_log_threshold.is_some(),
+            checksum: self.checksum,
         };
 
         persisted_config.serialize()
@@ -715,16 +1576,33 @@ impl Config {
 
     pub(crate) fn set_global_error(&self, error_value: Error) {
         let guard = pin();
+        let reported = error_value.clone();
         let error = Owned::new(error_value);
 
         let expected_old = Shared::null();
 
-        let _ = self.global_error.compare_and_set(
+        let set = self.global_error.compare_and_set(
             expected_old,
             error,
             SeqCst,
             &guard,
         );
+        // only the first error stops the system
+        if set.is_ok() {
+            self.report_fault(None, &reported, true);
+        }
+    }
+
+    pub(crate) fn report_fault(
+        &self,
+        pid: Option<PageId>,
+        error: &Error,
+        fatal: bool,
+    ) {
+        if let Some(ref handler) = self.on_fault {
+            let fault = Fault { pid, error: error.clone(), fatal };
+            handler.report(&fault);
+        }
     }
 
     #[cfg(feature = "failpoints")]
@@ -746,7 +1624,27 @@ impl Config {
 pub struct RunningConfig {
     inner: Config,
     pub(crate) file: Arc<File>,
+    // the log opened with O_DIRECT, see `Config::direct_io`
+    pub(crate) direct_file: Option<Arc<File>>,
+    // maps of the log to read from, see `Config::mmap_reads`
+    pub(crate) mmaps: Option<Arc<Mmaps>>,
     pub(crate) heap: Arc<Heap>,
+    // held for reading around every write to the storage
+    // files, and for writing by `Db::checkpoint`
+    pub(crate) io_barrier: Arc<RwLock<()>>,
+    // set while a buffer can't be written because the storage
+    // device is full, see `Error::NoSpace`
+    pub(crate) no_space: Arc<AtomicBool>,
+    // starts as `Config::sync_policy`, see `Db::set_sync_policy`
+    pub(crate) runtime_sync_policy: Arc<RwLock<SyncPolicy>>,
+    pub(crate) readers: Arc<Readers>,
+    pub(crate) dictionaries: Arc<Dictionaries>,
+    pub(crate) value_log: Arc<ValueLog>,
+    pub(crate) metrics: Arc<db_metrics::Counters>,
+    pub(crate) latency: Arc<latency::Histograms>,
+    pub(crate) memory: Arc<memory::Gauges>,
+    // the buffers that the write path reuses, see `BufferPool`
+    pub(crate) buffers: Arc<BufferPool>,
 }
 
 impl Deref for RunningConfig {
@@ -770,6 +1668,21 @@ impl Drop for Inner {
 }
 
 impl RunningConfig {
+    /// Returns `Error::NoSpace` while a buffer is waiting for
+    /// space to be freed on the storage device.
+    pub(crate) fn check_space(&self) -> Result<()> {
+        if self.no_space.load(Acquire) {
+            Err(Error::NoSpace)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the sync policy that is in effect now.
+    pub(crate) fn current_sync_policy(&self) -> SyncPolicy {
+        *self.runtime_sync_policy.read()
+    }
+
     // returns the snapshot file paths for this system
     #[doc(hidden)]
     pub fn get_snapshot_files(&self) -> io::Result<Vec<PathBuf>> {
@@ -26,8 +26,29 @@ pub struct Context {
         )
     ))]
     pub(crate) flusher: Arc<Mutex<Option<flusher::Flusher>>>,
+    /// The threads that clean segments alongside the flusher,
+    /// see `Config::background_threads`.
+    #[cfg(all(
+        not(miri),
+        any(
+            windows,
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "dragonfly",
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd",
+            target_os = "ios",
+        )
+    ))]
+    pub(crate) cleaners: Arc<Mutex<Vec<flusher::Flusher>>>,
     #[doc(hidden)]
     pub pagecache: PageCache,
+    pub(crate) merge_operators: Arc<MergeOperators>,
+    pub(crate) scrubber: Arc<scrub::Scrubber>,
+    pub(crate) key_locks: Arc<key_lock::KeyLocks>,
+    pub(crate) snapshots: Arc<snapshot::Snapshots>,
+    pub(crate) sweeper: Arc<expiration::Sweeper>,
 }
 
 impl std::ops::Deref for Context {
@@ -46,7 +67,12 @@ impl Context {
 
         Ok(Self {
             config,
+            scrubber: Arc::new(scrub::Scrubber::new(pagecache.clone())),
             pagecache,
+            merge_operators: Arc::new(MergeOperators::default()),
+            key_locks: Arc::new(key_lock::KeyLocks::default()),
+            snapshots: Arc::new(snapshot::Snapshots::default()),
+            sweeper: Arc::new(expiration::Sweeper::default()),
             #[cfg(all(
                 not(miri),
                 any(
@@ -61,6 +87,20 @@ impl Context {
                 )
             ))]
             flusher: Arc::new(parking_lot::Mutex::new(None)),
+            #[cfg(all(
+                not(miri),
+                any(
+                    windows,
+                    target_os = "linux",
+                    target_os = "macos",
+                    target_os = "dragonfly",
+                    target_os = "freebsd",
+                    target_os = "openbsd",
+                    target_os = "netbsd",
+                    target_os = "ios",
+                )
+            ))]
+            cleaners: Arc::new(Mutex::new(vec![])),
         })
     }
 
@@ -0,0 +1,149 @@
+//! Persistent counters, see `Tree::increment`.
+//!
+//! A counter is a key whose value is a big-endian `u64`. Rather
+//! than racing to swap in the sum of their own delta, concurrent
+//! increments of the same key queue their deltas: the first one
+//! that finds no increment of the key in flight takes every delta
+//! queued so far and writes their sum in a single update to the
+//! log, while the others wait for it and work out the value that
+//! their own delta produced from the value it started from. So a
+//! hot counter is written once per batch of increments instead of
+//! once per increment, and none of them retries a write that lost
+//! a race against another increment of the same key.
+use std::cell::Cell;
+
+use crate::*;
+
+/// The increments of the counters of a `Tree` that are in flight.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    keys: Mutex<FastMap8<IVec, KeyState>>,
+    applied: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct KeyState {
+    // whether a batch of deltas is being written
+    applying: bool,
+    // the batch that the queued deltas belong to
+    batch: u64,
+    queued: Vec<u64>,
+    // the batches that were written but not yet picked up by every
+    // increment that queued a delta in them
+    done: FastMap8<u64, Applied>,
+}
+
+impl KeyState {
+    fn is_idle(&self) -> bool {
+        !self.applying && self.queued.is_empty() && self.done.is_empty()
+    }
+}
+
+#[derive(Debug)]
+struct Applied {
+    // the value of the counter before the batch was added to it
+    previous: Result<u64>,
+    deltas: Vec<u64>,
+    waiting: usize,
+}
+
+// the value that the delta at `index` of a batch produced
+fn value_after(previous: u64, deltas: &[u64], index: usize) -> u64 {
+    deltas[..=index]
+        .iter()
+        .fold(previous, |sum, delta| sum.wrapping_add(*delta))
+}
+
+/// Adds `delta` to the counter at `key`, returning its new value.
+pub(crate) fn increment(tree: &Tree, key: &[u8], delta: u64) -> Result<u64> {
+    let counters = &tree.counters;
+    let mut keys = counters.keys.lock();
+    let queue = keys.entry(key.into()).or_default();
+    let batch = queue.batch;
+    let index = queue.queued.len();
+    queue.queued.push(delta);
+
+    loop {
+        let state = keys.get_mut(key).unwrap();
+
+        if let Some(applied) = state.done.get_mut(&batch) {
+            let ret = applied
+                .previous
+                .clone()
+                .map(|old| value_after(old, &applied.deltas, index));
+            applied.waiting -= 1;
+            if applied.waiting == 0 {
+                let _ = state.done.remove(&batch);
+            }
+            if state.is_idle() {
+                let _ = keys.remove(key);
+            }
+            return ret;
+        }
+
+        if !state.applying {
+            // our delta is still queued, so we write the batch
+            let deltas = std::mem::take(&mut state.queued);
+            state.batch += 1;
+            state.applying = true;
+            drop(keys);
+
+            let sum = deltas
+                .iter()
+                .fold(0_u64, |sum, queued| sum.wrapping_add(*queued));
+            let previous = add(tree, key, sum);
+            let ret =
+                previous.clone().map(|old| value_after(old, &deltas, index));
+
+            keys = counters.keys.lock();
+            let written = keys.get_mut(key).unwrap();
+            written.applying = false;
+            if deltas.len() > 1 {
+                let waiting = deltas.len() - 1;
+                let applied = Applied { previous, deltas, waiting };
+                let _ = written.done.insert(batch, applied);
+            } else if written.is_idle() {
+                let _ = keys.remove(key);
+            }
+            drop(keys);
+            counters.applied.notify_all();
+            return ret;
+        }
+
+        counters.applied.wait(&mut keys);
+    }
+}
+
+// adds `sum` to the counter in a single write, returning the value
+// that it had before
+fn add(tree: &Tree, key: &[u8], sum: u64) -> Result<u64> {
+    memory::wait_for_room(&tree.context)?;
+    quota::check(tree, Some((key, 8)))?;
+    let stored_key = tree.order.encode(key);
+    let _cc = concurrency_control::read();
+    let indexed = index::begin_write(tree, &pin())?;
+
+    let previous = Cell::new(Ok(0));
+    let merge = |_: &[u8], old: Option<&[u8]>, _: &[u8]| {
+        let old_value = match old.map(<[u8; 8]>::try_from) {
+            None => 0,
+            Some(Ok(bytes)) => u64::from_be_bytes(bytes),
+            Some(Err(_)) => {
+                previous.set(Err(()));
+                return old.map(<[u8]>::to_vec);
+            }
+        };
+        previous.set(Ok(old_value));
+        Some(old_value.wrapping_add(sum).to_be_bytes().to_vec())
+    };
+    while tree.merge_with(key, &stored_key, &[], merge)?.is_err() {}
+    index::finish_write(indexed)?;
+
+    previous.get().map_err(|()| {
+        Error::Unsupported(format!(
+            "the value of {:?} is not a counter, which must be \
+             a big-endian u64",
+            IVec::from(key)
+        ))
+    })
+}
@@ -1,9 +1,23 @@
-use std::ops::Deref;
+use std::{
+    ops::{Bound, Deref},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use crate::*;
+use crate::{
+    backup::{BackupReader, BackupWriter, Header},
+    *,
+};
 
 const DEFAULT_TREE_ID: &[u8] = b"__sled__default";
 
+/// The prefix of the names that sled uses itself, for the
+/// default tree, temporary trees, the hidden trees of other trees
+/// and the entries of the `Meta` that aren't trees at all.
+const RESERVED_PREFIX: &[u8] = b"__sled__";
+
+/// The number of entries written per batch by `Db::restore_from`.
+const RESTORE_BATCH_SIZE: usize = 1024;
+
 /// The `sled` embedded database! Implements
 /// `Deref<Target = sled::Tree>` to refer to
 /// a default keyspace / namespace / bucket.
@@ -13,6 +27,8 @@ pub struct Db {
     pub context: Context,
     pub(crate) default: Tree,
     tenants: Arc<RwLock<FastMap8<IVec, Tree>>>,
+    lost: Arc<Vec<LostRange>>,
+    sequences: Arc<sequence::Sequences>,
 }
 
 impl Deref for Db {
@@ -61,43 +77,166 @@ impl Db {
                 target_os = "ios",
             )
         ))]
-        {
-            let flusher_pagecache = context.pagecache.clone();
-            let flusher = context.flush_every_ms.map(move |fem| {
-                flusher::Flusher::new(
-                    "log flusher".to_owned(),
-                    flusher_pagecache,
-                    fem,
-                )
-            });
-            *context.flusher.lock() = flusher;
-        }
+        flusher::start(&context);
+
+        scrub::start_scrubber(&context);
 
         // create or open the default tree
         let guard = pin();
         let default =
-            meta::open_tree(&context, DEFAULT_TREE_ID.to_vec(), &guard)?;
+            meta::open_tree(&context, DEFAULT_TREE_ID.to_vec(), None, &guard)?;
+
+        // the default tree is salvaged before the meta is read
+        // below, so that the handle loaded from it sees the new root
+        let salvage = context.recovery_mode == RecoveryMode::Salvage;
+        let mut lost = vec![];
+        if salvage {
+            salvage::salvage_tree(&default.tree_id, &default, &mut lost)?;
+        }
+
+        // the default tree is a tenant like any other, sharing its
+        // handle with the `Db`
+        let mut tenants = FastMap8::default();
+        let _ = tenants.insert(IVec::from(DEFAULT_TREE_ID), default.clone());
+        let mut expiration_trees = vec![];
+        let mut history_trees = vec![];
+        let mut versions_trees = vec![];
+        let mut key_versions_trees = vec![];
+
+        for (id, root) in context.pagecache.get_meta(&guard).tenants() {
+            // the default tree was loaded above, index trees and
+            // the tree of the sequences are loaded by name when
+            // they are needed, and the entries that persist the
+            // names of merge operators and the creation times and
+            // lengths of trees are not trees at all
+            if &*id == DEFAULT_TREE_ID
+                || index::is_index_tree_name(&id)
+                || sequence::is_sequences_tree_name(&id)
+                || merge_operators::is_meta_key(&id)
+                || tree_stats::is_meta_key(&id)
+            {
+                continue;
+            }
+            let tree = meta::load_tree(&context, id.clone(), root, &guard)?;
+            if salvage {
+                salvage::salvage_tree(&id, &tree, &mut lost)?;
+            }
+            if expiration::is_expiration_tree_name(&id) {
+                expiration_trees.push(tree);
+                continue;
+            }
+            if history::is_history_tree_name(&id) {
+                history_trees.push(tree);
+                continue;
+            }
+            if versions::is_versions_tree_name(&id) {
+                versions_trees.push(tree);
+                continue;
+            }
+            if key_version::is_key_versions_tree_name(&id) {
+                key_versions_trees.push(tree);
+                continue;
+            }
+            assert!(tenants.insert(id, tree).is_none());
+        }
+
+        // deadlines for expiring keys live in hidden companion
+        // trees that are attached to their parents rather
+        // than being exposed as tenants
+        for expirations in expiration_trees {
+            let parent_name =
+                expiration::parent_tree_name(&expirations.tree_id).unwrap();
+            if let Some(parent) = tenants.get(parent_name) {
+                expiration::attach(parent, expirations);
+            }
+        }
+
+        // as are the events that trees retain, which are
+        // discarded if they are no longer retained
+        let mut stale_companions = vec![];
+        for history in history_trees {
+            if context.event_history.is_none() {
+                stale_companions.push(history);
+                continue;
+            }
+            let parent_name =
+                history::parent_tree_name(&history.tree_id).unwrap();
+            if let Some(parent) = tenants.get(parent_name) {
+                history::attach(parent, history)?;
+            }
+        }
+        if context.event_history.is_some() {
+            for tree in tenants.values() {
+                if !is_temporary_tree_name(&tree.tree_id) {
+                    history::open(tree)?;
+                }
+            }
+        }
+
+        // and the versions that they retain
+        for versions in versions_trees {
+            if context.version_retention_ms.is_none() {
+                stale_companions.push(versions);
+                continue;
+            }
+            let parent_name =
+                versions::parent_tree_name(&versions.tree_id).unwrap();
+            if let Some(parent) = tenants.get(parent_name) {
+                versions::attach(parent, versions);
+            }
+        }
+        if context.version_retention_ms.is_some() {
+            for tree in tenants.values() {
+                if !is_temporary_tree_name(&tree.tree_id) {
+                    versions::open(tree)?;
+                }
+            }
+        }
+
+        // and the versions of their keys
+        for key_versions in key_versions_trees {
+            let parent_name =
+                key_version::parent_tree_name(&key_versions.tree_id).unwrap();
+            if let Some(parent) = tenants.get(parent_name) {
+                key_version::attach(parent, key_versions);
+            }
+        }
+
+        // temporary trees only last as long as the process that
+        // opened them, so they are dropped along with their
+        // companions, or hidden from readers
+        let temporary: Vec<IVec> = tenants
+            .keys()
+            .filter(|name| is_temporary_tree_name(name))
+            .cloned()
+            .collect();
+        if context.read_only {
+            for name in &temporary {
+                let _ = tenants.remove(name);
+            }
+        }
 
         let ret = Self {
             context: context.clone(),
             default,
-            tenants: Arc::new(RwLock::new(FastMap8::default())),
+            tenants: Arc::new(RwLock::new(tenants)),
+            lost: Arc::new(lost),
+            sequences: Arc::new(sequence::Sequences::default()),
         };
 
-        let mut tenants = ret.tenants.write();
-
-        for (id, root) in context.pagecache.get_meta(&guard).tenants() {
-            let tree = Tree(Arc::new(TreeInner {
-                tree_id: id.clone(),
-                subscribers: Subscribers::default(),
-                context: context.clone(),
-                root: AtomicU64::new(root),
-                merge_operator: RwLock::new(None),
-            }));
-            assert!(tenants.insert(id, tree).is_none());
+        if !context.read_only {
+            for history in stale_companions {
+                let chain = ret.detach_tree(&history)?;
+                ret.gc_pages(chain)?;
+            }
+            for name in temporary {
+                let _ = ret.drop_tree(name)?;
+            }
         }
 
-        drop(tenants);
+        for tree in ret.tenants.read().values() {
+            tree.recover_len(&guard)?;
+        }
 
         #[cfg(feature = "event_log")]
         {
@@ -112,8 +251,265 @@ impl Db {
 
     /// Open or create a new disk-backed Tree with its own keyspace,
     /// accessible from the `Db` via the provided identifier.
+    ///
+    /// Returns `Error::Unsupported` if the Tree uses a merge
+    /// operator that has not been registered with
+    /// `Db::register_merge_operator`, or if the name starts with
+    /// `__sled__` and isn't one of `Db::tree_names`, because
+    /// those names are reserved for sled itself.
     pub fn open_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<Tree> {
-        let name_ref = name.as_ref();
+        self.open_tree_inner(name.as_ref(), None)
+    }
+
+    /// Open or create a temporary Tree for scratch space, whose
+    /// contents don't outlive the process: it's dropped the next
+    /// time the `Db` is opened, and isn't visible to the readers
+    /// of `Config::read_only`. Its name is `name` behind a prefix
+    /// that keeps it apart from the trees of `open_tree`, which
+    /// is what `Tree::name` returns and `Db::tree_names` lists,
+    /// and what it can be dropped with before then.
+    ///
+    /// Its writes go to the log like those of any other tree, so
+    /// that it can grow beyond the cache, but it never retains
+    /// the history or the versions of its keys, and its space is
+    /// reclaimed once it has been dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let scratch = db.open_temporary_tree("join_spill")?;
+    /// scratch.insert("k", "v")?;
+    ///
+    /// assert!(db.tree_names().contains(&scratch.name()));
+    /// assert!(db.open_tree("join_spill")?.is_empty());
+    /// assert!(db.drop_tree(scratch.name())?);
+    /// # Ok(()) }
+    /// ```
+    pub fn open_temporary_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<Tree> {
+        if self.context.read_only {
+            return Err(Error::Unsupported(
+                "temporary trees can't be opened in read-only mode".into(),
+            ));
+        }
+        self.tenant(&temporary_tree_name(name.as_ref()), None)
+    }
+
+    // opens a tree by a name that `Db::tree_names` returned when
+    // a backup, an export or a dump was written, which is opened
+    // as a temporary tree again if it was one
+    pub(crate) fn open_listed_tree(&self, name: &[u8]) -> Result<Tree> {
+        if is_temporary_tree_name(name) {
+            self.tenant(name, None)
+        } else {
+            self.open_tree(name)
+        }
+    }
+
+    /// Returns a handle to the namespace `name`, whose
+    /// `open_tree`, `tree_names` and `drop_tree` are scoped to
+    /// the trees of the namespace, and which can have namespaces
+    /// nested in it. A tree `t` of the namespace `ns` is a tree
+    /// named `ns/t` of the `Db`, so the names of namespaces
+    /// shouldn't be used as the names of other trees.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let tenant = db.open_namespace("tenant_42")?;
+    /// tenant.open_tree("orders")?.insert("k", "v")?;
+    /// tenant.open_namespace("eu")?.open_tree("orders")?;
+    ///
+    /// assert_eq!(tenant.tree_names(), vec!["eu/orders", "orders"]);
+    /// assert!(db.tree_names().contains(&"tenant_42/orders".into()));
+    ///
+    /// assert_eq!(db.drop_namespace("tenant_42")?, 2);
+    /// assert!(tenant.tree_names().is_empty());
+    /// # Ok(()) }
+    /// ```
+    pub fn open_namespace<V: AsRef<[u8]>>(&self, name: V) -> Result<Namespace> {
+        namespace::open(self, &[], name.as_ref())
+    }
+
+    /// Drops every tree of the namespace `name`, including the
+    /// trees of its nested namespaces, returning how many were
+    /// dropped. The trees are dropped one at a time like with
+    /// `Db::drop_tree`, so a crash may leave some of them.
+    pub fn drop_namespace<V: AsRef<[u8]>>(&self, name: V) -> Result<usize> {
+        namespace::drop_nested(self, &[], name.as_ref())
+    }
+
+    /// Open or create a new disk-backed Tree like `open_tree`,
+    /// with options that apply only to it. The options are fixed
+    /// once the Tree has been created, so an error is returned if
+    /// an existing Tree was created with different ones. The
+    /// merge operator is persisted like with
+    /// `Tree::use_merge_operator`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use sled::{CachePriority, Codec, TreeConfig};
+    ///
+    /// let db = sled::Config::new().temporary(true).open()?;
+    ///
+    /// let hot = db.open_tree_with(
+    ///     "index",
+    ///     TreeConfig {
+    ///         compression: Codec::None,
+    ///         cache_priority: CachePriority::High,
+    ///         ..TreeConfig::default()
+    ///     },
+    /// )?;
+    /// hot.insert("k", "v")?;
+    /// # Ok(()) }
+    /// ```
+    pub fn open_tree_with<V: AsRef<[u8]>>(
+        &self,
+        name: V,
+        tree_config: TreeConfig,
+    ) -> Result<Tree> {
+        let codec = tree_config.compression;
+        if let Codec::Zstd(level) = codec {
+            if !cfg!(feature = "compression") {
+                return Err(Error::Unsupported(
+                    "zstd compression requires the compression feature".into(),
+                ));
+            }
+            if !(1..=22).contains(&level) {
+                return Err(Error::Unsupported(
+                    "zstd compression level must be between 1 and 22".into(),
+                ));
+            }
+        }
+
+        let tree = self.open_tree_inner(name.as_ref(), Some(tree_config))?;
+
+        if tree.codec()?.0 != Some(codec) {
+            return Err(Error::Unsupported(format!(
+                "tree was created with a codec other than {:?}",
+                codec
+            )));
+        }
+
+        if tree.order != tree_config.order {
+            return Err(Error::Unsupported(format!(
+                "tree was created with a key order other than {:?}",
+                tree_config.order
+            )));
+        }
+
+        let (cache_priority, expected_value_size) = tree.tree_hints()?;
+        if cache_priority != tree_config.cache_priority {
+            return Err(Error::Unsupported(format!(
+                "tree was created with a cache priority other than {:?}",
+                tree_config.cache_priority
+            )));
+        }
+
+        let rounded = tree_config
+            .expected_value_size
+            .map(|size| size.max(1).next_power_of_two());
+        if expected_value_size != rounded {
+            return Err(Error::Unsupported(format!(
+                "tree was created with an expected value size other \
+                 than {:?}",
+                tree_config.expected_value_size
+            )));
+        }
+
+        if let Some(merge_operator) = tree_config.merge_operator {
+            tree.use_merge_operator(merge_operator)?;
+        }
+
+        Ok(tree)
+    }
+
+    /// Registers a merge operator under `name`, which trees can
+    /// then use with `Tree::use_merge_operator`. The names of the
+    /// operators that trees use are persisted, so operators need
+    /// to be registered every time the `Db` is opened, before the
+    /// trees that use them are opened. Registering an operator
+    /// with the name of an existing one replaces it, including
+    /// for trees that are already open.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// db.register_merge_operator("replace", |_k, _old, merged| {
+    ///     Some(merged.to_vec())
+    /// });
+    /// db.open_tree("latest")?.use_merge_operator("replace")?;
+    /// # Ok(()) }
+    /// ```
+    pub fn register_merge_operator(
+        &self,
+        name: &str,
+        merge_operator: impl MergeOperator + 'static,
+    ) {
+        merge_operators::register(
+            &self.context,
+            name,
+            Arc::new(merge_operator),
+        );
+
+        let mut trees: Vec<Tree> =
+            self.tenants.read().values().cloned().collect();
+        trees.push(self.default.clone());
+
+        for tree in trees {
+            // a name that can't be read fails to be read
+            // again when its tree is opened
+            let persisted = merge_operators::persisted_name(&tree);
+            if persisted.ok().flatten().as_deref() == Some(name) {
+                let _ = merge_operators::attach(&tree);
+            }
+        }
+    }
+
+    fn open_tree_inner(
+        &self,
+        name_ref: &[u8],
+        tree_config: Option<TreeConfig>,
+    ) -> Result<Tree> {
+        // the trees that sled uses itself can be opened, but
+        // no other tree can be created with a reserved name
+        if is_reserved_name(name_ref)
+            && !self.tenants.read().contains_key(name_ref)
+        {
+            return Err(reserved_name_error(name_ref));
+        }
+
+        let tree = self.tenant(name_ref, tree_config)?;
+
+        // a tree that uses a named merge operator must not be
+        // merged into with any other one
+        merge_operators::attach(&tree)?;
+
+        if self.context.event_history.is_some() {
+            history::open(&tree)?;
+        }
+        if self.context.version_retention_ms.is_some() {
+            versions::open(&tree)?;
+        }
+
+        Ok(tree)
+    }
+
+    fn tenant(
+        &self,
+        name_ref: &[u8],
+        tree_config: Option<TreeConfig>,
+    ) -> Result<Tree> {
         let tenants = self.tenants.read();
         if let Some(tree) = tenants.get(name_ref) {
             return Ok(tree.clone());
@@ -130,7 +526,12 @@ impl Db {
             return Ok(tree.clone());
         }
 
-        let tree = meta::open_tree(&self.context, name_ref.to_vec(), &guard)?;
+        let tree = meta::open_tree(
+            &self.context,
+            name_ref.to_vec(),
+            tree_config,
+            &guard,
+        )?;
 
         assert!(tenants.insert(name_ref.into(), tree.clone()).is_none());
 
@@ -138,6 +539,10 @@ impl Db {
     }
 
     /// Remove a disk-backed collection. This is blocking and fairly slow.
+    ///
+    /// Returns `Error::Unsupported` for the default tree and for
+    /// other names that start with `__sled__`, apart from those
+    /// of temporary trees.
     pub fn drop_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<bool> {
         let name_ref = name.as_ref();
         if name_ref == DEFAULT_TREE_ID {
@@ -145,6 +550,9 @@ impl Db {
                 "cannot remove the core structures".into(),
             ));
         }
+        if is_reserved_name(name_ref) && !is_temporary_tree_name(name_ref) {
+            return Err(reserved_name_error(name_ref));
+        }
         trace!("dropping tree {:?}", name_ref,);
 
         let mut tenants = self.tenants.write();
@@ -155,311 +563,1854 @@ impl Db {
             return Ok(false);
         };
 
-        // signal to all threads that this tree is no longer valid
-        tree.root.store(u64::max_value(), SeqCst);
-
-        let guard = pin();
-
-        let mut root_id =
-            Some(self.context.pagecache.meta_pid_for_name(name_ref, &guard)?);
-
-        let mut leftmost_chain: Vec<PageId> = vec![root_id.unwrap()];
-        let mut cursor = root_id.unwrap();
-        while let Some(view) = self.view_for_pid(cursor, &guard)? {
-            if view.is_index {
-                let leftmost_child = view.iter_index_pids().next().unwrap();
-                leftmost_chain.push(leftmost_child);
-                cursor = leftmost_child;
-            } else {
-                break;
-            }
-        }
+        let leftmost_chain = self.detach_tree(&tree)?;
 
-        loop {
-            let res = self
-                .context
-                .pagecache
-                .cas_root_in_meta(name_ref, root_id, None, &guard)?;
+        // the deadlines of any expiring keys are stored
+        // in a companion tree that is dropped along with
+        // its parent
+        let expirations = tree.expirations.write().take();
+        let expiration_chain = if let Some(expirations) = expirations {
+            Some(self.detach_tree(&expirations)?)
+        } else {
+            None
+        };
+        let history = tree.history.write().take();
+        let history_chain = if let Some(history) = history {
+            Some(self.detach_tree(&history.tree)?)
+        } else {
+            None
+        };
+        let versions = tree.versions.write().take();
+        let versions_chain = if let Some(companion) = versions {
+            Some(self.detach_tree(&companion)?)
+        } else {
+            None
+        };
+        let key_versions = tree.key_versions.write().take();
+        let key_versions_chain = if let Some(companion) = key_versions {
+            Some(self.detach_tree(&companion.tree)?)
+        } else {
+            None
+        };
 
-            if let Err(actual_root) = res {
-                root_id = actual_root;
-            } else {
-                break;
-            }
+        // as are its indexes and the name of its merge operator
+        merge_operators::forget(&tree)?;
+        let mut index_chains = vec![];
+        for index_tree in index::take_index_trees(&tree, &pin())? {
+            index_chains.push(self.detach_tree(&index_tree)?);
         }
 
         // drop writer lock and asynchronously
         drop(tenants);
 
-        guard.flush();
-
-        drop(guard);
-
         self.gc_pages(leftmost_chain)?;
 
-        Ok(true)
-    }
+        if let Some(expiration_chain) = expiration_chain {
+            self.gc_pages(expiration_chain)?;
+        }
 
-    // Remove all pages for this tree from the underlying
-    // PageCache. This will leave orphans behind if
-    // the tree crashes during gc.
-    fn gc_pages(&self, mut leftmost_chain: Vec<PageId>) -> Result<()> {
-        let mut guard = pin();
-
-        let mut ops = 0;
-        while let Some(mut pid) = leftmost_chain.pop() {
-            loop {
-                ops += 1;
-                if ops % 64 == 0 {
-                    // we re-pin here to avoid memory blow-ups during
-                    // long-running tree removals.
-                    guard = pin();
-                }
-                let cursor_view =
-                    if let Some(view) = self.view_for_pid(pid, &guard)? {
-                        view
-                    } else {
-                        trace!(
-                            "encountered Free node pid {} while GC'ing tree",
-                            pid
-                        );
-                        break;
-                    };
-
-                let ret = self.context.pagecache.free(
-                    pid,
-                    cursor_view.node_view.0,
-                    &guard,
-                )?;
-
-                if ret.is_ok() {
-                    let next_pid = if let Some(next_pid) = cursor_view.next {
-                        next_pid
-                    } else {
-                        break;
-                    };
-                    assert_ne!(pid, next_pid.get());
-                    pid = next_pid.get();
-                }
-            }
+        if let Some(history_chain) = history_chain {
+            self.gc_pages(history_chain)?;
         }
 
-        Ok(())
-    }
+        if let Some(chain) = versions_chain {
+            self.gc_pages(chain)?;
+        }
 
-    /// Returns the trees names saved in this Db.
-    pub fn tree_names(&self) -> Vec<IVec> {
-        let tenants = self.tenants.read();
-        tenants.iter().map(|(name, _)| name.clone()).collect()
-    }
+        if let Some(chain) = key_versions_chain {
+            self.gc_pages(chain)?;
+        }
 
-    /// Returns `true` if the database was
-    /// recovered from a previous process.
-    /// Note that database state is only
-    /// guaranteed to be present up to the
-    /// last call to `flush`! Otherwise state
-    /// is synced to disk periodically if the
-    /// `sync_every_ms` configuration option
-    /// is set to `Some(number_of_ms_between_syncs)`
-    /// or if the IO buffer gets filled to
-    /// capacity before being rotated.
-    pub fn was_recovered(&self) -> bool {
-        self.context.was_recovered()
-    }
+        for index_chain in index_chains {
+            self.gc_pages(index_chain)?;
+        }
 
-    /// Generate a monotonic ID. Not guaranteed to be
-    /// contiguous. Written to disk every `idgen_persist_interval`
-    /// operations, followed by a blocking flush. During recovery, we
-    /// take the last recovered generated ID and add 2x
-    /// the `idgen_persist_interval` to it. While persisting, if the
-    /// previous persisted counter wasn't synced to disk yet, we will do
-    /// a blocking flush to fsync the latest counter, ensuring
-    /// that we will never give out the same counter twice.
-    pub fn generate_id(&self) -> Result<u64> {
-        self.context.generate_id()
+        Ok(true)
     }
 
-    /// A database export method for all collections in the `Db`,
-    /// for use in sled version upgrades. Can be used in combination
-    /// with the `import` method below on a database running a later
-    /// version.
+    /// Atomically renames a tree, along with its indexes and the
+    /// hidden trees that hold its expiring keys and its retained
+    /// history and versions. Returns `false` if there is no tree
+    /// named `old`. If a tree named `new` exists, it is dropped and
+    /// replaced when `overwrite` is set, and `Error::Unsupported`
+    /// is returned otherwise.
     ///
-    /// # Panics
-    ///
-    /// Panics if any IO problems occur while trying
-    /// to perform the export.
+    /// The tree has to be opened again under its new name: like
+    /// the handles of a dropped tree, its old handles and their
+    /// subscribers return `Error::CollectionNotFound` from then on,
+    /// and a merge operator that was set with
+    /// `Tree::set_merge_operator` has to be set again. A tree with
+    /// indexes that were created since the `Db` was opened, or
+    /// whose values are separated by `Config::value_log_threshold`,
+    /// can't be renamed, and neither can the trees whose names
+    /// start with `__sled__`, which are reserved for sled itself.
     ///
     /// # Examples
     ///
-    /// If you want to migrate from one version of sled
-    /// to another, you need to pull in both versions
-    /// by using version renaming:
-    ///
-    /// `Cargo.toml`:
-    ///
-    /// ```toml
-    /// [dependencies]
-    /// sled = "0.32"
-    /// old_sled = { version = "0.31", package = "sled" }
-    /// ```
-    ///
-    /// and in your code, remember that old versions of
-    /// sled might have a different way to open them
-    /// than the current `sled::open` method:
-    ///
     /// ```
-    /// # use sled as old_sled;
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
-    /// let old = old_sled::open("my_old__db")?;
-    ///
-    /// // may be a different version of sled,
-    /// // the export type is version agnostic.
-    /// let new = sled::open("my_new__db")?;
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let staging = db.open_tree("staging")?;
+    /// staging.insert(b"k", b"v")?;
+    /// db.open_tree("live")?;
     ///
-    /// let export = old.export();
-    /// new.import(export);
+    /// assert!(db.rename_tree("staging", "live", false).is_err());
+    /// assert_eq!(db.rename_tree("staging", "live", true), Ok(true));
     ///
-    /// assert_eq!(old.checksum()?, new.checksum()?);
-    /// # drop(old);
-    /// # drop(new);
-    /// # std::fs::remove_file("my_old__db");
+    /// let live = db.open_tree("live")?;
+    /// assert_eq!(live.get(b"k")?, Some(sled::IVec::from(b"v")));
+    /// assert!(!db.tree_names().contains(&sled::IVec::from("staging")));
+    /// # Ok(()) }
+    /// ```
+    pub fn rename_tree<O, N>(
+        &self,
+        old: O,
+        new: N,
+        overwrite: bool,
+    ) -> Result<bool>
+    where
+        O: AsRef<[u8]>,
+        N: AsRef<[u8]>,
+    {
+        let (old, new) = (old.as_ref(), new.as_ref());
+        if old == DEFAULT_TREE_ID || new == DEFAULT_TREE_ID {
+            return Err(Error::Unsupported(
+                "cannot rename the core structures".into(),
+            ));
+        }
+        for name in &[old, new] {
+            if is_reserved_name(name) {
+                return Err(reserved_name_error(name));
+            }
+        }
+        trace!("renaming tree {:?} to {:?}", old, new);
+
+        let mut tenants = self.tenants.write();
+
+        let tree = if let Some(tree) = tenants.get(old) {
+            tree.clone()
+        } else {
+            return Ok(false);
+        };
+        if old == new {
+            return Ok(true);
+        }
+        if tree.separates_values {
+            return Err(Error::Unsupported(
+                "the value log refers to trees by name, so trees can't \
+                 be renamed while Config::value_log_threshold is set"
+                    .into(),
+            ));
+        }
+        if index::is_indexed(&tree) {
+            return Err(Error::Unsupported(
+                "trees with created indexes can't be renamed".into(),
+            ));
+        }
+        let target = tenants.get(new).cloned();
+        if target.is_some() && !overwrite {
+            return Err(Error::Unsupported(format!(
+                "a tree named {:?} already exists",
+                new
+            )));
+        }
+
+        // no write may split a root that is being moved
+        let cc = concurrency_control::write();
+        let guard = pin();
+
+        let meta = self.context.pagecache.get_meta(&guard).tenants();
+        let mut removed = vec![];
+        let mut removed_chains = vec![];
+        for (name, root) in &meta {
+            if renamed_meta_key(name, new, new).is_none() {
+                continue;
+            }
+            if !merge_operators::is_meta_key(name)
+                && !tree_stats::is_meta_key(name)
+            {
+                removed_chains.push(self.leftmost_chain(*root, &guard)?);
+            }
+            removed.push(name.clone());
+        }
+        let moved: Vec<(IVec, IVec)> = meta
+            .keys()
+            .filter_map(|name| {
+                renamed_meta_key(name, old, new).map(|to| (name.clone(), to))
+            })
+            .collect();
+
+        if let Some(target) = &target {
+            let _ = index::take_index_trees(target, &guard)?;
+        }
+        let pids =
+            self.context.pagecache.rename_in_meta(&removed, &moved, &guard)?;
+
+        // signal to all threads that the old handles are no longer
+        // valid
+        for handle in target.iter().chain(Some(&tree)) {
+            handle.root.store(u64::max_value(), SeqCst);
+            for companion in companions(handle) {
+                companion.root.store(u64::max_value(), SeqCst);
+            }
+        }
+
+        let mut renamed = None;
+        let mut attached = vec![];
+        for ((_, to), pid) in moved.into_iter().zip(pids) {
+            let pid = if let Some(pid) = pid {
+                pid
+            } else {
+                continue;
+            };
+            if &*to == new {
+                renamed =
+                    Some(meta::load_tree(&self.context, to, pid, &guard)?);
+            } else if !merge_operators::is_meta_key(&to)
+                && !tree_stats::is_meta_key(&to)
+                && !index::is_index_tree_name(&to)
+            {
+                attached.push(meta::load_tree(&self.context, to, pid, &guard)?);
+            }
+        }
+        let renamed = renamed.ok_or_else(|| {
+            Error::ReportableBug("the renamed tree has no root".into())
+        })?;
+
+        for companion in attached {
+            if expiration::is_expiration_tree_name(&companion.tree_id) {
+                expiration::attach(&renamed, companion);
+            } else if history::is_history_tree_name(&companion.tree_id) {
+                history::attach(&renamed, companion)?;
+            } else if versions::is_versions_tree_name(&companion.tree_id) {
+                versions::attach(&renamed, companion);
+            } else {
+                key_version::attach(&renamed, companion);
+            }
+        }
+        merge_operators::attach(&renamed)?;
+
+        // writes are blocked, so the count of the old handle is
+        // still exact
+        renamed.item_count.store(tree.item_count.load(Acquire), Release);
+
+        let _ = tenants.remove(old);
+        let _ = tenants.insert(new.into(), renamed);
+
+        drop(tenants);
+        drop(cc);
+        drop(guard);
+
+        for chain in removed_chains {
+            self.gc_pages(chain)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Copies the tree named `src` into a new tree named `dst`, as
+    /// of one point in time, returning `false` if there is no tree
+    /// named `src`. Returns `Error::Unsupported` if a tree named
+    /// `dst` already exists, or if `dst` starts with `__sled__`.
+    ///
+    /// The copy is read from a `Snapshot` of `src`, which keeps
+    /// being writable meanwhile, and is written like with
+    /// `Tree::bulk_load`, one node at a time and without logging
+    /// each key, so it takes about as long as reading `src`. It has
+    /// the compression and key order of `src`, and uses its merge
+    /// operator, but none of its indexes, and keys that expire in
+    /// `src` don't expire in the copy. If the `Db` retains the
+    /// history or versions of its trees, the copy's start with it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let base = db.open_tree("base")?;
+    /// base.insert(b"k", b"v")?;
+    ///
+    /// assert_eq!(db.copy_tree("base", "sandbox"), Ok(true));
+    /// assert!(db.copy_tree("base", "sandbox").is_err());
+    ///
+    /// let sandbox = db.open_tree("sandbox")?;
+    /// sandbox.insert(b"k", b"changed")?;
+    /// assert_eq!(base.get(b"k")?, Some(sled::IVec::from(b"v")));
+    /// # Ok(()) }
+    /// ```
+    pub fn copy_tree<S, D>(&self, src: S, dst: D) -> Result<bool>
+    where
+        S: AsRef<[u8]>,
+        D: AsRef<[u8]>,
+    {
+        let (src, dst) = (src.as_ref(), dst.as_ref());
+        trace!("copying tree {:?} to {:?}", src, dst);
+        if is_reserved_name(dst) {
+            return Err(reserved_name_error(dst));
+        }
+
+        let tree = if let Some(tree) = self.tenants.read().get(src) {
+            tree.clone()
+        } else {
+            return Ok(false);
+        };
+        if self.tenants.read().contains_key(dst) {
+            return Err(Error::Unsupported(format!(
+                "a tree named {:?} already exists",
+                dst
+            )));
+        }
+
+        let snapshot = self.snapshot();
+
+        // trees without a codec use the one of the Db
+        let tree_config = tree.tree_config()?;
+
+        // the history and versions are opened once the copy is
+        // loaded, because bulk loads are only supported without them
+        let copy = self.tenant(dst, tree_config)?;
+        if let Err(e) = copy.try_bulk_load(snapshot.iter(&tree)) {
+            let _ = self.drop_tree(dst);
+            return Err(e);
+        }
+
+        if let Some(name) = merge_operators::persisted_name(&tree)? {
+            merge_operators::use_operator(&copy, &name)?;
+        }
+        if self.context.event_history.is_some() {
+            history::open(&copy)?;
+        }
+        if self.context.version_retention_ms.is_some() {
+            versions::open(&copy)?;
+        }
+
+        Ok(true)
+    }
+
+    // Unlinks a tree's root from the meta page, returning
+    // the chain of leftmost pages that can be used to
+    // find all of its pages for gc.
+    fn detach_tree(&self, tree: &Tree) -> Result<Vec<PageId>> {
+        let name_ref = &*tree.tree_id;
+
+        // signal to all threads that this tree is no longer valid
+        tree.root.store(u64::max_value(), SeqCst);
+
+        let guard = pin();
+
+        let mut root_id =
+            Some(self.context.pagecache.meta_pid_for_name(name_ref, &guard)?);
+
+        let leftmost_chain = self.leftmost_chain(root_id.unwrap(), &guard)?;
+
+        loop {
+            let res = self
+                .context
+                .pagecache
+                .cas_root_in_meta(name_ref, root_id, None, &guard)?;
+
+            if let Err(actual_root) = res {
+                root_id = actual_root;
+            } else {
+                break;
+            }
+        }
+        tree_stats::forget_created(&self.context, name_ref, &guard)?;
+        tree_stats::record_len(&self.context, name_ref, None, &guard)?;
+
+        guard.flush();
+
+        Ok(leftmost_chain)
+    }
+
+    // returns the chain of leftmost pages of the tree with `root`
+    fn leftmost_chain(
+        &self,
+        root: PageId,
+        guard: &Guard,
+    ) -> Result<Vec<PageId>> {
+        let mut leftmost_chain: Vec<PageId> = vec![root];
+        let mut cursor = root;
+        while let Some(view) = self.view_for_pid(cursor, guard)? {
+            if view.is_index {
+                let leftmost_child = view.iter_index_pids().next().unwrap();
+                leftmost_chain.push(leftmost_child);
+                cursor = leftmost_child;
+            } else {
+                break;
+            }
+        }
+        Ok(leftmost_chain)
+    }
+
+    /// Returns the trees names saved in this Db. See
+    /// `Db::tree_stats` for more about each of them.
+    pub fn tree_names(&self) -> Vec<IVec> {
+        let tenants = self.tenants.read();
+        tenants.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// Returns `true` if the database was
+    /// recovered from a previous process.
+    /// Note that database state is only
+    /// guaranteed to be present up to the
+    /// last call to `flush`! Otherwise state
+    /// is synced to disk periodically if the
+    /// `sync_every_ms` configuration option
+    /// is set to `Some(number_of_ms_between_syncs)`
+    /// or if the IO buffer gets filled to
+    /// capacity before being rotated.
+    pub fn was_recovered(&self) -> bool {
+        self.context.was_recovered()
+    }
+
+    /// Reopens a database that was opened with
+    /// `Config::read_only`, returning a new `Db` that
+    /// reflects everything that the writing process has
+    /// flushed since this one was opened. This `Db` keeps
+    /// serving the state that it was opened with, and
+    /// should be dropped once it's no longer needed,
+    /// because the writer won't reuse any space that it
+    /// may still refer to. Readers must also refresh
+    /// after the writer restarts.
+    ///
+    /// Returns `Error::Unsupported` if this `Db` is not
+    /// read-only.
+    pub fn refresh(&self) -> Result<Db> {
+        if !self.context.read_only {
+            return Err(Error::Unsupported(
+                "only read-only databases can be refreshed".into(),
+            ));
+        }
+        Config::clone(&self.context).open()
+    }
+
+    /// Generate a monotonic ID. Not guaranteed to be
+    /// contiguous. Written to disk every `idgen_persist_interval`
+    /// operations, followed by a blocking flush. During recovery, we
+    /// take the last recovered generated ID and add 2x
+    /// the `idgen_persist_interval` to it. While persisting, if the
+    /// previous persisted counter wasn't synced to disk yet, we will do
+    /// a blocking flush to fsync the latest counter, ensuring
+    /// that we will never give out the same counter twice.
+    /// See `Db::sequence` for independent sequences of IDs.
+    pub fn generate_id(&self) -> Result<u64> {
+        self.context.generate_id()
+    }
+
+    /// Returns the persistent sequence of IDs named `name`, which
+    /// starts at 0 and is independent of `generate_id` and of the
+    /// other sequences. Like `generate_id`, a sequence reserves
+    /// its IDs in blocks, see `Sequence::set_block_size`, so
+    /// that it only writes to the log once per block.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let orders = db.sequence("order_id")?;
+    /// orders.set_block_size(100)?;
+    /// assert_eq!(orders.next_id()?, 0);
+    /// assert_eq!(orders.next_id()?, 1);
+    ///
+    /// let invoices = db.sequence("invoice_id")?;
+    /// invoices.set(1000)?;
+    /// assert_eq!(invoices.next_id()?, 1000);
+    /// assert_eq!(db.sequence("order_id")?.current(), 2);
+    /// # Ok(()) }
+    /// ```
+    pub fn sequence<V: AsRef<[u8]>>(&self, name: V) -> Result<Sequence> {
+        sequence::open(&self.context, &self.sequences, name.as_ref())
+    }
+
+    /// Atomically applies a batch to each of several trees of this
+    /// `Db`, like a transaction that only writes, for when the
+    /// trees are only known at runtime. The batches are recovered
+    /// together after a crash, or not at all, and subscribers
+    /// receive a single `Event` with all of them. Several batches
+    /// for the same tree are applied in order.
+    ///
+    /// Returns `Error::Unsupported` if a tree belongs to another
+    /// `Db`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let names = ["orders", "invoices"];
+    /// let trees = names
+    ///     .iter()
+    ///     .map(|name| db.open_tree(name))
+    ///     .collect::<sled::Result<Vec<_>>>()?;
+    ///
+    /// let batches = trees.iter().map(|tree| {
+    ///     let mut batch = sled::Batch::default();
+    ///     batch.insert("42", "pending");
+    ///     (tree, batch)
+    /// });
+    /// db.apply_batches(batches)?;
+    ///
+    /// assert!(trees[1].contains_key("42")?);
+    /// # Ok(()) }
+    /// ```
+    pub fn apply_batches<'a, I>(&self, batches: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (&'a Tree, Batch)>,
+    {
+        let encoded = transaction::encode_batches(&self.context, batches)?;
+        if encoded.is_empty() {
+            return Ok(());
+        }
+
+        let _cc = concurrency_control::write();
+        let guard = pin();
+        transaction::apply_batches(&encoded, &guard)
+    }
+
+    /// Begins a transaction that is driven by calling its methods,
+    /// rather than by a closure like `Tree::transaction`, so that
+    /// it can span `await` points and IO to other systems, which
+    /// would be repeated if a closure was retried. Nothing is
+    /// locked until `Txn::commit`, see `Txn`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let stock = db.open_tree("stock")?;
+    /// let orders = db.open_tree("orders")?;
+    /// stock.insert("widget", &[3])?;
+    ///
+    /// let mut txn = db.begin_transaction();
+    /// let left = txn.get(&stock, "widget")?.unwrap()[0];
+    /// // ... ask another system whether the order may be placed ...
+    /// txn.insert(&stock, "widget", &[left - 1]);
+    /// txn.insert(&orders, "order-1", "widget");
+    /// txn.commit()?;
+    ///
+    /// assert_eq!(stock.get("widget")?, Some(sled::IVec::from(&[2])));
+    /// # Ok(()) }
+    /// ```
+    pub fn begin_transaction(&self) -> transaction::Txn {
+        transaction::Txn::new(self.context.clone())
+    }
+
+    /// Begins a transaction like `begin_transaction`, after locking
+    /// the given keys of their trees until it is committed or
+    /// dropped. This is pessimistic rather than optimistic: the
+    /// transactions that lock a key wait for each other instead of
+    /// conflicting at commit and being retried, which serializes
+    /// the transactions of a few hot keys cheaply. The keys are
+    /// only locked against other locked transactions, and the
+    /// reads of the transaction are still checked at commit, so
+    /// any other write to them still makes it conflict.
+    ///
+    /// Every key that the transaction will read has to be given,
+    /// because a transaction can't lock more keys later, which is
+    /// what keeps transactions that lock overlapping keys from
+    /// deadlocking.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let mut txn = db.begin_locked_transaction(vec![(&*db, "hot")]);
+    /// let hits = txn.get(&db, "hot")?.map_or(0, |v| v[0]);
+    /// txn.insert(&db, "hot", &[hits + 1]);
+    /// txn.commit()?;
+    /// # Ok(()) }
+    /// ```
+    pub fn begin_locked_transaction<'a, I, K>(
+        &self,
+        keys: I,
+    ) -> transaction::Txn
+    where
+        I: IntoIterator<Item = (&'a Tree, K)>,
+        K: AsRef<[u8]>,
+    {
+        let options = keys
+            .into_iter()
+            .fold(transaction::TxnOptions::new(), |options, (tree, key)| {
+                options.lock(tree, key)
+            });
+        // the keys are waited for without a timeout
+        transaction::Txn::with_options(self.context.clone(), options)
+            .expect("locks can only time out with a timeout")
+    }
+
+    /// Begins a transaction like `begin_transaction`, or like
+    /// `begin_locked_transaction` if the options lock keys, which
+    /// may then give up on the locks after `TxnOptions::timeout`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::time::Duration;
+    ///
+    /// use sled::transaction::{TransactionError, TxnOptions};
+    ///
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let options = TxnOptions::new()
+    ///     .lock(&db, "hot")
+    ///     .timeout(Duration::from_millis(10));
+    /// let held = db.begin_transaction_with(options.clone()).unwrap();
+    ///
+    /// // the same thread can't lock the key again until it is released
+    /// let again = db.begin_transaction_with(options.clone());
+    /// assert_eq!(again.unwrap_err(), TransactionError::Timeout);
+    /// drop(held);
+    /// assert!(db.begin_transaction_with(options).is_ok());
+    /// # Ok(()) }
+    /// ```
+    pub fn begin_transaction_with(
+        &self,
+        options: transaction::TxnOptions,
+    ) -> transaction::TransactionResult<transaction::Txn> {
+        transaction::Txn::with_options(self.context.clone(), options)
+    }
+
+    /// Takes a consistent, read-only view of every `Tree` of the
+    /// `Db`, which the writes that happen afterwards don't change,
+    /// see `Snapshot`. Writes that are in progress are waited for.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let accounts = db.open_tree("accounts")?;
+    /// accounts.insert("alice", &[10])?;
+    /// accounts.insert("bob", &[0])?;
+    ///
+    /// let snapshot = db.snapshot();
+    /// let mut transfer = sled::Batch::default();
+    /// transfer.insert("alice", &[5]);
+    /// transfer.insert("bob", &[5]);
+    /// accounts.apply_batch(transfer)?;
+    ///
+    /// let mut total = 0;
+    /// for res in snapshot.iter(&accounts) {
+    ///     total += res?.1[0];
+    /// }
+    /// assert_eq!(total, 10);
+    /// let bob = snapshot.get(&accounts, "bob")?;
+    /// assert_eq!(bob, Some(sled::IVec::from(&[0])));
+    /// # Ok(()) }
+    /// ```
+    pub fn snapshot(&self) -> snapshot::Snapshot {
+        snapshot::Snapshot::new(&self.context)
+    }
+
+    /// A database export method for all collections in the `Db`,
+    /// for use in sled version upgrades. Can be used in combination
+    /// with the `import` method below on a database running a later
+    /// version.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any IO problems occur while trying
+    /// to perform the export.
+    ///
+    /// # Examples
+    ///
+    /// If you want to migrate from one version of sled
+    /// to another, you need to pull in both versions
+    /// by using version renaming:
+    ///
+    /// `Cargo.toml`:
+    ///
+    /// ```toml
+    /// [dependencies]
+    /// sled = "0.32"
+    /// old_sled = { version = "0.31", package = "sled" }
+    /// ```
+    ///
+    /// and in your code, remember that old versions of
+    /// sled might have a different way to open them
+    /// than the current `sled::open` method:
+    ///
+    /// ```
+    /// # use sled as old_sled;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let old = old_sled::open("my_old__db")?;
+    ///
+    /// // may be a different version of sled,
+    /// // the export type is version agnostic.
+    /// let new = sled::open("my_new__db")?;
+    ///
+    /// let export = old.export();
+    /// new.import(export);
+    ///
+    /// assert_eq!(old.checksum()?, new.checksum()?);
+    /// # drop(old);
+    /// # drop(new);
+    /// # std::fs::remove_file("my_old__db");
     /// # std::fs::remove_file("my_new__db");
     /// # Ok(()) }
     /// ```
-    pub fn export(
+    pub fn export(
+        &self,
+    ) -> Vec<(CollectionType, CollectionName, impl Iterator<Item = Vec<Vec<u8>>>)>
+    {
+        let tenants = self.tenants.read();
+
+        let mut ret = vec![];
+
+        for (name, tree) in tenants.iter() {
+            ret.push((
+                b"tree".to_vec(),
+                name.to_vec(),
+                tree.iter().map(|kv_opt| {
+                    let kv = kv_opt.unwrap();
+                    vec![kv.0.to_vec(), kv.1.to_vec()]
+                }),
+            ));
+        }
+
+        ret
+    }
+
+    /// Imports the collections from a previous database.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any IO problems occur while trying
+    /// to perform the import.
+    ///
+    /// # Examples
+    ///
+    /// If you want to migrate from one version of sled
+    /// to another, you need to pull in both versions
+    /// by using version renaming:
+    ///
+    /// `Cargo.toml`:
+    ///
+    /// ```toml
+    /// [dependencies]
+    /// sled = "0.32"
+    /// old_sled = { version = "0.31", package = "sled" }
+    /// ```
+    ///
+    /// and in your code, remember that old versions of
+    /// sled might have a different way to open them
+    /// than the current `sled::open` method:
+    ///
+    /// ```
+    /// # use sled as old_sled;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let old = old_sled::open("my_old_db")?;
+    ///
+    /// // may be a different version of sled,
+    /// // the export type is version agnostic.
+    /// let new = sled::open("my_new_db")?;
+    ///
+    /// let export = old.export();
+    /// new.import(export);
+    ///
+    /// assert_eq!(old.checksum()?, new.checksum()?);
+    /// # drop(old);
+    /// # drop(new);
+    /// # std::fs::remove_file("my_old_db");
+    /// # std::fs::remove_file("my_new_db");
+    /// # Ok(()) }
+    /// ```
+    pub fn import(
+        &self,
+        export: Vec<(
+            CollectionType,
+            CollectionName,
+            impl Iterator<Item = Vec<Vec<u8>>>,
+        )>,
+    ) {
+        for (collection_type, collection_name, collection_iter) in export {
+            match collection_type {
+                ref t if t == b"tree" => {
+                    let tree = self
+                        .open_listed_tree(&collection_name)
+                        .expect("failed to open new tree during import");
+                    for mut kv in collection_iter {
+                        let v = kv
+                            .pop()
+                            .expect("failed to get value from tree export");
+                        let k = kv
+                            .pop()
+                            .expect("failed to get key from tree export");
+                        let old = tree.insert(k, v).expect(
+                            "failed to insert value during tree import",
+                        );
+                        assert!(
+                            old.is_none(),
+                            "import is overwriting existing data"
+                        );
+                    }
+                }
+                other => panic!("unknown collection type {:?}", other),
+            }
+        }
+    }
+
+    /// Writes a consistent backup of every `Tree` in this `Db`
+    /// to the provided writer as a single stream, which may be
+    /// restored using `Db::restore_from`. Unlike `Db::export`,
+    /// the contents are written as they are read, so memory
+    /// usage does not grow with the size of the database.
+    ///
+    /// Returns the log sequence number (LSN) that the backup is
+    /// consistent with. LSNs increase monotonically and are made
+    /// durable before this method returns, so the LSN may later
+    /// be passed to `Db::backup_since` to write an incremental
+    /// backup of only what has changed since this one.
+    ///
+    /// The backup is read from a `Db::snapshot`, so writes go on
+    /// while it is written, and the values that they replace are
+    /// kept in memory until it is done. To back up a busy
+    /// database without keeping them, first create a copy using
+    /// `Db::checkpoint` and back up that copy instead. Copies
+    /// made by `Db::checkpoint` share LSNs with the original.
+    ///
+    /// Keys that have expired are skipped, and the deadlines
+    /// of keys inserted with `Tree::insert_with_ttl` are
+    /// not preserved.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let config = sled::Config::new().temporary(true);
+    /// let db = config.open()?;
+    /// db.open_tree("tree")?.insert("k", "v")?;
+    ///
+    /// let mut full = vec![];
+    /// let lsn = db.backup_to(&mut full)?;
+    ///
+    /// db.insert("k2", "v2")?;
+    /// let mut incremental = vec![];
+    /// db.backup_since(lsn, &mut incremental)?;
+    ///
+    /// let restored = sled::Config::new().temporary(true).open()?;
+    /// restored.restore_from(&*full)?;
+    /// restored.restore_from(&*incremental)?;
+    ///
+    /// assert_eq!(db.checksum()?, restored.checksum()?);
+    /// # Ok(()) }
+    /// ```
+    pub fn backup_to<W: Write>(&self, writer: W) -> Result<u64> {
+        self.backup(writer, None)
+    }
+
+    /// Writes an incremental backup containing the changes made
+    /// since the backup that returned the provided LSN, see
+    /// `Db::backup_to`. Returns the LSN of this backup, which
+    /// may be used as the base of the next incremental backup.
+    ///
+    /// Changes are tracked for each leaf node of each `Tree`,
+    /// so the backup contains the entire contents of every leaf
+    /// that was written to since the provided LSN, rather than
+    /// only the keys that changed. It is restored by applying
+    /// it with `Db::restore_from` on top of the backups it
+    /// builds upon, in the order that they were written.
+    ///
+    /// Returns `Error::Unsupported` if the LSN is newer than
+    /// any that has been written by this `Db`.
+    pub fn backup_since<W: Write>(&self, lsn: u64, writer: W) -> Result<u64> {
+        let since = Lsn::try_from(lsn).map_err(|_| {
+            Error::Unsupported(format!("invalid backup lsn {}", lsn))
+        })?;
+        self.backup(writer, Some(since))
+    }
+
+    fn backup<W: Write>(&self, writer: W, since: Option<Lsn>) -> Result<u64> {
+        let tenants: BTreeMap<IVec, Tree> = self
+            .tenants
+            .read()
+            .iter()
+            .map(|(name, tree)| (name.clone(), tree.clone()))
+            .collect();
+
+        // the snapshot only blocks writes while it is registered
+        let (snapshot, lsn) = snapshot::Snapshot::with_lsn(&self.context);
+        let log = &self.context.pagecache.log;
+
+        if since.map_or(false, |since| since > lsn) {
+            return Err(Error::Unsupported(format!(
+                "backup lsn {:?} is newer than the latest lsn {}",
+                since, lsn
+            )));
+        }
+
+        let timestamp = expiration::now_millis();
+        let header = Header { since, lsn, timestamp };
+        let mut backup = BackupWriter::new(writer, header)?;
+
+        for (name, tree) in &tenants {
+            backup.tree(name)?;
+
+            if let Some(since) = since {
+                // the leaves that were written since the snapshot
+                // was taken are included too, with their contents
+                // as of the snapshot
+                for (lo, hi) in tree.changed_ranges(since)? {
+                    backup.range(&lo, hi.as_deref())?;
+
+                    let hi = hi.map_or(Bound::Unbounded, Bound::Excluded);
+                    let range =
+                        snapshot.range_inner(tree, Bound::Included(lo), hi);
+                    backup.kvs(tree, range)?;
+                }
+            } else {
+                backup.kvs(tree, snapshot.iter(tree))?;
+            }
+        }
+
+        drop(snapshot);
+
+        backup.finish()?;
+
+        // if the LSN were not durable, writes after a crash
+        // could reuse it and be missed by the next backup.
+        log.make_stable(lsn)?;
+
+        Ok(u64::try_from(lsn).unwrap())
+    }
+
+    /// Restores a backup written by `Db::backup_to` or
+    /// `Db::backup_since` into this `Db`. The stream is first
+    /// copied to a temporary file next to the database and its
+    /// checksum verified, and then written in batches. Returns
+    /// the LSN of the restored backup.
+    ///
+    /// A full backup may only be restored into an empty `Db`.
+    /// An incremental backup must be restored on top of the
+    /// backup it was based on, and also drops any `Tree` that
+    /// had been dropped before it was written.
+    ///
+    /// Returns `Error::Unsupported` if a full backup is restored
+    /// into a `Db` that already contains data or the stream is
+    /// not a backup, and `Error::Corruption` if the stream's
+    /// checksum does not match its contents, in which case
+    /// nothing is restored.
+    ///
+    /// Backups contain keys in the form that they are stored in,
+    /// but not the options of trees opened with
+    /// `Db::open_tree_with`, so such trees must be created with
+    /// the same options before the backup is restored.
+    pub fn restore_from<R: Read>(&self, reader: R) -> Result<u64> {
+        self.restore(BackupReader::new(reader)?)
+    }
+
+    /// Restores a full backup followed by a sequence of the
+    /// incremental backups that build upon it, stopping before
+    /// the first backup that is newer than the provided LSN.
+    /// Returns the LSN of the last backup that was restored.
+    ///
+    /// The backups must be provided in the order that they were
+    /// written, and each incremental backup must be based on the
+    /// backup before it. The `Db` is restored to the state of
+    /// the latest backup at or before the target, so the
+    /// precision of the restore depends on how often
+    /// backups were taken.
+    ///
+    /// Returns `Error::Unsupported` if the backups do not form
+    /// such a sequence, or if even the full backup is newer
+    /// than the target.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    ///
+    /// db.insert("k", "good")?;
+    /// let mut full = vec![];
+    /// let good_lsn = db.backup_to(&mut full)?;
+    ///
+    /// db.insert("k", "bad")?;
+    /// let mut incremental = vec![];
+    /// db.backup_since(good_lsn, &mut incremental)?;
+    ///
+    /// let restored = sled::Config::new().temporary(true).open()?;
+    /// let backups = vec![&*full, &*incremental];
+    /// assert_eq!(restored.restore_to(backups, good_lsn)?, good_lsn);
+    ///
+    /// assert_eq!(restored.get("k")?, Some(sled::IVec::from("good")));
+    /// # Ok(()) }
+    /// ```
+    pub fn restore_to<R, I>(&self, backups: I, target_lsn: u64) -> Result<u64>
+    where
+        R: Read,
+        I: IntoIterator<Item = R>,
+    {
+        self.restore_until(backups, |header| {
+            u64::try_from(header.lsn).unwrap() <= target_lsn
+        })
+    }
+
+    /// Like `Db::restore_to`, but stops before the first backup
+    /// that was taken after the provided time.
+    pub fn restore_to_time<R, I>(
+        &self,
+        backups: I,
+        target_time: SystemTime,
+    ) -> Result<u64>
+    where
+        R: Read,
+        I: IntoIterator<Item = R>,
+    {
+        let target_ms = target_time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| u64::try_from(t.as_millis()).unwrap());
+
+        self.restore_until(backups, |header| header.timestamp <= target_ms)
+    }
+
+    fn restore_until<R, I, F>(&self, backups: I, is_target: F) -> Result<u64>
+    where
+        R: Read,
+        I: IntoIterator<Item = R>,
+        F: Fn(&Header) -> bool,
+    {
+        let mut restored: Option<Lsn> = None;
+
+        for reader in backups {
+            let backup = BackupReader::new(reader)?;
+
+            if backup.header.since != restored {
+                return Err(Error::Unsupported(format!(
+                    "backup based on lsn {:?} can't be restored after \
+                     lsn {:?}, backups must be provided in the order \
+                     that they were written",
+                    backup.header.since, restored
+                )));
+            }
+
+            if !is_target(&backup.header) {
+                break;
+            }
+
+            restored = Some(Lsn::try_from(self.restore(backup)?).unwrap());
+        }
+
+        if let Some(lsn) = restored {
+            Ok(u64::try_from(lsn).unwrap())
+        } else {
+            Err(Error::Unsupported(
+                "no full backup was taken before the restore target".into(),
+            ))
+        }
+    }
+
+    fn restore<R: Read>(&self, stream: BackupReader<R>) -> Result<u64> {
+        let incremental = stream.header.since.is_some();
+
+        if !incremental
+            && self.tenants.read().values().any(|tree| !tree.is_empty())
+        {
+            return Err(Error::Unsupported(
+                "can only restore a full backup into an empty database"
+                    .into(),
+            ));
+        }
+
+        let mut backup = stream.verified(&self.context.get_path())?;
+
+        let mut names = vec![];
+        let mut tree: Option<Tree> = None;
+        let mut batch = Batch::default();
+
+        while let Some(record) = backup.next()? {
+            match record {
+                backup::Record::Tree(name) => {
+                    if let Some(tree) = tree.take() {
+                        apply_restored(&tree, std::mem::take(&mut batch))?;
+                    }
+                    tree = Some(self.open_listed_tree(&name)?);
+                    names.push(name);
+                }
+                backup::Record::Range(lo, hi) => {
+                    let tree =
+                        tree.as_ref().ok_or_else(|| Error::corruption(None))?;
+                    let hi = hi.map_or(Bound::Unbounded, Bound::Excluded);
+                    let mut iter = tree.range_inner(Bound::Included(lo), hi);
+                    while let Some(kv_res) = iter.next_inner() {
+                        batch.remove(kv_res?.0);
+                    }
+                }
+                backup::Record::Kv(k, v) => {
+                    let tree =
+                        tree.as_ref().ok_or_else(|| Error::corruption(None))?;
+                    batch.insert(k, v);
+                    if batch.writes.len() >= RESTORE_BATCH_SIZE {
+                        apply_restored(tree, std::mem::take(&mut batch))?;
+                    }
+                }
+            }
+        }
+
+        if let Some(tree) = tree {
+            apply_restored(&tree, batch)?;
+        }
+
+        if incremental {
+            for name in self.tree_names() {
+                if name != DEFAULT_TREE_ID && !names.contains(&name) {
+                    let _ = self.drop_tree(name)?;
+                }
+            }
+        }
+
+        Ok(u64::try_from(backup.header.lsn).unwrap())
+    }
+
+    /// Returns the CRC32 of all keys and values
+    /// in this Db.
+    ///
+    /// This is O(N) and locks all underlying Trees
+    /// for the duration of the entire scan.
+    pub fn checksum(&self) -> Result<u32> {
+        let tenants_mu = self.tenants.write();
+
+        // we use a btreemap to ensure lexicographic
+        // iteration over tree names to have consistent
+        // checksums.
+        let tenants: BTreeMap<_, _> = tenants_mu.iter().collect();
+
+        let mut hasher = crc32fast::Hasher::new();
+
+        for (name, tree) in &tenants {
+            hasher.update(name);
+
+            let mut iter = tree.iter();
+            while let Some(kv_res) = iter.next_inner() {
+                let (k, v) = kv_res?;
+                hasher.update(&k);
+                hasher.update(&v);
+            }
+        }
+
+        Ok(hasher.finalize())
+    }
+
+    /// Walks every level of every `Tree`, reading each node back
+    /// from the log to check its checksum, and checks that the
+    /// nodes of each level are linked to their siblings, begin
+    /// where their left sibling ends, hold their keys in order
+    /// and within their bounds, and are all reachable from the
+    /// index nodes above them.
+    ///
+    /// Problems are collected in the returned report rather than
+    /// returned as errors, so that everything that is wrong can
+    /// be found at once. The hidden trees that hold the indexes,
+    /// expiring keys, history and versions of trees are checked
+    /// as well. Writes go on while the trees are walked, so the
+    /// counts in the report are only exact for a `Db` that isn't
+    /// written to meanwhile.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// db.insert("k", "v")?;
+    ///
+    /// let report = db.verify_integrity()?;
+    /// assert!(report.is_healthy(), "{:?}", report.problems);
+    /// assert_eq!(report.keys, 1);
+    /// # Ok(()) }
+    /// ```
+    pub fn verify_integrity(&self) -> Result<IntegrityReport> {
+        self.context.pagecache.config.global_error()?;
+
+        let names: Vec<IVec> = self
+            .context
+            .pagecache
+            .get_meta(&pin())
+            .tenants()
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| {
+                !merge_operators::is_meta_key(name)
+                    && !tree_stats::is_meta_key(name)
+            })
+            .collect();
+
+        let mut report = IntegrityReport::default();
+        for name in &names {
+            integrity::verify_tree(name, &self.context.pagecache, &mut report);
+        }
+        Ok(report)
+    }
+
+    /// Returns the ranges of keys that were lost while opening
+    /// the database with `RecoveryMode::Salvage`, because the
+    /// nodes that held them couldn't be read. It is empty if
+    /// nothing was lost, or if the database was opened with
+    /// `RecoveryMode::Strict`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use sled::{Config, RecoveryMode};
+    ///
+    /// let db = Config::new()
+    ///     .temporary(true)
+    ///     .recovery_mode(RecoveryMode::Salvage)
+    ///     .open()?;
+    ///
+    /// for range in db.lost_ranges() {
+    ///     eprintln!("{}", range);
+    /// }
+    /// assert!(db.lost_ranges().is_empty());
+    /// # Ok(()) }
+    /// ```
+    pub fn lost_ranges(&self) -> &[LostRange] {
+        &self.lost
+    }
+
+    /// Returns a receiver of the pages that fail the checks of the
+    /// background scrub, which is enabled with
+    /// `Config::scrub_every_ms`. Every page is reported to every
+    /// receiver each time that it fails, until the receiver is
+    /// dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::time::Duration;
+    ///
+    /// let db = sled::Config::new()
+    ///     .temporary(true)
+    ///     .scrub_every_ms(Some(10))
+    ///     .open()?;
+    /// let failures = db.scrub_failures();
+    ///
+    /// db.insert("k", "v")?;
+    /// assert!(failures.recv_timeout(Duration::from_millis(50)).is_err());
+    /// # Ok(()) }
+    /// ```
+    pub fn scrub_failures(&self) -> std::sync::mpsc::Receiver<ScrubFailure> {
+        self.context.scrubber.subscribe()
+    }
+
+    /// Writes a consistent copy of this database to the provided
+    /// directory, which can later be opened as a `Db` of its own.
+    /// Writes continue while the bulk of the log is copied,
+    /// and are only paused while the recently written parts of
+    /// the log, the snapshot and any large values stored
+    /// in the heap files are copied.
+    ///
+    /// The copy contains every write that completed before this
+    /// method was called, and will be recovered as if the
+    /// database had crashed at the moment writes were paused.
+    ///
+    /// Returns `Error::Unsupported` if the directory
+    /// already contains a database.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// db.insert("k", "v")?;
+    ///
+    /// let dir = std::env::temp_dir().join("sled_checkpoint_doctest");
+    /// # let _ = std::fs::remove_dir_all(&dir);
+    /// db.checkpoint(&dir)?;
+    ///
+    /// let copy = sled::open(&dir)?;
+    /// assert_eq!(copy.get("k")?, Some(sled::IVec::from("v")));
+    /// # drop(copy);
+    /// # let _ = std::fs::remove_dir_all(&dir);
+    /// # Ok(()) }
+    /// ```
+    pub fn checkpoint<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+    ) -> Result<()> {
+        self.flush()?;
+        pagecache::checkpoint(&self.context.pagecache, path.as_ref())
+    }
+
+    /// Reclaims the space of values that were stored in the
+    /// value log files (see `Config::value_log_threshold`) and
+    /// have since been overwritten or removed. Every file that
+    /// is at least half garbage has its remaining values moved
+    /// to the end of the value log and is then removed.
+    /// Transactions are paused while each file is moved, and
+    /// other writes go on. A file with an entry that can't be
+    /// read is left alone, since the entries after it can't be
+    /// found. Returns the number of bytes that were reclaimed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new()
+    ///     .temporary(true)
+    ///     .value_log_threshold(Some(4096))
+    ///     .open()?;
+    ///
+    /// for i in 0..64_u8 {
+    ///     db.insert([i % 4], vec![i; 64 * 1024])?;
+    /// }
+    ///
+    /// assert!(db.collect_value_log_garbage()? > 0);
+    /// assert_eq!(db.get([3])?, Some(vec![63; 64 * 1024].into()));
+    /// # Ok(()) }
+    /// ```
+    pub fn collect_value_log_garbage(&self) -> Result<u64> {
+        let tenants = self.tenants.read().clone();
+        value_log::collect_garbage(&self.context, &tenants)
+    }
+
+    /// Returns the on-disk size of the storage files
+    /// for this database.
+    pub fn size_on_disk(&self) -> Result<u64> {
+        self.context.pagecache.size_on_disk()
+    }
+
+    /// Returns the capacity of the page cache in bytes, which is
+    /// `Config::cache_capacity` unless it was changed with
+    /// `set_cache_capacity`, or the capacity of the `SharedCache`
+    /// that the database uses.
+    pub fn cache_capacity(&self) -> usize {
+        self.context.pagecache.cache_capacity()
+    }
+
+    /// Changes the capacity of the page cache in bytes from now on,
+    /// replacing `Config::cache_capacity` until the database is
+    /// closed. When the cache shrinks, the least recently used
+    /// pages are paged out before this returns, so that the memory
+    /// is freed once the threads that are reading them are done.
+    /// Unlike `Config::cache_capacity`, the capacity is not limited
+    /// to the memory limit of the cgroup of the process.
+    ///
+    /// Returns `Error::Unsupported` if `cache_capacity` is below
+    /// 256 bytes, or if the database uses a `SharedCache`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new()
+    ///     .temporary(true)
+    ///     .cache_capacity(64 * 1024 * 1024)
+    ///     .open()?;
+    ///
+    /// // the other services of the host need the memory back
+    /// db.set_cache_capacity(8 * 1024 * 1024)?;
+    /// assert_eq!(db.cache_capacity(), 8 * 1024 * 1024);
+    /// # Ok(()) }
+    /// ```
+    pub fn set_cache_capacity(&self, cache_capacity: usize) -> Result<()> {
+        if cache_capacity < 256 {
+            return Err(Error::Unsupported(
+                "the cache capacity must be at least 256 bytes".into(),
+            ));
+        }
+        self.context.pagecache.set_cache_capacity(cache_capacity)
+    }
+
+    /// Writes a cache manifest to `path`, listing the pages that
+    /// are in the page cache, so that `Db::warm_cache_from` can
+    /// read the same pages back into the cache after the database
+    /// is opened again. Returns the number of pages listed.
+    ///
+    /// The manifest is only a hint about which pages were hot, and
+    /// is never read by the database itself. A manifest that
+    /// already exists at `path` is replaced atomically.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let dir = std::env::temp_dir().join("sled_write_cache_manifest");
+    /// # let _ = std::fs::remove_dir_all(&dir);
+    /// # std::fs::create_dir_all(&dir)?;
+    /// # let manifest = dir.join("hot_pages");
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// db.insert(b"hot", b"value")?;
+    ///
+    /// assert!(db.write_cache_manifest(&manifest)? > 0);
+    /// # std::fs::remove_dir_all(&dir)?;
+    /// # Ok(()) }
+    /// ```
+    pub fn write_cache_manifest<P: AsRef<std::path::Path>>(
         &self,
-    ) -> Vec<(CollectionType, CollectionName, impl Iterator<Item = Vec<Vec<u8>>>)>
-    {
-        let tenants = self.tenants.read();
+        path: P,
+    ) -> Result<usize> {
+        warmup::write_manifest(self, path.as_ref())
+    }
 
-        let mut ret = vec![];
+    /// Reads the pages that a cache manifest written by
+    /// `Db::write_cache_manifest` lists into the page cache, so
+    /// that a process that was just restarted can pull its hot set
+    /// into the cache before it takes traffic. Returns the number
+    /// of pages that were read from storage.
+    ///
+    /// Pages that are already cached, or that no longer exist, are
+    /// skipped, and reading stops once the pages that were read
+    /// fill the capacity of the cache. Returns `Error::Unsupported`
+    /// if the file isn't a cache manifest, and `Error::Corruption`
+    /// if its checksum doesn't match.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let dir = std::env::temp_dir().join("sled_warm_cache_from");
+    /// # let _ = std::fs::remove_dir_all(&dir);
+    /// # std::fs::create_dir_all(&dir)?;
+    /// # let db_path = dir.join("db");
+    /// # let manifest = dir.join("hot_pages");
+    /// let db = sled::open(&db_path)?;
+    /// db.insert(b"hot", b"value")?;
+    /// db.write_cache_manifest(&manifest)?;
+    /// drop(db);
+    ///
+    /// let db = sled::open(&db_path)?;
+    /// db.warm_cache_from(&manifest)?;
+    /// assert_eq!(db.get(b"hot")?, Some(sled::IVec::from(b"value")));
+    /// # drop(db);
+    /// # std::fs::remove_dir_all(&dir)?;
+    /// # Ok(()) }
+    /// ```
+    pub fn warm_cache_from<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+    ) -> Result<usize> {
+        warmup::warm_from(self, path.as_ref())
+    }
 
-        for (name, tree) in tenants.iter() {
-            ret.push((
-                b"tree".to_vec(),
-                name.to_vec(),
-                tree.iter().map(|kv_opt| {
-                    let kv = kv_opt.unwrap();
-                    vec![kv.0.to_vec(), kv.1.to_vec()]
-                }),
+    /// Changes when writes are synced to the storage device from
+    /// now on, replacing `Config::sync_policy` until the database
+    /// is closed. The background threads are restarted for the new
+    /// policy, after the flush thread, if there was one, has
+    /// flushed what was written before.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use sled::SyncPolicy;
+    ///
+    /// let db = sled::Config::new().temporary(true).open()?;
+    ///
+    /// // a bulk load that may be lost if the process dies
+    /// db.set_sync_policy(SyncPolicy::OsManaged)?;
+    /// for i in 0..1000_u32 {
+    ///     db.insert(i.to_be_bytes(), vec![0; 64])?;
+    /// }
+    /// db.flush()?;
+    ///
+    /// db.set_sync_policy(SyncPolicy::EveryWrite)?;
+    /// db.insert("durable", "as soon as this returns")?;
+    /// # Ok(()) }
+    /// ```
+    pub fn set_sync_policy(&self, policy: SyncPolicy) -> Result<()> {
+        policy.check()?;
+        if self.context.read_only {
+            return Err(Error::Unsupported(
+                "the database was opened in read-only mode".into(),
             ));
         }
+        *self.context.runtime_sync_policy.write() = policy;
 
-        ret
+        #[cfg(all(
+            not(miri),
+            any(
+                windows,
+                target_os = "linux",
+                target_os = "macos",
+                target_os = "dragonfly",
+                target_os = "freebsd",
+                target_os = "openbsd",
+                target_os = "netbsd",
+                target_os = "ios",
+            )
+        ))]
+        flusher::start(&self.context);
+
+        Ok(())
     }
 
-    /// Imports the collections from a previous database.
+    /// Returns the LSN up to which the log is durable on the
+    /// storage device. Every write that ends at or below it is
+    /// recovered after a crash.
+    pub fn stable_lsn(&self) -> u64 {
+        let stable = self.context.pagecache.log.stable_offset();
+        u64::try_from(stable).unwrap_or(0)
+    }
+
+    /// Returns the LSN at which the latest write that has been
+    /// made to the log so far ends. Every write that has returned
+    /// ends at or below it, so a write is durable once
+    /// `Db::wait_for_durability` returns for the `last_lsn` that
+    /// was read after it.
+    pub fn last_lsn(&self) -> u64 {
+        let log = &self.context.pagecache.log;
+        let last = log.iobufs.max_reserved_lsn.load(Acquire);
+        u64::try_from(last).unwrap_or(0)
+    }
+
+    /// Blocks until the log is durable up to `lsn`, writing and
+    /// syncing the buffers that hold it if they haven't been yet.
+    /// Unlike `Db::flush`, it doesn't wait for the writes that came
+    /// after the ones it needs, so that an application can
+    /// acknowledge a request as soon as its own write is on disk.
     ///
-    /// # Panics
+    /// Returns `Error::Unsupported` if the LSN is newer than any
+    /// that has been written.
     ///
-    /// Panics if any IO problems occur while trying
-    /// to perform the import.
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// db.insert("request", "applied")?;
+    ///
+    /// let lsn = db.last_lsn();
+    /// db.wait_for_durability(lsn)?;
+    /// assert!(db.stable_lsn() >= lsn);
+    /// # Ok(()) }
+    /// ```
+    pub fn wait_for_durability(&self, lsn: u64) -> Result<()> {
+        if lsn > self.last_lsn() {
+            return Err(Error::Unsupported(format!(
+                "lsn {} is newer than the latest lsn {}",
+                lsn,
+                self.last_lsn()
+            )));
+        }
+        let target = Lsn::try_from(lsn).unwrap();
+        let _written = self.context.pagecache.log.make_stable(target)?;
+        Ok(())
+    }
+
+    /// Waits for the log to be durable up to `lsn` without
+    /// blocking the calling task, see `Db::wait_for_durability`.
+    // this clippy check is mis-firing on async code.
+    #[allow(clippy::used_underscore_binding)]
+    pub async fn wait_for_durability_async(&self, lsn: u64) -> Result<()> {
+        let db = self.clone();
+        if let Some(result) =
+            threadpool::spawn(move || db.wait_for_durability(lsn)).await
+        {
+            result
+        } else {
+            Err(Error::ReportableBug(
+                "threadpool failed to complete \
+                action before shutdown"
+                    .to_string(),
+            ))
+        }
+    }
+
+    /// Moves the pages out of the segments of the log that are
+    /// mostly garbage now, rather than waiting for the background
+    /// garbage collector, which only gets to segments once they
+    /// are at most half live. Returns once every selected page
+    /// was moved, with the number of segments and pages, which
+    /// `CompactOptions::on_progress` also reports along the way.
+    ///
+    /// When the compaction is limited to a `Tree`, only the
+    /// leaves of that tree are moved, and the segments are freed
+    /// once the garbage collector gets to what else is in them.
+    /// Use `Db::shrink_to_fit` afterwards to also give the space
+    /// of the freed segments back to the filesystem.
     ///
     /// # Examples
     ///
-    /// If you want to migrate from one version of sled
-    /// to another, you need to pull in both versions
-    /// by using version renaming:
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use sled::CompactOptions;
     ///
-    /// `Cargo.toml`:
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// let tree = db.open_tree("purged")?;
+    /// for i in 0..1000_u32 {
+    ///     tree.insert(i.to_be_bytes(), vec![0; 64])?;
+    /// }
+    /// for i in (0..1000_u32).filter(|i| i % 10 != 0) {
+    ///     tree.remove(i.to_be_bytes())?;
+    /// }
     ///
-    /// ```toml
-    /// [dependencies]
-    /// sled = "0.32"
-    /// old_sled = { version = "0.31", package = "sled" }
+    /// let progress = db.compact(
+    ///     CompactOptions::new()
+    ///         .tree("purged")
+    ///         .on_progress(|p| println!("{} pages left", p.remaining)),
+    /// )?;
+    /// assert_eq!(progress.remaining, 0);
+    /// assert_eq!(tree.len(), 100);
+    /// # Ok(()) }
     /// ```
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn compact(&self, options: CompactOptions) -> Result<CompactProgress> {
+        let tenants = self.tenants.read().clone();
+        compact::compact(&self.context, &tenants, &options)
+    }
+
+    /// Gives the space of the segments of the log that the
+    /// garbage collector has freed back to the filesystem, so
+    /// that the log file stops taking the space that it took at
+    /// its largest. Free segments at the end of the file are
+    /// truncated, and holes are punched in the others on
+    /// filesystems that support it, so that they take no space
+    /// until they are reused. The heap files that large nodes are
+    /// written to already have holes punched in them as soon as
+    /// their slots are freed. Returns the number of bytes that
+    /// were given back.
     ///
-    /// and in your code, remember that old versions of
-    /// sled might have a different way to open them
-    /// than the current `sled::open` method:
+    /// Free segments are also given back this way when the
+    /// database is opened.
+    ///
+    /// # Examples
     ///
     /// ```
-    /// # use sled as old_sled;
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
-    /// let old = old_sled::open("my_old_db")?;
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// for i in 0..1000_u32 {
+    ///     db.insert(i.to_be_bytes(), vec![0; 1024])?;
+    /// }
+    /// db.clear()?;
+    /// db.flush()?;
     ///
-    /// // may be a different version of sled,
-    /// // the export type is version agnostic.
-    /// let new = sled::open("my_new_db")?;
+    /// // nothing that is still referred to is given back
+    /// db.shrink_to_fit()?;
+    /// assert!(db.is_empty());
+    /// # Ok(()) }
+    /// ```
+    pub fn shrink_to_fit(&self) -> Result<u64> {
+        self.context.pagecache.shrink_to_fit()
+    }
+
+    /// Returns how much of the space of the storage files is
+    /// live and how much is dead, using the same accounting of
+    /// the segments of the log that the garbage collector uses
+    /// to choose which of them to clean, along with the bytes
+    /// that the nodes and separated values of every `Tree` take.
     ///
-    /// let export = old.export();
-    /// new.import(export);
+    /// The nodes of every tree are walked but not read back from
+    /// the log. Writes aren't paused, so the result may not match
+    /// a single point in time while they are happening.
     ///
-    /// assert_eq!(old.checksum()?, new.checksum()?);
-    /// # drop(old);
-    /// # drop(new);
-    /// # std::fs::remove_file("my_old_db");
-    /// # std::fs::remove_file("my_new_db");
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// db.insert("k", vec![0; 1024])?;
+    /// db.flush()?;
+    ///
+    /// let usage = db.space_usage()?;
+    /// assert_eq!(usage.live + usage.dead, usage.on_disk);
+    /// let default = &usage.trees[0];
+    /// assert_eq!(&*default.tree, b"__sled__default");
+    /// assert!(default.live() > 1024);
     /// # Ok(()) }
     /// ```
-    pub fn import(
-        &self,
-        export: Vec<(
-            CollectionType,
-            CollectionName,
-            impl Iterator<Item = Vec<Vec<u8>>>,
-        )>,
-    ) {
-        for (collection_type, collection_name, collection_iter) in export {
-            match collection_type {
-                ref t if t == b"tree" => {
-                    let tree = self
-                        .open_tree(collection_name)
-                        .expect("failed to open new tree during import");
-                    for mut kv in collection_iter {
-                        let v = kv
-                            .pop()
-                            .expect("failed to get value from tree export");
-                        let k = kv
-                            .pop()
-                            .expect("failed to get key from tree export");
-                        let old = tree.insert(k, v).expect(
-                            "failed to insert value during tree import",
-                        );
-                        assert!(
-                            old.is_none(),
-                            "import is overwriting existing data"
-                        );
-                    }
-                }
-                other => panic!("unknown collection type {:?}", other),
-            }
-        }
+    pub fn space_usage(&self) -> Result<SpaceUsage> {
+        let tenants: BTreeMap<IVec, Tree> = self
+            .tenants
+            .read()
+            .iter()
+            .map(|(name, tree)| (name.clone(), tree.clone()))
+            .collect();
+
+        space::space_usage(&self.context, &tenants)
     }
 
-    /// Returns the CRC32 of all keys and values
-    /// in this Db.
+    /// Returns the statistics of every `Tree`, ordered by name:
+    /// the number of its keys, its live and dead bytes, how many
+    /// of its nodes are in the page cache, and when it was
+    /// created.
     ///
-    /// This is O(N) and locks all underlying Trees
-    /// for the duration of the entire scan.
-    pub fn checksum(&self) -> Result<u32> {
-        let tenants_mu = self.tenants.write();
+    /// The keys are counted like with `Tree::len`, or estimated
+    /// before they have been counted, and the live bytes are
+    /// estimated like with `Tree::size_of_range`, so neither
+    /// scans the trees. The nodes of every tree are
+    /// walked to count them, but only the index nodes are read,
+    /// so the leaves that aren't in the cache stay out of it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let users = db.open_tree("users")?;
+    /// users.insert("ada", "ada@example.com")?;
+    ///
+    /// let stats = db.tree_stats()?;
+    /// let users_stats = stats.iter().find(|s| s.tree == "users").unwrap();
+    /// assert_eq!(users_stats.len, 1);
+    /// assert!(users_stats.live_bytes > 0);
+    /// assert_eq!(users_stats.cache_residency(), 1.0);
+    /// assert!(users_stats.created.is_some());
+    /// # Ok(()) }
+    /// ```
+    pub fn tree_stats(&self) -> Result<Vec<TreeStats>> {
+        let tenants: BTreeMap<IVec, Tree> = self
+            .tenants
+            .read()
+            .iter()
+            .map(|(name, tree)| (name.clone(), tree.clone()))
+            .collect();
 
-        // we use a btreemap to ensure lexicographic
-        // iteration over tree names to have consistent
-        // checksums.
-        let tenants: BTreeMap<_, _> = tenants_mu.iter().collect();
+        let guard = pin();
+        tenants
+            .iter()
+            .map(|(name, tree)| tree_stats::tree_stats(name, tree, &guard))
+            .collect()
+    }
 
-        let mut hasher = crc32fast::Hasher::new();
+    /// Returns a snapshot of the read, write and space
+    /// amplification of the database since it was opened: the
+    /// bytes written to the storage files against the bytes of
+    /// the keys and values written, the pages read from them
+    /// per `Tree::get`, the pages and bytes that the garbage
+    /// collector rewrote, the hit rate of the page cache, and
+    /// the same counters for every `Tree`.
+    ///
+    /// These counters are always kept, unlike the process-wide
+    /// ones of the `metrics` feature, and reading them only
+    /// estimates the live bytes of every tree like
+    /// `Tree::size_of_range`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let users = db.open_tree("users")?;
+    /// users.insert("ada", "ada@example.com")?;
+    /// users.get("ada")?;
+    ///
+    /// let metrics = db.metrics()?;
+    /// assert!(metrics.write_amplification() > 1.0);
+    /// let users_metrics =
+    ///     metrics.trees.iter().find(|t| t.tree == "users").unwrap();
+    /// assert_eq!(users_metrics.writes, 1);
+    /// assert_eq!(users_metrics.user_bytes_written, 18);
+    /// assert_eq!(users_metrics.gets, 1);
+    /// # Ok(()) }
+    /// ```
+    pub fn metrics(&self) -> Result<DbMetrics> {
+        let tenants: BTreeMap<IVec, Tree> = self
+            .tenants
+            .read()
+            .iter()
+            .map(|(name, tree)| (name.clone(), tree.clone()))
+            .collect();
 
-        for (name, tree) in &tenants {
-            hasher.update(name);
+        db_metrics::snapshot(&self.context, &tenants)
+    }
 
-            let mut iter = tree.iter();
-            while let Some(kv_res) = iter.next_inner() {
-                let (k, v) = kv_res?;
-                hasher.update(&k);
-                hasher.update(&v);
-            }
-        }
+    /// Returns the memory that the database holds in its page
+    /// cache, in the buffers of its log, in the events that are
+    /// queued for its subscribers and in the writes of its `Txn`s,
+    /// along with the limit of `Config::memory_limit` that writes
+    /// keep them within.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let config = sled::Config::new()
+    ///     .temporary(true)
+    ///     .cache_capacity(1024 * 1024)
+    ///     .memory_limit(Some(8 * 1024 * 1024));
+    /// let db = config.open()?;
+    /// db.insert("a", "1")?;
+    ///
+    /// let usage = db.memory_usage();
+    /// assert!(usage.io_buffers > 0);
+    /// assert_eq!(usage.transactions, 0);
+    /// assert!(usage.total() <= usage.limit.unwrap());
+    /// # Ok(()) }
+    /// ```
+    pub fn memory_usage(&self) -> MemoryUsage {
+        memory::usage(&self.context)
+    }
 
-        Ok(hasher.finalize())
+    /// Returns a snapshot of the latency histograms of the gets,
+    /// inserts, removes, flushes and transactions of every `Tree`
+    /// since the database was opened or the histograms were last
+    /// reset with `reset_latency_histograms`.
+    ///
+    /// The operations are timed inside sled, so their latencies
+    /// include the time they spend waiting on other threads, like
+    /// a flush that waits for the buffers that they're writing to
+    /// be made stable, or a transaction that is retried. Unlike
+    /// the histograms of the `metrics` feature, they're always
+    /// kept and belong to a single `Db`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// db.insert("ada", "ada@example.com")?;
+    /// db.get("ada")?;
+    ///
+    /// let histograms = db.latency_histograms();
+    /// assert_eq!(histograms.insert.count(), 1);
+    /// assert_eq!(histograms.get.count(), 1);
+    /// println!("p99 get latency: {:?}", histograms.get.percentile(99.));
+    ///
+    /// db.reset_latency_histograms();
+    /// assert_eq!(db.latency_histograms().get.count(), 0);
+    /// # Ok(()) }
+    /// ```
+    pub fn latency_histograms(&self) -> LatencyHistograms {
+        self.context.latency.snapshot()
     }
 
-    /// Returns the on-disk size of the storage files
-    /// for this database.
-    pub fn size_on_disk(&self) -> Result<u64> {
-        self.context.pagecache.size_on_disk()
+    /// Empties the latency histograms of `latency_histograms`.
+    /// The operations that finish while they're emptied may be
+    /// kept or dropped.
+    pub fn reset_latency_histograms(&self) {
+        self.context.latency.reset();
+    }
+
+    /// Renders `Db::metrics`, `Db::tree_stats` and the latency
+    /// histograms of the `metrics` feature in the Prometheus text
+    /// exposition format, to be served to a Prometheus scraper
+    /// as is. The counters of the trees are labeled with their
+    /// names, and the histograms are shared by every `Db` of the
+    /// process. Requires the `metrics-prometheus` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// db.open_tree("users")?.insert("ada", "ada@example.com")?;
+    ///
+    /// let exposition = db.prometheus_metrics()?;
+    /// assert!(exposition.contains("# TYPE sled_cache_hits_total counter"));
+    /// assert!(exposition.contains("sled_tree_writes_total{tree=\"users\"} 1"));
+    /// # Ok(()) }
+    /// ```
+    #[cfg(feature = "metrics-prometheus")]
+    pub fn prometheus_metrics(&self) -> Result<String> {
+        prometheus::render(self)
     }
 
     /// Traverses all files and calculates their total physical
@@ -472,6 +2423,75 @@ impl Db {
     }
 }
 
+// applies restored keys as they are stored, bypassing the
+// encoding of `Tree::apply_batch`.
+fn apply_restored(tree: &Tree, batch: Batch) -> Result<()> {
+    let _cc = concurrency_control::write();
+    let mut guard = pin();
+    tree.apply_batch_inner(batch, &mut guard)
+}
+
+fn is_reserved_name(name: &[u8]) -> bool {
+    name.starts_with(RESERVED_PREFIX)
+}
+
+fn reserved_name_error(name: &[u8]) -> Error {
+    Error::Unsupported(format!(
+        "the name {:?} is reserved, because names starting with \
+         __sled__ are used by sled itself",
+        String::from_utf8_lossy(name)
+    ))
+}
+
+const TEMPORARY_TREE_PREFIX: &[u8] = b"__sled__temporary__";
+
+fn is_temporary_tree_name(name: &[u8]) -> bool {
+    name.starts_with(TEMPORARY_TREE_PREFIX)
+}
+
+fn temporary_tree_name(name: &[u8]) -> IVec {
+    let mut ret = TEMPORARY_TREE_PREFIX.to_vec();
+    ret.extend_from_slice(name);
+    ret.into()
+}
+
+// returns the name that an entry of the `Meta` which belongs to
+// the tree `from` has once the tree is renamed to `to`, or `None`
+// if the entry doesn't belong to it
+fn renamed_meta_key(key: &[u8], from: &[u8], to: &[u8]) -> Option<IVec> {
+    if key == from {
+        Some(to.into())
+    } else if expiration::parent_tree_name(key) == Some(from) {
+        Some(expiration::expiration_tree_name(to))
+    } else if history::parent_tree_name(key) == Some(from) {
+        Some(history::history_tree_name(to))
+    } else if versions::parent_tree_name(key) == Some(from) {
+        Some(versions::versions_tree_name(to))
+    } else if key_version::parent_tree_name(key) == Some(from) {
+        Some(key_version::key_versions_tree_name(to))
+    } else if index::parent_tree_name(key) == Some(from) {
+        index::renamed_tree_name(key, to)
+    } else if let Some(name) = tree_stats::created_tree_name(key) {
+        renamed_meta_key(name, from, to)
+            .map(|renamed| tree_stats::created_meta_key(&renamed))
+    } else if let Some(name) = tree_stats::len_tree_name(key) {
+        renamed_meta_key(name, from, to)
+            .map(|renamed| tree_stats::len_meta_key(&renamed))
+    } else {
+        merge_operators::renamed_meta_key(key, from, to)
+    }
+}
+
+// returns the hidden trees that are attached to a tree
+fn companions(tree: &Tree) -> Vec<Tree> {
+    let mut ret = vec![];
+    ret.extend(tree.expirations.read().clone());
+    ret.extend(tree.history.read().as_ref().map(|h| h.tree.clone()));
+    ret.extend(tree.versions.read().clone());
+    ret.extend(tree.key_versions.read().as_ref().map(|kv| kv.tree.clone()));
+    ret
+}
+
 /// These types provide the information that allows an entire
 /// system to be exported and imported to facilitate
 /// major upgrades. It is comprised entirely
@@ -480,3 +2500,4 @@ impl Db {
 /// they impact the migration path.
 type CollectionType = Vec<u8>;
 type CollectionName = Vec<u8>;
+
@@ -0,0 +1,264 @@
+//! The read, write and space amplification of a `Db`, see
+//! `Db::metrics`.
+//!
+//! Unlike the histograms behind the `metrics` feature, which are
+//! shared by every `Db` of the process and only printed, these
+//! counters belong to a single `Db`, are always kept, and are
+//! cheap enough for that: every one of them is a relaxed atomic
+//! addition on a path that already does IO or links an update.
+//!
+//! The pages that a `Tree::get` reads are counted with a counter
+//! of the thread that reads them, which the get compares before
+//! and after, so that the reads of concurrent gets of other trees
+//! aren't attributed to it.
+use std::cell::Cell;
+
+use crate::*;
+
+thread_local! {
+    static PAGES_READ: Cell<u64> = Cell::new(0);
+}
+
+/// The counters of a `Db`, which are reset when it's opened.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    log_bytes: AtomicU64,
+    heap_bytes: AtomicU64,
+    cache_hits: AtomicU64,
+    compressed_cache_hits: AtomicU64,
+    secondary_cache_hits: AtomicU64,
+    pages_read: AtomicU64,
+    gc_pages: AtomicU64,
+    gc_bytes: AtomicU64,
+}
+
+/// The counters of a `Tree`.
+#[derive(Debug, Default)]
+pub(crate) struct TreeCounters {
+    writes: AtomicU64,
+    written_bytes: AtomicU64,
+    gets: AtomicU64,
+    pages_read: AtomicU64,
+}
+
+/// A snapshot of the amplification of a `Db` since it was
+/// opened, returned by `Db::metrics`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbMetrics {
+    /// The bytes of the keys and values that were written to
+    /// every `Tree`, counting only the key of a removal.
+    pub user_bytes_written: u64,
+    /// The bytes that were written to the log, including the
+    /// headers of its messages and the rewrites of the garbage
+    /// collector.
+    pub log_bytes_written: u64,
+    /// The bytes that were written to the heap files for the
+    /// nodes that are too large for the log.
+    pub heap_bytes_written: u64,
+    /// The number of `Tree::get` calls.
+    pub gets: u64,
+    /// The number of pages that were read from the storage
+    /// files, by gets and everything else.
+    pub pages_read: u64,
+    /// The number of pages that were asked for and found in the
+    /// page cache.
+    pub cache_hits: u64,
+    /// The number of the `cache_hits` that were found in the
+    /// compressed tier of the page cache, see
+    /// `Config::compressed_cache_fraction`.
+    pub compressed_cache_hits: u64,
+    /// The number of the `cache_hits` that were read from the
+    /// secondary cache, see `Config::secondary_cache_path`.
+    pub secondary_cache_hits: u64,
+    /// The number of pages that were asked for and read from
+    /// the storage files, which is the same as `pages_read`.
+    pub cache_misses: u64,
+    /// The number of pages that the garbage collector rewrote to
+    /// clean the segments of the log.
+    pub gc_pages_rewritten: u64,
+    /// The bytes that the garbage collector rewrote.
+    pub gc_bytes_rewritten: u64,
+    /// The bytes that the storage files take, like
+    /// `Db::size_on_disk`.
+    pub size_on_disk: u64,
+    /// The bytes of the keys and values of every `Tree`,
+    /// estimated like with `Tree::size_of_range`.
+    pub live_bytes: u64,
+    /// The metrics of every `Tree`, ordered by name.
+    pub trees: Vec<TreeMetrics>,
+}
+
+/// The metrics of a `Tree`, see `DbMetrics::trees`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeMetrics {
+    /// The name of the tree.
+    pub tree: IVec,
+    /// The number of keys that were set or removed.
+    pub writes: u64,
+    /// The bytes of the keys and values that were written.
+    pub user_bytes_written: u64,
+    /// The number of `Tree::get` calls.
+    pub gets: u64,
+    /// The number of pages that gets read from the storage
+    /// files.
+    pub pages_read: u64,
+    /// The bytes of the keys and values, estimated like with
+    /// `Tree::size_of_range`.
+    pub live_bytes: u64,
+}
+
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::float_arithmetic)]
+fn ratio(numerator: u64, denominator: u64) -> f64 {
+    if denominator == 0 {
+        return 0.;
+    }
+    numerator as f64 / denominator as f64
+}
+
+impl DbMetrics {
+    /// Returns the bytes written to the storage files for every
+    /// byte of user data written, or 0 before anything was.
+    pub fn write_amplification(&self) -> f64 {
+        ratio(
+            self.log_bytes_written + self.heap_bytes_written,
+            self.user_bytes_written,
+        )
+    }
+
+    /// Returns the pages read from the storage files per get,
+    /// counting only the pages that the gets read.
+    pub fn read_amplification(&self) -> f64 {
+        let pages_read = self.trees.iter().map(|tree| tree.pages_read).sum();
+        ratio(pages_read, self.gets)
+    }
+
+    /// Returns the bytes that the storage files take for every
+    /// live byte.
+    pub fn space_amplification(&self) -> f64 {
+        ratio(self.size_on_disk, self.live_bytes)
+    }
+
+    /// Returns the share of the pages that were asked for and
+    /// found in the page cache, between 0 and 1.
+    pub fn cache_hit_rate(&self) -> f64 {
+        ratio(self.cache_hits, self.cache_hits + self.cache_misses)
+    }
+}
+
+impl TreeMetrics {
+    /// Returns the pages read from the storage files per get.
+    pub fn read_amplification(&self) -> f64 {
+        ratio(self.pages_read, self.gets)
+    }
+}
+
+/// Counts a reservation in the log, and the heap slot of its
+/// item if it has one.
+pub(crate) fn record_log_write(
+    config: &RunningConfig,
+    log_bytes: u64,
+    heap_bytes: u64,
+) {
+    let counters = &config.metrics;
+    let _ = counters.log_bytes.fetch_add(log_bytes, Relaxed);
+    let _ = counters.heap_bytes.fetch_add(heap_bytes, Relaxed);
+}
+
+pub(crate) fn record_cache_hit(config: &RunningConfig) {
+    let _ = config.metrics.cache_hits.fetch_add(1, Relaxed);
+}
+
+/// Counts a page that was found in the compressed tier of the
+/// page cache, which is a cache hit too.
+pub(crate) fn record_compressed_cache_hit(config: &RunningConfig) {
+    record_cache_hit(config);
+    let _ = config.metrics.compressed_cache_hits.fetch_add(1, Relaxed);
+}
+
+/// Counts a page that was read from the secondary cache, which
+/// is a cache hit too.
+pub(crate) fn record_secondary_cache_hit(config: &RunningConfig) {
+    record_cache_hit(config);
+    let _ = config.metrics.secondary_cache_hits.fetch_add(1, Relaxed);
+}
+
+/// Counts a page that was read from the storage files.
+pub(crate) fn record_page_read(config: &RunningConfig) {
+    let _ = config.metrics.pages_read.fetch_add(1, Relaxed);
+    PAGES_READ.with(|pages_read| pages_read.set(pages_read.get() + 1));
+}
+
+/// Counts a page that the garbage collector rewrote.
+pub(crate) fn record_rewrite(config: &RunningConfig, bytes: u64) {
+    let counters = &config.metrics;
+    let _ = counters.gc_pages.fetch_add(1, Relaxed);
+    let _ = counters.gc_bytes.fetch_add(bytes, Relaxed);
+}
+
+/// Counts a key that was set to a value of `value_len` bytes, or
+/// removed if it's `None`.
+pub(crate) fn record_write(
+    tree: &Tree,
+    key_len: usize,
+    value_len: Option<usize>,
+) {
+    let bytes = u64::try_from(key_len + value_len.unwrap_or(0)).unwrap();
+    let _ = tree.metrics.writes.fetch_add(1, Relaxed);
+    let _ = tree.metrics.written_bytes.fetch_add(bytes, Relaxed);
+}
+
+/// Returns the pages that this thread has read so far, to pass
+/// to `record_get` once a get is done.
+pub(crate) fn pages_read_by_thread() -> u64 {
+    PAGES_READ.with(Cell::get)
+}
+
+pub(crate) fn record_get(tree: &Tree, pages_read_before: u64) {
+    let pages_read = pages_read_by_thread() - pages_read_before;
+    let _ = tree.metrics.gets.fetch_add(1, Relaxed);
+    let _ = tree.metrics.pages_read.fetch_add(pages_read, Relaxed);
+}
+
+/// Takes a snapshot of the metrics of a `Db` and its trees.
+pub(crate) fn snapshot(
+    context: &Context,
+    tenants: &BTreeMap<IVec, Tree>,
+) -> Result<DbMetrics> {
+    let counters = &context.metrics;
+    let pages_read = counters.pages_read.load(Relaxed);
+    let mut ret = DbMetrics {
+        user_bytes_written: 0,
+        log_bytes_written: counters.log_bytes.load(Relaxed),
+        heap_bytes_written: counters.heap_bytes.load(Relaxed),
+        gets: 0,
+        pages_read,
+        cache_hits: counters.cache_hits.load(Relaxed),
+        compressed_cache_hits: counters.compressed_cache_hits.load(Relaxed),
+        secondary_cache_hits: counters.secondary_cache_hits.load(Relaxed),
+        cache_misses: pages_read,
+        gc_pages_rewritten: counters.gc_pages.load(Relaxed),
+        gc_bytes_rewritten: counters.gc_bytes.load(Relaxed),
+        size_on_disk: context.pagecache.size_on_disk()?,
+        live_bytes: 0,
+        trees: Vec::with_capacity(tenants.len()),
+    };
+
+    for (name, tree) in tenants {
+        let (live_bytes, _) = tree.size_of_range::<&[u8], _>(..)?;
+        let tree_metrics = TreeMetrics {
+            tree: name.clone(),
+            writes: tree.metrics.writes.load(Relaxed),
+            user_bytes_written: tree.metrics.written_bytes.load(Relaxed),
+            gets: tree.metrics.gets.load(Relaxed),
+            pages_read: tree.metrics.pages_read.load(Relaxed),
+            live_bytes,
+        };
+        ret.user_bytes_written += tree_metrics.user_bytes_written;
+        ret.gets += tree_metrics.gets;
+        ret.live_bytes += live_bytes;
+        ret.trees.push(tree_metrics);
+    }
+
+    Ok(ret)
+}
@@ -173,6 +173,27 @@ impl DoublyLinkedList {
         }
     }
 
+    /// Removes an item from anywhere in the list.
+    pub(crate) fn remove(&mut self, ptr: *mut Node) -> CacheAccess {
+        self.len -= 1;
+
+        unsafe {
+            if self.tail == ptr {
+                self.tail = (*ptr).next;
+            }
+
+            if self.head == ptr {
+                self.head = (*ptr).prev;
+            }
+
+            let mut node = Box::from_raw(ptr);
+
+            node.unwire();
+
+            **node
+        }
+    }
+
     #[cfg(test)]
     pub(crate) fn pop_head(&mut self) -> Option<CacheAccess> {
         if self.head.is_null() {
@@ -196,6 +217,15 @@ impl DoublyLinkedList {
         }
     }
 
+    /// Returns the item at the tail without removing it.
+    pub(crate) fn peek_tail(&self) -> Option<&CacheAccess> {
+        if self.tail.is_null() {
+            return None;
+        }
+
+        unsafe { Some(&*(*self.tail).inner.get()) }
+    }
+
     pub(crate) fn pop_tail(&mut self) -> Option<CacheAccess> {
         if self.tail.is_null() {
             return None;
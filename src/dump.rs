@@ -0,0 +1,545 @@
+//! Human-readable dumps of every `Tree` in a `Db`, for inspecting
+//! production data and seeding test fixtures.
+//!
+//! A `Dump` writes one record for every pair, holding the name of
+//! its tree, its key and its value. Keys and tree names are
+//! written with one `Encoding` and values with another, and the
+//! records may be written as line-delimited JSON:
+//!
+//! ```text
+//! {"tree":"__sled__default","key":"k1","value":"7631"}
+//! ```
+//!
+//! or as CSV with a header line:
+//!
+//! ```text
+//! tree,key,value
+//! __sled__default,k1,7631
+//! ```
+//!
+//! Dumps are read back with the same `Dump`, which inserts every
+//! record into the tree that it names, opening that tree if it
+//! doesn't exist yet. Only the pairs are dumped, so trees that
+//! need a `TreeConfig` or a merge operator should be opened with
+//! them before a dump is loaded.
+//!
+//! # Examples
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use sled::dump::{Dump, Encoding, Format};
+//!
+//! let db = sled::Config::new().temporary(true).open()?;
+//! db.insert("k1", "v1")?;
+//! db.open_tree("users")?.insert("alice", vec![0, 255])?;
+//!
+//! let dump = Dump::new(Format::Json).keys(Encoding::Utf8);
+//! let mut out = vec![];
+//! assert_eq!(dump.write(&db, &mut out)?, 2);
+//!
+//! let lines = String::from_utf8(out.clone())?;
+//! assert!(lines.contains(r#"{"tree":"users","key":"alice","value":"00ff"}"#));
+//!
+//! let copy = sled::Config::new().temporary(true).open()?;
+//! assert_eq!(dump.load(&copy, &out[..])?, 2);
+//! assert_eq!(copy.get("k1")?, Some(sled::IVec::from("v1")));
+//! assert_eq!(copy.open_tree("users")?.get("alice")?.unwrap(), vec![0, 255]);
+//! # Ok(()) }
+//! ```
+use std::io::{BufRead, BufReader, BufWriter};
+
+use crate::*;
+
+const BASE64: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+const CSV_HEADER: &str = "tree,key,value";
+
+/// How the bytes of keys and values are written as text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// Two lowercase hexadecimal digits for every byte.
+    Hex,
+    /// The bytes themselves, which must be valid UTF-8.
+    Utf8,
+    /// Standard base64, with padding.
+    Base64,
+}
+
+impl Encoding {
+    /// Writes bytes as text in this encoding, failing with
+    /// `Error::Unsupported` if they can't be.
+    pub fn encode(self, bytes: &[u8]) -> Result<String> {
+        encode(self, bytes)
+    }
+
+    /// Reads the bytes back from text in this encoding, failing
+    /// with `Error::Unsupported` if it isn't valid.
+    pub fn decode(self, text: &str) -> Result<Vec<u8>> {
+        decode(self, text).map_err(|reason| {
+            Error::Unsupported(format!("{:?} {}", text, reason))
+        })
+    }
+}
+
+/// How the records of a dump are written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// One JSON object per line.
+    Json,
+    /// Comma-separated values, quoted where needed.
+    Csv,
+}
+
+/// Writes and loads dumps of a `Db`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dump {
+    format: Format,
+    keys: Encoding,
+    values: Encoding,
+}
+
+impl Dump {
+    /// Returns a `Dump` that uses the given format, and writes
+    /// keys, tree names and values as `Encoding::Hex`.
+    pub const fn new(format: Format) -> Dump {
+        Dump { format, keys: Encoding::Hex, values: Encoding::Hex }
+    }
+
+    /// Sets the encoding of keys and tree names.
+    pub const fn keys(self, encoding: Encoding) -> Dump {
+        Dump { format: self.format, keys: encoding, values: self.values }
+    }
+
+    /// Sets the encoding of values.
+    pub const fn values(self, encoding: Encoding) -> Dump {
+        Dump { format: self.format, keys: self.keys, values: encoding }
+    }
+
+    /// Writes every pair of every `Tree` in the `Db`, returning
+    /// the number of pairs that were written.
+    ///
+    /// Each tree is read like `Tree::iter` reads it, so writes
+    /// that happen while it is dumped may or may not be
+    /// included. Use `Db::checkpoint` first for a consistent
+    /// dump of a busy database.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::Unsupported` if a key, tree name or value
+    /// that is written as `Encoding::Utf8` is not valid UTF-8.
+    pub fn write<W: Write>(&self, db: &Db, writer: W) -> Result<u64> {
+        let mut out = BufWriter::new(writer);
+        if self.format == Format::Csv {
+            writeln!(out, "{}", CSV_HEADER)?;
+        }
+
+        let mut written = 0;
+        for name in db.tree_names() {
+            let tree = db.open_tree(&name)?;
+            let tree_name = encode(self.keys, &name)?;
+            for kv_res in &tree {
+                let (k, v) = kv_res?;
+                let key = encode(self.keys, &k)?;
+                let value = encode(self.values, &v)?;
+                match self.format {
+                    Format::Json => writeln!(
+                        out,
+                        "{{\"tree\":{},\"key\":{},\"value\":{}}}",
+                        json_string(&tree_name),
+                        json_string(&key),
+                        json_string(&value),
+                    )?,
+                    Format::Csv => writeln!(
+                        out,
+                        "{},{},{}",
+                        csv_field(&tree_name),
+                        csv_field(&key),
+                        csv_field(&value),
+                    )?,
+                }
+                written += 1;
+            }
+        }
+
+        out.flush()?;
+        Ok(written)
+    }
+
+    /// Inserts every record of a dump into the `Db`, returning
+    /// the number of pairs that were inserted. Records replace
+    /// any existing values of their keys.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::Unsupported` if a record can't be parsed
+    /// in the format and encodings of this `Dump`. The records
+    /// that precede it will already have been inserted.
+    pub fn load<R: Read>(&self, db: &Db, reader: R) -> Result<u64> {
+        let mut buffered = BufReader::new(reader);
+        let mut loaded = 0;
+        let mut line = 0;
+        let mut tree: Option<(Vec<u8>, Tree)> = None;
+        let mut record = String::new();
+
+        loop {
+            record.clear();
+            let first_line = line + 1;
+            if buffered.read_line(&mut record)? == 0 {
+                break;
+            }
+            line += 1;
+            if self.format == Format::Csv {
+                // quoted fields may contain line breaks
+                while record.matches('"').count() % 2 == 1 {
+                    if buffered.read_line(&mut record)? == 0 {
+                        return Err(invalid(first_line, "unterminated quote"));
+                    }
+                    line += 1;
+                }
+            }
+            let trimmed = record.trim_end_matches(&['\n', '\r'][..]);
+            if self.format == Format::Csv && first_line == 1 {
+                if trimmed != CSV_HEADER {
+                    return Err(invalid(first_line, "missing CSV header"));
+                }
+                continue;
+            }
+            if trimmed.trim().is_empty() {
+                continue;
+            }
+
+            let parsed = match self.format {
+                Format::Json => parse_json(trimmed),
+                Format::Csv => parse_csv(trimmed),
+            };
+            let (name, k, v) = parsed
+                .and_then(|(tree_name, key, value)| {
+                    Ok((
+                        decode(self.keys, &tree_name)?,
+                        decode(self.keys, &key)?,
+                        decode(self.values, &value)?,
+                    ))
+                })
+                .map_err(|reason| invalid(first_line, reason))?;
+
+            let reuse = match tree {
+                Some((ref last_name, _)) => *last_name == name,
+                None => false,
+            };
+            if !reuse {
+                let opened = db.open_listed_tree(&name)?;
+                tree = Some((name, opened));
+            }
+            let _ = tree.as_ref().unwrap().1.insert(k, v)?;
+            loaded += 1;
+        }
+
+        Ok(loaded)
+    }
+}
+
+fn invalid(line: usize, reason: &str) -> Error {
+    Error::Unsupported(format!(
+        "invalid dump record on line {}: {}",
+        line, reason
+    ))
+}
+
+fn encode(encoding: Encoding, bytes: &[u8]) -> Result<String> {
+    match encoding {
+        Encoding::Hex => {
+            let mut ret = String::with_capacity(bytes.len() * 2);
+            for byte in bytes {
+                ret.push_str(&format!("{:02x}", byte));
+            }
+            Ok(ret)
+        }
+        Encoding::Utf8 => match std::str::from_utf8(bytes) {
+            Ok(s) => Ok(s.to_owned()),
+            Err(_) => Err(Error::Unsupported(format!(
+                "{:?} is not valid UTF-8, use another Encoding",
+                bytes
+            ))),
+        },
+        Encoding::Base64 => {
+            let mut ret = String::with_capacity(bytes.len() / 3 * 4 + 4);
+            for chunk in bytes.chunks(3) {
+                let mut buf = [0; 3];
+                buf[..chunk.len()].copy_from_slice(chunk);
+                let bits = u32::from(buf[0]) << 16
+                    | u32::from(buf[1]) << 8
+                    | u32::from(buf[2]);
+                for i in 0..4 {
+                    if i <= chunk.len() {
+                        let sextet = (bits >> (18 - 6 * i)) & 0x3f;
+                        let index = usize::try_from(sextet).unwrap();
+                        ret.push(char::from(BASE64[index]));
+                    } else {
+                        ret.push('=');
+                    }
+                }
+            }
+            Ok(ret)
+        }
+    }
+}
+
+type Parsed<T> = std::result::Result<T, &'static str>;
+
+fn decode(encoding: Encoding, text: &str) -> Parsed<Vec<u8>> {
+    match encoding {
+        Encoding::Hex => {
+            if text.len() % 2 != 0 {
+                return Err("odd number of hex digits");
+            }
+            text.as_bytes()
+                .chunks(2)
+                .map(|pair| {
+                    std::str::from_utf8(pair)
+                        .ok()
+                        .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                        .ok_or("invalid hex digit")
+                })
+                .collect()
+        }
+        Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+        Encoding::Base64 => {
+            let bytes = text.as_bytes();
+            if bytes.len() % 4 != 0 {
+                return Err("base64 length is not a multiple of 4");
+            }
+            let mut ret = Vec::with_capacity(bytes.len() / 4 * 3);
+            for (i, chunk) in bytes.chunks(4).enumerate() {
+                let last = (i + 1) * 4 == bytes.len();
+                let padding =
+                    chunk.iter().rev().take_while(|b| **b == b'=').count();
+                if padding > 2 || (padding > 0 && !last) {
+                    return Err("invalid base64 padding");
+                }
+                let mut bits = 0_u32;
+                for byte in &chunk[..4 - padding] {
+                    let sextet = match BASE64.iter().position(|b| b == byte) {
+                        Some(sextet) => sextet,
+                        None => return Err("invalid base64 character"),
+                    };
+                    bits = bits << 6 | u32::try_from(sextet).unwrap();
+                }
+                bits <<= 6 * padding;
+                let decoded = bits.to_be_bytes();
+                ret.extend_from_slice(&decoded[1..4 - padding]);
+            }
+            Ok(ret)
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            '\0'..='\u{1f}' => {
+                ret.push_str(&format!("\\u{:04x}", u32::from(c)))
+            }
+            _ => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+type Fields = (String, String, String);
+
+/// Parses an object with exactly the string members `tree`,
+/// `key` and `value`, in any order.
+fn parse_json(record: &str) -> Parsed<Fields> {
+    let mut chars = record.trim().chars().peekable();
+    let (mut tree, mut key, mut value) = (None, None, None);
+
+    if chars.next() != Some('{') {
+        return Err("expected a JSON object");
+    }
+    loop {
+        skip_whitespace(&mut chars);
+        let name = json_parse_string(&mut chars)?;
+        skip_whitespace(&mut chars);
+        if chars.next() != Some(':') {
+            return Err("expected ':'");
+        }
+        skip_whitespace(&mut chars);
+        let member = json_parse_string(&mut chars)?;
+        let slot = match &*name {
+            "tree" => &mut tree,
+            "key" => &mut key,
+            "value" => &mut value,
+            _ => return Err("unknown member"),
+        };
+        if slot.replace(member).is_some() {
+            return Err("duplicate member");
+        }
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            Some(',') => {}
+            Some('}') => break,
+            _ => return Err("expected ',' or '}'"),
+        }
+    }
+    if chars.next().is_some() {
+        return Err("trailing characters after object");
+    }
+
+    match (tree, key, value) {
+        (Some(t), Some(k), Some(v)) => Ok((t, k, v)),
+        _ => Err("missing member"),
+    }
+}
+
+fn skip_whitespace<I>(chars: &mut std::iter::Peekable<I>)
+where
+    I: Iterator<Item = char>,
+{
+    while let Some(' ') | Some('\t') = chars.peek() {
+        let _ = chars.next();
+    }
+}
+
+fn json_parse_string<I: Iterator<Item = char>>(
+    chars: &mut I,
+) -> Parsed<String> {
+    if chars.next() != Some('"') {
+        return Err("expected a JSON string");
+    }
+    let mut ret = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(ret),
+            Some('\\') => {
+                let c = match chars.next() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('/') => '/',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => {
+                        let high = json_parse_hex4(chars)?;
+                        let code = if (0xd800..0xdc00).contains(&high) {
+                            if chars.next() != Some('\\')
+                                || chars.next() != Some('u')
+                            {
+                                return Err("unpaired surrogate");
+                            }
+                            let low = json_parse_hex4(chars)?;
+                            if !(0xdc00..0xe000).contains(&low) {
+                                return Err("unpaired surrogate");
+                            }
+                            0x10000
+                                + ((high - 0xd800) << 10)
+                                + (low - 0xdc00)
+                        } else {
+                            high
+                        };
+                        std::char::from_u32(code)
+                            .ok_or("invalid \\u escape")?
+                    }
+                    _ => return Err("invalid escape"),
+                };
+                ret.push(c);
+            }
+            Some(c) => ret.push(c),
+            None => return Err("unterminated string"),
+        }
+    }
+}
+
+fn json_parse_hex4<I: Iterator<Item = char>>(chars: &mut I) -> Parsed<u32> {
+    let mut ret = 0;
+    for _ in 0..4 {
+        let digit = chars
+            .next()
+            .and_then(|c| c.to_digit(16))
+            .ok_or("invalid \\u escape")?;
+        ret = ret << 4 | digit;
+    }
+    Ok(ret)
+}
+
+fn parse_csv(record: &str) -> Parsed<Fields> {
+    let mut fields = vec![];
+    let mut chars = record.chars().peekable();
+    loop {
+        let mut field = String::new();
+        if chars.peek() == Some(&'"') {
+            let _ = chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => {
+                        if chars.peek() == Some(&'"') {
+                            let _ = chars.next();
+                            field.push('"');
+                        } else {
+                            break;
+                        }
+                    }
+                    Some(c) => field.push(c),
+                    None => return Err("unterminated quote"),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ',' {
+                    break;
+                }
+                if c == '"' {
+                    return Err("quote in unquoted field");
+                }
+                field.push(c);
+                let _ = chars.next();
+            }
+        }
+        fields.push(field);
+        match chars.next() {
+            Some(',') => {}
+            None => break,
+            Some(_) => return Err("expected ',' after quoted field"),
+        }
+    }
+
+    if fields.len() != 3 {
+        return Err("expected 3 fields");
+    }
+    let value = fields.pop().unwrap();
+    let key = fields.pop().unwrap();
+    let tree = fields.pop().unwrap();
+    Ok((tree, key, value))
+}
+
+#[test]
+fn encodings_roundtrip() {
+    for len in 0..8_u8 {
+        let bytes: Vec<u8> = (0..len).map(|i| i.wrapping_mul(97)).collect();
+        for &encoding in &[Encoding::Hex, Encoding::Base64] {
+            let text = encode(encoding, &bytes).unwrap();
+            assert_eq!(decode(encoding, &text).unwrap(), bytes);
+        }
+    }
+    assert_eq!(encode(Encoding::Base64, b"sled").unwrap(), "c2xlZA==");
+    assert_eq!(decode(Encoding::Hex, "0A").unwrap(), vec![10]);
+    assert!(decode(Encoding::Base64, "c2x=ZA==").is_err());
+}
@@ -0,0 +1,80 @@
+//! Encryption at rest, see `Config::encryption`.
+//!
+//! Every message written to the log or the heap, every snapshot,
+//! and every node written to the secondary cache is passed
+//! through the configured `KeyProvider` after any compression
+//! has been applied, and stored as the id of the key that was
+//! used followed by the ciphertext.
+//! Checksums are computed over the stored bytes, so they can
+//! be verified without any keys. Segment and message headers,
+//! which only contain offsets, lengths, page ids and message
+//! kinds, are not encrypted.
+use std::convert::TryInto;
+
+use crate::*;
+
+/// Encrypts and decrypts the data that sled writes to disk.
+///
+/// Implementations should use an authenticated cipher such
+/// as AES-GCM, generating a unique nonce for every call to
+/// `encrypt` and storing it in the returned ciphertext, and
+/// should return an error from `decrypt` if authentication
+/// fails or the key is unknown.
+///
+/// Keys may be rotated by changing the id that is returned
+/// from `current_key_id`. New writes use the current key,
+/// and data written with an older key is re-encrypted with
+/// the current one whenever it's rewritten, which happens
+/// continuously as pages are consolidated and segments are
+/// cleaned. Older keys must remain available for decryption
+/// until nothing written with them is left on disk.
+///
+/// Exports and backups are written in plaintext.
+pub trait KeyProvider: Send + Sync {
+    /// The id of the key that new data is encrypted with.
+    fn current_key_id(&self) -> u32;
+
+    /// Encrypts `plaintext` with the key identified by `key_id`.
+    fn encrypt(&self, key_id: u32, plaintext: &[u8]) -> Result<Vec<u8>>;
+
+    /// Decrypts `ciphertext` that was returned by `encrypt` for
+    /// the key identified by `key_id`.
+    fn decrypt(&self, key_id: u32, ciphertext: &[u8]) -> Result<Vec<u8>>;
+}
+
+#[derive(Clone)]
+pub(crate) struct Encryption(Arc<dyn KeyProvider>);
+
+impl Debug for Encryption {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> std::result::Result<(), fmt::Error> {
+        write!(f, "Encryption")
+    }
+}
+
+impl Encryption {
+    pub(crate) fn new<K: KeyProvider + 'static>(provider: K) -> Encryption {
+        Encryption(Arc::new(provider))
+    }
+
+    pub(crate) fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let key_id = self.0.current_key_id();
+        let ciphertext = self.0.encrypt(key_id, plaintext)?;
+
+        let mut ret = Vec::with_capacity(4 + ciphertext.len());
+        ret.extend_from_slice(&key_id.to_le_bytes());
+        ret.extend_from_slice(&ciphertext);
+        Ok(ret)
+    }
+
+    pub(crate) fn decrypt(&self, buf: &[u8]) -> Result<Vec<u8>> {
+        if buf.len() < 4 {
+            return Err(Error::corruption(None));
+        }
+        let (key_id, ciphertext) = buf.split_at(4);
+        let key_id = u32::from_le_bytes(key_id.try_into().unwrap());
+        self.0.decrypt(key_id, ciphertext)
+    }
+}
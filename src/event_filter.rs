@@ -0,0 +1,149 @@
+//! Filters that subscribers are registered with, see
+//! `Tree::watch_prefix_with`.
+//!
+//! A filter is evaluated while a write reserves a place in the
+//! queue of each subscriber that watches one of its keys, so a
+//! subscriber that the filter rejects the write for neither gets
+//! a place in its queue nor a copy of the event. The previous
+//! values of the keys of an event are only read if one of the
+//! subscribers that reserved a place for it asked for them.
+use std::{fmt, ops::BitOr};
+
+use crate::*;
+
+type KeyPredicate = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+/// The kinds of writes that an `EventFilter` lets through, which
+/// can be combined with `|`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventKinds(u8);
+
+impl EventKinds {
+    /// Writes that set a key to a value.
+    pub const INSERT: EventKinds = EventKinds(0b01);
+    /// Writes that remove a key.
+    pub const REMOVE: EventKinds = EventKinds(0b10);
+    /// Writes of every kind.
+    pub const ALL: EventKinds = EventKinds(0b11);
+
+    /// Returns `true` if every kind in `other` is also in `self`.
+    pub fn contains(self, other: EventKinds) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Default for EventKinds {
+    fn default() -> EventKinds {
+        EventKinds::ALL
+    }
+}
+
+impl BitOr for EventKinds {
+    type Output = EventKinds;
+
+    fn bitor(self, other: EventKinds) -> EventKinds {
+        EventKinds(self.0 | other.0)
+    }
+}
+
+/// The writes that a subscriber receives events for, and what
+/// the events carry, see `Tree::watch_prefix_with`.
+///
+/// An event is only sent to the subscriber if at least one of
+/// its writes to a key that starts with the watched prefix
+/// passes every condition of the filter. The event still holds
+/// all of its writes. The default filter lets everything
+/// through, without previous values.
+#[derive(Clone, Default)]
+pub struct EventFilter {
+    kinds: EventKinds,
+    key_predicate: Option<KeyPredicate>,
+    min_value_len: usize,
+    pub(crate) previous_values: bool,
+}
+
+impl Debug for EventFilter {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> std::result::Result<(), fmt::Error> {
+        f.debug_struct("EventFilter")
+            .field("kinds", &self.kinds)
+            .field("key_predicate", &self.key_predicate.is_some())
+            .field("min_value_len", &self.min_value_len)
+            .field("previous_values", &self.previous_values)
+            .finish()
+    }
+}
+
+impl EventFilter {
+    /// Returns the default filter, which lets every write through.
+    pub fn new() -> EventFilter {
+        EventFilter::default()
+    }
+
+    /// Only lets through writes of these kinds.
+    pub fn kinds(mut self, kinds: EventKinds) -> EventFilter {
+        self.kinds = kinds;
+        self
+    }
+
+    /// Only lets through writes to keys that `key_predicate`
+    /// returns `true` for. It is called by the writing thread,
+    /// with the key as it was passed to the `Tree`, and should
+    /// be quick.
+    pub fn key_predicate<F>(mut self, key_predicate: F) -> EventFilter
+    where
+        F: Fn(&[u8]) -> bool + Send + Sync + 'static,
+    {
+        self.key_predicate = Some(Arc::new(key_predicate));
+        self
+    }
+
+    /// Only lets through insertions of values that are at least
+    /// this many bytes long. Removals are not affected.
+    pub fn min_value_len(mut self, min_value_len: usize) -> EventFilter {
+        self.min_value_len = min_value_len;
+        self
+    }
+
+    /// Makes the events carry the values that their keys held
+    /// before they were written, see `Event::iter_with_previous`,
+    /// so that the subscriber doesn't have to keep a copy of the
+    /// tree to tell what changed. Most writes already know the
+    /// previous value, but those of the keys that a transaction
+    /// writes, and values that are kept in the value log, are
+    /// read for it.
+    pub fn previous_values(mut self, previous_values: bool) -> EventFilter {
+        self.previous_values = previous_values;
+        self
+    }
+
+    // returns `true` if the write of a value of `value_len` bytes,
+    // or the removal if it is `None`, to the stored `key` of a
+    // tree with the `order` passes the filter
+    pub(crate) fn admits(
+        &self,
+        order: KeyOrder,
+        key: &[u8],
+        value_len: Option<usize>,
+    ) -> bool {
+        let kind = match value_len {
+            Some(len) if len < self.min_value_len => return false,
+            Some(_) => EventKinds::INSERT,
+            None => EventKinds::REMOVE,
+        };
+        if !self.kinds.contains(kind) {
+            return false;
+        }
+        if let Some(ref key_predicate) = self.key_predicate {
+            if order == KeyOrder::Lexicographic {
+                key_predicate(key)
+            } else {
+                key_predicate(&order.decode(IVec::from(key)))
+            }
+        } else {
+            true
+        }
+    }
+}
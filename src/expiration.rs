@@ -0,0 +1,369 @@
+//! Support for keys that expire after a time-to-live.
+//!
+//! Expiration deadlines for a `Tree` are stored in a hidden
+//! companion `Tree` that is created the first time a key is
+//! written using `Tree::insert_with_ttl`. The companion
+//! keeps two indexes:
+//!
+//! * `[BY_KEY] ++ key` -> big-endian deadline, used to filter
+//!   expired keys out of reads
+//! * `[BY_DEADLINE] ++ deadline ++ key` -> empty, used by the
+//!   sweeper to find expired keys in deadline order
+//!
+//! Expired keys are hidden from reads as soon as their deadline
+//! passes, and are physically removed by `Tree::sweep_expired`,
+//! which one background thread of the `Db` runs for every tree
+//! with a companion every `Config::expiration_sweep_every_ms`.
+//! The space used by swept keys is then reclaimed by the usual
+//! segment cleaning.
+//!
+//! A deadline is cleared when its key is rewritten or removed,
+//! within the same pin of the log as the write, so that the two
+//! are recovered atomically.
+use std::{
+    convert::TryInto,
+    sync::{atomic::AtomicBool, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::*;
+
+const EXPIRATION_TREE_PREFIX: &[u8] = b"__sled__expirations__";
+
+const BY_KEY: u8 = 0;
+const BY_DEADLINE: u8 = 1;
+
+/// The maximum number of expired keys that are removed
+/// while holding the read lock and pinning the log during a sweep.
+const SWEEP_CHUNK: usize = 128;
+
+pub(crate) fn is_expiration_tree_name(name: &[u8]) -> bool {
+    name.starts_with(EXPIRATION_TREE_PREFIX)
+}
+
+pub(crate) fn parent_tree_name(name: &[u8]) -> Option<&[u8]> {
+    if is_expiration_tree_name(name) {
+        Some(&name[EXPIRATION_TREE_PREFIX.len()..])
+    } else {
+        None
+    }
+}
+
+pub(crate) fn expiration_tree_name(tree_id: &[u8]) -> IVec {
+    let mut name = EXPIRATION_TREE_PREFIX.to_vec();
+    name.extend_from_slice(tree_id);
+    name.into()
+}
+
+pub(crate) fn now_millis() -> u64 {
+    let since_epoch =
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    u64::try_from(since_epoch.as_millis()).unwrap_or(u64::max_value())
+}
+
+fn by_key(key: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(1 + key.len());
+    ret.push(BY_KEY);
+    ret.extend_from_slice(key);
+    ret
+}
+
+fn by_deadline(deadline: u64, key: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(9 + key.len());
+    ret.push(BY_DEADLINE);
+    ret.extend_from_slice(&deadline.to_be_bytes());
+    ret.extend_from_slice(key);
+    ret
+}
+
+fn decode_deadline(raw: &[u8]) -> u64 {
+    u64::from_be_bytes(raw.try_into().expect("corrupt expiration deadline"))
+}
+
+/// The trees of a `Db` that have expiring keys, which are swept
+/// by a single thread that is started when the first of them is
+/// registered.
+#[derive(Debug, Default)]
+pub(crate) struct Sweeper {
+    trees: Mutex<Vec<Weak<TreeInner>>>,
+    started: AtomicBool,
+}
+
+impl Sweeper {
+    // returns the registered trees that are still alive, forgetting
+    // the ones that were dropped
+    fn live(&self) -> Vec<Tree> {
+        let mut trees = self.trees.lock();
+        let mut ret = vec![];
+        trees.retain(|weak| {
+            if let Some(inner) = weak.upgrade() {
+                ret.push(Tree(inner));
+                true
+            } else {
+                false
+            }
+        });
+        ret
+    }
+}
+
+/// Registers a `Tree` to have its expired keys swept until it is
+/// dropped, starting the sweeper of its `Db` if it isn't running.
+/// Only a weak reference is held between sweeps so that the
+/// sweeper never keeps a `Tree` or the `Db` alive on its own.
+fn start_sweeper(tree: &Tree) {
+    let every_ms = if let (Some(every_ms), false) =
+        (tree.context.expiration_sweep_every_ms, tree.context.read_only)
+    {
+        every_ms
+    } else {
+        return;
+    };
+
+    let sweeper = &tree.context.sweeper;
+    sweeper.trees.lock().push(Arc::downgrade(&tree.0));
+    if sweeper.started.swap(true, SeqCst) {
+        return;
+    }
+
+    let weak: Weak<Sweeper> = Arc::downgrade(sweeper);
+
+    let spawned =
+        background::spawn(&tree.context, "expiration-sweeper", move || loop {
+            std::thread::sleep(Duration::from_millis(every_ms));
+
+            let trees = if let Some(sweeper) = weak.upgrade() {
+                sweeper.live()
+            } else {
+                return;
+            };
+
+            for tree in trees {
+                match sweep(&tree) {
+                    Ok(0) => {}
+                    Ok(swept) => {
+                        debug!(
+                            "swept {} expired keys from tree {:?}",
+                            swept, tree.tree_id
+                        );
+                    }
+                    Err(e) => {
+                        error!("failed to sweep expired keys: {:?}", e);
+                        return;
+                    }
+                }
+            }
+        });
+
+    if let Err(e) = spawned {
+        error!("failed to spawn expiration sweeper thread: {:?}", e);
+    }
+}
+
+/// Inserts a value and records its deadline, see `Tree::insert_with_ttl`.
+pub(crate) fn insert_with_deadline(
+    tree: &Tree,
+    key: &[u8],
+    value: IVec,
+    ttl: Duration,
+) -> Result<Option<IVec>> {
+    let ttl_ms = u64::try_from(ttl.as_millis()).unwrap_or(u64::max_value());
+    let deadline = now_millis().saturating_add(ttl_ms);
+
+    let expirations = expiration_tree(tree)?;
+
+    let mut guard = pin();
+    let _cc = concurrency_control::read();
+    let _indexed = index::lock(tree);
+
+    // the value and its deadline are recovered atomically
+    let peg = tree.context.pin_log(&guard)?;
+
+    // `insert_inner` also clears any previous deadline
+    let last_value = tree.set_inner(key, Some(value), &mut guard)?;
+
+    let encoded_deadline = Some(IVec::from(&deadline.to_be_bytes()));
+    let _ =
+        expirations.set_inner(&by_key(key), encoded_deadline, &mut guard)?;
+    let _ = expirations.set_inner(
+        &by_deadline(deadline, key),
+        Some(IVec::default()),
+        &mut guard,
+    )?;
+
+    peg.seal_batch()?;
+
+    Ok(last_value)
+}
+
+/// Removes expired keys, see `Tree::sweep_expired`.
+pub(crate) fn sweep(tree: &Tree) -> Result<usize> {
+    let expirations =
+        if let Some(expirations) = tree.expirations.read().clone() {
+            expirations
+        } else {
+            return Ok(0);
+        };
+
+    let mut lo = vec![BY_DEADLINE];
+    let hi = by_deadline(now_millis().saturating_add(1), &[]);
+
+    let mut swept = 0;
+
+    loop {
+        // like other single-key writes, a sweep only holds the
+        // read lock, and the writer lock of a tree with indexes
+        // or a history, so that writes to other trees go on
+        let _cc = concurrency_control::read();
+        let _indexed = index::lock(tree);
+        let mut guard = pin();
+
+        let mut entries = vec![];
+        let mut iter = expirations.range(lo.as_slice()..hi.as_slice());
+        while entries.len() < SWEEP_CHUNK {
+            if let Some(res) = iter.next_inner() {
+                let (k, _v) = res?;
+                entries.push(k);
+            } else {
+                break;
+            }
+        }
+
+        // keys that were rewritten meanwhile are skipped rather
+        // than removed, so the next chunk starts after this one
+        if let Some(last) = entries.last() {
+            lo = last.to_vec();
+            lo.push(0);
+        } else {
+            return Ok(swept);
+        }
+
+        let peg = tree.context.pin_log(&guard)?;
+
+        for entry in &entries {
+            if remove_expired(tree, &entry[9..], &mut guard)? {
+                swept += 1;
+            }
+        }
+
+        peg.seal_batch()?;
+    }
+}
+
+// removes a key like `Tree::remove` as long as it's expired, which
+// also clears both of its expiration index entries. a key that was
+// rewritten has had its deadline cleared, so it is left alone, and
+// the check is repeated whenever a write to the same node got in
+// the way of the removal
+fn remove_expired(tree: &Tree, key: &[u8], guard: &mut Guard) -> Result<bool> {
+    loop {
+        if !is_expired(tree, key, guard)? {
+            return Ok(false);
+        }
+        if tree.insert_inner(key, None, false, guard)?.is_ok() {
+            return Ok(true);
+        }
+    }
+}
+
+/// Returns the number of keys whose deadline has passed but
+/// which haven't been swept yet, which `Tree::len` doesn't count.
+pub(crate) fn expired_len(tree: &Tree) -> Result<usize> {
+    let expirations =
+        if let Some(expirations) = tree.expirations.read().clone() {
+            expirations
+        } else {
+            return Ok(0);
+        };
+
+    let lo = vec![BY_DEADLINE];
+    let hi = by_deadline(now_millis().saturating_add(1), &[]);
+
+    let mut ret = 0;
+    for res in expirations.range(lo.as_slice()..hi.as_slice()).keys() {
+        let _ = res?;
+        ret += 1;
+    }
+    Ok(ret)
+}
+
+/// Returns `true` if the key has a deadline that has passed.
+pub(crate) fn is_expired(
+    tree: &Tree,
+    key: &[u8],
+    guard: &Guard,
+) -> Result<bool> {
+    let expirations =
+        if let Some(expirations) = tree.expirations.read().clone() {
+            expirations
+        } else {
+            return Ok(false);
+        };
+
+    let k = by_key(key);
+    let view = expirations.view_for_key(&k, guard)?;
+    let deadline = view.node_kv_pair(&k).1.map(decode_deadline);
+
+    Ok(deadline.map_or(false, |deadline| deadline <= now_millis()))
+}
+
+/// Clears the deadline for a key after it has been
+/// rewritten or removed, returning `true` if the
+/// previous value had already expired.
+pub(crate) fn clear(tree: &Tree, key: &[u8]) -> Result<bool> {
+    let expirations =
+        if let Some(expirations) = tree.expirations.read().clone() {
+            expirations
+        } else {
+            return Ok(false);
+        };
+
+    let mut guard = pin();
+
+    let deadline = if let Some(raw) =
+        expirations.set_inner(&by_key(key), None, &mut guard)?
+    {
+        decode_deadline(&raw)
+    } else {
+        return Ok(false);
+    };
+
+    let by_deadline = by_deadline(deadline, key);
+    let _ = expirations.set_inner(&by_deadline, None, &mut guard)?;
+
+    Ok(deadline <= now_millis())
+}
+
+/// Attaches an existing companion `Tree` to its parent
+/// during startup.
+pub(crate) fn attach(tree: &Tree, expirations: Tree) {
+    *tree.expirations.write() = Some(expirations);
+    start_sweeper(tree);
+}
+
+/// Returns the companion `Tree` holding expiration deadlines,
+/// creating it and starting its sweeper if necessary.
+fn expiration_tree(tree: &Tree) -> Result<Tree> {
+    if let Some(expirations) = tree.expirations.read().clone() {
+        return Ok(expirations);
+    }
+
+    let mut expirations = tree.expirations.write();
+    if let Some(expirations) = expirations.clone() {
+        return Ok(expirations);
+    }
+
+    let guard = pin();
+    let companion = meta::open_tree(
+        &tree.context,
+        expiration_tree_name(&tree.tree_id),
+        None,
+        &guard,
+    )?;
+    *expirations = Some(companion.clone());
+    drop(expirations);
+
+    start_sweeper(tree);
+
+    Ok(companion)
+}
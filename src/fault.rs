@@ -0,0 +1,76 @@
+//! Reporting of the faults that sled runs into while reading
+//! and writing its files, see `Config::on_fault`.
+//!
+//! Faults are reported from the thread that ran into them, as
+//! they happen. Reads of pages report every error that they
+//! return, and an error that stops the `Db` is reported once,
+//! when it is first recorded, along with every fault found by
+//! the background scrub and every error that prevents the
+//! snapshot from being recovered.
+use std::fmt;
+
+use crate::*;
+
+/// A fault that was reported to the callback of
+/// `Config::on_fault`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+    /// The page that was being read, if the fault happened
+    /// while reading one.
+    pub pid: Option<u64>,
+    /// What went wrong. Checksum mismatches and data that can't
+    /// be decoded are `Error::Corruption`, failed or short reads
+    /// and writes are `Error::Io`, and writes to a full storage
+    /// device are `Error::NoSpace`.
+    pub error: Error,
+    /// Whether the fault stopped the `Db`, so that every later
+    /// operation on it returns the error.
+    pub fatal: bool,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.fatal {
+            write!(f, "fatal ")?;
+        }
+        write!(f, "fault")?;
+        if let Some(pid) = self.pid {
+            write!(f, " reading page {}", pid)?;
+        }
+        write!(f, ": {}", self.error)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct FaultHandler(Arc<dyn Fn(&Fault) + Send + Sync>);
+
+impl Debug for FaultHandler {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> std::result::Result<(), fmt::Error> {
+        write!(f, "FaultHandler")
+    }
+}
+
+impl FaultHandler {
+    pub(crate) fn new<F>(handler: F) -> FaultHandler
+    where
+        F: Fn(&Fault) + Send + Sync + 'static,
+    {
+        FaultHandler(Arc::new(handler))
+    }
+
+    pub(crate) fn report(&self, fault: &Fault) {
+        (self.0)(fault)
+    }
+}
+
+/// Returns `true` for the errors that are reported as faults,
+/// rather than being caused by how sled was used.
+pub(crate) fn is_fault(error: &Error) -> bool {
+    match error {
+        Error::Corruption { .. } | Error::Io(_) | Error::NoSpace => true,
+        _ => false,
+    }
+}
@@ -1,10 +1,13 @@
-use std::thread;
 use std::time::Duration;
 
 use parking_lot::{Condvar, Mutex};
 
 use super::*;
 
+// how often the background threads clean segments under the
+// sync policies that don't flush from them
+const CLEAN_EVERY_MS: u64 = 500;
+
 #[derive(Debug, Clone, Copy)]
 pub(crate) enum ShutdownState {
     Running,
@@ -37,25 +40,132 @@ pub(crate) struct Flusher {
     join_handle: Mutex<Option<std::thread::JoinHandle<()>>>,
 }
 
+// pauses the flush thread often enough to keep to
+// `Config::background_io_limit` and `background_cpu_limit`
+#[derive(Debug)]
+struct Throttle {
+    io_limit: Option<u64>,
+    cpu_limit: Option<u8>,
+    owed: Duration,
+}
+
+impl Throttle {
+    // pauses shorter than this are saved up rather than slept
+    const MIN_PAUSE: Duration = Duration::from_millis(1);
+
+    fn new(config: &RunningConfig) -> Throttle {
+        // the io limit is shared by every background thread
+        let threads = config.background_threads as u64;
+        Throttle {
+            io_limit: config
+                .background_io_limit
+                .map(|limit| std::cmp::max(limit / threads, 1)),
+            cpu_limit: config.background_cpu_limit,
+            owed: Duration::from_secs(0),
+        }
+    }
+
+    /// Records that `bytes` were written in `busy`, and returns
+    /// how long to pause for once enough has been saved up.
+    fn pause_after(&mut self, bytes: u64, busy: Duration) -> Option<Duration> {
+        let mut pause = Duration::from_secs(0);
+        if let Some(io_limit) = self.io_limit {
+            let paced = Duration::from_micros(
+                bytes.saturating_mul(1_000_000) / io_limit,
+            );
+            if let Some(ahead) = paced.checked_sub(busy) {
+                pause = ahead;
+            }
+        }
+        if let Some(cpu_limit) = self.cpu_limit {
+            // busy / (busy + idle) stays at most cpu_limit / 100
+            let percent = u32::from(cpu_limit);
+            let idle = busy * (100 - percent) / percent;
+            pause = std::cmp::max(pause, idle);
+        }
+
+        self.owed += pause;
+        if self.owed < Self::MIN_PAUSE {
+            return None;
+        }
+        Some(std::mem::replace(&mut self.owed, Duration::from_secs(0)))
+    }
+}
+
+/// Starts the background threads that the current sync policy
+/// calls for, after stopping the ones that were started for the
+/// policy before it.
+pub(crate) fn start(context: &Context) {
+    let mut flusher = context.flusher.lock();
+    let mut cleaners = context.cleaners.lock();
+
+    // the flush thread flushes what is left before it stops
+    *flusher = None;
+    cleaners.clear();
+
+    if context.read_only {
+        return;
+    }
+    let (every_ms, flushes) = match context.current_sync_policy() {
+        SyncPolicy::EveryNMillis(every_ms) => (every_ms, true),
+        SyncPolicy::EveryWrite | SyncPolicy::EveryNBytes(_) => {
+            (CLEAN_EVERY_MS, false)
+        }
+        SyncPolicy::OsManaged => return,
+    };
+
+    let pagecache = &context.pagecache;
+    *flusher = Some(if flushes {
+        Flusher::new("flusher", pagecache.clone(), every_ms)
+    } else {
+        Flusher::cleaner("flusher", pagecache.clone(), every_ms)
+    });
+    *cleaners = (1..context.background_threads)
+        .map(|i| {
+            let role = format!("cleaner-{}", i);
+            Flusher::cleaner(&role, pagecache.clone(), every_ms)
+        })
+        .collect();
+}
+
 impl Flusher {
-    /// Spawns a thread that periodically calls `callback` until dropped.
+    /// Spawns a thread that periodically flushes the log and
+    /// cleans segments until dropped.
     pub(crate) fn new(
-        name: String,
+        role: &str,
         pagecache: PageCache,
         flush_every_ms: u64,
+    ) -> Self {
+        Flusher::spawn(role, pagecache, flush_every_ms, true)
+    }
+
+    /// Spawns a thread that periodically cleans segments, without
+    /// flushing the log, until dropped.
+    pub(crate) fn cleaner(
+        role: &str,
+        pagecache: PageCache,
+        clean_every_ms: u64,
+    ) -> Self {
+        Flusher::spawn(role, pagecache, clean_every_ms, false)
+    }
+
+    fn spawn(
+        role: &str,
+        pagecache: PageCache,
+        every_ms: u64,
+        flushes: bool,
     ) -> Self {
         #[allow(clippy::mutex_atomic)] // mutex used in CondVar below
         let shutdown = Arc::new(Mutex::new(ShutdownState::Running));
         let sc = Arc::new(Condvar::new());
 
-        let join_handle = thread::Builder::new()
-            .name(name)
-            .spawn({
-                let shutdown = shutdown.clone();
-                let sc = sc.clone();
-                move || run(&shutdown, &sc, &pagecache, flush_every_ms)
-            })
-            .unwrap();
+        let config = pagecache.config.clone();
+        let join_handle = background::spawn(&config, role, {
+            let shutdown = shutdown.clone();
+            let sc = sc.clone();
+            move || run(&shutdown, &sc, &pagecache, every_ms, flushes)
+        })
+        .unwrap();
 
         Self { shutdown, sc, join_handle: Mutex::new(Some(join_handle)) }
     }
@@ -66,21 +176,26 @@ fn run(
     sc: &Arc<Condvar>,
     pagecache: &PageCache,
     flush_every_ms: u64,
+    flushes: bool,
 ) {
     let flush_every = Duration::from_millis(flush_every_ms);
+    let mut throttle = Throttle::new(&pagecache.config);
     let mut shutdown = shutdown.lock();
     let mut wrote_data = false;
     while shutdown.is_running() || wrote_data {
         let before = std::time::Instant::now();
+        let mut flushed = 0;
         let cc = concurrency_control::read();
-        match pagecache.log.roll_iobuf() {
+        // cleaners leave the flushing to the flush thread
+        let rolled = if flushes { pagecache.log.roll_iobuf() } else { Ok(0) };
+        match rolled {
             Ok(0) => {
                 wrote_data = false;
                 if !shutdown.is_running() {
                     break;
                 }
             }
-            Ok(_) => {
+            Ok(written) => {
                 wrote_data = true;
                 if !shutdown.is_running() {
                     // loop right away if we're in
@@ -88,6 +203,12 @@ fn run(
                     // more quickly.
                     continue;
                 }
+                flushed = written as u64;
+            }
+            Err(Error::NoSpace) => {
+                // the write is retried until space is freed, and the
+                // thread that waits for it reports the condition
+                wrote_data = false;
             }
             Err(e) => {
                 error!("failed to flush from periodic flush thread: {}", e);
@@ -107,15 +228,23 @@ fn run(
         }
         drop(cc);
 
+        if let Some(pause) = throttle.pause_after(flushed, before.elapsed()) {
+            sc.wait_for(&mut shutdown, pause);
+        }
+
         // so we can spend a little effort
         // cleaning up the segments. try not to
         // spend more than half of our sleep
-        // time rewriting pages though.
+        // time rewriting pages though, unless
+        // cleaning is all that this thread does.
         //
         // this looks weird because it's a rust-style do-while
         // where the conditional is the full body
         while {
+            let gc_started = std::time::Instant::now();
             let made_progress = match pagecache.attempt_gc() {
+                // a full device is waited on by the writes
+                Err(Error::NoSpace) | Ok(None) => false,
                 Err(e) => {
                     error!(
                         "failed to clean file from periodic flush thread: {}",
@@ -134,16 +263,23 @@ fn run(
                     let _notified = sc.notify_all();
                     return;
                 }
-                Ok(false) => false,
-                Ok(true) => true,
+                Ok(Some(moved)) => {
+                    let busy = gc_started.elapsed();
+                    if let Some(pause) = throttle.pause_after(moved, busy) {
+                        sc.wait_for(&mut shutdown, pause);
+                    }
+                    true
+                }
             };
             made_progress
                 && shutdown.is_running()
-                && before.elapsed() < flush_every / 2
+                && (!flushes || before.elapsed() < flush_every / 2)
         } {}
 
-        if let Err(e) = pagecache.config.file.sync_all() {
-            error!("failed to fsync from periodic flush thread: {}", e);
+        if flushes {
+            if let Err(e) = pagecache.config.file.sync_all() {
+                error!("failed to fsync from periodic flush thread: {}", e);
+            }
         }
 
         let sleep_duration = flush_every
@@ -192,3 +328,34 @@ impl Drop for Flusher {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle(io_limit: Option<u64>, cpu_limit: Option<u8>) -> Throttle {
+        Throttle { io_limit, cpu_limit, owed: Duration::from_secs(0) }
+    }
+
+    #[test]
+    fn throttle_pauses_to_limits() {
+        let ms = Duration::from_millis;
+
+        let mut io = throttle(Some(1000), None);
+        assert_eq!(io.pause_after(100, ms(0)), Some(ms(100)));
+        assert_eq!(io.pause_after(100, ms(40)), Some(ms(60)));
+        assert_eq!(io.pause_after(100, ms(200)), None);
+
+        let mut cpu = throttle(None, Some(25));
+        assert_eq!(cpu.pause_after(0, ms(10)), Some(ms(30)));
+        assert_eq!(throttle(None, Some(100)).pause_after(0, ms(10)), None);
+
+        // short pauses are saved up until they are worth sleeping
+        let mut small = throttle(Some(1_000_000), None);
+        assert_eq!(small.pause_after(600, ms(0)), None);
+        let saved = small.pause_after(600, ms(0));
+        assert_eq!(saved, Some(Duration::from_micros(1200)));
+
+        assert_eq!(throttle(None, None).pause_after(1 << 30, ms(1)), None);
+    }
+}
@@ -0,0 +1,263 @@
+//! The events that a `Tree` retains so that they can be replayed
+//! to subscribers, see `Config::event_history` and
+//! `Tree::watch_prefix_since`.
+//!
+//! The history of a `Tree` is stored in a hidden companion `Tree`
+//! that is attached to it when it is opened:
+//!
+//! * `[EVENT] ++ big-endian sequence number` -> the writes of
+//!   the event to the tree, as a serialized `Batch` of keys that
+//!   are decoded for the `KeyOrder` of the tree
+//! * `[TRIMMED]` -> the big-endian sequence number of the newest
+//!   event that is missing from the history, because it was
+//!   removed to keep the history to its size or because it
+//!   happened before the history was created
+//!
+//! An event is recorded while the log is pinned for the writes
+//! that it was made for, so that it is recovered along with them.
+//! Writes to a tree with a history hold the writer lock of the
+//! tree, as writes to a tree with indexes do, so that its events
+//! are recorded and sent to subscribers in the order of their
+//! sequence numbers.
+use std::{
+    collections::VecDeque,
+    convert::{TryFrom, TryInto},
+};
+
+use crate::{subscriber::ReservedBroadcast, *};
+
+const HISTORY_TREE_PREFIX: &[u8] = b"__sled__history__";
+
+const EVENT: u8 = 0;
+const TRIMMED: u8 = 1;
+
+/// The retained history of a `Tree`.
+pub(crate) struct History {
+    pub(crate) tree: Tree,
+    // the number of events that are retained, which is kept here
+    // because `Tree::len` can't be called while writing
+    len: AtomicUsize,
+}
+
+pub(crate) fn is_history_tree_name(name: &[u8]) -> bool {
+    name.starts_with(HISTORY_TREE_PREFIX)
+}
+
+pub(crate) fn parent_tree_name(name: &[u8]) -> Option<&[u8]> {
+    if is_history_tree_name(name) {
+        Some(&name[HISTORY_TREE_PREFIX.len()..])
+    } else {
+        None
+    }
+}
+
+pub(crate) fn history_tree_name(tree_id: &[u8]) -> IVec {
+    let mut name = HISTORY_TREE_PREFIX.to_vec();
+    name.extend_from_slice(tree_id);
+    name.into()
+}
+
+fn event_key(seq: u64) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(9);
+    ret.push(EVENT);
+    ret.extend_from_slice(&seq.to_be_bytes());
+    ret
+}
+
+fn decode_seq(raw: &[u8]) -> Result<u64> {
+    let bytes = raw.try_into().map_err(|_| Error::corruption(None))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Attaches a companion `Tree` that was loaded during startup to
+/// its parent.
+pub(crate) fn attach(tree: &Tree, history: Tree) -> Result<()> {
+    let mut len = 0;
+    let mut iter = history.range(vec![EVENT]..vec![TRIMMED]);
+    iter.parts = iter::Parts::Keys;
+    while let Some(res) = iter.next_inner() {
+        let _ = res?;
+        len += 1;
+    }
+
+    *tree.history.write() =
+        Some(Arc::new(History { tree: history, len: AtomicUsize::new(len) }));
+    Ok(())
+}
+
+/// Creates the companion `Tree` of a tree that doesn't have one
+/// yet.
+pub(crate) fn open(tree: &Tree) -> Result<()> {
+    if tree.history.read().is_some() || tree.context.read_only {
+        return Ok(());
+    }
+
+    let mut history = tree.history.write();
+    if history.is_some() {
+        return Ok(());
+    }
+
+    let guard = pin();
+    let companion = meta::open_tree(
+        &tree.context,
+        history_tree_name(&tree.tree_id),
+        None,
+        &guard,
+    )?;
+
+    // the events of the keys that the tree already holds were
+    // never recorded
+    if !tree.is_empty() {
+        let log = &tree.context.pagecache.log;
+        let last = seq(log.iobufs.max_reserved_lsn.load(Acquire));
+        let marker = IVec::from(&last.to_be_bytes());
+        let _ = companion.insert([TRIMMED], marker)?;
+    }
+
+    *history =
+        Some(Arc::new(History { tree: companion, len: AtomicUsize::new(0) }));
+    Ok(())
+}
+
+/// Records an event in the history of a tree, if it has one, and
+/// hands it to the subscribers that it was reserved for. The
+/// event is only made if it is needed, and is told whether it has
+/// to carry the previous values of its keys.
+pub(crate) fn publish<F>(
+    tree: &Tree,
+    reservation: Option<ReservedBroadcast>,
+    make_event: F,
+) -> Result<()>
+where
+    F: FnOnce(bool) -> Result<Event>,
+{
+    let retained = tree.history.read().clone();
+    if reservation.is_none() && retained.is_none() {
+        return Ok(());
+    }
+
+    let wants_previous =
+        reservation.as_ref().map(|res| res.wants_previous) == Some(true);
+    let event = make_event(wants_previous)?;
+
+    if let Some(history) = retained {
+        record(tree, &history, &event)?;
+    }
+
+    if let Some(res) = reservation {
+        res.complete(&event);
+    }
+
+    Ok(())
+}
+
+/// Records an event in the history of a tree, if it has one.
+pub(crate) fn record_event(tree: &Tree, event: &Event) -> Result<()> {
+    if let Some(history) = tree.history.read().clone() {
+        record(tree, &history, event)
+    } else {
+        Ok(())
+    }
+}
+
+fn record(tree: &Tree, history: &History, event: &Event) -> Result<()> {
+    let batch = if let Some((_, batch)) =
+        event.batches.iter().find(|(t, _)| t.tree_id == tree.tree_id)
+    {
+        batch
+    } else {
+        return Ok(());
+    };
+
+    let mut guard = pin();
+    let key = event_key(event.seq);
+
+    // the chunks of a transaction that spilled its writes share
+    // its sequence number, and are retained as one event
+    let view = history.tree.view_for_key(&key, &guard)?;
+    if let Some(raw) = view.node_kv_pair(&key).1 {
+        let mut merged = Batch::deserialize(&mut &*raw)?;
+        merged.writes.extend(batch.writes.clone());
+        let value = IVec::from(merged.serialize());
+        let _ = history.tree.set_inner(&key, Some(value), &mut guard)?;
+        return Ok(());
+    }
+
+    let value = IVec::from(batch.serialize());
+    let _ = history.tree.set_inner(&key, Some(value), &mut guard)?;
+    let mut len = history.len.fetch_add(1, SeqCst) + 1;
+
+    let limit = tree.context.event_history.unwrap_or(0);
+    while len > limit {
+        let mut iter = history.tree.range(vec![EVENT]..vec![TRIMMED]);
+        iter.parts = iter::Parts::Keys;
+        let oldest = if let Some(res) = iter.next_inner() {
+            res?.0
+        } else {
+            break;
+        };
+        drop(iter);
+
+        let _ = history.tree.set_inner(&oldest, None, &mut guard)?;
+        let trimmed = Some(IVec::from(&oldest[1..]));
+        let _ = history.tree.set_inner(&[TRIMMED], trimmed, &mut guard)?;
+        len = history.len.fetch_sub(1, SeqCst) - 1;
+    }
+
+    Ok(())
+}
+
+/// Returns the events of the history of a tree whose sequence
+/// numbers are at least `since` and that write keys that start
+/// with `prefix`, see `Tree::watch_prefix_since`.
+pub(crate) fn replay(
+    tree: &Tree,
+    prefix: &[u8],
+    since: u64,
+) -> Result<VecDeque<Event>> {
+    let history = if let Some(history) = tree.history.read().clone() {
+        history
+    } else {
+        return Err(Error::Unsupported(
+            "the tree retains no history, see Config::event_history".into(),
+        ));
+    };
+
+    let guard = pin();
+    let trimmed = [TRIMMED];
+    let view = history.tree.view_for_key(trimmed, &guard)?;
+    if let Some(raw) = view.node_kv_pair(&trimmed).1 {
+        let newest_trimmed = decode_seq(raw)?;
+        if since <= newest_trimmed {
+            return Err(Error::Unsupported(format!(
+                "the events before {} are no longer retained",
+                newest_trimmed + 1
+            )));
+        }
+    }
+    drop(guard);
+
+    let mut events = VecDeque::new();
+    for res in history.tree.range(event_key(since)..vec![TRIMMED]) {
+        let (k, v) = res?;
+        let seq = decode_seq(&k[1..])?;
+        let batch = Batch::deserialize(&mut &*v)?;
+        if !batch.writes.keys().any(|key| key.starts_with(prefix)) {
+            continue;
+        }
+        let batches = vec![(tree.clone(), batch)];
+        events.push_back(Event {
+            seq,
+            batches: Arc::from(batches.into_boxed_slice()),
+            previous: None,
+            missed: 0,
+        });
+    }
+
+    Ok(events)
+}
+
+/// Converts the LSN of a write to the sequence number of its event.
+pub(crate) fn seq(lsn: Lsn) -> u64 {
+    u64::try_from(lsn).unwrap()
+}
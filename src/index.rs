@@ -0,0 +1,450 @@
+//! Secondary indexes, see `Tree::create_index`.
+//!
+//! Each index is stored in a hidden companion `Tree` whose keys
+//! are the escaped index key, the terminator used by `KeyOrder`
+//! and the primary key, so that entries sort by index key and
+//! then by primary key, and every entry for an index key starts
+//! with the same prefix. The values of the entries are empty.
+//!
+//! Indexes are updated after every write to their primary tree
+//! that changes the key that the index function returns for a
+//! record. Writes to a tree with indexes hold the tree's writer
+//! lock and pin the log, so that the primary write and the index
+//! entries it changes are recovered atomically, and so that the
+//! index entries for a key can't be updated out of order by
+//! concurrent writers. Batches and transactions already pin the
+//! log while holding the global write lock.
+//!
+//! Index functions are not persisted, so `Tree::create_index` is
+//! called again after every restart and rebuilds the index from
+//! the primary tree. The index trees are what is persisted
+//! instead: a tree that has an index tree whose index wasn't
+//! created again since the `Db` was opened refuses to be written
+//! to, since the write couldn't update that index.
+//!
+//! An index is rebuilt while holding the tree's writer lock and
+//! the global read lock, so that writes to other trees go on.
+//! It is registered under the global write lock first, which
+//! waits for the writes to the tree that started before it, and
+//! makes every later one take the writer lock.
+use std::{convert::TryInto, ops};
+
+use parking_lot::MutexGuard;
+
+use crate::{
+    key_order::{escape, unescape, TERMINATOR},
+    *,
+};
+
+const INDEX_TREE_PREFIX: &[u8] = b"__sled__index__";
+
+/// The number of index entries that are written at a time while
+/// an index is rebuilt.
+const REBUILD_CHUNK: usize = 1024;
+
+/// A function that returns the key that a record is indexed
+/// under, or `None` if the record should not be indexed. It is
+/// passed the key and the value of the record.
+pub trait IndexFunction:
+    Send + Sync + Fn(&[u8], &[u8]) -> Option<Vec<u8>>
+{
+}
+impl<F> IndexFunction for F where
+    F: Send + Sync + Fn(&[u8], &[u8]) -> Option<Vec<u8>>
+{
+}
+
+/// The indexes of a `Tree`.
+#[derive(Default)]
+pub(crate) struct Indexes {
+    registered: RwLock<Vec<Index>>,
+    // the names of the indexes whose trees exist but that were not
+    // created since the `Db` was opened
+    unregistered: RwLock<Vec<IVec>>,
+    writer: Mutex<()>,
+}
+
+impl Debug for Indexes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.registered.read().iter()).finish()
+    }
+}
+
+/// A secondary index of a `Tree`, created with
+/// `Tree::create_index`, which maps index keys to the keys of
+/// the records in the `Tree` that they were returned for.
+///
+/// An index is kept up to date as its `Tree` is written to, and
+/// more than one record may have the same index key.
+#[derive(Clone)]
+pub struct Index(Arc<IndexInner>);
+
+struct IndexInner {
+    name: IVec,
+    tree: Tree,
+    index_function: Box<dyn IndexFunction>,
+}
+
+impl Debug for Index {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Index").field("name", &self.0.name).finish()
+    }
+}
+
+impl Index {
+    /// Returns the name of the index.
+    pub fn name(&self) -> IVec {
+        self.0.name.clone()
+    }
+
+    /// Create a double-ended iterator over the index keys and
+    /// primary keys of the records that have the given index
+    /// key, in the order of their primary keys.
+    pub fn get<K: AsRef<[u8]>>(&self, index_key: K) -> IndexIter {
+        self.range(index_key.as_ref()..=index_key.as_ref())
+    }
+
+    /// Create a double-ended iterator over the index keys and
+    /// primary keys of the index, in the order of the index keys
+    /// and then of the primary keys.
+    pub fn iter(&self) -> IndexIter {
+        self.range::<&[u8], _>(..)
+    }
+
+    /// Create a double-ended iterator over the index keys and
+    /// primary keys of the records whose index keys fall within
+    /// the specified range.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// // index each user, whose value is their age, by the age
+    /// let by_age = db.create_index("by_age", |_k, v| Some(v.to_vec()))?;
+    ///
+    /// db.insert("ada", &[36])?;
+    /// db.insert("bob", &[17])?;
+    /// db.insert("cyd", &[52])?;
+    ///
+    /// let mut adults = by_age.range(&[18][..]..);
+    /// assert_eq!(adults.next(), Some(Ok((vec![36].into(), "ada".into()))));
+    /// assert_eq!(adults.next(), Some(Ok((vec![52].into(), "cyd".into()))));
+    /// assert_eq!(adults.next(), None);
+    /// # Ok(()) }
+    /// ```
+    pub fn range<K, R>(&self, range: R) -> IndexIter
+    where
+        K: AsRef<[u8]>,
+        R: ops::RangeBounds<K>,
+    {
+        // the entries for an index key start with its escaped
+        // bytes followed by the terminator, and the entries for
+        // longer index keys that it is a prefix of start with
+        // its escaped bytes followed by a zero and a byte that
+        // is greater than the second byte of the terminator.
+        let before = |key: &K| {
+            let mut ret = escape(key.as_ref());
+            ret.extend_from_slice(&TERMINATOR);
+            IVec::from(ret)
+        };
+        let after = |key: &K| {
+            let mut ret = escape(key.as_ref());
+            ret.extend_from_slice(&[TERMINATOR[0], TERMINATOR[1] + 1]);
+            IVec::from(ret)
+        };
+
+        let lo = match range.start_bound() {
+            ops::Bound::Included(start) => ops::Bound::Included(before(start)),
+            ops::Bound::Excluded(start) => ops::Bound::Included(after(start)),
+            ops::Bound::Unbounded => ops::Bound::Included(IVec::from(&[])),
+        };
+
+        let hi = match range.end_bound() {
+            ops::Bound::Included(end) => ops::Bound::Excluded(after(end)),
+            ops::Bound::Excluded(end) => ops::Bound::Excluded(before(end)),
+            ops::Bound::Unbounded => ops::Bound::Unbounded,
+        };
+
+        IndexIter(self.0.tree.range_inner(lo, hi))
+    }
+
+    /// Returns the number of entries in the index.
+    ///
+    /// The count is kept up to date like that of `Tree::len`,
+    /// so this is O(1).
+    pub fn len(&self) -> usize {
+        self.0.tree.len()
+    }
+
+    /// Returns `true` if the index contains no entries.
+    pub fn is_empty(&self) -> bool {
+        self.0.tree.is_empty()
+    }
+
+    fn entry(&self, key: &[u8], value: &[u8]) -> Option<IVec> {
+        let index_key = (self.0.index_function)(key, value)?;
+        let mut ret = escape(&index_key);
+        ret.extend_from_slice(&TERMINATOR);
+        ret.extend_from_slice(key);
+        Some(ret.into())
+    }
+}
+
+/// An iterator over the index keys and primary keys of an
+/// `Index`.
+pub struct IndexIter(Iter);
+
+impl IndexIter {
+    fn decode((entry, _): (IVec, IVec)) -> (IVec, IVec) {
+        let (index_key, len) = unescape(&entry);
+        (index_key.into(), entry[len..].into())
+    }
+}
+
+impl Iterator for IndexIter {
+    type Item = Result<(IVec, IVec)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|res| res.map(IndexIter::decode))
+    }
+}
+
+impl DoubleEndedIterator for IndexIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0.next_back().map(|res| res.map(IndexIter::decode))
+    }
+}
+
+pub(crate) fn is_index_tree_name(name: &[u8]) -> bool {
+    name.starts_with(INDEX_TREE_PREFIX)
+}
+
+/// Returns the name of the parent of an index tree.
+pub(crate) fn parent_tree_name(name: &[u8]) -> Option<&[u8]> {
+    if !is_index_tree_name(name) {
+        return None;
+    }
+    let rest = &name[INDEX_TREE_PREFIX.len()..];
+    let len = u64::from_be_bytes(rest.get(..8)?.try_into().unwrap());
+    rest.get(8..8 + usize::try_from(len).ok()?)
+}
+
+// returns the name of the index that an index tree belongs to
+fn index_name<'a>(name: &'a [u8], parent: &[u8]) -> &'a [u8] {
+    &name[INDEX_TREE_PREFIX.len() + 8 + parent.len()..]
+}
+
+fn index_tree_name(tree_id: &[u8], name: &[u8]) -> IVec {
+    let mut ret = INDEX_TREE_PREFIX.to_vec();
+    let len = u64::try_from(tree_id.len()).unwrap();
+    ret.extend_from_slice(&len.to_be_bytes());
+    ret.extend_from_slice(tree_id);
+    ret.extend_from_slice(name);
+    ret.into()
+}
+
+/// Returns the name that an index tree has once its parent is
+/// renamed to `to`.
+pub(crate) fn renamed_tree_name(name: &[u8], to: &[u8]) -> Option<IVec> {
+    let parent = parent_tree_name(name)?;
+    Some(index_tree_name(to, index_name(name, parent)))
+}
+
+/// Finds the index trees of a tree that is being loaded, whose
+/// indexes need to be created again before it is written to.
+pub(crate) fn load(tree: &Tree, guard: &Guard) {
+    let mut unregistered = tree.indexes.unregistered.write();
+    for (id, _) in tree.context.pagecache.get_meta(guard).tenants() {
+        if parent_tree_name(&id) == Some(&tree.tree_id) {
+            unregistered.push(index_name(&id, &tree.tree_id).into());
+        }
+    }
+}
+
+/// Returns an error if the tree has an index that wasn't created
+/// since the `Db` was opened, which a write couldn't update.
+pub(crate) fn check_registered(tree: &Tree) -> Result<()> {
+    if let Some(name) = tree.indexes.unregistered.read().first() {
+        return Err(Error::Unsupported(format!(
+            "the index {:?} of tree {:?} needs to be created again \
+             with Tree::create_index before the tree is written to",
+            name,
+            tree.name()
+        )));
+    }
+    Ok(())
+}
+
+/// Creates an index, or replaces the function of an existing
+/// one, and rebuilds it from the records of the tree.
+pub(crate) fn create(
+    tree: &Tree,
+    name: &[u8],
+    index_function: Box<dyn IndexFunction>,
+) -> Result<Index> {
+    let index_tree = meta::open_tree(
+        &tree.context,
+        index_tree_name(&tree.tree_id, name),
+        None,
+        &pin(),
+    )?;
+
+    let index = Index(Arc::new(IndexInner {
+        name: name.into(),
+        tree: index_tree,
+        index_function,
+    }));
+
+    {
+        // waits for the writes that didn't see the index, so that
+        // every later write to the tree takes the writer lock
+        let _cc = concurrency_control::write();
+        let mut registered = tree.indexes.registered.write();
+        registered.retain(|other| other.0.name != index.0.name);
+        registered.push(index.clone());
+        tree.indexes.unregistered.write().retain(|other| other != name);
+    }
+
+    let _cc = concurrency_control::read();
+    let _writer = tree.indexes.writer.lock();
+    let mut guard = pin();
+
+    let mut batch = Batch::default();
+    let mut stale = index.0.tree.iter();
+    while let Some(res) = stale.next_inner() {
+        let (entry, _) = res?;
+        batch.remove(entry);
+        if batch.writes.len() == REBUILD_CHUNK {
+            let chunk = std::mem::take(&mut batch);
+            index.0.tree.apply_batch_inner(chunk, &mut guard)?;
+        }
+    }
+
+    let mut records = tree.iter();
+    while let Some(res) = records.next_inner() {
+        let (k, v) = res?;
+        if let Some(entry) = index.entry(&tree.order.decode(k), &v) {
+            batch.insert(entry, IVec::default());
+        }
+        if batch.writes.len() == REBUILD_CHUNK {
+            let chunk = std::mem::take(&mut batch);
+            index.0.tree.apply_batch_inner(chunk, &mut guard)?;
+        }
+    }
+
+    if !batch.writes.is_empty() {
+        index.0.tree.apply_batch_inner(batch, &mut guard)?;
+    }
+
+    // the entries of an index tree that was loaded are counted
+    // while nothing else can write to it
+    index.0.tree.recount_len();
+
+    Ok(index)
+}
+
+/// Removes the indexes of a tree that is being dropped, and
+/// returns its index trees, whether or not their indexes have
+/// been created since the `Db` was opened.
+pub(crate) fn take_index_trees(
+    tree: &Tree,
+    guard: &Guard,
+) -> Result<Vec<Tree>> {
+    tree.indexes.registered.write().clear();
+    tree.indexes.unregistered.write().clear();
+
+    let mut ret = vec![];
+    for (id, root) in tree.context.pagecache.get_meta(guard).tenants() {
+        if parent_tree_name(&id) == Some(&tree.tree_id) {
+            ret.push(meta::load_tree(&tree.context, id, root, guard)?);
+        }
+    }
+    Ok(ret)
+}
+
+/// Returns `true` if indexes of the tree were created since the
+/// `Db` was opened.
+pub(crate) fn is_indexed(tree: &Tree) -> bool {
+    !tree.indexes.registered.read().is_empty()
+}
+
+/// Prevents concurrent writes to a tree with indexes, and makes
+/// a write and the updates of the indexes, and the clearing of
+/// its expiration, recover atomically, until it is sealed.
+pub(crate) struct IndexedWrite<'a> {
+    _writer: Option<MutexGuard<'a, ()>>,
+    peg: RecoveryGuard<'a>,
+}
+
+/// Starts a write to a tree outside of a batch or transaction,
+/// returning `None` if the tree has no indexes, history or
+/// expiring keys.
+pub(crate) fn begin_write<'a>(
+    tree: &'a Tree,
+    guard: &Guard,
+) -> Result<Option<IndexedWrite<'a>>> {
+    check_registered(tree)?;
+    let writer = lock(tree);
+    if writer.is_none() && tree.expirations.read().is_none() {
+        return Ok(None);
+    }
+    let peg = tree.context.pin_log(guard)?;
+    Ok(Some(IndexedWrite { _writer: writer, peg }))
+}
+
+pub(crate) fn finish_write(indexed: Option<IndexedWrite<'_>>) -> Result<()> {
+    if let Some(write) = indexed {
+        write.peg.seal_batch()
+    } else {
+        Ok(())
+    }
+}
+
+/// Prevents concurrent writes to a tree with indexes or a retained
+/// history, returning `None` if the tree has neither. For writes
+/// that already pin the log.
+pub(crate) fn lock(tree: &Tree) -> Option<MutexGuard<'_, ()>> {
+    if tree.indexes.registered.read().is_empty()
+        && tree.history.read().is_none()
+    {
+        None
+    } else {
+        Some(tree.indexes.writer.lock())
+    }
+}
+
+/// Updates the indexes of a tree after the value of a stored
+/// key changed from `old` to `new`.
+pub(crate) fn update(
+    tree: &Tree,
+    key: &[u8],
+    old: Option<&[u8]>,
+    new: Option<&[u8]>,
+) -> Result<()> {
+    let registered = tree.indexes.registered.read();
+    if registered.is_empty() {
+        return Ok(());
+    }
+
+    let user_key = tree.order.decode(key.into());
+    let mut guard = pin();
+
+    for index in registered.iter() {
+        let old_entry = old.and_then(|value| index.entry(&user_key, value));
+        let new_entry = new.and_then(|value| index.entry(&user_key, value));
+        if old_entry == new_entry {
+            continue;
+        }
+        if let Some(entry) = old_entry {
+            let _ = index.0.tree.set_inner(&entry, None, &mut guard)?;
+        }
+        if let Some(entry) = new_entry {
+            let value = Some(IVec::default());
+            let _ = index.0.tree.set_inner(&entry, value, &mut guard)?;
+        }
+    }
+
+    Ok(())
+}
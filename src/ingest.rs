@@ -0,0 +1,169 @@
+//! Bulk ingestion that keeps writes out of the log until it is
+//! finished, see `Tree::ingest_session`.
+//!
+//! The writes of a session are held in memory, sorted by the
+//! `KeyOrder` of the tree, and whenever they grow past a limit
+//! they are written next to the database as a sorted run, like
+//! the writes of a `Txn` with `TxnOptions::spill_after`.
+//! Finishing the session merges the runs and bulk loads the
+//! result when the tree is empty and has no indexes or history,
+//! so that each node is written once. Otherwise the writes are
+//! applied in batches of a bounded size, which go through the
+//! log like any other batch and are not atomic as a whole.
+//! The log is then flushed and a snapshot is taken, so that the
+//! ingested keys don't have to be replayed from the log when the
+//! database is opened again.
+use std::collections::BTreeMap;
+
+use crate::{spill::Spill, *};
+
+/// The number of bytes of keys and values that a session holds in
+/// memory before it writes them to a run.
+#[cfg(not(feature = "testing"))]
+const SPILL_AFTER: usize = 64 * 1024 * 1024;
+#[cfg(feature = "testing")]
+const SPILL_AFTER: usize = 64 * 1024;
+
+/// The number of writes that are applied as one batch when the
+/// session can't be bulk loaded.
+const BATCH_SIZE: usize = 1024;
+
+/// A bulk ingestion into a `Tree` that writes nothing until it
+/// is finished, returned by `Tree::ingest_session`.
+///
+/// This is unsafe in the sense of durability, not of memory:
+/// everything that is written to a session is lost if the
+/// process crashes or the session is dropped before `finish`
+/// returns, and none of it can be read from the tree until then.
+/// Writes that don't fit in memory are spilled to temporary
+/// files next to the database, except when it is encrypted, in
+/// which case they are all held in memory.
+#[derive(Debug)]
+pub struct IngestSession {
+    tree: Tree,
+    // encoded key -> value, where None removes the key
+    writes: BTreeMap<IVec, Option<IVec>>,
+    buffered: usize,
+    spill: Spill,
+    spilled: usize,
+}
+
+impl IngestSession {
+    pub(crate) fn new(tree: &Tree) -> IngestSession {
+        IngestSession {
+            tree: tree.clone(),
+            writes: BTreeMap::new(),
+            buffered: 0,
+            spill: Spill::new(tree.context.get_path(), SPILL_AFTER),
+            spilled: 0,
+        }
+    }
+
+    /// Sets a key to a new value once the session is finished.
+    pub fn insert<K, V>(&mut self, key: K, value: V) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: Into<IVec>,
+    {
+        self.write(key.as_ref(), Some(value.into()))
+    }
+
+    /// Removes a key once the session is finished.
+    pub fn remove<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
+        self.write(key.as_ref(), None)
+    }
+
+    /// Returns the number of keys that the session writes. A key
+    /// that was written again after the session spilled its
+    /// writes to disk is counted more than once.
+    pub fn len(&self) -> usize {
+        self.spilled + self.writes.len()
+    }
+
+    /// Returns `true` if the session writes no keys.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes everything in the session to the tree, flushes it
+    /// and takes a snapshot, returning the number of keys that
+    /// were written.
+    pub fn finish(mut self) -> Result<usize> {
+        let mut written = 0;
+        if self.spill.runs() == 0 {
+            let writes = std::mem::replace(&mut self.writes, BTreeMap::new());
+            let writes = writes.into_iter().map(Ok);
+            self.apply(writes.inspect(|_| written += 1))?;
+        } else {
+            self.spill_writes()?;
+            let merge = self.spill.merge()?;
+            let writes =
+                merge.map(|entry| entry.map(|(_, key, value)| (key, value)));
+            self.apply(writes.inspect(|_| written += 1))?;
+        }
+
+        let _flushed = self.tree.flush()?;
+        self.tree.context.pagecache.clone().take_fuzzy_snapshot()?;
+        Ok(written)
+    }
+
+    fn apply<I>(&self, writes: I) -> Result<()>
+    where
+        I: Iterator<Item = Result<(IVec, Option<IVec>)>>,
+    {
+        let tree = &self.tree;
+        let order = tree.order;
+        if index::lock(tree).is_none() && tree.is_empty() {
+            // removing keys from an empty tree does nothing
+            let pairs = writes.filter_map(|write| match write {
+                Ok((key, Some(value))) => Some(Ok((order.decode(key), value))),
+                Ok((_, None)) => None,
+                Err(e) => Some(Err(e)),
+            });
+            let _loaded = tree.try_bulk_load(pairs)?;
+            return Ok(());
+        }
+
+        let mut batch = Batch::default();
+        let mut batched = 0;
+        for write in writes {
+            let (key, value) = write?;
+            let key = order.decode(key);
+            match value {
+                Some(v) => batch.insert(key, v),
+                None => batch.remove(key),
+            }
+            batched += 1;
+            if batched == BATCH_SIZE {
+                tree.apply_batch(std::mem::take(&mut batch))?;
+                batched = 0;
+            }
+        }
+        if batched > 0 {
+            tree.apply_batch(batch)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, key: &[u8], value: Option<IVec>) -> Result<()> {
+        let encoded = IVec::from(&*self.tree.order.encode(key));
+        self.buffered +=
+            encoded.len() + value.as_ref().map_or(0, |v| v.len());
+        let _replaced = self.writes.insert(encoded, value);
+
+        if self.buffered > self.spill.limit
+            && self.tree.context.encryption.is_none()
+        {
+            self.spill_writes()?;
+        }
+        Ok(())
+    }
+
+    fn spill_writes(&mut self) -> Result<()> {
+        self.spill.write_run(self.writes.iter().map(|(k, v)| (0, k, v)))?;
+        self.spilled += self.writes.len();
+        self.writes.clear();
+        self.buffered = 0;
+        Ok(())
+    }
+}
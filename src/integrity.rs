@@ -0,0 +1,295 @@
+//! Checks of the on-disk and in-memory structure of the trees
+//! of a `Db`, see `Db::verify_integrity`.
+//!
+//! Each level of a tree is walked from its leftmost node along
+//! the sibling links. Every node must begin at the upper bound
+//! of its left sibling, hold only keys within its own bounds and
+//! in order, and begin at the key that its parent indexes it
+//! under. Every child of the level above must be reached on the
+//! way. Problems are collected rather than returned as errors,
+//! so that one damaged node doesn't hide the others.
+//!
+//! Writes go on while the trees are walked, and a split or merge
+//! that is only partly installed can make a node look misplaced
+//! for a moment, so a tree that shows problems is walked again,
+//! and only the problems that every walk found are reported.
+use std::fmt;
+
+use crate::{pagecache::PageCache, *};
+
+/// The number of times that a tree that shows problems is walked.
+const WALKS: usize = 3;
+
+/// The result of `Db::verify_integrity`.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[must_use]
+pub struct IntegrityReport {
+    /// The number of trees that were checked.
+    pub trees: u64,
+    /// The number of nodes that were checked.
+    pub nodes: u64,
+    /// The number of keys in the leaves that were checked.
+    pub keys: u64,
+    /// Everything that was found to be wrong, in the order that
+    /// it was found.
+    pub problems: Vec<IntegrityProblem>,
+}
+
+impl IntegrityReport {
+    /// Returns `true` if no problems were found.
+    pub fn is_healthy(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// A problem with a node of a tree, found by
+/// `Db::verify_integrity`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IntegrityProblem {
+    /// The name of the tree.
+    pub tree: IVec,
+    /// The page that holds the node.
+    pub pid: u64,
+    /// What is wrong with the node.
+    pub description: String,
+}
+
+impl fmt::Display for IntegrityProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "tree {:?} node {}: {}",
+            String::from_utf8_lossy(&self.tree),
+            self.pid,
+            self.description
+        )
+    }
+}
+
+/// Checks every level of the tree with the given name, adding
+/// what is found to the report. A tree that no longer exists is
+/// skipped.
+pub(crate) fn verify_tree(
+    name: &IVec,
+    pagecache: &PageCache,
+    report: &mut IntegrityReport,
+) {
+    let mut found: Option<IntegrityReport> = None;
+    for _ in 0..WALKS {
+        let mut walked = IntegrityReport::default();
+        let guard = pin();
+        let root = match pagecache.meta_pid_for_name(name, &guard) {
+            Ok(root) => root,
+            Err(_) => return,
+        };
+        walk_tree(name, pagecache, root, &mut walked, &guard);
+
+        if let Some(earlier) = found {
+            let problems = &mut walked.problems;
+            problems.retain(|problem| earlier.problems.contains(problem));
+        }
+        let healthy = walked.is_healthy();
+        found = Some(walked);
+        if healthy {
+            break;
+        }
+    }
+
+    if let Some(found) = found {
+        report.trees += found.trees;
+        report.nodes += found.nodes;
+        report.keys += found.keys;
+        report.problems.extend(found.problems);
+    }
+}
+
+fn walk_tree(
+    name: &IVec,
+    pagecache: &PageCache,
+    root: PageId,
+    report: &mut IntegrityReport,
+    guard: &Guard,
+) {
+    let mut walk = Walk { name, pagecache, report };
+
+    // the children of the level above, which each level must
+    // reach, with the keys that they are indexed under
+    let mut expected: FastMap8<PageId, IVec> = FastMap8::default();
+    let mut leftmost = root;
+
+    loop {
+        let level = match walk.level(leftmost, expected, guard) {
+            Some(level) => level,
+            None => break,
+        };
+        match level.leftmost_child {
+            Some(child) => {
+                expected = level.children;
+                leftmost = child;
+            }
+            None => break,
+        }
+    }
+
+    walk.report.trees += 1;
+}
+
+struct Walk<'a> {
+    name: &'a IVec,
+    pagecache: &'a PageCache,
+    report: &'a mut IntegrityReport,
+}
+
+/// The children of the index nodes of a level.
+struct Level {
+    leftmost_child: Option<PageId>,
+    children: FastMap8<PageId, IVec>,
+}
+
+impl Walk<'_> {
+    fn problem(&mut self, pid: PageId, description: String) {
+        self.report.problems.push(IntegrityProblem {
+            tree: self.name.clone(),
+            pid,
+            description,
+        });
+    }
+
+    /// Checks the nodes of a level, returning `None` if its
+    /// sibling links can't be followed to its end.
+    fn level(
+        &mut self,
+        leftmost: PageId,
+        mut expected: FastMap8<PageId, IVec>,
+        guard: &Guard,
+    ) -> Option<Level> {
+        let pagecache = self.pagecache;
+        let mut ret =
+            Level { leftmost_child: None, children: FastMap8::default() };
+        let mut pid = leftmost;
+        let mut lo = IVec::default();
+        let mut is_index = None;
+        let mut visited = FastSet8::default();
+
+        loop {
+            if !visited.insert(pid) {
+                self.problem(pid, "sibling links form a cycle".into());
+                return None;
+            }
+            if let Err(e) = pagecache.verify_page(pid, guard) {
+                let description = format!("can't be read from the log: {}", e);
+                self.problem(pid, description);
+                return None;
+            }
+            let node_view = match pagecache.get(pid, guard) {
+                Ok(Some(node_view)) => node_view,
+                Ok(None) => {
+                    self.problem(pid, "is linked to but was freed".into());
+                    return None;
+                }
+                Err(e) => {
+                    self.problem(pid, format!("can't be read: {}", e));
+                    return None;
+                }
+            };
+            self.report.nodes += 1;
+
+            if node_view.lo() != &*lo {
+                let description = format!(
+                    "begins at {:?} instead of at the upper bound {:?} \
+                     of its left sibling",
+                    node_view.lo(),
+                    lo
+                );
+                self.problem(pid, description);
+            }
+            if let Some(indexed) = expected.remove(&pid) {
+                if node_view.lo() != &*indexed {
+                    let description = format!(
+                        "begins at {:?} but is indexed by its parent \
+                         under {:?}",
+                        node_view.lo(),
+                        indexed
+                    );
+                    self.problem(pid, description);
+                }
+            }
+            match is_index {
+                Some(level) if level != node_view.is_index => {
+                    self.problem(pid, "is on a level of the other kind".into())
+                }
+                Some(_) => {}
+                None => is_index = Some(node_view.is_index),
+            }
+            self.keys(pid, &node_view);
+
+            if node_view.is_index {
+                let items =
+                    node_view.decoded_keys().zip(node_view.iter_index_pids());
+                for (child_lo, child) in items {
+                    if ret.leftmost_child.is_none() {
+                        ret.leftmost_child = Some(child);
+                    }
+                    let _ = ret.children.insert(child, child_lo);
+                }
+                if ret.children.is_empty() {
+                    let description = "is an index node without children";
+                    self.problem(pid, description.into());
+                }
+            }
+
+            match (node_view.next, node_view.hi()) {
+                (Some(next), Some(hi)) => {
+                    lo = hi.into();
+                    pid = next.get();
+                }
+                (None, None) => break,
+                (Some(_), None) => {
+                    let description = "has a right sibling but no upper bound";
+                    self.problem(pid, description.into());
+                    return None;
+                }
+                (None, Some(_)) => {
+                    let description = "has an upper bound but no right sibling";
+                    self.problem(pid, description.into());
+                    return None;
+                }
+            }
+        }
+
+        let mut unreached: Vec<PageId> = expected.keys().copied().collect();
+        unreached.sort_unstable();
+        for child in unreached {
+            let description = "is indexed by its parent but isn't reached \
+                               through the sibling links of its level";
+            self.problem(child, description.into());
+        }
+
+        Some(ret)
+    }
+
+    /// Checks that the keys of a node are in order and within
+    /// its bounds, counting those of leaves.
+    fn keys(&mut self, pid: PageId, node: &Node) {
+        let mut last: Option<IVec> = None;
+        for key in node.decoded_keys() {
+            let below_hi = match node.hi() {
+                Some(hi) => &*key < hi,
+                None => true,
+            };
+            if &*key < node.lo() || !below_hi {
+                let description = format!("holds {:?} out of bounds", key);
+                self.problem(pid, description);
+            }
+            if let Some(ref last_key) = last {
+                if *last_key >= key {
+                    self.problem(pid, format!("holds {:?} out of order", key));
+                }
+            }
+            if !node.is_index {
+                self.report.keys += 1;
+            }
+            last = Some(key);
+        }
+    }
+}
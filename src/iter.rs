@@ -33,6 +33,17 @@ macro_rules! iter_try {
     };
 }
 
+/// The parts of the items that an `Iter` returns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Parts {
+    KeysAndValues,
+    // values are neither copied out of nodes nor read from
+    // the value log
+    Keys,
+    // keys are not decoded for the `KeyOrder` of the tree
+    Values,
+}
+
 /// An iterator over keys and values in a `Tree`.
 pub struct Iter {
     pub(super) tree: Tree,
@@ -40,23 +51,115 @@ pub struct Iter {
     pub(super) lo: Bound<IVec>,
     pub(super) cached_node: Option<(PageId, Node)>,
     pub(super) going_forward: bool,
+    // the bounds that the iterator was created with, which
+    // `seek` and `seek_for_prev` stay within
+    pub(super) start: Bound<IVec>,
+    pub(super) end: Bound<IVec>,
+    pub(super) parts: Parts,
 }
 
 impl Iter {
-    /// Iterate over the keys of this Tree
+    /// Iterate over the keys of this Tree, without copying
+    /// their values or reading them from the value log.
     pub fn keys(
-        self,
+        mut self,
     ) -> impl DoubleEndedIterator<Item = Result<IVec>> + Send + Sync {
+        self.parts = Parts::Keys;
         self.map(|r| r.map(|(k, _v)| k))
     }
 
-    /// Iterate over the values of this Tree
+    /// Iterate over the values of this Tree, without decoding
+    /// their keys for the `KeyOrder` of the Tree.
     pub fn values(
-        self,
+        mut self,
     ) -> impl DoubleEndedIterator<Item = Result<IVec>> + Send + Sync {
+        self.parts = Parts::Values;
         self.map(|r| r.map(|(_k, v)| v))
     }
 
+    /// Moves the front of the iterator, so that `next` returns
+    /// the first key that is at least `key`, even if it was
+    /// already returned. Keys outside of the range that the
+    /// iterator was created for are never returned, and the
+    /// back of the iterator is not moved.
+    ///
+    /// The tree is only traversed from its root again if the
+    /// key is not in the leaf that the iterator read last, so
+    /// seeking forward in small steps, like merge joins do, is
+    /// cheap.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// use sled::IVec;
+    ///
+    /// for i in 0..10_u8 {
+    ///     db.insert(&[i * 2], vec![])?;
+    /// }
+    ///
+    /// let mut iter = db.range([4]..);
+    /// iter.seek([9]);
+    /// assert_eq!(iter.next().unwrap()?.0, IVec::from(&[10]));
+    /// iter.seek([0]);
+    /// assert_eq!(iter.next().unwrap()?.0, IVec::from(&[4]));
+    /// # Ok(()) }
+    /// ```
+    pub fn seek<K: AsRef<[u8]>>(&mut self, key: K) {
+        let target = IVec::from(&*self.tree.order.encode(key.as_ref()));
+        self.lo = match self.start {
+            Bound::Included(ref start) if *start > target => {
+                Bound::Included(start.clone())
+            }
+            Bound::Excluded(ref start) if *start >= target => {
+                Bound::Excluded(start.clone())
+            }
+            _ => Bound::Included(target),
+        };
+        self.going_forward = true;
+    }
+
+    /// Moves the back of the iterator, so that `next_back`
+    /// returns the last key that is at most `key`, even if it
+    /// was already returned. Keys outside of the range that the
+    /// iterator was created for are never returned, and the
+    /// front of the iterator is not moved, see `seek`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// use sled::IVec;
+    ///
+    /// for i in 0..10_u8 {
+    ///     db.insert(&[i * 2], vec![])?;
+    /// }
+    ///
+    /// let mut iter = db.range(..[10]);
+    /// iter.seek_for_prev([7]);
+    /// assert_eq!(iter.next_back().unwrap()?.0, IVec::from(&[6]));
+    /// iter.seek_for_prev([100]);
+    /// assert_eq!(iter.next_back().unwrap()?.0, IVec::from(&[8]));
+    /// # Ok(()) }
+    /// ```
+    pub fn seek_for_prev<K: AsRef<[u8]>>(&mut self, key: K) {
+        let target = IVec::from(&*self.tree.order.encode(key.as_ref()));
+        self.hi = match self.end {
+            Bound::Included(ref end) if *end < target => {
+                Bound::Included(end.clone())
+            }
+            Bound::Excluded(ref end) if *end <= target => {
+                Bound::Excluded(end.clone())
+            }
+            _ => Bound::Included(target),
+        };
+        self.going_forward = false;
+    }
+
     fn bounds_collapsed(&self) -> bool {
         match (&self.lo, &self.hi) {
             (Bound::Included(ref start), Bound::Included(ref end))
@@ -84,6 +187,27 @@ impl Iter {
         }
     }
 
+    // expired keys are still physically present until they
+    // are swept, so they need to be skipped during iteration.
+    fn is_expired(&self, item: &Option<Result<(IVec, IVec)>>) -> Result<bool> {
+        if let Some(Ok((k, _v))) = item {
+            expiration::is_expired(&self.tree, k, &pin())
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn decode(
+        &self,
+        item: Option<<Self as Iterator>::Item>,
+    ) -> Option<<Self as Iterator>::Item> {
+        if self.parts == Parts::Values {
+            return item;
+        }
+        let order = self.tree.order;
+        item.map(|res| res.map(|(k, v)| (order.decode(k), v)))
+    }
+
     pub(crate) fn next_inner(&mut self) -> Option<<Self as Iterator>::Item> {
         let guard = pin();
         let (mut pid, mut node) = if let (true, Some((pid, node))) =
@@ -96,6 +220,8 @@ impl Iter {
             (view.pid, view.deref().clone())
         };
 
+        let mut moved = None;
+
         for _ in 0..MAX_LOOPS {
             if self.bounds_collapsed() {
                 return None;
@@ -118,21 +244,41 @@ impl Iter {
                 continue;
             }
 
-            if let Some((key, value)) = node.successor(&self.lo) {
+            if let Some((key, stored)) = node.successor(&self.lo) {
+                let in_bounds = match self.hi {
+                    Bound::Unbounded => true,
+                    Bound::Included(ref h) => *h >= key,
+                    Bound::Excluded(ref h) => *h > key,
+                };
+
+                let value = if in_bounds && self.parts == Parts::Keys {
+                    Some(IVec::default())
+                } else if in_bounds {
+                    let read = value_log::read(&self.tree, stored);
+                    if let Some(loaded) = iter_try!(read) {
+                        Some(loaded)
+                    } else {
+                        // the node was cached before the value
+                        // was moved by a value log collection
+                        if moved.as_deref() == Some(stored) {
+                            return Some(Err(Error::corruption(None)));
+                        }
+                        moved = Some(IVec::from(stored));
+                        let view =
+                            iter_try!(self.tree.view_for_key(&key, &guard));
+                        pid = view.pid;
+                        node = view.deref().clone();
+                        continue;
+                    }
+                } else {
+                    None
+                };
+
                 self.lo = Bound::Excluded(key.clone());
                 self.cached_node = Some((pid, node));
                 self.going_forward = true;
 
-                match self.hi {
-                    Bound::Unbounded => return Some(Ok((key, value))),
-                    Bound::Included(ref h) if *h >= key => {
-                        return Some(Ok((key, value)));
-                    }
-                    Bound::Excluded(ref h) if *h > key => {
-                        return Some(Ok((key, value)));
-                    }
-                    _ => return None,
-                }
+                return value.map(|loaded| Ok((key, loaded)));
             } else if let Some(hi) = node.hi() {
                 self.lo = Bound::Included(hi.into());
                 continue;
@@ -145,29 +291,11 @@ impl Iter {
             self.lo, self.tree
         );
     }
-}
 
-impl Iterator for Iter {
-    type Item = Result<(IVec, IVec)>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        #[cfg(feature = "metrics")]
-        let _measure = Measure::new(&M.tree_scan);
-        let _cc = concurrency_control::read();
-        self.next_inner()
-    }
-
-    fn last(mut self) -> Option<Self::Item> {
-        self.next_back()
-    }
-}
-
-impl DoubleEndedIterator for Iter {
-    fn next_back(&mut self) -> Option<Self::Item> {
-        #[cfg(feature = "metrics")]
-        let _measure = Measure::new(&M.tree_reverse_scan);
+    pub(crate) fn next_back_inner(
+        &mut self,
+    ) -> Option<<Self as Iterator>::Item> {
         let guard = pin();
-        let _cc = concurrency_control::read();
 
         let (mut pid, mut node) = if let (false, Some((pid, node))) =
             (self.going_forward, self.cached_node.take())
@@ -179,6 +307,8 @@ impl DoubleEndedIterator for Iter {
             (view.pid, view.deref().clone())
         };
 
+        let mut moved = None;
+
         for _ in 0..MAX_LOOPS {
             if self.bounds_collapsed() {
                 return None;
@@ -201,21 +331,41 @@ impl DoubleEndedIterator for Iter {
                 continue;
             }
 
-            if let Some((key, value)) = node.predecessor(&self.hi) {
+            if let Some((key, stored)) = node.predecessor(&self.hi) {
+                let in_bounds = match self.lo {
+                    Bound::Unbounded => true,
+                    Bound::Included(ref l) => *l <= key,
+                    Bound::Excluded(ref l) => *l < key,
+                };
+
+                let value = if in_bounds && self.parts == Parts::Keys {
+                    Some(IVec::default())
+                } else if in_bounds {
+                    let read = value_log::read(&self.tree, stored);
+                    if let Some(loaded) = iter_try!(read) {
+                        Some(loaded)
+                    } else {
+                        // the node was cached before the value
+                        // was moved by a value log collection
+                        if moved.as_deref() == Some(stored) {
+                            return Some(Err(Error::corruption(None)));
+                        }
+                        moved = Some(IVec::from(stored));
+                        let view =
+                            iter_try!(self.tree.view_for_key(&key, &guard));
+                        pid = view.pid;
+                        node = view.deref().clone();
+                        continue;
+                    }
+                } else {
+                    None
+                };
+
                 self.hi = Bound::Excluded(key.clone());
                 self.cached_node = Some((pid, node));
                 self.going_forward = false;
 
-                match self.lo {
-                    Bound::Unbounded => return Some(Ok((key, value))),
-                    Bound::Included(ref l) if *l <= key => {
-                        return Some(Ok((key, value)));
-                    }
-                    Bound::Excluded(ref l) if *l < key => {
-                        return Some(Ok((key, value)));
-                    }
-                    _ => return None,
-                }
+                return value.map(|loaded| Ok((key, loaded)));
             } else if node.lo().is_empty() {
                 return None;
             } else {
@@ -230,6 +380,40 @@ impl DoubleEndedIterator for Iter {
     }
 }
 
+impl Iterator for Iter {
+    type Item = Result<(IVec, IVec)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        #[cfg(feature = "metrics")]
+        let _measure = Measure::new(&M.tree_scan);
+        let _cc = concurrency_control::read();
+        loop {
+            let item = self.next_inner();
+            if !iter_try!(self.is_expired(&item)) {
+                return self.decode(item);
+            }
+        }
+    }
+
+    fn last(mut self) -> Option<Self::Item> {
+        self.next_back()
+    }
+}
+
+impl DoubleEndedIterator for Iter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        #[cfg(feature = "metrics")]
+        let _measure = Measure::new(&M.tree_reverse_scan);
+        let _cc = concurrency_control::read();
+        loop {
+            let item = self.next_back_inner();
+            if !iter_try!(self.is_expired(&item)) {
+                return self.decode(item);
+            }
+        }
+    }
+}
+
 #[test]
 fn basic_functionality() {
     assert_eq!(possible_predecessor(b""), None);
@@ -11,12 +11,15 @@ use std::{
     sync::atomic::{AtomicUsize, Ordering},
 };
 
-const SZ: usize = size_of::<usize>();
+// three words, so that the 16 byte keys and 8 byte values that
+// most workloads use are stored inline without allocating
+const SZ: usize = 3 * size_of::<usize>();
 const CUTOFF: usize = SZ - 1;
 
 /// A buffer that may either be inline or remote and protected
 /// by an Arc. The inner buffer is guaranteed to be aligned to
-/// 8 byte boundaries.
+/// 8 byte boundaries. Buffers of up to 23 bytes, or 11 on 32-bit
+/// targets, are stored inline.
 #[repr(align(8))]
 pub struct IVec([u8; SZ]);
 
@@ -143,9 +146,9 @@ impl IVec {
                 std::ptr::write_unaligned(data.as_mut_ptr() as _, ptr);
             }
 
-            // assert that the bottom 3 bits are empty, as we expect
-            // the buffer to always have an alignment of 8 (2 ^ 3).
-            assert_eq!(data[SZ - 1] & 0b111, 0);
+            // the pointer is stored before the trailer, which is
+            // left as 0 to mark the buffer as remote
+            assert_eq!(data[SZ - 1], 0);
         }
         Self(data)
     }
@@ -315,6 +318,21 @@ mod qc {
         assert_eq!(iv2, vec![4; 128]);
     }
 
+    #[test]
+    fn ivec_inline_capacity() {
+        let key = IVec::from(&[7; 16]);
+        assert!(key.is_inline());
+        assert_eq!(key, [7; 16]);
+
+        let full = IVec::from(&[8; super::CUTOFF][..]);
+        assert!(full.is_inline());
+        assert_eq!(full, vec![8; super::CUTOFF]);
+
+        let remote = IVec::from(&[9; super::CUTOFF + 1][..]);
+        assert!(!remote.is_inline());
+        assert_eq!(remote, vec![9; super::CUTOFF + 1]);
+    }
+
     #[test]
     fn ivec_as_mut_identity() {
         let initial = &[1];
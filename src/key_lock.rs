@@ -0,0 +1,78 @@
+//! Locks on single keys that a transaction takes up front, see
+//! `Db::begin_locked_transaction` and `TxnOptions::lock`.
+//!
+//! A key is locked by the id of its tree and its stored bytes.
+//! The keys of a transaction are locked one by one in sorted
+//! order, so that transactions whose keys overlap queue up on the
+//! first key they share instead of deadlocking, and they are all
+//! released together when the transaction is dropped. A thread
+//! that locks a key of a transaction that it holds itself still
+//! waits forever, unless it gives up after `TxnOptions::timeout`.
+use std::time::{Duration, Instant};
+
+use crate::*;
+
+type LockedKey = (IVec, IVec);
+
+/// The keys of a `Db` that are locked by transactions.
+#[derive(Debug, Default)]
+pub(crate) struct KeyLocks {
+    held: Mutex<FastSet8<LockedKey>>,
+    released: Condvar,
+}
+
+impl KeyLocks {
+    /// Blocks until every key is locked for the returned guard, or
+    /// returns `None` once the `timeout` has passed, without
+    /// holding any of them.
+    pub(crate) fn lock(
+        locks: &Arc<KeyLocks>,
+        mut keys: Vec<LockedKey>,
+        timeout: Option<Duration>,
+    ) -> Option<KeyLockGuard> {
+        keys.sort();
+        keys.dedup();
+        let deadline = timeout.map(|wait| Instant::now() + wait);
+
+        let mut held = locks.held.lock();
+        for (locked, key) in keys.iter().enumerate() {
+            while held.contains(key) {
+                if let Some(until) = deadline {
+                    let waited = locks.released.wait_until(&mut held, until);
+                    if waited.timed_out() && held.contains(key) {
+                        for acquired in &keys[..locked] {
+                            let _ = held.remove(acquired);
+                        }
+                        drop(held);
+                        locks.released.notify_all();
+                        return None;
+                    }
+                } else {
+                    locks.released.wait(&mut held);
+                }
+            }
+            let _ = held.insert(key.clone());
+        }
+        drop(held);
+
+        Some(KeyLockGuard { locks: locks.clone(), keys })
+    }
+}
+
+/// Releases the keys that it holds when it is dropped.
+#[derive(Debug)]
+pub(crate) struct KeyLockGuard {
+    locks: Arc<KeyLocks>,
+    keys: Vec<LockedKey>,
+}
+
+impl Drop for KeyLockGuard {
+    fn drop(&mut self) {
+        let mut held = self.locks.held.lock();
+        for key in &self.keys {
+            let _ = held.remove(key);
+        }
+        drop(held);
+        self.locks.released.notify_all();
+    }
+}
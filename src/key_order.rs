@@ -0,0 +1,204 @@
+//! Key orders other than the lexicographic order of the bytes,
+//! see `TreeConfig::order`.
+//!
+//! The nodes of a tree always sort their keys lexicographically,
+//! so a tree with a different order stores an encoding of each
+//! key that sorts lexicographically in the chosen order. Zero
+//! bytes are escaped as `00 FF` and the escaped key is followed
+//! by the terminator `00 00`, which makes the encoding of a key
+//! sort before the encoding of every longer key that it is a
+//! prefix of. Keys are encoded wherever they enter the `Tree`
+//! API and decoded wherever they leave it, everything below
+//! that, including the subscribers, the expiration of keys and
+//! backups, works with the encoded keys.
+use std::borrow::Cow;
+
+use crate::*;
+
+const ESCAPED_ZERO: u8 = 0xFF;
+pub(crate) const TERMINATOR: [u8; 2] = [0, 0];
+
+/// The order that the keys of a `Tree` are sorted in, which
+/// determines the order that they are iterated over in and the
+/// keys that fall within a range. The bounds of a range, and
+/// the keys passed to `Tree::get_lt` and `Tree::get_gt`, are
+/// interpreted in this order.
+///
+/// # Examples
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use sled::{KeyOrder, TreeConfig};
+///
+/// let db = sled::Config::new().temporary(true).open()?;
+///
+/// let newest_first = db.open_tree_with(
+///     "events",
+///     TreeConfig { order: KeyOrder::Reverse, ..TreeConfig::default() },
+/// )?;
+/// newest_first.insert(1_u64.to_be_bytes(), "first")?;
+/// newest_first.insert(2_u64.to_be_bytes(), "second")?;
+///
+/// let (_, latest) = newest_first.first()?.unwrap();
+/// assert_eq!(latest, "second");
+/// # Ok(()) }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyOrder {
+    /// Keys are sorted by their bytes, which is the order of
+    /// trees opened with `Db::open_tree`.
+    Lexicographic,
+    /// Keys are sorted by their bytes in descending order, so
+    /// iteration starts at the greatest key.
+    Reverse,
+    /// Keys are sorted by their bytes with ASCII letters folded
+    /// to lowercase, and keys that only differ in case are sorted
+    /// by their bytes. Such keys are still distinct, and
+    /// `Tree::scan_prefix` matches prefixes regardless of case.
+    CaseInsensitive,
+}
+
+impl KeyOrder {
+    /// Encodes a key into the bytes that are stored for it.
+    pub(crate) fn encode(self, key: &[u8]) -> Cow<'_, [u8]> {
+        match self {
+            KeyOrder::Lexicographic => Cow::Borrowed(key),
+            KeyOrder::Reverse => {
+                let mut ret = escape(key);
+                ret.extend_from_slice(&TERMINATOR);
+                for byte in &mut ret {
+                    *byte = !*byte;
+                }
+                Cow::Owned(ret)
+            }
+            KeyOrder::CaseInsensitive => {
+                let mut ret = escape(&key.to_ascii_lowercase());
+                ret.extend_from_slice(&TERMINATOR);
+                ret.extend_from_slice(key);
+                Cow::Owned(ret)
+            }
+        }
+    }
+
+    /// Encodes a prefix, such that the stored bytes of every key
+    /// that starts with it start with the result.
+    pub(crate) fn encode_prefix(self, prefix: &[u8]) -> Cow<'_, [u8]> {
+        match self {
+            KeyOrder::Lexicographic => Cow::Borrowed(prefix),
+            KeyOrder::Reverse => {
+                let mut ret = escape(prefix);
+                for byte in &mut ret {
+                    *byte = !*byte;
+                }
+                Cow::Owned(ret)
+            }
+            KeyOrder::CaseInsensitive => {
+                Cow::Owned(escape(&prefix.to_ascii_lowercase()))
+            }
+        }
+    }
+
+    /// Decodes the bytes that are stored for a key.
+    pub(crate) fn decode(self, key: IVec) -> IVec {
+        match self {
+            KeyOrder::Lexicographic => key,
+            KeyOrder::Reverse => {
+                let complement: Vec<u8> = key.iter().map(|b| !b).collect();
+                unescape(&complement).0.into()
+            }
+            KeyOrder::CaseInsensitive => {
+                let (_, len) = unescape(&key);
+                key[len..].into()
+            }
+        }
+    }
+
+    pub(crate) fn encode_batch(self, batch: Batch) -> Batch {
+        if self == KeyOrder::Lexicographic {
+            return batch;
+        }
+        let writes = batch
+            .writes
+            .into_iter()
+            .map(|(k, v)| (IVec::from(&*self.encode(&k)), v))
+            .collect();
+        Batch { writes }
+    }
+
+    pub(crate) fn decode_batch(self, batch: Batch) -> Batch {
+        if self == KeyOrder::Lexicographic {
+            return batch;
+        }
+        let writes = batch
+            .writes
+            .into_iter()
+            .map(|(k, v)| (self.decode(k), v))
+            .collect();
+        Batch { writes }
+    }
+}
+
+pub(crate) fn escape(key: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(key.len() + TERMINATOR.len());
+    for &byte in key {
+        ret.push(byte);
+        if byte == 0 {
+            ret.push(ESCAPED_ZERO);
+        }
+    }
+    ret
+}
+
+// returns the unescaped bytes before the terminator, and
+// the number of bytes up to the end of the terminator.
+pub(crate) fn unescape(buf: &[u8]) -> (Vec<u8>, usize) {
+    let mut ret = Vec::with_capacity(buf.len());
+    let mut idx = 0;
+    while idx < buf.len() {
+        if buf[idx] != 0 {
+            ret.push(buf[idx]);
+            idx += 1;
+        } else if buf.get(idx + 1) == Some(&ESCAPED_ZERO) {
+            ret.push(0);
+            idx += 2;
+        } else {
+            return (ret, (idx + TERMINATOR.len()).min(buf.len()));
+        }
+    }
+    (ret, buf.len())
+}
+
+#[test]
+fn encodings_sort_in_order() {
+    let mut keys: Vec<&[u8]> =
+        vec![b"", b"\0", b"\0\0", b"\0a", b"A", b"a", b"aB", b"ab", b"b"];
+
+    for order in
+        [KeyOrder::Lexicographic, KeyOrder::Reverse, KeyOrder::CaseInsensitive]
+            .iter()
+    {
+        let mut encoded: Vec<IVec> =
+            keys.iter().map(|k| IVec::from(&*order.encode(k))).collect();
+        encoded.sort();
+
+        let decoded: Vec<IVec> =
+            encoded.into_iter().map(|k| order.decode(k)).collect();
+
+        keys.sort_by(|a, b| match order {
+            KeyOrder::Lexicographic => a.cmp(b),
+            KeyOrder::Reverse => b.cmp(a),
+            KeyOrder::CaseInsensitive => a
+                .to_ascii_lowercase()
+                .cmp(&b.to_ascii_lowercase())
+                .then(a.cmp(b)),
+        });
+
+        let expected: Vec<IVec> = keys.iter().map(|k| IVec::from(*k)).collect();
+        assert_eq!(decoded, expected, "{:?}", order);
+
+        for key in &keys {
+            let prefix = order.encode_prefix(&key[..key.len() / 2]);
+            assert!(order.encode(key).starts_with(&prefix));
+        }
+    }
+}
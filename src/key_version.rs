@@ -0,0 +1,174 @@
+//! The version of the value that each key of a `Tree` holds, see
+//! `Tree::get_versioned` and `Tree::insert_if_version`.
+//!
+//! The versions of a `Tree` are tracked in a hidden companion
+//! `Tree` that is created the first time one of them is asked for:
+//!
+//! * `stored key` -> the big-endian sequence number of the write
+//!   that set the key, which is removed when the key is removed
+//!
+//! A write records its sequence number once it is linked and
+//! while it still holds the concurrency control, so a read looks
+//! up the version before the value, and may return an older
+//! version with a newer value, but never the other way around.
+//! `Tree::insert_if_version` holds the writer lock, which waits
+//! for every write to record its version, so a version that is
+//! older than the value only fails the swap.
+//!
+//! The sequence numbers of writes that were not recorded, because
+//! they happened before the versions were tracked or because the
+//! database crashed between a write and its record, are unknown,
+//! so a version is never older than a floor: the last sequence
+//! number that was reserved when the versions started to be
+//! tracked, or when the database was opened again. Every key
+//! gets a new version when the database is reopened.
+use std::convert::TryInto;
+
+use crate::*;
+
+const KEY_VERSIONS_TREE_PREFIX: &[u8] = b"__sled__key_versions__";
+
+/// The version of the value of a key, returned by
+/// `Tree::get_versioned`. A key gets a new version each time it is
+/// written, which is greater than the previous one, and when the
+/// database is reopened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version(u64);
+
+impl From<u64> for Version {
+    fn from(version: u64) -> Version {
+        Version(version)
+    }
+}
+
+impl From<Version> for u64 {
+    fn from(version: Version) -> u64 {
+        version.0
+    }
+}
+
+/// The versions of the keys of a `Tree`.
+#[derive(Debug)]
+pub(crate) struct KeyVersions {
+    pub(crate) tree: Tree,
+    // the oldest version that a key can have
+    floor: AtomicU64,
+}
+
+pub(crate) fn is_key_versions_tree_name(name: &[u8]) -> bool {
+    name.starts_with(KEY_VERSIONS_TREE_PREFIX)
+}
+
+pub(crate) fn parent_tree_name(name: &[u8]) -> Option<&[u8]> {
+    if is_key_versions_tree_name(name) {
+        Some(&name[KEY_VERSIONS_TREE_PREFIX.len()..])
+    } else {
+        None
+    }
+}
+
+pub(crate) fn key_versions_tree_name(tree_id: &[u8]) -> IVec {
+    let mut name = KEY_VERSIONS_TREE_PREFIX.to_vec();
+    name.extend_from_slice(tree_id);
+    name.into()
+}
+
+// the sequence number of the newest write that was reserved
+fn last_seq(tree: &Tree) -> u64 {
+    let log = &tree.context.pagecache.log;
+    history::seq(log.iobufs.max_reserved_lsn.load(Acquire))
+}
+
+/// Attaches a companion `Tree` that was loaded during startup to
+/// its parent.
+pub(crate) fn attach(tree: &Tree, companion: Tree) {
+    let floor = AtomicU64::new(last_seq(tree));
+    *tree.key_versions.write() =
+        Some(Arc::new(KeyVersions { tree: companion, floor }));
+}
+
+/// Returns the versions of a tree, creating the companion `Tree`
+/// that tracks them if necessary.
+pub(crate) fn open(tree: &Tree) -> Result<Arc<KeyVersions>> {
+    if let Some(key_versions) = tree.key_versions.read().clone() {
+        return Ok(key_versions);
+    }
+    if tree.context.read_only {
+        return Err(Error::Unsupported(
+            "the versions of keys can't be tracked in read-only mode".into(),
+        ));
+    }
+
+    let mut key_versions = tree.key_versions.write();
+    if let Some(key_versions) = key_versions.clone() {
+        return Ok(key_versions);
+    }
+
+    let guard = pin();
+    let companion = meta::open_tree(
+        &tree.context,
+        key_versions_tree_name(&tree.tree_id),
+        None,
+        &guard,
+    )?;
+
+    // a write that found no companion was reserved before the
+    // lock was taken, so the floor covers it
+    let floor = AtomicU64::new(last_seq(tree));
+    let created = Arc::new(KeyVersions { tree: companion, floor });
+    *key_versions = Some(created.clone());
+
+    Ok(created)
+}
+
+/// Records that the write with `seq` set a stored key, or removed
+/// it, if the tree tracks the versions of its keys. Must be called
+/// once the write is linked.
+pub(crate) fn record(
+    tree: &Tree,
+    key: &[u8],
+    set: bool,
+    seq: u64,
+) -> Result<()> {
+    let key_versions = if let Some(kv) = tree.key_versions.read().clone() {
+        kv
+    } else {
+        return Ok(());
+    };
+
+    let value = if set { Some(IVec::from(&seq.to_be_bytes())) } else { None };
+    let mut guard = pin();
+    let _ = key_versions.tree.set_inner(key, value, &mut guard)?;
+    Ok(())
+}
+
+/// Gives every key of a tree a new version, for writes that
+/// aren't recorded one key at a time. Must be called while the
+/// writer lock is held.
+pub(crate) fn renew(tree: &Tree) {
+    if let Some(key_versions) = tree.key_versions.read().clone() {
+        key_versions.floor.store(last_seq(tree), SeqCst);
+    }
+}
+
+/// Returns the version of a stored key, which is read before its
+/// value.
+pub(crate) fn get(
+    key_versions: &KeyVersions,
+    key: &[u8],
+    guard: &mut Guard,
+) -> Result<Version> {
+    let floor = key_versions.floor.load(SeqCst);
+    let raw = loop {
+        if let Ok(raw) = key_versions.tree.get_inner(key, guard)? {
+            break raw;
+        }
+    };
+    let recorded = if let Some(raw) = raw {
+        let bytes = (&*raw).try_into().map_err(|_| Error::corruption(None))?;
+        u64::from_be_bytes(bytes)
+    } else {
+        0
+    };
+    Ok(Version(recorded.max(floor)))
+}
@@ -0,0 +1,427 @@
+//! The latency histograms of a `Db`, see `Db::latency_histograms`.
+//!
+//! Every operation is timed inside sled, from the moment it's
+//! called to the moment it returns, so the histograms include the
+//! time it spends waiting on others, like a flush that waits for
+//! the buffers that other threads are writing to be made stable,
+//! or a transaction that waits for the writer lock and retries.
+//!
+//! Like an HDR histogram, the buckets are linear within every
+//! power of two, with 32 buckets between each power and the next,
+//! so that every latency is recorded with an error below 4% while
+//! a histogram takes only 9 KiB however long its tail is.
+//! Latencies above 2^40 nanoseconds, a little over 18 minutes,
+//! are recorded as that.
+use std::time::{Duration, Instant};
+
+use crate::*;
+
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+const MAX_BIT: u32 = 39;
+const MAX_NANOS: u64 = (1 << (MAX_BIT + 1)) - 1;
+const BUCKETS: usize =
+    (MAX_BIT - SUB_BUCKET_BITS + 2) as usize * (1 << SUB_BUCKET_BITS);
+
+/// An operation that is timed, see `Db::latency_histograms` and
+/// `Config::on_slow_op`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// `Tree::get`.
+    Get,
+    /// `Tree::insert`.
+    Insert,
+    /// `Tree::remove`.
+    Remove,
+    /// `Tree::flush` and `Tree::flush_async`.
+    Flush,
+    /// A transaction, from its first attempt to its commit.
+    Transaction,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Operation::Get => "get",
+            Operation::Insert => "insert",
+            Operation::Remove => "remove",
+            Operation::Flush => "flush",
+            Operation::Transaction => "transaction",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The histograms of a `Db`, which are reset when it's opened.
+#[derive(Debug, Default)]
+pub(crate) struct Histograms {
+    get: Recorder,
+    insert: Recorder,
+    remove: Recorder,
+    flush: Recorder,
+    transaction: Recorder,
+}
+
+#[derive(Debug)]
+struct Recorder {
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Default for Recorder {
+    fn default() -> Recorder {
+        let mut buckets = Vec::with_capacity(BUCKETS);
+        buckets.resize_with(BUCKETS, AtomicU64::default);
+        Recorder {
+            buckets,
+            sum: AtomicU64::new(0),
+            min: AtomicU64::new(u64::max_value()),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Times an operation and the phases that it goes through, which
+/// are only timed when slow operations are reported.
+#[derive(Debug)]
+pub(crate) struct Stopwatch {
+    op: Operation,
+    key_len: Option<usize>,
+    started: Instant,
+    pages_read_before: u64,
+    phases: Option<Phases>,
+}
+
+#[derive(Debug)]
+struct Phases {
+    current: &'static str,
+    entered: Instant,
+    // the phases that were left, with the time spent in every
+    // one of them summed when it's entered more than once
+    left: Vec<(&'static str, Duration)>,
+}
+
+/// Records the latency of an operation of a `Tree` when it's
+/// dropped, see `time`.
+pub(crate) struct Timer<'a> {
+    tree: &'a Tree,
+    stopwatch: Option<Stopwatch>,
+}
+
+impl Timer<'_> {
+    /// Leaves the current phase of the operation for `phase`.
+    pub(crate) fn enter(&mut self, phase: &'static str) {
+        if let Some(stopwatch) = &mut self.stopwatch {
+            stopwatch.enter(phase);
+        }
+    }
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        if let Some(stopwatch) = self.stopwatch.take() {
+            stopwatch.finish(self.tree);
+        }
+    }
+}
+
+/// Starts timing an operation of `tree`, starting with `phase`.
+/// It's recorded when the returned `Timer` is dropped.
+pub(crate) fn time<'a>(
+    tree: &'a Tree,
+    op: Operation,
+    key_len: Option<usize>,
+    phase: &'static str,
+) -> Timer<'a> {
+    let track_phases = tree.context.on_slow_op.is_some();
+    let stopwatch = Stopwatch::start(op, key_len, phase, track_phases);
+    Timer { tree, stopwatch: Some(stopwatch) }
+}
+
+impl Stopwatch {
+    pub(crate) fn start(
+        op: Operation,
+        key_len: Option<usize>,
+        phase: &'static str,
+        track_phases: bool,
+    ) -> Stopwatch {
+        let started = Instant::now();
+        let phases = if track_phases {
+            Some(Phases { current: phase, entered: started, left: vec![] })
+        } else {
+            None
+        };
+        Stopwatch {
+            op,
+            key_len,
+            started,
+            pages_read_before: db_metrics::pages_read_by_thread(),
+            phases,
+        }
+    }
+
+    pub(crate) fn enter(&mut self, phase: &'static str) {
+        if let Some(phases) = &mut self.phases {
+            let now = Instant::now();
+            phases.leave(now);
+            phases.current = phase;
+            phases.entered = now;
+        }
+    }
+
+    /// Records the latency of the operation in the histograms of
+    /// the `Db` of `tree`, and reports it if it was slow.
+    pub(crate) fn finish(self, tree: &Tree) {
+        let finished = Instant::now();
+        let latency = finished - self.started;
+        tree.context.latency.record(self.op, latency);
+
+        let handler = if let Some(handler) = &tree.context.on_slow_op {
+            handler
+        } else {
+            return;
+        };
+        if latency < handler.threshold() {
+            return;
+        }
+        let phases = self.phases.map_or_else(Vec::new, |mut phases| {
+            phases.leave(finished);
+            phases.left
+        });
+        let pages_read =
+            db_metrics::pages_read_by_thread() - self.pages_read_before;
+        handler.report(&SlowOp {
+            op: self.op,
+            tree: tree.tree_id.clone(),
+            key_len: self.key_len,
+            latency,
+            phases,
+            pages_read,
+        });
+    }
+}
+
+impl Phases {
+    fn leave(&mut self, now: Instant) {
+        let spent = now - self.entered;
+        let current = self.current;
+        if let Some(left) = self.left.iter_mut().find(|p| p.0 == current) {
+            left.1 += spent;
+        } else {
+            self.left.push((current, spent));
+        }
+    }
+}
+
+/// A snapshot of the latency histograms of a `Db`, returned by
+/// `Db::latency_histograms`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyHistograms {
+    /// The latency of `Tree::get`.
+    pub get: LatencyHistogram,
+    /// The latency of `Tree::insert`.
+    pub insert: LatencyHistogram,
+    /// The latency of `Tree::remove`.
+    pub remove: LatencyHistogram,
+    /// The latency of `Tree::flush` and `Tree::flush_async`,
+    /// including the wait for the writes of other threads that
+    /// are flushed with it.
+    pub flush: LatencyHistogram,
+    /// The latency of transactions, from the first attempt to
+    /// the commit, including the waits between their retries.
+    pub transaction: LatencyHistogram,
+}
+
+/// A snapshot of the latencies of one operation, see
+/// `LatencyHistograms`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyHistogram {
+    buckets: Vec<u64>,
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+// the bucket of a latency in nanoseconds
+fn bucket(latency: u64) -> usize {
+    let nanos = latency.min(MAX_NANOS);
+    if nanos < SUB_BUCKETS {
+        return usize::try_from(nanos).unwrap();
+    }
+    let shift = 63 - nanos.leading_zeros() - SUB_BUCKET_BITS;
+    let index = u64::from(shift) * SUB_BUCKETS + (nanos >> shift);
+    usize::try_from(index).unwrap()
+}
+
+// the highest latency in nanoseconds that is recorded in a bucket
+fn bucket_max(bucket: usize) -> u64 {
+    let index = u64::try_from(bucket).unwrap();
+    if index < 2 * SUB_BUCKETS {
+        return index;
+    }
+    let shift = index / SUB_BUCKETS - 1;
+    ((index - shift * SUB_BUCKETS + 1) << shift) - 1
+}
+
+fn nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::max_value())
+}
+
+impl Histograms {
+    fn recorder(&self, op: Operation) -> &Recorder {
+        match op {
+            Operation::Get => &self.get,
+            Operation::Insert => &self.insert,
+            Operation::Remove => &self.remove,
+            Operation::Flush => &self.flush,
+            Operation::Transaction => &self.transaction,
+        }
+    }
+
+    fn record(&self, op: Operation, latency: Duration) {
+        self.recorder(op).record(nanos(latency));
+    }
+
+    pub(crate) fn snapshot(&self) -> LatencyHistograms {
+        LatencyHistograms {
+            get: self.get.snapshot(),
+            insert: self.insert.snapshot(),
+            remove: self.remove.snapshot(),
+            flush: self.flush.snapshot(),
+            transaction: self.transaction.snapshot(),
+        }
+    }
+
+    /// Empties every histogram. The operations that finish while
+    /// they're emptied may be kept or dropped.
+    pub(crate) fn reset(&self) {
+        for recorder in &[
+            &self.get,
+            &self.insert,
+            &self.remove,
+            &self.flush,
+            &self.transaction,
+        ] {
+            recorder.reset();
+        }
+    }
+}
+
+impl Recorder {
+    fn record(&self, nanos: u64) {
+        let _ = self.buckets[bucket(nanos)].fetch_add(1, Relaxed);
+        let _ = self.sum.fetch_add(nanos, Relaxed);
+
+        let mut min = self.min.load(Relaxed);
+        while nanos < min {
+            match self.min.compare_exchange_weak(min, nanos, Relaxed, Relaxed)
+            {
+                Ok(_) => break,
+                Err(current) => min = current,
+            }
+        }
+        let mut max = self.max.load(Relaxed);
+        while nanos > max {
+            match self.max.compare_exchange_weak(max, nanos, Relaxed, Relaxed)
+            {
+                Ok(_) => break,
+                Err(current) => max = current,
+            }
+        }
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        let buckets: Vec<u64> =
+            self.buckets.iter().map(|bucket| bucket.load(Relaxed)).collect();
+        LatencyHistogram {
+            count: buckets.iter().sum(),
+            buckets,
+            sum: self.sum.load(Relaxed),
+            min: self.min.load(Relaxed),
+            max: self.max.load(Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Relaxed);
+        }
+        self.sum.store(0, Relaxed);
+        self.min.store(u64::max_value(), Relaxed);
+        self.max.store(0, Relaxed);
+    }
+}
+
+impl LatencyHistogram {
+    /// Returns the number of latencies that were recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the lowest latency, or 0 if none was recorded.
+    pub fn min(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::from_nanos(0);
+        }
+        Duration::from_nanos(self.min)
+    }
+
+    /// Returns the highest latency, or 0 if none was recorded.
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max)
+    }
+
+    /// Returns the sum of the latencies.
+    pub fn sum(&self) -> Duration {
+        Duration::from_nanos(self.sum)
+    }
+
+    /// Returns the mean latency, or 0 if none was recorded.
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::from_nanos(0);
+        }
+        Duration::from_nanos(self.sum / self.count)
+    }
+
+    /// Returns the latency that `percentile` percent of the
+    /// latencies are at or below, with an error below 4%, or 0 if
+    /// none was recorded. `percentile` is clamped between 0 and
+    /// 100.
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::cast_sign_loss)]
+    #[allow(clippy::float_arithmetic)]
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::from_nanos(0);
+        }
+        let fraction = percentile.max(0.).min(100.) / 100.;
+        let rank = ((fraction * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let nanos = bucket_max(index).min(self.max).max(self.min);
+                return Duration::from_nanos(nanos);
+            }
+        }
+        self.max()
+    }
+
+    /// Returns the buckets that recorded any latency, in order,
+    /// as the highest latency that each one records and the
+    /// number of latencies that it recorded.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(index, count)| {
+                (Duration::from_nanos(bucket_max(index)), *count)
+            })
+    }
+}
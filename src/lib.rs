@@ -177,39 +177,110 @@ macro_rules! testing_assert {
     };
 }
 
+// enters a `tracing` span for the rest of the enclosing block when
+// the `tracing` feature is enabled. the fields are only evaluated
+// if a subscriber is interested in the span.
+macro_rules! tracing_span {
+    ($name:literal $(, $field:ident = $value:expr)* $(,)?) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            target: "sled",
+            $name
+            $(, $field = $value)*
+        )
+        .entered();
+    };
+}
+
+// emits a `tracing` event when the `tracing` feature is enabled
+macro_rules! tracing_event {
+    ($name:literal $(, $field:ident = $value:expr)* $(,)?) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(target: "sled", $($field = $value,)* $name);
+    };
+}
+
+mod async_db;
 mod atomic_shim;
+mod background;
 mod backoff;
+mod backup;
 mod batch;
+mod bloom;
+mod bulk_load;
 mod cache_padded;
+mod checksum;
+mod compact;
 mod concurrency_control;
 mod config;
 mod context;
+mod counter;
 mod db;
+mod db_metrics;
+pub mod dump;
 mod dll;
 mod ebr;
+mod encryption;
+mod event_filter;
+mod expiration;
 mod fastcmp;
+mod fault;
 mod fastlock;
 mod fnv;
 mod histogram;
+mod history;
+mod index;
+mod ingest;
+mod integrity;
 mod iter;
 mod ivec;
+mod key_lock;
+mod key_version;
+mod key_order;
+mod latency;
 mod lazy;
 mod lru;
+mod memory;
+pub mod merge;
+mod merge_operators;
 mod meta;
 #[cfg(feature = "metrics")]
 mod metrics;
+mod namespace;
 mod node;
 mod oneshot;
 mod pagecache;
+#[cfg(feature = "metrics-prometheus")]
+mod prometheus;
+mod quota;
+mod range_size;
 mod result;
+mod salvage;
+mod sample;
+mod scrub;
+mod sequence;
+mod shared_cache;
+mod slow_op;
 mod serialization;
+pub mod snapshot;
+mod space;
+mod spill;
 mod stack;
 mod subscriber;
 mod sys_limits;
 mod threadpool;
 pub mod transaction;
 mod tree;
+mod tree_file;
+mod tree_stats;
+#[cfg(feature = "serde")]
+mod typed;
+mod value_guard;
+mod value_log;
 mod varint;
+mod versions;
+mod warmup;
+mod write_options;
 
 /// Functionality for conditionally triggering failpoints under test.
 #[cfg(feature = "failpoints")]
@@ -289,17 +360,52 @@ pub use self::{
 };
 
 pub use self::{
+    async_db::{AsyncDb, AsyncTree},
     batch::Batch,
-    config::{Config, Mode},
+    checksum::Checksum,
+    compact::{CompactOptions, CompactProgress},
+    config::{
+        CachePolicy, CachePriority, Codec, Config, Mode, RecoveryMode,
+        SubscriberOverflow, SyncPolicy, TreeConfig,
+    },
     db::Db,
+    db_metrics::{DbMetrics, TreeMetrics},
+    encryption::KeyProvider,
+    event_filter::{EventFilter, EventKinds},
+    fault::Fault,
+    index::{Index, IndexFunction, IndexIter},
+    ingest::IngestSession,
+    integrity::{IntegrityProblem, IntegrityReport},
     iter::Iter,
     ivec::IVec,
+    key_order::KeyOrder,
+    key_version::Version,
+    latency::{LatencyHistogram, LatencyHistograms, Operation},
+    memory::MemoryUsage,
+    namespace::Namespace,
+    quota::{Quota, QuotaAction, QuotaCallback, QuotaLimit},
     result::{Error, Result},
-    subscriber::{Event, Subscriber},
+    salvage::LostRange,
+    scrub::ScrubFailure,
+    sequence::Sequence,
+    shared_cache::SharedCache,
+    slow_op::SlowOp,
+    space::{SegmentSpace, SegmentState, SpaceUsage, TreeSpace},
+    subscriber::{Event, KeyWatch, PreviousIter, Subscriber},
     transaction::Transactional,
-    tree::{CompareAndSwapError, Tree},
+    tree::{
+        CompareAndSwapError, CompareAndSwapManyError, Tree,
+        VersionMismatchError,
+    },
+    tree_stats::TreeStats,
+    value_guard::ValueGuard,
+    versions::{VersionAt, VersionIter},
+    write_options::{Durability, WriteOptions},
 };
 
+#[cfg(feature = "serde")]
+pub use self::typed::{TypedIter, TypedTree};
+
 #[cfg(feature = "metrics")]
 use self::{
     histogram::Histogram,
@@ -310,6 +416,7 @@ use {
     self::{
         atomic_shim::{AtomicI64 as AtomicLsn, AtomicU64},
         backoff::Backoff,
+        bloom::Blooms,
         cache_padded::CachePadded,
         concurrency_control::Protector,
         context::Context,
@@ -318,13 +425,17 @@ use {
             Shared,
         },
         fastcmp::fastcmp,
+        index::Indexes,
         lru::Lru,
+        merge_operators::MergeOperators,
         meta::Meta,
         node::Node,
         oneshot::{OneShot, OneShotFiller},
         result::CasResult,
+        shared_cache::Tenant,
         subscriber::Subscribers,
         tree::TreeInner,
+        value_log::ValueLog,
     },
     log::{debug, error, trace, warn},
     pagecache::{constants::MAX_BLOB, RecoveryGuard},
@@ -373,13 +484,18 @@ fn crc32(buf: &[u8]) -> u32 {
     hasher.finalize()
 }
 
-fn calculate_message_crc32(header: &[u8], body: &[u8]) -> u32 {
+fn calculate_message_crc32(
+    checksum: Checksum,
+    header: &[u8],
+    body: &[u8],
+) -> u32 {
     trace!(
-        "calculating crc32 for header len {} body len {}",
+        "calculating {:?} for header len {} body len {}",
+        checksum,
         header.len(),
         body.len()
     );
-    let mut hasher = crc32fast::Hasher::new();
+    let mut hasher = checksum.hasher();
     hasher.update(body);
     hasher.update(&header[4..]);
     let crc32 = hasher.finalize();
@@ -515,14 +631,19 @@ mod compile_time_assertions {
     #[allow(unreachable_code)]
     fn _assert_public_types_send_sync() {
         _assert_send::<Subscriber>(unreachable!());
+        _assert_send::<transaction::Txn>(unreachable!());
 
         _assert_send_sync::<Iter>(unreachable!());
+        _assert_send_sync::<snapshot::Snapshot>(unreachable!());
+        _assert_send_sync::<snapshot::SnapshotIter>(unreachable!());
+        _assert_send_sync::<VersionIter>(unreachable!());
         _assert_send_sync::<Tree>(unreachable!());
         _assert_send_sync::<Db>(unreachable!());
         _assert_send_sync::<Batch>(unreachable!());
         _assert_send_sync::<IVec>(unreachable!());
         _assert_send_sync::<Config>(unreachable!());
         _assert_send_sync::<CompareAndSwapError>(unreachable!());
+        _assert_send_sync::<CompareAndSwapManyError>(unreachable!());
         _assert_send_sync::<Error>(unreachable!());
         _assert_send_sync::<Event>(unreachable!());
         _assert_send_sync::<Mode>(unreachable!());
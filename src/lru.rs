@@ -10,10 +10,11 @@ use std::{
 
 use crate::{
     atomic_shim::AtomicU64,
+    backoff::Backoff,
     debug_delay,
     dll::{DoublyLinkedList, Node},
-    fastlock::FastLock,
-    FastSet8, Guard, PageId,
+    fastlock::{FastLock, FastLockGuard},
+    CachePolicy, CachePriority, FastSet8, Guard, PageId,
 };
 
 #[cfg(any(test, feature = "lock_free_delays"))]
@@ -28,6 +29,24 @@ const N_SHARDS: usize = 2;
 #[cfg(not(any(test, feature = "lock_free_delays")))]
 const N_SHARDS: usize = 256;
 
+const SHARD_BITS: usize = N_SHARDS.trailing_zeros() as usize;
+
+/// The bits of the ids that an `Lru` can track, which are stored
+/// without the bits of their shard in 32 bits.
+pub(crate) const ID_BITS: usize = 32 + SHARD_BITS;
+
+// the rows of a `FrequencySketch`, and the count at which its
+// counters saturate
+const SKETCH_DEPTH: usize = 4;
+const MAX_FREQUENCY: u8 = 15;
+
+const SKETCH_SEEDS: [u64; SKETCH_DEPTH] = [
+    0x9E37_79B9_7F4A_7C15,
+    0xC2B2_AE3D_27D4_EB4F,
+    0x1656_67B1_9E37_79F9,
+    0x85EB_CA77_C2B2_AE63,
+];
+
 struct AccessBlock {
     len: AtomicUsize,
     block: [AtomicU64; MAX_QUEUE_ITEMS],
@@ -212,11 +231,16 @@ pub(crate) struct CacheAccess {
     //                     2**37 /   2**8   < 2**32
     pub pid: u32,
     pub sz: u8,
+    // the index of the list of the item in its shard,
+    // 0: low, 1: normal, 2: high, 3: pinned
+    pub priority: u8,
 }
 
 impl From<CacheAccess> for u64 {
     fn from(ca: CacheAccess) -> u64 {
-        (u64::from(ca.pid) << 8) | u64::from(ca.sz)
+        (u64::from(ca.pid) << 10)
+            | (u64::from(ca.priority) << 8)
+            | u64::from(ca.sz)
     }
 }
 
@@ -225,11 +249,13 @@ impl From<u64> for CacheAccess {
     fn from(u: u64) -> CacheAccess {
         let sz = usize::try_from((u << 56) >> 56).unwrap();
         assert_ne!(sz, 0);
-        let pid = u >> 8;
+        let priority = (u >> 8) & 0b11;
+        let pid = u >> 10;
         assert!(pid < u64::from(std::u32::MAX));
         CacheAccess {
             pid: u32::try_from(pid).unwrap(),
             sz: u8::try_from(sz).unwrap(),
+            priority: u8::try_from(priority).unwrap(),
         }
     }
 }
@@ -239,13 +265,19 @@ impl CacheAccess {
         1 << usize::from(self.sz)
     }
 
-    fn new(pid: PageId, sz: usize) -> CacheAccess {
+    fn new(pid: PageId, sz: usize, priority: CachePriority) -> CacheAccess {
         let rounded_up_power_of_2 =
             u8::try_from(sz.next_power_of_two().trailing_zeros()).unwrap();
 
         CacheAccess {
             pid: u32::try_from(pid).expect("expected caller to shift pid down"),
             sz: rounded_up_power_of_2,
+            priority: match priority {
+                CachePriority::Low => 0,
+                CachePriority::Normal => 1,
+                CachePriority::High => 2,
+                CachePriority::Pinned => PINNED,
+            },
         }
     }
 }
@@ -253,11 +285,12 @@ impl CacheAccess {
 /// A simple LRU cache.
 pub struct Lru {
     shards: Vec<(AccessQueue, FastLock<Shard>)>,
+    capacity: AtomicUsize,
 }
 
 impl Lru {
     /// Instantiates a new `Lru` cache.
-    pub(crate) fn new(cache_capacity: usize) -> Self {
+    pub(crate) fn new(cache_capacity: usize, policy: CachePolicy) -> Self {
         assert!(
             cache_capacity >= N_SHARDS,
             "Please configure the cache \
@@ -267,10 +300,52 @@ impl Lru {
 
         let mut shards = Vec::with_capacity(N_SHARDS);
         shards.resize_with(N_SHARDS, || {
-            (AccessQueue::default(), FastLock::new(Shard::new(shard_capacity)))
+            let shard = Shard::new(shard_capacity, policy);
+            (AccessQueue::default(), FastLock::new(shard))
         });
 
-        Self { shards }
+        Self { shards, capacity: AtomicUsize::new(cache_capacity) }
+    }
+
+    /// Returns the capacity of the cache in bytes.
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Acquire)
+    }
+
+    /// Returns the bytes of the items in the cache. Waits for the
+    /// shards that are being accessed.
+    pub(crate) fn size(&self) -> usize {
+        self.shards.iter().map(|(_, shard_mu)| lock_shard(shard_mu).size).sum()
+    }
+
+    /// Changes the capacity of the cache, returning the items
+    /// that have to be evicted for it to fit in a smaller one.
+    /// Waits for the shards that are being accessed.
+    pub(crate) fn set_capacity(&self, cache_capacity: usize) -> Vec<PageId> {
+        assert!(cache_capacity >= N_SHARDS);
+        self.capacity.store(cache_capacity, Ordering::Release);
+        let shard_capacity = cache_capacity / N_SHARDS;
+
+        let mut ret = vec![];
+        for (idx, (_, shard_mu)) in self.shards.iter().enumerate() {
+            let mut shard = lock_shard(shard_mu);
+            shard.capacity = shard_capacity;
+            let shard_idx = PageId::try_from(idx).unwrap();
+            for pos in shard.evict() {
+                ret.push((PageId::from(pos) << SHARD_BITS) + shard_idx);
+            }
+        }
+        ret
+    }
+
+    /// Stops tracking the items for which `f` returns true, without
+    /// evicting them. Waits for the shards that are being accessed.
+    pub(crate) fn forget<F: Fn(PageId) -> bool>(&self, f: F) {
+        for (idx, (_, shard_mu)) in self.shards.iter().enumerate() {
+            let mut shard = lock_shard(shard_mu);
+            let shard_idx = PageId::try_from(idx).unwrap();
+            shard.forget(|pos| f((PageId::from(pos) << SHARD_BITS) + shard_idx));
+        }
     }
 
     /// Called when an item is accessed. Returns a Vec of items to be
@@ -282,20 +357,24 @@ impl Lru {
     ///   shards:  1 0 1 0 1 0 1 0 1 0
     ///   shard 0:   2   4   6   8   10
     ///   shard 1: 1   3   5   7   9
+    ///
+    /// Items are evicted from the least recently used ones with
+    /// the lowest `CachePriority` up, and pinned items are never
+    /// evicted. Under `CachePolicy::TinyLfu`,
+    /// an item that isn't cached may be evicted right away instead.
     pub(crate) fn accessed(
         &self,
         id: PageId,
         item_size: usize,
+        priority: CachePriority,
         guard: &Guard,
     ) -> Vec<PageId> {
-        const SHARD_BITS: usize = N_SHARDS.trailing_zeros() as usize;
-
         let mut ret = vec![];
         let shards = N_SHARDS as u64;
         let (shard_idx, shifted_pid) = (id % shards, id >> SHARD_BITS);
         let (access_queue, shard_mu) = &self.shards[safe_usize(shard_idx)];
 
-        let cache_access = CacheAccess::new(shifted_pid, item_size);
+        let cache_access = CacheAccess::new(shifted_pid, item_size, priority);
         let filled = access_queue.push(cache_access);
 
         if filled {
@@ -339,7 +418,7 @@ impl PartialOrd<Entry> for Entry {
 
 impl PartialEq for Entry {
     fn eq(&self, other: &Entry) -> bool {
-        unsafe { (*self.0).pid == (*other.0).pid }
+        unsafe { (&*self.0).pid == (&*other.0).pid }
     }
 }
 
@@ -357,7 +436,7 @@ impl Borrow<CacheAccess> for Entry {
 
 impl Borrow<u32> for Entry {
     fn borrow(&self) -> &u32 {
-        unsafe { &(*self.0).pid }
+        unsafe { &(&*self.0).pid }
     }
 }
 
@@ -365,55 +444,217 @@ impl Borrow<u32> for Entry {
 // sz sometimes and we access the item by pid
 impl Hash for Entry {
     fn hash<H: Hasher>(&self, hasher: &mut H) {
-        unsafe { (*self.0).pid.hash(hasher) }
+        unsafe { (&*self.0).pid.hash(hasher) }
     }
 }
 
+/// A count-min sketch of how often the items of a shard were
+/// accessed, with 4 bit counters that are halved once it has
+/// counted 10 accesses per counter of a row, so that it follows
+/// the items that are used now.
+struct FrequencySketch {
+    table: Vec<u8>,
+    mask: usize,
+    additions: usize,
+    sample_size: usize,
+}
+
+impl FrequencySketch {
+    fn new(shard_capacity: usize) -> FrequencySketch {
+        // about one counter per kb of the shard in every row
+        let width = (shard_capacity / 1024)
+            .next_power_of_two()
+            .max(16)
+            .min(1 << 12);
+
+        FrequencySketch {
+            table: vec![0; width * SKETCH_DEPTH],
+            mask: width - 1,
+            additions: 0,
+            sample_size: width * 10,
+        }
+    }
+
+    fn index(&self, pid: u32, row: usize) -> usize {
+        let hash = (u64::from(pid) + 1).wrapping_mul(SKETCH_SEEDS[row]);
+        let column = usize::try_from(hash >> 32).unwrap() & self.mask;
+        row * (self.mask + 1) + column
+    }
+
+    fn frequency(&self, pid: u32) -> u8 {
+        (0..SKETCH_DEPTH)
+            .map(|row| self.table[self.index(pid, row)])
+            .min()
+            .unwrap()
+    }
+
+    fn increment(&mut self, pid: u32) {
+        let mut added = false;
+        for row in 0..SKETCH_DEPTH {
+            let idx = self.index(pid, row);
+            if self.table[idx] < MAX_FREQUENCY {
+                self.table[idx] += 1;
+                added = true;
+            }
+        }
+
+        if added {
+            self.additions += 1;
+            if self.additions >= self.sample_size {
+                for counter in &mut self.table {
+                    *counter /= 2;
+                }
+                self.additions /= 2;
+            }
+        }
+    }
+}
+
+// the list of the pinned items, which are never evicted
+const PINNED: u8 = 3;
+
 struct Shard {
-    dll: DoublyLinkedList,
+    // one list per priority, which are evicted from in order
+    dlls: [DoublyLinkedList; 4],
     entries: FastSet8<Entry>,
     capacity: usize,
     size: usize,
+    // only kept under `CachePolicy::TinyLfu`
+    sketch: Option<FrequencySketch>,
 }
 
 impl Shard {
-    fn new(capacity: usize) -> Self {
+    fn new(capacity: usize, policy: CachePolicy) -> Self {
         assert!(capacity > 0, "shard capacity must be non-zero");
 
+        let sketch = match policy {
+            CachePolicy::Lru => None,
+            CachePolicy::TinyLfu => Some(FrequencySketch::new(capacity)),
+        };
+
         Self {
-            dll: DoublyLinkedList::default(),
+            dlls: Default::default(),
             entries: FastSet8::default(),
             capacity,
             size: 0,
+            sketch,
         }
     }
 
+    fn len(&self) -> usize {
+        self.dlls.iter().map(DoublyLinkedList::len).sum()
+    }
+
     /// `PageId`s in the shard list are indexes of the entries.
     fn accessed(&mut self, cache_access: CacheAccess) -> Vec<u32> {
+        if let Some(sketch) = &mut self.sketch {
+            sketch.increment(cache_access.pid);
+        }
+
+        let priority = usize::from(cache_access.priority);
         if let Some(entry) = self.entries.get(&cache_access.pid) {
-            let old_sz_po2 = unsafe { (*entry.0).swap_sz(cache_access.sz) };
-            let old_size = 1 << usize::from(old_sz_po2);
+            let old_priority = usize::from(unsafe { (&*entry.0).priority });
+            if old_priority == priority {
+                let old_sz_po2 = unsafe { (*entry.0).swap_sz(cache_access.sz) };
+                let old_size = 1 << usize::from(old_sz_po2);
 
-            self.size -= old_size;
-            self.dll.promote(entry.0);
+                self.size -= old_size;
+                self.dlls[priority].promote(entry.0);
+            } else {
+                // the page was reused by a tree with another priority
+                let ptr = entry.0;
+                self.entries.remove(&cache_access.pid);
+                let old = self.dlls[old_priority].remove(ptr);
+                self.size -= old.size();
+
+                let ptr = self.dlls[priority].push_head(cache_access);
+                self.entries.insert(Entry(ptr));
+            }
         } else {
-            let ptr = self.dll.push_head(cache_access);
+            if !self.admits(cache_access) {
+                return vec![cache_access.pid];
+            }
+            let ptr = self.dlls[priority].push_head(cache_access);
             self.entries.insert(Entry(ptr));
         };
 
         self.size += cache_access.size();
 
+        self.evict()
+    }
+
+    fn forget<F: Fn(u32) -> bool>(&mut self, f: F) {
+        let pids: Vec<u32> = self
+            .entries
+            .iter()
+            .map(|entry| unsafe { (&*entry.0).pid })
+            .filter(|pid| f(*pid))
+            .collect();
+
+        for pid in pids {
+            let entry = self.entries.take(&pid).unwrap();
+            let priority = usize::from(unsafe { (&*entry.0).priority });
+            let old = self.dlls[priority].remove(entry.0);
+            self.size -= old.size();
+        }
+    }
+
+    /// Whether an item that isn't cached may take the place of the
+    /// one that would be evicted to make room for it, which is
+    /// always the case unless the shard keeps a `FrequencySketch`.
+    /// Items of a higher priority are always admitted.
+    fn admits(&self, candidate: CacheAccess) -> bool {
+        let sketch = if let Some(sketch) = &self.sketch {
+            sketch
+        } else {
+            return true;
+        };
+
+        if self.size + candidate.size() <= self.capacity {
+            return true;
+        }
+
+        let victim = if let Some(victim) = self.dlls[..usize::from(PINNED)]
+            .iter()
+            .find_map(DoublyLinkedList::peek_tail)
+        {
+            victim
+        } else {
+            return true;
+        };
+
+        (candidate.priority, sketch.frequency(candidate.pid))
+            > (victim.priority, sketch.frequency(victim.pid))
+    }
+
+    /// Evicts the least recently used entries that aren't pinned
+    /// until the shard fits in its capacity, returning their
+    /// `PageId`s.
+    fn evict(&mut self) -> Vec<u32> {
         let mut to_evict = vec![];
 
         while self.size > self.capacity {
-            if self.dll.len() == 1 {
-                // don't evict what we just added
+            if self.len() == 1 {
+                // don't evict what may have just been added
                 break;
             }
 
-            let min_pid = self.dll.pop_tail().unwrap();
+            let dll = if let Some(dll) = self.dlls[..usize::from(PINNED)]
+                .iter_mut()
+                .find(|dll| dll.len() > 0)
+            {
+                dll
+            } else {
+                // only pinned entries are left
+                break;
+            };
 
-            self.entries.remove(&min_pid.pid);
+            // the entries are compared through their nodes, so the
+            // entry has to be removed before its node is freed
+            let tail_pid = dll.peek_tail().unwrap().pid;
+            self.entries.remove(&tail_pid);
+
+            let min_pid = dll.pop_tail().unwrap();
 
             to_evict.push(min_pid.pid);
 
@@ -424,6 +665,16 @@ impl Shard {
     }
 }
 
+fn lock_shard(shard_mu: &FastLock<Shard>) -> FastLockGuard<'_, Shard> {
+    let backoff = Backoff::new();
+    loop {
+        if let Some(shard) = shard_mu.try_lock() {
+            return shard;
+        }
+        backoff.spin();
+    }
+}
+
 #[inline]
 fn safe_usize(value: PageId) -> usize {
     usize::try_from(value).unwrap()
@@ -433,10 +684,10 @@ fn safe_usize(value: PageId) -> usize {
 fn lru_smoke_test() {
     use crate::pin;
 
-    let lru = Lru::new(2);
+    let lru = Lru::new(2, CachePolicy::Lru);
     for i in 0..1000 {
         let guard = pin();
-        lru.accessed(i, 16, &guard);
+        lru.accessed(i, 16, CachePriority::Normal, &guard);
     }
 }
 
@@ -444,24 +695,112 @@ fn lru_smoke_test() {
 fn lru_access_test() {
     use crate::pin;
 
-    let ci = CacheAccess::new(6, 20667);
+    let ci = CacheAccess::new(6, 20667, CachePriority::Normal);
     assert_eq!(ci.size(), 32 * 1024);
 
-    let lru = Lru::new(4096);
+    let lru = Lru::new(4096, CachePolicy::Lru);
+
+    let guard = pin();
+
+    assert_eq!(lru.accessed(0, 20667, CachePriority::Normal, &guard), vec![]);
+    assert_eq!(lru.accessed(2, 20667, CachePriority::Normal, &guard), vec![]);
+    assert_eq!(lru.accessed(4, 20667, CachePriority::Normal, &guard), vec![]);
+    assert_eq!(lru.accessed(6, 20667, CachePriority::Normal, &guard), vec![]);
+    assert_eq!(lru.accessed(8, 20667, CachePriority::Normal, &guard), vec![0, 2, 4]);
+    assert_eq!(lru.accessed(10, 20667, CachePriority::Normal, &guard), vec![]);
+    assert_eq!(lru.accessed(12, 20667, CachePriority::Normal, &guard), vec![]);
+    assert_eq!(lru.accessed(14, 20667, CachePriority::Normal, &guard), vec![]);
+    assert_eq!(lru.accessed(16, 20667, CachePriority::Normal, &guard), vec![6, 8, 10, 12]);
+    assert_eq!(lru.accessed(18, 20667, CachePriority::Normal, &guard), vec![]);
+    assert_eq!(lru.accessed(20, 20667, CachePriority::Normal, &guard), vec![]);
+    assert_eq!(lru.accessed(22, 20667, CachePriority::Normal, &guard), vec![]);
+    assert_eq!(lru.accessed(24, 20667, CachePriority::Normal, &guard), vec![14, 16, 18, 20]);
+}
+
+#[test]
+fn lru_priority_test() {
+    use crate::pin;
+
+    let lru = Lru::new(4096, CachePolicy::Lru);
+
+    let guard = pin();
+
+    let high = CachePriority::High;
+    let low = CachePriority::Low;
+
+    assert_eq!(lru.accessed(0, 20667, high, &guard), vec![]);
+    assert_eq!(lru.accessed(2, 20667, low, &guard), vec![]);
+    assert_eq!(lru.accessed(4, 20667, high, &guard), vec![]);
+    assert_eq!(lru.accessed(6, 20667, low, &guard), vec![]);
+    // low priority items are evicted before high priority ones,
+    // even right after they were used
+    assert_eq!(lru.accessed(8, 20667, high, &guard), vec![2, 0, 6]);
+}
+
+#[test]
+fn lru_pinned_test() {
+    use crate::pin;
+
+    let lru = Lru::new(4096, CachePolicy::Lru);
+
+    let guard = pin();
+
+    // pinned items are kept even though they don't fit, while the
+    // others are evicted around them
+    let mut evicted = vec![];
+    for i in 0..4 {
+        evicted.extend(lru.accessed(i, 20667, CachePriority::Pinned, &guard));
+    }
+    for i in 4..64 {
+        evicted.extend(lru.accessed(i, 20667, CachePriority::High, &guard));
+    }
+    assert!(!evicted.is_empty());
+    assert!(evicted.iter().all(|id| *id >= 4), "{:?}", evicted);
+}
+
+
+#[test]
+fn lru_set_capacity_test() {
+    use crate::pin;
+
+    let lru = Lru::new(1 << 20, CachePolicy::Lru);
 
     let guard = pin();
 
-    assert_eq!(lru.accessed(0, 20667, &guard), vec![]);
-    assert_eq!(lru.accessed(2, 20667, &guard), vec![]);
-    assert_eq!(lru.accessed(4, 20667, &guard), vec![]);
-    assert_eq!(lru.accessed(6, 20667, &guard), vec![]);
-    assert_eq!(lru.accessed(8, 20667, &guard), vec![0, 2, 4]);
-    assert_eq!(lru.accessed(10, 20667, &guard), vec![]);
-    assert_eq!(lru.accessed(12, 20667, &guard), vec![]);
-    assert_eq!(lru.accessed(14, 20667, &guard), vec![]);
-    assert_eq!(lru.accessed(16, 20667, &guard), vec![6, 8, 10, 12]);
-    assert_eq!(lru.accessed(18, 20667, &guard), vec![]);
-    assert_eq!(lru.accessed(20, 20667, &guard), vec![]);
-    assert_eq!(lru.accessed(22, 20667, &guard), vec![]);
-    assert_eq!(lru.accessed(24, 20667, &guard), vec![14, 16, 18, 20]);
+    for i in 0..24 {
+        assert_eq!(lru.accessed(i, 20667, CachePriority::Normal, &guard), vec![]);
+    }
+
+    // the accesses are applied in blocks, and every shard keeps
+    // the page that was accessed last
+    let mut evicted = lru.set_capacity(4096);
+    evicted.sort_unstable();
+    assert_eq!(evicted, (0..14).collect::<Vec<_>>());
+    assert_eq!(lru.capacity(), 4096);
+    assert_eq!(lru.set_capacity(1 << 20), vec![]);
+}
+
+#[test]
+fn lru_tiny_lfu_test() {
+    use crate::pin;
+
+    let lru = Lru::new(3 << 16, CachePolicy::TinyLfu);
+
+    let guard = pin();
+
+    // 3 of the 32k items that are accessed over and over fit in
+    // each shard
+    let mut evicted = vec![];
+    for _ in 0..8 {
+        for i in 0..6 {
+            evicted.extend(lru.accessed(i, 20667, CachePriority::Normal, &guard));
+        }
+    }
+    assert_eq!(evicted, vec![]);
+
+    // a scan only evicts the items that it reads itself
+    for i in 6..206 {
+        let scanned = lru.accessed(i, 20667, CachePriority::Normal, &guard);
+        assert!(scanned.iter().all(|pid| *pid >= 6), "{:?}", scanned);
+    }
 }
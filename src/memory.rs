@@ -0,0 +1,179 @@
+//! The memory that a `Db` holds, see `Db::memory_usage` and
+//! `Config::memory_limit`.
+//!
+//! The page cache keeps track of the size of its pages itself.
+//! The other allocations that grow with the load, which are the
+//! buffers of the log, the events that are queued for subscribers
+//! and the writes that a `Txn` holds, are each counted by a
+//! `Charge`, which adds its bytes to a gauge of the `Db` and
+//! subtracts them again when it's dropped.
+//!
+//! Writes compare the total with the limit before they take the
+//! concurrency control, like the limits of a `Quota`, so that a
+//! write that waits doesn't hold up the writes that would free
+//! memory. They count the page cache at its capacity, which it
+//! never grows beyond, so that they don't have to lock it to
+//! measure it. One that finds the total over the limit flushes the
+//! log, whose buffers are freed once they're written, and then
+//! waits with a growing backoff for the subscribers to receive
+//! their events and for the transactions to be committed. It
+//! gives up waiting after `MAX_WAIT` and is made anyway, so that a
+//! thread that holds the memory itself, like a subscriber that
+//! writes before it receives its events, slows down instead of
+//! waiting forever.
+use std::time::{Duration, Instant};
+
+use crate::*;
+
+// the longest that a write waits for the memory of a `Db` to fall
+// below its limit
+const MAX_WAIT: Duration = Duration::from_secs(1);
+
+/// The bytes that the allocations of a `Db` outside of its page
+/// cache take.
+#[derive(Debug, Default)]
+pub(crate) struct Gauges {
+    io_buffers: AtomicU64,
+    subscriber_queues: AtomicU64,
+    transactions: AtomicU64,
+}
+
+/// What the bytes of a `Charge` are held by.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Kind {
+    IoBuffers,
+    SubscriberQueues,
+    Transactions,
+}
+
+impl Gauges {
+    fn gauge(&self, kind: Kind) -> &AtomicU64 {
+        match kind {
+            Kind::IoBuffers => &self.io_buffers,
+            Kind::SubscriberQueues => &self.subscriber_queues,
+            Kind::Transactions => &self.transactions,
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.io_buffers.load(Relaxed)
+            + self.subscriber_queues.load(Relaxed)
+            + self.transactions.load(Relaxed)
+    }
+}
+
+/// Bytes that are counted against the memory of a `Db` until the
+/// charge is dropped.
+#[derive(Debug)]
+pub(crate) struct Charge {
+    gauges: Arc<Gauges>,
+    kind: Kind,
+    bytes: u64,
+}
+
+impl Charge {
+    pub(crate) fn new(gauges: &Arc<Gauges>, kind: Kind, bytes: u64) -> Charge {
+        let _ = gauges.gauge(kind).fetch_add(bytes, Relaxed);
+        Charge { gauges: gauges.clone(), kind, bytes }
+    }
+
+    /// Returns the bytes that are counted.
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Changes the bytes that are counted.
+    pub(crate) fn set(&mut self, bytes: u64) {
+        let gauge = self.gauges.gauge(self.kind);
+        if bytes > self.bytes {
+            let _ = gauge.fetch_add(bytes - self.bytes, Relaxed);
+        } else {
+            let _ = gauge.fetch_sub(self.bytes - bytes, Relaxed);
+        }
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        let _ = self.gauges.gauge(self.kind).fetch_sub(self.bytes, Relaxed);
+    }
+}
+
+/// The memory that a `Db` holds, returned by `Db::memory_usage`.
+/// Every figure is in bytes, and is an estimate of what the
+/// allocations behind it take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The pages in the page cache, including its compressed
+    /// tier. A `SharedCache` is counted whole, along with the
+    /// pages of the other databases that use it.
+    pub cache: u64,
+    /// The buffers of the log that are being filled or written,
+    /// not counting the couple that are kept for reuse once
+    /// they're written.
+    pub io_buffers: u64,
+    /// The keys and values of the events that are queued for
+    /// subscribers that haven't received them yet, counted once
+    /// for every subscriber.
+    pub subscriber_queues: u64,
+    /// The keys and values that `Txn`s hold in memory until they
+    /// are committed.
+    pub transactions: u64,
+    /// The limit of `Config::memory_limit`.
+    pub limit: Option<u64>,
+}
+
+impl MemoryUsage {
+    /// Returns the bytes of every allocation together, which
+    /// writes keep at most `limit`.
+    pub fn total(&self) -> u64 {
+        self.cache
+            + self.io_buffers
+            + self.subscriber_queues
+            + self.transactions
+    }
+}
+
+pub(crate) fn usage(context: &Context) -> MemoryUsage {
+    let gauges = &context.memory;
+    MemoryUsage {
+        cache: context.pagecache.cache_size(),
+        io_buffers: gauges.io_buffers.load(Relaxed),
+        subscriber_queues: gauges.subscriber_queues.load(Relaxed),
+        transactions: gauges.transactions.load(Relaxed),
+        limit: context.memory_limit,
+    }
+}
+
+/// Waits while the memory of the `Db` is over its limit, see the
+/// module docs.
+pub(crate) fn wait_for_room(context: &Context) -> Result<()> {
+    let limit = if let Some(limit) = context.memory_limit {
+        limit
+    } else {
+        return Ok(());
+    };
+    let cache_capacity = context.pagecache.cache_capacity() as u64;
+    let over = || context.memory.total() + cache_capacity > limit;
+    if !over() {
+        return Ok(());
+    }
+
+    let _ = context.pagecache.flush()?;
+    let start = Instant::now();
+    let mut backoff = Duration::from_micros(100);
+    while over() {
+        if start.elapsed() > MAX_WAIT {
+            warn!(
+                "making a write while the memory of the database \
+                 is above its limit of {} bytes",
+                limit
+            );
+            break;
+        }
+        std::thread::sleep(backoff);
+        backoff = (backoff * 2).min(Duration::from_millis(10));
+    }
+    Ok(())
+}
@@ -0,0 +1,404 @@
+//! Ready-made merge operators for common data types, for use
+//! with `Tree::set_merge_operator`, `Db::register_merge_operator`
+//! and `TransactionalTree::merge`.
+//!
+//! Each type has an associated `merge` function that is the
+//! merge operator, and functions that encode the bytes that are
+//! passed to `Tree::merge` and decode the stored values. Values
+//! or merged bytes that can't be decoded are ignored, so the
+//! stored value is left unchanged rather than being corrupted
+//! or removed.
+//!
+//! `Max`, `Min`, `LwwRegister` and `OrSet` produce the same value
+//! regardless of the order that the merges are applied in, and
+//! also when a merge is applied more than once, so their values
+//! may be merged across replicas. `Counter` produces the same
+//! value in any order as long as it never saturates at the
+//! bounds of an `i64`, but not when a merge is applied more than
+//! once. `Append` depends on the order of the merges.
+//!
+//! # Examples
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use sled::merge::Counter;
+//!
+//! let db = sled::Config::new().temporary(true).open()?;
+//! db.set_merge_operator(Counter::merge);
+//!
+//! db.merge("visits", Counter::delta(3))?;
+//! db.merge("visits", Counter::delta(-1))?;
+//!
+//! let visits = db.get("visits")?.unwrap();
+//! assert_eq!(Counter::decode(&visits), Some(2));
+//! # Ok(()) }
+//! ```
+use std::{
+    collections::BTreeMap,
+    convert::{TryFrom, TryInto},
+};
+
+/// A signed 64-bit counter, stored as big-endian bytes. Merging
+/// a delta adds it to the counter, saturating at the bounds of
+/// an `i64` instead of overflowing, so the order of the deltas
+/// matters once it saturates.
+#[derive(Debug, Clone, Copy)]
+pub struct Counter;
+
+impl Counter {
+    /// The merge operator.
+    pub fn merge(
+        _key: &[u8],
+        old: Option<&[u8]>,
+        delta: &[u8],
+    ) -> Option<Vec<u8>> {
+        let current = match old.map(Counter::decode) {
+            Some(Some(current)) => current,
+            Some(None) => return old.map(<[u8]>::to_vec),
+            None => 0,
+        };
+        let new = if let Some(amount) = Counter::decode(delta) {
+            current.saturating_add(amount)
+        } else {
+            current
+        };
+        Some(Counter::encode(new))
+    }
+
+    /// Encodes a delta to merge into a counter.
+    pub fn delta(delta: i64) -> [u8; 8] {
+        delta.to_be_bytes()
+    }
+
+    /// Decodes the value of a counter, or a delta.
+    pub fn decode(value: &[u8]) -> Option<i64> {
+        value.try_into().ok().map(i64::from_be_bytes)
+    }
+
+    fn encode(value: i64) -> Vec<u8> {
+        value.to_be_bytes().to_vec()
+    }
+}
+
+/// Keeps the greatest of the merged values, comparing their
+/// bytes. Unsigned integers compare by value when they are
+/// encoded as big-endian bytes of the same length.
+#[derive(Debug, Clone, Copy)]
+pub struct Max;
+
+impl Max {
+    /// The merge operator.
+    pub fn merge(
+        _key: &[u8],
+        old: Option<&[u8]>,
+        merged: &[u8],
+    ) -> Option<Vec<u8>> {
+        Some(old.map_or(merged, |current| current.max(merged)).to_vec())
+    }
+}
+
+/// Keeps the least of the merged values, comparing their bytes.
+/// Unsigned integers compare by value when they are encoded as
+/// big-endian bytes of the same length.
+#[derive(Debug, Clone, Copy)]
+pub struct Min;
+
+impl Min {
+    /// The merge operator.
+    pub fn merge(
+        _key: &[u8],
+        old: Option<&[u8]>,
+        merged: &[u8],
+    ) -> Option<Vec<u8>> {
+        Some(old.map_or(merged, |current| current.min(merged)).to_vec())
+    }
+}
+
+/// Appends the merged bytes to the end of the value.
+#[derive(Debug, Clone, Copy)]
+pub struct Append;
+
+impl Append {
+    /// The merge operator.
+    pub fn merge(
+        _key: &[u8],
+        old: Option<&[u8]>,
+        merged: &[u8],
+    ) -> Option<Vec<u8>> {
+        let mut ret = old.map_or_else(Vec::new, <[u8]>::to_vec);
+        ret.extend_from_slice(merged);
+        Some(ret)
+    }
+}
+
+/// A last-writer-wins register, which keeps the value that was
+/// written with the greatest timestamp. Writes with the same
+/// timestamp are ordered by their values, so that every replica
+/// keeps the same one.
+///
+/// # Examples
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use sled::merge::LwwRegister;
+///
+/// let db = sled::Config::new().temporary(true).open()?;
+/// db.set_merge_operator(LwwRegister::merge);
+///
+/// db.merge("status", LwwRegister::encode(2, b"online"))?;
+/// db.merge("status", LwwRegister::encode(1, b"offline"))?;
+///
+/// let status = db.get("status")?.unwrap();
+/// assert_eq!(LwwRegister::decode(&status), Some((2, &b"online"[..])));
+/// # Ok(()) }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct LwwRegister;
+
+impl LwwRegister {
+    /// The merge operator.
+    pub fn merge(
+        _key: &[u8],
+        old: Option<&[u8]>,
+        merged: &[u8],
+    ) -> Option<Vec<u8>> {
+        let merged_write = LwwRegister::decode(merged);
+        let keep_old = match (old.map(LwwRegister::decode), merged_write) {
+            (None, None) => return None,
+            (Some(_), None) | (Some(None), Some(_)) => true,
+            (None, Some(_)) => false,
+            (Some(Some(old_write)), Some(new_write)) => old_write >= new_write,
+        };
+        Some(if keep_old { old.unwrap() } else { merged }.to_vec())
+    }
+
+    /// Encodes a write of `value` at `timestamp`.
+    pub fn encode(timestamp: u64, value: &[u8]) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(8 + value.len());
+        ret.extend_from_slice(&timestamp.to_be_bytes());
+        ret.extend_from_slice(value);
+        ret
+    }
+
+    /// Decodes the timestamp and the value of a register, or of
+    /// a write.
+    pub fn decode(value: &[u8]) -> Option<(u64, &[u8])> {
+        if value.len() < 8 {
+            return None;
+        }
+        let (timestamp, rest) = value.split_at(8);
+        Some((u64::from_be_bytes(timestamp.try_into().unwrap()), rest))
+    }
+}
+
+/// An observed-remove set of byte strings, where an element is
+/// present if it has been added since it was last removed as
+/// observed by the remover, so a concurrent add and remove of an
+/// element leaves it in the set.
+///
+/// Every add is identified by a tag, which must be unique, such
+/// as one returned by `Db::generate_id`. A remove is encoded from
+/// a value of the set that was read, and removes the adds that
+/// are in it. The tags of removed adds are kept in the set, so
+/// that they stay removed when the merges of other replicas are
+/// applied.
+///
+/// # Examples
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use sled::merge::OrSet;
+///
+/// let db = sled::Config::new().temporary(true).open()?;
+/// db.set_merge_operator(OrSet::merge);
+///
+/// db.merge("tags", OrSet::add(b"red", db.generate_id()?))?;
+/// db.merge("tags", OrSet::add(b"blue", db.generate_id()?))?;
+///
+/// let observed = db.get("tags")?.unwrap();
+/// db.merge("tags", OrSet::remove(&observed, b"red"))?;
+///
+/// let tags = db.get("tags")?.unwrap();
+/// assert_eq!(OrSet::elements(&tags), Some(vec![&b"blue"[..]]));
+/// # Ok(()) }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct OrSet;
+
+// the state of a set maps the tag of every add that it has seen
+// to the added element, or to `None` once the add was removed.
+type OrSetState<'a> = BTreeMap<u64, Option<&'a [u8]>>;
+
+impl OrSet {
+    /// The merge operator, which combines two values of the set.
+    pub fn merge(
+        _key: &[u8],
+        old: Option<&[u8]>,
+        merged: &[u8],
+    ) -> Option<Vec<u8>> {
+        let mut state = match old.map(OrSet::decode) {
+            Some(Some(state)) => state,
+            Some(None) => return old.map(<[u8]>::to_vec),
+            None => OrSetState::new(),
+        };
+        let merged_state = if let Some(merged_state) = OrSet::decode(merged) {
+            merged_state
+        } else {
+            return old.map(<[u8]>::to_vec);
+        };
+
+        for (tag, element) in merged_state {
+            let entry = state.entry(tag).or_insert(element);
+            if element.is_none() {
+                *entry = None;
+            }
+        }
+
+        Some(OrSet::encode(&state))
+    }
+
+    /// Encodes the add of `element`, identified by `tag`.
+    pub fn add(element: &[u8], tag: u64) -> Vec<u8> {
+        let mut state = OrSetState::new();
+        let _ = state.insert(tag, Some(element));
+        OrSet::encode(&state)
+    }
+
+    /// Encodes the remove of `element` from `observed`, a value
+    /// of the set. Returns an empty remove if `observed` can't be
+    /// decoded.
+    pub fn remove(observed: &[u8], element: &[u8]) -> Vec<u8> {
+        let state: OrSetState<'_> = OrSet::decode(observed)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(_tag, added)| *added == Some(element))
+            .map(|(tag, _added)| (tag, None))
+            .collect();
+        OrSet::encode(&state)
+    }
+
+    /// Decodes the elements that are in a value of the set, in
+    /// ascending order.
+    pub fn elements(value: &[u8]) -> Option<Vec<&[u8]>> {
+        let mut ret: Vec<&[u8]> =
+            OrSet::decode(value)?.values().filter_map(|e| *e).collect();
+        ret.sort();
+        ret.dedup();
+        Some(ret)
+    }
+
+    // each entry is its tag, followed by 0 for a removed add, or
+    // by 1, the length of the element, and the element.
+    fn encode(state: &OrSetState<'_>) -> Vec<u8> {
+        let mut ret = vec![];
+        for (tag, element) in state {
+            ret.extend_from_slice(&tag.to_be_bytes());
+            if let Some(bytes) = element {
+                ret.push(1);
+                let len = u64::try_from(bytes.len()).unwrap();
+                ret.extend_from_slice(&len.to_be_bytes());
+                ret.extend_from_slice(bytes);
+            } else {
+                ret.push(0);
+            }
+        }
+        ret
+    }
+
+    fn decode(mut buf: &[u8]) -> Option<OrSetState<'_>> {
+        fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+            if buf.len() < len {
+                return None;
+            }
+            let (ret, rest) = buf.split_at(len);
+            *buf = rest;
+            Some(ret)
+        }
+
+        fn take_u64(buf: &mut &[u8]) -> Option<u64> {
+            Some(u64::from_be_bytes(take(buf, 8)?.try_into().unwrap()))
+        }
+
+        let mut state = OrSetState::new();
+        while !buf.is_empty() {
+            let tag = take_u64(&mut buf)?;
+            let element = match take(&mut buf, 1)? {
+                [0] => None,
+                [1] => {
+                    let len = usize::try_from(take_u64(&mut buf)?).ok()?;
+                    Some(take(&mut buf, len)?)
+                }
+                _ => return None,
+            };
+            let _ = state.insert(tag, element);
+        }
+        Some(state)
+    }
+}
+
+#[test]
+fn merges_are_order_independent() {
+    fn apply(
+        merge: fn(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>>,
+        operands: &[Vec<u8>],
+    ) -> Option<Vec<u8>> {
+        operands
+            .iter()
+            .fold(None, |value, operand| merge(b"k", value.as_deref(), operand))
+    }
+
+    fn check(
+        merge: fn(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>>,
+        mut operands: Vec<Vec<u8>>,
+    ) -> Option<Vec<u8>> {
+        let forward = apply(merge, &operands);
+        operands.reverse();
+        assert_eq!(apply(merge, &operands), forward);
+        forward
+    }
+
+    let deltas = vec![Counter::delta(5).to_vec(), Counter::delta(-7).to_vec()];
+    let counter = check(Counter::merge, deltas).unwrap();
+    assert_eq!(Counter::decode(&counter), Some(-2));
+
+    let min = Counter::delta(i64::min_value());
+    let saturated = Counter::merge(b"k", Some(&counter), &min).unwrap();
+    assert_eq!(Counter::decode(&saturated), Some(i64::min_value()));
+
+    // bytes that can't be decoded leave the value unchanged
+    assert_eq!(Counter::merge(b"k", Some(&counter), b"x"), Some(counter));
+    let malformed = Counter::merge(b"k", Some(b"x"), &Counter::delta(1));
+    assert_eq!(malformed, Some(b"x".to_vec()));
+
+    let bytes = vec![b"b".to_vec(), b"c".to_vec(), b"a".to_vec()];
+    assert_eq!(check(Max::merge, bytes.clone()), Some(b"c".to_vec()));
+    assert_eq!(check(Min::merge, bytes.clone()), Some(b"a".to_vec()));
+    assert_eq!(apply(Append::merge, &bytes), Some(b"bca".to_vec()));
+
+    let writes = vec![
+        LwwRegister::encode(1, b"z"),
+        LwwRegister::encode(3, b"a"),
+        LwwRegister::encode(3, b"b"),
+        LwwRegister::encode(2, b"y"),
+    ];
+    let register = check(LwwRegister::merge, writes).unwrap();
+    assert_eq!(LwwRegister::decode(&register), Some((3, &b"b"[..])));
+
+    let observed = apply(
+        OrSet::merge,
+        &[OrSet::add(b"x", 1), OrSet::add(b"y", 2), OrSet::add(b"x", 3)],
+    )
+    .unwrap();
+    let operands = vec![
+        OrSet::add(b"x", 1),
+        OrSet::add(b"y", 2),
+        OrSet::add(b"x", 3),
+        OrSet::remove(&observed, b"x"),
+        // a concurrent add that the remove did not observe
+        OrSet::add(b"x", 4),
+        OrSet::remove(&observed, b"y"),
+    ];
+    let set = check(OrSet::merge, operands).unwrap();
+    assert_eq!(OrSet::elements(&set), Some(vec![&b"x"[..]]));
+    assert_eq!(OrSet::merge(b"k", Some(&set), &set), Some(set));
+}
@@ -0,0 +1,183 @@
+//! Merge operators that are registered on a `Db` by name, see
+//! `Db::register_merge_operator`.
+//!
+//! The name of the operator that a `Tree` uses is persisted in
+//! the `Meta` page next to the roots of the trees, as a key made
+//! of a prefix, the name of the `Tree` and the name of the
+//! operator, which is written when a `Tree` first uses a named
+//! operator with `Tree::use_merge_operator`. A `Tree` with a
+//! persisted operator can't be opened until that operator has
+//! been registered again, so its values are never merged by a
+//! function other than the one that they were written for.
+use std::ops::Bound;
+
+use crate::*;
+
+const MERGE_OPERATOR_PREFIX: &[u8] = b"__sled__merge_operator__";
+
+// the value of the `Meta` entries, which are not roots of trees
+const NO_ROOT: PageId = 0;
+
+/// The merge operators registered on a `Db`.
+#[derive(Default)]
+pub(crate) struct MergeOperators {
+    registered: RwLock<FastMap8<String, Arc<dyn MergeOperator>>>,
+    // serializes the persisting of names, so that a tree
+    // can't be given two names concurrently
+    persisting: Mutex<()>,
+}
+
+impl Debug for MergeOperators {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.registered.read().keys()).finish()
+    }
+}
+
+pub(crate) fn is_meta_key(name: &[u8]) -> bool {
+    name.starts_with(MERGE_OPERATOR_PREFIX)
+}
+
+fn meta_key_prefix(tree_id: &[u8]) -> Vec<u8> {
+    let mut ret = MERGE_OPERATOR_PREFIX.to_vec();
+    let len = u64::try_from(tree_id.len()).unwrap();
+    ret.extend_from_slice(&len.to_be_bytes());
+    ret.extend_from_slice(tree_id);
+    ret
+}
+
+/// Returns the key that an entry of the `Meta` which persists the
+/// operator of the tree `from` has once the tree is renamed to
+/// `to`.
+pub(crate) fn renamed_meta_key(
+    key: &[u8],
+    from: &[u8],
+    to: &[u8],
+) -> Option<IVec> {
+    let prefix = meta_key_prefix(from);
+    if !key.starts_with(&prefix) {
+        return None;
+    }
+    let mut ret = meta_key_prefix(to);
+    ret.extend_from_slice(&key[prefix.len()..]);
+    Some(ret.into())
+}
+
+/// Registers an operator, or replaces the one registered under
+/// the same name, see `Db::register_merge_operator`.
+pub(crate) fn register(
+    context: &Context,
+    name: &str,
+    merge_operator: Arc<dyn MergeOperator>,
+) {
+    let mut registered = context.merge_operators.registered.write();
+    let _ = registered.insert(name.to_owned(), merge_operator);
+}
+
+/// Returns the `Meta` key that persists the operator of a tree.
+fn meta_key(tree: &Tree, guard: &Guard) -> Option<IVec> {
+    let prefix = meta_key_prefix(&tree.tree_id);
+    let meta = tree.context.pagecache.get_meta(guard);
+    let mut keys = meta
+        .inner
+        .range::<[u8], _>((Bound::Included(&*prefix), Bound::Unbounded))
+        .map(|(key, _root)| key);
+    keys.next().filter(|key| key.starts_with(&prefix)).cloned()
+}
+
+/// Returns the name of the operator persisted for a tree.
+pub(crate) fn persisted_name(tree: &Tree) -> Result<Option<String>> {
+    let key = if let Some(key) = meta_key(tree, &pin()) {
+        key
+    } else {
+        return Ok(None);
+    };
+
+    let name = &key[meta_key_prefix(&tree.tree_id).len()..];
+    String::from_utf8(name.to_vec())
+        .map(Some)
+        .map_err(|_| Error::corruption(None))
+}
+
+/// Sets the merge operator of a tree to the registered operator
+/// that was persisted for it, if any, returning an error if that
+/// operator is not registered.
+pub(crate) fn attach(tree: &Tree) -> Result<()> {
+    let name = if let Some(name) = persisted_name(tree)? {
+        name
+    } else {
+        return Ok(());
+    };
+
+    if let Some(merge_operator) =
+        tree.context.merge_operators.registered.read().get(&name)
+    {
+        *tree.merge_operator.write() = Some(merge_operator.clone());
+        return Ok(());
+    }
+
+    Err(Error::Unsupported(format!(
+        "tree {:?} uses the merge operator {:?}, which must be \
+         registered with Db::register_merge_operator before the \
+         tree is opened",
+        tree.tree_id, name
+    )))
+}
+
+/// Persists a registered operator for a tree and sets it, see
+/// `Tree::use_merge_operator`.
+pub(crate) fn use_operator(tree: &Tree, name: &str) -> Result<()> {
+    let merge_operator = if let Some(merge_operator) =
+        tree.context.merge_operators.registered.read().get(name)
+    {
+        merge_operator.clone()
+    } else {
+        return Err(Error::Unsupported(format!(
+            "the merge operator {:?} is not registered",
+            name
+        )));
+    };
+
+    let _persisting = tree.context.merge_operators.persisting.lock();
+
+    match persisted_name(tree)? {
+        Some(ref persisted) if persisted == name => {}
+        Some(persisted) => {
+            return Err(Error::Unsupported(format!(
+                "tree {:?} already uses the merge operator {:?}",
+                tree.tree_id, persisted
+            )));
+        }
+        None => {
+            let mut key = meta_key_prefix(&tree.tree_id);
+            key.extend_from_slice(name.as_bytes());
+            let guard = pin();
+            let _ = tree.context.pagecache.cas_root_in_meta(
+                &key,
+                None,
+                Some(NO_ROOT),
+                &guard,
+            )?;
+        }
+    }
+
+    *tree.merge_operator.write() = Some(merge_operator);
+
+    Ok(())
+}
+
+/// Removes the persisted operator of a tree that is dropped.
+pub(crate) fn forget(tree: &Tree) -> Result<()> {
+    let _persisting = tree.context.merge_operators.persisting.lock();
+
+    let guard = pin();
+    if let Some(key) = meta_key(tree, &guard) {
+        let _ = tree.context.pagecache.cas_root_in_meta(
+            &key,
+            Some(NO_ROOT),
+            None,
+            &guard,
+        )?;
+    }
+
+    Ok(())
+}
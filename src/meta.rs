@@ -43,16 +43,26 @@ impl Meta {
 }
 
 /// Open or create a new disk-backed Tree with its own keyspace,
-/// accessible from the `Db` via the provided identifier.
+/// accessible from the `Db` via the provided identifier. If the
+/// Tree is created, it uses the options in `tree_config`, or the
+/// ones configured for the `Db` if it's `None`.
 pub(crate) fn open_tree<V>(
     context: &Context,
     raw_name: V,
+    tree_config: Option<TreeConfig>,
     guard: &Guard,
 ) -> Result<Tree>
 where
     V: Into<IVec>,
 {
     let name = raw_name.into();
+    let codec = tree_config.map(|config| config.compression);
+    let order =
+        tree_config.map_or(KeyOrder::Lexicographic, |config| config.order);
+    let cache_priority = tree_config
+        .map_or(CachePriority::Normal, |config| config.cache_priority);
+    let expected_value_size =
+        tree_config.and_then(|config| config.expected_value_size);
 
     // we loop because creating this Tree may race with
     // concurrent attempts to open the same one.
@@ -60,20 +70,25 @@ where
         match context.pagecache.meta_pid_for_name(&name, guard) {
             Ok(root_id) => {
                 assert_ne!(root_id, 0);
-                return Ok(Tree(Arc::new(TreeInner {
-                    tree_id: name,
-                    context: context.clone(),
-                    subscribers: Subscribers::default(),
-                    root: AtomicU64::new(root_id),
-                    merge_operator: RwLock::new(None),
-                })));
+                return load_tree(context, name, root_id, guard);
             }
             Err(Error::CollectionNotFound(_)) => {}
             Err(other) => return Err(other),
         }
 
+        // trees created with zstd may have dictionaries
+        // trained for them later.
+        let dictionary = if let Some(Codec::Zstd(_)) = codec {
+            context.dictionaries.allocate()?
+        } else {
+            0
+        };
+
         // set up empty leaf
-        let leaf = Node::new_empty_leaf();
+        let mut leaf = Node::new_empty_leaf();
+        leaf.set_codec(codec, dictionary);
+        leaf.set_key_order(order);
+        leaf.set_tree_hints(cache_priority, expected_value_size);
         let (leaf_id, leaf_ptr) = context.pagecache.allocate(leaf, guard)?;
 
         trace!(
@@ -85,7 +100,10 @@ where
         // set up root index
 
         // vec![0] represents a prefix-encoded empty prefix
-        let root = Node::new_root(leaf_id);
+        let mut root = Node::new_root(leaf_id);
+        root.set_codec(codec, dictionary);
+        root.set_key_order(order);
+        root.set_tree_hints(cache_priority, expected_value_size);
         let (root_id, root_ptr) = context.pagecache.allocate(root, guard)?;
 
         debug!("allocated pid {} for root of new_tree {:?}", root_id, name);
@@ -110,13 +128,41 @@ where
                 .expect("could not free allocated page");
             continue;
         }
+        tree_stats::record_created(context, &name, guard)?;
+
+        let tree = Tree(Arc::new(TreeInner::new(
+            name,
+            context.clone(),
+            root_id,
+            order,
+        )));
 
-        return Ok(Tree(Arc::new(TreeInner {
-            tree_id: name,
-            subscribers: Subscribers::default(),
-            context: context.clone(),
-            root: AtomicU64::new(root_id),
-            merge_operator: RwLock::new(None),
-        })));
+        // there are no keys to count in a new tree
+        tree.item_count.store(0, Release);
+
+        return Ok(tree);
     }
 }
+
+/// Instantiates an existing Tree, reading the options that it
+/// was created with from its root.
+pub(crate) fn load_tree(
+    context: &Context,
+    name: IVec,
+    root_id: PageId,
+    guard: &Guard,
+) -> Result<Tree> {
+    let root = context
+        .pagecache
+        .get(root_id, guard)?
+        .ok_or_else(|| Error::corruption(None))?;
+
+    let tree = Tree(Arc::new(TreeInner::new(
+        name,
+        context.clone(),
+        root_id,
+        root.key_order(),
+    )));
+    index::load(&tree, guard);
+    Ok(tree)
+}
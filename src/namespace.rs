@@ -0,0 +1,120 @@
+//! Namespaces that group the trees of a `Db` under a common
+//! prefix, see `Db::open_namespace`.
+//!
+//! A namespace is only a convention on the names of its trees:
+//! the tree `orders` of the namespace `tenant_42` is named
+//! `tenant_42/orders`, and the namespace `eu` nested in it gives
+//! its trees names like `tenant_42/eu/orders`. Nothing about a
+//! namespace is persisted apart from the names of its trees, so
+//! a namespace exists as long as one of them does.
+use crate::*;
+
+const SEPARATOR: u8 = b'/';
+
+/// A handle that scopes opening, listing and dropping trees to
+/// the trees of a namespace, returned by `Db::open_namespace`.
+#[derive(Clone)]
+pub struct Namespace {
+    db: Db,
+    // the name of the namespace followed by the separator
+    prefix: IVec,
+}
+
+impl Debug for Namespace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Namespace").field("name", &self.name()).finish()
+    }
+}
+
+/// Returns the namespace `name` nested in the one with `prefix`.
+pub(crate) fn open(db: &Db, prefix: &[u8], name: &[u8]) -> Result<Namespace> {
+    if name.is_empty() {
+        return Err(Error::Unsupported(
+            "the name of a namespace can't be empty".into(),
+        ));
+    }
+    let mut nested = prefix.to_vec();
+    nested.extend_from_slice(name);
+    nested.push(SEPARATOR);
+    Ok(Namespace { db: db.clone(), prefix: nested.into() })
+}
+
+/// Drops every tree of the namespace `name` nested in the one
+/// with `prefix`, returning how many were dropped.
+pub(crate) fn drop_nested(
+    db: &Db,
+    prefix: &[u8],
+    name: &[u8],
+) -> Result<usize> {
+    let namespace = open(db, prefix, name)?;
+    let mut dropped = 0;
+    for tree_name in db.tree_names() {
+        if tree_name.starts_with(&namespace.prefix)
+            && db.drop_tree(&tree_name)?
+        {
+            dropped += 1;
+        }
+    }
+    Ok(dropped)
+}
+
+impl Namespace {
+    /// Returns the full name of the namespace, which the names
+    /// of its trees start with.
+    pub fn name(&self) -> &[u8] {
+        &self.prefix[..self.prefix.len() - 1]
+    }
+
+    fn tree_name(&self, name: &[u8]) -> IVec {
+        let mut ret = self.prefix.to_vec();
+        ret.extend_from_slice(name);
+        ret.into()
+    }
+
+    /// Opens or creates the tree `name` of the namespace, like
+    /// `Db::open_tree`.
+    pub fn open_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<Tree> {
+        self.db.open_tree(self.tree_name(name.as_ref()))
+    }
+
+    /// Opens or creates the tree `name` of the namespace with
+    /// options that apply only to it, like `Db::open_tree_with`.
+    pub fn open_tree_with<V: AsRef<[u8]>>(
+        &self,
+        name: V,
+        tree_config: TreeConfig,
+    ) -> Result<Tree> {
+        self.db.open_tree_with(self.tree_name(name.as_ref()), tree_config)
+    }
+
+    /// Returns the namespace `name` nested in this one.
+    pub fn open_namespace<V: AsRef<[u8]>>(&self, name: V) -> Result<Namespace> {
+        open(&self.db, &self.prefix, name.as_ref())
+    }
+
+    /// Returns the names of the trees of the namespace, including
+    /// those of its nested namespaces, without its prefix.
+    pub fn tree_names(&self) -> Vec<IVec> {
+        let mut names: Vec<IVec> = self
+            .db
+            .tree_names()
+            .into_iter()
+            .filter(|name| name.starts_with(&self.prefix))
+            .map(|name| IVec::from(&name[self.prefix.len()..]))
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Drops the tree `name` of the namespace, like
+    /// `Db::drop_tree`.
+    pub fn drop_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<bool> {
+        self.db.drop_tree(self.tree_name(name.as_ref()))
+    }
+
+    /// Drops the namespace `name` nested in this one, like
+    /// `Db::drop_namespace`.
+    pub fn drop_namespace<V: AsRef<[u8]>>(&self, name: V) -> Result<usize> {
+        drop_nested(&self.db, &self.prefix, name.as_ref())
+    }
+}
@@ -13,10 +13,20 @@ use std::{
     sync::Arc,
 };
 
-use crate::{varint, IVec, Link};
+use crate::{
+    pagecache::constants::PAGE_CONSOLIDATION_THRESHOLD, varint, CachePriority,
+    Codec, IVec, KeyOrder, Link,
+};
 
 const ALIGNMENT: usize = align_of::<Header>();
 
+const SPLIT_SIZE: usize = 1024 - crate::MAX_MSG_HEADER_LEN;
+
+// the number of expected values that a leaf holds before it's
+// split, up to `MAX_LEAF_SPLIT_SIZE` bytes
+const LEAF_VALUES: usize = 8;
+const MAX_LEAF_SPLIT_SIZE: usize = 256 * 1024;
+
 macro_rules! tf {
     ($e:expr) => {
         usize::try_from($e).unwrap()
@@ -76,6 +86,22 @@ pub struct Header {
     pub merging: bool,
     // can be 1 bit
     pub is_index: bool,
+    // the codec chosen for the tree with `Db::open_tree_with`.
+    // 0: the default, 1: none, 2: zstd at `codec_level`.
+    codec: u8,
+    codec_level: u8,
+    // the slot of the tree's trained zstd dictionaries,
+    // or 0 if it has none.
+    dictionary: u16,
+    // the `KeyOrder` chosen for the tree with `Db::open_tree_with`.
+    // 0: lexicographic, 1: reverse, 2: case-insensitive.
+    key_order: u8,
+    // the `CachePriority` and expected value size chosen for the
+    // tree with `Db::open_tree_with`. The low 2 bits hold the
+    // priority, 0: normal, 1: low, 2: high, and the other 6 hold
+    // the log2 of the expected value size plus one, or 0 if it
+    // has none. This fills the padding at the end of the header.
+    tree_hints: u8,
 }
 
 fn apply_computed_distance(mut buf: &mut [u8], mut distance: usize) {
@@ -364,8 +390,89 @@ impl PartialEq<[u8]> for KeyRef<'_> {
     }
 }
 
+/// The writes and tombstones that were linked to a node and not
+/// merged into it yet, sorted by key. A page is consolidated after
+/// `PAGE_CONSOLIDATION_THRESHOLD` links, so they are few, and are
+/// kept in a single allocation that is copied on every link. Keys
+/// and values that fit inline in an `IVec`, like 16 byte keys and
+/// 8 byte values, don't take any other.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Overlay(Vec<(IVec, Option<IVec>)>);
+
+impl Overlay {
+    // returns a new overlay with `key` set to `value`
+    fn update(&self, key: IVec, value: Option<IVec>) -> Overlay {
+        let mut items = Vec::with_capacity(self.0.len() + 1);
+        match self.position(&key) {
+            Ok(idx) => {
+                items.extend_from_slice(&self.0[..idx]);
+                items.push((key, value));
+                items.extend_from_slice(&self.0[idx + 1..]);
+            }
+            Err(idx) => {
+                items.extend_from_slice(&self.0[..idx]);
+                items.push((key, value));
+                items.extend_from_slice(&self.0[idx..]);
+            }
+        }
+        Overlay(items)
+    }
+
+    fn position(&self, key: &[u8]) -> Result<usize, usize> {
+        self.0.binary_search_by(|(k, _)| (**k).cmp(key))
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&Option<IVec>> {
+        self.position(key).ok().map(|idx| &self.0[idx].1)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.position(key).is_ok()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn iter(&self) -> std::slice::Iter<'_, (IVec, Option<IVec>)> {
+        self.0.iter()
+    }
+
+    // the items with keys from `key` on, or above it if `inclusive`
+    // is false
+    fn iter_from(
+        &self,
+        key: &[u8],
+        inclusive: bool,
+    ) -> std::slice::Iter<'_, (IVec, Option<IVec>)> {
+        let start = match self.position(key) {
+            Ok(idx) if !inclusive => idx + 1,
+            Ok(idx) | Err(idx) => idx,
+        };
+        self.0[start..].iter()
+    }
+
+    // the items with keys up to `key`, or below it if `inclusive`
+    // is false
+    fn iter_to(
+        &self,
+        key: &[u8],
+        inclusive: bool,
+    ) -> std::slice::Iter<'_, (IVec, Option<IVec>)> {
+        let end = match self.position(key) {
+            Ok(idx) if inclusive => idx + 1,
+            Ok(idx) | Err(idx) => idx,
+        };
+        self.0[..end].iter()
+    }
+}
+
 struct Iter<'a> {
-    overlay: std::iter::Skip<im::ordmap::Iter<'a, IVec, Option<IVec>>>,
+    overlay: std::slice::Iter<'a, (IVec, Option<IVec>)>,
     node: &'a Inner,
     node_position: usize,
     node_back_position: usize,
@@ -547,7 +654,7 @@ pub struct Node {
     // the overlay accumulates new writes and tombstones
     // for deletions that have not yet been merged
     // into the inner backing node
-    pub(crate) overlay: im::OrdMap<IVec, Option<IVec>>,
+    pub(crate) overlay: Overlay,
     inner: Arc<Inner>,
 }
 
@@ -567,7 +674,7 @@ impl Deref for Node {
 impl Node {
     fn iter(&self) -> Iter<'_> {
         Iter {
-            overlay: self.overlay.iter().skip(0),
+            overlay: self.overlay.iter(),
             node: &self.inner,
             node_position: 0,
             next_a: None,
@@ -578,6 +685,16 @@ impl Node {
         }
     }
 
+    pub(crate) fn decoded_keys(&self) -> impl '_ + Iterator<Item = IVec> {
+        self.iter().map(move |(k, _)| self.prefix_decode(k))
+    }
+
+    pub(crate) fn decoded_items(
+        &self,
+    ) -> impl '_ + Iterator<Item = (IVec, &[u8])> {
+        self.iter().map(move |(k, v)| (self.prefix_decode(k), v))
+    }
+
     pub(crate) fn iter_index_pids(&self) -> impl '_ + Iterator<Item = u64> {
         log::trace!("iter_index_pids on node {:?}", self);
         self.iter().map(|(_, v)| u64::from_le_bytes(v.try_into().unwrap()))
@@ -611,6 +728,42 @@ impl Node {
         }
     }
 
+    /// Creates a node from sorted items whose keys are not
+    /// prefix encoded yet, for `Tree::bulk_load`.
+    pub(crate) fn new_sorted(
+        lo: &[u8],
+        hi: Option<&[u8]>,
+        is_index: bool,
+        items: &[(IVec, IVec)],
+    ) -> Node {
+        let prefix_len = if let Some(end) = hi {
+            lo.iter()
+                .zip(end.iter())
+                .take(std::u8::MAX as usize)
+                .take_while(|(a, b)| a == b)
+                .count()
+        } else {
+            0
+        };
+
+        let encoded: Vec<_> = items
+            .iter()
+            .map(|(k, v)| (KeyRef::Slice(&k[prefix_len..]), &**v))
+            .collect();
+
+        Node {
+            overlay: Default::default(),
+            inner: Arc::new(Inner::new(
+                lo,
+                hi,
+                tf!(prefix_len, u8),
+                is_index,
+                None,
+                &encoded,
+            )),
+        }
+    }
+
     pub(crate) fn apply(&self, link: &Link) -> Node {
         use self::Link::*;
 
@@ -708,7 +861,7 @@ impl Node {
                 let mut prev = self.inner.index_key(self.inner.children() - 1);
                 let stride: u16 = self.fixed_key_stride.unwrap().get();
                 let mut length_and_stride_matches = true;
-                for (k, v) in &self.overlay {
+                for (k, v) in self.overlay.iter() {
                     length_and_stride_matches &=
                         v.is_some() && v.as_ref().unwrap().is_empty();
                     length_and_stride_matches &= KeyRef::Slice(&*k) > prev
@@ -772,6 +925,7 @@ impl Node {
 
         ret.merging = self.merging;
         ret.merging_child = self.merging_child;
+        ret.inherit_tree_config(self);
         ret.probation_ops_remaining =
             self.probation_ops_remaining.saturating_sub(
                 u8::try_from(self.overlay.len().min(std::u8::MAX as usize))
@@ -786,6 +940,88 @@ impl Node {
         Arc::get_mut(&mut self.inner).unwrap().next = next;
     }
 
+    /// The codec that was chosen for this node's tree, or `None`
+    /// if it uses the compression configured for the `Db`.
+    pub(crate) fn codec(&self) -> Option<Codec> {
+        match self.codec {
+            1 => Some(Codec::None),
+            2 => Some(Codec::Zstd(i32::from(self.codec_level))),
+            _ => None,
+        }
+    }
+
+    /// The slot of the dictionaries trained for this node's
+    /// tree, or 0 if it has none.
+    pub(crate) fn dictionary(&self) -> u32 {
+        u32::from(self.dictionary)
+    }
+
+    pub(crate) fn key_order(&self) -> KeyOrder {
+        match self.key_order {
+            1 => KeyOrder::Reverse,
+            2 => KeyOrder::CaseInsensitive,
+            _ => KeyOrder::Lexicographic,
+        }
+    }
+
+    pub(crate) fn cache_priority(&self) -> CachePriority {
+        match self.tree_hints & 0b11 {
+            1 => CachePriority::Low,
+            2 => CachePriority::High,
+            3 => CachePriority::Pinned,
+            _ => CachePriority::Normal,
+        }
+    }
+
+    /// The expected value size chosen for this node's tree,
+    /// rounded up to a power of two.
+    pub(crate) fn expected_value_size(&self) -> Option<usize> {
+        match self.tree_hints >> 2 {
+            0 => None,
+            log2 => Some(1 << (log2 - 1)),
+        }
+    }
+
+    pub(crate) fn set_codec(&mut self, codec: Option<Codec>, dictionary: u32) {
+        let (codec, codec_level) = match codec {
+            None => (0, 0),
+            Some(Codec::None) => (1, 0),
+            Some(Codec::Zstd(level)) => (2, u8::try_from(level).unwrap()),
+        };
+        let inner = Arc::get_mut(&mut self.inner).unwrap();
+        inner.codec = codec;
+        inner.codec_level = codec_level;
+        inner.dictionary = u16::try_from(dictionary).unwrap();
+    }
+
+    pub(crate) fn set_key_order(&mut self, key_order: KeyOrder) {
+        Arc::get_mut(&mut self.inner).unwrap().key_order = match key_order {
+            KeyOrder::Lexicographic => 0,
+            KeyOrder::Reverse => 1,
+            KeyOrder::CaseInsensitive => 2,
+        };
+    }
+
+    pub(crate) fn set_tree_hints(
+        &mut self,
+        cache_priority: CachePriority,
+        expected_value_size: Option<usize>,
+    ) {
+        let priority = match cache_priority {
+            CachePriority::Normal => 0,
+            CachePriority::Low => 1,
+            CachePriority::High => 2,
+            CachePriority::Pinned => 3,
+        };
+        let size = expected_value_size.map_or(0, |size| {
+            let log2 = size.max(1).next_power_of_two().trailing_zeros();
+            u8::try_from(log2 + 1).unwrap()
+        });
+        // the node may share its inner node with the one that it
+        // was cloned from, see `Tree::set_cache_priority`
+        Arc::make_mut(&mut self.inner).tree_hints = (size << 2) | priority;
+    }
+
     pub(crate) fn increment_rewrite_generations(&mut self) {
         let rewrite_generations = self.rewrite_generations;
 
@@ -928,15 +1164,15 @@ impl Node {
     pub(crate) fn successor(
         &self,
         bound: &Bound<IVec>,
-    ) -> Option<(IVec, IVec)> {
+    ) -> Option<(IVec, &[u8])> {
         let (overlay, node_position) = match bound {
-            Bound::Unbounded => (self.overlay.iter().skip(0), 0),
+            Bound::Unbounded => (self.overlay.iter(), 0),
             Bound::Included(b) => {
                 if let Some(Some(v)) = self.overlay.get(b) {
                     // short circuit return
-                    return Some((b.clone(), v.clone()));
+                    return Some((b.clone(), v.as_ref()));
                 }
-                let overlay_search = self.overlay.range(b.clone()..).skip(0);
+                let overlay_search = self.overlay.iter_from(b, true);
 
                 let inner_search = if &**b < self.lo() {
                     Err(0)
@@ -947,7 +1183,7 @@ impl Node {
                     Ok(idx) => {
                         return Some((
                             self.prefix_decode(self.inner.index_key(idx)),
-                            self.inner.index_value(idx).into(),
+                            self.inner.index_value(idx),
                         ))
                     }
                     Err(idx) => idx,
@@ -956,11 +1192,7 @@ impl Node {
                 (overlay_search, node_position)
             }
             Bound::Excluded(b) => {
-                let overlay_search = if self.overlay.contains_key(b) {
-                    self.overlay.range(b.clone()..).skip(1)
-                } else {
-                    self.overlay.range(b.clone()..).skip(0)
-                };
+                let overlay_search = self.overlay.iter_from(b, false);
 
                 let inner_search = if &**b < self.lo() {
                     Err(0)
@@ -995,17 +1227,17 @@ impl Node {
 
         let ret: Option<(KeyRef<'_>, &[u8])> = iter.find(|(k, _)| in_bounds(k));
 
-        ret.map(|(k, v)| (self.prefix_decode(k), v.into()))
+        ret.map(|(k, v)| (self.prefix_decode(k), v))
     }
 
     pub(crate) fn predecessor(
         &self,
         bound: &Bound<IVec>,
-    ) -> Option<(IVec, IVec)> {
+    ) -> Option<(IVec, &[u8])> {
         let (overlay, node_back_position) = match bound {
-            Bound::Unbounded => (self.overlay.iter().skip(0), self.children()),
+            Bound::Unbounded => (self.overlay.iter(), self.children()),
             Bound::Included(b) => {
-                let overlay = self.overlay.range(..=b.clone()).skip(0);
+                let overlay = self.overlay.iter_to(b, true);
 
                 let inner_search = if &**b < self.lo() {
                     Err(0)
@@ -1016,7 +1248,7 @@ impl Node {
                     Ok(idx) => {
                         return Some((
                             self.prefix_decode(self.inner.index_key(idx)),
-                            self.inner.index_value(idx).into(),
+                            self.inner.index_value(idx),
                         ))
                     }
                     Err(idx) => idx,
@@ -1025,7 +1257,7 @@ impl Node {
                 (overlay, node_back_position)
             }
             Bound::Excluded(b) => {
-                let overlay = self.overlay.range(..b.clone()).skip(0);
+                let overlay = self.overlay.iter_to(b, false);
 
                 let above_hi =
                     if let Some(hi) = self.hi() { &**b >= hi } else { false };
@@ -1065,7 +1297,7 @@ impl Node {
         let ret: Option<(KeyRef<'_>, &[u8])> =
             iter.rev().find(|(k, _)| in_bounds(k));
 
-        ret.map(|(k, v)| (self.prefix_decode(k), v.into()))
+        ret.map(|(k, v)| (self.prefix_decode(k), v))
     }
 
     pub(crate) fn index_next_node(&self, key: &[u8]) -> (bool, u64) {
@@ -1096,7 +1328,7 @@ impl Node {
         let size_checks = if cfg!(any(test, feature = "lock_free_delays")) {
             self.iter().take(6).count() > 5
         } else {
-            let size_threshold = 1024 - crate::MAX_MSG_HEADER_LEN;
+            let size_threshold = self.split_size();
             let child_threshold = 56 * 1024;
 
             self.len > size_threshold || self.children > child_threshold
@@ -1124,7 +1356,11 @@ impl Node {
         let size_check = if cfg!(any(test, feature = "lock_free_delays")) {
             self.iter().take(2).count() < 2
         } else {
-            let size_threshold = 256 - crate::MAX_MSG_HEADER_LEN;
+            let size_threshold = if self.split_size() > SPLIT_SIZE {
+                self.split_size() / 4
+            } else {
+                256 - crate::MAX_MSG_HEADER_LEN
+            };
             self.len < size_threshold
         };
 
@@ -1134,6 +1370,19 @@ impl Node {
 
         safety_checks && size_check
     }
+
+    /// The size above which the node is split, which is raised
+    /// for the leaves of trees with large expected values so that
+    /// they hold several values each.
+    pub(crate) fn split_size(&self) -> usize {
+        match self.expected_value_size() {
+            Some(size) if !self.is_index => size
+                .saturating_mul(LEAF_VALUES)
+                .max(SPLIT_SIZE)
+                .min(MAX_LEAF_SPLIT_SIZE),
+            _ => SPLIT_SIZE,
+        }
+    }
 }
 
 /// An immutable sorted string table
@@ -1438,6 +1687,11 @@ impl Inner {
             version: 1,
             next,
             is_index,
+            codec: 0,
+            codec_level: 0,
+            dictionary: 0,
+            key_order: 0,
+            tree_hints: 0,
         };
 
         ret.lo_mut().copy_from_slice(lo);
@@ -1578,6 +1832,14 @@ impl Inner {
         Inner::new(&[], None, 0, false, None, &[])
     }
 
+    fn inherit_tree_config(&mut self, other: &Inner) {
+        self.codec = other.codec;
+        self.codec_level = other.codec_level;
+        self.dictionary = other.dictionary;
+        self.key_order = other.key_order;
+        self.tree_hints = other.tree_hints;
+    }
+
     fn fixed_value_length(&self) -> Option<usize> {
         self.fixed_value_length.map(|fvl| usize::from(fvl.get()) - 1)
     }
@@ -1797,12 +2059,59 @@ impl Inner {
             .max(1)
     }
 
+    /// For leaves, looks around the weighted split point for the
+    /// one whose separator can be truncated the most, because that
+    /// separator is copied into the index above and becomes the lo
+    /// and hi of the halves, whose shared prefix is compressed away.
+    /// When keys share long prefixes, like keys that start with the
+    /// id of their tenant, splitting where the prefix changes keeps
+    /// the separator to a few bytes instead of a whole key.
+    fn shortest_separator_split_point(&self) -> usize {
+        let weighted = self.weighted_split_point();
+        if self.is_index {
+            return weighted;
+        }
+
+        let window = self.children() / 8;
+        let start = weighted.saturating_sub(window).max(1);
+        let end = (weighted + window).min(self.children() - 1);
+
+        let separator_len = |split_point: usize| {
+            let left_max: IVec = self.index_key(split_point - 1).into();
+            let right_min: IVec = self.index_key(split_point).into();
+            right_min
+                .iter()
+                .zip(left_max.iter())
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        let distance = |split_point: usize| {
+            if split_point > weighted {
+                split_point - weighted
+            } else {
+                weighted - split_point
+            }
+        };
+
+        let mut best = weighted;
+        let mut best_len = separator_len(weighted);
+        for split_point in start..=end {
+            let len = separator_len(split_point);
+            let closer = distance(split_point) < distance(best);
+            if len < best_len || (len == best_len && closer) {
+                best = split_point;
+                best_len = len;
+            }
+        }
+        best
+    }
+
     fn split(&self) -> (Inner, Inner) {
         assert!(self.children() >= 2);
         assert!(!self.merging);
         assert!(self.merging_child.is_none());
 
-        let split_point = self.weighted_split_point();
+        let split_point = self.shortest_separator_split_point();
 
         let left_max: IVec = self.index_key(split_point - 1).into();
         let right_min: IVec = self.index_key(split_point).into();
@@ -1941,8 +2250,21 @@ impl Inner {
 
         left.rewrite_generations =
             if split_point == 1 { 0 } else { self.rewrite_generations };
+        left.inherit_tree_config(self);
+        // probation is only counted down when the overlay is merged,
+        // every `PAGE_CONSOLIDATION_THRESHOLD` updates, so rounding it
+        // down keeps a half from taking another round of updates past
+        // its split size, after which its halves would wait even
+        // longer, and so on. appends would otherwise make the leaves
+        // grow whenever a short separator moves the split point.
+        let half = self.children() / 2;
+        let probation = if half > PAGE_CONSOLIDATION_THRESHOLD {
+            half - half % PAGE_CONSOLIDATION_THRESHOLD
+        } else {
+            half
+        };
         left.probation_ops_remaining =
-            tf!((self.children() / 2).min(std::u8::MAX as usize), u8);
+            tf!(probation.min(std::u8::MAX as usize), u8);
 
         let mut right = Inner::new(
             &split_key,
@@ -1958,6 +2280,7 @@ impl Inner {
         } else {
             self.rewrite_generations
         };
+        right.inherit_tree_config(self);
         right.probation_ops_remaining = left.probation_ops_remaining;
 
         right.next = self.next;
@@ -2089,6 +2412,7 @@ impl Inner {
 
         ret.rewrite_generations =
             self.rewrite_generations.max(other_rewrite_generations);
+        ret.inherit_tree_config(self);
 
         testing_assert!(ret.is_sorted());
 
@@ -2600,6 +2924,50 @@ mod test {
             .merge_overlay();
     }
 
+    #[test]
+    fn split_shortest_separator() {
+        let keys: Vec<Vec<u8>> = (0..16_u8)
+            .map(|i| {
+                let tenant = if i < 9 { b'a' } else { b'b' };
+                let mut key = vec![tenant; 24];
+                key.push(i);
+                key
+            })
+            .collect();
+        let children: Vec<_> =
+            keys.iter().map(|k| (KeyRef::Slice(k), &[][..])).collect();
+        let node = Inner::new(&[], None, 0, false, None, &children);
+
+        let (left, right) = node.split();
+        assert_eq!(right.lo(), b"b");
+        assert_eq!(left.children(), 9);
+        assert_eq!(right.children(), 7);
+    }
+
+    #[test]
+    fn overlay_ranges() {
+        let overlay = Overlay::default()
+            .update(vec![3].into(), Some(vec![30].into()))
+            .update(vec![1].into(), None)
+            .update(vec![2].into(), Some(vec![20].into()))
+            .update(vec![3].into(), Some(vec![31].into()));
+        let keys = |iter: std::slice::Iter<'_, (IVec, Option<IVec>)>| {
+            iter.map(|(k, _)| k[0]).collect::<Vec<_>>()
+        };
+
+        assert_eq!(overlay.len(), 3);
+        assert_eq!(keys(overlay.iter()), vec![1, 2, 3]);
+        assert_eq!(overlay.get(&[3]), Some(&Some(vec![31].into())));
+        assert_eq!(overlay.get(&[1]), Some(&None));
+        assert_eq!(overlay.get(&[4]), None);
+        assert_eq!(keys(overlay.iter_from(&[2], true)), vec![2, 3]);
+        assert_eq!(keys(overlay.iter_from(&[2], false)), vec![3]);
+        assert_eq!(keys(overlay.iter_from(&[0], false)), vec![1, 2, 3]);
+        assert_eq!(keys(overlay.iter_to(&[2], true)), vec![1, 2]);
+        assert_eq!(keys(overlay.iter_to(&[2], false)), vec![1]);
+        assert_eq!(keys(overlay.iter_to(&[9], false)), vec![1, 2, 3]);
+    }
+
     impl Arbitrary for Node {
         fn arbitrary<G: Gen>(g: &mut G) -> Node {
             Node {
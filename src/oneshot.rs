@@ -60,9 +60,10 @@ impl<T> OneShot<T> {
 
     /// Block on the `OneShot`'s completion
     /// or dropping of the `OneShotFiller`,
-    /// returning an error if not filled
-    /// before a given timeout or if the
-    /// system shuts down before then.
+    /// returning `Timeout` if not filled
+    /// before a given timeout, or
+    /// `Disconnected` if the filler is
+    /// dropped before then.
     pub fn wait_timeout(
         self,
         mut timeout: Duration,
@@ -72,7 +73,7 @@ impl<T> OneShot<T> {
             let start = Instant::now();
             let res = self.cv.wait_for(&mut inner, timeout);
             if res.timed_out() {
-                return Err(std::sync::mpsc::RecvTimeoutError::Disconnected);
+                return Err(std::sync::mpsc::RecvTimeoutError::Timeout);
             }
             timeout =
                 if let Some(timeout) = timeout.checked_sub(start.elapsed()) {
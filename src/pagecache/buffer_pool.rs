@@ -0,0 +1,246 @@
+//! Buffers that the write path reuses instead of allocating and
+//! freeing them for every write.
+//!
+//! A node or link that is compressed or encrypted before it's
+//! written is first serialized into a buffer of its own, and one
+//! that is too large for the log is serialized into a buffer that
+//! is written to the heap. These are taken from the pool and are
+//! returned to it when they're dropped, up to `MAX_POOLED_BYTES`
+//! of them, so that a buffer that was grown for a large item is
+//! reused by the next one. zstd block compressors, which allocate
+//! their context when they're created, are kept in the same way.
+//!
+//! The io buffers of the log are as long as a segment and are
+//! allocated when the log rolls over to a new segment. The memory
+//! of up to `MAX_SPARE_SEGMENTS` of them is kept after they're
+//! written, for the next ones. The spare memory isn't counted in
+//! the `io_buffers` of `Db::memory_usage`, which counts the
+//! buffers that are being filled or written.
+use std::{
+    alloc::{alloc, dealloc, Layout},
+    ops::{Deref, DerefMut},
+};
+
+use crate::*;
+
+// the most bytes of vecs that are kept for reuse
+const MAX_POOLED_BYTES: usize = 4 * 1024 * 1024;
+
+// the most zstd compressors that are kept for reuse
+#[cfg(feature = "compression")]
+const MAX_POOLED_COMPRESSORS: usize = 8;
+
+// the most io buffers whose memory is kept for reuse
+const MAX_SPARE_SEGMENTS: usize = 2;
+
+// the alignment of the io buffers, which are written with
+// O_DIRECT when `Config::direct_io` is set
+const SEGMENT_ALIGNMENT: usize = 8192;
+
+// the memory of an io buffer that has been written
+struct SpareSegment(*mut u8, usize);
+
+#[allow(unsafe_code)]
+unsafe impl Send for SpareSegment {}
+
+#[derive(Default)]
+pub(crate) struct BufferPool {
+    vecs: Mutex<(Vec<Vec<u8>>, usize)>,
+    #[cfg(feature = "compression")]
+    compressors: Mutex<Vec<zstd::block::Compressor>>,
+    segments: Mutex<Vec<SpareSegment>>,
+}
+
+impl Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (vecs, bytes) = &*self.vecs.lock();
+        f.debug_struct("BufferPool")
+            .field("vecs", &vecs.len())
+            .field("vec_bytes", bytes)
+            .field("spare_segments", &self.segments.lock().len())
+            .finish()
+    }
+}
+
+impl Drop for BufferPool {
+    fn drop(&mut self) {
+        for SpareSegment(ptr, len) in self.segments.get_mut().drain(..) {
+            let layout =
+                Layout::from_size_align(len, SEGMENT_ALIGNMENT).unwrap();
+            #[allow(unsafe_code)]
+            unsafe {
+                dealloc(ptr, layout);
+            }
+        }
+    }
+}
+
+impl BufferPool {
+    /// Returns a buffer of `len` zeroed bytes.
+    pub(crate) fn take(&self, len: usize) -> PooledBuf<'_> {
+        let mut vecs = self.vecs.lock();
+        let position = vecs.0.iter().position(|buf| buf.capacity() >= len);
+        let taken = position
+            .or_else(|| vecs.0.len().checked_sub(1))
+            .map(|idx| vecs.0.swap_remove(idx));
+        if let Some(buf) = &taken {
+            vecs.1 -= buf.capacity();
+        }
+        drop(vecs);
+
+        let mut buf = taken.unwrap_or_default();
+        buf.resize(len, 0);
+        PooledBuf { buf, pool: self }
+    }
+
+    /// Serializes an item into a buffer of the pool.
+    pub(crate) fn serialize<T: Serialize>(&self, item: &T) -> PooledBuf<'_> {
+        let mut buf =
+            self.take(usize::try_from(item.serialized_size()).unwrap());
+        item.serialize_into(&mut &mut buf.buf[..]);
+        buf
+    }
+
+    /// Compresses a buffer with a zstd compressor of the pool into
+    /// a buffer of the pool.
+    #[cfg(feature = "compression")]
+    pub(crate) fn compress(
+        &self,
+        buf: &[u8],
+        level: i32,
+    ) -> Result<PooledBuf<'_>> {
+        #[cfg(feature = "metrics")]
+        let _measure = Measure::new(&M.compress);
+
+        let mut compressor = self.compressors.lock().pop().unwrap_or_default();
+        let mut compressed = self.take(compress_bound(buf.len()));
+        let result = compressor.compress_to_buffer(buf, &mut compressed, level);
+
+        let mut compressors = self.compressors.lock();
+        if compressors.len() < MAX_POOLED_COMPRESSORS {
+            compressors.push(compressor);
+        }
+        drop(compressors);
+
+        compressed.buf.truncate(result?);
+        Ok(compressed)
+    }
+
+    fn put(&self, mut buf: Vec<u8>) {
+        let mut vecs = self.vecs.lock();
+        if vecs.1 + buf.capacity() > MAX_POOLED_BYTES {
+            return;
+        }
+        buf.clear();
+        vecs.1 += buf.capacity();
+        vecs.0.push(buf);
+    }
+
+    /// Returns the uninitialized memory of an io buffer that is
+    /// `len` bytes long, which is given back with `put_segment`.
+    pub(crate) fn take_segment(&self, len: usize) -> *mut u8 {
+        let mut segments = self.segments.lock();
+        if let Some(idx) = segments.iter().position(|spare| spare.1 == len) {
+            return segments.swap_remove(idx).0;
+        }
+        drop(segments);
+
+        let layout = Layout::from_size_align(len, SEGMENT_ALIGNMENT).unwrap();
+        #[allow(unsafe_code)]
+        let ptr = unsafe { alloc(layout) };
+
+        assert!(!ptr.is_null(), "failed to allocate critical IO buffer");
+        ptr
+    }
+
+    /// Keeps the memory of an io buffer that has been written, or
+    /// frees it if enough are kept already.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `take_segment` with the
+    /// same `len`, and must not be used after it's put back.
+    #[allow(unsafe_code)]
+    pub(crate) unsafe fn put_segment(&self, ptr: *mut u8, len: usize) {
+        let mut segments = self.segments.lock();
+        if segments.len() < MAX_SPARE_SEGMENTS {
+            segments.push(SpareSegment(ptr, len));
+            return;
+        }
+        drop(segments);
+
+        let layout = Layout::from_size_align(len, SEGMENT_ALIGNMENT).unwrap();
+        dealloc(ptr, layout);
+    }
+}
+
+// the most bytes that zstd may compress `len` bytes into, see
+// `ZSTD_COMPRESSBOUND` in zstd.h
+#[cfg(feature = "compression")]
+fn compress_bound(len: usize) -> usize {
+    const BLOCK: usize = 128 * 1024;
+    len + (len >> 8) + if len < BLOCK { (BLOCK - len) >> 11 } else { 0 }
+}
+
+/// A buffer that is returned to its `BufferPool` when it's
+/// dropped.
+pub(crate) struct PooledBuf<'a> {
+    buf: Vec<u8>,
+    pool: &'a BufferPool,
+}
+
+impl Deref for PooledBuf<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuf<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuf<'_> {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buf));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pooled_bufs_are_reused() {
+        let pool = BufferPool::default();
+        let ptr = {
+            let mut buf = pool.take(1000);
+            buf[999] = 1;
+            buf.as_ptr()
+        };
+
+        let buf = pool.take(10);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(&*buf, &[0; 10]);
+
+        let other = pool.take(10);
+        assert_ne!(other.as_ptr(), ptr);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn pooled_compression() {
+        let pool = BufferPool::default();
+        for &len in &[0, 1, 1000, 200_000] {
+            let data: Vec<u8> = (0..len).map(|i| b"pooled!"[i % 7]).collect();
+            let compressed = pool.compress(&data, 1).unwrap();
+            let decompressed =
+                zstd::block::decompress(&compressed, len).unwrap();
+            assert_eq!(decompressed, data);
+        }
+        assert_eq!(pool.compressors.lock().len(), 1);
+    }
+}
@@ -0,0 +1,206 @@
+//! Consistent on-disk copies of a running database.
+//!
+//! The log file and the heap are first copied while writes
+//! continue. All writes to the storage files are then paused
+//! briefly, and only the segments that may have been written
+//! since that fuzzy copy began are copied again, along with the
+//! heap items that were written since then, the snapshot, config
+//! and dictionary files, and what was appended to the value log
+//! files since they were copied along with the log. The result
+//! is identical
+//! to the state the files would have been left in by a crash
+//! at the moment writes were paused, so opening it performs
+//! normal recovery.
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io,
+    path::Path,
+};
+
+use super::{pread_exact_or_eof, pwrite_all, read_segment_header, PageCache};
+use crate::*;
+
+const COPY_CHUNK: usize = 1024 * 1024;
+
+/// Writes a consistent copy of the storage files to the
+/// provided directory, see `Db::checkpoint`.
+pub(crate) fn checkpoint(pc: &PageCache, path: &Path) -> Result<()> {
+    if path.join("db").exists() {
+        return Err(Error::Unsupported(format!(
+            "refusing to checkpoint into {:?}, which already \
+             contains a database",
+            path
+        )));
+    }
+
+    let config = &pc.config;
+    let heap_src = config.get_path().join("heap");
+    let heap_dst = path.join("heap");
+    std::fs::create_dir_all(&heap_dst)?;
+
+    let mut options = OpenOptions::new();
+    options.create(true).read(true).write(true);
+    let db_dst = options.open(path.join("db"))?;
+
+    // everything below this offset is already on disk
+    // before the fuzzy copy begins, so only segments
+    // that have been written at or after it may have
+    // changed while we were copying.
+    let start_lsn = pc.log.stable_offset();
+
+    let fuzzy_len = config.file.metadata()?.len();
+    copy_range(&config.file, &db_dst, 0, fuzzy_len)?;
+
+    // the heap items that are written from now on are copied
+    // again once writes are paused
+    let heap_writes = config.heap.track_writes();
+    let mut copied = vec![];
+    for entry in std::fs::read_dir(&heap_src)? {
+        let entry = entry?;
+        let dst = heap_dst.join(entry.file_name());
+        let src_file = File::open(entry.path())?;
+        let dst_file = options.open(&dst)?;
+        copy_range(&src_file, &dst_file, 0, src_file.metadata()?.len())?;
+        copied.push(dst);
+    }
+
+    let values_src = config.get_path().join("values");
+    let values_dst = path.join("values");
+    let fuzzy_values =
+        copy_values(&values_src, &values_dst, &BTreeMap::new())?;
+
+    let io_barrier = config.io_barrier.write();
+
+    let len = config.file.metadata()?.len();
+    db_dst.set_len(len)?;
+
+    let segment_size = config.segment_size as u64;
+    let mut base = 0;
+    while base < len {
+        let header = read_segment_header(&config.file, base)?;
+        if !header.ok || header.lsn + segment_size as Lsn > start_lsn {
+            let segment_len = segment_size.min(len - base);
+            copy_range(&config.file, &db_dst, base, segment_len)?;
+        }
+        base += segment_size;
+    }
+
+    copied.push(path.join("conf"));
+    std::fs::copy(config.config_path(), path.join("conf"))?;
+
+    for snapshot in config.get_snapshot_files()? {
+        let name = snapshot.file_name().expect("snapshot should have a name");
+        let dst = path.join(name);
+        std::fs::copy(&snapshot, &dst)?;
+        copied.push(dst);
+    }
+
+    for heap_id in heap_writes.written() {
+        let (src, at) = heap_id.location_in(&heap_src);
+        let (dst, _) = heap_id.location_in(&heap_dst);
+        let src_file = File::open(src)?;
+        let dst_file = options.open(&dst)?;
+        copy_range(&src_file, &dst_file, at, heap_id.slab_size())?;
+        if !copied.contains(&dst) {
+            copied.push(dst);
+        }
+    }
+
+    // dictionaries are durable before anything is compressed
+    // with them and are never removed, so any that a copied
+    // page refers to is present by now.
+    let dictionaries_src = config.get_path().join("dictionaries");
+    let dictionaries_dst = path.join("dictionaries");
+    if dictionaries_src.exists() {
+        std::fs::create_dir_all(&dictionaries_dst)?;
+        for entry in std::fs::read_dir(&dictionaries_src)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().ends_with(".generating") {
+                continue;
+            }
+            let dst = dictionaries_dst.join(entry.file_name());
+            std::fs::copy(entry.path(), &dst)?;
+            copied.push(dst);
+        }
+    }
+
+    let values = copy_values(&values_src, &values_dst, &fuzzy_values)?;
+    for name in values.keys() {
+        copied.push(values_dst.join(name));
+    }
+
+    drop(io_barrier);
+    drop(heap_writes);
+
+    db_dst.sync_all()?;
+    for dst in copied {
+        File::open(dst)?.sync_all()?;
+    }
+    maybe_fsync_directory(&heap_dst)?;
+    if dictionaries_dst.exists() {
+        maybe_fsync_directory(&dictionaries_dst)?;
+    }
+    if values_dst.exists() {
+        maybe_fsync_directory(&values_dst)?;
+    }
+    maybe_fsync_directory(path)?;
+
+    Ok(())
+}
+
+fn copy_range(src: &File, dst: &File, at: u64, len: u64) -> Result<()> {
+    let mut buf = vec![0; COPY_CHUNK];
+    let mut copied = 0;
+
+    while copied < len {
+        let want = usize::try_from(len - copied).unwrap().min(COPY_CHUNK);
+        let read = pread_exact_or_eof(src, &mut buf[..want], at + copied)?;
+        pwrite_all(dst, &buf[..read], at + copied)?;
+        if read < want {
+            break;
+        }
+        copied += read as u64;
+    }
+
+    Ok(())
+}
+
+// copies what was appended to the value log files after the
+// lengths that they were already copied up to, returning the
+// lengths of the copies.
+fn copy_values(
+    src: &Path,
+    dst: &Path,
+    copied: &BTreeMap<OsString, u64>,
+) -> Result<BTreeMap<OsString, u64>> {
+    let mut ret = BTreeMap::new();
+    if !src.exists() {
+        return Ok(ret);
+    }
+
+    std::fs::create_dir_all(dst)?;
+    for res in std::fs::read_dir(src)? {
+        let entry = res?;
+        let src_file = match File::open(entry.path()) {
+            Ok(file) => file,
+            // the file was removed by a value log collection
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        let name = entry.file_name();
+        let len = src_file.metadata()?.len();
+        let at = copied.get(&name).cloned().unwrap_or(0);
+
+        let mut options = OpenOptions::new();
+        options.create(true).write(true);
+        let dst_file = options.open(dst.join(&name))?;
+        copy_range(&src_file, &dst_file, at, len.saturating_sub(at))?;
+
+        ret.insert(name, len);
+    }
+
+    Ok(ret)
+}
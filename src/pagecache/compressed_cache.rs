@@ -0,0 +1,179 @@
+//! The compressed tier of the page cache, see
+//! `Config::compressed_cache_fraction`.
+//!
+//! Nodes that are paged out are compressed with zstd and kept here
+//! along with the `CacheInfo`s of their page, so that a page that
+//! is read again before it's evicted from this tier is decompressed
+//! instead of read from the storage files. An entry is only used if
+//! the page hasn't changed since, and is removed once it's used,
+//! since the node is back in the page cache then. The oldest
+//! entries are evicted first.
+#![cfg_attr(not(feature = "compression"), allow(dead_code))]
+use std::{collections::BTreeMap, mem::size_of};
+
+use super::{BufferPool, CacheInfo};
+use crate::*;
+
+const SHARDS: usize = 16;
+
+// favors speed over ratio, since pages are compressed as they are
+// paged out by the threads that read or write
+#[cfg(feature = "compression")]
+const LEVEL: i32 = 1;
+
+// the memory that an entry takes besides its data
+const ENTRY_OVERHEAD: usize = 64;
+
+pub(crate) struct CompressedCache {
+    capacity: AtomicUsize,
+    shards: Vec<Mutex<Shard>>,
+}
+
+#[derive(Default)]
+struct Shard {
+    entries: FastMap8<PageId, Entry>,
+    // the pages of the entries by the order that they were
+    // inserted in, oldest first
+    order: BTreeMap<u64, PageId>,
+    next_seq: u64,
+    size: usize,
+}
+
+struct Entry {
+    seq: u64,
+    cache_infos: Vec<CacheInfo>,
+    len: usize,
+    data: Vec<u8>,
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        self.data.len()
+            + self.cache_infos.len() * size_of::<CacheInfo>()
+            + ENTRY_OVERHEAD
+    }
+}
+
+impl Shard {
+    fn remove(&mut self, pid: PageId) -> Option<Entry> {
+        let entry = self.entries.remove(&pid)?;
+        self.order.remove(&entry.seq);
+        self.size -= entry.size();
+        Some(entry)
+    }
+
+    fn evict(&mut self, capacity: usize) {
+        while self.size > capacity {
+            let oldest = if let Some((_, pid)) = self.order.iter().next() {
+                *pid
+            } else {
+                break;
+            };
+            self.remove(oldest);
+        }
+    }
+}
+
+impl CompressedCache {
+    pub(crate) fn new(capacity: usize) -> CompressedCache {
+        let mut shards = Vec::with_capacity(SHARDS);
+        shards.resize_with(SHARDS, Mutex::default);
+        CompressedCache { capacity: AtomicUsize::new(capacity), shards }
+    }
+
+    /// Returns the capacity of the tier in bytes.
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity.load(Acquire)
+    }
+
+    /// Returns the bytes of the entries in the tier.
+    pub(crate) fn size(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().size).sum()
+    }
+
+    /// Changes the capacity of the tier, evicting the oldest
+    /// entries until it fits.
+    pub(crate) fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity, Release);
+        for shard in &self.shards {
+            shard.lock().evict(capacity / SHARDS);
+        }
+    }
+
+    fn shard(&self, pid: PageId) -> &Mutex<Shard> {
+        &self.shards[usize::try_from(pid).unwrap() % SHARDS]
+    }
+
+    /// Keeps a node that is being paged out.
+    pub(crate) fn insert(
+        &self,
+        pid: PageId,
+        cache_infos: &[CacheInfo],
+        node: &Node,
+        buffers: &BufferPool,
+    ) {
+        let shard_capacity = self.capacity() / SHARDS;
+        let serialized = buffers.serialize(node);
+        let data = if let Some(data) = compress(&serialized, buffers) {
+            data
+        } else {
+            return;
+        };
+        let mut entry = Entry {
+            seq: 0,
+            cache_infos: cache_infos.to_vec(),
+            len: serialized.len(),
+            data,
+        };
+        if entry.size() > shard_capacity {
+            return;
+        }
+
+        let mut shard = self.shard(pid).lock();
+        shard.remove(pid);
+        entry.seq = shard.next_seq;
+        shard.next_seq += 1;
+        shard.size += entry.size();
+        shard.order.insert(entry.seq, pid);
+        shard.entries.insert(pid, entry);
+        shard.evict(shard_capacity);
+    }
+
+    /// Removes the node of a page, returning it if the page is
+    /// still made of `cache_infos`.
+    pub(crate) fn take(
+        &self,
+        pid: PageId,
+        cache_infos: &[CacheInfo],
+    ) -> Option<Node> {
+        let entry = self.shard(pid).lock().remove(pid)?;
+        if entry.cache_infos != cache_infos {
+            return None;
+        }
+        let serialized = decompress(&entry.data, entry.len)?;
+        Node::deserialize(&mut serialized.as_slice()).ok()
+    }
+}
+
+#[cfg(feature = "compression")]
+fn compress(buf: &[u8], buffers: &BufferPool) -> Option<Vec<u8>> {
+    buffers.compress(buf, LEVEL).ok().map(|compressed| compressed.to_vec())
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress(_buf: &[u8], _buffers: &BufferPool) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(feature = "compression")]
+fn decompress(buf: &[u8], len: usize) -> Option<Vec<u8>> {
+    #[cfg(feature = "metrics")]
+    let _measure = Measure::new(&M.decompress);
+
+    zstd::block::decompress(buf, len).ok()
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(_buf: &[u8], _len: usize) -> Option<Vec<u8>> {
+    None
+}
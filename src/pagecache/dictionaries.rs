@@ -0,0 +1,251 @@
+//! Trained zstd dictionaries, see `Tree::train_dictionary`.
+//!
+//! Each tree that is created with `Codec::Zstd` is assigned a
+//! slot, which is recorded in the header of every one of its
+//! nodes. The dictionaries trained for a tree are numbered
+//! with versions starting at 1, and are durably written to the
+//! `dictionaries` directory, in a file named after the slot and
+//! the version, before anything is compressed with them. Pages
+//! record the slot and version of the dictionary that they were
+//! compressed with, so dictionaries are never removed. A slot is
+//! reserved with an empty file named after it.
+#![cfg_attr(not(feature = "compression"), allow(dead_code))]
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{encryption::Encryption, *};
+
+pub(crate) struct Dictionaries {
+    dir: PathBuf,
+    read_only: bool,
+    encryption: Option<Encryption>,
+    next_slot: Mutex<u32>,
+    // keyed by slot and version
+    dictionaries: RwLock<BTreeMap<(u32, u32), Arc<Dictionary>>>,
+}
+
+impl Debug for Dictionaries {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> std::result::Result<(), fmt::Error> {
+        f.debug_struct("Dictionaries")
+            .field("dir", &self.dir)
+            .field("next_slot", &*self.next_slot.lock())
+            .field("dictionaries", &self.dictionaries.read().len())
+            .finish()
+    }
+}
+
+impl Dictionaries {
+    pub(crate) fn start(
+        path: &Path,
+        read_only: bool,
+        encryption: Option<Encryption>,
+    ) -> Result<Dictionaries> {
+        let dir = path.join("dictionaries");
+        let mut next_slot = 1;
+        let mut dictionaries = BTreeMap::new();
+
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => Some(entries),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        for entry in entries.into_iter().flatten() {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| Error::corruption(None))?
+                .to_owned();
+
+            if name.ends_with(".generating") {
+                if !read_only {
+                    fs::remove_file(&path)?;
+                }
+                continue;
+            }
+
+            let mut parts = name.splitn(2, '-');
+            let slot: u32 = parse(parts.next())?;
+            next_slot = next_slot.max(slot + 1);
+
+            if let Some(version) = parts.next() {
+                let version = parse(Some(version))?;
+                let mut data = fs::read(&path)?;
+                if let Some(encryption) = &encryption {
+                    data = encryption.decrypt(&data)?;
+                }
+                let dictionary = Dictionary::new(slot, version, data);
+                dictionaries.insert((slot, version), Arc::new(dictionary));
+            }
+        }
+
+        Ok(Dictionaries {
+            dir,
+            read_only,
+            encryption,
+            next_slot: Mutex::new(next_slot),
+            dictionaries: RwLock::new(dictionaries),
+        })
+    }
+
+    /// Reserves a slot for the dictionaries of a new tree.
+    pub(crate) fn allocate(&self) -> Result<u32> {
+        if self.read_only {
+            return Err(Error::Unsupported(
+                "the database was opened in read-only mode".into(),
+            ));
+        }
+
+        let mut next_slot = self.next_slot.lock();
+        let slot = *next_slot;
+
+        // slots are stored in 16 bits of the node header
+        if slot > u32::from(u16::max_value()) {
+            return Err(Error::Unsupported(
+                "too many trees have been created with zstd compression"
+                    .into(),
+            ));
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.dir.join(slot.to_string()))?;
+        file.sync_all()?;
+        maybe_fsync_directory(&self.dir)?;
+
+        *next_slot += 1;
+        Ok(slot)
+    }
+
+    /// Durably stores a new dictionary for a slot, which becomes
+    /// the one that is used for compressing it.
+    pub(crate) fn add(&self, slot: u32, data: Vec<u8>) -> Result<()> {
+        if self.read_only {
+            return Err(Error::Unsupported(
+                "the database was opened in read-only mode".into(),
+            ));
+        }
+
+        // held across the write so that versions are
+        // assigned in order.
+        let mut dictionaries = self.dictionaries.write();
+
+        let version = dictionaries
+            .range((slot, 0)..=(slot, u32::max_value()))
+            .next_back()
+            .map_or(1, |(&(_, version), _)| version + 1);
+
+        let name = format!("{}-{}", slot, version);
+        let path = self.dir.join(&name);
+        let generating = self.dir.join(name + ".generating");
+
+        let sealed = if let Some(encryption) = &self.encryption {
+            encryption.encrypt(&data)?
+        } else {
+            data.clone()
+        };
+
+        let mut file = File::create(&generating)?;
+        file.write_all(&sealed)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&generating, &path)?;
+        maybe_fsync_directory(&self.dir)?;
+
+        let dictionary = Dictionary::new(slot, version, data);
+        dictionaries.insert((slot, version), Arc::new(dictionary));
+
+        Ok(())
+    }
+
+    /// Returns the most recent dictionary for a slot.
+    pub(crate) fn current(&self, slot: u32) -> Option<Arc<Dictionary>> {
+        self.dictionaries
+            .read()
+            .range((slot, 0)..=(slot, u32::max_value()))
+            .next_back()
+            .map(|(_, dictionary)| dictionary.clone())
+    }
+
+    pub(crate) fn get(
+        &self,
+        slot: u32,
+        version: u32,
+    ) -> Option<Arc<Dictionary>> {
+        self.dictionaries.read().get(&(slot, version)).cloned()
+    }
+}
+
+fn parse(part: Option<&str>) -> Result<u32> {
+    part.and_then(|part| part.parse().ok())
+        .ok_or_else(|| Error::corruption(None))
+}
+
+pub(crate) struct Dictionary {
+    pub(crate) slot: u32,
+    pub(crate) version: u32,
+    #[cfg(feature = "compression")]
+    data: Vec<u8>,
+    #[cfg(feature = "compression")]
+    decoder: zstd::dict::DecoderDictionary<'static>,
+    // prepared for each compression level that is used
+    #[cfg(feature = "compression")]
+    encoders:
+        Mutex<BTreeMap<i32, Arc<zstd::dict::EncoderDictionary<'static>>>>,
+}
+
+impl Dictionary {
+    #[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+    fn new(slot: u32, version: u32, data: Vec<u8>) -> Dictionary {
+        Dictionary {
+            slot,
+            version,
+            #[cfg(feature = "compression")]
+            decoder: zstd::dict::DecoderDictionary::copy(&data),
+            #[cfg(feature = "compression")]
+            data,
+            #[cfg(feature = "compression")]
+            encoders: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    pub(crate) fn compress(&self, buf: &[u8], level: i32) -> Result<Vec<u8>> {
+        use zstd::{dict::EncoderDictionary, stream::write::Encoder};
+
+        let encoder_dictionary = self
+            .encoders
+            .lock()
+            .entry(level)
+            .or_insert_with(|| {
+                Arc::new(EncoderDictionary::copy(&self.data, level))
+            })
+            .clone();
+
+        let mut encoder =
+            Encoder::with_prepared_dictionary(vec![], &encoder_dictionary)?;
+        encoder.write_all(buf)?;
+        Ok(encoder.finish()?)
+    }
+
+    #[cfg(feature = "compression")]
+    pub(crate) fn decompress(&self, buf: &[u8]) -> Result<Vec<u8>> {
+        use zstd::stream::read::Decoder;
+
+        let mut out = vec![];
+        Decoder::with_prepared_dictionary(buf, &self.decoder)
+            .and_then(|mut decoder| decoder.read_to_end(&mut out))
+            .map_err(|_| Error::corruption(None))?;
+        Ok(out)
+    }
+}
@@ -0,0 +1,208 @@
+//! Reads and writes of the log that bypass the page cache of the
+//! OS, see `Config::direct_io`.
+//!
+//! A file that is opened with `O_DIRECT` can only be read and
+//! written in whole blocks, from and to memory that is aligned
+//! to a block. Buffers of the log start and end anywhere in a
+//! block, so the first and last blocks that a buffer is written
+//! to are read back first and written again with the bytes of
+//! the buffers around it left as they were. Reads are made of
+//! the blocks that hold the requested bytes, which are then
+//! copied out.
+use std::{
+    alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout},
+    convert::TryFrom,
+    fs::File,
+    io,
+    ops::{Deref, DerefMut},
+    path::Path,
+    slice,
+};
+
+use super::{logger::ReadAt, pwrite_all, LogOffset};
+
+// a multiple of the logical block size of the devices in use
+const BLOCK_SIZE: usize = 4096;
+
+// a zeroed buffer that direct IO can read into and write from
+struct AlignedBuf(*mut u8, usize);
+
+impl AlignedBuf {
+    fn new(len: usize) -> AlignedBuf {
+        let layout = Layout::from_size_align(len, BLOCK_SIZE).unwrap();
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            handle_alloc_error(layout);
+        }
+        AlignedBuf(ptr, len)
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.0, self.1) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.0, self.1) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        let layout = Layout::from_size_align(self.1, BLOCK_SIZE).unwrap();
+        unsafe {
+            dealloc(self.0, layout);
+        }
+    }
+}
+
+/// Opens the log file for direct IO, which fails on filesystems
+/// that don't support it, like tmpfs.
+pub(crate) fn open(path: &Path, read_only: bool) -> io::Result<File> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)
+    }
+
+    // `Config::validate` refuses `direct_io` on other platforms
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (path, read_only);
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "direct IO is only supported on linux",
+        ))
+    }
+}
+
+/// Writes `data` at `offset` of a file that was opened for direct
+/// IO. The blocks at either end are shared with the bytes around
+/// `data`, so the caller has to keep other writes to them from
+/// running at the same time.
+pub(crate) fn pwrite(
+    file: &File,
+    data: &[u8],
+    offset: LogOffset,
+) -> io::Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let (base, mut buf) = blocks(offset, data.len());
+    let start = usize::try_from(offset - base).unwrap();
+    let end = start + data.len();
+    let last = buf.len() - BLOCK_SIZE;
+
+    if start != 0 {
+        let _ = read_blocks(file, &mut buf[..BLOCK_SIZE], base)?;
+    }
+    if end != buf.len() && (last != 0 || start == 0) {
+        let at = base + last as LogOffset;
+        let _ = read_blocks(file, &mut buf[last..], at)?;
+    }
+    buf[start..end].copy_from_slice(data);
+    pwrite_all(file, &buf, base)
+}
+
+/// A file that was opened for direct IO.
+pub(crate) struct DirectFile<'a>(pub(crate) &'a File);
+
+impl ReadAt for DirectFile<'_> {
+    fn pread_exact(&self, dst: &mut [u8], at: u64) -> io::Result<()> {
+        let read = self.pread_exact_or_eof(dst, at)?;
+        if read < dst.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill buffer",
+            ));
+        }
+        Ok(())
+    }
+
+    fn pread_exact_or_eof(
+        &self,
+        dst: &mut [u8],
+        at: u64,
+    ) -> io::Result<usize> {
+        if dst.is_empty() {
+            return Ok(0);
+        }
+        let (base, mut buf) = blocks(at, dst.len());
+        let start = usize::try_from(at - base).unwrap();
+        let read = read_blocks(self.0, &mut buf, base)?;
+        if read <= start {
+            return Ok(0);
+        }
+        let len = std::cmp::min(dst.len(), read - start);
+        dst[..len].copy_from_slice(&buf[start..start + len]);
+        Ok(len)
+    }
+}
+
+// the offset of the first block that holds `len` bytes at
+// `offset`, and a buffer for all of the blocks that hold them
+fn blocks(offset: LogOffset, len: usize) -> (LogOffset, AlignedBuf) {
+    let block = BLOCK_SIZE as LogOffset;
+    let base = offset / block * block;
+    let mut blocks_len = offset + len as LogOffset - base;
+    let partial = blocks_len % block;
+    if partial > 0 {
+        blocks_len += block - partial;
+    }
+    (base, AlignedBuf::new(usize::try_from(blocks_len).unwrap()))
+}
+
+// reads whole blocks until `buf` is full or the end of the file
+// is reached, leaving the rest of `buf` zeroed. reads that stop
+// at the end of the file stop in the middle of a block, and
+// reading on from there would be unaligned.
+fn read_blocks(
+    file: &File,
+    buf: &mut [u8],
+    offset: LogOffset,
+) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        let at = offset + read as LogOffset;
+        match read_at(file, &mut buf[read..], at) {
+            Ok(0) => break,
+            Ok(n) => {
+                read += n;
+                if n % BLOCK_SIZE != 0 {
+                    break;
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(unix)]
+fn read_at(
+    file: &File,
+    buf: &mut [u8],
+    offset: LogOffset,
+) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(not(unix))]
+fn read_at(
+    file: &File,
+    buf: &mut [u8],
+    offset: LogOffset,
+) -> io::Result<usize> {
+    super::pread_exact_or_eof(file, buf, offset)
+}
@@ -5,18 +5,20 @@ use std::{
     fmt::{self, Debug},
     fs::File,
     mem::{transmute, MaybeUninit},
-    path::Path,
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicU32, Ordering::Acquire},
         Arc,
     },
 };
 
+use parking_lot::Mutex;
+
 use crate::{
     ebr::pin,
     pagecache::{pread_exact, pwrite_all, MessageKind},
     stack::Stack,
-    Error, Lsn, Result,
+    Checksum, Error, Lsn, Result,
 };
 
 #[cfg(not(feature = "testing"))]
@@ -76,7 +78,14 @@ impl HeapId {
         slab_id_to_size(slab_id) * u64::from(idx)
     }
 
-    fn slab_size(&self) -> u64 {
+    /// Returns the path of the slab file that holds the item
+    /// within the heap directory, and its offset in that file.
+    pub(crate) fn location_in(&self, heap_dir: &Path) -> (PathBuf, u64) {
+        let (slab_id, _idx, _lsn) = self.decompose();
+        (heap_dir.join(format!("{:02}", slab_id)), self.offset())
+    }
+
+    pub(crate) fn slab_size(&self) -> u64 {
         let (slab_id, _idx, _lsn) = self.decompose();
         slab_id_to_size(slab_id)
     }
@@ -102,6 +111,7 @@ fn size_to_slab_id(size: u64) -> SlabId {
 
 pub(crate) struct Reservation {
     slab_free: Arc<Stack<u32>>,
+    written: Arc<Mutex<Written>>,
     completed: bool,
     file: File,
     pub heap_id: HeapId,
@@ -130,6 +140,12 @@ impl Reservation {
         // write data
         pwrite_all(&self.file, data, self.heap_id.offset())?;
 
+        let mut written = self.written.lock();
+        if written.trackers > 0 {
+            written.items.push(self.heap_id);
+        }
+        drop(written);
+
         // sync data
         if self.from_tip {
             self.file.sync_all()?;
@@ -182,18 +198,69 @@ pub(crate) struct Heap {
     // smallest slab to 2^48 in
     // the last.
     slabs: [Slab; 32],
+    checksum: Checksum,
+    written: Arc<Mutex<Written>>,
+}
+
+/// The items that were written while a `WriteTracker` exists.
+#[derive(Debug, Default)]
+struct Written {
+    trackers: usize,
+    items: Vec<HeapId>,
+}
+
+/// Records the items that are written to the heap from its
+/// creation on, see `Heap::track_writes`.
+pub(crate) struct WriteTracker<'a> {
+    written: &'a Mutex<Written>,
+    start: usize,
+}
+
+impl<'a> WriteTracker<'a> {
+    /// Returns the items that were written since the tracker was
+    /// created.
+    pub(crate) fn written(&self) -> Vec<HeapId> {
+        self.written.lock().items[self.start..].to_vec()
+    }
+}
+
+impl<'a> Drop for WriteTracker<'a> {
+    fn drop(&mut self) {
+        let mut written = self.written.lock();
+        written.trackers -= 1;
+        if written.trackers == 0 {
+            written.items.clear();
+        }
+    }
 }
 
 impl Heap {
-    pub fn start<P: AsRef<Path>>(p: P) -> Result<Heap> {
+    pub fn start<P: AsRef<Path>>(
+        p: P,
+        read_only: bool,
+        checksum: Checksum,
+    ) -> Result<Heap> {
         let mut slabs: [MaybeUninit<Slab>; 32] = unsafe { std::mem::zeroed() };
 
         for slab_id in 0..32 {
-            let slab = Slab::start(&p, slab_id)?;
+            let slab = Slab::start(&p, slab_id, read_only)?;
             slabs[slab_id as usize] = MaybeUninit::new(slab);
         }
 
-        Ok(Heap { slabs: unsafe { transmute(slabs) } })
+        Ok(Heap {
+            slabs: unsafe { transmute(slabs) },
+            checksum,
+            written: Arc::default(),
+        })
+    }
+
+    /// Starts recording the items that are written to the heap,
+    /// so that a copy of it that is made while writes go on can
+    /// be brought up to date, see `Db::checkpoint`.
+    pub(crate) fn track_writes(&self) -> WriteTracker<'_> {
+        let mut written = self.written.lock();
+        written.trackers += 1;
+        WriteTracker { written: &self.written, start: written.items.len() }
     }
 
     pub fn gc_unknown_items(&self, snapshot: &crate::pagecache::Snapshot) {
@@ -233,18 +300,13 @@ impl Heap {
         }
     }
 
-    pub fn read(
-        &self,
-        heap_id: HeapId,
-        use_compression: bool,
-    ) -> Result<(MessageKind, Vec<u8>)> {
+    /// Reads the stored message, which must still be
+    /// decoded with `decode_message`.
+    pub fn read(&self, heap_id: HeapId) -> Result<(MessageKind, Vec<u8>)> {
         log::trace!("Heap::read({:?})", heap_id);
         let (slab_id, slab_idx, original_lsn) = heap_id.decompose();
-        self.slabs[slab_id as usize].read(
-            slab_idx,
-            original_lsn,
-            use_compression,
-        )
+        let slab = &self.slabs[slab_id as usize];
+        slab.read(slab_idx, original_lsn, self.checksum)
     }
 
     pub fn free(&self, heap_id: HeapId) {
@@ -256,7 +318,8 @@ impl Heap {
     pub fn reserve(&self, size: u64, original_lsn: Lsn) -> Reservation {
         assert!(size < 1 << 48);
         let slab_id = size_to_slab_id(size);
-        let ret = self.slabs[slab_id as usize].reserve(original_lsn);
+        let ret =
+            self.slabs[slab_id as usize].reserve(original_lsn, &self.written);
         log::trace!("Heap::reserve({}) -> {:?}", size, ret.heap_id);
         ret
     }
@@ -271,14 +334,20 @@ struct Slab {
 }
 
 impl Slab {
-    pub fn start<P: AsRef<Path>>(directory: P, slab_id: u8) -> Result<Slab> {
+    pub fn start<P: AsRef<Path>>(
+        directory: P,
+        slab_id: u8,
+        read_only: bool,
+    ) -> Result<Slab> {
         let bs = slab_id_to_size(slab_id);
         let free = Arc::new(Stack::default());
 
         let mut options = std::fs::OpenOptions::new();
-        options.create(true);
         options.read(true);
-        options.write(true);
+        if !read_only {
+            options.create(true);
+            options.write(true);
+        }
 
         let file =
             options.open(directory.as_ref().join(format!("{:02}", slab_id)))?;
@@ -299,7 +368,7 @@ impl Slab {
         &self,
         slab_idx: SlabIdx,
         original_lsn: Lsn,
-        use_compression: bool,
+        checksum: Checksum,
     ) -> Result<(MessageKind, Vec<u8>)> {
         let bs = slab_id_to_size(self.slab_id);
         let offset = u64::from(slab_idx) * bs;
@@ -313,7 +382,7 @@ impl Slab {
         let stored_crc =
             u32::from_le_bytes(heap_buf[1..5].as_ref().try_into().unwrap());
 
-        let mut hasher = crc32fast::Hasher::new();
+        let mut hasher = checksum.hasher();
         hasher.update(&heap_buf[0..1]);
         hasher.update(&heap_buf[5..]);
         let actual_crc = hasher.finalize();
@@ -331,11 +400,6 @@ impl Slab {
                 return Err(Error::corruption(None));
             }
             let buf = heap_buf[13..].to_vec();
-            let buf = if use_compression {
-                crate::pagecache::decompress(buf)
-            } else {
-                buf
-            };
             Ok((MessageKind::from(heap_buf[0]), buf))
         } else {
             log::debug!(
@@ -347,7 +411,11 @@ impl Slab {
         }
     }
 
-    fn reserve(&self, original_lsn: Lsn) -> Reservation {
+    fn reserve(
+        &self,
+        original_lsn: Lsn,
+        written: &Arc<Mutex<Written>>,
+    ) -> Reservation {
         let (idx, from_tip) = if let Some(idx) = self.free.pop(&pin()) {
             log::trace!(
                 "reusing heap index {} in slab for sizes of {}",
@@ -373,6 +441,7 @@ impl Slab {
 
         Reservation {
             slab_free: self.free.clone(),
+            written: written.clone(),
             completed: false,
             file: self.file.try_clone().unwrap(),
             from_tip,
@@ -385,45 +454,57 @@ impl Slab {
         self.free.push(idx, &pin());
     }
 
-    fn punch_hole(&self, #[allow(unused)] idx: u32) {
-        #[cfg(all(target_os = "linux", not(miri)))]
-        {
-            use std::{
-                os::unix::io::AsRawFd,
-                sync::atomic::{AtomicBool, Ordering::Relaxed},
-            };
+    fn punch_hole(&self, idx: u32) {
+        let bs = slab_id_to_size(self.slab_id);
+        let _punched = punch_hole(&self.file, u64::from(idx) * bs, bs);
+    }
+}
 
-            use libc::{fallocate, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE};
+/// Deallocates the blocks of a range of a file, which reads back
+/// as zeroes, without changing the length of the file. Returns
+/// `false` if hole punching isn't supported, in which case it is
+/// not attempted again.
+pub(crate) fn punch_hole(
+    #[allow(unused)] file: &File,
+    #[allow(unused)] offset: u64,
+    #[allow(unused)] len: u64,
+) -> bool {
+    #[cfg(all(target_os = "linux", not(miri)))]
+    {
+        use std::{
+            os::unix::io::AsRawFd,
+            sync::atomic::{AtomicBool, Ordering::Relaxed},
+        };
 
-            static HOLE_PUNCHING_ENABLED: AtomicBool = AtomicBool::new(true);
-            const MODE: i32 = FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE;
+        use libc::{fallocate, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE};
 
-            if HOLE_PUNCHING_ENABLED.load(Relaxed) {
-                let bs = i64::try_from(slab_id_to_size(self.slab_id)).unwrap();
-                let offset = i64::from(idx) * bs;
+        static HOLE_PUNCHING_ENABLED: AtomicBool = AtomicBool::new(true);
+        const MODE: i32 = FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE;
 
-                let fd = self.file.as_raw_fd();
+        if HOLE_PUNCHING_ENABLED.load(Relaxed) {
+            let fd = file.as_raw_fd();
 
-                let ret = unsafe {
-                    fallocate(
-                        fd,
-                        MODE,
-                        #[allow(clippy::useless_conversion)]
-                        offset.try_into().unwrap(),
-                        #[allow(clippy::useless_conversion)]
-                        bs.try_into().unwrap(),
-                    )
-                };
+            let ret = unsafe {
+                fallocate(
+                    fd,
+                    MODE,
+                    libc::off_t::try_from(offset).unwrap(),
+                    libc::off_t::try_from(len).unwrap(),
+                )
+            };
 
-                if ret != 0 {
-                    let err = std::io::Error::last_os_error();
-                    log::error!(
-                        "failed to punch hole in heap file: {:?}. disabling hole punching",
-                        err
-                    );
-                    HOLE_PUNCHING_ENABLED.store(false, Relaxed);
-                }
+            if ret == 0 {
+                return true;
             }
+
+            let err = std::io::Error::last_os_error();
+            log::error!(
+                "failed to punch hole in file: {:?}. disabling hole punching",
+                err
+            );
+            HOLE_PUNCHING_ENABLED.store(false, Relaxed);
         }
     }
+
+    false
 }
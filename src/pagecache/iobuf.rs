@@ -1,11 +1,15 @@
 use std::{
-    alloc::{alloc, dealloc, Layout},
     cell::UnsafeCell,
     sync::atomic::AtomicPtr,
+    time::{Duration, Instant},
 };
 
 use crate::{pagecache::*, *};
 
+// how long a buffer that can't be written because the storage
+// device is full is retried before the `Db` is stopped
+const NO_SPACE_TIMEOUT: Duration = Duration::from_secs(60);
+
 macro_rules! io_fail {
     ($self:expr, $e:expr) => {
         #[cfg(feature = "failpoints")]
@@ -27,24 +31,33 @@ macro_rules! io_fail {
     };
 }
 
-struct AlignedBuf(*mut u8, usize);
+// the length of the buffer is counted against the memory of the
+// `Db` until it's dropped, see `Config::memory_limit`, after which
+// its memory is given back to the `BufferPool`
+struct AlignedBuf(
+    *mut u8,
+    usize,
+    #[allow(dead_code)] memory::Charge,
+    Arc<BufferPool>,
+);
 
 impl AlignedBuf {
-    fn new(len: usize) -> AlignedBuf {
-        let layout = Layout::from_size_align(len, 8192).unwrap();
-        let ptr = unsafe { alloc(layout) };
-
-        assert!(!ptr.is_null(), "failed to allocate critical IO buffer");
+    fn new(len: usize, config: &RunningConfig) -> AlignedBuf {
+        let ptr = config.buffers.take_segment(len);
 
-        AlignedBuf(ptr, len)
+        let charge = memory::Charge::new(
+            &config.memory,
+            memory::Kind::IoBuffers,
+            len as u64,
+        );
+        AlignedBuf(ptr, len, charge, config.buffers.clone())
     }
 }
 
 impl Drop for AlignedBuf {
     fn drop(&mut self) {
-        let layout = Layout::from_size_align(self.1, 8192).unwrap();
         unsafe {
-            dealloc(self.0, layout);
+            self.3.put_segment(self.0, self.1);
         }
     }
 }
@@ -311,10 +324,15 @@ pub(crate) struct IoBufs {
     pub segment_accountant: Mutex<SegmentAccountant>,
     pub segment_cleaner: SegmentCleaner,
     deferred_segment_ops: stack::Stack<SegmentOp>,
+    // serializes direct writes, which read back and write again
+    // the blocks that they share with the buffers around them
+    direct_mutex: Mutex<()>,
     #[cfg(feature = "io_uring")]
     pub submission_mutex: Mutex<()>,
+    // `None` when the kernel doesn't support io_uring, in which
+    // case the log is written and read with pwrite and pread
     #[cfg(feature = "io_uring")]
-    pub io_uring: rio::Rio,
+    pub io_uring: Option<rio::Rio>,
 }
 
 impl Drop for IoBufs {
@@ -390,7 +408,10 @@ impl IoBufs {
         let base = assert_usize(next_lid % segment_size as LogOffset);
 
         let mut iobuf = IoBuf {
-            buf: Arc::new(UnsafeCell::new(AlignedBuf::new(segment_size))),
+            buf: Arc::new(UnsafeCell::new(AlignedBuf::new(
+                segment_size,
+                &config,
+            ))),
             header: CachePadded::new(AtomicU64::new(0)),
             base,
             offset: next_lid,
@@ -404,6 +425,9 @@ impl IoBufs {
             iobuf.store_segment_header(0, next_lsn, stable);
         }
 
+        #[cfg(feature = "io_uring")]
+        let io_uring = start_io_uring(&config);
+
         Ok(IoBufs {
             config,
 
@@ -418,10 +442,11 @@ impl IoBufs {
             segment_accountant: Mutex::new(segment_accountant),
             segment_cleaner,
             deferred_segment_ops: stack::Stack::default(),
+            direct_mutex: Mutex::new(()),
             #[cfg(feature = "io_uring")]
             submission_mutex: Mutex::new(()),
             #[cfg(feature = "io_uring")]
-            io_uring: rio::new()?,
+            io_uring,
         })
     }
 
@@ -584,13 +609,12 @@ impl IoBufs {
         if let Some(heap_reservation) = heap_reservation {
             // write blob to file
             io_fail!(self, "blob blob write");
-            let mut heap_buf = vec![
-                0;
+            let mut heap_buf = self.config.buffers.take(
                 usize::try_from(super::heap::slab_size(
-                    13 + item.serialized_size()
+                    13 + item.serialized_size(),
                 ))
-                .unwrap()
-            ];
+                .unwrap(),
+            );
 
             #[cfg(feature = "metrics")]
             let serialization_timer = Measure::new(&M.serialize);
@@ -603,7 +627,7 @@ impl IoBufs {
             #[cfg(feature = "metrics")]
             drop(serialization_timer);
 
-            let mut hasher = crc32fast::Hasher::new();
+            let mut hasher = self.config.checksum.hasher();
             hasher.update(&heap_buf[0..1]);
             hasher.update(&heap_buf[5..]);
             let crc = hasher.finalize().to_le_bytes();
@@ -615,7 +639,9 @@ impl IoBufs {
             heap_reservation.heap_id.serialize_into(out_buf_ref);
 
             // write the blob file
+            let io_barrier = self.config.io_barrier.read();
             heap_reservation.complete(&heap_buf)?;
+            drop(io_barrier);
         } else {
             #[cfg(feature = "metrics")]
             let _ = Measure::new(&M.serialize);
@@ -644,6 +670,12 @@ impl IoBufs {
         let log_offset = iobuf.offset;
         let base_lsn = iobuf.lsn;
         let capacity = iobuf.capacity;
+        tracing_span!(
+            "log.write",
+            lsn = base_lsn,
+            lid = log_offset,
+            len = header::offset(header),
+        );
 
         let segment_size = self.config.segment_size;
 
@@ -673,11 +705,19 @@ impl IoBufs {
         let unused_space = capacity - bytes_to_write;
         let should_pad = maxed && unused_space >= MAX_MSG_HEADER_LEN;
 
+        // the remainder of a maxed buffer's red zone, which is
+        // written right after the buffer rather than copied into
+        // it when the platform supports vectored writes
+        let mut tail = if maxed {
+            vec![MessageKind::Corrupted.into(); unused_space]
+        } else {
+            vec![]
+        };
+
         // a pad is a null message written to the end of a buffer
         // to signify that nothing else will be written into it
         if should_pad {
             let pad_len = unused_space - MAX_MSG_HEADER_LEN;
-            let data = iobuf.get_mut_range(bytes_to_write, unused_space);
 
             let segment_number = SegmentNumber(
                 u64::try_from(base_lsn).unwrap()
@@ -695,153 +735,68 @@ impl IoBufs {
             trace!("writing segment cap {:?}", header);
 
             let header_bytes = header.serialize();
+            let header_len = header_bytes.len();
+            tail[..header_len].copy_from_slice(&header_bytes);
 
-            // initialize the remainder of this buffer (only pad_len of this
-            // will be part of the Cap message)
-            let padding_bytes = vec![
-                MessageKind::Corrupted.into();
-                unused_space - header_bytes.len()
-            ];
-
-            #[allow(unsafe_code)]
-            unsafe {
-                std::ptr::copy_nonoverlapping(
-                    header_bytes.as_ptr(),
-                    data.as_mut_ptr(),
-                    header_bytes.len(),
-                );
-                std::ptr::copy_nonoverlapping(
-                    padding_bytes.as_ptr(),
-                    data.as_mut_ptr().add(header_bytes.len()),
-                    padding_bytes.len(),
-                );
-            }
-
-            // this as to stay aligned with the hashing
+            // this as to stay aligned with the hashing (only pad_len
+            // of the tail is part of the Cap message)
             let crc32_arr = u32_to_arr(calculate_message_crc32(
+                self.config.checksum,
                 &header_bytes,
-                &padding_bytes[..pad_len],
+                &tail[header_len..header_len + pad_len],
             ));
 
-            #[allow(unsafe_code)]
-            unsafe {
-                std::ptr::copy_nonoverlapping(
-                    crc32_arr.as_ptr(),
-                    // the crc32 is the first part of the buffer
-                    data.as_mut_ptr(),
-                    std::mem::size_of::<u32>(),
-                );
-            }
-        } else if maxed {
-            // initialize the remainder of this buffer's red zone
-            let data = iobuf.get_mut_range(bytes_to_write, unused_space);
-
-            #[allow(unsafe_code)]
-            unsafe {
-                // note: this could use slice::fill() if it stabilizes
-                std::ptr::write_bytes(
-                    data.as_mut_ptr(),
-                    MessageKind::Corrupted.into(),
-                    unused_space,
-                );
-            }
+            // the crc32 is the first part of the buffer
+            tail[..crc32_arr.len()].copy_from_slice(&crc32_arr);
         }
 
         let total_len = if maxed { capacity } else { bytes_to_write };
 
-        let data = iobuf.get_mut_range(0, total_len);
+        // io_uring and direct writes take a single buffer, so the
+        // tail is copied into the end of the iobuf for them
+        let data: &[u8] = if self.writes_vectored() || tail.is_empty() {
+            iobuf.get_mut_range(0, bytes_to_write)
+        } else {
+            iobuf
+                .get_mut_range(bytes_to_write, unused_space)
+                .copy_from_slice(&tail);
+            tail.clear();
+            iobuf.get_mut_range(0, total_len)
+        };
+        let bufs: &[&[u8]] =
+            if tail.is_empty() { &[data] } else { &[data, &tail] };
         let stored_max_stable_lsn = iobuf.stored_max_stable_lsn;
 
         io_fail!(self, "buffer write");
-        #[cfg(feature = "io_uring")]
-        {
-            let mut wrote = 0;
-            while wrote < total_len {
-                let to_write = &data[wrote..];
-                let offset = log_offset + wrote as u64;
-
-                // we take out this mutex to guarantee
-                // that our `Link` write operation below
-                // is serialized with the following sync.
-                // we don't put the `Rio` instance into
-                // the `Mutex` because we want to drop the
-                // `Mutex` right after beginning the async
-                // submission.
-                let link_mu = self.submission_mutex.lock();
-
-                // using the `Link` ordering, we specify
-                // that `io_uring` should not begin
-                // the following `sync_file_range`
-                // until the previous write is
-                // complete.
-                let wrote_completion = self.io_uring.write_at_ordered(
-                    &*self.config.file,
-                    &to_write,
-                    offset,
-                    rio::Ordering::Link,
-                );
-
-                let sync_completion = if iobuf.from_tip {
-                    self.io_uring.fsync(&*self.config.file)
-                } else {
-                    self.io_uring.sync_file_range(
-                        &*self.config.file,
-                        offset,
-                        to_write.len(),
-                    )
-                };
-
-                sync_completion.wait()?;
-
-                // TODO we want to move this above the previous `wait`
-                // but there seems to be an issue in `rio` that is
-                // triggered when multiple threads are submitting
-                // events while events from other threads are in play.
-                drop(link_mu);
-
-                wrote += wrote_completion.wait()?;
-            }
-        }
-        #[cfg(not(feature = "io_uring"))]
-        {
-            let f = &self.config.file;
-            pwrite_all(f, data, log_offset)?;
-            if !self.config.temporary {
-                if iobuf.from_tip {
-                    f.sync_all()?;
-                } else if cfg!(not(target_os = "linux")) {
-                    f.sync_data()?;
-                } else {
-                    #[allow(clippy::assertions_on_constants)]
-                    {
-                        assert!(cfg!(target_os = "linux"));
-                    }
 
-                    #[cfg(target_os = "linux")]
-                    {
-                        use std::os::unix::io::AsRawFd;
-                        let ret = unsafe {
-                            libc::sync_file_range(
-                                f.as_raw_fd(),
-                                i64::try_from(log_offset).unwrap(),
-                                i64::try_from(total_len).unwrap(),
-                                libc::SYNC_FILE_RANGE_WAIT_BEFORE
-                                    | libc::SYNC_FILE_RANGE_WRITE
-                                    | libc::SYNC_FILE_RANGE_WAIT_AFTER,
-                            )
-                        };
-                        if ret < 0 {
-                            let err = std::io::Error::last_os_error();
-                            if let Some(libc::ENOSYS) = err.raw_os_error() {
-                                f.sync_all()?;
-                            } else {
-                                return Err(err.into());
-                            }
-                        }
-                    }
+        // a full device leaves the buffer unwritten and is retried
+        // until space is freed, refusing new writes meanwhile, so
+        // that nothing that was already written to the buffer is
+        // lost. if no space is freed in time the error is returned,
+        // which stops the `Db`. the barrier is held while the
+        // buffer is written and synced so that `Db::checkpoint`
+        // never copies a half-written region
+        let deadline = Instant::now() + NO_SPACE_TIMEOUT;
+        let mut backoff_ms = 1;
+        loop {
+            let io_barrier = self.config.io_barrier.read();
+            let res = self.write_and_sync(&iobuf, bufs, log_offset);
+            drop(io_barrier);
+
+            match res {
+                Err(Error::NoSpace) if Instant::now() < deadline => {
+                    self.wait_for_space(backoff_ms);
+                    backoff_ms = std::cmp::min(backoff_ms * 2, 1000);
+                }
+                other => {
+                    other?;
+                    break;
                 }
             }
         }
+        if self.config.no_space.swap(false, SeqCst) {
+            warn!("space was freed on the storage device, resuming writes");
+        }
 
         // get rid of the iobuf as quickly as possible because
         // it is a huge allocation
@@ -899,6 +854,172 @@ impl IoBufs {
     // above an offset that corresponds to a buffer that hasn't actually
     // been written yet! It's OK to use a mutex here because it is pretty
     // fast, compared to the other operations on shared state.
+    fn write_and_sync(
+        &self,
+        iobuf: &IoBuf,
+        bufs: &[&[u8]],
+        log_offset: LogOffset,
+    ) -> Result<()> {
+        #[cfg(feature = "io_uring")]
+        {
+            if let Some(ref io_uring) = self.io_uring {
+                assert_eq!(bufs.len(), 1);
+                return self.uring_write_and_sync(
+                    io_uring, iobuf, bufs[0], log_offset,
+                );
+            }
+        }
+
+        let f = &self.config.file;
+        if let Some(ref direct_file) = self.config.direct_file {
+            assert_eq!(bufs.len(), 1);
+            let _serialized = self.direct_mutex.lock();
+            direct_io::pwrite(direct_file, bufs[0], log_offset)
+                .map_err(write_error)?;
+        } else {
+            pwritev_all(f, bufs, log_offset).map_err(write_error)?;
+        }
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if !self.config.temporary {
+            if iobuf.from_tip {
+                f.sync_all().map_err(write_error)?;
+            } else if cfg!(not(target_os = "linux")) {
+                f.sync_data().map_err(write_error)?;
+            } else {
+                #[allow(clippy::assertions_on_constants)]
+                {
+                    assert!(cfg!(target_os = "linux"));
+                }
+
+                #[cfg(target_os = "linux")]
+                {
+                    use std::os::unix::io::AsRawFd;
+                    let ret = unsafe {
+                        libc::sync_file_range(
+                            f.as_raw_fd(),
+                            i64::try_from(log_offset).unwrap(),
+                            i64::try_from(len).unwrap(),
+                            libc::SYNC_FILE_RANGE_WAIT_BEFORE
+                                | libc::SYNC_FILE_RANGE_WRITE
+                                | libc::SYNC_FILE_RANGE_WAIT_AFTER,
+                        )
+                    };
+                    if ret < 0 {
+                        let err = std::io::Error::last_os_error();
+                        if let Some(libc::ENOSYS) = err.raw_os_error() {
+                            f.sync_all().map_err(write_error)?;
+                        } else {
+                            return Err(write_error(err));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // whether a buffer can be written together with its tail
+    // by `write_and_sync`
+    fn writes_vectored(&self) -> bool {
+        #[cfg(feature = "io_uring")]
+        {
+            if self.io_uring.is_some() {
+                return false;
+            }
+        }
+        self.config.direct_file.is_none()
+    }
+
+    // submits the write and the sync that follows it together,
+    // so that the sync starts as soon as the write completes
+    #[cfg(feature = "io_uring")]
+    fn uring_write_and_sync(
+        &self,
+        io_uring: &rio::Rio,
+        iobuf: &IoBuf,
+        data: &[u8],
+        log_offset: LogOffset,
+    ) -> Result<()> {
+        let f = &*self.config.file;
+        let mut wrote = 0;
+        while wrote < data.len() {
+            let to_write = &data[wrote..];
+            let offset = log_offset + wrote as u64;
+
+            if self.config.temporary {
+                wrote += io_uring
+                    .write_at(f, &to_write, offset)
+                    .wait()
+                    .map_err(write_error)?;
+                continue;
+            }
+
+            // we take out this mutex to guarantee
+            // that our `Link` write operation below
+            // is serialized with the following sync.
+            // we don't put the `Rio` instance into
+            // the `Mutex` because we want to drop the
+            // `Mutex` right after beginning the async
+            // submission.
+            let link_mu = self.submission_mutex.lock();
+
+            // using the `Link` ordering, we specify
+            // that `io_uring` should not begin
+            // the following `sync_file_range`
+            // until the previous write is
+            // complete.
+            let wrote_completion = io_uring.write_at_ordered(
+                f,
+                &to_write,
+                offset,
+                rio::Ordering::Link,
+            );
+
+            let sync_completion = if iobuf.from_tip {
+                io_uring.fsync(f)
+            } else {
+                io_uring.sync_file_range(f, offset, to_write.len())
+            };
+
+            let synced = sync_completion.wait();
+
+            // TODO we want to move this above the previous `wait`
+            // but there seems to be an issue in `rio` that is
+            // triggered when multiple threads are submitting
+            // events while events from other threads are in play.
+            drop(link_mu);
+
+            // the sync is cancelled when the write fails, so the
+            // error of the write is the one to return
+            wrote += wrote_completion.wait().map_err(write_error)?;
+            synced.map_err(write_error)?;
+        }
+        Ok(())
+    }
+
+    // records that the device is full, waking up the threads that
+    // wait for the buffer so that they return `Error::NoSpace`,
+    // and sleeps before the write is retried
+    fn wait_for_space(&self, backoff_ms: u64) {
+        if !self.config.no_space.swap(true, SeqCst) {
+            error!(
+                "the storage device is full, refusing writes \
+                 until space is freed"
+            );
+            self.config.report_fault(None, &Error::NoSpace, false);
+        }
+
+        let intervals = self.intervals.lock();
+
+        // having held the mutex makes this linearized
+        // with the notify below.
+        drop(intervals);
+
+        let _notified = self.interval_updated.notify_all();
+
+        std::thread::sleep(Duration::from_millis(backoff_ms));
+    }
+
     fn mark_interval(&self, whence: Lsn, len: usize) {
         debug!("mark_interval({}, {})", whence, len);
         assert!(
@@ -945,6 +1066,27 @@ impl IoBufs {
     }
 }
 
+// io_uring first appeared in linux 5.1, and may also be disabled
+// by the kernel or the sandbox that sled runs in
+#[cfg(feature = "io_uring")]
+fn start_io_uring(config: &RunningConfig) -> Option<rio::Rio> {
+    if config.direct_file.is_some() {
+        // direct writes are made of whole blocks, see `direct_io`
+        return None;
+    }
+    match rio::new() {
+        Ok(io_uring) => Some(io_uring),
+        Err(e) => {
+            warn!(
+                "io_uring is unavailable, falling back to \
+                 pread and pwrite for the log: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
 pub(crate) fn roll_iobuf(iobufs: &Arc<IoBufs>) -> Result<usize> {
     let iobuf = iobufs.current_iobuf();
     let header = iobuf.get_header();
@@ -996,6 +1138,7 @@ pub(in crate::pagecache) fn make_stable_inner(
 ) -> Result<usize> {
     #[cfg(feature = "metrics")]
     let _measure = Measure::new(&M.make_stable);
+    tracing_span!("log.make_stable", lsn = lsn, partial = partial_durability);
 
     // NB before we write the 0th byte of the file, stable  is -1
     let first_stable = iobufs.stable();
@@ -1005,8 +1148,21 @@ pub(in crate::pagecache) fn make_stable_inner(
 
     let mut stable = first_stable;
 
+    // a flush gives other writers until the deadline to join the
+    // buffer that it writes, see `Config::group_commit_latency_us`
+    let deadline = match iobufs.config.group_commit_latency_us {
+        Some(us) if !partial_durability => {
+            Some(Instant::now() + Duration::from_micros(us))
+        }
+        _ => None,
+    };
+
     while stable < lsn {
-        if let Err(e) = iobufs.config.global_error() {
+        // a buffer that waits for space on the storage device is
+        // treated like an error, except that it may still be
+        // written later
+        let space = iobufs.config.check_space();
+        if let Err(e) = iobufs.config.global_error().and(space) {
             let intervals = iobufs.intervals.lock();
 
             // having held the mutex makes this linearized
@@ -1026,6 +1182,18 @@ pub(in crate::pagecache) fn make_stable_inner(
             // nothing to write, don't bother sealing
             // current IO buffer.
         } else {
+            if let Some(until) = deadline {
+                let now = Instant::now();
+                if now < until {
+                    let nap = std::cmp::min(
+                        until - now,
+                        Duration::from_micros(100),
+                    );
+                    std::thread::sleep(nap);
+                    stable = iobufs.stable();
+                    continue;
+                }
+            }
             maybe_seal_and_write_iobuf(iobufs, &iobuf, header, false)?;
             stable = iobufs.stable();
             // NB we have to continue here to possibly clear
@@ -1039,7 +1207,8 @@ pub(in crate::pagecache) fn make_stable_inner(
         let mut intervals = iobufs.intervals.lock();
 
         // check global error again now that we are holding a mutex
-        if let Err(e) = iobufs.config.global_error() {
+        let space_now = iobufs.config.check_space();
+        if let Err(e) = iobufs.config.global_error().and(space_now) {
             // having held the mutex makes this linearized
             // with the notify below.
             drop(intervals);
@@ -1068,7 +1237,7 @@ pub(in crate::pagecache) fn make_stable_inner(
             if cfg!(feature = "event_log") {
                 let timeout = iobufs.interval_updated.wait_for(
                     &mut intervals,
-                    std::time::Duration::from_secs(30),
+                    Duration::from_secs(30),
                 );
                 if timeout.timed_out() {
                     fn tn() -> String {
@@ -1215,7 +1384,10 @@ pub(in crate::pagecache) fn maybe_seal_and_write_iobuf(
     // its entire life cycle as soon as we do that.
     let next_iobuf = if maxed {
         let mut next_iobuf = IoBuf {
-            buf: Arc::new(UnsafeCell::new(AlignedBuf::new(segment_size))),
+            buf: Arc::new(UnsafeCell::new(AlignedBuf::new(
+                segment_size,
+                &iobufs.config,
+            ))),
             header: CachePadded::new(AtomicU64::new(0)),
             base: 0,
             offset: next_offset,
@@ -1289,6 +1461,23 @@ pub(in crate::pagecache) fn maybe_seal_and_write_iobuf(
     }
 }
 
+// the error of a write to the log, which is `Error::NoSpace` when
+// the storage device is full so that the write is retried
+fn write_error(io_error: std::io::Error) -> Error {
+    #[cfg(unix)]
+    let codes = [libc::ENOSPC];
+    // ERROR_HANDLE_DISK_FULL and ERROR_DISK_FULL
+    #[cfg(windows)]
+    let codes = [39, 112];
+    #[cfg(not(any(unix, windows)))]
+    let codes: [i32; 0] = [];
+
+    match io_error.raw_os_error() {
+        Some(code) if codes.contains(&code) => Error::NoSpace,
+        _ => Error::Io(io_error),
+    }
+}
+
 impl Debug for IoBufs {
     fn fmt(
         &self,
@@ -1314,3 +1503,42 @@ impl Debug for IoBuf {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn no_space_refuses_writes_until_cleared() -> Result<()> {
+        let db = Config::new().temporary(true).flush_every_ms(None).open()?;
+        let _ = db.insert(b"a", b"1")?;
+
+        db.context.no_space.store(true, SeqCst);
+        assert_eq!(db.insert(b"b", b"2"), Err(Error::NoSpace));
+        assert_eq!(db.flush(), Err(Error::NoSpace));
+        assert_eq!(db.get(b"a")?, Some(IVec::from(b"1")));
+
+        db.context.no_space.store(false, SeqCst);
+        let _ = db.insert(b"b", b"2")?;
+        let _ = db.flush()?;
+        assert_eq!(db.get(b"b")?, Some(IVec::from(b"2")));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn only_log_writes_map_a_full_device_to_no_space() {
+        use std::io;
+
+        let full = || io::Error::from_raw_os_error(libc::ENOSPC);
+        assert_eq!(super::write_error(full()), Error::NoSpace);
+        match super::write_error(io::Error::from_raw_os_error(libc::EIO)) {
+            Error::Io(_) => {}
+            unexpected => panic!("expected Io, got {:?}", unexpected),
+        }
+        match Error::from(full()) {
+            Error::Io(_) => {}
+            unexpected => panic!("expected Io, got {:?}", unexpected),
+        }
+    }
+}
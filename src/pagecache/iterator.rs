@@ -1,9 +1,9 @@
 use std::{collections::BTreeMap, io};
 
 use super::{
-    pread_exact_or_eof, read_message, read_segment_header, BasedBuf, DiskPtr,
-    LogKind, LogOffset, LogRead, Lsn, SegmentHeader, SegmentNumber,
-    MAX_MSG_HEADER_LEN, SEG_HEADER_LEN,
+    logger::ReadAt, pread_exact_or_eof, read_message, read_segment_header,
+    BasedBuf, DirectFile, DiskPtr, LogKind, LogOffset, LogRead, Lsn,
+    SegmentHeader, SegmentNumber, MAX_MSG_HEADER_LEN, SEG_HEADER_LEN,
 };
 use crate::*;
 
@@ -251,7 +251,11 @@ impl LogIter {
         trace!("read segment header {:?}", segment_header);
 
         let mut buf = vec![0; self.config.segment_size];
-        let size = pread_exact_or_eof(f, &mut buf, offset)?;
+        let size = if let Some(ref direct_file) = self.config.direct_file {
+            DirectFile(direct_file).pread_exact_or_eof(&mut buf, offset)?
+        } else {
+            pread_exact_or_eof(f, &mut buf, offset)?
+        };
 
         trace!("setting stored segment buffer length to {} after read", size);
         buf.truncate(size);
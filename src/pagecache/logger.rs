@@ -1,11 +1,12 @@
 use std::fs::File;
 
 use super::{
-    arr_to_lsn, arr_to_u32, assert_usize, bump_atomic_lsn, decompress, header,
-    iobuf, lsn_to_arr, pread_exact, pread_exact_or_eof, roll_iobuf, u32_to_arr,
-    Arc, BasedBuf, DiskPtr, HeapId, IoBuf, IoBufs, LogKind, LogOffset, Lsn,
-    MessageKind, Reservation, Serialize, Snapshot, BATCH_MANIFEST_PID,
-    COUNTER_PID, MAX_MSG_HEADER_LEN, META_PID, SEG_HEADER_LEN,
+    arr_to_lsn, arr_to_u32, assert_usize, bump_atomic_lsn, decode_message,
+    header, iobuf, lsn_to_arr, pread_exact, pread_exact_or_eof, roll_iobuf,
+    u32_to_arr, Arc, BasedBuf, DirectFile, DiskPtr, Encoded, HeapId, IoBuf,
+    IoBufs, LogKind, LogOffset, Lsn, MessageKind, MmapFile, Reservation,
+    Serialize, Snapshot, BATCH_MANIFEST_PID, COUNTER_PID, MAX_MSG_HEADER_LEN,
+    META_PID, SEG_HEADER_LEN,
 };
 
 use crate::*;
@@ -33,6 +34,9 @@ impl Log {
     /// Flushes any pending IO buffers to disk to ensure durability.
     /// Returns the number of bytes written during this call.
     pub fn flush(&self) -> Result<usize> {
+        if self.config.read_only {
+            return Ok(0);
+        }
         iobuf::flush(&self.iobufs)
     }
 
@@ -58,31 +62,79 @@ impl Log {
         iobuf::make_durable(&self.iobufs, lsn)?;
 
         if ptr.is_inline() {
-            let f = &self.config.file;
-            read_message(
-                &**f,
-                ptr.lid().unwrap(),
-                expected_segment_number,
-                &self.config,
-            )
+            let read =
+                self.read_inline(ptr.lid().unwrap(), expected_segment_number)?;
+            Ok(match read {
+                LogRead::Inline(header, buf, inline_len) => {
+                    let buf = decode_message(&self.config, header.kind, buf)?;
+                    LogRead::Inline(header, buf, inline_len)
+                }
+                LogRead::Heap(header, buf, heap_id, inline_len) => {
+                    let buf = decode_message(&self.config, header.kind, buf)?;
+                    LogRead::Heap(header, buf, heap_id, inline_len)
+                }
+                other => other,
+            })
         } else {
             // we short-circuit the inline read
             // here because it might not still
             // exist in the inline log.
             let heap_id = ptr.heap_id().unwrap();
-            self.config.heap.read(heap_id, self.config.use_compression).map(
-                |(kind, buf)| {
-                    let header = MessageHeader {
-                        kind,
-                        pid,
-                        segment_number: expected_segment_number,
-                        crc32: 0,
-                        len: 0,
-                    };
-                    LogRead::Heap(header, buf, heap_id, 0)
-                },
-            )
+            let (kind, buf) = self.config.heap.read(heap_id)?;
+            let header = MessageHeader {
+                kind,
+                pid,
+                segment_number: expected_segment_number,
+                crc32: 0,
+                len: 0,
+            };
+            let buf = decode_message(&self.config, kind, buf)?;
+            Ok(LogRead::Heap(header, buf, heap_id, 0))
+        }
+    }
+
+    // reads a message from the log file without the page cache
+    // when `direct_io` is set, from a map of its segment when
+    // `mmap_reads` is set, or through io_uring when the kernel
+    // supports it
+    fn read_inline(
+        &self,
+        lid: LogOffset,
+        expected_segment_number: SegmentNumber,
+    ) -> Result<LogRead> {
+        let f = &*self.config.file;
+
+        if let Some(ref direct_file) = self.config.direct_file {
+            return read_message(
+                &DirectFile(direct_file),
+                lid,
+                expected_segment_number,
+                &self.config,
+            );
+        }
+        if let Some(ref mmaps) = self.config.mmaps {
+            return read_message(
+                &MmapFile { mmaps, file: f },
+                lid,
+                expected_segment_number,
+                &self.config,
+            );
+        }
+
+        #[cfg(feature = "io_uring")]
+        {
+            if let Some(ref io_uring) = self.iobufs.io_uring {
+                let file = UringFile { io_uring, file: f };
+                return read_message(
+                    &file,
+                    lid,
+                    expected_segment_number,
+                    &self.config,
+                );
+            }
         }
+
+        read_message(f, lid, expected_segment_number, &self.config)
     }
 
     /// returns the current stable offset written to disk
@@ -131,29 +183,96 @@ impl Log {
         item: &T,
         guard: &Guard,
     ) -> Result<Reservation<'_>> {
-        #[cfg(feature = "compression")]
-        {
-            if self.config.use_compression && pid != BATCH_MANIFEST_PID {
-                use zstd::block::compress;
+        self.reserve_with(log_kind, pid, item, None, 0, guard)
+    }
 
-                let buf = item.serialize();
+    /// Like `reserve`, but compresses the item with the given
+    /// `Codec` instead of the one configured for the `Db`, using
+    /// the latest dictionary trained for the `dictionary` slot.
+    pub(crate) fn reserve_with<T: Serialize + Debug>(
+        &self,
+        log_kind: LogKind,
+        pid: PageId,
+        item: &T,
+        codec: Option<Codec>,
+        dictionary: u32,
+        guard: &Guard,
+    ) -> Result<Reservation<'_>> {
+        let encryption = if pid == BATCH_MANIFEST_PID {
+            None
+        } else {
+            self.config.encryption.as_ref()
+        };
+
+        if let Some(codec) = codec {
+            let dictionary = self.config.dictionaries.current(dictionary);
+            let buffers = &self.config.buffers;
+            let encoded = Encoded::new(
+                codec,
+                dictionary.as_deref(),
+                &buffers.serialize(item),
+                buffers,
+            )?;
 
-                #[cfg(feature = "metrics")]
-                let _measure = Measure::new(&M.compress);
+            if let Some(encryption) = encryption {
+                let sealed = encryption.encrypt(&buffers.serialize(&encoded))?;
+                return self.reserve_inner(
+                    log_kind,
+                    pid,
+                    &IVec::from(sealed),
+                    None,
+                    guard,
+                );
+            }
 
-                let compressed_buf =
-                    compress(&buf, self.config.compression_factor).unwrap();
+            return self.reserve_inner(log_kind, pid, &encoded, None, guard);
+        }
+
+        #[cfg(feature = "compression")]
+        {
+            if self.config.use_compression && pid != BATCH_MANIFEST_PID {
+                let buffers = &self.config.buffers;
+                let buf = buffers.serialize(item);
+
+                let compressed_buf = IVec::from(&*buffers.compress(
+                    &buf,
+                    self.config.compression_factor,
+                )?);
+
+                if let Some(encryption) = encryption {
+                    let sealed = encryption
+                        .encrypt(&buffers.serialize(&compressed_buf))?;
+                    return self.reserve_inner(
+                        log_kind,
+                        pid,
+                        &IVec::from(sealed),
+                        None,
+                        guard,
+                    );
+                }
 
                 return self.reserve_inner(
                     log_kind,
                     pid,
-                    &IVec::from(compressed_buf),
+                    &compressed_buf,
                     None,
                     guard,
                 );
             }
         }
 
+        if let Some(encryption) = encryption {
+            let sealed =
+                encryption.encrypt(&self.config.buffers.serialize(item))?;
+            return self.reserve_inner(
+                log_kind,
+                pid,
+                &IVec::from(sealed),
+                None,
+                guard,
+            );
+        }
+
         self.reserve_inner(log_kind, pid, item, None, guard)
     }
 
@@ -168,6 +287,13 @@ impl Log {
         #[cfg(feature = "metrics")]
         let _measure = Measure::new(&M.reserve_lat);
 
+        if self.config.read_only {
+            return Err(Error::Unsupported(
+                "the database was opened in read-only mode".into(),
+            ));
+        }
+        self.config.check_space()?;
+
         let serialized_len = item.serialized_size();
         let max_buf_len =
             u64::try_from(MAX_MSG_HEADER_LEN).unwrap() + serialized_len;
@@ -349,10 +475,13 @@ impl Log {
             let bumped_offset = header::bump_offset(header, inline_buf_len);
 
             // check for maxed out IO buffer writers
-            if header::n_writers(bumped_offset) == header::MAX_WRITERS {
+            let max_writers =
+                u64::try_from(self.config.max_concurrent_reservations)
+                    .unwrap();
+            if header::n_writers(bumped_offset) >= max_writers {
                 trace_once!(
                     "spinning because our buffer has {} writers already",
-                    header::MAX_WRITERS
+                    max_writers
                 );
                 backoff.spin();
                 continue;
@@ -376,6 +505,19 @@ impl Log {
             // should never have claimed a sealed buffer
             assert!(!header::is_sealed(claimed));
 
+            // the reservation that fills a batch seals the buffer,
+            // which is written once every reservation in it is done
+            if let Some(batch_bytes) = self.config.group_commit_bytes {
+                if header::offset(claimed) >= batch_bytes {
+                    iobuf::maybe_seal_and_write_iobuf(
+                        &self.iobufs,
+                        &iobuf,
+                        claimed,
+                        false,
+                    )?;
+                }
+            }
+
             // MAX is used to signify unreadiness of
             // the underlying IO buffer, and if it's
             // still set here, the buffer counters
@@ -403,6 +545,20 @@ impl Log {
                 &self.iobufs.max_reserved_lsn,
                 reservation_lsn + inline_buf_len as Lsn - 1,
             );
+            tracing_event!(
+                "log.reserve",
+                pid = pid,
+                lsn = reservation_lsn,
+                lid = reservation_lid,
+                len = inline_buf_len,
+                kind = tracing::field::debug(kind),
+            );
+
+            db_metrics::record_log_write(
+                &self.config,
+                u64::try_from(inline_buf_len).unwrap(),
+                if over_heap_threshold { serialized_len + 13 } else { 0 },
+            );
 
             let (heap_reservation, heap_id) = if over_heap_threshold {
                 let heap_reservation = self
@@ -433,6 +589,8 @@ impl Log {
                 DiskPtr::new_inline(reservation_lid)
             };
 
+            super::reservation::hold();
+
             return Ok(Reservation {
                 iobuf,
                 log: self,
@@ -500,7 +658,8 @@ impl Log {
 impl Drop for Log {
     fn drop(&mut self) {
         // don't do any more IO if we're crashing
-        if self.config.global_error().is_err() {
+        // or were never allowed to write
+        if self.config.global_error().is_err() || self.config.read_only {
             return;
         }
 
@@ -745,7 +904,50 @@ impl ReadAt for BasedBuf {
     }
 }
 
-/// read a buffer from the disk
+/// A file that is read through `io_uring`.
+#[cfg(feature = "io_uring")]
+pub(crate) struct UringFile<'a> {
+    pub(crate) io_uring: &'a rio::Rio,
+    pub(crate) file: &'a File,
+}
+
+#[cfg(feature = "io_uring")]
+impl ReadAt for UringFile<'_> {
+    fn pread_exact(&self, dst: &mut [u8], at: u64) -> std::io::Result<()> {
+        let read = self.pread_exact_or_eof(dst, at)?;
+        if read < dst.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "failed to fill buffer",
+            ));
+        }
+        Ok(())
+    }
+
+    fn pread_exact_or_eof(
+        &self,
+        dst: &mut [u8],
+        at: u64,
+    ) -> std::io::Result<usize> {
+        let mut read = 0;
+        while read < dst.len() {
+            let to_read = &mut dst[read..];
+            let offset = at + read as u64;
+            match self.io_uring.read_at(self.file, &to_read, offset).wait() {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(read)
+    }
+}
+
+/// read a buffer from the disk. The contents of messages are
+/// returned as they were stored, without being decoded, so that
+/// the log can be scanned during recovery without needing to
+/// decrypt or decompress anything.
 pub(crate) fn read_message<R: ReadAt>(
     file: &R,
     lid: LogOffset,
@@ -815,6 +1017,7 @@ pub(crate) fn read_message<R: ReadAt>(
     }
 
     let crc32 = calculate_message_crc32(
+        config.checksum,
         msg_header_buf[..message_offset].as_ref(),
         &buf,
     );
@@ -856,7 +1059,7 @@ pub(crate) fn read_message<R: ReadAt>(
             assert_eq!(buf.len(), 16);
             let heap_id = HeapId::deserialize(&mut &buf[..]).unwrap();
 
-            match config.heap.read(heap_id, config.use_compression) {
+            match config.heap.read(heap_id) {
                 Ok((kind, buf)) => {
                     assert_eq!(header.kind, kind);
                     trace!(
@@ -879,8 +1082,6 @@ pub(crate) fn read_message<R: ReadAt>(
         | MessageKind::Free
         | MessageKind::Counter => {
             trace!("read a successful inline message");
-            let buf =
-                if config.use_compression { decompress(buf) } else { buf };
 
             Ok(LogRead::Inline(header, buf, inline_len))
         }
@@ -895,3 +1096,36 @@ pub(crate) fn read_message<R: ReadAt>(
         ),
     }
 }
+
+#[cfg(all(test, feature = "io_uring"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uring_reads_match_pread() -> std::io::Result<()> {
+        let io_uring = if let Ok(io_uring) = rio::new() {
+            io_uring
+        } else {
+            return Ok(());
+        };
+        let path = "test_uring_reads_match_pread";
+        let data: Vec<u8> = (0..10_000_u32).map(|i| i as u8).collect();
+        std::fs::write(path, &data)?;
+        let file = File::open(path)?;
+        let uring_file = UringFile { io_uring: &io_uring, file: &file };
+
+        let mut pread_buf = vec![0; 4096];
+        let mut uring_buf = vec![0; 4096];
+        file.pread_exact(&mut pread_buf, 1000)?;
+        uring_file.pread_exact(&mut uring_buf, 1000)?;
+        assert_eq!(pread_buf, uring_buf);
+        assert_eq!(uring_buf[..], data[1000..5096]);
+
+        // reads past the end stop at it
+        assert_eq!(uring_file.pread_exact_or_eof(&mut uring_buf, 8000)?, 2000);
+        assert_eq!(uring_buf[..2000], data[8000..]);
+        assert!(uring_file.pread_exact(&mut uring_buf, 8000).is_err());
+
+        std::fs::remove_file(path)
+    }
+}
@@ -0,0 +1,183 @@
+//! Reads of the log from memory maps of its segments, see
+//! `Config::mmap_reads`.
+//!
+//! The log is mapped in chunks of at least one segment, each of
+//! which is mapped the first time that it is read from once the
+//! file is long enough to hold all of it. Reads from the end of
+//! the log, where the file may still be growing, and reads that
+//! cross the end of a chunk, go to the file instead. Accessing a
+//! map past the end of its file kills the process, so the maps
+//! past the new end of the log are dropped before it is
+//! truncated, and nothing else may shorten the file.
+use std::{convert::TryFrom, fmt, fs::File, io, ptr, slice};
+
+use super::{logger::ReadAt, pread_exact, pread_exact_or_eof, LogOffset};
+use crate::*;
+
+// a multiple of the page size of the platforms in use
+const MIN_CHUNK_SIZE: usize = 64 * 1024;
+
+// a read-only map of a chunk of the log
+struct Map(*mut u8, usize);
+
+#[allow(unsafe_code)]
+unsafe impl Send for Map {}
+
+#[allow(unsafe_code)]
+unsafe impl Sync for Map {}
+
+impl Map {
+    #[cfg(unix)]
+    fn new(file: &File, offset: LogOffset, len: usize) -> io::Result<Map> {
+        use std::os::unix::io::AsRawFd;
+
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                libc::off_t::try_from(offset).unwrap(),
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Map(ptr.cast(), len))
+        }
+    }
+
+    // `Config::validate` refuses `mmap_reads` on other platforms
+    #[cfg(not(unix))]
+    fn new(_: &File, _: LogOffset, _: usize) -> io::Result<Map> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "maps of the log are only supported on unix",
+        ))
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.0, self.1) }
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            let _ = libc::munmap(self.0.cast(), self.1);
+        }
+    }
+}
+
+/// The maps of the chunks of the log.
+pub(crate) struct Mmaps {
+    chunk_size: usize,
+    maps: RwLock<Vec<Option<Map>>>,
+}
+
+impl Debug for Mmaps {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> std::result::Result<(), fmt::Error> {
+        f.debug_struct("Mmaps")
+            .field("chunk_size", &self.chunk_size)
+            .field("mapped", &self.maps.read().iter().flatten().count())
+            .finish()
+    }
+}
+
+impl Mmaps {
+    pub(crate) fn new(segment_size: usize) -> Mmaps {
+        Mmaps {
+            chunk_size: std::cmp::max(segment_size, MIN_CHUNK_SIZE),
+            maps: RwLock::new(vec![]),
+        }
+    }
+
+    /// Copies `dst.len()` bytes at `at` from the map of their
+    /// chunk, mapping it first if needed. Returns `false`
+    /// without reading anything when the bytes can't be read
+    /// from a map.
+    fn read(&self, file: &File, dst: &mut [u8], at: LogOffset) -> bool {
+        let chunk_size = self.chunk_size as LogOffset;
+        let idx = usize::try_from(at / chunk_size).unwrap();
+        let start = usize::try_from(at % chunk_size).unwrap();
+        let end = start + dst.len();
+        if end > self.chunk_size {
+            return false;
+        }
+
+        if let Some(Some(map)) = self.maps.read().get(idx) {
+            dst.copy_from_slice(&map.as_slice()[start..end]);
+            return true;
+        }
+
+        let mut maps = self.maps.write();
+        if maps.len() <= idx {
+            maps.resize_with(idx + 1, || None);
+        }
+        if maps[idx].is_none() {
+            let base = idx as LogOffset * chunk_size;
+            match file.metadata() {
+                Ok(metadata) if metadata.len() >= base + chunk_size => {}
+                _ => return false,
+            }
+            match Map::new(file, base, self.chunk_size) {
+                Ok(map) => maps[idx] = Some(map),
+                Err(e) => {
+                    debug!("failed to map the log at {}: {}", base, e);
+                    return false;
+                }
+            }
+        }
+        let map = maps[idx].as_ref().unwrap();
+        dst.copy_from_slice(&map.as_slice()[start..end]);
+        true
+    }
+
+    /// Truncates the log file to `at` after dropping the maps
+    /// of the chunks that end past it, keeping new maps from
+    /// being made until it is done.
+    pub(crate) fn truncate(
+        &self,
+        file: &File,
+        at: LogOffset,
+    ) -> io::Result<()> {
+        let mut maps = self.maps.write();
+        let chunk_size = self.chunk_size as LogOffset;
+        let keep = usize::try_from(at / chunk_size).unwrap();
+        maps.truncate(keep);
+        file.set_len(at)
+    }
+}
+
+/// The log file, read from the maps of its chunks when it can be.
+pub(crate) struct MmapFile<'a> {
+    pub(crate) mmaps: &'a Mmaps,
+    pub(crate) file: &'a File,
+}
+
+impl ReadAt for MmapFile<'_> {
+    fn pread_exact(&self, dst: &mut [u8], at: u64) -> io::Result<()> {
+        if self.mmaps.read(self.file, dst, at) {
+            Ok(())
+        } else {
+            pread_exact(self.file, dst, at)
+        }
+    }
+
+    fn pread_exact_or_eof(
+        &self,
+        dst: &mut [u8],
+        at: u64,
+    ) -> io::Result<usize> {
+        if self.mmaps.read(self.file, dst, at) {
+            Ok(dst.len())
+        } else {
+            pread_exact_or_eof(self.file, dst, at)
+        }
+    }
+}
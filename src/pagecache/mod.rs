@@ -5,11 +5,17 @@
 pub mod constants;
 pub mod logger;
 
+mod buffer_pool;
+mod checkpoint;
+mod compressed_cache;
+mod dictionaries;
+pub(crate) mod direct_io;
 mod disk_pointer;
 mod header;
 mod heap;
 pub(crate) mod iobuf;
 mod iterator;
+mod mmap;
 mod pagetable;
 #[cfg(any(all(not(unix), not(windows)), miri))]
 mod parallel_io_polyfill;
@@ -17,7 +23,9 @@ mod parallel_io_polyfill;
 mod parallel_io_unix;
 #[cfg(all(windows, not(miri)))]
 mod parallel_io_windows;
+mod readers;
 mod reservation;
+mod secondary_cache;
 mod segment;
 mod snapshot;
 
@@ -26,15 +34,22 @@ use std::ops::Deref;
 use crate::*;
 
 #[cfg(any(all(not(unix), not(windows)), miri))]
-use parallel_io_polyfill::{pread_exact, pread_exact_or_eof, pwrite_all};
+pub(crate) use parallel_io_polyfill::{
+    pread_exact, pread_exact_or_eof, pwrite_all, pwritev_all,
+};
 
 #[cfg(all(unix, not(miri)))]
-use parallel_io_unix::{pread_exact, pread_exact_or_eof, pwrite_all};
+pub(crate) use parallel_io_unix::{
+    pread_exact, pread_exact_or_eof, pwrite_all, pwritev_all,
+};
 
 #[cfg(all(windows, not(miri)))]
-use parallel_io_windows::{pread_exact, pread_exact_or_eof, pwrite_all};
+pub(crate) use parallel_io_windows::{
+    pread_exact, pread_exact_or_eof, pwrite_all, pwritev_all,
+};
 
 use self::{
+    compressed_cache::CompressedCache,
     constants::{
         BATCH_MANIFEST_PID, COUNTER_PID, META_PID,
         PAGE_CONSOLIDATION_THRESHOLD, SEGMENT_CLEANUP_THRESHOLD,
@@ -43,16 +58,23 @@ use self::{
     iobuf::{roll_iobuf, IoBuf, IoBufs},
     iterator::{raw_segment_iter_from, LogIter},
     pagetable::PageTable,
+    secondary_cache::SecondaryCache,
     segment::{SegmentAccountant, SegmentCleaner, SegmentOp},
 };
 
 pub(crate) use self::{
-    heap::{Heap, HeapId},
+    buffer_pool::BufferPool,
+    checkpoint::checkpoint,
+    dictionaries::{Dictionaries, Dictionary},
+    direct_io::DirectFile,
+    mmap::{MmapFile, Mmaps},
+    heap::{punch_hole, Heap, HeapId},
+    readers::Readers,
     logger::{
         read_message, read_segment_header, MessageHeader, SegmentHeader,
         SegmentNumber,
     },
-    reservation::Reservation,
+    reservation::{durably, Reservation},
     snapshot::{read_snapshot_or_default, PageState, Snapshot},
 };
 
@@ -276,6 +298,129 @@ pub(crate) fn decompress(in_buf: Vec<u8>) -> Vec<u8> {
     in_buf
 }
 
+/// Reverses the encryption and compression that were applied
+/// to a message by `Log::reserve` before it was written.
+pub(crate) fn decode_message(
+    config: &RunningConfig,
+    kind: MessageKind,
+    buf: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let buf = if let Some(encryption) = &config.encryption {
+        let sealed = IVec::deserialize(&mut &*buf)?;
+        encryption.decrypt(&sealed)?
+    } else {
+        buf
+    };
+
+    let is_page = match kind {
+        MessageKind::InlineNode
+        | MessageKind::HeapNode
+        | MessageKind::InlineLink
+        | MessageKind::HeapLink => true,
+        _ => false,
+    };
+
+    if is_page && buf.first() == Some(&Encoded::TAG) {
+        return Encoded::deserialize(&mut &*buf)?.decode(&config.dictionaries);
+    }
+
+    Ok(if config.use_compression { decompress(buf) } else { buf })
+}
+
+/// A page that was encoded with the `Codec` chosen for its tree,
+/// written as `Encoded::TAG` ++ codec id ++ len ++ data. No other
+/// encoding of a `Node` or `Link` can begin with `Encoded::TAG`,
+/// because it begins with either a varint length or a `Link`
+/// discriminant. When a trained dictionary is used, the data
+/// begins with its little-endian slot and version.
+#[derive(Debug)]
+pub(crate) struct Encoded {
+    pub(crate) codec_id: u8,
+    pub(crate) data: IVec,
+}
+
+impl Encoded {
+    pub(crate) const TAG: u8 = 255;
+
+    const NONE: u8 = 1;
+    const ZSTD: u8 = 2;
+    const ZSTD_DICTIONARY: u8 = 3;
+
+    #[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+    pub(crate) fn new(
+        codec: Codec,
+        dictionary: Option<&Dictionary>,
+        buf: &[u8],
+        buffers: &BufferPool,
+    ) -> Result<Encoded> {
+        match (codec, dictionary) {
+            (Codec::None, _) => {
+                Ok(Encoded { codec_id: Self::NONE, data: buf.into() })
+            }
+            #[cfg(feature = "compression")]
+            (Codec::Zstd(level), None) => {
+                let compressed = buffers.compress(buf, level)?;
+                Ok(Encoded {
+                    codec_id: Self::ZSTD,
+                    data: IVec::from(&*compressed),
+                })
+            }
+            #[cfg(feature = "compression")]
+            (Codec::Zstd(level), Some(dictionary)) => {
+                #[cfg(feature = "metrics")]
+                let _measure = Measure::new(&M.compress);
+
+                let mut data = dictionary.slot.to_le_bytes().to_vec();
+                data.extend_from_slice(&dictionary.version.to_le_bytes());
+                data.extend_from_slice(&dictionary.compress(buf, level)?);
+                Ok(Encoded {
+                    codec_id: Self::ZSTD_DICTIONARY,
+                    data: data.into(),
+                })
+            }
+            #[cfg(not(feature = "compression"))]
+            (Codec::Zstd(_), _) => Err(Error::Unsupported(
+                "zstd compression requires the compression feature".into(),
+            )),
+        }
+    }
+
+    #[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+    fn decode(self, dictionaries: &Dictionaries) -> Result<Vec<u8>> {
+        match self.codec_id {
+            Self::NONE => Ok(self.data.to_vec()),
+            #[cfg(feature = "compression")]
+            Self::ZSTD => {
+                #[cfg(feature = "metrics")]
+                let _measure = Measure::new(&M.decompress);
+
+                zstd::stream::decode_all(&self.data[..])
+                    .map_err(|_| Error::corruption(None))
+            }
+            #[cfg(feature = "compression")]
+            Self::ZSTD_DICTIONARY => {
+                #[cfg(feature = "metrics")]
+                let _measure = Measure::new(&M.decompress);
+
+                if self.data.len() < 8 {
+                    return Err(Error::corruption(None));
+                }
+                let slot = arr_to_u32(&self.data[..4]);
+                let version = arr_to_u32(&self.data[4..8]);
+                let dictionary = dictionaries
+                    .get(slot, version)
+                    .ok_or_else(|| Error::corruption(None))?;
+                dictionary.decompress(&self.data[8..])
+            }
+            #[cfg(not(feature = "compression"))]
+            Self::ZSTD | Self::ZSTD_DICTIONARY => Err(Error::Unsupported(
+                "zstd compression requires the compression feature".into(),
+            )),
+            _ => Err(Error::corruption(None)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct NodeView<'g>(pub(crate) PageView<'g>);
 
@@ -408,6 +553,11 @@ impl<'a> RecoveryGuard<'a> {
             self.batch_res.log.iobufs.max_reserved_lsn.load(Acquire);
         self.batch_res.mark_writebatch(max_reserved).map(|_| ())
     }
+
+    /// The LSN of the start of the batch.
+    pub(crate) fn lsn(&self) -> Lsn {
+        self.batch_res.lsn
+    }
 }
 
 /// A page consists of a sequence of state transformations
@@ -461,10 +611,19 @@ impl Page {
         }
     }
 
-    fn last_lsn(&self) -> Lsn {
+    pub(crate) fn last_lsn(&self) -> Lsn {
         self.cache_infos.last().map(|ci| ci.lsn).unwrap()
     }
 
+    /// The `CachePriority` of the tree of a node.
+    pub(crate) fn cache_priority(&self) -> CachePriority {
+        if let Some(Update::Node(node)) = &self.update {
+            node.cache_priority()
+        } else {
+            CachePriority::Normal
+        }
+    }
+
     pub(crate) fn log_size(&self) -> u64 {
         let first = self.cache_infos[0].log_size;
         let extrapolated_rest = if let Some(ci) = self.cache_infos.get(1) {
@@ -514,7 +673,9 @@ pub struct PageCacheInner {
     free: Arc<Mutex<FastSet8<PageId>>>,
     #[doc(hidden)]
     pub log: Log,
-    lru: Lru,
+    cache: Cache,
+    compressed_cache: Option<CompressedCache>,
+    secondary_cache: Option<SecondaryCache>,
 
     idgen: AtomicU64,
     idgen_persists: AtomicU64,
@@ -526,6 +687,13 @@ pub struct PageCacheInner {
     snapshot_lock: Mutex<()>,
 }
 
+// the cache that a `PageCacheInner` tracks its pages in, which
+// is its own unless `Config::shared_cache` is set
+enum Cache {
+    Private(Lru),
+    Shared(Tenant),
+}
+
 impl Debug for PageCache {
     fn fmt(
         &self,
@@ -581,7 +749,13 @@ impl PageCache {
         // snapshot before loading it.
         let snapshot = read_snapshot_or_default(&config)?;
 
-        config.heap.gc_unknown_items(&snapshot);
+        // a reader serves the state that it recovered, so
+        // let the writer know what it may still refer to.
+        config.readers.publish(snapshot.stable_lsn.unwrap_or(0))?;
+
+        if !config.read_only {
+            config.heap.gc_unknown_items(&snapshot);
+        }
 
         #[cfg(feature = "testing")]
         {
@@ -640,8 +814,31 @@ impl PageCache {
         #[cfg(feature = "metrics")]
         let _measure = Measure::new(&M.start_pagecache);
 
-        let cache_capacity = config.cache_capacity;
-        let lru = Lru::new(cache_capacity);
+        let compressed_capacity =
+            config.compressed_cache_capacity(config.cache_capacity);
+        let cache = if let Some(shared_cache) = &config.shared_cache {
+            Cache::Shared(shared_cache.register(config.shared_cache_priority)?)
+        } else {
+            Cache::Private(Lru::new(
+                config.cache_capacity - compressed_capacity,
+                config.cache_policy,
+            ))
+        };
+        let compressed_cache = if compressed_capacity > 0 {
+            Some(CompressedCache::new(compressed_capacity))
+        } else {
+            None
+        };
+        let secondary_cache = if let Some(path) = &config.secondary_cache_path
+        {
+            Some(SecondaryCache::open(
+                path,
+                config.secondary_cache_capacity,
+                config.encryption.clone(),
+            )?)
+        } else {
+            None
+        };
 
         let mut pc = PageCacheInner {
             was_recovered: false,
@@ -652,7 +849,9 @@ impl PageCache {
             idgen_persists: AtomicU64::new(0),
             inner: PageTable::default(),
             log: Log::start(config, &snapshot)?,
-            lru,
+            cache,
+            compressed_cache,
+            secondary_cache,
             next_pid_to_allocate: Mutex::new(0),
             snapshot_min_lsn: AtomicLsn::new(snapshot.stable_lsn.unwrap_or(0)),
             links: AtomicU64::new(0),
@@ -725,7 +924,7 @@ impl PageCache {
         pc.idgen.store(idgen_recovery, Release);
         pc.idgen_persists.store(idgen_persists, Release);
 
-        if was_recovered {
+        if was_recovered && !pc.config.read_only {
             // advance pc.idgen_persists and the counter page by one
             // interval, so that when generate_id() is next called, it
             // will advance them further by another interval, and wait for
@@ -739,7 +938,7 @@ impl PageCache {
             // CAS should never fail because the PageCache is still being constructed.
             pc.cas_page(COUNTER_PID, idgen_key, counter_update, false, &guard)?
                 .unwrap();
-        } else {
+        } else if !was_recovered {
             drop(guard);
             // persist the meta and idgen pages now, so that we don't hand
             // out id 0 again if we crash and recover
@@ -759,7 +958,12 @@ impl PageCache {
 
         trace!("pagecache started");
 
-        Ok(PageCache(Arc::new(pc)))
+        let pc = Arc::new(pc);
+        if let Cache::Shared(tenant) = &pc.cache {
+            tenant.attach(&pc);
+        }
+
+        Ok(PageCache(pc))
     }
 
     /// Try to atomically add a `PageLink` to the page.
@@ -815,6 +1019,7 @@ impl PageCache {
         }
 
         let node = old.as_node().apply(&new);
+        let (codec, dictionary) = (node.codec(), node.dictionary());
 
         // see if we should short-circuit replace
         if old.cache_infos.len() >= PAGE_CONSOLIDATION_THRESHOLD {
@@ -838,8 +1043,14 @@ impl PageCache {
         loop {
             // TODO handle replacement on threshold here instead
 
-            let log_reservation =
-                self.log.reserve(LogKind::Link, pid, &new, guard)?;
+            let log_reservation = self.log.reserve_with(
+                LogKind::Link,
+                pid,
+                &new,
+                codec,
+                dictionary,
+                guard,
+            )?;
             let lsn = log_reservation.lsn;
             let pointer = log_reservation.pointer;
 
@@ -900,9 +1111,10 @@ impl PageCache {
                     // possibly evict an item now that our cache has grown
                     let total_page_size =
                         unsafe { new_shared.deref().log_size() };
-                    let to_evict = self.lru.accessed(
+                    let to_evict = self.cache_accessed(
                         pid,
                         usize::try_from(total_page_size).unwrap(),
+                        unsafe { new_shared.deref().cache_priority() },
                         guard,
                     );
                     trace!(
@@ -1110,10 +1322,10 @@ impl PageCacheInner {
 
     /// Attempt to opportunistically rewrite data from a Draining
     /// segment of the file to help with space amplification.
-    /// Returns Ok(true) if we had the opportunity to attempt to
-    /// move a page. Returns Ok(false) if there were no pages
-    /// to GC. Returns an Err if we encountered an IO problem
-    /// while performing this GC.
+    /// Returns Ok(Some(bytes)) with the size of the page if we had
+    /// the opportunity to attempt to move a page. Returns Ok(None)
+    /// if there were no pages to GC. Returns an Err if we
+    /// encountered an IO problem while performing this GC.
     #[cfg(all(
         not(miri),
         any(
@@ -1127,15 +1339,18 @@ impl PageCacheInner {
             target_os = "ios",
         )
     ))]
-    pub(crate) fn attempt_gc(&self) -> Result<bool> {
+    pub(crate) fn attempt_gc(&self) -> Result<Option<u64>> {
+        tracing_span!("gc.attempt");
         let guard = pin();
         let cc = concurrency_control::read();
         let to_clean = self.log.iobufs.segment_cleaner.pop();
         let ret = if let Some((pid_to_clean, segment_to_clean)) = to_clean {
+            let (log_bytes, heap_bytes) =
+                self.page_space(pid_to_clean, &guard);
             self.rewrite_page(pid_to_clean, Some(segment_to_clean), &guard)
-                .map(|_| true)
+                .map(|_| Some(log_bytes + heap_bytes))
         } else {
-            Ok(false)
+            Ok(None)
         };
         drop(cc);
         guard.flush();
@@ -1325,6 +1540,11 @@ impl PageCacheInner {
     ) -> Result<()> {
         #[cfg(feature = "metrics")]
         let _measure = Measure::new(&M.rewrite_page);
+        tracing_span!(
+            "gc.rewrite_page",
+            pid = pid,
+            segment = tracing::field::debug(segment_to_purge),
+        );
 
         trace!("rewriting pid {}", pid);
 
@@ -1430,6 +1650,10 @@ impl PageCacheInner {
                     if let Some(log_reservation) = log_reservation {
                         log_reservation.complete()?;
                     }
+                    db_metrics::record_rewrite(
+                        &self.config,
+                        cache_info.log_size,
+                    );
 
                     // only call accessed & page_out if called from
                     // something other than page_out itself
@@ -1437,9 +1661,10 @@ impl PageCacheInner {
                         // possibly evict an item now that our cache has grown
                         let total_page_size =
                             unsafe { new_shared.deref().log_size() };
-                        let to_evict = self.lru.accessed(
+                        let to_evict = self.cache_accessed(
                             pid,
                             usize::try_from(total_page_size).unwrap(),
+                            unsafe { new_shared.deref().cache_priority() },
                             guard,
                         );
                         trace!(
@@ -1507,6 +1732,14 @@ impl PageCacheInner {
                     },
                 )?;
                 if res.is_ok() {
+                    let rewritten = self
+                        .inner
+                        .get(pid, guard)
+                        .cache_infos
+                        .iter()
+                        .map(|ci| ci.log_size)
+                        .sum();
+                    db_metrics::record_rewrite(&self.config, rewritten);
                     return Ok(());
                 }
             }
@@ -1561,6 +1794,8 @@ impl PageCacheInner {
             }
         }
 
+        size += self.config.value_log.size_on_disk()?;
+
         Ok(size)
     }
 
@@ -1613,9 +1848,14 @@ impl PageCacheInner {
                     self.log.reserve(log_kind, pid, m, guard)?
                 }
                 Update::Free => self.log.reserve(log_kind, pid, &(), guard)?,
-                Update::Node(ref node) => {
-                    self.log.reserve(log_kind, pid, node, guard)?
-                }
+                Update::Node(ref node) => self.log.reserve_with(
+                    log_kind,
+                    pid,
+                    node,
+                    node.codec(),
+                    node.dictionary(),
+                    guard,
+                )?,
                 other => {
                     panic!("non-replacement used in cas_page: {:?}", other)
                 }
@@ -1678,9 +1918,10 @@ impl PageCacheInner {
                     // possibly evict an item now that our cache has grown
                     let total_page_size =
                         unsafe { new_shared.deref().log_size() };
-                    let to_evict = self.lru.accessed(
+                    let to_evict = self.cache_accessed(
                         pid,
                         usize::try_from(total_page_size).unwrap(),
+                        unsafe { new_shared.deref().cache_priority() },
                         guard,
                     );
                     trace!(
@@ -1794,11 +2035,14 @@ impl PageCacheInner {
             }
 
             if page_view.update.is_some() {
+                db_metrics::record_cache_hit(&self.config);
+
                 // possibly evict an item now that our cache has grown
                 let total_page_size = page_view.log_size();
-                let to_evict = self.lru.accessed(
+                let to_evict = self.cache_accessed(
                     pid,
                     usize::try_from(total_page_size).unwrap(),
+                    page_view.cache_priority(),
                     guard,
                 );
                 trace!(
@@ -1818,6 +2062,24 @@ impl PageCacheInner {
                 page_view,
                 page_view.deref()
             );
+            if let Some(node) = self
+                .compressed_cache
+                .as_ref()
+                .and_then(|cc| cc.take(pid, &page_view.cache_infos))
+            {
+                db_metrics::record_compressed_cache_hit(&self.config);
+                break vec![Update::Node(node)];
+            }
+
+            if let Some(node) = self
+                .secondary_cache
+                .as_ref()
+                .and_then(|sc| sc.get(pid, &page_view.cache_infos))
+            {
+                db_metrics::record_secondary_cache_hit(&self.config);
+                break vec![Update::Node(node)];
+            }
+
             if page_view.cache_infos.first()
                 == last_attempted_cache_info.as_ref()
             {
@@ -1830,6 +2092,9 @@ impl PageCacheInner {
             #[cfg(feature = "metrics")]
             let _measure = Measure::new(&M.pull);
 
+            db_metrics::record_page_read(&self.config);
+            tracing_event!("page_in", pid = pid);
+
             // need to page-in
             let updates_result: Result<Vec<Update>> = page_view
                 .cache_infos
@@ -1878,9 +2143,10 @@ impl PageCacheInner {
 
             // possibly evict an item now that our cache has grown
             let total_page_size = unsafe { new_shared.deref().log_size() };
-            let to_evict = self.lru.accessed(
+            let to_evict = self.cache_accessed(
                 pid,
                 usize::try_from(total_page_size).unwrap(),
+                unsafe { new_shared.deref().cache_priority() },
                 guard,
             );
             trace!("accessed pid {} -> paging out pids {:?}", pid, to_evict);
@@ -1922,6 +2188,13 @@ impl PageCacheInner {
     /// a blocking flush to fsync the latest counter, ensuring
     /// that we will never give out the same counter twice.
     pub(crate) fn generate_id_inner(&self) -> Result<u64> {
+        if self.config.read_only {
+            // ids must be persisted before they are handed out
+            return Err(Error::Unsupported(
+                "the database was opened in read-only mode".into(),
+            ));
+        }
+
         let ret = self.idgen.fetch_add(1, Release);
 
         trace!("generating ID {}", ret);
@@ -2042,6 +2315,56 @@ impl PageCacheInner {
         }
     }
 
+    /// Atomically removes the `removed` identifiers from the `Meta`,
+    /// and moves the mapping of each `(from, to)` identifier in
+    /// `moved` to `to`, returning the `PageId` of each one that was
+    /// moved.
+    pub(crate) fn rename_in_meta<'g>(
+        &self,
+        removed: &[IVec],
+        moved: &[(IVec, IVec)],
+        guard: &'g Guard,
+    ) -> Result<Vec<Option<PageId>>> {
+        loop {
+            let meta_view = self.get_meta(guard);
+
+            let mut new_meta = meta_view.deref().clone();
+            for name in removed {
+                let _ = new_meta.del_root(name);
+            }
+            let mut pids = Vec::with_capacity(moved.len());
+            for (from, to) in moved {
+                let pid = new_meta.del_root(from);
+                if let Some(pid) = pid {
+                    let _ = new_meta.set_root(to.clone(), pid);
+                }
+                pids.push(pid);
+            }
+
+            let new_meta_link = Update::Meta(new_meta);
+
+            let res = self.cas_page(
+                META_PID,
+                meta_view.0,
+                new_meta_link,
+                false,
+                guard,
+            )?;
+
+            match res {
+                Ok(_worked) => return Ok(pids),
+                Err(Some((_current_pointer, _rejected))) => {}
+                Err(None) => {
+                    return Err(Error::ReportableBug(
+                        "replacing the META page has failed because \
+                         the pagecache does not think it currently exists."
+                            .into(),
+                    ));
+                }
+            }
+        }
+    }
+
     fn page_out(&self, to_evict: Vec<PageId>, guard: &Guard) -> Result<()> {
         #[cfg(feature = "metrics")]
         let _measure = Measure::new(&M.page_out);
@@ -2060,7 +2383,7 @@ impl PageCacheInner {
                     break;
                 }
 
-                if page_view.cache_infos.len() > 1 {
+                if page_view.cache_infos.len() > 1 && !self.config.read_only {
                     // compress pages on page-out
                     self.rewrite_page(pid, None, guard)?;
                     continue 'pid;
@@ -2077,6 +2400,21 @@ impl PageCacheInner {
                     .compare_and_set(page_view.read, new_page, SeqCst, guard)
                     .is_ok()
                 {
+                    if let Some(Update::Node(node)) = &page_view.update {
+                        let cache_infos = &page_view.cache_infos;
+                        if let Some(compressed_cache) = &self.compressed_cache {
+                            compressed_cache.insert(
+                                pid,
+                                cache_infos,
+                                node,
+                                &self.config.buffers,
+                            );
+                        }
+                        if let Some(secondary_cache) = &self.secondary_cache {
+                            secondary_cache.insert(pid, cache_infos, node);
+                        }
+                    }
+
                     unsafe {
                         guard.defer_destroy(page_view.read);
                     }
@@ -2090,6 +2428,21 @@ impl PageCacheInner {
     }
 
     fn pull(&self, pid: PageId, lsn: Lsn, pointer: DiskPtr) -> Result<Update> {
+        let res = self.pull_inner(pid, lsn, pointer);
+        if let Err(ref e) = res {
+            if fault::is_fault(e) {
+                self.config.report_fault(Some(pid), e, false);
+            }
+        }
+        res
+    }
+
+    fn pull_inner(
+        &self,
+        pid: PageId,
+        lsn: Lsn,
+        pointer: DiskPtr,
+    ) -> Result<Update> {
         use MessageKind::*;
 
         trace!("pulling pid {} lsn {} pointer {} from disk", pid, lsn, pointer);
@@ -2102,37 +2455,8 @@ impl PageCacheInner {
         iobuf::make_durable(&self.log.iobufs, lsn)?;
 
         let (header, bytes) = match self.log.read(pid, lsn, pointer) {
-            Ok(LogRead::Inline(header, buf, _len)) => {
-                assert_eq!(
-                    header.pid, pid,
-                    "expected pid {} on pull of pointer {}, \
-                     but got {} instead",
-                    pid, pointer, header.pid
-                );
-                assert_eq!(
-                    header.segment_number, expected_segment_number,
-                    "expected segment number {:?} on pull of pointer {}, \
-                     but got segment number {:?} instead",
-                    expected_segment_number, pointer, header.segment_number
-                );
-                Ok((header, buf))
-            }
-            Ok(LogRead::Heap(header, buf, _heap_id, _inline_len)) => {
-                assert_eq!(
-                    header.pid, pid,
-                    "expected pid {} on pull of pointer {}, \
-                     but got {} instead",
-                    pid, pointer, header.pid
-                );
-                assert_eq!(
-                    header.segment_number, expected_segment_number,
-                    "expected segment number {:?} on pull of pointer {}, \
-                     but got segment number {:?} instead",
-                    expected_segment_number, pointer, header.segment_number
-                );
-
-                Ok((header, buf))
-            }
+            Ok(LogRead::Inline(header, buf, _))
+            | Ok(LogRead::Heap(header, buf, _, _)) => Ok((header, buf)),
             Ok(other) => {
                 debug!("read unexpected page: {:?}", other);
                 Err(Error::corruption(Some(pointer)))
@@ -2143,6 +2467,21 @@ impl PageCacheInner {
             }
         }?;
 
+        if header.pid != pid
+            || header.segment_number != expected_segment_number
+        {
+            error!(
+                "expected pid {} and segment number {:?} on pull of \
+                 pointer {}, but got pid {} and segment number {:?}",
+                pid,
+                expected_segment_number,
+                pointer,
+                header.pid,
+                header.segment_number
+            );
+            return Err(Error::corruption(Some(pointer)));
+        }
+
         // We create this &mut &[u8] to assist the `Serializer`
         // implementation that incrementally consumes bytes
         // without taking ownership of them.
@@ -2165,12 +2504,16 @@ impl PageCacheInner {
                 }
                 Free => Ok(Update::Free),
                 Corrupted | Canceled | Cap | BatchManifest => {
-                    panic!("unexpected pull: {:?}", header.kind)
+                    error!("unexpected pull: {:?}", header.kind);
+                    Err(Error::corruption(Some(pointer)))
                 }
             }
         };
 
-        let update = update_res.expect("failed to deserialize data");
+        let update = update_res.map_err(|e| {
+            error!("failed to deserialize data at {}: {:?}", pointer, e);
+            e
+        })?;
 
         // TODO this feels racy, test it better?
         if let Update::Free = update {
@@ -2183,6 +2526,303 @@ impl PageCacheInner {
         }
     }
 
+    /// Returns the lowest page ID that hasn't been allocated
+    /// yet.
+    pub(crate) fn next_pid_to_allocate(&self) -> PageId {
+        *self.next_pid_to_allocate.lock()
+    }
+
+    /// Hands the inactive segments that are at most
+    /// `max_live_pct` percent live to the segment cleaner,
+    /// returning how many there were, see `Db::compact`.
+    pub(crate) fn drain_fragmented(
+        &self,
+        max_live_pct: usize,
+    ) -> Result<u64> {
+        self.log.iobufs.with_sa(|sa| sa.drain_fragmented(max_live_pct))
+    }
+
+    /// Returns the number of pages that the segment cleaner has
+    /// yet to move.
+    pub(crate) fn pages_to_clean(&self) -> u64 {
+        self.log.iobufs.segment_cleaner.pending()
+    }
+
+    /// Rewrites a page to the end of the log, see `Db::compact`.
+    pub(crate) fn rewrite(&self, pid: PageId) -> Result<()> {
+        let guard = pin();
+        let _cc = concurrency_control::read();
+        self.rewrite_page(pid, None, &guard)
+    }
+
+    /// Returns the offsets in the log of the fragments of a page.
+    pub(crate) fn page_lids(
+        &self,
+        pid: PageId,
+        guard: &Guard,
+    ) -> Vec<LogOffset> {
+        let page_view = self.inner.get(pid, guard);
+        page_view
+            .cache_infos
+            .iter()
+            .filter_map(|ci| ci.pointer.lid())
+            .collect()
+    }
+
+    /// Gives the space of the free segments of the log back to
+    /// the filesystem, see `Db::shrink_to_fit`.
+    pub(crate) fn shrink_to_fit(&self) -> Result<u64> {
+        self.log.iobufs.with_sa(SegmentAccountant::shrink_to_fit)
+    }
+
+    /// Returns the capacity of the page cache in bytes, including
+    /// its compressed tier.
+    pub(crate) fn cache_capacity(&self) -> usize {
+        let compressed_capacity =
+            self.compressed_cache.as_ref().map_or(0, CompressedCache::capacity);
+        match &self.cache {
+            Cache::Private(lru) => lru.capacity() + compressed_capacity,
+            Cache::Shared(tenant) => tenant.capacity(),
+        }
+    }
+
+    /// Returns the bytes of the pages in the page cache, including
+    /// its compressed tier, see `Db::memory_usage`.
+    pub(crate) fn cache_size(&self) -> u64 {
+        let compressed_size =
+            self.compressed_cache.as_ref().map_or(0, CompressedCache::size);
+        let size = match &self.cache {
+            Cache::Private(lru) => lru.size() + compressed_size,
+            Cache::Shared(tenant) => tenant.size(),
+        };
+        size as u64
+    }
+
+    /// Changes the capacity of the page cache, paging out the least
+    /// recently used pages until it fits, see
+    /// `Db::set_cache_capacity`.
+    pub(crate) fn set_cache_capacity(&self, cache_capacity: usize) -> Result<()> {
+        let compressed_capacity =
+            self.config.compressed_cache_capacity(cache_capacity);
+        if cache_capacity - compressed_capacity < 256 {
+            return Err(Error::Unsupported(
+                "the cache capacity must leave at least 256 bytes \
+                 beside its compressed tier"
+                    .into(),
+            ));
+        }
+        if let Some(compressed_cache) = &self.compressed_cache {
+            compressed_cache.set_capacity(compressed_capacity);
+        }
+        let to_evict = match &self.cache {
+            Cache::Private(lru) => {
+                lru.set_capacity(cache_capacity - compressed_capacity)
+            }
+            Cache::Shared(_) => {
+                return Err(Error::Unsupported(
+                    "the capacity of a cache that is shared with \
+                     other databases can't be changed"
+                        .into(),
+                ));
+            }
+        };
+        let guard = pin();
+        self.page_out(to_evict, &guard)
+    }
+
+    /// Records an access to a page in the cache, returning the
+    /// pages that have to be paged out for the cache to fit in its
+    /// capacity. The pages of the other databases of a shared
+    /// cache are paged out by it.
+    fn cache_accessed(
+        &self,
+        pid: PageId,
+        item_size: usize,
+        priority: CachePriority,
+        guard: &Guard,
+    ) -> Vec<PageId> {
+        match &self.cache {
+            Cache::Private(lru) => lru.accessed(pid, item_size, priority, guard),
+            Cache::Shared(tenant) => {
+                let evicted = tenant.accessed(pid, item_size, priority, guard);
+                self.allocated(evicted, guard)
+            }
+        }
+    }
+
+    /// Pages out the pages that another database evicted from
+    /// the cache that they share.
+    pub(crate) fn page_out_evicted(
+        &self,
+        pids: Vec<PageId>,
+        guard: &Guard,
+    ) -> Result<()> {
+        let to_evict = self.allocated(pids, guard);
+        self.page_out(to_evict, guard)
+    }
+
+    // a shared cache may still track the pages of the database that
+    // used a slot before this one, which may not exist in this one
+    fn allocated(&self, pids: Vec<PageId>, guard: &Guard) -> Vec<PageId> {
+        pids.into_iter()
+            .filter(|pid| self.inner.contains_pid(*pid, guard))
+            .collect()
+    }
+
+    /// Returns the sizes of the log file, the heap files and the
+    /// value log files.
+    pub(crate) fn file_sizes(&self) -> Result<(u64, u64, u64)> {
+        let log_size = self.config.file.metadata()?.len();
+        let mut heap_size = 0;
+        let heap_dir = self.config.get_path().join("heap");
+        for entry in std::fs::read_dir(heap_dir)? {
+            heap_size += entry?.metadata()?.len();
+        }
+        let value_log_size = self.config.value_log.size_on_disk()?;
+        Ok((log_size, heap_size, value_log_size))
+    }
+
+    /// Returns the state and the number of live bytes of every
+    /// segment of the log, see `Db::space_usage`.
+    pub(crate) fn segment_space(&self) -> Vec<SegmentSpace> {
+        self.log.iobufs.with_sa(|sa| sa.segment_space())
+    }
+
+    /// Returns the number of bytes that the fragments of a page
+    /// take in the log and in the heap files, without reading
+    /// them.
+    pub(crate) fn page_space(&self, pid: PageId, guard: &Guard) -> (u64, u64) {
+        let page_view = self.inner.get(pid, guard);
+        if page_view.is_free() {
+            return (0, 0);
+        }
+        let mut log_bytes = 0;
+        let mut heap_bytes = 0;
+        for ci in &page_view.cache_infos {
+            if ci.pointer.lid().is_some() {
+                log_bytes += ci.log_size;
+            }
+            if let Some(heap_id) = ci.pointer.heap_id() {
+                heap_bytes += heap_id.slab_size();
+            }
+        }
+        (log_bytes, heap_bytes)
+    }
+
+    /// Returns a node if it's in the cache, without reading it
+    /// from the log or counting it as accessed. Returns `None` if
+    /// the page is free, and `Some(None)` if it has been paged
+    /// out.
+    pub(crate) fn get_cached<'g>(
+        &self,
+        pid: PageId,
+        guard: &'g Guard,
+    ) -> Option<Option<NodeView<'g>>> {
+        let page_view = self.inner.get(pid, guard);
+        if page_view.is_free() {
+            None
+        } else if page_view.update.is_some() {
+            Some(Some(NodeView(page_view)))
+        } else {
+            Some(None)
+        }
+    }
+
+    /// Returns the pages whose nodes are in the cache, see
+    /// `Db::write_cache_manifest`.
+    pub(crate) fn cached_pids(&self) -> Vec<PageId> {
+        let guard = pin();
+        (COUNTER_PID + 1..self.next_pid_to_allocate())
+            .filter(|pid| {
+                if *pid == BATCH_MANIFEST_PID
+                    || !self.inner.contains_pid(*pid, &guard)
+                {
+                    return false;
+                }
+                if let Some(Some(_)) = self.get_cached(*pid, &guard) {
+                    true
+                } else {
+                    false
+                }
+            })
+            .collect()
+    }
+
+    /// Reads the pages that aren't cached into the cache, until
+    /// the ones that were read fill its capacity, returning how
+    /// many were read, see `Db::warm_cache_from`. Pages that
+    /// don't exist are skipped.
+    pub(crate) fn warm(&self, pids: &[PageId]) -> Result<usize> {
+        let next_pid_to_allocate = self.next_pid_to_allocate();
+        let capacity = u64::try_from(self.cache_capacity()).unwrap();
+        let mut read = 0;
+        let mut size = 0;
+        for pid in pids.iter().copied() {
+            if size >= capacity {
+                break;
+            }
+            let guard = pin();
+            if pid <= COUNTER_PID
+                || pid == BATCH_MANIFEST_PID
+                || pid >= next_pid_to_allocate
+                || !self.inner.contains_pid(pid, &guard)
+            {
+                continue;
+            }
+            if let Some(None) = self.get_cached(pid, &guard) {
+                if let Some(node_view) = self.get(pid, &guard)? {
+                    read += 1;
+                    size += node_view.0.log_size();
+                }
+            }
+        }
+        Ok(read)
+    }
+
+    /// Reads every fragment of a page back from the log and
+    /// checks it, returning `Error::Corruption` instead of
+    /// panicking like `pull` does when a fragment can't be read
+    /// or belongs to another page.
+    pub(crate) fn verify_page(&self, pid: PageId, guard: &Guard) -> Result<()> {
+        let mut last_cache_infos = None;
+        loop {
+            let page_view = self.inner.get(pid, guard);
+            if page_view.is_free() {
+                return Ok(());
+            }
+            let cache_infos = page_view.cache_infos.clone();
+
+            let res = cache_infos.iter().try_for_each(|ci| {
+                self.pull_inner(pid, ci.lsn, ci.pointer).map(drop)
+            });
+            match res {
+                // the fragments may have been moved by the segment
+                // cleaner since the page was read, so only a page
+                // that fails twice in the same place is corrupt.
+                Err(Error::Corruption { .. })
+                    if last_cache_infos.as_ref() != Some(&cache_infos) =>
+                {
+                    last_cache_infos = Some(cache_infos);
+                }
+                other => return other,
+            }
+        }
+    }
+
+    /// Frees a page without reading it, for pages that can't be
+    /// read. Returns `Ok(())` if the page was already free.
+    pub(crate) fn free_unread(&self, pid: PageId, guard: &Guard) -> Result<()> {
+        loop {
+            let page_view = self.inner.get(pid, guard);
+            if page_view.is_free() {
+                return Ok(());
+            }
+            if self.free(pid, page_view, guard)?.is_ok() {
+                return Ok(());
+            }
+        }
+    }
+
     fn load_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
         let next_pid_to_allocate = snapshot.pt.len() as PageId;
 
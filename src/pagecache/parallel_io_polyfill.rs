@@ -99,3 +99,15 @@ pub(crate) fn pwrite_all(
     }
     Ok(())
 }
+
+pub(crate) fn pwritev_all(
+    file: &File,
+    bufs: &[&[u8]],
+    mut offset: LogOffset,
+) -> io::Result<()> {
+    for buf in bufs {
+        pwrite_all(file, buf, offset)?;
+        offset += buf.len() as LogOffset;
+    }
+    Ok(())
+}
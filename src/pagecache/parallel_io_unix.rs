@@ -41,3 +41,105 @@ pub(crate) fn pwrite_all(
 ) -> io::Result<()> {
     file.write_all_at(buf, offset)
 }
+
+/// Writes `bufs` one after the other at `offset` with a single
+/// pwritev, unless it writes fewer bytes than were asked for.
+#[cfg(target_os = "linux")]
+pub(crate) fn pwritev_all(
+    file: &File,
+    mut bufs: &[&[u8]],
+    mut offset: LogOffset,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // the bytes of the first buffer that a previous call wrote
+    let mut skip = 0;
+    while !bufs.is_empty() {
+        let iovecs: Vec<libc::iovec> = bufs
+            .iter()
+            .enumerate()
+            .map(|(i, buf)| {
+                let unwritten = if i == 0 { &buf[skip..] } else { buf };
+                libc::iovec {
+                    iov_base: unwritten.as_ptr() as *mut libc::c_void,
+                    iov_len: unwritten.len(),
+                }
+            })
+            .collect();
+
+        let ret = unsafe {
+            libc::pwritev(
+                file.as_raw_fd(),
+                iovecs.as_ptr(),
+                libc::c_int::try_from(iovecs.len()).unwrap(),
+                libc::off_t::try_from(offset).unwrap(),
+            )
+        };
+        let mut written = match usize::try_from(ret) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ));
+            }
+            Ok(written) => written,
+            Err(_) => {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+        };
+        offset += u64::try_from(written).unwrap();
+
+        while !bufs.is_empty() && written >= bufs[0].len() - skip {
+            written -= bufs[0].len() - skip;
+            bufs = &bufs[1..];
+            skip = 0;
+        }
+        skip += written;
+    }
+    Ok(())
+}
+
+// writes the buffers one by one where pwritev isn't available
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pwritev_all(
+    file: &File,
+    bufs: &[&[u8]],
+    mut offset: LogOffset,
+) -> io::Result<()> {
+    for buf in bufs {
+        pwrite_all(file, buf, offset)?;
+        offset += u64::try_from(buf.len()).unwrap();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pwritev_writes_buffers_in_order() {
+        let path = std::env::temp_dir()
+            .join(format!("sled_pwritev.{}", std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+
+        let bufs: &[&[u8]] = &[b"hello", b"", b" ", &[7; 5000]];
+        pwritev_all(&file, bufs, 3).unwrap();
+
+        let mut read = vec![0; 5009];
+        pread_exact(&file, &mut read, 0).unwrap();
+        assert_eq!(&read[..9], b"\0\0\0hello ");
+        assert!(read[9..].iter().all(|b| *b == 7));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
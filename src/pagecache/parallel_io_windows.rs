@@ -94,3 +94,15 @@ pub(crate) fn pwrite_all(
     let mut f = file.try_clone()?;
     seek_write_all(&mut f, buf, offset)
 }
+
+pub(crate) fn pwritev_all(
+    file: &File,
+    bufs: &[&[u8]],
+    mut offset: LogOffset,
+) -> io::Result<()> {
+    for buf in bufs {
+        pwrite_all(file, buf, offset)?;
+        offset += buf.len() as LogOffset;
+    }
+    Ok(())
+}
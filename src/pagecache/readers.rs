@@ -0,0 +1,242 @@
+//! Coordination between a writer and the read-only processes
+//! that have the same database open.
+//!
+//! Each reader registers itself with a file in the `readers`
+//! directory that it holds an exclusive lock on for as long as
+//! it runs. The file contains the stable `Lsn` that the reader
+//! recovered up to, which is the snapshot of the database that
+//! it serves reads from. The writer never reuses a segment or a
+//! heap slot that was freed after the lowest registered `Lsn`,
+//! because that reader may still refer to it. A registration
+//! file that the writer is able to lock belongs to a reader
+//! that has exited, and is removed.
+//!
+//! The lowest registered `Lsn` is cached for a short while, until
+//! a reader of this process registers or exits, or the directory
+//! is modified by another process registering or removing a
+//! reader. A cached `Lsn` is never higher than the current one,
+//! because a reader only raises its own `Lsn` once it recovered.
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::AtomicUsize,
+    time::{Duration, Instant, SystemTime},
+};
+
+use super::{Heap, HeapId};
+use crate::*;
+
+static REGISTRATIONS: AtomicUsize = AtomicUsize::new(0);
+
+// bumped whenever a reader of this process registers, publishes
+// its `Lsn` or exits
+static CHANGES: AtomicUsize = AtomicUsize::new(0);
+
+/// How long the lowest registered `Lsn` is cached for, which
+/// bounds how long a reader that raised its `Lsn` or exited
+/// without removing its registration keeps pinning more than it
+/// needs to.
+const MIN_PIN_TTL: Duration = Duration::from_secs(1);
+
+/// How old the modification time of the directory must be for
+/// the lowest registered `Lsn` to be cached, so that a reader
+/// that registers within the granularity of the timestamps of
+/// the filesystem is not missed.
+const MTIME_GRANULARITY: Duration = Duration::from_secs(2);
+
+// the state of the registrations that a lowest `Lsn` was read for
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Scanned {
+    changes: usize,
+    modified: SystemTime,
+}
+
+#[derive(Debug)]
+pub(crate) struct Readers {
+    dir: PathBuf,
+    segment_size: Lsn,
+    // the file that registers this process as a reader
+    registration: Mutex<Option<(PathBuf, File)>>,
+    // the highest lsn that a segment has been handed out for
+    tip_lsn: AtomicLsn,
+    deferred_heap_frees: Mutex<Vec<(Lsn, HeapId)>>,
+    // the result of the last scan of the registrations
+    cached_min_pin: Mutex<Option<(Scanned, Instant, Option<Lsn>)>>,
+}
+
+impl Drop for Readers {
+    fn drop(&mut self) {
+        if let Some((path, file)) = self.registration.get_mut().take() {
+            drop(file);
+            let _ = fs::remove_file(path);
+            CHANGES.fetch_add(1, SeqCst);
+        }
+    }
+}
+
+impl Readers {
+    pub(crate) fn new(path: &Path, segment_size: usize) -> Readers {
+        Readers {
+            dir: path.join("readers"),
+            segment_size: Lsn::try_from(segment_size).unwrap(),
+            registration: Mutex::new(None),
+            tip_lsn: AtomicLsn::new(0),
+            deferred_heap_frees: Mutex::new(vec![]),
+            cached_min_pin: Mutex::new(None),
+        }
+    }
+
+    /// Registers this process as a reader. Until `publish` is
+    /// called, the writer assumes that we may refer to anything.
+    pub(crate) fn register(&self) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let name = format!(
+            "{}-{}",
+            std::process::id(),
+            REGISTRATIONS.fetch_add(1, SeqCst)
+        );
+        let path = self.dir.join(name);
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)?;
+
+        #[cfg(all(
+            not(miri),
+            any(windows, target_os = "linux", target_os = "macos")
+        ))]
+        fs2::FileExt::lock_exclusive(&file)?;
+
+        *self.registration.lock() = Some((path, file));
+        CHANGES.fetch_add(1, SeqCst);
+
+        self.publish(0)
+    }
+
+    /// Records the stable `Lsn` that this reader recovered up to.
+    pub(crate) fn publish(&self, lsn: Lsn) -> Result<()> {
+        if let Some((_, file)) = &*self.registration.lock() {
+            super::pwrite_all(file, &lsn.to_le_bytes(), 0)?;
+            file.sync_all()?;
+            CHANGES.fetch_add(1, SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Returns the lowest `Lsn` that a running reader may
+    /// refer to, or `None` if no readers are running.
+    pub(crate) fn min_pin(&self) -> Result<Option<Lsn>> {
+        let changes = CHANGES.load(SeqCst);
+        let modified = match fs::metadata(&self.dir) {
+            Ok(metadata) => metadata.modified().ok(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let scanned = modified.map(|modified| Scanned { changes, modified });
+
+        let mut cached = self.cached_min_pin.lock();
+        if let (Some(scanned), Some((at, since, min_pin))) = (scanned, *cached)
+        {
+            if scanned == at && since.elapsed() < MIN_PIN_TTL {
+                return Ok(min_pin);
+            }
+        }
+
+        let min_pin = self.scan()?;
+
+        // a directory that was modified too recently may be
+        // modified again without its timestamp changing
+        let settled = scanned.filter(|scanned| {
+            scanned.modified.elapsed().map_or(false, |e| e > MTIME_GRANULARITY)
+        });
+        *cached = settled.map(|scanned| (scanned, Instant::now(), min_pin));
+
+        Ok(min_pin)
+    }
+
+    // reads the lowest `Lsn` of the registrations, removing those
+    // of readers that have exited
+    fn scan(&self) -> Result<Option<Lsn>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut min_pin = None;
+
+        for entry in entries {
+            let path = entry?.path();
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            #[cfg(all(
+                not(miri),
+                any(windows, target_os = "linux", target_os = "macos")
+            ))]
+            {
+                if fs2::FileExt::try_lock_exclusive(&file).is_ok() {
+                    // nobody holds the lock, so this reader is gone
+                    drop(file);
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+            }
+
+            // a registration that is still being written
+            // pins everything.
+            let mut buf = [0; 8];
+            let lsn = match super::pread_exact(&file, &mut buf, 0) {
+                Ok(()) => Lsn::from_le_bytes(buf),
+                Err(_) => 0,
+            };
+
+            min_pin = Some(min_pin.map_or(lsn, |min: Lsn| min.min(lsn)));
+        }
+
+        Ok(min_pin)
+    }
+
+    /// Records that a segment has been handed out for `lsn`.
+    pub(crate) fn set_tip(&self, lsn: Lsn) {
+        self.tip_lsn.store(lsn, Release);
+    }
+
+    /// Returns an `Lsn` that is higher than that of anything
+    /// that has been written so far, which is the point that
+    /// something being freed now must be considered freed at.
+    pub(crate) fn freed_at(&self) -> Lsn {
+        self.tip_lsn.load(Acquire) + self.segment_size
+    }
+
+    /// Frees a heap slot once no reader may refer to it, along
+    /// with any earlier frees that were deferred.
+    pub(crate) fn free_heap(&self, heap: &Heap, heap_id: HeapId) {
+        let mut deferred = self.deferred_heap_frees.lock();
+        deferred.push((self.freed_at(), heap_id));
+
+        let min_pin = match self.min_pin() {
+            Ok(min_pin) => min_pin,
+            Err(e) => {
+                // try again on the next free
+                error!("failed to read reader registrations: {:?}", e);
+                return;
+            }
+        };
+
+        deferred.retain(|(freed_at, heap_id)| {
+            if min_pin.map_or(true, |pin| *freed_at <= pin) {
+                heap.free(*heap_id);
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
@@ -1,5 +1,44 @@
+use std::cell::Cell;
+
 use crate::{pagecache::*, *};
 
+thread_local! {
+    // the number of reservations that this thread holds, and the
+    // last lsn that it completed while holding others. a thread
+    // may hold the reservation of a batch while it writes what is
+    // in it, and the policies that sync from the writes wait for
+    // the last of them, see `Reservation::complete`
+    static HELD: Cell<(usize, Lsn)> = Cell::new((0, -1));
+
+    // set while this thread makes writes that are synced whatever
+    // the sync policy is, see `durably`
+    static DURABLE: Cell<bool> = Cell::new(false);
+}
+
+/// Runs `write` with every write that it completes on this thread
+/// synced like `SyncPolicy::EveryWrite` would.
+pub(crate) fn durably<T, F: FnOnce() -> T>(write: F) -> T {
+    struct Restore(bool);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let was = self.0;
+            DURABLE.with(|durable| durable.set(was));
+        }
+    }
+
+    let _restore = Restore(DURABLE.with(|durable| durable.replace(true)));
+    write()
+}
+
+// records that this thread took out a reservation
+pub(super) fn hold() {
+    HELD.with(|held| {
+        let (count, pending) = held.get();
+        held.set((count + 1, pending));
+    });
+}
+
 /// A pending log reservation which can be aborted or completed.
 /// NB the holder should quickly call `complete` or `abort` as
 /// taking too long to decide will cause the underlying IO
@@ -51,7 +90,42 @@ impl<'a> Reservation<'a> {
     /// Complete the reservation, placing the buffer on disk. returns
     /// the log sequence number of the write, and the file offset.
     pub fn complete(mut self) -> Result<(Lsn, DiskPtr)> {
-        self.flush(true)
+        let ret = self.flush(true)?;
+
+        // the policies that sync from the writes do it once the
+        // message is complete, so that the buffer can be written,
+        // and once this thread holds no other reservation whose
+        // buffer the sync would wait for
+        let completed = self.lsn + Lsn::try_from(self.buf.len()).unwrap() - 1;
+        let outermost = HELD.with(|held| {
+            let (count, pending) = held.get();
+            let last = std::cmp::max(pending, completed);
+            if count == 0 {
+                held.set((0, -1));
+                Some(last)
+            } else {
+                held.set((count, last));
+                None
+            }
+        });
+        let last_lsn = if let Some(last_lsn) = outermost {
+            last_lsn
+        } else {
+            return Ok(ret);
+        };
+        let must_sync = match self.log.config.current_sync_policy() {
+            _ if DURABLE.with(Cell::get) => true,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::EveryNBytes(bytes) => {
+                let unsynced = last_lsn - self.log.iobufs.stable();
+                u64::try_from(unsynced).unwrap_or(0) >= bytes
+            }
+            SyncPolicy::EveryNMillis(_) | SyncPolicy::OsManaged => false,
+        };
+        if must_sync {
+            iobuf::make_stable(&self.log.iobufs, last_lsn)?;
+        }
+        Ok(ret)
     }
 
     /// Returns the length of the on-log reservation.
@@ -105,6 +179,10 @@ impl<'a> Reservation<'a> {
         }
 
         self.flushed = true;
+        HELD.with(|held| {
+            let (count, pending) = held.get();
+            held.set((count - 1, pending));
+        });
 
         if !valid {
             // don't actually zero the message, still check its hash
@@ -113,6 +191,7 @@ impl<'a> Reservation<'a> {
         }
 
         let crc32 = calculate_message_crc32(
+            self.log.config.checksum,
             self.buf[..self.header_len].as_ref(),
             &self.buf[self.header_len..],
         );
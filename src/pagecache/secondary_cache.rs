@@ -0,0 +1,255 @@
+//! A secondary cache in a file on a local device, see
+//! `Config::secondary_cache_path`.
+//!
+//! The nodes of the pages that are paged out are written to a
+//! cache file of a fixed capacity, which is written like a ring:
+//! every entry is written after the one before it, wrapping around
+//! to the start of the file once the next one doesn't fit, and the
+//! entries that it overwrites are forgotten. A page that isn't
+//! cached is read from the cache file instead of the storage files
+//! if its entry was written while the page was made of the same
+//! `CacheInfo`s. The file is emptied when the database is opened
+//! and removed when it's closed, since nothing in it outlives the
+//! process.
+//!
+//! Every entry is written as crc32 ++ seq ++ pid ++ len ++ node,
+//! where the crc32 covers the rest of it, and the seq tells it
+//! apart from the entries that were written at the same offset
+//! before it, which a read may race with. When `Config::encryption`
+//! is set, the node is encrypted in the same way as the messages
+//! of the log, and an entry that fails to decrypt is a miss.
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use super::{arr_to_u32, pread_exact, pwrite_all, CacheInfo};
+use crate::{encryption::Encryption, *};
+
+const HEADER_LEN: usize = 4 + 8 + 8 + 4;
+
+pub(crate) struct SecondaryCache {
+    path: PathBuf,
+    file: File,
+    encryption: Option<Encryption>,
+    index: Mutex<Index>,
+}
+
+struct Index {
+    capacity: u64,
+    // the offset that the next entry is written at
+    head: u64,
+    next_seq: u64,
+    entries: FastMap8<PageId, Slot>,
+    // the end and the page of the entry at every offset
+    by_offset: BTreeMap<u64, (u64, PageId)>,
+}
+
+struct Slot {
+    offset: u64,
+    len: usize,
+    seq: u64,
+    cache_infos: Vec<CacheInfo>,
+}
+
+impl Index {
+    fn remove(&mut self, pid: PageId) {
+        if let Some(slot) = self.entries.remove(&pid) {
+            self.by_offset.remove(&slot.offset);
+        }
+    }
+
+    // forgets the entries that overlap the range from `start` to
+    // `end`, which is about to be overwritten
+    fn evict_range(&mut self, start: u64, end: u64) {
+        let overwritten: Vec<PageId> = self
+            .by_offset
+            .range(..end)
+            .rev()
+            .take_while(|(_, (entry_end, _))| *entry_end > start)
+            .map(|(_, (_, pid))| *pid)
+            .collect();
+        for pid in overwritten {
+            self.remove(pid);
+        }
+    }
+}
+
+impl SecondaryCache {
+    /// Opens the cache file at `path`, emptying it, which fails if
+    /// another database uses it.
+    pub(crate) fn open(
+        path: &Path,
+        capacity: u64,
+        encryption: Option<Encryption>,
+    ) -> Result<SecondaryCache> {
+        // only emptied once it's locked
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        #[cfg(all(
+            not(miri),
+            any(windows, target_os = "linux", target_os = "macos")
+        ))]
+        {
+            use fs2::FileExt;
+
+            if let Err(e) = file.try_lock_exclusive() {
+                return Err(Error::Io(io::Error::new(
+                    ErrorKind::Other,
+                    format!(
+                        "could not acquire lock on the secondary cache \
+                         {:?}: {:?}",
+                        path, e
+                    ),
+                )));
+            }
+        }
+
+        file.set_len(0)?;
+
+        Ok(SecondaryCache {
+            path: path.to_owned(),
+            file,
+            encryption,
+            index: Mutex::new(Index {
+                capacity,
+                head: 0,
+                next_seq: 0,
+                entries: FastMap8::default(),
+                by_offset: BTreeMap::new(),
+            }),
+        })
+    }
+
+    /// Writes a node that is being paged out, unless the cache
+    /// file already has it.
+    pub(crate) fn insert(
+        &self,
+        pid: PageId,
+        cache_infos: &[CacheInfo],
+        node: &Node,
+    ) {
+        let mut index = self.index.lock();
+        if let Some(slot) = index.entries.get(&pid) {
+            if slot.cache_infos == cache_infos {
+                return;
+            }
+        }
+        index.remove(pid);
+
+        let serialized = node.serialize();
+        let body = if let Some(encryption) = &self.encryption {
+            match encryption.encrypt(&serialized) {
+                Ok(sealed) => sealed,
+                Err(e) => {
+                    warn!(
+                        "failed to encrypt pid {} for the secondary cache: \
+                         {:?}",
+                        pid, e
+                    );
+                    return;
+                }
+            }
+        } else {
+            serialized
+        };
+        let len = HEADER_LEN + body.len();
+        let len_u64 = u64::try_from(len).unwrap();
+        if len_u64 > index.capacity {
+            return;
+        }
+        if index.head + len_u64 > index.capacity {
+            index.head = 0;
+        }
+        let offset = index.head;
+        index.evict_range(offset, offset + len_u64);
+
+        let seq = index.next_seq;
+        index.next_seq += 1;
+
+        let mut buf = Vec::with_capacity(len);
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&seq.to_le_bytes());
+        buf.extend_from_slice(&pid.to_le_bytes());
+        let body_len = u32::try_from(body.len()).unwrap();
+        buf.extend_from_slice(&body_len.to_le_bytes());
+        buf.extend_from_slice(&body);
+        let crc = crc32(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+
+        if let Err(e) = pwrite_all(&self.file, &buf, offset) {
+            warn!(
+                "failed to write pid {} to the secondary cache {:?}: {:?}",
+                pid, self.path, e
+            );
+            return;
+        }
+
+        index.head = offset + len_u64;
+        index.by_offset.insert(offset, (offset + len_u64, pid));
+        index.entries.insert(
+            pid,
+            Slot { offset, len, seq, cache_infos: cache_infos.to_vec() },
+        );
+    }
+
+    /// Reads the node of a page, if the cache file has it for the
+    /// page made of `cache_infos`.
+    pub(crate) fn get(
+        &self,
+        pid: PageId,
+        cache_infos: &[CacheInfo],
+    ) -> Option<Node> {
+        let (offset, len, seq) = {
+            let index = self.index.lock();
+            let slot = index.entries.get(&pid)?;
+            if slot.cache_infos != cache_infos {
+                return None;
+            }
+            (slot.offset, slot.len, slot.seq)
+        };
+
+        let mut buf = vec![0; len];
+        if let Err(e) = pread_exact(&self.file, &mut buf, offset) {
+            warn!(
+                "failed to read pid {} from the secondary cache {:?}: {:?}",
+                pid, self.path, e
+            );
+            return None;
+        }
+
+        // the entry may have been overwritten since it was looked up
+        let crc = arr_to_u32(&buf[..4]);
+        if crc != crc32(&buf[4..])
+            || buf[4..12] != seq.to_le_bytes()
+            || buf[12..20] != pid.to_le_bytes()
+        {
+            return None;
+        }
+
+        if let Some(encryption) = &self.encryption {
+            let serialized = encryption.decrypt(&buf[HEADER_LEN..]).ok()?;
+            Node::deserialize(&mut &serialized[..]).ok()
+        } else {
+            Node::deserialize(&mut &buf[HEADER_LEN..]).ok()
+        }
+    }
+}
+
+impl Drop for SecondaryCache {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(
+                "failed to remove the secondary cache {:?}: {:?}",
+                self.path, e
+            );
+        }
+    }
+}
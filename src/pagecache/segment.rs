@@ -90,6 +90,11 @@ pub(crate) struct SegmentAccountant {
 
     // TODO put behind a single mutex
     free: BTreeSet<LogOffset>,
+    // free segments whose blocks were given back to the filesystem
+    punched: BTreeSet<LogOffset>,
+    // the lsn that each free segment was freed at, which
+    // must not be reused until no reader refers to it
+    freed_at: BTreeMap<LogOffset, Lsn>,
     tip: LogOffset,
     max_stabilized_lsn: Lsn,
     segment_cleaner: SegmentCleaner,
@@ -118,6 +123,12 @@ impl SegmentCleaner {
         None
     }
 
+    /// Returns the number of pages that are waiting to be moved.
+    pub(crate) fn pending(&self) -> u64 {
+        let inner = self.inner.lock();
+        inner.values().map(|pids| pids.len() as u64).sum()
+    }
+
     fn add_pids(&self, offset: LogOffset, pids: BTreeSet<PageId>) {
         let mut inner = self.inner.lock();
         let prev = inner.insert(offset, pids);
@@ -175,6 +186,7 @@ struct Inactive {
 #[derive(Debug, Clone, Default)]
 struct Draining {
     lsn: Lsn,
+    rss: usize,
     max_pids: usize,
     replaced_pids: usize,
     latest_replacement_lsn: Lsn,
@@ -272,7 +284,7 @@ impl Segment {
                     heap_id,
                     active.lsn,
                 );
-                config.heap.free(*heap_id);
+                config.readers.free_heap(&config.heap, *heap_id);
             }
 
             let max_pids = active.pids.len();
@@ -314,6 +326,7 @@ impl Segment {
             let ret = mem::take(&mut inactive.pids);
             *self = Segment::Draining(Draining {
                 lsn: inactive.lsn,
+                rss: inactive.rss,
                 max_pids: inactive.max_pids,
                 replaced_pids: inactive.replaced_pids,
                 latest_replacement_lsn: inactive.latest_replacement_lsn,
@@ -420,6 +433,7 @@ impl Segment {
             }
             Segment::Draining(Draining {
                 lsn,
+                rss,
                 latest_replacement_lsn,
                 replaced_pids,
                 ..
@@ -428,6 +442,7 @@ impl Segment {
                 if replacement_lsn != *lsn {
                     *replaced_pids += 1;
                 }
+                *rss = rss.saturating_sub(sz);
                 if replacement_lsn > *latest_replacement_lsn {
                     *latest_replacement_lsn = replacement_lsn;
                 }
@@ -453,7 +468,7 @@ impl Segment {
                      or Draining.",
                     heap_id,
                 );
-                config.heap.free(heap_id);
+                config.readers.free_heap(&config.heap, heap_id);
             }
             Segment::Free(_) => {
                 panic!("remove_heap_item called on a Free Segment")
@@ -483,6 +498,8 @@ impl SegmentAccountant {
             config,
             segments: vec![],
             free: BTreeSet::default(),
+            punched: BTreeSet::default(),
+            freed_at: BTreeMap::default(),
             tip: 0,
             max_stabilized_lsn: -1,
             segment_cleaner,
@@ -490,6 +507,7 @@ impl SegmentAccountant {
             async_truncations: BTreeMap::default(),
         };
 
+        ret.config.readers.set_tip(snapshot.stable_lsn.unwrap_or(0));
         ret.initialize_from_snapshot(snapshot)?;
 
         if let Some(max_free) = ret.free.iter().max() {
@@ -671,7 +689,19 @@ impl SegmentAccountant {
 
         for segment_base in to_free {
             self.free_segment(segment_base)?;
+            if self.config.read_only {
+                continue;
+            }
             io_fail!(self.config, "zero garbage segment SA");
+            // a hole reads back as zeroes too, without taking space
+            if punch_hole(
+                &self.config.file,
+                segment_base,
+                self.config.segment_size as LogOffset,
+            ) {
+                self.punched.insert(segment_base);
+                continue;
+            }
             pwrite_all(
                 &self.config.file,
                 &*vec![MessageKind::Corrupted.into(); self.config.segment_size],
@@ -726,6 +756,7 @@ impl SegmentAccountant {
         assert!(!self.free.contains(&lid), "double-free of a segment occurred");
 
         self.free.insert(lid);
+        self.freed_at.insert(lid, self.config.readers.freed_at());
 
         // remove the old ordering from our list
         if let Segment::Free(Free { previous_lsn: Some(last_lsn) }) =
@@ -743,11 +774,21 @@ impl SegmentAccountant {
         // blocking if we allocate a segment that was just truncated.
         let laziness_factor = 1;
 
+        if self.config.read_only {
+            return Ok(());
+        }
+
+        let min_pin = self.config.readers.min_pin()?;
+
         // truncate if possible
         while self.tip != 0 && self.free.len() > laziness_factor {
             let last_segment = self.tip - self.config.segment_size as LogOffset;
-            if self.free.contains(&last_segment) {
+            if self.free.contains(&last_segment)
+                && self.is_unpinned(last_segment, min_pin)
+            {
                 self.free.remove(&last_segment);
+                self.freed_at.remove(&last_segment);
+                self.punched.remove(&last_segment);
                 self.truncate(last_segment)?;
             } else {
                 break;
@@ -837,7 +878,10 @@ impl SegmentAccountant {
                 } else {
                     // this was migrated off-log and is present and stabilized
                     // in the snapshot.
-                    self.config.heap.free(old_ptr.heap_id().unwrap());
+                    self.config.readers.free_heap(
+                        &self.config.heap,
+                        old_ptr.heap_id().unwrap(),
+                    );
                 }
             }
 
@@ -1115,11 +1159,21 @@ impl SegmentAccountant {
 
         trace!("evaluating free list {:?} in SA::next", &self.free);
 
-        // pop free or add to end
-        let safe = self.free.iter().next().copied();
+        self.config.readers.set_tip(lsn);
+
+        // pop free or add to end, skipping segments that
+        // a reader may still refer to
+        let min_pin = self.config.readers.min_pin()?;
+        let safe = self
+            .free
+            .iter()
+            .find(|lid| self.is_unpinned(**lid, min_pin))
+            .copied();
 
         let (lid, from_tip) = if let Some(next) = safe {
             self.free.remove(&next);
+            self.freed_at.remove(&next);
+            self.punched.remove(&next);
             (next, false)
         } else {
             (self.bump_tip()?, true)
@@ -1148,6 +1202,131 @@ impl SegmentAccountant {
         Ok((lid, from_tip))
     }
 
+    /// Hands every inactive segment whose live bytes are at most
+    /// `max_live_pct` percent of it to the segment cleaner, as
+    /// is done for those below `SEGMENT_CLEANUP_THRESHOLD` when
+    /// pages are replaced. Returns the number of segments.
+    pub(super) fn drain_fragmented(
+        &mut self,
+        max_live_pct: usize,
+    ) -> Result<u64> {
+        let segment_size = self.config.segment_size;
+        let fragmented: Vec<(usize, Lsn)> = self
+            .segments
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, segment)| match segment {
+                Segment::Inactive(inactive)
+                    if inactive.rss * 100 / segment_size <= max_live_pct =>
+                {
+                    Some((idx, inactive.lsn))
+                }
+                _ => None,
+            })
+            .collect();
+
+        for &(idx, lsn) in &fragmented {
+            let segment_start = (idx * segment_size) as LogOffset;
+            trace!(
+                "SA inserting {} into to_clean for compaction",
+                segment_start
+            );
+            let to_clean = self.segments[idx].inactive_to_draining(lsn);
+            self.segment_cleaner.add_pids(segment_start, to_clean);
+
+            // frees the segment if nothing was left in it
+            self.possibly_clean_or_free_segment(idx, lsn)?;
+        }
+
+        Ok(fragmented.len() as u64)
+    }
+
+    /// Truncates the free segments at the end of the file, and
+    /// punches holes in the other free segments, skipping those
+    /// that a reader may still refer to. Returns the number of
+    /// bytes that were given back to the filesystem.
+    pub(super) fn shrink_to_fit(&mut self) -> Result<u64> {
+        if self.config.read_only {
+            return Ok(0);
+        }
+
+        let segment_size = self.config.segment_size as LogOffset;
+        let min_pin = self.config.readers.min_pin()?;
+        let mut shrunk = 0;
+
+        while self.tip != 0 {
+            let last_segment = self.tip - segment_size;
+            if !self.free.contains(&last_segment)
+                || !self.is_unpinned(last_segment, min_pin)
+            {
+                break;
+            }
+            self.free.remove(&last_segment);
+            self.freed_at.remove(&last_segment);
+            if !self.punched.remove(&last_segment) {
+                shrunk += segment_size;
+            }
+            self.truncate(last_segment)?;
+        }
+
+        for (_, promise) in self.async_truncations.split_off(&0) {
+            promise.wait().expect("threadpool should not crash")?;
+        }
+
+        let to_punch: Vec<LogOffset> = self
+            .free
+            .iter()
+            .filter(|lid| {
+                !self.punched.contains(lid) && self.is_unpinned(**lid, min_pin)
+            })
+            .copied()
+            .collect();
+
+        for lid in to_punch {
+            if !punch_hole(&self.config.file, lid, segment_size) {
+                break;
+            }
+            self.punched.insert(lid);
+            shrunk += segment_size;
+        }
+
+        Ok(shrunk)
+    }
+
+    /// Returns the state and the number of live bytes of every
+    /// segment, as they are tracked for choosing the segments
+    /// to clean.
+    pub(super) fn segment_space(&self) -> Vec<SegmentSpace> {
+        let segment_size = self.config.segment_size as u64;
+
+        // segments past the tip were truncated away
+        let in_file = assert_usize(self.tip / segment_size);
+        let segments = self.segments.iter().take(in_file).enumerate();
+        let spaces = segments.map(|(idx, segment)| {
+            let (state, rss) = match segment {
+                Segment::Free(_) => (SegmentState::Free, 0),
+                Segment::Active(active) => (
+                    SegmentState::Active,
+                    active.rss.saturating_sub(active.deferred_replaced_rss),
+                ),
+                Segment::Inactive(inactive) => {
+                    (SegmentState::Inactive, inactive.rss)
+                }
+                Segment::Draining(draining) => {
+                    (SegmentState::Draining, draining.rss)
+                }
+            };
+            let live = std::cmp::min(rss as u64, segment_size);
+            SegmentSpace {
+                offset: idx as u64 * segment_size,
+                state,
+                live,
+                dead: segment_size - live,
+            }
+        });
+        spaces.collect()
+    }
+
     /// Returns an iterator over a snapshot of current segment
     /// log sequence numbers and their corresponding file offsets.
     pub(super) fn segment_snapshot_iter_from(
@@ -1179,6 +1358,10 @@ impl SegmentAccountant {
             .collect()
     }
 
+    fn is_unpinned(&self, lid: LogOffset, min_pin: Option<Lsn>) -> bool {
+        min_pin.map_or(true, |pin| self.freed_at[&lid] <= pin)
+    }
+
     // truncate the file to the desired length
     fn truncate(&mut self, at: LogOffset) -> Result<()> {
         trace!("asynchronously truncating file to length {}", at);
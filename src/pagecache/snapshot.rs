@@ -378,8 +378,10 @@ fn advance_snapshot(
                     shred_base,
                     shred_base + shred_len as LogOffset
                 );
-                pwrite_all(&config.file, &shred_zone, shred_base)?;
-                config.file.sync_all()?;
+                if !config.read_only {
+                    pwrite_all(&config.file, &shred_zone, shred_base)?;
+                    config.file.sync_all()?;
+                }
             }
             (iterated_lsn, iter.segment_base.map(|bb| bb.offset))
         };
@@ -408,7 +410,7 @@ fn advance_snapshot(
         return Err(Error::corruption(None));
     }
 
-    if snapshot.stable_lsn > old_stable_lsn {
+    if snapshot.stable_lsn > old_stable_lsn && !config.read_only {
         write_snapshot(config, &snapshot)?;
     }
 
@@ -445,6 +447,12 @@ fn advance_snapshot(
     };
 
     for (lsn, to_zero) in &iter.segments {
+        if config.read_only {
+            // recovery ignores torn segments whether
+            // or not they have been zeroed.
+            break;
+        }
+
         debug!("zeroing torn segment at lsn {} lid {}", lsn, to_zero);
 
         #[cfg(feature = "testing")]
@@ -486,15 +494,35 @@ fn advance_snapshot(
 /// the tip of the data file, if present.
 pub fn read_snapshot_or_default(config: &RunningConfig) -> Result<Snapshot> {
     // NB we want to error out if the read snapshot was corrupted.
-    // We only use a default Snapshot when there is no snapshot found.
+    // We only use a default Snapshot when there is no snapshot found,
+    // or when salvaging, in which case the whole log is replayed.
+    let salvage = config.recovery_mode == RecoveryMode::Salvage;
+    match read_and_advance_snapshot(config) {
+        Err(e @ Error::Corruption { .. }) if salvage => {
+            config.report_fault(None, &e, false);
+            warn!(
+                "snapshot can't be recovered because of {}, \
+                 rebuilding it from the whole log",
+                e
+            );
+            let log_iter = raw_segment_iter_from(0, config)?;
+            advance_snapshot(log_iter, Snapshot::default(), config)
+        }
+        Err(e) if fault::is_fault(&e) => {
+            config.report_fault(None, &e, true);
+            Err(e)
+        }
+        other => other,
+    }
+}
+
+fn read_and_advance_snapshot(config: &RunningConfig) -> Result<Snapshot> {
     let last_snap = read_snapshot(config)?.unwrap_or_else(Snapshot::default);
 
     let log_iter =
         raw_segment_iter_from(last_snap.stable_lsn.unwrap_or(0), config)?;
 
-    let res = advance_snapshot(log_iter, last_snap, config)?;
-
-    Ok(res)
+    advance_snapshot(log_iter, last_snap, config)
 }
 
 /// Read a `Snapshot` from disk.
@@ -540,6 +568,12 @@ fn read_snapshot(config: &RunningConfig) -> Result<Option<Snapshot>> {
         return Err(Error::corruption(None));
     }
 
+    let buf = if let Some(encryption) = &config.encryption {
+        encryption.decrypt(&buf)?
+    } else {
+        buf
+    };
+
     #[cfg(feature = "zstd")]
     let bytes = if config.use_compression {
         use std::convert::TryInto;
@@ -564,6 +598,10 @@ pub(in crate::pagecache) fn write_snapshot(
 ) -> Result<()> {
     trace!("writing snapshot {:?}", snapshot);
 
+    // `Db::checkpoint` must observe either the old or
+    // the new set of snapshot files, never a mix
+    let _io_barrier = config.io_barrier.read();
+
     let raw_bytes = snapshot.serialize();
     let decompressed_len = raw_bytes.len();
 
@@ -577,6 +615,12 @@ pub(in crate::pagecache) fn write_snapshot(
     #[cfg(not(feature = "zstd"))]
     let bytes = raw_bytes;
 
+    let bytes = if let Some(encryption) = &config.encryption {
+        encryption.encrypt(&bytes)?
+    } else {
+        bytes
+    };
+
     let crc32: [u8; 4] = u32_to_arr(crc32(&bytes));
     let len_bytes: [u8; 8] = u64_to_arr(decompressed_len as u64);
 
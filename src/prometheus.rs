@@ -0,0 +1,308 @@
+//! An exporter of the metrics of a `Db` in the Prometheus text
+//! exposition format, see `Db::prometheus_metrics`.
+//!
+//! The output is rendered by hand rather than through a client
+//! library, so that it can be served from whatever HTTP server an
+//! application already runs, and scraped next to metrics that are
+//! registered with any of them. It combines:
+//!
+//! * the counters of `Db::metrics`, which belong to the `Db`
+//! * the statistics of `Db::tree_stats`, labeled by tree
+//! * the latency histograms of the `metrics` feature, which are
+//!   shared by every `Db` of the process, as summaries
+use std::fmt::Write;
+
+use crate::*;
+
+const QUANTILES: [f64; 4] = [0.5, 0.9, 0.99, 0.999];
+
+struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.out, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.out, "# TYPE {} {}", name, kind).unwrap();
+    }
+
+    fn sample<V: fmt::Display>(&mut self, name: &str, labels: &str, value: V) {
+        if labels.is_empty() {
+            writeln!(self.out, "{} {}", name, value).unwrap();
+        } else {
+            writeln!(self.out, "{}{{{}}} {}", name, labels, value).unwrap();
+        }
+    }
+
+    fn single<V: fmt::Display>(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        value: V,
+    ) {
+        self.family(name, kind, help);
+        self.sample(name, "", value);
+    }
+
+    fn per_tree<V: fmt::Display, F: Fn(&TreeMetrics, &TreeStats) -> V>(
+        &mut self,
+        trees: &[(TreeMetrics, TreeStats)],
+        name: &str,
+        kind: &str,
+        help: &str,
+        value: F,
+    ) {
+        self.family(name, kind, help);
+        for (metrics, stats) in trees {
+            let labels = format!("tree=\"{}\"", label_value(&metrics.tree));
+            self.sample(name, &labels, value(metrics, stats));
+        }
+    }
+
+    // histograms are measured in nanoseconds and exported in
+    // seconds, with `NaN` quantiles until they measure anything
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::float_arithmetic)]
+    fn summary(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.family(name, "summary", help);
+        for quantile in &QUANTILES {
+            let labels = format!("quantile=\"{}\"", quantile);
+            let nanos = histogram.percentile(quantile * 100.);
+            self.sample(name, &labels, nanos / 1e9);
+        }
+        let sum = histogram.sum() as f64 / 1e9;
+        self.sample(&format!("{}_sum", name), "", sum);
+        self.sample(&format!("{}_count", name), "", histogram.count());
+    }
+}
+
+// escapes a tree name for a label value, replacing the bytes that
+// aren't valid UTF-8
+fn label_value(name: &[u8]) -> String {
+    let mut ret = String::with_capacity(name.len());
+    for c in String::from_utf8_lossy(name).chars() {
+        match c {
+            '\\' => ret.push_str("\\\\"),
+            '"' => ret.push_str("\\\""),
+            '\n' => ret.push_str("\\n"),
+            other => ret.push(other),
+        }
+    }
+    ret
+}
+
+/// Renders the metrics of a `Db`.
+pub(crate) fn render(db: &Db) -> Result<String> {
+    let metrics = db.metrics()?;
+    let stats = db.tree_stats()?;
+    // trees may be opened or dropped between the two snapshots
+    let trees: Vec<(TreeMetrics, TreeStats)> = stats
+        .into_iter()
+        .filter_map(|tree_stats| {
+            let tree_metrics =
+                metrics.trees.iter().find(|m| m.tree == tree_stats.tree)?;
+            Some((tree_metrics.clone(), tree_stats))
+        })
+        .collect();
+
+    let mut exposition = Exposition { out: String::new() };
+    let e = &mut exposition;
+
+    e.single(
+        "sled_user_bytes_written_total",
+        "counter",
+        "Bytes of keys and values written to every tree.",
+        metrics.user_bytes_written,
+    );
+    e.single(
+        "sled_log_bytes_written_total",
+        "counter",
+        "Bytes written to the log.",
+        metrics.log_bytes_written,
+    );
+    e.single(
+        "sled_heap_bytes_written_total",
+        "counter",
+        "Bytes written to the heap files.",
+        metrics.heap_bytes_written,
+    );
+    e.single(
+        "sled_write_amplification",
+        "gauge",
+        "Bytes written to the storage files per byte of user data.",
+        metrics.write_amplification(),
+    );
+    e.single("sled_gets_total", "counter", "Calls to Tree::get.", metrics.gets);
+    e.single(
+        "sled_pages_read_total",
+        "counter",
+        "Pages read from the storage files.",
+        metrics.pages_read,
+    );
+    e.single(
+        "sled_read_amplification",
+        "gauge",
+        "Pages read from the storage files per get.",
+        metrics.read_amplification(),
+    );
+    e.single(
+        "sled_cache_hits_total",
+        "counter",
+        "Pages found in the page cache.",
+        metrics.cache_hits,
+    );
+    e.single(
+        "sled_compressed_cache_hits_total",
+        "counter",
+        "Pages found in the compressed tier of the page cache.",
+        metrics.compressed_cache_hits,
+    );
+    e.single(
+        "sled_secondary_cache_hits_total",
+        "counter",
+        "Pages read from the secondary cache.",
+        metrics.secondary_cache_hits,
+    );
+    e.single(
+        "sled_cache_misses_total",
+        "counter",
+        "Pages read into the page cache.",
+        metrics.cache_misses,
+    );
+    e.single(
+        "sled_cache_hit_ratio",
+        "gauge",
+        "Share of the pages that were found in the page cache.",
+        metrics.cache_hit_rate(),
+    );
+    e.single(
+        "sled_gc_pages_rewritten_total",
+        "counter",
+        "Pages rewritten by the garbage collector.",
+        metrics.gc_pages_rewritten,
+    );
+    e.single(
+        "sled_gc_bytes_rewritten_total",
+        "counter",
+        "Bytes rewritten by the garbage collector.",
+        metrics.gc_bytes_rewritten,
+    );
+    e.single(
+        "sled_size_on_disk_bytes",
+        "gauge",
+        "Bytes taken by the storage files.",
+        metrics.size_on_disk,
+    );
+    e.single(
+        "sled_live_bytes",
+        "gauge",
+        "Estimated bytes of the keys and values of every tree.",
+        metrics.live_bytes,
+    );
+    e.single(
+        "sled_space_amplification",
+        "gauge",
+        "Bytes taken by the storage files per live byte.",
+        metrics.space_amplification(),
+    );
+
+    e.per_tree(
+        &trees,
+        "sled_tree_writes_total",
+        "counter",
+        "Keys set or removed in the tree.",
+        |tree, _| tree.writes,
+    );
+    e.per_tree(
+        &trees,
+        "sled_tree_user_bytes_written_total",
+        "counter",
+        "Bytes of keys and values written to the tree.",
+        |tree, _| tree.user_bytes_written,
+    );
+    e.per_tree(
+        &trees,
+        "sled_tree_gets_total",
+        "counter",
+        "Calls to Tree::get on the tree.",
+        |tree, _| tree.gets,
+    );
+    e.per_tree(
+        &trees,
+        "sled_tree_pages_read_total",
+        "counter",
+        "Pages read from the storage files by gets on the tree.",
+        |tree, _| tree.pages_read,
+    );
+    e.per_tree(
+        &trees,
+        "sled_tree_keys",
+        "gauge",
+        "Keys in the tree, estimated until they are counted.",
+        |_, tree| tree.len,
+    );
+    e.per_tree(
+        &trees,
+        "sled_tree_live_bytes",
+        "gauge",
+        "Estimated bytes of the keys and values of the tree.",
+        |_, tree| tree.live_bytes,
+    );
+    e.per_tree(
+        &trees,
+        "sled_tree_dead_bytes",
+        "gauge",
+        "Bytes taken by the nodes of the tree beyond its live bytes.",
+        |_, tree| tree.dead_bytes,
+    );
+    e.per_tree(
+        &trees,
+        "sled_tree_nodes",
+        "gauge",
+        "Nodes of the tree.",
+        |_, tree| tree.nodes,
+    );
+    e.per_tree(
+        &trees,
+        "sled_tree_cached_nodes",
+        "gauge",
+        "Nodes of the tree in the page cache.",
+        |_, tree| tree.cached_nodes,
+    );
+
+    e.summary(
+        "sled_flush_seconds",
+        "Latency of making the log durable, for every Db of the process.",
+        &M.make_stable,
+    );
+    e.summary(
+        "sled_log_write_seconds",
+        "Latency of writing a buffer to the log, for every Db of the process.",
+        &M.write_to_log,
+    );
+    e.summary(
+        "sled_page_in_seconds",
+        "Latency of reading a page into the cache, for every Db of the \
+         process.",
+        &M.pull,
+    );
+    e.summary(
+        "sled_gc_rewrite_seconds",
+        "Latency of rewriting a page for the garbage collector, for every \
+         Db of the process.",
+        &M.rewrite_page,
+    );
+    e.summary(
+        "sled_get_seconds",
+        "Latency of Tree::get, for every Db of the process.",
+        &M.tree_get,
+    );
+    e.summary(
+        "sled_insert_seconds",
+        "Latency of Tree::insert, for every Db of the process.",
+        &M.tree_set,
+    );
+
+    Ok(exposition.out)
+}
@@ -0,0 +1,302 @@
+//! Limits on the size of a `Tree` and on the rate that it is
+//! written at, see `Tree::set_quota`.
+//!
+//! The limits are checked before a write takes the concurrency
+//! control, so that a throttled write doesn't block the writes
+//! to other trees, and so that keys can be evicted by regular
+//! removals. Writes that race with each other are checked
+//! against the same state, so they may exceed a limit by about
+//! as much as they write together.
+//!
+//! The number of keys is the count of `Tree::len`, which is
+//! kept up to date by every write. The number of bytes is
+//! estimated with `Tree::size_of_range` every `REFRESH_WRITES`
+//! writes, and every write in between adds the size of its key
+//! and value to the estimate, without subtracting what it
+//! replaces. The estimate is refreshed before a write is found
+//! to exceed the limit, so the limit is as exact as the
+//! estimates of `Tree::size_of_range` are.
+use std::time::{Duration, Instant};
+
+use crate::*;
+
+// the number of writes after which the estimated size of a tree
+// is refreshed
+const REFRESH_WRITES: u64 = 64;
+
+/// Limits on the size of a `Tree` and on the rate that it is
+/// written at, see `Tree::set_quota`. The default quota has no
+/// limits.
+#[derive(Clone, Default)]
+pub struct Quota {
+    /// The maximum number of bytes of the keys and values of the
+    /// `Tree`, which is estimated like with `Tree::size_of_range`.
+    pub max_bytes: Option<u64>,
+    /// The maximum number of keys in the `Tree`.
+    pub max_keys: Option<u64>,
+    /// The maximum number of writes per second. Writes above it
+    /// wait until they are within it, whatever the `action`.
+    pub max_writes_per_sec: Option<u64>,
+    /// What happens when a write would exceed `max_bytes` or
+    /// `max_keys`.
+    pub action: QuotaAction,
+}
+
+impl Debug for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Quota")
+            .field("max_bytes", &self.max_bytes)
+            .field("max_keys", &self.max_keys)
+            .field("max_writes_per_sec", &self.max_writes_per_sec)
+            .field("action", &self.action)
+            .finish()
+    }
+}
+
+/// The limit of a `Quota` that a write would exceed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaLimit {
+    /// `Quota::max_bytes`.
+    Bytes,
+    /// `Quota::max_keys`.
+    Keys,
+}
+
+/// The function that `QuotaAction::Callback` calls with the
+/// `Tree` and the limit that a write would exceed. The write is
+/// made if it returns `Ok`, and fails with its error otherwise.
+pub type QuotaCallback =
+    Arc<dyn Fn(&Tree, QuotaLimit) -> Result<()> + Send + Sync>;
+
+/// What happens when a write would exceed the size limits of a
+/// `Quota`. Removals are never limited.
+#[derive(Clone)]
+pub enum QuotaAction {
+    /// The write fails with `Error::QuotaExceeded`.
+    Error,
+    /// The first keys of the `Tree` are removed until the write
+    /// is within the limits, which for keys that grow over time,
+    /// like timestamps or the IDs of `Db::generate_id`, are the
+    /// oldest ones. Subscribers receive a removal event for each
+    /// of them.
+    EvictOldest,
+    /// The callback is called, and decides whether the write is
+    /// made. It must not insert into the `Tree`, which would
+    /// call it again.
+    Callback(QuotaCallback),
+}
+
+impl Default for QuotaAction {
+    fn default() -> QuotaAction {
+        QuotaAction::Error
+    }
+}
+
+impl Debug for QuotaAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaAction::Error => write!(f, "Error"),
+            QuotaAction::EvictOldest => write!(f, "EvictOldest"),
+            QuotaAction::Callback(_) => write!(f, "Callback(..)"),
+        }
+    }
+}
+
+/// The quota of a tree and the state that it is checked with.
+pub(crate) struct Limiter {
+    quota: Quota,
+    // the estimated number of bytes of the tree
+    bytes: AtomicU64,
+    // the number of writes since the estimate was refreshed
+    writes: AtomicU64,
+    // the time before which the next write may not start
+    next_write: Mutex<Instant>,
+}
+
+impl Debug for Limiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Limiter").field("quota", &self.quota).finish()
+    }
+}
+
+/// Sets or removes the quota of a tree, see `Tree::set_quota`.
+pub(crate) fn set(tree: &Tree, quota: Option<Quota>) -> Result<()> {
+    let new_quota = if let Some(new_quota) = quota {
+        new_quota
+    } else {
+        *tree.quota.write() = None;
+        return Ok(());
+    };
+    if new_quota.max_writes_per_sec == Some(0) {
+        return Err(Error::Unsupported(
+            "the write rate of a quota must be above 0".into(),
+        ));
+    }
+
+    let (bytes, _) = tree.size_of_range::<&[u8], _>(..)?;
+    let limiter = Limiter {
+        quota: new_quota,
+        bytes: AtomicU64::new(bytes),
+        writes: AtomicU64::new(0),
+        next_write: Mutex::new(Instant::now()),
+    };
+    *tree.quota.write() = Some(Arc::new(limiter));
+    Ok(())
+}
+
+/// Checks the writes of keys and the sizes of their values
+/// against the quota of a tree, before the writes take the
+/// concurrency control. Waits for the write rate, and evicts
+/// keys or calls the callback of the quota if they would exceed
+/// one of its limits.
+pub(crate) fn check<'a, I>(tree: &Tree, writes: I) -> Result<()>
+where
+    I: IntoIterator<Item = (&'a [u8], usize)> + Clone,
+{
+    let limiter = if let Some(limiter) = tree.quota.read().clone() {
+        limiter
+    } else {
+        return Ok(());
+    };
+    let quota = &limiter.quota;
+
+    if let Some(rate) = quota.max_writes_per_sec {
+        throttle(&limiter, rate);
+    }
+    if quota.max_bytes.is_none() && quota.max_keys.is_none() {
+        return Ok(());
+    }
+
+    let added_bytes: u64 = writes
+        .clone()
+        .into_iter()
+        .map(|(key, value_len)| (key.len() + value_len) as u64)
+        .sum();
+    let added_keys = added_keys(tree, quota, writes)?;
+
+    let mut refreshed = false;
+    loop {
+        let limit = if let Some(limit) =
+            exceeded(tree, &limiter, added_bytes, added_keys)?
+        {
+            limit
+        } else {
+            break;
+        };
+        if limit == QuotaLimit::Bytes && !refreshed {
+            // the estimate may be too large until it's refreshed
+            refresh(tree, &limiter)?;
+            refreshed = true;
+            continue;
+        }
+        match &quota.action {
+            QuotaAction::Error => {
+                return Err(Error::QuotaExceeded {
+                    tree: tree.tree_id.clone(),
+                    limit,
+                });
+            }
+            QuotaAction::Callback(callback) => {
+                callback(tree, limit)?;
+                break;
+            }
+            QuotaAction::EvictOldest => {
+                if let Some((key, value)) = tree.pop_min()? {
+                    let evicted = (key.len() + value.len()) as u64;
+                    let mut current = limiter.bytes.load(SeqCst);
+                    loop {
+                        let last = limiter.bytes.compare_exchange_weak(
+                            current,
+                            current.saturating_sub(evicted),
+                            SeqCst,
+                            SeqCst,
+                        );
+                        if let Err(actual) = last {
+                            current = actual;
+                        } else {
+                            break;
+                        }
+                    }
+                } else {
+                    // the write would exceed the limit on its own
+                    return Err(Error::QuotaExceeded {
+                        tree: tree.tree_id.clone(),
+                        limit,
+                    });
+                }
+            }
+        }
+    }
+
+    if limiter.writes.fetch_add(1, SeqCst) + 1 >= REFRESH_WRITES {
+        refresh(tree, &limiter)?;
+    }
+    let _ = limiter.bytes.fetch_add(added_bytes, SeqCst);
+
+    Ok(())
+}
+
+// estimates the number of bytes of a tree again
+fn refresh(tree: &Tree, limiter: &Limiter) -> Result<()> {
+    limiter.writes.store(0, SeqCst);
+    let (bytes, _) = tree.size_of_range::<&[u8], _>(..)?;
+    limiter.bytes.store(bytes, SeqCst);
+    Ok(())
+}
+
+// paces the writes of a tree to `rate` per second
+fn throttle(limiter: &Limiter, rate: u64) {
+    let interval = Duration::from_nanos(1_000_000_000 / rate);
+    let now = Instant::now();
+    let start = {
+        let mut next_write = limiter.next_write.lock();
+        let start = std::cmp::max(*next_write, now);
+        *next_write = start + interval;
+        start
+    };
+    if start > now {
+        std::thread::sleep(start - now);
+    }
+}
+
+// the number of the written keys that don't exist yet, which is
+// only looked up if the tree has a key limit
+fn added_keys<'a, I>(tree: &Tree, quota: &Quota, writes: I) -> Result<u64>
+where
+    I: IntoIterator<Item = (&'a [u8], usize)>,
+{
+    if quota.max_keys.is_none() {
+        return Ok(0);
+    }
+    let mut added = 0;
+    for (key, _) in writes {
+        if !tree.contains_key(key)? {
+            added += 1;
+        }
+    }
+    Ok(added)
+}
+
+// returns the limit that writing `added_bytes` and `added_keys`
+// would exceed, if any
+fn exceeded(
+    tree: &Tree,
+    limiter: &Limiter,
+    added_bytes: u64,
+    added_keys: u64,
+) -> Result<Option<QuotaLimit>> {
+    let quota = &limiter.quota;
+    if let Some(max_keys) = quota.max_keys {
+        let keys = tree.counted_len() as u64;
+        if added_keys > 0 && keys + added_keys > max_keys {
+            return Ok(Some(QuotaLimit::Keys));
+        }
+    }
+    if let Some(max_bytes) = quota.max_bytes {
+        let bytes = limiter.bytes.load(SeqCst);
+        if bytes + added_bytes > max_bytes {
+            return Ok(Some(QuotaLimit::Bytes));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,181 @@
+//! Estimates of the size of a range of keys, see
+//! `Tree::size_of_range`.
+//!
+//! Only the nodes on the paths to the bounds of the range are
+//! read in full. The size of each subtree that falls entirely
+//! inside of the range is estimated from the fanout of the
+//! nodes along a single path down it, to the leaf in the middle
+//! of each of them, assuming that their siblings are about as
+//! large.
+use std::ops::Bound;
+
+use crate::*;
+
+/// The approximate size of the keys and values of a subtree.
+#[derive(Debug, Default, Clone, Copy)]
+struct Estimate {
+    bytes: u64,
+    keys: u64,
+}
+
+impl Estimate {
+    fn add(&mut self, other: Estimate, times: u64) {
+        self.bytes =
+            self.bytes.saturating_add(other.bytes.saturating_mul(times));
+        self.keys = self.keys.saturating_add(other.keys.saturating_mul(times));
+    }
+}
+
+/// Returns the approximate bytes and keys that are stored
+/// between the encoded bounds of a range.
+pub(crate) fn estimate(
+    tree: &Tree,
+    lo: &Bound<IVec>,
+    hi: &Bound<IVec>,
+) -> Result<(u64, u64)> {
+    let guard = pin();
+    let root = tree.root.load(Acquire);
+    let estimate = partial(tree, root, lo, hi, &guard)?;
+    Ok((estimate.bytes, estimate.keys))
+}
+
+fn partial(
+    tree: &Tree,
+    pid: PageId,
+    lo: &Bound<IVec>,
+    hi: &Bound<IVec>,
+    guard: &Guard,
+) -> Result<Estimate> {
+    let mut ret = Estimate::default();
+    let node_view =
+        if let Some(node_view) = tree.context.pagecache.get(pid, guard)? {
+            node_view
+        } else {
+            return Ok(ret);
+        };
+
+    if !node_view.is_index {
+        for (key, value) in node_view.decoded_items() {
+            if above(lo, &key) && below(hi, &key) {
+                ret.add(item(tree, &key, value), 1);
+            }
+        }
+        return Ok(ret);
+    }
+
+    let children: Vec<(IVec, PageId)> =
+        node_view.decoded_keys().zip(node_view.iter_index_pids()).collect();
+
+    let mut covered = 0;
+    let mut sample = None;
+    for (idx, (child_lo, child)) in children.iter().enumerate() {
+        let child_hi = match children.get(idx + 1) {
+            Some((next_lo, _)) => Some(&**next_lo),
+            None => node_view.hi(),
+        };
+
+        if covers(lo, hi, child_lo, child_hi) {
+            covered += 1;
+            if covered == 1 {
+                sample = Some(*child);
+            }
+        } else if overlaps(lo, hi, child_lo, child_hi) {
+            ret.add(partial(tree, *child, lo, hi, guard)?, 1);
+        }
+    }
+
+    if let Some(sampled) = sample {
+        ret.add(full(tree, sampled, guard)?, covered);
+    }
+
+    Ok(ret)
+}
+
+fn full(tree: &Tree, pid: PageId, guard: &Guard) -> Result<Estimate> {
+    let mut ret = Estimate::default();
+    let mut subtrees = 1_u64;
+    let mut cursor = pid;
+    loop {
+        let node_view = if let Some(node_view) =
+            tree.context.pagecache.get(cursor, guard)?
+        {
+            node_view
+        } else {
+            return Ok(ret);
+        };
+
+        if !node_view.is_index {
+            let mut leaf = Estimate::default();
+            for (key, value) in node_view.decoded_items() {
+                leaf.add(item(tree, &key, value), 1);
+            }
+            ret.add(leaf, subtrees);
+            return Ok(ret);
+        }
+
+        let children: Vec<PageId> = node_view.iter_index_pids().collect();
+        if children.is_empty() {
+            return Ok(ret);
+        }
+        subtrees = subtrees
+            .saturating_mul(u64::try_from(children.len()).unwrap());
+        cursor = children[children.len() / 2];
+    }
+}
+
+fn item(tree: &Tree, key: &[u8], value: &[u8]) -> Estimate {
+    let key_len = u64::try_from(key.len()).unwrap();
+    Estimate {
+        bytes: key_len.saturating_add(value_log::stored_len(tree, value)),
+        keys: 1,
+    }
+}
+
+fn above(lo: &Bound<IVec>, key: &[u8]) -> bool {
+    match lo {
+        Bound::Included(bound) => key >= &**bound,
+        Bound::Excluded(bound) => key > &**bound,
+        Bound::Unbounded => true,
+    }
+}
+
+fn below(hi: &Bound<IVec>, key: &[u8]) -> bool {
+    match hi {
+        Bound::Included(bound) => key <= &**bound,
+        Bound::Excluded(bound) => key < &**bound,
+        Bound::Unbounded => true,
+    }
+}
+
+// returns `true` if every key from `child_lo` up to `child_hi`
+// is inside of the range
+fn covers(
+    lo: &Bound<IVec>,
+    hi: &Bound<IVec>,
+    child_lo: &[u8],
+    child_hi: Option<&[u8]>,
+) -> bool {
+    let covers_hi = match (hi, child_hi) {
+        (Bound::Unbounded, _) => true,
+        (Bound::Included(bound), Some(end))
+        | (Bound::Excluded(bound), Some(end)) => end <= &**bound,
+        (_, None) => false,
+    };
+    covers_hi && above(lo, child_lo)
+}
+
+// returns `true` if some key from `child_lo` up to `child_hi`
+// may be inside of the range
+pub(crate) fn overlaps(
+    lo: &Bound<IVec>,
+    hi: &Bound<IVec>,
+    child_lo: &[u8],
+    child_hi: Option<&[u8]>,
+) -> bool {
+    let overlaps_lo = match (lo, child_hi) {
+        (Bound::Unbounded, _) | (_, None) => true,
+        (Bound::Included(bound), Some(end))
+        | (Bound::Excluded(bound), Some(end)) => &**bound < end,
+    };
+    overlaps_lo && below(hi, child_lo)
+}
@@ -10,7 +10,7 @@ use backtrace::Backtrace;
 
 use crate::{
     pagecache::{DiskPtr, PageView},
-    IVec,
+    IVec, QuotaLimit,
 };
 
 /// The top-level result type for dealing with
@@ -43,6 +43,20 @@ pub enum Error {
     /// A read or write error has happened when interacting with the file
     /// system.
     Io(io::Error),
+    /// The storage device is full. Writes return this error
+    /// until enough space has been freed for the buffered writes
+    /// to be written, which is retried in the background, while
+    /// reads keep working. If no space is freed within a minute
+    /// the `Db` stops, and every later operation returns it.
+    NoSpace,
+    /// A write would exceed a limit of the `Quota` of a tree,
+    /// see `Tree::set_quota`.
+    QuotaExceeded {
+        /// The name of the tree.
+        tree: IVec,
+        /// The limit that the write would exceed.
+        limit: QuotaLimit,
+    },
     /// Corruption has been detected in the storage file.
     Corruption {
         /// The file location that corrupted data was found at.
@@ -81,6 +95,10 @@ impl Clone for Error {
             CollectionNotFound(name) => CollectionNotFound(name.clone()),
             Unsupported(why) => Unsupported(why.clone()),
             ReportableBug(what) => ReportableBug(what.clone()),
+            NoSpace => NoSpace,
+            QuotaExceeded { tree, limit } => {
+                QuotaExceeded { tree: tree.clone(), limit: *limit }
+            }
             Corruption { at, bt } => Corruption { at: *at, bt: bt.clone() },
             #[cfg(feature = "failpoints")]
             FailPoint => FailPoint,
@@ -116,6 +134,20 @@ impl PartialEq for Error {
                     false
                 }
             }
+            NoSpace => {
+                if let NoSpace = *other {
+                    true
+                } else {
+                    false
+                }
+            }
+            QuotaExceeded { tree: ref l, limit: ll } => {
+                if let QuotaExceeded { tree: ref r, limit: rl } = *other {
+                    l == r && ll == rl
+                } else {
+                    false
+                }
+            }
             #[cfg(feature = "failpoints")]
             FailPoint => {
                 if let FailPoint = *other {
@@ -164,6 +196,14 @@ impl From<Error> for io::Error {
                     what
                 ),
             ),
+            NoSpace => io::Error::new(
+                ErrorKind::Other,
+                "no space left on the storage device",
+            ),
+            QuotaExceeded { tree, limit } => io::Error::new(
+                ErrorKind::Other,
+                format!("quota of tree {:?} exceeded: {:?}", tree, limit),
+            ),
             Corruption { .. } => io::Error::new(
                 ErrorKind::InvalidData,
                 format!("corruption encountered: {:?}", error),
@@ -197,6 +237,10 @@ impl Display for Error {
             #[cfg(feature = "failpoints")]
             FailPoint => write!(f, "Fail point has been triggered."),
             Io(ref e) => write!(f, "IO error: {}", e),
+            NoSpace => write!(f, "No space left on the storage device"),
+            QuotaExceeded { ref tree, limit } => {
+                write!(f, "Quota of tree {:?} exceeded: {:?}", tree, limit)
+            }
             Corruption { at, ref bt } => write!(
                 f,
                 "Read corrupted data at file offset {:?} backtrace {:?}",
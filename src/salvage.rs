@@ -0,0 +1,320 @@
+//! Rebuilding the trees of a `Db` that have nodes that can't be
+//! read, see `RecoveryMode::Salvage`.
+//!
+//! Every tree is walked from its root, following each child of
+//! an index node for the range of keys that the index node gives
+//! it, and the sibling links of nodes that end before their range
+//! does, as they do while a split hasn't reached the parent. The
+//! range of a node that can't be read is lost, and so are the
+//! keys of the readable nodes below it. A damaged tree is then
+//! walked a second time, loading the keys that could be read
+//! into new nodes as `Tree::bulk_load` does, and its root is
+//! swapped for the new one before every page of the old tree is
+//! freed without being read again.
+use std::fmt;
+
+use crate::*;
+
+/// A range of keys of a tree that was lost because the nodes
+/// holding them couldn't be read, see `Db::lost_ranges`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LostRange {
+    /// The name of the tree.
+    pub tree: IVec,
+    /// The first key of the range.
+    pub start: IVec,
+    /// The key that the range ends before, or `None` if it
+    /// reaches the end of the tree.
+    pub end: Option<IVec>,
+}
+
+impl fmt::Display for LostRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "tree {:?} lost the keys from {:?} ",
+            String::from_utf8_lossy(&self.tree),
+            self.start
+        )?;
+        match self.end {
+            Some(ref end) => write!(f, "up to {:?}", end),
+            None => write!(f, "to the end"),
+        }
+    }
+}
+
+/// Rebuilds a tree from the nodes that can be read if any can't
+/// be, adding the key ranges that were lost to `lost`. Must be
+/// called before the tree may be written to.
+pub(crate) fn salvage_tree(
+    name: &IVec,
+    tree: &Tree,
+    lost: &mut Vec<LostRange>,
+) -> Result<()> {
+    let root = tree.root.load(Acquire);
+
+    let mut check = Walk::new(tree, None);
+    check.visit(root, IVec::default(), &None)?;
+    if check.lost.is_empty() {
+        return Ok(());
+    }
+
+    for (lo, hi) in &check.lost {
+        let range = LostRange {
+            tree: name.clone(),
+            start: tree.order.decode(lo.clone()),
+            end: hi.clone().map(|end| tree.order.decode(end)),
+        };
+        error!("salvaging a damaged tree: {}", range);
+        lost.push(range);
+    }
+
+    let mut rebuild = Walk::new(tree, Some(bulk_load::Loader::new(tree)?));
+    let walked = rebuild.visit(root, IVec::default(), &None);
+    let mut loader = rebuild.loader.take().unwrap();
+    let new_root = match walked.and_then(|()| loader.finish()) {
+        Ok(new_root) => new_root,
+        Err(e) => {
+            loader.abort()?;
+            return Err(e);
+        }
+    };
+
+    {
+        let _cc = concurrency_control::write();
+        let _old_root = tree.swap_root(new_root, rebuild.loaded)?;
+    }
+
+    let guard = pin();
+    for pid in rebuild.visited {
+        tree.context.pagecache.free_unread(pid, &guard)?;
+    }
+    bloom::clear(tree);
+
+    Ok(())
+}
+
+struct Walk<'a> {
+    tree: &'a Tree,
+    visited: FastSet8<PageId>,
+    // ranges of stored keys, merged when they touch
+    lost: Vec<(IVec, Option<IVec>)>,
+    loader: Option<bulk_load::Loader<'a>>,
+    last: Option<IVec>,
+    loaded: usize,
+}
+
+impl<'a> Walk<'a> {
+    fn new(tree: &'a Tree, loader: Option<bulk_load::Loader<'a>>) -> Self {
+        Walk {
+            tree,
+            visited: FastSet8::default(),
+            lost: vec![],
+            loader,
+            last: None,
+            loaded: 0,
+        }
+    }
+
+    fn lose(&mut self, lo: IVec, hi: Option<IVec>) {
+        if let Some(last) = self.lost.last_mut() {
+            if last.1.as_ref() == Some(&lo) {
+                last.1 = hi;
+                return;
+            }
+        }
+        self.lost.push((lo, hi));
+    }
+
+    /// Walks the node at `pid` and those that it leads to for the
+    /// keys from `lo` up to `hi`.
+    fn visit(
+        &mut self,
+        mut pid: PageId,
+        lo: IVec,
+        hi: &Option<IVec>,
+    ) -> Result<()> {
+        let pagecache = &self.tree.context.pagecache;
+        let guard = pin();
+        let below_hi = |key: &[u8]| match hi {
+            Some(bound) => key < &**bound,
+            None => true,
+        };
+
+        let mut cursor = lo;
+        loop {
+            // a node that was already visited would only lead to
+            // keys that were already walked
+            let first_visit = self.visited.insert(pid);
+            let read = if first_visit
+                && pagecache.verify_page(pid, &guard).is_ok()
+            {
+                pagecache.get(pid, &guard)
+            } else {
+                Ok(None)
+            };
+            let node_view = if let Ok(Some(node_view)) = read {
+                node_view
+            } else {
+                warn!("page {} of a tree can't be read", pid);
+                self.lose(cursor, hi.clone());
+                return Ok(());
+            };
+
+            if node_view.is_index {
+                let node_hi = lower(node_view.hi().map(IVec::from), hi);
+                let mut children = node_view
+                    .decoded_keys()
+                    .zip(node_view.iter_index_pids())
+                    .peekable();
+                while let Some((child_lo, child)) = children.next() {
+                    let next_lo = children.peek().map(|(k, _)| k.clone());
+                    let child_hi = lower(next_lo, &node_hi);
+                    let start = std::cmp::max(&child_lo, &cursor).clone();
+                    let nonempty = match child_hi {
+                        Some(ref bound) => start < *bound,
+                        None => true,
+                    };
+                    if nonempty && below_hi(&start) {
+                        self.visit(child, start, &child_hi)?;
+                    }
+                }
+            } else {
+                for (key, value) in node_view.decoded_items() {
+                    let ascending = match self.last {
+                        Some(ref last) => key > *last,
+                        None => true,
+                    };
+                    if key < cursor || !below_hi(&key) || !ascending {
+                        continue;
+                    }
+                    if let Some(ref mut loader) = self.loader {
+                        loader.push(key.clone(), value.into())?;
+                    }
+                    self.last = Some(key);
+                    self.loaded += 1;
+                }
+            }
+
+            match (node_view.hi(), node_view.next) {
+                (None, _) => return Ok(()),
+                (Some(node_hi), _) if !below_hi(node_hi) => return Ok(()),
+                (Some(node_hi), Some(next)) => {
+                    cursor = std::cmp::max(&cursor[..], node_hi).into();
+                    pid = next.get();
+                }
+                (Some(node_hi), None) => {
+                    self.lose(node_hi.into(), hi.clone());
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// the lower of two upper bounds, where `None` is unbounded
+fn lower(bound: Option<IVec>, other: &Option<IVec>) -> Option<IVec> {
+    match (bound, other) {
+        (Some(ref first), Some(second)) if second < first => other.clone(),
+        (None, _) => other.clone(),
+        (first, _) => first,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{Seek, SeekFrom};
+
+    use super::*;
+
+    const MARKER: [u8; 64] = [b'Z'; 64];
+
+    pub(crate) fn key(i: u32) -> [u8; 4] {
+        i.to_be_bytes()
+    }
+
+    /// Writes keys 0 to 3000, where the value of key 500 is a
+    /// marker that can be found in the files, and flushes them.
+    /// The database should have a segment size of 4096.
+    pub(crate) fn write_keys(db: &Db) -> Result<()> {
+        for i in 0..1000 {
+            let value: &[u8] = if i == 500 { &MARKER } else { b"v" };
+            let _ = db.insert(key(i), value)?;
+        }
+        // move the end of the log past the segments that hold
+        // the write of the marker
+        for i in 1000..3000 {
+            let _ = db.insert(key(i), b"v")?;
+        }
+        let _ = db.flush()?;
+        Ok(())
+    }
+
+    /// Damages every copy of the leaf that holds key 500 in the
+    /// files of the database, in the log or in the heap files
+    /// that large nodes are put in.
+    pub(crate) fn damage_marked_leaf(path: &str) -> Result<()> {
+        let heap_dir = std::path::Path::new(path).join("heap");
+        let mut paths = vec![std::path::Path::new(path).join("db")];
+        for entry in std::fs::read_dir(heap_dir)? {
+            paths.push(entry?.path());
+        }
+        let mut damaged = 0;
+        for file_path in paths {
+            let data = std::fs::read(&file_path)?;
+            let mut file =
+                std::fs::OpenOptions::new().write(true).open(&file_path)?;
+            let mut offset = 0;
+            while let Some(at) = data[offset..]
+                .windows(MARKER.len())
+                .position(|w| w == MARKER)
+            {
+                offset += at;
+                let _ = file.seek(SeekFrom::Start(offset as u64))?;
+                file.write_all(&[data[offset] ^ 1])?;
+                offset += MARKER.len();
+                damaged += 1;
+            }
+            file.sync_all()?;
+        }
+        assert!(damaged > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn salvage_corrupt_leaf() -> Result<()> {
+        let path = "test_salvage_corrupt_leaf";
+        let _ = std::fs::remove_dir_all(path);
+        let config = Config::new().path(path).segment_size(4096);
+        {
+            let db =
+                config.clone().snapshot_after_ops(u64::max_value()).open()?;
+            write_keys(&db)?;
+            // the leaf is only read back lazily if it is covered
+            // by the snapshot, rather than replayed from the log
+            db.context.pagecache.clone().take_fuzzy_snapshot()?;
+        }
+        damage_marked_leaf(path)?;
+
+        let db = config.recovery_mode(RecoveryMode::Salvage).open()?;
+        let lost = db.lost_ranges().to_vec();
+        assert_eq!(lost.len(), 1, "{:?}", lost);
+        let in_lost = |i: u32| {
+            let k = IVec::from(&key(i));
+            let below_end = match lost[0].end {
+                Some(ref end) => k < *end,
+                None => true,
+            };
+            lost[0].start <= k && below_end
+        };
+        assert!(in_lost(500));
+        for i in 0..3000 {
+            assert_eq!(db.get(key(i))?.is_some(), !in_lost(i), "key {}", i);
+        }
+        assert!(db.verify_integrity()?.is_healthy());
+
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+//! Random samples of the keys of a `Tree`, see
+//! `Tree::sample_keys`.
+//!
+//! Each sample descends from the root to a leaf, choosing one
+//! child of every index node along the way and then one key of
+//! the leaf. Choosing uniformly would favor the keys of small
+//! subtrees, so every descent is weighted by the product of the
+//! fanouts along its path and the length of its leaf, which
+//! estimates how many keys the tree would hold if every subtree
+//! were like the ones it passed through. Descents are kept with
+//! a probability in proportion to that weight, so that the
+//! ones which pass through sparse subtrees are rejected more
+//! often, and every key is about equally likely to be sampled.
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+use crate::*;
+
+/// The number of descents that may be rejected for each
+/// sample before giving up, in case the tree is empty or all
+/// of its keys have expired.
+const ATTEMPTS_PER_SAMPLE: usize = 64;
+
+/// A xorshift generator, seeded randomly for every call to
+/// `Tree::sample_keys`.
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Rng {
+        let seed = RandomState::new().build_hasher().finish();
+        Rng(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Returns a number in `0..n`, which must not be 0.
+    fn below(&mut self, n: usize) -> usize {
+        usize::try_from(self.next() % u64::try_from(n).unwrap()).unwrap()
+    }
+}
+
+/// Returns `n` keys that are sampled with replacement, or fewer
+/// if not enough could be found.
+pub(crate) fn sample(tree: &Tree, n: usize) -> Result<Vec<IVec>> {
+    let mut rng = Rng::new();
+    let mut ret = Vec::with_capacity(n);
+    let mut max_weight = 0;
+    let mut attempts = n.saturating_mul(ATTEMPTS_PER_SAMPLE);
+
+    while ret.len() < n && attempts > 0 {
+        attempts -= 1;
+
+        let guard = pin();
+        let (key, weight) =
+            if let Some(descent) = descend(tree, &mut rng, &guard)? {
+                descent
+            } else {
+                continue;
+            };
+
+        // weights are only known once they have been seen, so
+        // the heaviest descent so far is always kept
+        if weight > max_weight {
+            max_weight = weight;
+        } else if rng.below(max_weight) >= weight {
+            continue;
+        }
+
+        if !expiration::is_expired(tree, &key, &guard)? {
+            ret.push(tree.order.decode(key));
+        }
+    }
+
+    Ok(ret)
+}
+
+// returns a random stored key with the weight of the path to it
+fn descend(
+    tree: &Tree,
+    rng: &mut Rng,
+    guard: &Guard,
+) -> Result<Option<(IVec, usize)>> {
+    let mut weight = 1_usize;
+    let mut cursor = tree.root.load(Acquire);
+    loop {
+        let node_view = if let Some(node_view) =
+            tree.context.pagecache.get(cursor, guard)?
+        {
+            node_view
+        } else {
+            return Ok(None);
+        };
+
+        if node_view.is_index {
+            let children: Vec<PageId> = node_view.iter_index_pids().collect();
+            if children.is_empty() {
+                return Ok(None);
+            }
+            weight = weight.saturating_mul(children.len());
+            cursor = children[rng.below(children.len())];
+            continue;
+        }
+
+        let keys: Vec<IVec> = node_view.decoded_keys().collect();
+        if keys.is_empty() {
+            return Ok(None);
+        }
+        weight = weight.saturating_mul(keys.len());
+        let key = keys[rng.below(keys.len())].clone();
+        return Ok(Some((key, weight)));
+    }
+}
@@ -0,0 +1,171 @@
+//! Background scrubbing of the storage files, see
+//! `Config::scrub_every_ms`.
+//!
+//! A thread walks the page table from the lowest page to the
+//! highest one and back again, reading every fragment of a few
+//! pages from the log and the heap files every interval and
+//! checking them as `Db::verify_integrity` does, so that damage
+//! to data that is rarely read is found before it is needed.
+//! Pages that fail the check are sent to every receiver that was
+//! returned by `Db::scrub_failures`.
+use std::{
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Weak,
+    },
+    time::Duration,
+};
+
+use crate::*;
+
+/// The number of pages that are checked every interval.
+const SCRUB_CHUNK: u64 = 64;
+
+/// A page that failed a check of the background scrub, see
+/// `Db::scrub_failures`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScrubFailure {
+    /// The page that can't be read.
+    pub pid: u64,
+    /// Why the page can't be read, usually `Error::Corruption`.
+    pub error: Error,
+}
+
+#[derive(Debug)]
+pub(crate) struct Scrubber {
+    pagecache: PageCache,
+    senders: Mutex<Vec<Sender<ScrubFailure>>>,
+}
+
+impl Scrubber {
+    pub(crate) fn new(pagecache: PageCache) -> Scrubber {
+        Scrubber { pagecache, senders: Mutex::new(vec![]) }
+    }
+
+    pub(crate) fn subscribe(&self) -> Receiver<ScrubFailure> {
+        let (tx, rx) = channel();
+        self.senders.lock().push(tx);
+        rx
+    }
+
+    fn report(&self, failure: &ScrubFailure) {
+        error!("scrub found page {} damaged: {}", failure.pid, failure.error);
+        self.pagecache.config.report_fault(
+            Some(failure.pid),
+            &failure.error,
+            false,
+        );
+        let mut senders = self.senders.lock();
+        senders.retain(|sender| sender.send(failure.clone()).is_ok());
+    }
+
+    /// Checks the pages from `lo` up to `hi`, returning the error
+    /// that stopped the check if it couldn't be finished.
+    fn scrub(&self, lo: PageId, hi: PageId) -> Result<()> {
+        let _cc = concurrency_control::read();
+        let guard = pin();
+        for pid in lo..hi {
+            match self.pagecache.verify_page(pid, &guard) {
+                Ok(()) => {}
+                Err(error @ Error::Corruption { .. }) => {
+                    self.report(&ScrubFailure { pid, error })
+                }
+                Err(other) => return Err(other),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Periodically checks a few pages until the `Db` is dropped.
+/// Only a weak reference is held between checks so that the
+/// scrubber never keeps the `Db` alive on its own.
+pub(crate) fn start_scrubber(context: &Context) {
+    let every_ms = if let (Some(every_ms), false) =
+        (context.scrub_every_ms, context.read_only)
+    {
+        every_ms
+    } else {
+        return;
+    };
+
+    let weak: Weak<Scrubber> = Arc::downgrade(&context.scrubber);
+
+    let spawned = background::spawn(context, "scrubber", move || {
+        let mut lo = 0;
+        loop {
+            std::thread::sleep(Duration::from_millis(every_ms));
+
+            let scrubber = if let Some(scrubber) = weak.upgrade() {
+                scrubber
+            } else {
+                return;
+            };
+
+            let end = scrubber.pagecache.next_pid_to_allocate();
+            let hi = std::cmp::min(lo + SCRUB_CHUNK, end);
+            if let Err(e) = scrubber.scrub(lo, hi) {
+                error!("failed to scrub pages: {:?}", e);
+                return;
+            }
+            lo = if hi >= end {
+                debug!("scrubbed all {} pages", end);
+                0
+            } else {
+                hi
+            };
+        }
+    });
+
+    if let Err(e) = spawned {
+        error!("failed to spawn scrubber thread: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::salvage::tests::{damage_marked_leaf, key, write_keys};
+
+    #[test]
+    fn scrub_reports_damaged_leaf() -> Result<()> {
+        let path = "test_scrub_reports_damaged_leaf";
+        let _ = std::fs::remove_dir_all(path);
+        let (tx, faults) = channel();
+        let tx = Mutex::new(tx);
+        let db = Config::new()
+            .path(path)
+            .segment_size(4096)
+            .scrub_every_ms(Some(1))
+            .on_fault(move |fault| {
+                let _ = tx.lock().send(fault.clone());
+            })
+            .open()?;
+        let failures = db.scrub_failures();
+        write_keys(&db)?;
+
+        // the leaf stays cached, but the scrub reads the files
+        damage_marked_leaf(path)?;
+        let damaged = {
+            let guard = pin();
+            db.view_for_key(key(500), &guard)?.pid
+        };
+        let failure = loop {
+            let failure =
+                failures.recv_timeout(Duration::from_secs(10)).unwrap();
+            if failure.pid == damaged {
+                break failure;
+            }
+        };
+        match failure.error {
+            Error::Corruption { .. } => {}
+            other => panic!("expected corruption, got {:?}", other),
+        }
+        let fault = faults.try_iter().find(|f| f.pid == Some(damaged));
+        assert!(!fault.unwrap().fatal);
+
+        drop(db);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+//! Named sequences of IDs, see `Db::sequence`.
+//!
+//! The sequences of a `Db` are persisted in a hidden `Tree` that
+//! is opened the first time one of them is asked for:
+//!
+//! * `name` -> the big-endian ID that the sequence has reserved
+//!   up to, but not including
+//!
+//! A sequence hands out the IDs that it has reserved from memory,
+//! and reserves a new block of them once they run out, which is
+//! flushed before any of them is handed out. When the database is
+//! opened again, a sequence continues from what it had reserved,
+//! so the IDs that weren't handed out before are skipped, but no
+//! ID is ever handed out twice.
+use std::convert::TryInto;
+
+use crate::*;
+
+const SEQUENCES_TREE_NAME: &[u8] = b"__sled__sequences";
+
+/// The sequences that have been opened in a `Db`, which are kept
+/// by the `Db` rather than its `Context` because they hold a `Tree`.
+#[derive(Debug, Default)]
+pub(crate) struct Sequences {
+    tree: Mutex<Option<Tree>>,
+    open: Mutex<FastMap8<IVec, Arc<SequenceState>>>,
+}
+
+#[derive(Debug)]
+struct SequenceState {
+    name: IVec,
+    // the ID that is handed out next and the one that the
+    // sequence has reserved up to
+    range: Mutex<(u64, u64)>,
+    block_size: AtomicU64,
+}
+
+/// A persistent sequence of IDs, returned by `Db::sequence`.
+/// Every handle to the same sequence of a `Db` shares its state.
+#[derive(Debug, Clone)]
+pub struct Sequence {
+    tree: Tree,
+    state: Arc<SequenceState>,
+}
+
+pub(crate) fn is_sequences_tree_name(name: &[u8]) -> bool {
+    name == SEQUENCES_TREE_NAME
+}
+
+/// Returns the sequence `name`, loading it if it hasn't been
+/// opened yet.
+pub(crate) fn open(
+    context: &Context,
+    sequences: &Sequences,
+    name: &[u8],
+) -> Result<Sequence> {
+    let tree = {
+        let mut tree = sequences.tree.lock();
+        if tree.is_none() {
+            let guard = pin();
+            *tree = Some(meta::open_tree(
+                context,
+                SEQUENCES_TREE_NAME,
+                None,
+                &guard,
+            )?);
+        }
+        tree.clone().unwrap()
+    };
+
+    let mut open = sequences.open.lock();
+    if let Some(state) = open.get(name) {
+        return Ok(Sequence { tree, state: state.clone() });
+    }
+
+    let reserved = if let Some(raw) = tree.get(name)? {
+        let bytes = (&*raw).try_into().map_err(|_| Error::corruption(None))?;
+        u64::from_be_bytes(bytes)
+    } else {
+        0
+    };
+    let state = Arc::new(SequenceState {
+        name: name.into(),
+        range: Mutex::new((reserved, reserved)),
+        block_size: AtomicU64::new(context.idgen_persist_interval),
+    });
+    let _ = open.insert(name.into(), state.clone());
+
+    Ok(Sequence { tree, state })
+}
+
+impl Sequence {
+    /// Returns the name of the sequence.
+    pub fn name(&self) -> IVec {
+        self.state.name.clone()
+    }
+
+    /// Returns the next ID of the sequence, which is greater than
+    /// every ID that it returned before, unless it was `set` to a
+    /// lower one. IDs are reserved in blocks, which are flushed
+    /// before their first ID is returned, so the IDs that were
+    /// reserved but not returned are skipped when the database is
+    /// opened again.
+    pub fn next_id(&self) -> Result<u64> {
+        let mut range = self.state.range.lock();
+        let (next, reserved) = *range;
+        if next == reserved {
+            let block_size = self.state.block_size.load(Acquire);
+            let new_reserved =
+                next.checked_add(block_size).ok_or_else(|| {
+                    Error::Unsupported(format!(
+                        "the sequence {:?} has run out of IDs",
+                        self.state.name
+                    ))
+                })?;
+            self.reserve(new_reserved)?;
+            range.1 = new_reserved;
+        }
+        range.0 = next + 1;
+        Ok(next)
+    }
+
+    /// Returns the ID that the next call to `next_id` returns.
+    pub fn current(&self) -> u64 {
+        self.state.range.lock().0
+    }
+
+    /// Sets the ID that the next call to `next_id` returns, which is
+    /// flushed before this returns. Setting a lower ID than one
+    /// that was returned before makes the sequence return it
+    /// again.
+    pub fn set(&self, id: u64) -> Result<()> {
+        let mut range = self.state.range.lock();
+        self.reserve(id)?;
+        *range = (id, id);
+        Ok(())
+    }
+
+    /// Sets how many IDs are reserved at a time, which is
+    /// `Config::idgen_persist_interval` by default. Larger blocks
+    /// flush less often, and skip more IDs when the database is
+    /// opened again. Applies to every handle to the sequence until
+    /// the database is closed.
+    pub fn set_block_size(&self, block_size: u64) -> Result<()> {
+        if block_size == 0 {
+            return Err(Error::Unsupported(
+                "the block size of a sequence must be above 0".into(),
+            ));
+        }
+        self.state.block_size.store(block_size, Release);
+        Ok(())
+    }
+
+    // persists the ID that the sequence has reserved up to
+    fn reserve(&self, reserved: u64) -> Result<()> {
+        if self.tree.context.read_only {
+            return Err(Error::Unsupported(
+                "the IDs of sequences can't be reserved in read-only mode"
+                    .into(),
+            ));
+        }
+        let _ = self.tree.insert(&self.state.name, &reserved.to_be_bytes())?;
+        let _ = self.tree.flush()?;
+        Ok(())
+    }
+}
@@ -8,10 +8,10 @@ use std::{
 
 use crate::{
     pagecache::{
-        BatchManifest, HeapId, MessageHeader, PageState, SegmentNumber,
-        Snapshot,
+        BatchManifest, Encoded, HeapId, MessageHeader, PageState,
+        SegmentNumber, Snapshot,
     },
-    varint, DiskPtr, Error, IVec, Link, Meta, Node, Result,
+    varint, Batch, DiskPtr, Error, IVec, Link, Meta, Node, Result,
 };
 
 /// Items that may be serialized and deserialized
@@ -78,6 +78,28 @@ impl Serialize for BatchManifest {
     }
 }
 
+impl Serialize for Encoded {
+    fn serialized_size(&self) -> u64 {
+        2 + self.data.serialized_size()
+    }
+
+    fn serialize_into(&self, buf: &mut &mut [u8]) {
+        buf[0] = Encoded::TAG;
+        buf[1] = self.codec_id;
+        scoot(buf, 2);
+        self.data.serialize_into(buf);
+    }
+
+    fn deserialize(buf: &mut &[u8]) -> Result<Encoded> {
+        if buf.len() < 2 || buf[0] != Encoded::TAG {
+            return Err(Error::corruption(None));
+        }
+        let codec_id = buf[1];
+        *buf = &buf[2..];
+        Ok(Encoded { codec_id, data: IVec::deserialize(buf)? })
+    }
+}
+
 impl Serialize for () {
     fn serialized_size(&self) -> u64 {
         0
@@ -441,6 +463,50 @@ fn unshift_i64_opt(value: i64) -> Option<i64> {
     }
 }
 
+impl Serialize for Option<IVec> {
+    fn serialized_size(&self) -> u64 {
+        1 + self.as_ref().map_or(0, IVec::serialized_size)
+    }
+
+    fn serialize_into(&self, buf: &mut &mut [u8]) {
+        self.is_some().serialize_into(buf);
+        if let Some(value) = self {
+            value.serialize_into(buf);
+        }
+    }
+
+    fn deserialize(buf: &mut &[u8]) -> Result<Self> {
+        if bool::deserialize(buf)? {
+            Ok(Some(IVec::deserialize(buf)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Serialize for Batch {
+    fn serialized_size(&self) -> u64 {
+        let len_sz: u64 = (self.writes.len() as u64).serialized_size();
+        let items_sz: u64 = self
+            .writes
+            .iter()
+            .map(|(k, v)| k.serialized_size() + v.serialized_size())
+            .sum();
+
+        len_sz + items_sz
+    }
+
+    fn serialize_into(&self, buf: &mut &mut [u8]) {
+        (self.writes.len() as u64).serialize_into(buf);
+        serialize_2tuple_sequence(self.writes.iter(), buf);
+    }
+
+    fn deserialize(buf: &mut &[u8]) -> Result<Self> {
+        let len = u64::deserialize(buf)?;
+        Ok(Batch { writes: deserialize_bounded_sequence(buf, len)? })
+    }
+}
+
 impl Serialize for Snapshot {
     fn serialized_size(&self) -> u64 {
         self.version.serialized_size()
@@ -769,6 +835,12 @@ mod qc {
         }
     }
 
+    impl Arbitrary for Batch {
+        fn arbitrary<G: Gen>(g: &mut G) -> Batch {
+            Batch { writes: Arbitrary::arbitrary(g) }
+        }
+    }
+
     impl Arbitrary for DiskPtr {
         fn arbitrary<G: Gen>(g: &mut G) -> DiskPtr {
             if g.gen() {
@@ -893,6 +965,11 @@ mod qc {
             prop_serialize(&item)
         }
 
+        #[cfg_attr(miri, ignore)]
+        fn batch(item: Batch) -> bool {
+            prop_serialize(&item)
+        }
+
         #[cfg_attr(miri, ignore)]
         fn snapshot(item: Snapshot) -> bool {
             prop_serialize(&item)
@@ -0,0 +1,248 @@
+//! A page cache that is shared by several `Db`s of the process, see
+//! `Config::shared_cache`.
+//!
+//! The pages of every `Db` are tracked by one `Lru`, under ids that
+//! put the slot of their `Db` above their `PageId`. The pages that
+//! an access evicts may belong to any of them, so the ones of the
+//! other `Db`s are paged out through the `PageCacheInner`s that are
+//! registered in their slots.
+use std::{
+    fmt,
+    sync::{Arc, Weak},
+};
+
+use crate::{lru::ID_BITS, pagecache::PageCacheInner, *};
+
+const SLOT_BITS: usize = 10;
+
+// the highest id is reserved by the `Lru`
+const MAX_SLOTS: usize = (1 << SLOT_BITS) - 1;
+
+const PID_BITS: usize = ID_BITS - SLOT_BITS;
+
+/// A page cache of a fixed capacity that several `Db`s share,
+/// so that the pages of the busiest ones take the memory that the
+/// others don't need, rather than every `Db` having a cache of its
+/// own. It's handed to every `Db` that should use it with
+/// `Config::shared_cache`, and `Config::shared_cache_priority`
+/// sets how long the pages of each of them are kept relative to
+/// the pages of the others.
+///
+/// Up to 1023 `Db`s can use the same cache at once.
+///
+/// # Examples
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use sled::{CachePriority, Config, SharedCache};
+///
+/// let cache = SharedCache::new(64 * 1024 * 1024)?;
+///
+/// let busy_tenant = Config::new()
+///     .temporary(true)
+///     .shared_cache(cache.clone())
+///     .shared_cache_priority(CachePriority::High)
+///     .open()?;
+/// let idle_tenant =
+///     Config::new().temporary(true).shared_cache(cache.clone()).open()?;
+///
+/// assert_eq!(busy_tenant.cache_capacity(), 64 * 1024 * 1024);
+/// # let _ = idle_tenant;
+/// # Ok(()) }
+/// ```
+#[derive(Clone)]
+pub struct SharedCache(Arc<SharedCacheInner>);
+
+struct SharedCacheInner {
+    lru: Lru,
+    // the page cache of the `Db` that uses each slot, which is
+    // `Weak::new()` while it's being started
+    slots: Mutex<Vec<Option<Weak<PageCacheInner>>>>,
+}
+
+impl Debug for SharedCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SharedCache({})", self.capacity())
+    }
+}
+
+impl SharedCache {
+    /// Creates a cache of `cache_capacity` bytes that evicts the
+    /// least recently used pages.
+    ///
+    /// Returns `Error::Unsupported` if `cache_capacity` is below
+    /// 256 bytes.
+    pub fn new(cache_capacity: usize) -> Result<SharedCache> {
+        SharedCache::with_policy(cache_capacity, CachePolicy::Lru)
+    }
+
+    /// Creates a cache of `cache_capacity` bytes that chooses the
+    /// pages that it keeps with `policy`, see `Config::cache_policy`.
+    ///
+    /// Returns `Error::Unsupported` if `cache_capacity` is below
+    /// 256 bytes.
+    pub fn with_policy(
+        cache_capacity: usize,
+        policy: CachePolicy,
+    ) -> Result<SharedCache> {
+        if cache_capacity < 256 {
+            return Err(Error::Unsupported(
+                "the cache capacity must be at least 256 bytes".into(),
+            ));
+        }
+        Ok(SharedCache(Arc::new(SharedCacheInner {
+            lru: Lru::new(cache_capacity, policy),
+            slots: Mutex::new(vec![]),
+        })))
+    }
+
+    /// Returns the capacity of the cache in bytes.
+    pub fn capacity(&self) -> usize {
+        self.0.lru.capacity()
+    }
+
+    /// Returns the number of `Db`s that use the cache.
+    pub fn databases(&self) -> usize {
+        self.0.slots.lock().iter().filter(|slot| slot.is_some()).count()
+    }
+
+    /// Reserves a slot for a `Db` that is being started.
+    pub(crate) fn register(&self, priority: CachePriority) -> Result<Tenant> {
+        let mut slots = self.0.slots.lock();
+        let slot = if let Some(free) = slots.iter().position(Option::is_none)
+        {
+            free
+        } else if slots.len() < MAX_SLOTS {
+            slots.push(None);
+            slots.len() - 1
+        } else {
+            return Err(Error::Unsupported(format!(
+                "at most {} databases can share a cache",
+                MAX_SLOTS
+            )));
+        };
+        slots[slot] = Some(Weak::new());
+
+        Ok(Tenant { cache: self.clone(), slot, priority })
+    }
+}
+
+/// The slot of a `Db` in a `SharedCache`, which is released when
+/// its `PageCacheInner` is dropped.
+#[derive(Debug)]
+pub(crate) struct Tenant {
+    cache: SharedCache,
+    slot: usize,
+    priority: CachePriority,
+}
+
+impl Tenant {
+    /// Lets the other `Db`s of the cache page out the pages that
+    /// they evict from this one, once it has been started.
+    pub(crate) fn attach(&self, pagecache: &Arc<PageCacheInner>) {
+        let mut slots = self.cache.0.slots.lock();
+        slots[self.slot] = Some(Arc::downgrade(pagecache));
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.cache.capacity()
+    }
+
+    /// Returns the bytes of the pages of every `Db` in the cache.
+    pub(crate) fn size(&self) -> usize {
+        self.cache.0.lru.size()
+    }
+
+    /// Called when a page of this `Db` is accessed. Pages out the
+    /// pages of the other `Db`s that it evicts, and returns those
+    /// of this one.
+    pub(crate) fn accessed(
+        &self,
+        pid: PageId,
+        item_size: usize,
+        priority: CachePriority,
+        guard: &Guard,
+    ) -> Vec<PageId> {
+        if pid >= 1 << PID_BITS {
+            // too high to be tracked, so it's never kept
+            return vec![pid];
+        }
+
+        // the priority of the `Db` moves the priority of the tree
+        // of the page up or down by one, unless it's pinned
+        let lru_priority = match (self.priority, priority) {
+            (CachePriority::Normal, tree_priority)
+            | (_, tree_priority @ CachePriority::Pinned) => tree_priority,
+            (CachePriority::Low, CachePriority::High)
+            | (CachePriority::High, CachePriority::Low) => {
+                CachePriority::Normal
+            }
+            (db_priority, _) => db_priority,
+        };
+
+        let slot = PageId::try_from(self.slot).unwrap();
+        let id = (slot << PID_BITS) | pid;
+        let evicted =
+            self.cache.0.lru.accessed(id, item_size, lru_priority, guard);
+
+        let mut ret = vec![];
+        let mut others: Vec<(usize, Vec<PageId>)> = vec![];
+        for evicted_id in evicted {
+            let evicted_slot = usize::try_from(evicted_id >> PID_BITS).unwrap();
+            let evicted_pid = evicted_id & ((1 << PID_BITS) - 1);
+            if evicted_slot == self.slot {
+                ret.push(evicted_pid);
+            } else if let Some((_, pids)) =
+                others.iter_mut().find(|(s, _)| *s == evicted_slot)
+            {
+                pids.push(evicted_pid);
+            } else {
+                others.push((evicted_slot, vec![evicted_pid]));
+            }
+        }
+
+        if !others.is_empty() {
+            self.page_out_others(others, guard);
+        }
+
+        ret
+    }
+
+    fn page_out_others(
+        &self,
+        others: Vec<(usize, Vec<PageId>)>,
+        guard: &Guard,
+    ) {
+        // the page caches are upgraded under the lock, and paged out
+        // from without it, since dropping the last reference to one
+        // releases its slot
+        let pagecaches: Vec<(Arc<PageCacheInner>, Vec<PageId>)> = {
+            let slots = self.cache.0.slots.lock();
+            others
+                .into_iter()
+                .filter_map(|(slot, pids)| {
+                    let weak = slots.get(slot)?.as_ref()?;
+                    Some((weak.upgrade()?, pids))
+                })
+                .collect()
+        };
+
+        for (pagecache, pids) in pagecaches {
+            if let Err(e) = pagecache.page_out_evicted(pids, guard) {
+                warn!(
+                    "failed to page out the pages of another database \
+                     that shares the cache: {:?}",
+                    e
+                );
+            }
+        }
+    }
+}
+
+impl Drop for Tenant {
+    fn drop(&mut self) {
+        let slot = PageId::try_from(self.slot).unwrap();
+        self.cache.0.lru.forget(|id| id >> PID_BITS == slot);
+        self.cache.0.slots.lock()[self.slot] = None;
+    }
+}
@@ -0,0 +1,105 @@
+//! Reporting of the operations that take longer than a threshold,
+//! see `Config::on_slow_op`.
+//!
+//! A slow operation is reported from the thread that ran it, once
+//! it's done, with the time that it spent in each of its phases:
+//!
+//! * `quota`: checking the quota of the tree, for inserts
+//! * `concurrency_control`: waiting for the transactions that are
+//!   being committed, for gets, inserts and removes
+//! * `index`: updating the indexes of the tree, for inserts and
+//!   removes
+//! * `read`: looking the key up, including the pages that are read
+//!   from the storage files, for gets
+//! * `write`: linking the update into the tree, including its
+//!   retries and its reservation in the log, for inserts and
+//!   removes
+//! * `flush`: writing the buffers of the log and waiting for them
+//!   to be made stable, for flushes
+//! * `lock`: waiting for the writer lock, for transactions
+//! * `body`: running the closure of a transaction
+//! * `commit`: applying the writes of a transaction
+//! * `retry`: waiting before a transaction is retried
+//!
+//! The phases are only timed when a handler is set, and the time
+//! spent in a phase that is entered more than once, like the
+//! `body` of a transaction that was retried, is summed.
+use std::{fmt, time::Duration};
+
+use crate::*;
+
+/// An operation that took longer than the threshold of
+/// `Config::on_slow_op`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowOp {
+    /// The operation that was slow.
+    pub op: Operation,
+    /// The name of the tree that it ran on, or the first tree
+    /// of a transaction.
+    pub tree: IVec,
+    /// The length of its key, if it had one.
+    pub key_len: Option<usize>,
+    /// How long it took, from the moment it was called to the
+    /// moment it returned.
+    pub latency: Duration,
+    /// The time that it spent in each of its phases, in the order
+    /// that they were first entered.
+    pub phases: Vec<(&'static str, Duration)>,
+    /// The number of pages that it read from the storage files.
+    pub pages_read: u64,
+}
+
+impl fmt::Display for SlowOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "slow {} on tree {:?} took {:?}",
+            self.op,
+            String::from_utf8_lossy(&self.tree),
+            self.latency
+        )?;
+        if let Some(key_len) = self.key_len {
+            write!(f, " with a key of {} bytes", key_len)?;
+        }
+        for (i, (phase, spent)) in self.phases.iter().enumerate() {
+            let separator = if i == 0 { ": " } else { ", " };
+            write!(f, "{}{} {:?}", separator, phase, spent)?;
+        }
+        if self.pages_read > 0 {
+            write!(f, " ({} pages read)", self.pages_read)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct SlowOpHandler {
+    threshold: Duration,
+    handler: Arc<dyn Fn(&SlowOp) + Send + Sync>,
+}
+
+impl Debug for SlowOpHandler {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> std::result::Result<(), fmt::Error> {
+        write!(f, "SlowOpHandler({:?})", self.threshold)
+    }
+}
+
+impl SlowOpHandler {
+    pub(crate) fn new<F>(threshold: Duration, handler: F) -> SlowOpHandler
+    where
+        F: Fn(&SlowOp) + Send + Sync + 'static,
+    {
+        SlowOpHandler { threshold, handler: Arc::new(handler) }
+    }
+
+    pub(crate) fn threshold(&self) -> Duration {
+        self.threshold
+    }
+
+    pub(crate) fn report(&self, slow_op: &SlowOp) {
+        (self.handler)(slow_op)
+    }
+}
@@ -0,0 +1,434 @@
+//! Consistent views of a `Db` as of some point in time, see
+//! `Db::snapshot`.
+//!
+//! A `Tree` only keeps the newest value of each key, so a snapshot
+//! keeps the values that it needs itself: while it is alive, each
+//! write first copies the value that its key held into every
+//! snapshot that doesn't have a copy of the key yet. A snapshot
+//! reads a key from its tree, and then returns its own copy of the
+//! key instead if it has one. Because the copy is made before the
+//! write is linked, a read of the tree that sees the write is
+//! always followed by a look-up that finds the copy. Iterators
+//! merge the keys of the tree with the copies in the same way, so
+//! that keys which were removed since are still returned, and
+//! keys which were inserted since are skipped.
+//!
+//! Snapshots are registered while writes are blocked by the
+//! concurrency control, which every write holds a shared lock of,
+//! so no write is only partly seen by a snapshot.
+#![allow(clippy::module_name_repetitions)]
+use std::{
+    ops::{Bound, RangeBounds},
+    sync::Weak,
+};
+
+use crate::*;
+
+static ID_GEN: AtomicUsize = AtomicUsize::new(0);
+
+/// The snapshots of a `Db` that are alive.
+#[derive(Debug, Default)]
+pub(crate) struct Snapshots {
+    live: RwLock<Vec<(usize, Weak<Versions>)>>,
+}
+
+impl Snapshots {
+    fn live(&self) -> Vec<Arc<Versions>> {
+        let live = self.live.read();
+        live.iter().filter_map(|(_, versions)| versions.upgrade()).collect()
+    }
+}
+
+/// The copies that one snapshot keeps.
+#[derive(Debug)]
+struct Versions {
+    id: usize,
+    snapshots: Arc<Snapshots>,
+    // tree id -> the copies of the keys of the tree
+    trees: Mutex<FastMap8<IVec, Copies>>,
+}
+
+impl Drop for Versions {
+    fn drop(&mut self) {
+        let mut live = self.snapshots.live.write();
+        live.retain(|(id, _)| *id != self.id);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Copies {
+    // stored key -> the value that it held when the snapshot was
+    // taken
+    values: BTreeMap<IVec, Option<IVec>>,
+    // set once the tree was bulk loaded, which doesn't copy the
+    // keys that it writes, so only the copies are left to read
+    detached: bool,
+}
+
+impl Versions {
+    fn copy_of(&self, tree: &Tree, key: &[u8]) -> Option<Option<IVec>> {
+        let trees = self.trees.lock();
+        let copies = trees.get(&tree.tree_id)?;
+        if let Some(value) = copies.values.get(key) {
+            Some(value.clone())
+        } else if copies.detached {
+            Some(None)
+        } else {
+            None
+        }
+    }
+
+    // returns the first or last copy between the bounds, and
+    // whether the tree was detached
+    fn copy_between(
+        &self,
+        tree: &Tree,
+        lo: &Bound<IVec>,
+        hi: &Bound<IVec>,
+        forward: bool,
+    ) -> (Option<(IVec, Option<IVec>)>, bool) {
+        let trees = self.trees.lock();
+        let copies = if let Some(copies) = trees.get(&tree.tree_id) {
+            copies
+        } else {
+            return (None, false);
+        };
+        let collapsed = match (lo, hi) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end))
+            | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            _ => false,
+        };
+        if collapsed {
+            return (None, copies.detached);
+        }
+        let mut range = copies.values.range((lo.clone(), hi.clone()));
+        let copy = if forward { range.next() } else { range.next_back() };
+        (copy.map(|(k, v)| (k.clone(), v.clone())), copies.detached)
+    }
+}
+
+/// Copies the value that the stored `key` of `tree` holds, which
+/// `previous` returns, into each live snapshot that doesn't have
+/// a copy of the key yet. Must be called before the write of the
+/// key is linked.
+pub(crate) fn record<F>(
+    tree: &Tree,
+    key: &[u8],
+    previous: F,
+    guard: &Guard,
+) -> Result<()>
+where
+    F: FnOnce() -> Result<Option<IVec>>,
+{
+    let live = tree.context.snapshots.live();
+    let missing = live.iter().any(|versions| {
+        let trees = versions.trees.lock();
+        trees.get(&tree.tree_id).map(|copies| copies.values.contains_key(key))
+            != Some(true)
+    });
+    if !missing {
+        return Ok(());
+    }
+
+    let value = if expiration::is_expired(tree, key, guard)? {
+        None
+    } else {
+        previous()?
+    };
+    for versions in &live {
+        let mut trees = versions.trees.lock();
+        let copies = trees.entry(tree.tree_id.clone()).or_default();
+        // the keys of a detached tree were all written since
+        let copy = if copies.detached { None } else { value.clone() };
+        let _ = copies.values.entry(IVec::from(key)).or_insert(copy);
+    }
+    Ok(())
+}
+
+/// Makes the live snapshots stop reading `tree`, before it is
+/// bulk loaded. The tree must be empty, and writes must be
+/// blocked.
+pub(crate) fn detach(tree: &Tree) {
+    for versions in tree.context.snapshots.live() {
+        let mut trees = versions.trees.lock();
+        trees.entry(tree.tree_id.clone()).or_default().detached = true;
+    }
+}
+
+/// A consistent, read-only view of every `Tree` of a `Db` as of
+/// the moment that it was taken by `Db::snapshot`. Writes that
+/// happen afterwards, including the writes of batches and
+/// transactions, are never seen by it, so a long scan returns
+/// the keys as they were at one point in time, without missing
+/// or repeating any of them.
+///
+/// While a snapshot or one of its iterators is alive, every
+/// write copies the value that it replaces into it the first time
+/// that the key is written, so the memory that a snapshot uses
+/// grows with the number of distinct keys that are written while
+/// it is held. Drop it once it is no longer needed.
+///
+/// Keys that expire are hidden once they expire, as they are by
+/// the `Tree`.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    versions: Arc<Versions>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(context: &Context) -> Snapshot {
+        Snapshot::with_lsn(context).0
+    }
+
+    /// Takes a snapshot along with the `Lsn` that it is consistent
+    /// with: every write that it sees has reserved its place in the
+    /// log at or below it, and every later one above it.
+    pub(crate) fn with_lsn(context: &Context) -> (Snapshot, Lsn) {
+        let versions = Arc::new(Versions {
+            id: ID_GEN.fetch_add(1, Relaxed),
+            snapshots: context.snapshots.clone(),
+            trees: Mutex::new(FastMap8::default()),
+        });
+
+        // blocks the writes that are in progress from being seen
+        // only partly
+        let _cc = concurrency_control::write();
+        let log = &context.pagecache.log;
+        let lsn = log.iobufs.max_reserved_lsn.load(Acquire);
+        let mut live = context.snapshots.live.write();
+        live.push((versions.id, Arc::downgrade(&versions)));
+        drop(live);
+
+        (Snapshot { versions }, lsn)
+    }
+
+    /// Retrieve the value that a key of a `Tree` held when the
+    /// snapshot was taken.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// db.insert("a", "old")?;
+    /// let snapshot = db.snapshot();
+    /// db.insert("a", "new")?;
+    /// db.insert("b", "new")?;
+    ///
+    /// assert_eq!(snapshot.get(&db, "a")?, Some(sled::IVec::from("old")));
+    /// assert_eq!(snapshot.get(&db, "b")?, None);
+    /// # Ok(()) }
+    /// ```
+    pub fn get<K: AsRef<[u8]>>(
+        &self,
+        tree: &Tree,
+        key: K,
+    ) -> Result<Option<IVec>> {
+        // the tree is read before the copies, so that a write that
+        // is seen always has its copy seen too
+        let current = tree.get(key.as_ref())?;
+        let stored_key = tree.order.encode(key.as_ref());
+        if let Some(copy) = self.versions.copy_of(tree, &stored_key) {
+            Ok(copy)
+        } else {
+            Ok(current)
+        }
+    }
+
+    /// Create a double-ended iterator over the keys and values
+    /// that a `Tree` held when the snapshot was taken.
+    pub fn iter(&self, tree: &Tree) -> SnapshotIter {
+        self.range::<Vec<u8>, _>(tree, ..)
+    }
+
+    /// Create a double-ended iterator over the keys and values
+    /// within a range of a `Tree` that it held when the snapshot
+    /// was taken.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// use sled::IVec;
+    ///
+    /// for i in 0..5_u8 {
+    ///     db.insert(&[i], vec![])?;
+    /// }
+    /// let snapshot = db.snapshot();
+    /// db.remove(&[1])?;
+    /// db.insert(&[2, 0], vec![])?;
+    ///
+    /// let keys: Vec<IVec> = snapshot
+    ///     .range(&db, [1]..[4])
+    ///     .map(|res| res.map(|(k, _v)| k))
+    ///     .collect::<sled::Result<_>>()?;
+    /// assert_eq!(
+    ///     keys,
+    ///     vec![IVec::from(&[1]), IVec::from(&[2]), IVec::from(&[3])]
+    /// );
+    /// # Ok(()) }
+    /// ```
+    pub fn range<K, R>(&self, tree: &Tree, range: R) -> SnapshotIter
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let (lo, hi) = tree.encode_range(&range);
+        self.range_inner(tree, lo, hi)
+    }
+
+    /// Iterates over the stored keys of a tree between `lo` and
+    /// `hi` as of the snapshot, see `Tree::range_inner`.
+    pub(crate) fn range_inner(
+        &self,
+        tree: &Tree,
+        lo: Bound<IVec>,
+        hi: Bound<IVec>,
+    ) -> SnapshotIter {
+        let iter = tree.range_inner(lo, hi);
+        SnapshotIter {
+            versions: self.versions.clone(),
+            lo: iter.lo.clone(),
+            hi: iter.hi.clone(),
+            iter,
+            front: None,
+            back: None,
+            exhausted: false,
+        }
+    }
+}
+
+/// An iterator over the keys and values of a `Tree` as of a
+/// `Snapshot`, which keeps the snapshot alive.
+pub struct SnapshotIter {
+    versions: Arc<Versions>,
+    iter: Iter,
+    // the next keys of the tree at each end, which were read but
+    // not returned yet
+    front: Option<(IVec, IVec)>,
+    back: Option<(IVec, IVec)>,
+    // the bounds of the keys that are left, which move past each
+    // key that is returned
+    lo: Bound<IVec>,
+    hi: Bound<IVec>,
+    exhausted: bool,
+}
+
+impl SnapshotIter {
+    // reads the next key of the tree at an end, skipping expired
+    // keys, without decoding it
+    fn read_tree(&mut self, forward: bool) -> Option<Result<(IVec, IVec)>> {
+        if self.exhausted {
+            return None;
+        }
+        let _cc = concurrency_control::read();
+        loop {
+            let item = if forward {
+                self.iter.next_inner()
+            } else {
+                self.iter.next_back_inner()
+            };
+            match item {
+                Some(Ok((k, v))) => {
+                    match expiration::is_expired(&self.iter.tree, &k, &pin()) {
+                        Ok(true) => {}
+                        Ok(false) => return Some(Ok((k, v))),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+                None => {
+                    self.exhausted = true;
+                    return None;
+                }
+                other => return other,
+            }
+        }
+    }
+
+    fn next_at(&mut self, forward: bool) -> Option<Result<(IVec, IVec)>> {
+        loop {
+            let peeked =
+                if forward { self.front.take() } else { self.back.take() };
+            let next_of_tree = if peeked.is_some() {
+                peeked
+            } else if let Some(res) = self.read_tree(forward) {
+                match res {
+                    Ok(kv) => Some(kv),
+                    Err(e) => return Some(Err(e)),
+                }
+            } else if forward {
+                // the other end holds the last key of the tree
+                self.back.take()
+            } else {
+                self.front.take()
+            };
+
+            // the copies up to the next key of the tree are read
+            // after the tree itself
+            let (lo, hi) = match (&next_of_tree, forward) {
+                (Some((k, _)), true) => {
+                    (self.lo.clone(), Bound::Included(k.clone()))
+                }
+                (Some((k, _)), false) => {
+                    (Bound::Included(k.clone()), self.hi.clone())
+                }
+                (None, _) => (self.lo.clone(), self.hi.clone()),
+            };
+            let (first_copy, detached) = self.versions.copy_between(
+                &self.iter.tree,
+                &lo,
+                &hi,
+                forward,
+            );
+
+            let (key, visible) = match (first_copy, next_of_tree) {
+                (Some((key, copy)), unreturned) => {
+                    if unreturned.as_ref().map(|(k, _)| k) != Some(&key) {
+                        if forward {
+                            self.front = unreturned;
+                        } else {
+                            self.back = unreturned;
+                        }
+                    }
+                    (key, copy)
+                }
+                (None, Some((key, current))) => {
+                    (key, if detached { None } else { Some(current) })
+                }
+                (None, None) => return None,
+            };
+
+            if forward {
+                self.lo = Bound::Excluded(key.clone());
+            } else {
+                self.hi = Bound::Excluded(key.clone());
+            }
+            if let Some(value) = visible {
+                return Some(Ok((self.iter.tree.order.decode(key), value)));
+            }
+        }
+    }
+}
+
+impl Iterator for SnapshotIter {
+    type Item = Result<(IVec, IVec)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_at(true)
+    }
+
+    fn last(mut self) -> Option<Self::Item> {
+        self.next_back()
+    }
+}
+
+impl DoubleEndedIterator for SnapshotIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_at(false)
+    }
+}
@@ -0,0 +1,164 @@
+//! A breakdown of the space that the storage files take, see
+//! `Db::space_usage`.
+//!
+//! The live bytes of each segment of the log are those that the
+//! segment accountant tracks for choosing the segments that the
+//! garbage collector cleans, and everything else in a segment is
+//! dead. The live bytes of the heap files and the value log are
+//! those that the pages point to. Each tree is walked from its
+//! root along the children of its index nodes and the sibling
+//! links of every node, adding up what its nodes take without
+//! reading them back from the log.
+use crate::*;
+
+/// The result of `Db::space_usage`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SpaceUsage {
+    /// The size of the log file, the heap files that large nodes
+    /// are written to and the value log files.
+    pub on_disk: u64,
+    /// The bytes of the log, the heap files and the value log
+    /// that hold the current state of some page or value.
+    pub live: u64,
+    /// The bytes of the storage files that aren't live, which
+    /// are either garbage that can be reclaimed or free space
+    /// that is reused for new writes.
+    pub dead: u64,
+    /// The space that every tree takes, ordered by name.
+    pub trees: Vec<TreeSpace>,
+    /// Every segment of the log, ordered by offset.
+    pub segments: Vec<SegmentSpace>,
+}
+
+/// The live bytes of the nodes and separated values of a tree.
+/// Bytes that are dead no longer belong to any tree, so they are
+/// only counted by segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeSpace {
+    /// The name of the tree.
+    pub tree: IVec,
+    /// The number of nodes of the tree.
+    pub nodes: u64,
+    /// The bytes that its nodes take in the log.
+    pub log: u64,
+    /// The bytes that its nodes take in the heap files, which
+    /// large nodes are written to.
+    pub heap: u64,
+    /// The bytes that its values take in the value log, see
+    /// `Config::value_log_threshold`.
+    pub value_log: u64,
+}
+
+impl TreeSpace {
+    /// Returns all of the live bytes of the tree.
+    pub fn live(&self) -> u64 {
+        self.log + self.heap + self.value_log
+    }
+}
+
+/// The space of a segment of the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentSpace {
+    /// The offset of the segment in the log file.
+    pub offset: u64,
+    /// What the segment is being used for.
+    pub state: SegmentState,
+    /// The bytes of the segment that hold the current state of
+    /// some page.
+    pub live: u64,
+    /// The rest of the segment, including the part of the
+    /// segment that is being written to that is still empty.
+    pub dead: u64,
+}
+
+/// What a segment of the log is being used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentState {
+    /// The segment may be reused for new writes.
+    Free,
+    /// The segment is being written to.
+    Active,
+    /// The segment is full, and its pages are marked as they are
+    /// replaced by later writes.
+    Inactive,
+    /// The garbage collector is moving the remaining pages of
+    /// the segment elsewhere, after which it is freed.
+    Draining,
+}
+
+/// Adds up the space that the storage files, the segments of the
+/// log and every tree take.
+pub(crate) fn space_usage(
+    context: &Context,
+    tenants: &BTreeMap<IVec, Tree>,
+) -> Result<SpaceUsage> {
+    let pagecache = &context.pagecache;
+    let guard = pin();
+
+    let (log_size, heap_size, value_log_size) = pagecache.file_sizes()?;
+    let mut ret = SpaceUsage {
+        on_disk: log_size + heap_size + value_log_size,
+        segments: pagecache.segment_space(),
+        ..SpaceUsage::default()
+    };
+
+    let log_live: u64 = ret.segments.iter().map(|segment| segment.live).sum();
+    let mut heap_live = 0;
+    for pid in 0..pagecache.next_pid_to_allocate() {
+        heap_live += pagecache.page_space(pid, &guard).1;
+    }
+    let mut value_log_live = 0;
+    for (name, tree) in tenants {
+        let tree_space = tree_space(name, tree, &guard)?;
+        value_log_live += tree_space.value_log;
+        ret.trees.push(tree_space);
+    }
+
+    ret.live = log_live + heap_live + value_log_live;
+    ret.dead = ret.on_disk.saturating_sub(ret.live);
+    Ok(ret)
+}
+
+fn tree_space(name: &IVec, tree: &Tree, guard: &Guard) -> Result<TreeSpace> {
+    let pagecache = &tree.context.pagecache;
+    let mut ret = TreeSpace {
+        tree: name.clone(),
+        nodes: 0,
+        log: 0,
+        heap: 0,
+        value_log: 0,
+    };
+
+    // siblings are followed as well as children, so that nodes
+    // whose split hasn't reached their parent are found
+    let mut visited = FastSet8::default();
+    let mut to_visit = vec![tree.root.load(Acquire)];
+    while let Some(pid) = to_visit.pop() {
+        if !visited.insert(pid) {
+            continue;
+        }
+        let node_view = if let Some(node_view) = pagecache.get(pid, guard)? {
+            node_view
+        } else {
+            continue;
+        };
+
+        let (log_bytes, heap_bytes) = pagecache.page_space(pid, guard);
+        ret.nodes += 1;
+        ret.log += log_bytes;
+        ret.heap += heap_bytes;
+
+        if node_view.is_index {
+            to_visit.extend(node_view.iter_index_pids());
+        } else {
+            for (_, stored) in node_view.decoded_items() {
+                ret.value_log += value_log::separated_len(tree, stored);
+            }
+        }
+        if let Some(next) = node_view.next {
+            to_visit.push(next.get());
+        }
+    }
+
+    Ok(ret)
+}
@@ -0,0 +1,264 @@
+//! The writes of a `Txn` that no longer fit in memory, see
+//! `TxnOptions::spill_after`.
+//!
+//! Whenever the buffered writes of a transaction grow past the
+//! limit, they are written to a temporary file next to the
+//! database as a sorted run and dropped from memory. A run is a
+//! sequence of entries, sorted by the position of their tree in
+//! the transaction and then by key:
+//!
+//! * the big-endian position of the tree, as a `u32`
+//! * the big-endian length of the key, as a `u64`, and the key
+//! * 1 and the big-endian length of the value and the value if
+//!   the key is set, or 0 if it is removed
+//!
+//! Every few kilobytes, the first key of the entries that follow
+//! is kept in memory with its offset, so that a read of the
+//! transaction only scans a block of each run. The runs are
+//! merged when the transaction commits, with the writes of newer
+//! runs replacing those of older ones.
+use std::{
+    cmp::Ordering,
+    convert::TryFrom,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+use crate::*;
+
+/// The number of bytes of entries between the keys that are kept
+/// in memory for a run.
+const BLOCK_SIZE: u64 = 4096;
+
+/// The runs of a transaction, which are removed when it is
+/// committed or dropped.
+#[derive(Debug)]
+pub(crate) struct Spill {
+    dir: PathBuf,
+    pub(crate) limit: usize,
+    runs: Vec<Run>,
+}
+
+#[derive(Debug)]
+struct Run {
+    path: PathBuf,
+    file: File,
+    // (tree position, key, offset) of the first entry of each block
+    index: Vec<(u32, IVec, u64)>,
+    len: u64,
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("failed to remove spilled writes {:?}: {:?}", self.path, e);
+        }
+    }
+}
+
+/// A spilled write: the position of its tree in the transaction,
+/// the key, and the value that the key is set to, or `None` if it
+/// is removed.
+pub(crate) type Entry = (u32, IVec, Option<IVec>);
+
+impl Spill {
+    pub(crate) fn new(dir: PathBuf, limit: usize) -> Spill {
+        Spill { dir, limit, runs: vec![] }
+    }
+
+    /// Returns the number of runs that were written.
+    pub(crate) fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Removes the runs after the first `runs`, see
+    /// `Txn::rollback_to`.
+    pub(crate) fn truncate(&mut self, runs: usize) {
+        self.runs.truncate(runs);
+    }
+
+    /// Writes a run of entries, which must be sorted.
+    pub(crate) fn write_run<'a, I>(&mut self, entries: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (u32, &'a IVec, &'a Option<IVec>)>,
+    {
+        let path = self.dir.join(format!(
+            "txn-spill-{}-{}",
+            std::process::id(),
+            SPILL_ID.fetch_add(1, SeqCst)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let mut run = Run { path, file, index: vec![], len: 0 };
+
+        let mut writer = BufWriter::new(&run.file);
+        let mut block_start = None;
+        for (tree, key, value) in entries {
+            if block_start.map(|start| run.len - start >= BLOCK_SIZE)
+                != Some(false)
+            {
+                run.index.push((tree, key.clone(), run.len));
+                block_start = Some(run.len);
+            }
+            let key_len = u64::try_from(key.len()).unwrap();
+            let mut header = tree.to_be_bytes().to_vec();
+            header.extend_from_slice(&key_len.to_be_bytes());
+            writer.write_all(&header)?;
+            writer.write_all(key)?;
+            run.len += 12 + key_len;
+            if let Some(new) = value {
+                let value_len = u64::try_from(new.len()).unwrap();
+                writer.write_all(&[1])?;
+                writer.write_all(&value_len.to_be_bytes())?;
+                writer.write_all(new)?;
+                run.len += 9 + value_len;
+            } else {
+                writer.write_all(&[0])?;
+                run.len += 1;
+            }
+        }
+        writer.into_inner().map_err(io::IntoInnerError::into_error)?;
+
+        self.runs.push(run);
+        Ok(())
+    }
+
+    /// Returns the newest spilled write of a key, if there was one.
+    pub(crate) fn get(
+        &self,
+        tree: u32,
+        key: &[u8],
+    ) -> Result<Option<Option<IVec>>> {
+        for run in self.runs.iter().rev() {
+            let block = if let Some(block) = run
+                .index
+                .iter()
+                .rposition(|(t, k, _)| (*t, &**k) <= (tree, key))
+            {
+                block
+            } else {
+                continue;
+            };
+            let start = run.index[block].2;
+            let end = run
+                .index
+                .get(block + 1)
+                .map_or(run.len, |(_, _, offset)| *offset);
+
+            let mut file = &run.file;
+            let _ = file.seek(SeekFrom::Start(start))?;
+            let mut reader = BufReader::new(file.take(end - start));
+            while let Some((t, k, value)) = read_entry(&mut reader)? {
+                match (t, &*k).cmp(&(tree, key)) {
+                    Ordering::Less => {}
+                    Ordering::Equal => return Ok(Some(value)),
+                    Ordering::Greater => break,
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Merges the runs, returning each key that was written once,
+    /// with its newest write.
+    pub(crate) fn merge(&self) -> Result<Merge<'_>> {
+        let mut heads = Vec::with_capacity(self.runs.len());
+        for run in &self.runs {
+            let mut file = &run.file;
+            let _ = file.seek(SeekFrom::Start(0))?;
+            let mut reader = BufReader::new(file);
+            let next = read_entry(&mut reader)?;
+            heads.push((reader, next));
+        }
+        Ok(Merge { heads })
+    }
+}
+
+static SPILL_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The writes of the runs of a `Spill` in the order of their
+/// trees and keys, see `Spill::merge`.
+pub(crate) struct Merge<'a> {
+    // the reader of each run, and its next entry
+    heads: Vec<(BufReader<&'a File>, Option<Entry>)>,
+}
+
+impl<'a> Merge<'a> {
+    fn next_inner(&mut self) -> Result<Option<Entry>> {
+        // the newest run wins when several wrote the same key
+        let mut first: Option<(usize, (u32, &IVec))> = None;
+        for (i, (_, head)) in self.heads.iter().enumerate() {
+            if let Some((tree, key, _)) = head {
+                let replaces = match first {
+                    Some((_, pos)) => (*tree, key) <= pos,
+                    None => true,
+                };
+                if replaces {
+                    first = Some((i, (*tree, key)));
+                }
+            }
+        }
+        let newest = if let Some((i, _)) = first {
+            i
+        } else {
+            return Ok(None);
+        };
+
+        let entry = self.heads[newest].1.take().unwrap();
+        for (i, (reader, head)) in self.heads.iter_mut().enumerate() {
+            let same = head.as_ref().map(|(t, k, _)| (*t, k))
+                == Some((entry.0, &entry.1));
+            if i == newest || same {
+                *head = read_entry(reader)?;
+            }
+        }
+        Ok(Some(entry))
+    }
+}
+
+impl<'a> Iterator for Merge<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_inner().transpose()
+    }
+}
+
+// reads the next entry of a run, or returns `None` at its end
+fn read_entry<R: Read>(reader: &mut R) -> Result<Option<Entry>> {
+    let mut header = [0; 12];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    }
+    let mut tree = [0; 4];
+    tree.copy_from_slice(&header[..4]);
+    let mut key_len = [0; 8];
+    key_len.copy_from_slice(&header[4..]);
+
+    let key = read_bytes(reader, u64::from_be_bytes(key_len))?;
+    let mut flag = [0; 1];
+    reader.read_exact(&mut flag)?;
+    let value = if flag[0] == 1 {
+        let mut value_len = [0; 8];
+        reader.read_exact(&mut value_len)?;
+        Some(read_bytes(reader, u64::from_be_bytes(value_len))?)
+    } else {
+        None
+    };
+    Ok(Some((u32::from_be_bytes(tree), key, value)))
+}
+
+fn read_bytes<R: Read>(reader: &mut R, n: u64) -> Result<IVec> {
+    let len = usize::try_from(n).map_err(|_| Error::corruption(None))?;
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf)?;
+    Ok(buf.into())
+}
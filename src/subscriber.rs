@@ -1,9 +1,11 @@
 use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
     future::Future,
     pin::Pin,
     sync::{
         atomic::{AtomicBool, Ordering::Relaxed},
-        mpsc::{sync_channel, Receiver, SyncSender, TryRecvError},
+        mpsc::{RecvTimeoutError, TryRecvError},
     },
     task::{Context, Poll, Waker},
     time::{Duration, Instant},
@@ -14,31 +16,107 @@ use crate::*;
 static ID_GEN: AtomicUsize = AtomicUsize::new(0);
 
 /// An event that happened to a key that a subscriber is interested in.
+///
+/// The writes of a batch or of a transaction arrive together as a
+/// single event, which is only sent once all of them, across every
+/// tree that a transaction writes to, can be read.
 #[derive(Debug, Clone)]
 pub struct Event {
+    pub(crate) seq: u64,
     /// A map of batches for each tree written to in a transaction,
     /// only one of which will be the one subscribed to.
     pub(crate) batches: Arc<[(Tree, Batch)]>,
+    // the values that the keys of each batch held before it was
+    // written, if a subscriber asked for them
+    pub(crate) previous: Option<Arc<[Batch]>>,
+    pub(crate) missed: u64,
 }
 
 impl Event {
     pub(crate) fn single_update(
         tree: Tree,
+        seq: u64,
         key: IVec,
         value: Option<IVec>,
+        previous: Option<Option<IVec>>,
     ) -> Event {
+        let previous_batch = previous.map(|previous_value| Batch {
+            writes: vec![(key.clone(), previous_value)].into_iter().collect(),
+        });
         Event::single_batch(
             tree,
+            seq,
             Batch { writes: vec![(key, value)].into_iter().collect() },
+            previous_batch,
+        )
+    }
+
+    pub(crate) fn single_batch(
+        tree: Tree,
+        seq: u64,
+        batch: Batch,
+        previous: Option<Batch>,
+    ) -> Event {
+        Event::from_batches(
+            seq,
+            vec![(tree, batch)],
+            previous.map(|previous_batch| vec![previous_batch]),
         )
     }
 
-    pub(crate) fn single_batch(tree: Tree, batch: Batch) -> Event {
-        Event::from_batches(vec![(tree, batch)])
+    // the batches contain keys as they are stored, which
+    // are decoded for the `KeyOrder` of their tree. the
+    // previous values are given for the same keys, in the
+    // order of the batches.
+    pub(crate) fn from_batches(
+        seq: u64,
+        batches: Vec<(Tree, Batch)>,
+        previous: Option<Vec<Batch>>,
+    ) -> Event {
+        let decoded_previous = previous.map(|previous_batches| {
+            let decoded: Vec<_> = previous_batches
+                .into_iter()
+                .zip(&batches)
+                .map(|(previous_batch, (tree, _))| {
+                    tree.order.decode_batch(previous_batch)
+                })
+                .collect();
+            Arc::from(decoded.into_boxed_slice())
+        });
+        let decoded: Vec<_> = batches
+            .into_iter()
+            .map(|(tree, batch)| {
+                let order = tree.order;
+                (tree, order.decode_batch(batch))
+            })
+            .collect();
+        Event {
+            seq,
+            batches: Arc::from(decoded.into_boxed_slice()),
+            previous: decoded_previous,
+            missed: 0,
+        }
+    }
+
+    /// The sequence number of this `Event`, which is the LSN of
+    /// the write that it was made for, or of the start of the
+    /// batch or transaction. Sequence numbers are unique and
+    /// keep increasing across restarts, and those of the events
+    /// of a `Tree` that retains its history, see
+    /// `Config::event_history`, increase in the order that the
+    /// events are received in. Pass one more than the sequence
+    /// number of the last event that was handled to
+    /// `Tree::watch_prefix_since` to carry on from it.
+    pub fn seq(&self) -> u64 {
+        self.seq
     }
 
-    pub(crate) fn from_batches(batches: Vec<(Tree, Batch)>) -> Event {
-        Event { batches: Arc::from(batches.into_boxed_slice()) }
+    /// The number of events that the subscriber missed right
+    /// before this one, because they were dropped from its full
+    /// queue under `SubscriberOverflow::DropOldest`. It may count
+    /// the reservations of writes that were retried as well.
+    pub fn missed(&self) -> u64 {
+        self.missed
     }
 
     /// Iterate over each Tree, key, and optional value in this `Event`
@@ -48,8 +126,36 @@ impl Event {
     {
         self.into_iter()
     }
+
+    /// Iterate over each Tree, key, the optional value that the
+    /// key held before this `Event`, and its optional new value.
+    /// Returns `None` if the event doesn't carry previous values,
+    /// because none of the subscribers that it was sent to asked
+    /// for them with `EventFilter::previous_values`. Replayed
+    /// events never carry them.
+    pub fn iter_with_previous<'a>(&'a self) -> Option<PreviousIter<'a>> {
+        let previous = self.previous.as_ref()?;
+        Some(Box::new(self.batches.iter().zip(previous.iter()).flat_map(
+            |((tree, batch), previous_batch)| {
+                batch.writes.iter().zip(previous_batch.writes.values()).map(
+                    move |((k, v_opt), previous_opt)| {
+                        (tree, k, previous_opt, v_opt)
+                    },
+                )
+            },
+        )))
+    }
 }
 
+/// The iterator that `Event::iter_with_previous` returns, of
+/// each Tree, key, optional previous value and optional value.
+pub type PreviousIter<'a> = Box<
+    dyn 'a
+        + Iterator<
+            Item = (&'a Tree, &'a IVec, &'a Option<IVec>, &'a Option<IVec>),
+        >,
+>;
+
 impl<'a> IntoIterator for &'a Event {
     type Item = (&'a Tree, &'a IVec, &'a Option<IVec>);
     type IntoIter = Box<dyn 'a + Iterator<Item = Self::Item>>;
@@ -61,7 +167,166 @@ impl<'a> IntoIterator for &'a Event {
     }
 }
 
-type Senders = Map<usize, (Option<Waker>, SyncSender<OneShot<Option<Event>>>)>;
+// the filter of a subscriber is kept with the order of the keys of
+// its tree, so that its key predicate sees them decoded
+type Filter = Option<(EventFilter, KeyOrder)>;
+
+type Senders = Map<usize, (Option<Waker>, Arc<Queue>, Filter)>;
+
+// the events that are reserved for a subscriber, which are at most
+// `Config::subscriber_capacity`
+#[derive(Debug)]
+struct Queue {
+    state: Mutex<QueueState>,
+    // signaled when an event is queued or taken, and when the
+    // queue is closed or abandoned
+    cv: Condvar,
+    capacity: usize,
+    overflow: SubscriberOverflow,
+}
+
+#[derive(Debug)]
+struct QueueState {
+    // the events with the bytes of their keys and values
+    events: VecDeque<(OneShot<Option<Event>>, u64)>,
+    // the bytes of the queued events, see `Config::memory_limit`
+    charge: memory::Charge,
+    // the events that were dropped since one was last taken
+    missed: u64,
+    // no more events are queued once the tree is gone, or the
+    // queue overflowed under `SubscriberOverflow::Disconnect`
+    closed: bool,
+    // the subscriber is gone
+    abandoned: bool,
+}
+
+impl Queue {
+    fn new(config: &RunningConfig) -> Queue {
+        let charge = memory::Charge::new(
+            &config.memory,
+            memory::Kind::SubscriberQueues,
+            0,
+        );
+        let state = QueueState {
+            events: VecDeque::new(),
+            charge,
+            missed: 0,
+            closed: false,
+            abandoned: false,
+        };
+        Queue {
+            state: Mutex::new(state),
+            cv: Condvar::new(),
+            capacity: config.subscriber_capacity,
+            overflow: config.subscriber_overflow,
+        }
+    }
+
+    // returns `false` if the event can't be queued, because the
+    // queue is closed or abandoned. `bytes` are those of the keys
+    // and values of the event.
+    fn send(&self, event: OneShot<Option<Event>>, bytes: u64) -> bool {
+        let mut state = self.state.lock();
+        while !state.closed
+            && !state.abandoned
+            && state.events.len() >= self.capacity
+        {
+            match self.overflow {
+                SubscriberOverflow::Block => self.cv.wait(&mut state),
+                SubscriberOverflow::DropOldest => {
+                    let _dropped = Queue::pop(&mut state);
+                    state.missed += 1;
+                }
+                SubscriberOverflow::Disconnect => {
+                    warn!("disconnecting a subscriber that fell behind");
+                    state.closed = true;
+                }
+            }
+        }
+        if state.closed || state.abandoned {
+            self.cv.notify_all();
+            return false;
+        }
+        let queued = state.charge.bytes() + bytes;
+        state.charge.set(queued);
+        state.events.push_back((event, bytes));
+        self.cv.notify_all();
+        true
+    }
+
+    fn pop(state: &mut QueueState) -> Option<OneShot<Option<Event>>> {
+        let (event, bytes) = state.events.pop_front()?;
+        let queued = state.charge.bytes() - bytes;
+        state.charge.set(queued);
+        Some(event)
+    }
+
+    // takes the oldest event, along with the number of events that
+    // were dropped before it
+    fn take(state: &mut QueueState) -> Option<(OneShot<Option<Event>>, u64)> {
+        let event = Queue::pop(state)?;
+        Some((event, std::mem::replace(&mut state.missed, 0)))
+    }
+
+    fn recv(&self) -> Option<(OneShot<Option<Event>>, u64)> {
+        let mut state = self.state.lock();
+        loop {
+            if let Some(taken) = Queue::take(&mut state) {
+                self.cv.notify_all();
+                return Some(taken);
+            }
+            if state.closed {
+                return None;
+            }
+            self.cv.wait(&mut state);
+        }
+    }
+
+    fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> std::result::Result<(OneShot<Option<Event>>, u64), RecvTimeoutError>
+    {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock();
+        loop {
+            if let Some(taken) = Queue::take(&mut state) {
+                self.cv.notify_all();
+                return Ok(taken);
+            }
+            if state.closed {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            if self.cv.wait_until(&mut state, deadline).timed_out() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+        }
+    }
+
+    fn try_recv(
+        &self,
+    ) -> std::result::Result<(OneShot<Option<Event>>, u64), TryRecvError> {
+        let mut state = self.state.lock();
+        if let Some(taken) = Queue::take(&mut state) {
+            self.cv.notify_all();
+            Ok(taken)
+        } else if state.closed {
+            Err(TryRecvError::Disconnected)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().closed = true;
+        self.cv.notify_all();
+    }
+
+    fn abandon(&self) {
+        self.state.lock().abandoned = true;
+        self.cv.notify_all();
+    }
+}
 
 /// A subscriber listening on a specified prefix
 ///
@@ -112,12 +377,23 @@ type Senders = Map<usize, (Option<Waker>, SyncSender<OneShot<Option<Event>>>)>;
 /// `while let Some(event) = (&mut subscriber).await { /* use it */ }`
 pub struct Subscriber {
     id: usize,
-    rx: Receiver<OneShot<Option<Event>>>,
+    rx: Arc<Queue>,
     home: Arc<RwLock<Senders>>,
+    // events replayed from the history of the tree, which are
+    // received before the ones that are sent to `rx`
+    replayed: RefCell<VecDeque<Event>>,
+    // events sent to `rx` that are older than this were
+    // already replayed
+    skip_below: u64,
+    // the events that were dropped before the next one
+    missed: Cell<u64>,
 }
 
 impl Drop for Subscriber {
     fn drop(&mut self) {
+        // releases writers that are blocked on the full queue,
+        // which hold the lock that is taken below
+        self.rx.abandon();
         let mut w_senders = self.home.write();
         w_senders.remove(&self.id);
     }
@@ -130,10 +406,13 @@ impl Subscriber {
     pub fn next_timeout(
         &self,
         mut timeout: Duration,
-    ) -> std::result::Result<Event, std::sync::mpsc::RecvTimeoutError> {
+    ) -> std::result::Result<Event, RecvTimeoutError> {
+        if let Some(event) = self.replayed.borrow_mut().pop_front() {
+            return Ok(event);
+        }
         loop {
             let start = Instant::now();
-            let future_rx = self.rx.recv_timeout(timeout)?;
+            let future_rx = self.receive(self.rx.recv_timeout(timeout)?);
             timeout =
                 if let Some(timeout) = timeout.checked_sub(start.elapsed()) {
                     timeout
@@ -142,8 +421,14 @@ impl Subscriber {
                 };
 
             let start = Instant::now();
-            if let Some(event) = future_rx.wait_timeout(timeout)? {
-                return Ok(event);
+            match future_rx.wait_timeout(timeout) {
+                Ok(Some(event)) if self.is_fresh(&event) => {
+                    return Ok(self.deliver(event));
+                }
+                // a write whose reservation is dropped was retried or
+                // not made, so it has no event
+                Ok(_) | Err(RecvTimeoutError::Disconnected) => {}
+                Err(timed_out) => return Err(timed_out),
             }
             timeout =
                 if let Some(timeout) = timeout.checked_sub(start.elapsed()) {
@@ -153,22 +438,57 @@ impl Subscriber {
                 };
         }
     }
+
+    // replays `events` before the events that are sent to this
+    // subscriber, skipping those that are sent to it as well
+    pub(crate) fn replay(&mut self, events: VecDeque<Event>, since: u64) {
+        let last = events.back().map(|event| event.seq);
+        self.skip_below =
+            last.map_or(since, |seq| std::cmp::max(since, seq + 1));
+        *self.replayed.get_mut() = events;
+    }
+
+    fn is_fresh(&self, event: &Event) -> bool {
+        event.seq >= self.skip_below
+    }
+
+    fn receive(
+        &self,
+        (future_rx, missed): (OneShot<Option<Event>>, u64),
+    ) -> OneShot<Option<Event>> {
+        self.missed.set(self.missed.get() + missed);
+        future_rx
+    }
+
+    fn deliver(&self, mut event: Event) -> Event {
+        event.missed = self.missed.replace(0);
+        event
+    }
 }
 
 impl Future for Subscriber {
     type Output = Option<Event>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(event) = self.replayed.borrow_mut().pop_front() {
+            return Poll::Ready(Some(event));
+        }
         loop {
             match self.rx.try_recv() {
-                Ok(mut future_rx) => {
+                Ok(taken) => {
+                    let mut future_rx = self.receive(taken);
                     #[allow(unsafe_code)]
                     let future_rx =
                         unsafe { std::pin::Pin::new_unchecked(&mut future_rx) };
 
                     match Future::poll(future_rx, cx) {
-                        Poll::Ready(Some(event)) => {
-                            return Poll::Ready(event);
+                        Poll::Ready(Some(Some(event))) => {
+                            if self.is_fresh(&event) {
+                                return Poll::Ready(Some(self.deliver(event)));
+                            }
+                        }
+                        Poll::Ready(Some(None)) => {
+                            return Poll::Ready(None);
                         }
                         Poll::Ready(None) => {
                             continue;
@@ -193,10 +513,17 @@ impl Iterator for Subscriber {
     type Item = Event;
 
     fn next(&mut self) -> Option<Event> {
+        if let Some(event) = self.replayed.get_mut().pop_front() {
+            return Some(event);
+        }
         loop {
-            let future_rx = self.rx.recv().ok()?;
+            let future_rx = self.rx.recv().map(|taken| self.receive(taken))?;
             match future_rx.wait() {
-                Some(Some(event)) => return Some(event),
+                Some(Some(event)) => {
+                    if self.is_fresh(&event) {
+                        return Some(self.deliver(event));
+                    }
+                }
                 Some(None) => return None,
                 None => continue,
             }
@@ -204,10 +531,103 @@ impl Iterator for Subscriber {
     }
 }
 
+/// A watch on the next write to a single key of a `Tree`, see
+/// `Tree::watch_key`.
+///
+/// It resolves to the new value of the key, which is `None` if
+/// the key was removed, either by blocking in `wait` or
+/// `wait_timeout`, or as a `Future<Output=Option<Option<IVec>>>`.
+/// Like a `Subscriber`, it receives the writes to the key from
+/// the moment that it is created, and should be dropped once it
+/// is no longer needed so that they aren't queued for it.
+pub struct KeyWatch {
+    tree_id: IVec,
+    key: IVec,
+    subscriber: Subscriber,
+}
+
+impl KeyWatch {
+    pub(crate) fn new(
+        tree_id: IVec,
+        key: IVec,
+        subscriber: Subscriber,
+    ) -> KeyWatch {
+        KeyWatch { tree_id, key, subscriber }
+    }
+
+    /// Blocks until the key is written, returning its new value.
+    /// Returns `None` if the backing `Db` shuts down first.
+    pub fn wait(mut self) -> Option<Option<IVec>> {
+        loop {
+            let event = self.subscriber.next()?;
+            if let Some(value) = self.value_in(&event) {
+                return Some(value);
+            }
+        }
+    }
+
+    /// Waits for the key to be written like `wait`, returning an
+    /// error if it isn't within the provided `Duration` or if the
+    /// backing `Db` shuts down.
+    pub fn wait_timeout(
+        self,
+        timeout: Duration,
+    ) -> std::result::Result<Option<IVec>, RecvTimeoutError> {
+        self.next_timeout(timeout)
+    }
+
+    pub(crate) fn next_timeout(
+        &self,
+        timeout: Duration,
+    ) -> std::result::Result<Option<IVec>, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline
+                .checked_duration_since(Instant::now())
+                .unwrap_or_else(|| Duration::from_nanos(0));
+            let event = self.subscriber.next_timeout(remaining)?;
+            if let Some(value) = self.value_in(&event) {
+                return Ok(value);
+            }
+        }
+    }
+
+    // the value that an event wrote to the key, if it wrote it. the
+    // events of transactions may write the same key to other trees
+    fn value_in(&self, event: &Event) -> Option<Option<IVec>> {
+        event
+            .iter()
+            .find(|(tree, k, _)| {
+                tree.tree_id == self.tree_id && **k == self.key
+            })
+            .map(|(_, _, value)| value.clone())
+    }
+}
+
+impl Future for KeyWatch {
+    type Output = Option<Option<IVec>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            match Pin::new(&mut this.subscriber).poll(cx) {
+                Poll::Ready(Some(event)) => {
+                    if let Some(value) = this.value_in(&event) {
+                        return Poll::Ready(Some(value));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub(crate) struct Subscribers {
     watched: RwLock<BTreeMap<Vec<u8>, Arc<RwLock<Senders>>>>,
     ever_used: AtomicBool,
+    ever_wanted_previous: AtomicBool,
 }
 
 impl Drop for Subscribers {
@@ -216,8 +636,8 @@ impl Drop for Subscribers {
 
         for senders in watched.values() {
             let senders = std::mem::take(&mut *senders.write());
-            for (_, (waker, sender)) in senders {
-                drop(sender);
+            for (_, (waker, sender, _)) in senders {
+                sender.close();
                 if let Some(waker) = waker {
                     waker.wake();
                 }
@@ -227,8 +647,16 @@ impl Drop for Subscribers {
 }
 
 impl Subscribers {
-    pub(crate) fn register(&self, prefix: &[u8]) -> Subscriber {
+    pub(crate) fn register(
+        &self,
+        prefix: &[u8],
+        filter: Filter,
+        config: &RunningConfig,
+    ) -> Subscriber {
         self.ever_used.store(true, Relaxed);
+        if wants_previous_values(&filter) {
+            self.ever_wanted_previous.store(true, Relaxed);
+        }
         let r_mu = {
             let r_mu = self.watched.read();
             if r_mu.contains_key(prefix) {
@@ -248,16 +676,30 @@ impl Subscribers {
             }
         };
 
-        let (tx, rx) = sync_channel(1024);
+        let rx = Arc::new(Queue::new(config));
 
         let arc_senders = &r_mu[prefix];
         let mut w_senders = arc_senders.write();
 
         let id = ID_GEN.fetch_add(1, Relaxed);
 
-        w_senders.insert(id, (None, tx));
+        w_senders.insert(id, (None, rx.clone(), filter));
 
-        Subscriber { id, rx, home: arc_senders.clone() }
+        Subscriber {
+            id,
+            rx,
+            home: arc_senders.clone(),
+            replayed: RefCell::new(VecDeque::new()),
+            skip_below: 0,
+            missed: Cell::new(0),
+        }
+    }
+
+    // transactions read the previous values of the keys that they
+    // write before their event is made, once any subscriber of
+    // the tree asked for them
+    pub(crate) fn ever_wanted_previous(&self) -> bool {
+        self.ever_wanted_previous.load(Relaxed)
     }
 
     pub(crate) fn reserve_batch(
@@ -270,36 +712,63 @@ impl Subscribers {
 
         let r_mu = self.watched.read();
 
-        let mut skip_indices = std::collections::HashSet::new();
         let mut subscribers = vec![];
+        let mut wants_previous = false;
+        let bytes: usize = batch
+            .writes
+            .iter()
+            .map(|(key, value)| {
+                key.len() + value.as_ref().map_or(0, |new| new.len())
+            })
+            .sum();
+
+        for (prefix, subs_rwl) in r_mu.iter() {
+            let mut watched = batch
+                .writes
+                .iter()
+                .filter(|(key, _)| key.starts_with(prefix))
+                .peekable();
+            if watched.peek().is_none() {
+                continue;
+            }
+            let subs = subs_rwl.read();
 
-        for key in batch.writes.keys() {
-            for (idx, (prefix, subs_rwl)) in r_mu.iter().enumerate() {
-                if key.starts_with(prefix) && !skip_indices.contains(&idx) {
-                    skip_indices.insert(idx);
-                    let subs = subs_rwl.read();
-
-                    for (_id, (waker, sender)) in subs.iter() {
-                        let (tx, rx) = OneShot::pair();
-                        if sender.send(rx).is_err() {
-                            continue;
-                        }
-                        subscribers.push((waker.clone(), tx));
+            for (_id, (waker, sender, filter)) in subs.iter() {
+                if let Some((event_filter, order)) = filter {
+                    let admitted = watched.clone().any(|(key, value)| {
+                        let value_len = value.as_ref().map(|v| v.len());
+                        event_filter.admits(*order, key, value_len)
+                    });
+                    if !admitted {
+                        continue;
                     }
                 }
+                let (tx, rx) = OneShot::pair();
+                if !sender.send(rx, bytes as u64) {
+                    // wakes a subscriber whose queue was closed
+                    if let Some(ref subscriber_waker) = waker {
+                        subscriber_waker.wake_by_ref();
+                    }
+                    continue;
+                }
+                wants_previous |= wants_previous_values(filter);
+                subscribers.push((waker.clone(), tx));
             }
         }
 
         if subscribers.is_empty() {
             None
         } else {
-            Some(ReservedBroadcast { subscribers })
+            Some(ReservedBroadcast { subscribers, wants_previous })
         }
     }
 
+    // reserves the broadcast of the event of a write of a value of
+    // `value_len` bytes to `key`, or of its removal if it is `None`
     pub(crate) fn reserve<R: AsRef<[u8]>>(
         &self,
         key: R,
+        value_len: Option<usize>,
     ) -> Option<ReservedBroadcast> {
         if !self.ever_used.load(Relaxed) {
             return None;
@@ -307,17 +776,29 @@ impl Subscribers {
 
         let r_mu = self.watched.read();
         let prefixes = r_mu.iter().filter(|(k, _)| key.as_ref().starts_with(k));
+        let bytes = key.as_ref().len() + value_len.unwrap_or(0);
 
         let mut subscribers = vec![];
+        let mut wants_previous = false;
 
         for (_, subs_rwl) in prefixes {
             let subs = subs_rwl.read();
 
-            for (_id, (waker, sender)) in subs.iter() {
+            for (_id, (waker, sender, filter)) in subs.iter() {
+                if let Some((event_filter, order)) = filter {
+                    if !event_filter.admits(*order, key.as_ref(), value_len) {
+                        continue;
+                    }
+                }
                 let (tx, rx) = OneShot::pair();
-                if sender.send(rx).is_err() {
+                if !sender.send(rx, bytes as u64) {
+                    // wakes a subscriber whose queue was closed
+                    if let Some(ref subscriber_waker) = waker {
+                        subscriber_waker.wake_by_ref();
+                    }
                     continue;
                 }
+                wants_previous |= wants_previous_values(filter);
                 subscribers.push((waker.clone(), tx));
             }
         }
@@ -325,13 +806,23 @@ impl Subscribers {
         if subscribers.is_empty() {
             None
         } else {
-            Some(ReservedBroadcast { subscribers })
+            Some(ReservedBroadcast { subscribers, wants_previous })
         }
     }
 }
 
 pub(crate) struct ReservedBroadcast {
     subscribers: Vec<(Option<Waker>, OneShotFiller<Option<Event>>)>,
+    // whether the event has to carry the previous values
+    pub(crate) wants_previous: bool,
+}
+
+fn wants_previous_values(filter: &Filter) -> bool {
+    if let Some((event_filter, _)) = filter {
+        event_filter.previous_values
+    } else {
+        false
+    }
 }
 
 impl ReservedBroadcast {
@@ -238,9 +238,13 @@ pub fn truncate(config: crate::RunningConfig, at: u64) -> OneShot<Result<()>> {
     spawn_to(
         move || {
             log::debug!("truncating file to length {}", at);
-            config
-                .file
-                .set_len(at)
+            let _io_barrier = config.io_barrier.read();
+            let truncated = if let Some(ref mmaps) = config.mmaps {
+                mmaps.truncate(&config.file, at)
+            } else {
+                config.file.set_len(at)
+            };
+            truncated
                 .and_then(|_| config.file.sync_all())
                 .map_err(|e| e.into())
         },
@@ -79,11 +79,27 @@
 //! # }
 //! ```
 #![allow(clippy::module_name_repetitions)]
-use std::{cell::RefCell, fmt, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::hash_map::RandomState,
+    convert::TryFrom,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    rc::Rc,
+    sync::Arc,
+    time::Duration,
+};
+
+use parking_lot::Mutex;
 
 use crate::{
-    concurrency_control, pin, Batch, Error, Event, Guard, IVec, Map, Protector,
-    Result, Tree,
+    concurrency_control, history, index,
+    key_lock::{KeyLockGuard, KeyLocks},
+    latency::{Operation, Stopwatch},
+    memory,
+    spill::Spill,
+    pin, Batch, Context, Error, Event, Guard, IVec, Map, Protector, Result,
+    Tree,
 };
 
 /// A transaction that will
@@ -104,7 +120,8 @@ pub enum UnabortableTransactionError {
     /// An internal conflict has occurred and the `transaction` method will
     /// retry the passed-in closure until it succeeds. This should never be
     /// returned directly from the user's closure, as it will create an
-    /// infinite loop that never returns. This is why it is hidden.
+    /// infinite loop that never returns. `Txn::commit` returns it when the
+    /// transaction has to be begun again.
     Conflict,
     /// A serious underlying storage issue has occurred that requires
     /// attention from an operator or a remediating system, such as
@@ -209,6 +226,14 @@ pub enum TransactionError<T = Error> {
     /// attention from an operator or a remediating system, such as
     /// corruption.
     Storage(Error),
+    /// The keys that a transaction locks could not all be locked
+    /// within `TxnOptions::timeout`, because other transactions
+    /// held them. It may be deadlocked with a transaction that the
+    /// same thread holds.
+    Timeout,
+    /// The transaction conflicted on every attempt that its
+    /// `RetryPolicy` allows.
+    Conflict,
 }
 
 impl<E: fmt::Display> fmt::Display for TransactionError<E> {
@@ -217,6 +242,8 @@ impl<E: fmt::Display> fmt::Display for TransactionError<E> {
         match self {
             Abort(e) => e.fmt(f),
             Storage(e) => e.fmt(f),
+            Timeout => write!(f, "Timed out locking the keys of a transaction"),
+            Conflict => write!(f, "Transaction conflicted on every attempt"),
         }
     }
 }
@@ -284,6 +311,69 @@ impl TransactionalTree {
         old
     }
 
+    /// Merge a value into a key using the merge operator of the
+    /// `Tree`, returning the new value. Returns an
+    /// `Error::Unsupported` if no merge operator is set, see
+    /// `Tree::merge`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use sled::{merge::Counter, transaction::TransactionResult, Config};
+    /// # fn main() -> TransactionResult<()> {
+    /// let db = Config::new().temporary(true).open()?;
+    /// db.set_merge_operator(Counter::merge);
+    ///
+    /// db.transaction(|db| {
+    ///     db.merge(b"stock", Counter::delta(-1))?;
+    ///     db.merge(b"sold", Counter::delta(1))?;
+    ///     Ok(())
+    /// })?;
+    ///
+    /// assert_eq!(Counter::decode(&db.get(b"sold")?.unwrap()), Some(1));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn merge<K, V>(
+        &self,
+        key: K,
+        value: V,
+    ) -> UnabortableTransactionResult<Option<IVec>>
+    where
+        K: AsRef<[u8]> + Into<IVec>,
+        V: AsRef<[u8]>,
+    {
+        let merge_operator =
+            if let Some(ref merge_operator) = *self.tree.merge_operator.read()
+            {
+                merge_operator.clone()
+            } else {
+                return Err(Error::Unsupported(
+                    "must set a merge operator on this Tree \
+                     before calling merge by calling \
+                     Tree::set_merge_operator"
+                        .to_owned(),
+                )
+                .into());
+            };
+
+        let old = self.get(key.as_ref())?;
+        let new = merge_operator(
+            key.as_ref(),
+            old.as_ref().map(AsRef::as_ref),
+            value.as_ref(),
+        )
+        .map(IVec::from);
+
+        let mut writes = self.writes.borrow_mut();
+        if let Some(ref merged) = new {
+            writes.insert(key, merged.clone());
+        } else {
+            writes.remove(key);
+        }
+        Ok(new)
+    }
+
     /// Get the value associated with a key
     pub fn get<K: AsRef<[u8]>>(
         &self,
@@ -299,9 +389,10 @@ impl TransactionalTree {
         }
 
         // not found in a cache, need to hit the backing db
+        let stored_key = self.tree.order.encode(key.as_ref());
         let mut guard = pin();
         let get = loop {
-            if let Ok(get) = self.tree.get_inner(key.as_ref(), &mut guard)? {
+            if let Ok(get) = self.tree.get_inner(&stored_key, &mut guard)? {
                 break get;
             }
         };
@@ -326,6 +417,47 @@ impl TransactionalTree {
         Ok(())
     }
 
+    /// Remembers the writes that the transaction made to this tree
+    /// so far, so that the writes after it can be undone with
+    /// `rollback_to` without aborting the transaction.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use sled::{transaction::TransactionResult, Config};
+    /// # fn main() -> TransactionResult<()> {
+    /// let db = Config::new().temporary(true).open()?;
+    ///
+    /// db.transaction(|db| {
+    ///     db.insert(b"step 1", b"done")?;
+    ///     let savepoint = db.savepoint();
+    ///     db.insert(b"step 2", b"done")?;
+    ///     db.insert(b"step 3", b"failed")?;
+    ///     db.rollback_to(&savepoint);
+    ///     Ok(())
+    /// })?;
+    ///
+    /// assert!(db.contains_key(b"step 1")?);
+    /// assert!(!db.contains_key(b"step 2")?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn savepoint(&self) -> Savepoint {
+        let writes = self.writes.borrow().clone();
+        Savepoint {
+            writes: vec![(self.tree.tree_id.clone(), writes)],
+            runs: 0,
+        }
+    }
+
+    /// Undoes the writes that were made to this tree since the
+    /// `savepoint` was taken from it. The keys that were read
+    /// since are still checked for conflicts when the transaction
+    /// commits. A savepoint can be rolled back to more than once.
+    pub fn rollback_to(&self, savepoint: &Savepoint) {
+        *self.writes.borrow_mut() = savepoint.writes_of(&self.tree);
+    }
+
     /// Flush the database before returning from the transaction.
     pub fn flush(&self) {
         *self.flush_on_commit.borrow_mut() = true;
@@ -353,12 +485,6 @@ impl TransactionalTree {
         true
     }
 
-    fn commit(&self, event: Event) -> Result<()> {
-        let writes = std::mem::take(&mut *self.writes.borrow_mut());
-        let mut guard = pin();
-        self.tree.apply_batch_inner(writes, Some(event), &mut guard)
-    }
-
     fn from_tree(tree: &Tree) -> Self {
         Self {
             tree: tree.clone(),
@@ -379,6 +505,10 @@ impl TransactionalTrees {
         concurrency_control::write()
     }
 
+    fn first_tree(&self) -> Option<Tree> {
+        Some(self.inner.first()?.tree.clone())
+    }
+
     fn unstage(&self) {
         for tree in &self.inner {
             tree.unstage();
@@ -395,24 +525,19 @@ impl TransactionalTrees {
     }
 
     fn commit(&self, guard: &Guard) -> Result<()> {
-        let peg = self.inner[0].tree.context.pin_log(guard)?;
-
-        let batches = self
+        tracing_span!("transaction.commit", trees = self.inner.len());
+        for tree in &self.inner {
+            index::check_registered(&tree.tree)?;
+        }
+        let batches: Vec<_> = self
             .inner
             .iter()
-            .map(|tree| (tree.tree.clone(), tree.writes.borrow().clone()))
+            .map(|tree| {
+                let writes = std::mem::take(&mut *tree.writes.borrow_mut());
+                (tree.tree.clone(), tree.tree.order.encode_batch(writes))
+            })
             .collect();
-
-        let event = Event::from_batches(batches);
-
-        for tree in &self.inner {
-            tree.commit(event.clone())?;
-        }
-
-        // when the peg drops, it ensures all updates
-        // written to the log since its creation are
-        // recovered atomically
-        peg.seal_batch()
+        apply_batches(&batches, guard)
     }
 
     fn flush_if_configured(&self) -> Result<()> {
@@ -432,6 +557,704 @@ impl TransactionalTrees {
     }
 }
 
+/// Encodes batches for their trees, merging those for the same
+/// tree, after checking that the trees belong to the `Db` of the
+/// `context`.
+pub(crate) fn encode_batches<'a, I>(
+    context: &Context,
+    batches: I,
+) -> Result<Vec<(Tree, Batch)>>
+where
+    I: IntoIterator<Item = (&'a Tree, Batch)>,
+{
+    let mut encoded: Vec<(Tree, Batch)> = vec![];
+    for (tree, batch) in batches {
+        if !std::ptr::eq(&*tree.context.pagecache, &*context.pagecache) {
+            return Err(Error::Unsupported(format!(
+                "tree {:?} belongs to another Db",
+                tree.name()
+            )));
+        }
+        index::check_registered(tree)?;
+        let writes = tree.order.encode_batch(batch).writes;
+        if let Some((_, merged)) =
+            encoded.iter_mut().find(|(t, _)| t.tree_id == tree.tree_id)
+        {
+            merged.writes.extend(writes);
+        } else {
+            encoded.push((tree.clone(), Batch { writes }));
+        }
+    }
+    Ok(encoded)
+}
+
+/// Applies the encoded batches of several trees of a `Db` as a
+/// single event that is recovered atomically, which the caller
+/// holds the writer lock for, and which has at least one batch.
+pub(crate) fn apply_batches(
+    batches: &[(Tree, Batch)],
+    guard: &Guard,
+) -> Result<()> {
+    let peg = batches[0].0.context.pin_log(guard)?;
+
+    apply_pinned_batches(batches, history::seq(peg.lsn()))?;
+
+    // when the peg drops, it ensures all updates
+    // written to the log since its creation are
+    // recovered atomically
+    peg.seal_batch()
+}
+
+// applies batches as an event with `seq` while the log is pinned
+fn apply_pinned_batches(batches: &[(Tree, Batch)], seq: u64) -> Result<()> {
+    let wants_previous =
+        batches.iter().any(|(tree, _)| tree.subscribers.ever_wanted_previous());
+    let previous = if wants_previous {
+        Some(previous_values(batches)?)
+    } else {
+        None
+    };
+
+    let event = Event::from_batches(seq, batches.to_vec(), previous);
+
+    let mut reservations = vec![];
+    let mut tree_guard = pin();
+    for (tree, batch) in batches {
+        reservations.extend(tree.apply_transaction_batch(
+            batch,
+            &event,
+            &mut tree_guard,
+        )?);
+    }
+
+    // the event is sent once every tree is written, so that
+    // subscribers can't read a part of the transaction from
+    // the other trees
+    for reservation in reservations {
+        reservation.complete(&event);
+    }
+
+    Ok(())
+}
+
+// reads the values that the keys of each batch hold before it is
+// applied, which is consistent because the trees are locked
+fn previous_values(batches: &[(Tree, Batch)]) -> Result<Vec<Batch>> {
+    let mut guard = pin();
+    let mut ret = Vec::with_capacity(batches.len());
+    for (tree, batch) in batches {
+        let mut previous = Batch::default();
+        for key in batch.writes.keys() {
+            loop {
+                if let Ok(value) = tree.get_inner(key, &mut guard)? {
+                    previous.writes.insert(key.clone(), value);
+                    break;
+                }
+            }
+        }
+        ret.push(previous);
+    }
+    Ok(ret)
+}
+
+/// Options for `Db::begin_transaction_with`.
+#[derive(Debug, Clone, Default)]
+pub struct TxnOptions {
+    // tree id -> stored key
+    locks: Vec<(IVec, IVec)>,
+    timeout: Option<Duration>,
+    spill_after: Option<usize>,
+}
+
+impl TxnOptions {
+    /// Returns the default options, which begin an optimistic
+    /// transaction like `Db::begin_transaction`.
+    pub fn new() -> TxnOptions {
+        TxnOptions::default()
+    }
+
+    /// Locks a key of a `Tree` before the transaction begins, until
+    /// it is committed or dropped, see `Db::begin_locked_transaction`.
+    pub fn lock<K: AsRef<[u8]>>(mut self, tree: &Tree, key: K) -> TxnOptions {
+        let stored_key = IVec::from(&*tree.order.encode(key.as_ref()));
+        self.locks.push((tree.tree_id.clone(), stored_key));
+        self
+    }
+
+    /// Gives up on locking the keys once this much time has passed,
+    /// returning `TransactionError::Timeout`, rather than waiting
+    /// for as long as other transactions hold them. This keeps a
+    /// thread from waiting forever for a transaction that it holds
+    /// itself, or that is never committed.
+    pub fn timeout(mut self, timeout: Duration) -> TxnOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Moves the writes of the transaction to a temporary file next
+    /// to the database whenever the ones that it holds in memory
+    /// take more than `bytes`, so that it can write more keys than
+    /// fit in memory. The files are removed once the transaction
+    /// is committed or dropped. An error that happens while moving
+    /// the writes is returned by the next `get` or by `commit`.
+    ///
+    /// Committing reads the writes back in chunks of about `bytes`
+    /// and applies them while holding the writer lock and keeping
+    /// the log pinned, so that other readers and recovery still see
+    /// all of them or none. Subscribers receive each chunk as an
+    /// event of its own, and all of them have the sequence number
+    /// of the transaction.
+    ///
+    /// The files would hold the writes in plaintext, so beginning
+    /// a transaction that may spill on a database that is opened
+    /// with `Config::encryption` returns `Error::Unsupported`.
+    pub fn spill_after(mut self, bytes: usize) -> TxnOptions {
+        self.spill_after = Some(bytes);
+        self
+    }
+}
+
+/// A transaction that is driven by calling its methods, returned
+/// by `Db::begin_transaction`.
+///
+/// Like the closure-based transactions, it is optimistic: reads
+/// go to the trees as they are and are remembered, and writes are
+/// buffered, so that every read of the transaction sees its own
+/// writes. `commit` then checks, while holding the writer lock,
+/// that each key that was read still holds the value that was
+/// read, and applies every write atomically if so. Otherwise it
+/// returns `UnabortableTransactionError::Conflict`, and the
+/// transaction can be begun again by the caller. Dropping the
+/// transaction, or calling `abort`, discards its writes.
+/// Transactions that are begun with `Db::begin_locked_transaction`
+/// lock their keys up front instead, and release them then.
+///
+/// Either way, transactions are serializable, and write skew is
+/// detected too: two transactions that read the same keys and
+/// then each change a different one of them can't both commit,
+/// because the second finds that a key it read was changed. An
+/// invariant across several keys is kept by reading all of them,
+/// without writing any key only so that it conflicts.
+///
+/// It is `Send`, so it can be held across `await` points.
+#[derive(Debug)]
+pub struct Txn {
+    context: Context,
+    trees: Vec<TxnTree>,
+    // the keys that were locked up front, which are released
+    // when the transaction is dropped
+    _locks: Option<KeyLockGuard>,
+    // the writes that were moved out of memory, with an estimate
+    // of the size of those that are still in it
+    spill: Option<Spill>,
+    buffered: usize,
+    spill_error: Option<Error>,
+    // counts `buffered` against the memory of the `Db`, see
+    // `Config::memory_limit`
+    charge: memory::Charge,
+}
+
+#[derive(Debug)]
+struct TxnTree {
+    tree: Tree,
+    // stored key -> the value that was read for it
+    reads: Map<IVec, Option<IVec>>,
+    writes: Batch,
+}
+
+impl Txn {
+    pub(crate) fn new(context: Context) -> Txn {
+        let charge =
+            memory::Charge::new(&context.memory, memory::Kind::Transactions, 0);
+        Txn {
+            context,
+            trees: vec![],
+            _locks: None,
+            spill: None,
+            buffered: 0,
+            spill_error: None,
+            charge,
+        }
+    }
+
+    pub(crate) fn with_options(
+        context: Context,
+        options: TxnOptions,
+    ) -> TransactionResult<Txn> {
+        if options.spill_after.is_some() && context.encryption.is_some() {
+            return Err(TransactionError::Storage(Error::Unsupported(
+                "the writes of a transaction can't be spilled to a file \
+                 when the database is encrypted"
+                    .into(),
+            )));
+        }
+        let locks = if options.locks.is_empty() {
+            None
+        } else {
+            let locks = KeyLocks::lock(
+                &context.key_locks,
+                options.locks,
+                options.timeout,
+            );
+            Some(locks.ok_or(TransactionError::Timeout)?)
+        };
+        let spill = options
+            .spill_after
+            .map(|limit| Spill::new(context.get_path(), limit));
+        let charge =
+            memory::Charge::new(&context.memory, memory::Kind::Transactions, 0);
+        Ok(Txn {
+            context,
+            trees: vec![],
+            _locks: locks,
+            spill,
+            buffered: 0,
+            spill_error: None,
+            charge,
+        })
+    }
+
+    /// Retrieve a value from a `Tree` if it exists, as of the
+    /// writes of this transaction.
+    pub fn get<K: AsRef<[u8]>>(
+        &mut self,
+        tree: &Tree,
+        key: K,
+    ) -> Result<Option<IVec>> {
+        if let Some(e) = &self.spill_error {
+            return Err(e.clone());
+        }
+        let position = self.position(tree);
+        let written = &mut self.trees[position];
+        if let Some(value) = written.writes.writes.get(key.as_ref()) {
+            return Ok(value.clone());
+        }
+        if let Some(spill) = &self.spill {
+            let run_position = u32::try_from(position).unwrap();
+            if let Some(value) = spill.get(run_position, key.as_ref())? {
+                return Ok(value);
+            }
+        }
+        let stored_key = IVec::from(&*tree.order.encode(key.as_ref()));
+        if let Some(value) = written.reads.get(&stored_key) {
+            return Ok(value.clone());
+        }
+        let value = tree.get(key)?;
+        let _ = written.reads.insert(stored_key, value.clone());
+        Ok(value)
+    }
+
+    /// Set a key of a `Tree` to a new value once the transaction
+    /// is committed. The current value isn't read, so it isn't
+    /// checked for either, unless it was read with `get`.
+    pub fn insert<K, V>(&mut self, tree: &Tree, key: K, value: V)
+    where
+        K: AsRef<[u8]>,
+        V: Into<IVec>,
+    {
+        let new = value.into();
+        self.buffered += key.as_ref().len() + new.len();
+        self.charge.set(self.buffered as u64);
+        self.tree(tree).writes.insert(key.as_ref(), new);
+        self.spill_if_full();
+    }
+
+    /// Remove a key from a `Tree` once the transaction is
+    /// committed, see `insert`.
+    pub fn remove<K: AsRef<[u8]>>(&mut self, tree: &Tree, key: K) {
+        self.buffered += key.as_ref().len();
+        self.charge.set(self.buffered as u64);
+        self.tree(tree).writes.remove(key.as_ref());
+        self.spill_if_full();
+    }
+
+    // moves the writes in memory to a new run once they take more
+    // than the limit of the spill
+    fn spill_if_full(&mut self) {
+        let Txn { spill, trees, buffered, spill_error, charge, .. } = self;
+        let runs = if let Some(runs) = spill {
+            runs
+        } else {
+            return;
+        };
+        if *buffered <= runs.limit || spill_error.is_some() {
+            return;
+        }
+        match runs.write_run(buffered_writes(trees)) {
+            Ok(()) => {
+                for written in trees.iter_mut() {
+                    written.writes.writes.clear();
+                }
+                *buffered = 0;
+                charge.set(0);
+            }
+            Err(e) => *spill_error = Some(e),
+        }
+    }
+
+    /// Atomically applies the writes of the transaction if none of
+    /// the keys that it read was changed since, returning
+    /// `UnabortableTransactionError::Conflict` otherwise.
+    pub fn commit(
+        mut self,
+    ) -> std::result::Result<(), UnabortableTransactionError> {
+        if let Some(e) = self.spill_error.take() {
+            return Err(e.into());
+        }
+        if let Some(spill) = self.spill.take() {
+            if spill.runs() > 0 {
+                return self.commit_spilled(spill);
+            }
+        }
+
+        let batches = encode_batches(
+            &self.context,
+            self.trees
+                .iter_mut()
+                .filter(|written| !written.writes.writes.is_empty())
+                .map(|written| {
+                    (&written.tree, std::mem::take(&mut written.writes))
+                }),
+        )?;
+
+        let _cc = concurrency_control::write();
+        let mut guard = pin();
+        self.check_reads(&mut guard)?;
+
+        if !batches.is_empty() {
+            apply_batches(&batches, &guard)?;
+        }
+        Ok(())
+    }
+
+    // commits a transaction whose writes were moved to runs, by
+    // merging them with those still in memory and applying them a
+    // chunk at a time under a single pin of the log
+    fn commit_spilled(
+        self,
+        mut spill: Spill,
+    ) -> std::result::Result<(), UnabortableTransactionError> {
+        for written in &self.trees {
+            let _ = encode_batches(
+                &self.context,
+                vec![(&written.tree, Batch::default())],
+            )?;
+        }
+        spill.write_run(buffered_writes(&self.trees))?;
+
+        let _cc = concurrency_control::write();
+        let mut guard = pin();
+        self.check_reads(&mut guard)?;
+
+        let peg = self.context.pin_log(&guard)?;
+        let seq = history::seq(peg.lsn());
+        let mut chunk: Vec<Batch> =
+            self.trees.iter().map(|_| Batch::default()).collect();
+        let mut chunk_bytes = 0;
+        for res in spill.merge()? {
+            let (position, key, value) = res?;
+            chunk_bytes += key.len() + value.as_ref().map_or(0, |v| v.len());
+            let _ = chunk[usize::try_from(position).unwrap()]
+                .writes
+                .insert(key, value);
+            if chunk_bytes > spill.limit {
+                self.apply_chunk(&mut chunk, seq)?;
+                chunk_bytes = 0;
+            }
+        }
+        self.apply_chunk(&mut chunk, seq)?;
+
+        peg.seal_batch()?;
+        Ok(())
+    }
+
+    fn apply_chunk(&self, chunk: &mut [Batch], seq: u64) -> Result<()> {
+        let batches: Vec<(Tree, Batch)> = self
+            .trees
+            .iter()
+            .zip(chunk.iter_mut())
+            .filter(|(_, batch)| !batch.writes.is_empty())
+            .map(|(written, batch)| {
+                let writes = std::mem::take(batch);
+                (written.tree.clone(), written.tree.order.encode_batch(writes))
+            })
+            .collect();
+        if batches.is_empty() {
+            return Ok(());
+        }
+        apply_pinned_batches(&batches, seq)
+    }
+
+    // checks that each key that was read still holds the value that
+    // was read, while the writer lock is held
+    fn check_reads(
+        &self,
+        guard: &mut Guard,
+    ) -> std::result::Result<(), UnabortableTransactionError> {
+        for written in &self.trees {
+            for (stored_key, read) in &written.reads {
+                let current = loop {
+                    let res = written.tree.get_inner(stored_key, guard)?;
+                    if let Ok(current) = res {
+                        break current;
+                    }
+                };
+                if current != *read {
+                    return Err(UnabortableTransactionError::Conflict);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Discards the writes of the transaction.
+    pub fn abort(self) {}
+
+    /// Remembers the writes of the transaction so far, so that the
+    /// writes after it can be undone with `rollback_to` without
+    /// aborting the transaction.
+    pub fn savepoint(&self) -> Savepoint {
+        let writes = self
+            .trees
+            .iter()
+            .map(|written| {
+                (written.tree.tree_id.clone(), written.writes.clone())
+            })
+            .collect();
+        let runs = self.spill.as_ref().map_or(0, Spill::runs);
+        Savepoint { writes, runs }
+    }
+
+    /// Undoes the writes that were made since the `savepoint` was
+    /// taken from this transaction. The keys that were read since
+    /// are still checked for conflicts by `commit`. A savepoint
+    /// can be rolled back to more than once.
+    pub fn rollback_to(&mut self, savepoint: &Savepoint) {
+        if let Some(spill) = &mut self.spill {
+            spill.truncate(savepoint.runs);
+        }
+        self.buffered = 0;
+        for written in &mut self.trees {
+            written.writes = savepoint.writes_of(&written.tree);
+            self.buffered += written
+                .writes
+                .writes
+                .iter()
+                .map(|(k, v)| k.len() + v.as_ref().map_or(0, |new| new.len()))
+                .sum::<usize>();
+        }
+        self.charge.set(self.buffered as u64);
+    }
+
+    fn tree(&mut self, tree: &Tree) -> &mut TxnTree {
+        let position = self.position(tree);
+        &mut self.trees[position]
+    }
+
+    // returns the position of a tree in the transaction, adding it
+    // if it wasn't used yet
+    fn position(&mut self, tree: &Tree) -> usize {
+        let position = self
+            .trees
+            .iter()
+            .position(|written| written.tree.tree_id == tree.tree_id);
+        if let Some(index) = position {
+            index
+        } else {
+            self.trees.push(TxnTree {
+                tree: tree.clone(),
+                reads: Map::default(),
+                writes: Batch::default(),
+            });
+            self.trees.len() - 1
+        }
+    }
+}
+
+// returns the writes in memory of the trees of a transaction in
+// the order of a run
+fn buffered_writes(
+    trees: &[TxnTree],
+) -> impl Iterator<Item = (u32, &IVec, &Option<IVec>)> {
+    trees.iter().enumerate().flat_map(|(i, written)| {
+        let position = u32::try_from(i).unwrap();
+        written.writes.writes.iter().map(move |(k, v)| (position, k, v))
+    })
+}
+
+/// The writes of a transaction at some point, which it can be
+/// rolled back to, see `Txn::savepoint` and
+/// `TransactionalTree::savepoint`.
+#[derive(Debug, Clone)]
+pub struct Savepoint {
+    // tree id -> the writes to the tree
+    writes: Vec<(IVec, Batch)>,
+    // the number of runs that a `Txn` had spilled
+    runs: usize,
+}
+
+impl Savepoint {
+    fn writes_of(&self, tree: &Tree) -> Batch {
+        self.writes
+            .iter()
+            .find(|(tree_id, _)| *tree_id == tree.tree_id)
+            .map(|(_, writes)| writes.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// The handle that the body of `Tree::transaction_async` reads
+/// and writes its tree with. It can be cloned, and moved into the
+/// future that the body returns. Its operations don't wait for
+/// anything but the tree itself, like those of `Tree`, so they
+/// aren't async.
+#[derive(Debug, Clone)]
+pub struct AsyncTransactionalTree {
+    tree: Tree,
+    txn: Arc<Mutex<Txn>>,
+}
+
+impl AsyncTransactionalTree {
+    pub(crate) fn new(tree: &Tree) -> AsyncTransactionalTree {
+        AsyncTransactionalTree {
+            tree: tree.clone(),
+            txn: Arc::new(Mutex::new(Txn::new(tree.context.clone()))),
+        }
+    }
+
+    /// Retrieve a value from the `Tree` if it exists, as of the
+    /// writes of this transaction.
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>> {
+        self.txn.lock().get(&self.tree, key)
+    }
+
+    /// Set a key to a new value once the transaction is committed.
+    pub fn insert<K, V>(&self, key: K, value: V)
+    where
+        K: AsRef<[u8]>,
+        V: Into<IVec>,
+    {
+        self.txn.lock().insert(&self.tree, key, value);
+    }
+
+    /// Remove a key once the transaction is committed.
+    pub fn remove<K: AsRef<[u8]>>(&self, key: K) {
+        self.txn.lock().remove(&self.tree, key);
+    }
+
+    /// Remembers the writes of the transaction so far, see
+    /// `Txn::savepoint`.
+    pub fn savepoint(&self) -> Savepoint {
+        self.txn.lock().savepoint()
+    }
+
+    /// Undoes the writes that were made since the `savepoint`, see
+    /// `Txn::rollback_to`.
+    pub fn rollback_to(&self, savepoint: &Savepoint) {
+        self.txn.lock().rollback_to(savepoint);
+    }
+
+    // takes the transaction out, leaving an empty one behind for
+    // the clones that the body may have kept
+    pub(crate) fn take(&self) -> Txn {
+        let empty = Txn::new(self.tree.context.clone());
+        std::mem::replace(&mut *self.txn.lock(), empty)
+    }
+}
+
+/// How a transaction is retried when it conflicts, see
+/// `Transactional::transaction_with_retry` and
+/// `Tree::transaction_async_with_retry`.
+///
+/// The default policy retries right away for as long as the
+/// transaction conflicts, as `transaction` and `transaction_async`
+/// do. Under contention, backing off keeps conflicting
+/// transactions from spinning a core while they wait for each
+/// other.
+#[derive(Clone, Default)]
+pub struct RetryPolicy {
+    max_attempts: Option<u32>,
+    // the first delay, and the delay that it doubles up to
+    backoff: Option<(Duration, Duration)>,
+    on_conflict: Option<Arc<dyn Fn(u32) + Send + Sync>>,
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("backoff", &self.backoff)
+            .field("on_conflict", &self.on_conflict.is_some())
+            .finish()
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the default policy, which retries right away and
+    /// never gives up.
+    pub fn new() -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// Runs the transaction at most this many times, returning
+    /// `TransactionError::Conflict` if it conflicted every time.
+    /// A transaction always runs at least once.
+    pub fn max_attempts(mut self, max_attempts: u32) -> RetryPolicy {
+        self.max_attempts = Some(max_attempts.max(1));
+        self
+    }
+
+    /// Waits before each retry for a delay that starts at
+    /// `initial` and doubles after every conflict until it
+    /// reaches `max`. Each wait lasts a random time between half
+    /// of the delay and all of it, so that transactions that
+    /// conflicted with each other retry at different times.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> RetryPolicy {
+        self.backoff = Some((initial, max.max(initial)));
+        self
+    }
+
+    /// Calls `f` each time the transaction conflicts, with the
+    /// number of times that it has run so far, before it is
+    /// retried or given up on, for example to count conflicts.
+    pub fn on_conflict<F>(mut self, f: F) -> RetryPolicy
+    where
+        F: Fn(u32) + Send + Sync + 'static,
+    {
+        self.on_conflict = Some(Arc::new(f));
+        self
+    }
+
+    // returns how long to wait before retrying a transaction that
+    // conflicted after running `attempts` times, or `None` if it
+    // is given up on
+    pub(crate) fn retry_after(&self, attempts: u32) -> Option<Duration> {
+        if let Some(on_conflict) = &self.on_conflict {
+            on_conflict(attempts);
+        }
+        if self.max_attempts.map(|max| attempts >= max) == Some(true) {
+            return None;
+        }
+
+        let (initial, max) = if let Some(backoff) = self.backoff {
+            backoff
+        } else {
+            return Some(Duration::from_secs(0));
+        };
+        let doublings = attempts.saturating_sub(1).min(31);
+        let delay = initial
+            .checked_mul(1 << doublings)
+            .map_or(max, |delay| delay.min(max));
+
+        let half = delay / 2;
+        let nanos = u64::try_from((delay - half).as_nanos())
+            .unwrap_or(u64::max_value());
+        let random = RandomState::new().build_hasher().finish();
+        let jitter = random % nanos.saturating_add(1);
+        Some(half + Duration::from_nanos(jitter))
+    }
+}
+
 /// A simple constructor for `Err(TransactionError::Abort(_))`
 pub fn abort<A, T>(t: T) -> ConflictableTransactionResult<A, T> {
     Err(ConflictableTransactionError::Abort(t))
@@ -460,35 +1283,94 @@ pub trait Transactional<E = ()> {
     where
         F: Fn(&Self::View) -> ConflictableTransactionResult<A, E>,
     {
-        loop {
-            let tt = self.make_overlay()?;
-            let view = Self::view_overlay(&tt);
-
-            // NB locks must exist until this function returns.
-            let locks = tt.stage();
-            let ret = f(&view);
-            if !tt.validate() {
-                tt.unstage();
-                continue;
-            }
-            match ret {
-                Ok(r) => {
-                    let guard = pin();
-                    tt.commit(&guard)?;
-                    drop(locks);
-                    tt.flush_if_configured()?;
-                    return Ok(r);
+        self.transaction_with_retry(&RetryPolicy::default(), f)
+    }
+
+    /// Runs a transaction like `transaction`, retrying it as the
+    /// `RetryPolicy` says when it conflicts. The writer lock is
+    /// held while the closure runs, so it only conflicts when it
+    /// returns `ConflictableTransactionError::Conflict` itself,
+    /// for example when it propagates a conflict from elsewhere.
+    fn transaction_with_retry<F, A>(
+        &self,
+        retry: &RetryPolicy,
+        f: F,
+    ) -> TransactionResult<A, E>
+    where
+        F: Fn(&Self::View) -> ConflictableTransactionResult<A, E>,
+    {
+        tracing_span!("transaction");
+        let mut stopwatch =
+            Stopwatch::start(Operation::Transaction, None, "lock", true);
+        // the first tree of the transaction, whose `Db` records its
+        // latency
+        let mut first_tree = None;
+        let mut attempts = 0_u32;
+        let mut run = || -> TransactionResult<A, E> {
+            loop {
+                attempts = attempts.saturating_add(1);
+                tracing_event!("transaction.attempt", attempt = attempts);
+                stopwatch.enter("lock");
+                let tt = self.make_overlay()?;
+                if first_tree.is_none() {
+                    first_tree = tt.first_tree();
                 }
-                Err(ConflictableTransactionError::Abort(e)) => {
-                    return Err(TransactionError::Abort(e));
+                let view = Self::view_overlay(&tt);
+
+                // NB locks must exist until this function returns.
+                let locks = tt.stage();
+                stopwatch.enter("body");
+                let ret = f(&view);
+                if !tt.validate() {
+                    tt.unstage();
+                    drop(locks);
+                    stopwatch.enter("retry");
+                    retry_or_give_up(retry, attempts)?;
+                    continue;
                 }
-                Err(ConflictableTransactionError::Conflict) => continue,
-                Err(ConflictableTransactionError::Storage(other)) => {
-                    return Err(TransactionError::Storage(other));
+                match ret {
+                    Ok(r) => {
+                        stopwatch.enter("commit");
+                        let guard = pin();
+                        tt.commit(&guard)?;
+                        drop(locks);
+                        stopwatch.enter("flush");
+                        tt.flush_if_configured()?;
+                        return Ok(r);
+                    }
+                    Err(ConflictableTransactionError::Abort(e)) => {
+                        return Err(TransactionError::Abort(e));
+                    }
+                    Err(ConflictableTransactionError::Conflict) => {
+                        drop(locks);
+                        stopwatch.enter("retry");
+                        retry_or_give_up(retry, attempts)?;
+                    }
+                    Err(ConflictableTransactionError::Storage(other)) => {
+                        return Err(TransactionError::Storage(other));
+                    }
                 }
             }
+        };
+        let ret = run();
+        if let Some(tree) = first_tree {
+            stopwatch.finish(&tree);
         }
+        ret
+    }
+}
+
+// waits for as long as the policy says before a transaction is
+// retried, or gives up on it
+fn retry_or_give_up<E>(
+    retry: &RetryPolicy,
+    attempts: u32,
+) -> TransactionResult<(), E> {
+    let delay = retry.retry_after(attempts).ok_or(TransactionError::Conflict)?;
+    if delay > Duration::from_secs(0) {
+        std::thread::sleep(delay);
     }
+    Ok(())
 }
 
 impl<E> Transactional<E> for &Tree {
@@ -1,14 +1,23 @@
 use std::{
     borrow::Cow,
+    convert::TryFrom,
     fmt::{self, Debug},
     num::NonZeroU64,
     ops::{self, Deref, RangeBounds},
+    path::Path,
     sync::atomic::Ordering::SeqCst,
+    time::{Duration, Instant},
 };
 
 use parking_lot::RwLock;
 
-use crate::{atomic_shim::AtomicU64, pagecache::NodeView, *};
+use crate::{
+    atomic_shim::AtomicU64,
+    pagecache::NodeView,
+    subscriber::ReservedBroadcast,
+    tree_file::{TreeFileReader, TreeFileWriter},
+    *,
+};
 
 #[derive(Debug, Clone)]
 pub(crate) struct View<'g> {
@@ -34,6 +43,11 @@ impl IntoIterator for &'_ Tree {
     }
 }
 
+const UNCOUNTED: u64 = u64::max_value();
+
+/// The number of keys that `Tree::remove_range` removes at a time.
+const REMOVE_RANGE_CHUNK: usize = 1024;
+
 const fn out_of_bounds(numba: usize) -> bool {
     numba > MAX_BLOB
 }
@@ -105,11 +119,82 @@ pub struct TreeInner {
     pub(crate) context: Context,
     pub(crate) subscribers: Subscribers,
     pub(crate) root: AtomicU64,
-    pub(crate) merge_operator: RwLock<Option<Box<dyn MergeOperator>>>,
+    pub(crate) merge_operator: RwLock<Option<Arc<dyn MergeOperator>>>,
+    pub(crate) expirations: RwLock<Option<Tree>>,
+    pub(crate) history: RwLock<Option<Arc<history::History>>>,
+    pub(crate) versions: RwLock<Option<Tree>>,
+    pub(crate) key_versions: RwLock<Option<Arc<key_version::KeyVersions>>>,
+    pub(crate) quota: RwLock<Option<Arc<quota::Limiter>>>,
+    pub(crate) order: KeyOrder,
+    pub(crate) indexes: Indexes,
+    pub(crate) counters: counter::Counters,
+    pub(crate) metrics: db_metrics::TreeCounters,
+    pub(crate) separates_values: bool,
+    pub(crate) blooms: Blooms,
+    // the number of keys stored in the tree, or `UNCOUNTED`
+    // until they are counted or recovered
+    pub(crate) item_count: AtomicU64,
+}
+
+impl TreeInner {
+    pub(crate) fn new(
+        tree_id: IVec,
+        context: Context,
+        root: PageId,
+        order: KeyOrder,
+    ) -> TreeInner {
+        TreeInner {
+            separates_values: value_log::separates_values(&context, &tree_id),
+            blooms: Blooms::new(context.bloom_bits_per_key),
+            tree_id,
+            context,
+            subscribers: Subscribers::default(),
+            root: AtomicU64::new(root),
+            merge_operator: RwLock::new(None),
+            expirations: RwLock::new(None),
+            history: RwLock::new(None),
+            versions: RwLock::new(None),
+            key_versions: RwLock::new(None),
+            quota: RwLock::new(None),
+            order,
+            indexes: Indexes::default(),
+            counters: counter::Counters::default(),
+            metrics: db_metrics::TreeCounters::default(),
+            item_count: AtomicU64::new(UNCOUNTED),
+        }
+    }
+
+    // the name of the tree as a field of `tracing` spans
+    #[cfg(feature = "tracing")]
+    pub(crate) fn tracing_name(
+        &self,
+    ) -> tracing::field::DisplayValue<Cow<'_, str>> {
+        tracing::field::display(String::from_utf8_lossy(&self.tree_id))
+    }
 }
 
 impl Drop for TreeInner {
     fn drop(&mut self) {
+        // the number of keys is persisted for the next time that
+        // the `Db` is opened, unless the tree was dropped or
+        // renamed, which leaves its root behind
+        let count = self.item_count.load(Acquire);
+        if count != UNCOUNTED && !self.context.read_only {
+            let guard = pin();
+            let meta = self.context.pagecache.get_meta(&guard);
+            if meta.get_root(&self.tree_id) == Some(self.root.load(Acquire)) {
+                let persisted = tree_stats::record_len(
+                    &self.context,
+                    &self.tree_id,
+                    Some(count),
+                    &guard,
+                );
+                if let Err(e) = persisted {
+                    error!("failed to persist the length of a tree: {:?}", e);
+                }
+            }
+        }
+
         // Flush the underlying system in a loop until we
         // have flushed all dirty data.
         loop {
@@ -153,21 +238,246 @@ impl Tree {
         V: Into<IVec>,
     {
         let value = value.into();
+        tracing_span!(
+            "tree.insert",
+            tree = self.tracing_name(),
+            key_len = key.as_ref().len(),
+            value_len = value.len(),
+        );
+        let mut timer = latency::time(
+            self,
+            Operation::Insert,
+            Some(key.as_ref().len()),
+            "quota",
+        );
+        memory::wait_for_room(&self.context)?;
+        quota::check(self, Some((key.as_ref(), value.len())))?;
+        let stored_key = self.order.encode(key.as_ref());
         let mut guard = pin();
+        timer.enter("concurrency_control");
         let _cc = concurrency_control::read();
+        timer.enter("index");
+        let indexed = index::begin_write(self, &guard)?;
+        timer.enter("write");
         loop {
             trace!("setting key {:?}", key.as_ref());
             if let Ok(res) = self.insert_inner(
-                key.as_ref(),
+                &stored_key,
                 Some(value.clone()),
                 false,
                 &mut guard,
             )? {
+                timer.enter("index");
+                index::finish_write(indexed)?;
                 return Ok(res);
             }
         }
     }
 
+    /// Insert a key to a new value like `insert`, with the
+    /// durability of the `WriteOptions`. An immediate insert is
+    /// synced before it returns, without waiting for what other
+    /// threads wrote after it, unlike a following `flush`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use sled::{Durability, WriteOptions};
+    ///
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let options = WriteOptions { flush: Durability::Immediate };
+    /// db.insert_with(b"critical", b"durable", options)?;
+    /// # Ok(()) }
+    /// ```
+    pub fn insert_with<K, V>(
+        &self,
+        key: K,
+        value: V,
+        options: WriteOptions,
+    ) -> Result<Option<IVec>>
+    where
+        K: AsRef<[u8]>,
+        V: Into<IVec>,
+    {
+        options.apply(|| self.insert(key, value))
+    }
+
+    /// Insert a key to a value that is read from `reader`,
+    /// returning the length of the value.
+    ///
+    /// If `Config::value_log_threshold` is set and the value
+    /// is not smaller than it, the value is written to the value
+    /// log in chunks as it is read, so that it is never held in
+    /// memory as a whole. Otherwise, or if the database is
+    /// encrypted, the value is read into memory and inserted
+    /// like with `insert`. Streamed values are only read back
+    /// into memory if the tree has indexes or subscribers.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::io::Read;
+    ///
+    /// let config = sled::Config::new()
+    ///     .temporary(true)
+    ///     .value_log_threshold(Some(4096));
+    /// let db = config.open()?;
+    ///
+    /// let value = vec![7; 1 << 20];
+    /// assert_eq!(db.insert_reader(b"big", &value[..])?, 1 << 20);
+    ///
+    /// let mut read = vec![];
+    /// db.get_reader(b"big")?.unwrap().read_to_end(&mut read)?;
+    /// assert_eq!(read, value);
+    /// # Ok(()) }
+    /// ```
+    pub fn insert_reader<K, R>(&self, key: K, mut reader: R) -> Result<u64>
+    where
+        K: AsRef<[u8]>,
+        R: Read,
+    {
+        let stored_key = self.order.encode(key.as_ref());
+        if out_of_bounds(stored_key.len()) {
+            bounds_error()?;
+        }
+
+        match value_log::stream(self, &stored_key, &mut reader)? {
+            value_log::Streamed::Buffered(value) => {
+                let len = u64::try_from(value.len()).unwrap();
+                let _ = self.insert(key, value)?;
+                Ok(len)
+            }
+            value_log::Streamed::Appended(stream) => {
+                let value_len =
+                    usize::try_from(stream.len).unwrap_or(usize::max_value());
+                memory::wait_for_room(&self.context)?;
+                quota::check(self, Some((key.as_ref(), value_len)))?;
+                self.insert_stream(&stored_key, &stream)?;
+                Ok(stream.len)
+            }
+        }
+    }
+
+    // links a value that was streamed to the value log, reading
+    // it back only where it is needed
+    fn insert_stream(
+        &self,
+        key: &[u8],
+        stream: &value_log::Stream<'_>,
+    ) -> Result<()> {
+        #[cfg(feature = "metrics")]
+        let _measure = Measure::new(&M.tree_set);
+
+        let guard = pin();
+        let _cc = concurrency_control::read();
+        let indexed = index::begin_write(self, &guard)?;
+        let stored = stream.stored();
+
+        loop {
+            let View { pid, node_view, .. } = self.view_for_key(key, &guard)?;
+
+            let value_len =
+                usize::try_from(stream.len).unwrap_or(usize::max_value());
+            let mut subscriber_reservation =
+                self.subscribers.reserve(key, Some(value_len));
+
+            let (encoded_key, raw_value) = node_view.node_kv_pair(key);
+            snapshot::record(
+                self,
+                key,
+                || value_log::load_opt(self, raw_value),
+                &guard,
+            )?;
+            let frag = Link::Set(encoded_key, stored.clone());
+            let linking = bloom::write(self, pid, key);
+            let link =
+                self.context.pagecache.link(pid, node_view.0, frag, &guard)?;
+            drop(linking);
+
+            if let Ok(ref linked) = link {
+                let seq = history::seq(linked.last_lsn());
+                tracing_event!("tree.linked", pid = pid, lsn = linked.last_lsn());
+                self.count_write(raw_value.is_some(), true);
+                db_metrics::record_write(self, key.len(), Some(value_len));
+
+                if index::is_indexed(self) {
+                    let last_value = value_log::load_opt(self, raw_value)?;
+                    let value = value_log::load(self, &stored)?;
+                    index::update(
+                        self,
+                        key,
+                        last_value.as_deref(),
+                        Some(&value),
+                    )?;
+                }
+
+                let expired = expiration::clear(self, key)?;
+
+                versions::record(
+                    self,
+                    key,
+                    || {
+                        if expired {
+                            Ok(None)
+                        } else {
+                            value_log::load_opt(self, raw_value)
+                        }
+                    },
+                    seq,
+                )?;
+                key_version::record(self, key, true, seq)?;
+
+                history::publish(
+                    self,
+                    subscriber_reservation.take(),
+                    |with_previous| {
+                        let previous = if !with_previous {
+                            None
+                        } else if expired {
+                            Some(None)
+                        } else {
+                            Some(value_log::load_opt(self, raw_value)?)
+                        };
+                        Ok(Event::single_update(
+                            self.clone(),
+                            seq,
+                            IVec::from(key),
+                            Some(value_log::load(self, &stored)?),
+                            previous,
+                        ))
+                    },
+                )?;
+
+                index::finish_write(indexed)?;
+
+                return Ok(());
+            }
+            #[cfg(feature = "metrics")]
+            M.tree_looped();
+        }
+    }
+
+    // sets a key of a tree outside of a batch or transaction,
+    // retrying until the write is linked, and returns the value
+    // that the key held before
+    pub(crate) fn set_inner(
+        &self,
+        key: &[u8],
+        value: Option<IVec>,
+        guard: &mut Guard,
+    ) -> Result<Option<IVec>> {
+        loop {
+            if let Ok(last) =
+                self.insert_inner(key, value.clone(), false, guard)?
+            {
+                return Ok(last);
+            }
+        }
+    }
+
     pub(crate) fn insert_inner(
         &self,
         key: &[u8],
@@ -185,49 +495,88 @@ impl Tree {
         if out_of_bounds(key.len()) {
             bounds_error()?;
         }
+        index::check_registered(self)?;
 
         let View { node_view, pid, .. } =
             self.view_for_key(key.as_ref(), guard)?;
 
+        // the writes of batches and transactions are published
+        // by `apply_batch_inner`
         let mut subscriber_reservation = if is_transactional {
             None
         } else {
-            Some(self.subscribers.reserve(&key))
+            let value_len = value.as_ref().map(|v| v.len());
+            Some(self.subscribers.reserve(&key, value_len))
         };
 
-        let (encoded_key, last_value) = node_view.node_kv_pair(key.as_ref());
-        let last_value = last_value.map(IVec::from);
+        let (encoded_key, stored_value) = node_view.node_kv_pair(key.as_ref());
+        let last_value = value_log::load_opt(self, stored_value)?;
 
         if value == last_value {
             // short-circuit a no-op set or delete
-            return Ok(Ok(value));
+            let expired = expiration::clear(self, key)?;
+            return Ok(Ok(if expired { None } else { value }));
         }
 
-        let frag = if let Some(value) = value.clone() {
-            if out_of_bounds(value.len()) {
+        let frag = if let Some(ref new) = value {
+            if out_of_bounds(new.len()) {
                 bounds_error()?;
             }
-            Link::Set(encoded_key, value)
+            Link::Set(encoded_key, value_log::store(self, key, new)?)
         } else {
             Link::Del(encoded_key)
         };
 
+        snapshot::record(self, key, || Ok(last_value.clone()), guard)?;
+        let linking = value.as_ref().and_then(|_| bloom::write(self, pid, key));
         let link =
             self.context.pagecache.link(pid, node_view.0, frag, guard)?;
+        drop(linking);
 
-        if link.is_ok() {
+        if let Ok(ref linked) = link {
             // success
-            if let Some(Some(res)) = subscriber_reservation.take() {
-                let event = subscriber::Event::single_update(
-                    self.clone(),
-                    key.as_ref().into(),
-                    value,
-                );
+            let seq = history::seq(linked.last_lsn());
+            tracing_event!("tree.linked", pid = pid, lsn = linked.last_lsn());
+            self.count_write(last_value.is_some(), value.is_some());
+            db_metrics::record_write(
+                self,
+                key.len(),
+                value.as_ref().map(|v| v.len()),
+            );
+
+            index::update(self, key, last_value.as_deref(), value.as_deref())?;
+
+            let expired = expiration::clear(self, key)?;
+            let previous_value = if expired { None } else { last_value };
 
-                res.complete(&event);
+            if !is_transactional {
+                versions::record(
+                    self,
+                    key,
+                    || Ok(previous_value.clone()),
+                    seq,
+                )?;
+                key_version::record(self, key, value.is_some(), seq)?;
+            }
+
+            if let Some(reservation) = subscriber_reservation.take() {
+                history::publish(self, reservation, |with_previous| {
+                    let previous = if with_previous {
+                        Some(previous_value.clone())
+                    } else {
+                        None
+                    };
+                    Ok(Event::single_update(
+                        self.clone(),
+                        seq,
+                        key.as_ref().into(),
+                        value,
+                        previous,
+                    ))
+                })?;
             }
 
-            Ok(Ok(last_value))
+            Ok(Ok(previous_value))
         } else {
             #[cfg(feature = "metrics")]
             M.tree_looped();
@@ -235,6 +584,60 @@ impl Tree {
         }
     }
 
+    /// Insert a key to a new value that will expire once the
+    /// provided time-to-live has elapsed, returning the last
+    /// value if it was set.
+    ///
+    /// Expired keys are no longer returned by reads or
+    /// iterators, and are eventually removed by a background
+    /// sweeper (see `Config::expiration_sweep_every_ms`) or by
+    /// calling `Tree::sweep_expired`. Overwriting or removing
+    /// a key using any other write method clears its
+    /// expiration.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::time::Duration;
+    ///
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// db.insert_with_ttl(b"session", b"data", Duration::from_secs(60))?;
+    /// assert!(db.get(b"session")?.is_some());
+    ///
+    /// db.insert_with_ttl(b"stale", b"data", Duration::from_secs(0))?;
+    /// assert_eq!(db.get(b"stale"), Ok(None));
+    /// # Ok(()) }
+    /// ```
+    pub fn insert_with_ttl<K, V>(
+        &self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<Option<IVec>>
+    where
+        K: AsRef<[u8]>,
+        V: Into<IVec>,
+    {
+        let ivec = value.into();
+        memory::wait_for_room(&self.context)?;
+        quota::check(self, Some((key.as_ref(), ivec.len())))?;
+        let stored_key = self.order.encode(key.as_ref());
+        expiration::insert_with_deadline(self, &stored_key, ivec, ttl)
+    }
+
+    /// Removes all keys whose time-to-live has elapsed,
+    /// returning the number of keys that were removed.
+    ///
+    /// This is called periodically by a background thread
+    /// when `Config::expiration_sweep_every_ms` is set, but
+    /// may also be called manually. Subscribers receive a
+    /// removal event for each swept key.
+    pub fn sweep_expired(&self) -> Result<usize> {
+        expiration::sweep(self)
+    }
+
     /// Perform a multi-key serializable transaction.
     ///
     /// sled transactions are **optimistic** which means that
@@ -359,6 +762,139 @@ impl Tree {
         Transactional::transaction(&self, f)
     }
 
+    /// Perform a multi-key serializable transaction that is retried
+    /// as the `RetryPolicy` says, see
+    /// `Transactional::transaction_with_retry`.
+    pub fn transaction_with_retry<F, A, E>(
+        &self,
+        retry: &transaction::RetryPolicy,
+        f: F,
+    ) -> transaction::TransactionResult<A, E>
+    where
+        F: Fn(
+            &transaction::TransactionalTree,
+        ) -> transaction::ConflictableTransactionResult<A, E>,
+    {
+        Transactional::transaction_with_retry(&self, retry, f)
+    }
+
+    /// Perform a multi-key serializable transaction whose body is
+    /// async, so that it can await other systems without blocking
+    /// the executor, which `transaction` would. The body is given
+    /// an `AsyncTransactionalTree` to read and write the tree with,
+    /// and is run again if the keys that it read were changed
+    /// before it could commit, like the body of a `Txn`, so it is
+    /// called for each attempt rather than awaited once. The commit
+    /// runs on sled's threadpool.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use sled::transaction::TransactionResult;
+    /// use sled::transaction::ConflictableTransactionError;
+    ///
+    /// # async fn example() -> TransactionResult<()> {
+    /// # async fn reserve_remotely(_: &[u8]) {}
+    /// # let db = sled::Config::new().temporary(true).open()?;
+    /// db.insert(b"seats", &[10])?;
+    ///
+    /// db.transaction_async(|tx| async move {
+    ///     let seats = tx.get(b"seats")?.unwrap();
+    ///     if seats[0] == 0 {
+    ///         return Err(ConflictableTransactionError::Abort(()));
+    ///     }
+    ///     reserve_remotely(&seats).await;
+    ///     tx.insert(b"seats", &[seats[0] - 1]);
+    ///     Ok(())
+    /// })
+    /// .await?;
+    /// # Ok(()) }
+    /// ```
+    pub async fn transaction_async<F, Fut, A, E>(
+        &self,
+        f: F,
+    ) -> transaction::TransactionResult<A, E>
+    where
+        F: FnMut(transaction::AsyncTransactionalTree) -> Fut,
+        Fut: std::future::Future<
+            Output = transaction::ConflictableTransactionResult<A, E>,
+        >,
+    {
+        self.transaction_async_with_retry(
+            &transaction::RetryPolicy::default(),
+            f,
+        )
+        .await
+    }
+
+    /// Perform a transaction with an async body like
+    /// `transaction_async`, retrying it as the `RetryPolicy` says
+    /// when the keys that it read were changed before it could
+    /// commit. Backoff waits on sled's threadpool, so it doesn't
+    /// block the executor either.
+    // this clippy check is mis-firing on async code.
+    #[allow(clippy::used_underscore_binding)]
+    pub async fn transaction_async_with_retry<F, Fut, A, E>(
+        &self,
+        retry: &transaction::RetryPolicy,
+        mut f: F,
+    ) -> transaction::TransactionResult<A, E>
+    where
+        F: FnMut(transaction::AsyncTransactionalTree) -> Fut,
+        Fut: std::future::Future<
+            Output = transaction::ConflictableTransactionResult<A, E>,
+        >,
+    {
+        use transaction::{
+            AsyncTransactionalTree, ConflictableTransactionError,
+            TransactionError, UnabortableTransactionError,
+        };
+
+        let mut attempts = 0_u32;
+        loop {
+            attempts = attempts.saturating_add(1);
+            let tx = AsyncTransactionalTree::new(self);
+            let finished = match f(tx.clone()).await {
+                Ok(value) => Some(value),
+                Err(ConflictableTransactionError::Abort(e)) => {
+                    return Err(TransactionError::Abort(e));
+                }
+                Err(ConflictableTransactionError::Conflict) => None,
+                Err(ConflictableTransactionError::Storage(e)) => {
+                    return Err(TransactionError::Storage(e));
+                }
+            };
+
+            if let Some(value) = finished {
+                let txn = tx.take();
+                match threadpool::spawn(move || txn.commit()).await {
+                    Some(Ok(())) => return Ok(value),
+                    Some(Err(UnabortableTransactionError::Conflict)) => {}
+                    Some(Err(UnabortableTransactionError::Storage(e))) => {
+                        return Err(TransactionError::Storage(e));
+                    }
+                    None => {
+                        return Err(TransactionError::Storage(
+                            Error::ReportableBug(
+                                "threadpool failed to complete \
+                                action before shutdown"
+                                    .to_string(),
+                            ),
+                        ));
+                    }
+                }
+            }
+
+            let delay = retry
+                .retry_after(attempts)
+                .ok_or(TransactionError::Conflict)?;
+            if delay > Duration::from_secs(0) {
+                let _ = threadpool::spawn(move || std::thread::sleep(delay))
+                    .await;
+            }
+        }
+    }
+
     /// Create a new batched update that can be
     /// atomically applied.
     ///
@@ -386,62 +922,69 @@ impl Tree {
     /// # Ok(()) }
     /// ```
     pub fn apply_batch(&self, batch: Batch) -> Result<()> {
+        tracing_span!(
+            "tree.apply_batch",
+            tree = self.tracing_name(),
+            writes = batch.writes.len(),
+        );
+        memory::wait_for_room(&self.context)?;
+        quota::check(
+            self,
+            batch.writes.iter().filter_map(|(key, write)| {
+                write.as_ref().map(|value| (&**key, value.len()))
+            }),
+        )?;
         let _cc = concurrency_control::write();
         let mut guard = pin();
-        self.apply_batch_inner(batch, None, &mut guard)
+        self.apply_batch_inner(self.order.encode_batch(batch), &mut guard)
     }
 
-    pub(crate) fn apply_batch_inner(
+    /// Atomically applies a batch like `apply_batch`, with the
+    /// durability of the `WriteOptions`. An immediate batch is
+    /// synced as a whole before it returns.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use sled::{Durability, WriteOptions};
+    ///
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let mut batch = sled::Batch::default();
+    /// batch.insert("key_a", "val_a");
+    /// batch.remove("key_b");
+    ///
+    /// let options = WriteOptions { flush: Durability::Immediate };
+    /// db.apply_batch_with(batch, options)?;
+    /// # Ok(()) }
+    /// ```
+    pub fn apply_batch_with(
         &self,
         batch: Batch,
-        transaction_batch: Option<Event>,
-        guard: &mut Guard,
+        options: WriteOptions,
     ) -> Result<()> {
-        let peg = if transaction_batch.is_none() {
-            Some(self.context.pin_log(guard)?)
-        } else {
-            None
-        };
-
-        trace!("applying batch {:?}", batch);
-
-        let mut subscriber_reservation = self.subscribers.reserve_batch(&batch);
-
-        for (k, v_opt) in &batch.writes {
-            loop {
-                if self
-                    .insert_inner(
-                        k,
-                        v_opt.clone(),
-                        transaction_batch.is_some(),
-                        guard,
-                    )?
-                    .is_ok()
-                {
-                    break;
-                }
-            }
-        }
-
-        if let Some(res) = subscriber_reservation.take() {
-            if let Some(transaction_batch) = transaction_batch {
-                res.complete(&transaction_batch);
-            } else {
-                res.complete(&Event::single_batch(self.clone(), batch));
-            }
-        }
-
-        if let Some(peg) = peg {
-            // when the peg drops, it ensures all updates
-            // written to the log since its creation are
-            // recovered atomically
-            peg.seal_batch()
-        } else {
-            Ok(())
-        }
+        options.apply(|| self.apply_batch(batch))
     }
 
-    /// Retrieve a value from the `Tree` if it exists.
+    /// Loads pairs of keys and values into an empty `Tree`,
+    /// returning how many were loaded. The keys must be sorted
+    /// in the `KeyOrder` of the tree, without duplicates.
+    ///
+    /// Rather than inserting the keys one at a time, the leaves
+    /// and index nodes are built directly and each is written
+    /// once, which is much faster for an initial import of a
+    /// large dataset. The new nodes only become visible once
+    /// they have all been written, so the tree stays empty if
+    /// an error occurs or the keys turn out not to be sorted.
+    /// Like other writes, the keys are durable after the next
+    /// flush. If the process crashes during a load, the space
+    /// used by the nodes that were written is not reclaimed.
+    ///
+    /// Returns an error if the tree isn't empty, has indexes or
+    /// retains its history, or if another write was made to it
+    /// during the load. Subscribers are not notified of the
+    /// loaded keys.
     ///
     /// # Examples
     ///
@@ -449,26 +992,37 @@ impl Tree {
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// # let config = sled::Config::new().temporary(true);
     /// # let db = config.open()?;
-    /// db.insert(&[0], vec![0])?;
-    /// assert_eq!(db.get(&[0]), Ok(Some(sled::IVec::from(vec![0]))));
-    /// assert_eq!(db.get(&[1]), Ok(None));
+    /// let pairs = (0..1000_u32).map(|i| (i.to_be_bytes(), vec![0; 8]));
+    /// assert_eq!(db.bulk_load(pairs)?, 1000);
+    /// assert_eq!(db.len(), 1000);
+    /// assert_eq!(db.get(7_u32.to_be_bytes())?, Some(vec![0; 8].into()));
+    ///
+    /// // only empty trees can be loaded
+    /// assert!(db.bulk_load(vec![(b"a", b"b")]).is_err());
     /// # Ok(()) }
     /// ```
-    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>> {
-        let mut guard = pin();
-        let _cc = concurrency_control::read();
-        loop {
-            if let Ok(get) = self.get_inner(key.as_ref(), &mut guard)? {
-                return Ok(get);
-            }
-        }
+    pub fn bulk_load<I, K, V>(&self, pairs: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: Into<IVec>,
+    {
+        self.try_bulk_load(pairs.into_iter().map(Ok))
     }
 
-    /// Pass the result of getting a key's value to a closure
-    /// without making a new allocation. This effectively
-    /// "pushes" your provided code to the data without ever copying
-    /// the data, rather than "pulling" a copy of the data to whatever code
-    /// is calling `get`.
+    /// Starts a bulk ingestion that writes nothing to the log
+    /// until `IngestSession::finish` is called, which then writes
+    /// everything at once, flushes it and takes a snapshot.
+    ///
+    /// Everything that was written to the session is lost if the
+    /// process crashes before it is finished, and none of it can
+    /// be read from the tree until then. Writes that don't fit in
+    /// memory are spilled to sorted runs next to the database
+    /// until then. When the tree is empty and has no indexes or
+    /// history, the keys are bulk loaded like with `bulk_load`,
+    /// and otherwise they are applied in batches of a bounded
+    /// size, which go through the log and are not atomic as a
+    /// whole.
     ///
     /// # Examples
     ///
@@ -476,59 +1030,298 @@ impl Tree {
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// # let config = sled::Config::new().temporary(true);
     /// # let db = config.open()?;
-    /// db.insert(&[0], vec![0])?;
-    /// db.get_zero_copy(&[0], |value_opt| {
-    ///     assert_eq!(
-    ///         value_opt,
-    ///         Some(&[0][..])
-    ///     )
-    /// });
-    /// db.get_zero_copy(&[1], |value_opt| assert!(value_opt.is_none()));
+    /// let mut session = db.ingest_session();
+    /// for i in (0..1000_u32).rev() {
+    ///     session.insert(i.to_be_bytes(), vec![0; 8])?;
+    /// }
+    /// assert!(db.is_empty());
+    ///
+    /// assert_eq!(session.finish()?, 1000);
+    /// assert_eq!(db.len(), 1000);
     /// # Ok(()) }
     /// ```
-    pub fn get_zero_copy<K: AsRef<[u8]>, B, F: FnOnce(Option<&[u8]>) -> B>(
-        &self,
-        key: K,
-        f: F,
-    ) -> Result<B> {
+    pub fn ingest_session(&self) -> IngestSession {
+        IngestSession::new(self)
+    }
+
+    // like `bulk_load`, but stops at the first error returned by
+    // the pairs, leaving the tree empty
+    pub(crate) fn try_bulk_load<I, K, V>(&self, pairs: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = Result<(K, V)>>,
+        K: AsRef<[u8]>,
+        V: Into<IVec>,
+    {
+        index::check_registered(self)?;
+        if index::lock(self).is_some() {
+            return Err(Error::Unsupported(
+                "bulk_load is not supported for trees with indexes \
+                 or a retained history"
+                    .into(),
+            ));
+        }
+        if !self.is_empty() {
+            return Err(Error::Unsupported(
+                "bulk_load requires an empty tree".into(),
+            ));
+        }
+
+        let mut loader = bulk_load::Loader::new(self)?;
+        let (loaded, old_root) =
+            match self.bulk_load_inner(&mut loader, pairs) {
+                Ok(ret) => ret,
+                Err(e) => {
+                    loader.abort()?;
+                    return Err(e);
+                }
+            };
+
+        // free the nodes of the empty tree
         let guard = pin();
-        let _cc = concurrency_control::read();
+        let mut leftmost_chain = vec![old_root];
+        let mut cursor = old_root;
+        while let Some(view) = self.view_for_pid(cursor, &guard)? {
+            if !view.is_index {
+                break;
+            }
+            cursor = view.iter_index_pids().next().unwrap();
+            leftmost_chain.push(cursor);
+        }
+        self.gc_pages(leftmost_chain)?;
+        bloom::clear(self);
 
-        #[cfg(feature = "metrics")]
-        let _measure = Measure::new(&M.tree_get);
+        Ok(loaded)
+    }
 
-        trace!("getting key {:?}", key.as_ref());
+    // loads the pairs and swaps in the new root, returning how
+    // many were loaded and the old root
+    fn bulk_load_inner<I, K, V>(
+        &self,
+        loader: &mut bulk_load::Loader<'_>,
+        pairs: I,
+    ) -> Result<(usize, PageId)>
+    where
+        I: IntoIterator<Item = Result<(K, V)>>,
+        K: AsRef<[u8]>,
+        V: Into<IVec>,
+    {
+        let mut loaded = 0;
+        let mut last: Option<IVec> = None;
+        for pair in pairs {
+            let (k, v) = pair?;
+            let key = IVec::from(&*self.order.encode(k.as_ref()));
+            let value: IVec = v.into();
+            if out_of_bounds(key.len()) || out_of_bounds(value.len()) {
+                bounds_error()?;
+            }
+            if let Some(ref last_key) = last {
+                if key <= *last_key {
+                    return Err(Error::Unsupported(
+                        "keys passed to bulk_load must be sorted \
+                        and unique"
+                            .into(),
+                    ));
+                }
+            }
+
+            let stored = value_log::store(self, &key, &value)?;
+            loader.push(key.clone(), stored)?;
+            last = Some(key);
+            loaded += 1;
+        }
 
-        let View { node_view, .. } =
-            self.view_for_key(key.as_ref(), &guard)?;
+        let new_root = loader.finish()?;
 
-        let pair = node_view.node_kv_pair(key.as_ref());
+        let _cc = concurrency_control::write();
+
+        let mut iter = self.iter();
+        iter.parts = iter::Parts::Keys;
+        if iter.next_inner().is_some() {
+            return Err(Error::Unsupported(
+                "the tree was written to during bulk_load".into(),
+            ));
+        }
+        snapshot::detach(self);
 
-        let ret = f(pair.1);
+        let old_root = self.swap_root(new_root, loaded)?;
+        key_version::renew(self);
 
-        Ok(ret)
+        Ok((loaded, old_root))
     }
 
-    pub(crate) fn get_inner(
+    // swaps in the root of nodes that hold `items` keys, and which
+    // aren't reachable yet, returning the old root. Must be called
+    // while writes are blocked.
+    pub(crate) fn swap_root(
         &self,
-        key: &[u8],
+        new_root: PageId,
+        items: usize,
+    ) -> Result<PageId> {
+        let guard = pin();
+        let mut old_root = self.root.load(Acquire);
+        loop {
+            match self.context.pagecache.cas_root_in_meta(
+                &self.tree_id,
+                Some(old_root),
+                Some(new_root),
+                &guard,
+            )? {
+                Ok(()) => break,
+                // the old root may have been split or merged
+                Err(Some(actual)) => old_root = actual,
+                Err(None) => return Err(Error::CollectionNotFound(
+                    self.tree_id.clone(),
+                )),
+            }
+        }
+        self.root.store(new_root, SeqCst);
+        self.item_count.store(u64::try_from(items).unwrap(), Release);
+
+        Ok(old_root)
+    }
+
+    /// Writes the keys and values of the `Tree` to a file that
+    /// can be loaded into an empty tree of another `Db` using
+    /// `Tree::import_from_file`, returning the number of pairs
+    /// that were written.
+    ///
+    /// The file is versioned, checksummed, and made durable
+    /// before this method returns. Its format doesn't depend on
+    /// how the `Db` stores keys and values, so it may be moved
+    /// between machines and versions of sled. Keys that have
+    /// expired are skipped, and the deadlines of keys inserted
+    /// with `Tree::insert_with_ttl` are not preserved.
+    ///
+    /// The file holds the tree as it was when the export started,
+    /// read from a `Snapshot`, and writes to the `Db` go on while
+    /// it is being written.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let path = std::env::temp_dir().join("sled_export_doctest");
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// let users = db.open_tree("users")?;
+    /// users.insert("alice", "admin")?;
+    /// users.insert("bob", "guest")?;
+    /// assert_eq!(users.export_to_file(&path)?, 2);
+    ///
+    /// let other = sled::Config::new().temporary(true).open()?;
+    /// let imported = other.open_tree("imported_users")?;
+    /// assert_eq!(imported.import_from_file(&path)?, 2);
+    /// assert_eq!(imported.get("bob")?, Some(sled::IVec::from("guest")));
+    /// # std::fs::remove_file(&path)?;
+    /// # Ok(()) }
+    /// ```
+    pub fn export_to_file<P: AsRef<Path>>(&self, path: P) -> Result<u64> {
+        let mut file = TreeFileWriter::create(path.as_ref(), self.order)?;
+
+        let snapshot = snapshot::Snapshot::new(&self.context);
+        for kv_res in snapshot.iter(self) {
+            let (k, v) = kv_res?;
+            file.pair(&k, &v)?;
+        }
+        drop(snapshot);
+
+        file.finish()
+    }
+
+    /// Loads a file written by `Tree::export_to_file` into an
+    /// empty `Tree`, returning the number of pairs that were
+    /// loaded. The nodes of the tree are built directly from
+    /// the sorted pairs, as by `Tree::bulk_load`.
+    ///
+    /// Returns `Error::Unsupported` if the tree isn't empty,
+    /// the file isn't an exported tree, or it was exported from
+    /// a tree with another `KeyOrder`, and `Error::Corruption`
+    /// if its checksum doesn't match its contents. The tree is
+    /// left empty if an error occurs.
+    pub fn import_from_file<P: AsRef<Path>>(&self, path: P) -> Result<u64> {
+        let file = TreeFileReader::open(path.as_ref(), self.order)?;
+        let loaded = self.try_bulk_load(file)?;
+        Ok(u64::try_from(loaded).unwrap())
+    }
+
+    pub(crate) fn apply_batch_inner(
+        &self,
+        batch: Batch,
         guard: &mut Guard,
-    ) -> Result<Conflictable<Option<IVec>>> {
-        #[cfg(feature = "metrics")]
-        let _measure = Measure::new(&M.tree_get);
+    ) -> Result<()> {
+        let peg = self.context.pin_log(guard)?;
 
-        trace!("getting key {:?}", key);
+        trace!("applying batch {:?}", batch);
 
-        let View { node_view,  .. } =
-            self.view_for_key(key.as_ref(), guard)?;
+        let subscriber_reservation = self.subscribers.reserve_batch(&batch);
+        let keep_previous =
+            subscriber_reservation.as_ref().map(|res| res.wants_previous)
+                == Some(true);
+        let seq = history::seq(peg.lsn());
+        let previous = self.apply_writes(&batch, keep_previous, seq, guard)?;
+
+        history::publish(self, subscriber_reservation, |with_previous| {
+            Ok(Event::single_batch(
+                self.clone(),
+                seq,
+                batch,
+                if with_previous { Some(previous) } else { None },
+            ))
+        })?;
 
-        let pair = node_view.node_kv_pair(key.as_ref());
-        let val = pair.1.map(IVec::from);
+        // when the peg drops, it ensures all updates
+        // written to the log since its creation are
+        // recovered atomically
+        peg.seal_batch()
+    }
 
-        Ok(Ok(val))
+    // applies the writes of a transaction to this tree and records
+    // its event, returning the reservation of the event, which is
+    // only completed once the transaction has written every tree
+    // so that no subscriber sees a part of it
+    pub(crate) fn apply_transaction_batch(
+        &self,
+        batch: &Batch,
+        event: &Event,
+        guard: &mut Guard,
+    ) -> Result<Option<ReservedBroadcast>> {
+        trace!("applying transaction batch {:?}", batch);
+
+        let subscriber_reservation = self.subscribers.reserve_batch(batch);
+        let _ = self.apply_writes(batch, false, event.seq, guard)?;
+        history::record_event(self, event)?;
+        Ok(subscriber_reservation)
     }
 
-    /// Delete a value, returning the old value if it existed.
+    // applies the writes of a batch, returning the values that its
+    // keys held before if `keep_previous` is set. the writes are
+    // published together as one event with `seq` once they are all
+    // applied
+    fn apply_writes(
+        &self,
+        batch: &Batch,
+        keep_previous: bool,
+        seq: u64,
+        guard: &mut Guard,
+    ) -> Result<Batch> {
+        let mut previous = Batch::default();
+        for (k, v_opt) in &batch.writes {
+            loop {
+                if let Ok(last) =
+                    self.insert_inner(k, v_opt.clone(), true, guard)?
+                {
+                    versions::record(self, k, || Ok(last.clone()), seq)?;
+                    key_version::record(self, k, v_opt.is_some(), seq)?;
+                    if keep_previous {
+                        previous.writes.insert(k.clone(), last);
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(previous)
+    }
+
+    /// Retrieve a value from the `Tree` if it exists.
     ///
     /// # Examples
     ///
@@ -536,39 +1329,48 @@ impl Tree {
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// # let config = sled::Config::new().temporary(true);
     /// # let db = config.open()?;
-    /// db.insert(&[1], vec![1]);
-    /// assert_eq!(db.remove(&[1]), Ok(Some(sled::IVec::from(vec![1]))));
-    /// assert_eq!(db.remove(&[1]), Ok(None));
+    /// db.insert(&[0], vec![0])?;
+    /// assert_eq!(db.get(&[0]), Ok(Some(sled::IVec::from(vec![0]))));
+    /// assert_eq!(db.get(&[1]), Ok(None));
     /// # Ok(()) }
     /// ```
-    pub fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>> {
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>> {
+        tracing_span!(
+            "tree.get",
+            tree = self.tracing_name(),
+            key_len = key.as_ref().len(),
+        );
+        let mut timer = latency::time(
+            self,
+            Operation::Get,
+            Some(key.as_ref().len()),
+            "concurrency_control",
+        );
+        let stored_key = self.order.encode(key.as_ref());
+        let pages_read_before = db_metrics::pages_read_by_thread();
         let mut guard = pin();
         let _cc = concurrency_control::read();
+        timer.enter("read");
         loop {
-            trace!("removing key {:?}", key.as_ref());
-
-            if let Ok(res) =
-                self.insert_inner(key.as_ref(), None, false, &mut guard)?
-            {
-                return Ok(res);
+            if let Ok(get) = self.get_inner(&stored_key, &mut guard)? {
+                db_metrics::record_get(self, pages_read_before);
+                return Ok(get);
             }
         }
     }
 
-    /// Compare and swap. Capable of unique creation, conditional modification,
-    /// or deletion. If old is `None`, this will only set the value if it
-    /// doesn't exist yet. If new is `None`, will delete the value if old is
-    /// correct. If both old and new are `Some`, will modify the value if
-    /// old is correct.
-    ///
-    /// It returns `Ok(Ok(()))` if operation finishes successfully.
+    /// Retrieve a value from the `Tree` without copying it out of
+    /// the page cache, which makes reading large values cheaper
+    /// than with `get`.
     ///
-    /// If it fails it returns:
-    ///     - `Ok(Err(CompareAndSwapError(current, proposed)))` if operation
-    ///       failed to setup a new value. `CompareAndSwapError` contains
-    ///       current and proposed values.
-    ///     - `Err(Error::Unsupported)` if the database is opened in read-only
-    ///       mode.
+    /// The returned `ValueGuard` borrows the value from the node
+    /// that holds it, and keeps the thread pinned so that the node
+    /// isn't freed while it's held. Memory that other threads free
+    /// meanwhile isn't reclaimed until it's dropped either, so it
+    /// should be dropped as soon as the value has been used, and
+    /// it can't be held across threads. A value that is stored in
+    /// the value log, see `Config::value_log_threshold`, is read
+    /// into memory of its own like with `get`.
     ///
     /// # Examples
     ///
@@ -576,499 +1378,1761 @@ impl Tree {
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// # let config = sled::Config::new().temporary(true);
     /// # let db = config.open()?;
-    /// // unique creation
-    /// assert_eq!(
-    ///     db.compare_and_swap(&[1], None as Option<&[u8]>, Some(&[10])),
-    ///     Ok(Ok(()))
-    /// );
+    /// db.insert(&[0], vec![0; 1024])?;
     ///
-    /// // conditional modification
-    /// assert_eq!(
-    ///     db.compare_and_swap(&[1], Some(&[10]), Some(&[20])),
-    ///     Ok(Ok(()))
-    /// );
+    /// let value = db.get_ref(&[0])?.unwrap();
+    /// assert_eq!(&*value, &[0; 1024][..]);
+    /// drop(value);
     ///
-    /// // failed conditional modification -- the current value is returned in
-    /// // the error variant
-    /// let operation = db.compare_and_swap(&[1], Some(&[30]), Some(&[40]));
-    /// assert!(operation.is_ok()); // the operation succeeded
-    /// let modification = operation.unwrap();
-    /// assert!(modification.is_err());
-    /// let actual_value = modification.unwrap_err();
-    /// assert_eq!(actual_value.current.map(|ivec| ivec.to_vec()), Some(vec![20]));
+    /// assert!(db.get_ref(&[1])?.is_none());
+    /// # Ok(()) }
+    /// ```
+    pub fn get_ref<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+    ) -> Result<Option<ValueGuard>> {
+        tracing_span!(
+            "tree.get_ref",
+            tree = self.tracing_name(),
+            key_len = key.as_ref().len(),
+        );
+        let mut timer = latency::time(
+            self,
+            Operation::Get,
+            Some(key.as_ref().len()),
+            "concurrency_control",
+        );
+        let stored_key = self.order.encode(key.as_ref());
+        let pages_read_before = db_metrics::pages_read_by_thread();
+        let guard = pin();
+        let _cc = concurrency_control::read();
+        timer.enter("read");
+        let ret = value_guard::get(self, &stored_key, guard)?;
+        db_metrics::record_get(self, pages_read_before);
+        Ok(ret)
+    }
+
+    /// Retrieve the values for several keys at once, returning
+    /// them in the same order as the provided keys.
+    ///
+    /// This is more efficient than calling `get` for each key,
+    /// because the keys are resolved in sorted order and keys
+    /// that fall within the same leaf share a single traversal
+    /// of the tree.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// use sled::IVec;
+    ///
+    /// db.insert(&[0], vec![0])?;
+    /// db.insert(&[2], vec![2])?;
     ///
-    /// // conditional deletion
     /// assert_eq!(
-    ///     db.compare_and_swap(&[1], Some(&[20]), None as Option<&[u8]>),
-    ///     Ok(Ok(()))
+    ///     db.get_many(&[[2], [1], [0]])?,
+    ///     vec![Some(IVec::from(&[2])), None, Some(IVec::from(&[0]))]
     /// );
-    /// assert_eq!(db.get(&[1]), Ok(None));
     /// # Ok(()) }
     /// ```
-    #[allow(clippy::needless_pass_by_value)]
-    pub fn compare_and_swap<K, OV, NV>(
-        &self,
-        key: K,
-        old: Option<OV>,
-        new: Option<NV>,
-    ) -> CompareAndSwapResult
+    pub fn get_many<K, I>(&self, keys: I) -> Result<Vec<Option<IVec>>>
     where
         K: AsRef<[u8]>,
-        OV: AsRef<[u8]>,
-        NV: Into<IVec>,
+        I: IntoIterator<Item = K>,
     {
-        trace!("cas'ing key {:?}", key.as_ref());
-        #[cfg(feature = "metrics")]
-        let _measure = Measure::new(&M.tree_cas);
+        let keys: Vec<K> = keys.into_iter().collect();
+        tracing_span!(
+            "tree.get_many",
+            tree = self.tracing_name(),
+            keys = keys.len(),
+        );
+        let stored_keys: Vec<_> =
+            keys.iter().map(|key| self.order.encode(key.as_ref())).collect();
+
+        // resolve keys in sorted order so that neighbors can
+        // reuse the leaf that we found for the previous key
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_unstable_by_key(|idx| &stored_keys[*idx]);
 
         let guard = pin();
         let _cc = concurrency_control::read();
 
-        let new = new.map(Into::into);
-
-        // we need to retry caps until old != cur, since just because
-        // cap fails it doesn't mean our value was changed.
-        loop {
-            let View { pid, node_view, .. } =
-                self.view_for_key(key.as_ref(), &guard)?;
+        let mut ret = vec![None; keys.len()];
+        let mut last_view: Option<View<'_>> = None;
 
-            let (encoded_key, current_value) =
-                node_view.node_kv_pair(key.as_ref());
-            let matches = match (old.as_ref(), &current_value) {
-                (None, None) => true,
-                (Some(o), Some(c)) => o.as_ref() == &**c,
-                _ => false,
-            };
+        for idx in order {
+            let key = stored_keys[idx].as_ref();
 
-            if !matches {
-                return Ok(Err(CompareAndSwapError {
-                    current: current_value.map(IVec::from),
-                    proposed: new,
-                }));
-            }
+            // each key is timed and counted like a `get` of its own
+            let _timer = latency::time(
+                self,
+                Operation::Get,
+                Some(keys[idx].as_ref().len()),
+                "read",
+            );
+            let pages_read_before = db_metrics::pages_read_by_thread();
+            #[cfg(feature = "metrics")]
+            let _measure = Measure::new(&M.tree_get);
 
-            if current_value == new.as_ref().map(AsRef::as_ref) {
-                // short-circuit no-op write. this is still correct
-                // because we verified that the input matches, so
-                // doing the work has the same semantic effect as not
-                // doing it in this case.
-                return Ok(Ok(()));
+            if out_of_bounds(key.len()) {
+                bounds_error()?;
             }
 
-            let mut subscriber_reservation = self.subscribers.reserve(&key);
+            if !bloom::excludes(self, key, &guard)? {
+                let cached = last_view.as_ref().map_or(false, |view| {
+                    key >= view.lo() && view.hi().map_or(true, |hi| key < hi)
+                });
 
-            let frag = if let Some(ref new) = new {
-                Link::Set(encoded_key, new.clone())
-            } else {
-                Link::Del(encoded_key)
-            };
-            let link =
-                self.context.pagecache.link(pid, node_view.0, frag, &guard)?;
+                if !cached {
+                    let view = self.view_for_key(key, &guard)?;
+                    bloom::build(self, view.pid, &guard)?;
+                    last_view = Some(view);
+                }
 
-            if link.is_ok() {
-                if let Some(res) = subscriber_reservation.take() {
-                    let event = subscriber::Event::single_update(
-                        self.clone(),
-                        key.as_ref().into(),
-                        new,
-                    );
+                let view = last_view.as_ref().unwrap();
+                let stored_value = view.node_kv_pair(key).1;
+                let value = value_log::load_opt(self, stored_value)?;
 
-                    res.complete(&event);
+                if value.is_none()
+                    || !expiration::is_expired(self, key, &guard)?
+                {
+                    ret[idx] = value;
                 }
-
-                return Ok(Ok(()));
             }
-            #[cfg(feature = "metrics")]
-            M.tree_looped();
+
+            db_metrics::record_get(self, pages_read_before);
         }
+
+        Ok(ret)
     }
 
-    /// Fetch the value, apply a function to it and return the result.
-    ///
-    /// # Note
-    ///
-    /// This may call the function multiple times if the value has been
-    /// changed from other threads in the meantime.
+    /// Pass the result of getting a key's value to a closure
+    /// without making a new allocation. This effectively
+    /// "pushes" your provided code to the data without ever copying
+    /// the data, rather than "pulling" a copy of the data to whatever code
+    /// is calling `get`.
     ///
     /// # Examples
     ///
     /// ```
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
-    /// use sled::{Config, Error, IVec};
-    /// use std::convert::TryInto;
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// db.insert(&[0], vec![0])?;
+    /// db.get_zero_copy(&[0], |value_opt| {
+    ///     assert_eq!(
+    ///         value_opt,
+    ///         Some(&[0][..])
+    ///     )
+    /// });
+    /// db.get_zero_copy(&[1], |value_opt| assert!(value_opt.is_none()));
+    /// # Ok(()) }
+    /// ```
+    pub fn get_zero_copy<K: AsRef<[u8]>, B, F: FnOnce(Option<&[u8]>) -> B>(
+        &self,
+        key: K,
+        f: F,
+    ) -> Result<B> {
+        let guard = pin();
+        let _cc = concurrency_control::read();
+
+        #[cfg(feature = "metrics")]
+        let _measure = Measure::new(&M.tree_get);
+
+        trace!("getting key {:?}", key.as_ref());
+
+        let stored_key = self.order.encode(key.as_ref());
+
+        if bloom::excludes(self, &stored_key, &guard)? {
+            return Ok(f(None));
+        }
+
+        let View { node_view, pid, .. } =
+            self.view_for_key(&stored_key, &guard)?;
+        bloom::build(self, pid, &guard)?;
+
+        let mut pair = node_view.node_kv_pair(&stored_key);
+
+        if pair.1.is_some()
+            && expiration::is_expired(self, &stored_key, &guard)?
+        {
+            pair.1 = None;
+        }
+
+        // separated values are not stored in the node
+        let ret = if self.separates_values {
+            f(value_log::load_opt(self, pair.1)?.as_deref())
+        } else {
+            f(pair.1)
+        };
+
+        Ok(ret)
+    }
+
+    /// Retrieve a reader for a value, which reads separated
+    /// values from the value log in chunks instead of holding
+    /// them in memory as a whole, see `insert_reader`.
+    ///
+    /// Reading a separated value fails with an
+    /// `io::ErrorKind::InvalidData` error at its end if it was
+    /// corrupted. Encrypted values are read into memory, so that
+    /// they can be authenticated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::io::Read;
+    ///
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// db.insert(b"a", vec![1, 2, 3])?;
+    ///
+    /// let mut value = vec![];
+    /// db.get_reader(b"a")?.unwrap().read_to_end(&mut value)?;
+    /// assert_eq!(value, vec![1, 2, 3]);
+    /// assert!(db.get_reader(b"b")?.is_none());
+    /// # Ok(()) }
+    /// ```
+    pub fn get_reader<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+    ) -> Result<Option<impl Read>> {
+        let guard = pin();
+        let _cc = concurrency_control::read();
+
+        #[cfg(feature = "metrics")]
+        let _measure = Measure::new(&M.tree_get);
+
+        let stored_key = self.order.encode(key.as_ref());
+
+        let View { node_view, .. } = self.view_for_key(&stored_key, &guard)?;
+
+        let stored = if let Some(stored) = node_view.node_kv_pair(&stored_key).1
+        {
+            stored
+        } else {
+            return Ok(None);
+        };
+
+        if expiration::is_expired(self, &stored_key, &guard)? {
+            return Ok(None);
+        }
+
+        value_log::reader(self, stored).map(Some)
+    }
+
+    pub(crate) fn get_inner(
+        &self,
+        key: &[u8],
+        guard: &mut Guard,
+    ) -> Result<Conflictable<Option<IVec>>> {
+        #[cfg(feature = "metrics")]
+        let _measure = Measure::new(&M.tree_get);
+
+        trace!("getting key {:?}", key);
+
+        if bloom::excludes(self, key, guard)? {
+            return Ok(Ok(None));
+        }
+
+        let View { node_view, pid, .. } =
+            self.view_for_key(key.as_ref(), guard)?;
+        bloom::build(self, pid, guard)?;
+
+        let pair = node_view.node_kv_pair(key.as_ref());
+        let val = value_log::load_opt(self, pair.1)?;
+
+        if val.is_some() && expiration::is_expired(self, key, guard)? {
+            return Ok(Ok(None));
+        }
+
+        Ok(Ok(val))
+    }
+
+    /// Delete a value, returning the old value if it existed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// db.insert(&[1], vec![1]);
+    /// assert_eq!(db.remove(&[1]), Ok(Some(sled::IVec::from(vec![1]))));
+    /// assert_eq!(db.remove(&[1]), Ok(None));
+    /// # Ok(()) }
+    /// ```
+    pub fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>> {
+        tracing_span!(
+            "tree.remove",
+            tree = self.tracing_name(),
+            key_len = key.as_ref().len(),
+        );
+        let mut timer = latency::time(
+            self,
+            Operation::Remove,
+            Some(key.as_ref().len()),
+            "concurrency_control",
+        );
+        let stored_key = self.order.encode(key.as_ref());
+        let mut guard = pin();
+        let _cc = concurrency_control::read();
+        timer.enter("index");
+        let indexed = index::begin_write(self, &guard)?;
+        timer.enter("write");
+        loop {
+            trace!("removing key {:?}", key.as_ref());
+
+            if let Ok(res) =
+                self.insert_inner(&stored_key, None, false, &mut guard)?
+            {
+                timer.enter("index");
+                index::finish_write(indexed)?;
+                return Ok(res);
+            }
+        }
+    }
+
+    /// Compare and swap. Capable of unique creation, conditional modification,
+    /// or deletion. If old is `None`, this will only set the value if it
+    /// doesn't exist yet. If new is `None`, will delete the value if old is
+    /// correct. If both old and new are `Some`, will modify the value if
+    /// old is correct.
+    ///
+    /// It returns `Ok(Ok(()))` if operation finishes successfully.
+    ///
+    /// If it fails it returns:
+    ///     - `Ok(Err(CompareAndSwapError(current, proposed)))` if operation
+    ///       failed to setup a new value. `CompareAndSwapError` contains
+    ///       current and proposed values.
+    ///     - `Err(Error::Unsupported)` if the database is opened in read-only
+    ///       mode.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// // unique creation
+    /// assert_eq!(
+    ///     db.compare_and_swap(&[1], None as Option<&[u8]>, Some(&[10])),
+    ///     Ok(Ok(()))
+    /// );
+    ///
+    /// // conditional modification
+    /// assert_eq!(
+    ///     db.compare_and_swap(&[1], Some(&[10]), Some(&[20])),
+    ///     Ok(Ok(()))
+    /// );
+    ///
+    /// // failed conditional modification -- the current value is returned in
+    /// // the error variant
+    /// let operation = db.compare_and_swap(&[1], Some(&[30]), Some(&[40]));
+    /// assert!(operation.is_ok()); // the operation succeeded
+    /// let modification = operation.unwrap();
+    /// assert!(modification.is_err());
+    /// let actual_value = modification.unwrap_err();
+    /// assert_eq!(actual_value.current.map(|ivec| ivec.to_vec()), Some(vec![20]));
+    ///
+    /// // conditional deletion
+    /// assert_eq!(
+    ///     db.compare_and_swap(&[1], Some(&[20]), None as Option<&[u8]>),
+    ///     Ok(Ok(()))
+    /// );
+    /// assert_eq!(db.get(&[1]), Ok(None));
+    /// # Ok(()) }
+    /// ```
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn compare_and_swap<K, OV, NV>(
+        &self,
+        key: K,
+        old: Option<OV>,
+        new: Option<NV>,
+    ) -> CompareAndSwapResult
+    where
+        K: AsRef<[u8]>,
+        OV: AsRef<[u8]>,
+        NV: Into<IVec>,
+    {
+        trace!("cas'ing key {:?}", key.as_ref());
+        tracing_span!(
+            "tree.compare_and_swap",
+            tree = self.tracing_name(),
+            key_len = key.as_ref().len(),
+        );
+        #[cfg(feature = "metrics")]
+        let _measure = Measure::new(&M.tree_cas);
+
+        let new = new.map(Into::into);
+        memory::wait_for_room(&self.context)?;
+        if let Some(new) = &new {
+            quota::check(self, Some((key.as_ref(), new.len())))?;
+        }
+
+        let guard = pin();
+        let _cc = concurrency_control::read();
+
+        let stored_key = self.order.encode(key.as_ref());
+        let indexed = index::begin_write(self, &guard)?;
+
+        // we need to retry caps until old != cur, since just because
+        // cap fails it doesn't mean our value was changed.
+        loop {
+            let View { pid, node_view, .. } =
+                self.view_for_key(&stored_key, &guard)?;
+
+            let (encoded_key, raw_value) = node_view.node_kv_pair(&stored_key);
+            let stored_value = value_log::load_opt(self, raw_value)?;
+            let mut current_value = stored_value.clone();
+            if current_value.is_some()
+                && expiration::is_expired(self, &stored_key, &guard)?
+            {
+                current_value = None;
+            }
+            let matches = match (old.as_ref(), &current_value) {
+                (None, None) => true,
+                (Some(o), Some(c)) => o.as_ref() == &**c,
+                _ => false,
+            };
+
+            if !matches {
+                return Ok(Err(CompareAndSwapError {
+                    current: current_value,
+                    proposed: new,
+                }));
+            }
+
+            if current_value.as_deref() == new.as_deref() {
+                // short-circuit no-op write. this is still correct
+                // because we verified that the input matches, so
+                // doing the work has the same semantic effect as not
+                // doing it in this case.
+                return Ok(Ok(()));
+            }
+
+            let mut subscriber_reservation = self
+                .subscribers
+                .reserve(&stored_key, new.as_ref().map(|v| v.len()));
+
+            let frag = if let Some(ref new) = new {
+                let stored = value_log::store(self, &stored_key, new)?;
+                Link::Set(encoded_key, stored)
+            } else {
+                Link::Del(encoded_key)
+            };
+            snapshot::record(
+                self,
+                &stored_key,
+                || Ok(stored_value.clone()),
+                &guard,
+            )?;
+            let linking =
+                new.as_ref().and_then(|_| bloom::write(self, pid, &stored_key));
+            let link =
+                self.context.pagecache.link(pid, node_view.0, frag, &guard)?;
+            drop(linking);
+
+            if let Ok(ref linked) = link {
+                let seq = history::seq(linked.last_lsn());
+                tracing_event!("tree.linked", pid = pid, lsn = linked.last_lsn());
+                self.count_write(stored_value.is_some(), new.is_some());
+                db_metrics::record_write(
+                    self,
+                    stored_key.len(),
+                    new.as_ref().map(|v| v.len()),
+                );
+
+                index::update(
+                    self,
+                    &stored_key,
+                    stored_value.as_deref(),
+                    new.as_deref(),
+                )?;
+
+                versions::record(
+                    self,
+                    &stored_key,
+                    || Ok(current_value.clone()),
+                    seq,
+                )?;
+                key_version::record(self, &stored_key, new.is_some(), seq)?;
+
+                history::publish(
+                    self,
+                    subscriber_reservation.take(),
+                    |with_previous| {
+                        let previous = if with_previous {
+                            Some(current_value)
+                        } else {
+                            None
+                        };
+                        Ok(Event::single_update(
+                            self.clone(),
+                            seq,
+                            IVec::from(&*stored_key),
+                            new,
+                            previous,
+                        ))
+                    },
+                )?;
+
+                let _ = expiration::clear(self, &stored_key)?;
+
+                index::finish_write(indexed)?;
+
+                return Ok(Ok(()));
+            }
+            #[cfg(feature = "metrics")]
+            M.tree_looped();
+        }
+    }
+
+    /// Atomically applies several compare and swaps to this `Tree`,
+    /// each of which sets a key from an old value to a new one like
+    /// `compare_and_swap`, where `None` stands for the key being
+    /// absent. Either every old value matches and every swap is
+    /// applied as a single batch, or none of them is and the first
+    /// mismatch is returned. This avoids the overhead of a
+    /// transaction when a few keys of one tree are updated
+    /// together. Writes to every `Tree` wait while it runs, and
+    /// each key may only be swapped once.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// db.insert(b"balance", b"10")?;
+    ///
+    /// let swaps: &[(&[u8], Option<&[u8]>, Option<&[u8]>)] = &[
+    ///     (b"balance", Some(b"10"), Some(b"7")),
+    ///     (b"receipt", None, Some(b"3")),
+    /// ];
+    /// assert_eq!(db.compare_and_swap_many(swaps)?, Ok(()));
+    ///
+    /// // the second swap fails, so the first isn't applied either
+    /// let again: &[(&[u8], Option<&[u8]>, Option<&[u8]>)] = &[
+    ///     (b"balance", Some(b"7"), Some(b"4")),
+    ///     (b"receipt", None, Some(b"3")),
+    /// ];
+    /// let err = db.compare_and_swap_many(again)?.unwrap_err();
+    /// assert_eq!(err.index, 1);
+    /// assert_eq!(err.current, Some(sled::IVec::from(b"3")));
+    /// assert_eq!(db.get(b"balance")?, Some(sled::IVec::from(b"7")));
+    /// # Ok(()) }
+    /// ```
+    pub fn compare_and_swap_many<K, OV, NV>(
+        &self,
+        swaps: &[(K, Option<OV>, Option<NV>)],
+    ) -> Result<std::result::Result<(), CompareAndSwapManyError>>
+    where
+        K: AsRef<[u8]>,
+        OV: AsRef<[u8]>,
+        NV: AsRef<[u8]>,
+    {
+        let mut batch = Batch::default();
+        let mut stored_keys = Vec::with_capacity(swaps.len());
+        for (key, _, new) in swaps {
+            let stored_key = IVec::from(&*self.order.encode(key.as_ref()));
+            let new_value = new.as_ref().map(|v| IVec::from(v.as_ref()));
+            if batch.writes.insert(stored_key.clone(), new_value).is_some() {
+                return Err(Error::Unsupported(format!(
+                    "key {:?} is swapped more than once",
+                    key.as_ref()
+                )));
+            }
+            stored_keys.push(stored_key);
+        }
+
+        let _cc = concurrency_control::write();
+        let mut guard = pin();
+
+        // the writer lock excludes every other write, so the values
+        // can't change between being compared and being swapped
+        for (index, ((_, old, _), stored_key)) in
+            swaps.iter().zip(&stored_keys).enumerate()
+        {
+            let current = loop {
+                if let Ok(current) = self.get_inner(stored_key, &mut guard)? {
+                    break current;
+                }
+            };
+            if current.as_deref() != old.as_ref().map(AsRef::as_ref) {
+                return Ok(Err(CompareAndSwapManyError {
+                    index,
+                    current,
+                    proposed: batch.writes[stored_key].clone(),
+                }));
+            }
+        }
+
+        if !batch.writes.is_empty() {
+            self.apply_batch_inner(batch, &mut guard)?;
+        }
+        Ok(Ok(()))
+    }
+
+    /// Retrieve a value from the `Tree` if it exists, along with
+    /// its `Version`, which can be passed to `insert_if_version` to
+    /// only overwrite the value if it wasn't written since. Unlike
+    /// `compare_and_swap`, this doesn't compare the whole value, and
+    /// fails if the key was written with the same value again.
+    ///
+    /// The versions of a tree are tracked from the first time that
+    /// this or `insert_if_version` is called on it, which makes
+    /// every write to it a little slower. A key also gets a new
+    /// version when the database is reopened.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// db.insert(b"k", vec![1])?;
+    /// let (value, version) = db.get_versioned(b"k")?.unwrap();
+    /// assert_eq!(value, sled::IVec::from(vec![1]));
+    ///
+    /// // writing the same value again still makes a new version
+    /// db.insert(b"k", vec![2])?;
+    /// db.insert(b"k", vec![1])?;
+    /// assert!(db.insert_if_version(b"k", vec![3], version)?.is_err());
+    ///
+    /// let (_, version) = db.get_versioned(b"k")?.unwrap();
+    /// assert!(db.insert_if_version(b"k", vec![3], version)?.is_ok());
+    /// # Ok(()) }
+    /// ```
+    pub fn get_versioned<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+    ) -> Result<Option<(IVec, Version)>> {
+        let key_versions = key_version::open(self)?;
+        let stored_key = self.order.encode(key.as_ref());
+        let mut guard = pin();
+        let _cc = concurrency_control::read();
+        self.get_versioned_inner(&key_versions, &stored_key, &mut guard)
+    }
+
+    fn get_versioned_inner(
+        &self,
+        key_versions: &key_version::KeyVersions,
+        key: &[u8],
+        guard: &mut Guard,
+    ) -> Result<Option<(IVec, Version)>> {
+        let version = key_version::get(key_versions, key, guard)?;
+        let value = loop {
+            if let Ok(value) = self.get_inner(key, guard)? {
+                break value;
+            }
+        };
+        Ok(value.map(|value| (value, version)))
+    }
+
+    /// Insert a key to a new value if it still has the `Version`
+    /// that `get_versioned` returned, returning its new version.
+    /// Otherwise nothing is written, and the error holds the value
+    /// and version of the key, if it is set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// db.insert(b"k", vec![1])?;
+    /// let (_, version) = db.get_versioned(b"k")?.unwrap();
+    ///
+    /// let new_version = db.insert_if_version(b"k", vec![2], version)?.unwrap();
+    /// assert!(new_version > version);
+    ///
+    /// let err = db.insert_if_version(b"k", vec![3], version)?.unwrap_err();
+    /// assert_eq!(err.current, Some((sled::IVec::from(vec![2]), new_version)));
+    /// # Ok(()) }
+    /// ```
+    pub fn insert_if_version<K, V>(
+        &self,
+        key: K,
+        value: V,
+        version: Version,
+    ) -> Result<std::result::Result<Version, VersionMismatchError>>
+    where
+        K: AsRef<[u8]>,
+        V: Into<IVec>,
+    {
+        let key_versions = key_version::open(self)?;
+        let stored_key = IVec::from(&*self.order.encode(key.as_ref()));
+        let value = value.into();
+
+        let _cc = concurrency_control::write();
+        let mut guard = pin();
+
+        // the writer lock excludes every other write, and waits for
+        // the ones before to record their versions
+        let current =
+            self.get_versioned_inner(&key_versions, &stored_key, &mut guard)?;
+        if current.as_ref().map(|(_, current)| *current) != Some(version) {
+            return Ok(Err(VersionMismatchError { current, proposed: value }));
+        }
+
+        let mut batch = Batch::default();
+        batch.insert(stored_key.clone(), value);
+        self.apply_batch_inner(batch, &mut guard)?;
+
+        Ok(Ok(key_version::get(&key_versions, &stored_key, &mut guard)?))
+    }
+
+    /// Fetch the value, apply a function to it and return the result.
+    ///
+    /// # Note
+    ///
+    /// This may call the function multiple times if the value has been
+    /// changed from other threads in the meantime.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use sled::{Config, Error, IVec};
+    /// use std::convert::TryInto;
+    ///
+    /// let config = Config::new().temporary(true);
+    /// let db = config.open()?;
+    ///
+    /// fn u64_to_ivec(number: u64) -> IVec {
+    ///     IVec::from(number.to_be_bytes().to_vec())
+    /// }
+    ///
+    /// let zero = u64_to_ivec(0);
+    /// let one = u64_to_ivec(1);
+    /// let two = u64_to_ivec(2);
+    /// let three = u64_to_ivec(3);
+    ///
+    /// fn increment(old: Option<&[u8]>) -> Option<Vec<u8>> {
+    ///     let number = match old {
+    ///         Some(bytes) => {
+    ///             let array: [u8; 8] = bytes.try_into().unwrap();
+    ///             let number = u64::from_be_bytes(array);
+    ///             number + 1
+    ///         }
+    ///         None => 0,
+    ///     };
+    ///
+    ///     Some(number.to_be_bytes().to_vec())
+    /// }
+    ///
+    /// assert_eq!(db.update_and_fetch("counter", increment), Ok(Some(zero)));
+    /// assert_eq!(db.update_and_fetch("counter", increment), Ok(Some(one)));
+    /// assert_eq!(db.update_and_fetch("counter", increment), Ok(Some(two)));
+    /// assert_eq!(db.update_and_fetch("counter", increment), Ok(Some(three)));
+    /// # Ok(()) }
+    /// ```
+    pub fn update_and_fetch<K, V, F>(
+        &self,
+        key: K,
+        mut f: F,
+    ) -> Result<Option<IVec>>
+    where
+        K: AsRef<[u8]>,
+        F: FnMut(Option<&[u8]>) -> Option<V>,
+        V: Into<IVec>,
+    {
+        let key_ref = key.as_ref();
+        let mut current = self.get(key_ref)?;
+
+        loop {
+            let tmp = current.as_ref().map(AsRef::as_ref);
+            let next = f(tmp).map(Into::into);
+            match self.compare_and_swap::<_, _, IVec>(
+                key_ref,
+                tmp,
+                next.clone(),
+            )? {
+                Ok(()) => return Ok(next),
+                Err(CompareAndSwapError { current: cur, .. }) => {
+                    current = cur;
+                }
+            }
+        }
+    }
+
+    /// Fetch the value, apply a function to it and return the previous value.
+    ///
+    /// # Note
+    ///
+    /// This may call the function multiple times if the value has been
+    /// changed from other threads in the meantime.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use sled::{Config, Error, IVec};
+    /// use std::convert::TryInto;
+    ///
+    /// let config = Config::new().temporary(true);
+    /// let db = config.open()?;
+    ///
+    /// fn u64_to_ivec(number: u64) -> IVec {
+    ///     IVec::from(number.to_be_bytes().to_vec())
+    /// }
+    ///
+    /// let zero = u64_to_ivec(0);
+    /// let one = u64_to_ivec(1);
+    /// let two = u64_to_ivec(2);
+    ///
+    /// fn increment(old: Option<&[u8]>) -> Option<Vec<u8>> {
+    ///     let number = match old {
+    ///         Some(bytes) => {
+    ///             let array: [u8; 8] = bytes.try_into().unwrap();
+    ///             let number = u64::from_be_bytes(array);
+    ///             number + 1
+    ///         }
+    ///         None => 0,
+    ///     };
+    ///
+    ///     Some(number.to_be_bytes().to_vec())
+    /// }
+    ///
+    /// assert_eq!(db.fetch_and_update("counter", increment), Ok(None));
+    /// assert_eq!(db.fetch_and_update("counter", increment), Ok(Some(zero)));
+    /// assert_eq!(db.fetch_and_update("counter", increment), Ok(Some(one)));
+    /// assert_eq!(db.fetch_and_update("counter", increment), Ok(Some(two)));
+    /// # Ok(()) }
+    /// ```
+    pub fn fetch_and_update<K, V, F>(
+        &self,
+        key: K,
+        mut f: F,
+    ) -> Result<Option<IVec>>
+    where
+        K: AsRef<[u8]>,
+        F: FnMut(Option<&[u8]>) -> Option<V>,
+        V: Into<IVec>,
+    {
+        let key_ref = key.as_ref();
+        let mut current = self.get(key_ref)?;
+
+        loop {
+            let tmp = current.as_ref().map(AsRef::as_ref);
+            let next = f(tmp);
+            match self.compare_and_swap(key_ref, tmp, next)? {
+                Ok(()) => return Ok(current),
+                Err(CompareAndSwapError { current: cur, .. }) => {
+                    current = cur;
+                }
+            }
+        }
+    }
+
+    /// Subscribe to `Event`s that happen to keys that have
+    /// the specified prefix. Events for particular keys are
+    /// guaranteed to be witnessed in the same order by all
+    /// threads, but threads may witness different interleavings
+    /// of `Event`s across different keys. If subscribers don't
+    /// keep up with new writes, they will cause new writes
+    /// to block. There is a buffer of 1024 items per
+    /// `Subscriber`. This can be used to build reactive
+    /// and replicated systems.
+    ///
+    /// `Subscriber` implements both `Iterator<Item = Event>`
+    /// and `Future<Output=Option<Event>>`
+    ///
+    /// # Examples
+    ///
+    /// Synchronous, blocking subscriber:
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// // watch all events by subscribing to the empty prefix
+    /// let mut subscriber = db.watch_prefix(vec![]);
+    ///
+    /// let tree_2 = db.clone();
+    /// let thread = std::thread::spawn(move || {
+    ///     db.insert(vec![0], vec![1])
+    /// });
+    ///
+    /// // `Subscription` implements `Iterator<Item=Event>`
+    /// for event in subscriber.take(1) {
+    ///     // Events occur due to single key operations,
+    ///     // batches, or transactions. The tree is included
+    ///     // so that you may perform a new transaction or
+    ///     // operation in response to the event.
+    ///     for (tree, key, value_opt) in &event {
+    ///         if let Some(value) = value_opt {
+    ///             // key `key` was set to value `value`
+    ///         } else {
+    ///             // key `key` was removed
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// # thread.join().unwrap();
+    /// # Ok(()) }
+    /// ```
+    /// Asynchronous, non-blocking subscriber:
+    ///
+    /// `Subscription` implements `Future<Output=Option<Event>>`.
+    ///
+    /// ```
+    /// # async fn foo() {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open().unwrap();
+    /// # let mut subscriber = db.watch_prefix(vec![]);
+    /// while let Some(event) = (&mut subscriber).await {
+    ///     /* use it */
+    /// }
+    /// # }
+    /// ```
+    pub fn watch_prefix<P: AsRef<[u8]>>(&self, prefix: P) -> Subscriber {
+        let encoded = self.order.encode_prefix(prefix.as_ref());
+        self.subscribers.register(&encoded, None, &self.context)
+    }
+
+    /// Subscribe to the `Event`s that happen to keys that have the
+    /// specified prefix like `watch_prefix`, only receiving those
+    /// that pass the `EventFilter`. The filter is evaluated by the
+    /// writing thread, before the event is queued for the
+    /// subscriber, so rejected events cost it nothing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use sled::{EventFilter, EventKinds};
+    ///
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let filter = EventFilter::new()
+    ///     .kinds(EventKinds::INSERT)
+    ///     .key_predicate(|key| key.ends_with(b".json"))
+    ///     .min_value_len(2);
+    /// let subscriber = db.watch_prefix_with(b"docs/", filter);
+    ///
+    /// db.insert(b"docs/a.txt", b"{}")?;
+    /// db.insert(b"docs/b.json", b"")?;
+    /// db.remove(b"docs/a.txt")?;
+    /// db.insert(b"docs/c.json", b"{}")?;
+    ///
+    /// let event = subscriber.take(1).next().unwrap();
+    /// let (_tree, key, _value) = event.iter().next().unwrap();
+    /// assert_eq!(key, &sled::IVec::from(b"docs/c.json"));
+    /// # Ok(()) }
+    /// ```
+    pub fn watch_prefix_with<P: AsRef<[u8]>>(
+        &self,
+        prefix: P,
+        filter: EventFilter,
+    ) -> Subscriber {
+        let encoded = self.order.encode_prefix(prefix.as_ref());
+        let ordered = Some((filter, self.order));
+        self.subscribers.register(&encoded, ordered, &self.context)
+    }
+
+    /// Subscribe to `Event`s that happen to keys that have the
+    /// specified prefix like `watch_prefix`, first replaying the
+    /// retained events whose sequence number is at least `seq`,
+    /// see `Event::seq`. No event is missed or received twice
+    /// between the replayed events and the new ones.
+    ///
+    /// The history of a tree is only retained with
+    /// `Config::event_history`. Returns `Error::Unsupported` if
+    /// it isn't, or if events from `seq` on were already dropped
+    /// to keep the history to its size. The replayed events of
+    /// transactions only hold their writes to this tree.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let config =
+    ///     sled::Config::new().temporary(true).event_history(Some(16));
+    /// let db = config.open()?;
+    ///
+    /// db.insert(b"a", b"1")?;
+    /// db.insert(b"b", b"2")?;
+    ///
+    /// // a consumer that handled everything up to the first insert
+    /// let first = db.watch_prefix_since(vec![], 0)?.next().unwrap();
+    /// let mut subscriber =
+    ///     db.watch_prefix_since(vec![], first.seq() + 1)?;
+    ///
+    /// let replayed = subscriber.next().unwrap();
+    /// let (_tree, key, _value) = replayed.iter().next().unwrap();
+    /// assert_eq!(key, &sled::IVec::from(b"b"));
+    /// # Ok(()) }
+    /// ```
+    pub fn watch_prefix_since<P: AsRef<[u8]>>(
+        &self,
+        prefix: P,
+        seq: u64,
+    ) -> Result<Subscriber> {
+        let encoded = self.order.encode_prefix(prefix.as_ref());
+
+        // no write is in flight while registering, so every event
+        // is either recorded before the history is read below or
+        // sent to the subscriber, or both
+        let cc = concurrency_control::write();
+        let mut subscriber =
+            self.subscribers.register(&encoded, None, &self.context);
+        drop(cc);
+
+        let events = history::replay(self, prefix.as_ref(), seq)?;
+        subscriber.replay(events, seq);
+        Ok(subscriber)
+    }
+
+    /// Watch the next write to exactly this key, which is cheaper
+    /// than watching a prefix when a single key is of interest,
+    /// because no other write is queued for it. The returned
+    /// `KeyWatch` can be waited on or awaited.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::time::Duration;
+    ///
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let watch = db.watch_key(b"config");
+    ///
+    /// db.insert(b"config-old", b"ignored")?;
+    /// db.insert(b"config", b"v2")?;
+    ///
+    /// let value = watch.wait_timeout(Duration::from_secs(1))?;
+    /// assert_eq!(value, Some(sled::IVec::from(b"v2")));
+    /// # Ok(()) }
+    /// ```
+    pub fn watch_key<K: AsRef<[u8]>>(&self, key: K) -> KeyWatch {
+        let watched = IVec::from(key.as_ref());
+        let encoded = self.order.encode(&watched);
+        let exact = watched.clone();
+        let filter = EventFilter::new().key_predicate(move |k| k == &*exact);
+        let subscriber = self.subscribers.register(
+            &encoded,
+            Some((filter, self.order)),
+            &self.context,
+        );
+        KeyWatch::new(self.tree_id.clone(), watched, subscriber)
+    }
+
+    /// Waits until the value of a key passes `predicate`, which is
+    /// given `None` while the key is absent, returning that value.
+    /// The current value is checked first, and every write to the
+    /// key after it, until the `timeout` runs out, in which case
+    /// `None` is returned. It also returns `None` if the `Tree` is
+    /// dropped first.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::time::Duration;
+    ///
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let ready = db.clone();
+    /// let thread = std::thread::spawn(move || {
+    ///     ready.insert(b"state", b"starting").unwrap();
+    ///     ready.insert(b"state", b"ready").unwrap();
+    /// });
+    ///
+    /// let state = db.wait_for(
+    ///     b"state",
+    ///     |value| value.map(|v| v == b"ready") == Some(true),
+    ///     Duration::from_secs(5),
+    /// )?;
+    /// assert_eq!(state, Some(Some(sled::IVec::from(b"ready"))));
+    /// # thread.join().unwrap();
+    /// # Ok(()) }
+    /// ```
+    pub fn wait_for<K, F>(
+        &self,
+        key: K,
+        predicate: F,
+        timeout: Duration,
+    ) -> Result<Option<Option<IVec>>>
+    where
+        K: AsRef<[u8]>,
+        F: Fn(Option<&IVec>) -> bool,
+    {
+        // the watch is created before the key is read, so that no
+        // write is missed in between
+        let watch = self.watch_key(key.as_ref());
+        let current = self.get(key)?;
+        if predicate(current.as_ref()) {
+            return Ok(Some(current));
+        }
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline
+                .checked_duration_since(Instant::now())
+                .unwrap_or_else(|| Duration::from_nanos(0));
+            match watch.next_timeout(remaining) {
+                Ok(value) if predicate(value.as_ref()) => {
+                    return Ok(Some(value));
+                }
+                Ok(_) => {}
+                Err(_) => return Ok(None),
+            }
+        }
+    }
+
+    /// Synchronously flushes all dirty IO buffers and calls
+    /// fsync. If this succeeds, it is guaranteed that all
+    /// previous writes will be recovered if the system
+    /// crashes. Returns the number of bytes flushed during
+    /// this call.
+    ///
+    /// Flushing can take quite a lot of time, and you should
+    /// measure the performance impact of using it on
+    /// realistic sustained workloads running on realistic
+    /// hardware.
+    pub fn flush(&self) -> Result<usize> {
+        tracing_span!("tree.flush", tree = self.tracing_name());
+        let _timer = latency::time(self, Operation::Flush, None, "flush");
+        self.context.pagecache.flush()
+    }
+
+    /// Asynchronously flushes all dirty IO buffers
+    /// and calls fsync. If this succeeds, it is
+    /// guaranteed that all previous writes will
+    /// be recovered if the system crashes. Returns
+    /// the number of bytes flushed during this call.
+    ///
+    /// Flushing can take quite a lot of time, and you
+    /// should measure the performance impact of
+    /// using it on realistic sustained workloads
+    /// running on realistic hardware.
+    // this clippy check is mis-firing on async code.
+    #[allow(clippy::used_underscore_binding)]
+    pub async fn flush_async(&self) -> Result<usize> {
+        let _timer = latency::time(self, Operation::Flush, None, "flush");
+        let pagecache = self.context.pagecache.clone();
+        if let Some(result) =
+            threadpool::spawn(move || pagecache.flush()).await
+        {
+            result
+        } else {
+            Err(Error::ReportableBug(
+                "threadpool failed to complete \
+                action before shutdown"
+                    .to_string(),
+            ))
+        }
+    }
+
+    /// Returns `true` if the `Tree` contains a value for
+    /// the specified key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// db.insert(&[0], vec![0])?;
+    /// assert!(db.contains_key(&[0])?);
+    /// assert!(!db.contains_key(&[1])?);
+    /// # Ok(()) }
+    /// ```
+    pub fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+        self.get(key).map(|v| v.is_some())
+    }
+
+    /// Retrieve the key and value before the provided key,
+    /// if one exists.
+    ///
+    /// # Note
+    /// The order follows the Ord implementation for `Vec<u8>`:
+    ///
+    /// `[] < [0] < [255] < [255, 0] < [255, 255] ...`
+    ///
+    /// To retain the ordering of numerical types use big endian reprensentation
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use sled::IVec;
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// for i in 0..10 {
+    ///     db.insert(&[i], vec![i])
+    ///         .expect("should write successfully");
+    /// }
+    ///
+    /// assert_eq!(db.get_lt(&[]), Ok(None));
+    /// assert_eq!(db.get_lt(&[0]), Ok(None));
+    /// assert_eq!(
+    ///     db.get_lt(&[1]),
+    ///     Ok(Some((IVec::from(&[0]), IVec::from(&[0]))))
+    /// );
+    /// assert_eq!(
+    ///     db.get_lt(&[9]),
+    ///     Ok(Some((IVec::from(&[8]), IVec::from(&[8]))))
+    /// );
+    /// assert_eq!(
+    ///     db.get_lt(&[10]),
+    ///     Ok(Some((IVec::from(&[9]), IVec::from(&[9]))))
+    /// );
+    /// assert_eq!(
+    ///     db.get_lt(&[255]),
+    ///     Ok(Some((IVec::from(&[9]), IVec::from(&[9]))))
+    /// );
+    /// # Ok(()) }
+    /// ```
+    pub fn get_lt<K>(&self, key: K) -> Result<Option<(IVec, IVec)>>
+    where
+        K: AsRef<[u8]>,
+    {
+        #[cfg(feature = "metrics")]
+        let _measure = Measure::new(&M.tree_get);
+        self.range(..key).next_back().transpose()
+    }
+
+    /// Retrieve the next key and value from the `Tree` after the
+    /// provided key.
     ///
-    /// let config = Config::new().temporary(true);
-    /// let db = config.open()?;
+    /// # Note
+    /// The order follows the Ord implementation for `Vec<u8>`:
     ///
-    /// fn u64_to_ivec(number: u64) -> IVec {
-    ///     IVec::from(number.to_be_bytes().to_vec())
+    /// `[] < [0] < [255] < [255, 0] < [255, 255] ...`
+    ///
+    /// To retain the ordering of numerical types use big endian reprensentation
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use sled::IVec;
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// for i in 0..10 {
+    ///     db.insert(&[i], vec![i])?;
     /// }
     ///
-    /// let zero = u64_to_ivec(0);
-    /// let one = u64_to_ivec(1);
-    /// let two = u64_to_ivec(2);
-    /// let three = u64_to_ivec(3);
+    /// assert_eq!(
+    ///     db.get_gt(&[]),
+    ///     Ok(Some((IVec::from(&[0]), IVec::from(&[0]))))
+    /// );
+    /// assert_eq!(
+    ///     db.get_gt(&[0]),
+    ///     Ok(Some((IVec::from(&[1]), IVec::from(&[1]))))
+    /// );
+    /// assert_eq!(
+    ///     db.get_gt(&[1]),
+    ///     Ok(Some((IVec::from(&[2]), IVec::from(&[2]))))
+    /// );
+    /// assert_eq!(
+    ///     db.get_gt(&[8]),
+    ///     Ok(Some((IVec::from(&[9]), IVec::from(&[9]))))
+    /// );
+    /// assert_eq!(db.get_gt(&[9]), Ok(None));
     ///
-    /// fn increment(old: Option<&[u8]>) -> Option<Vec<u8>> {
-    ///     let number = match old {
-    ///         Some(bytes) => {
-    ///             let array: [u8; 8] = bytes.try_into().unwrap();
-    ///             let number = u64::from_be_bytes(array);
-    ///             number + 1
-    ///         }
-    ///         None => 0,
-    ///     };
+    /// db.insert(500u16.to_be_bytes(), vec![10]);
+    /// assert_eq!(
+    ///     db.get_gt(&499u16.to_be_bytes()),
+    ///     Ok(Some((IVec::from(&500u16.to_be_bytes()), IVec::from(&[10]))))
+    /// );
+    /// # Ok(()) }
+    /// ```
+    pub fn get_gt<K>(&self, key: K) -> Result<Option<(IVec, IVec)>>
+    where
+        K: AsRef<[u8]>,
+    {
+        #[cfg(feature = "metrics")]
+        let _measure = Measure::new(&M.tree_get);
+        self.range((ops::Bound::Excluded(key), ops::Bound::Unbounded))
+            .next()
+            .transpose()
+    }
+
+    /// Merge state directly into a given key's value using the
+    /// configured merge operator. This allows state to be written
+    /// into a value directly, without any read-modify-write steps.
+    /// Merge operators can be used to implement arbitrary data
+    /// structures.
     ///
-    ///     Some(number.to_be_bytes().to_vec())
+    /// Calling `merge` will return an `Unsupported` error if it
+    /// is called without first setting a merge operator function.
+    ///
+    /// Merge operators are shared by all instances of a particular
+    /// `Tree`. Different merge operators may be set on different
+    /// `Tree`s.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// use sled::IVec;
+    ///
+    /// fn concatenate_merge(
+    ///   _key: &[u8],               // the key being merged
+    ///   old_value: Option<&[u8]>,  // the previous value, if one existed
+    ///   merged_bytes: &[u8]        // the new bytes being merged in
+    /// ) -> Option<Vec<u8>> {       // set the new value, return None to delete
+    ///   let mut ret = old_value
+    ///     .map(|ov| ov.to_vec())
+    ///     .unwrap_or_else(|| vec![]);
+    ///
+    ///   ret.extend_from_slice(merged_bytes);
+    ///
+    ///   Some(ret)
     /// }
     ///
-    /// assert_eq!(db.update_and_fetch("counter", increment), Ok(Some(zero)));
-    /// assert_eq!(db.update_and_fetch("counter", increment), Ok(Some(one)));
-    /// assert_eq!(db.update_and_fetch("counter", increment), Ok(Some(two)));
-    /// assert_eq!(db.update_and_fetch("counter", increment), Ok(Some(three)));
+    /// db.set_merge_operator(concatenate_merge);
+    ///
+    /// let k = b"k1";
+    ///
+    /// db.insert(k, vec![0]);
+    /// db.merge(k, vec![1]);
+    /// db.merge(k, vec![2]);
+    /// assert_eq!(db.get(k), Ok(Some(IVec::from(vec![0, 1, 2]))));
+    ///
+    /// // Replace previously merged data. The merge function will not be called.
+    /// db.insert(k, vec![3]);
+    /// assert_eq!(db.get(k), Ok(Some(IVec::from(vec![3]))));
+    ///
+    /// // Merges on non-present values will cause the merge function to be called
+    /// // with `old_value == None`. If the merge function returns something (which it
+    /// // does, in this case) a new value will be inserted.
+    /// db.remove(k);
+    /// db.merge(k, vec![4]);
+    /// assert_eq!(db.get(k), Ok(Some(IVec::from(vec![4]))));
     /// # Ok(()) }
     /// ```
-    pub fn update_and_fetch<K, V, F>(
-        &self,
-        key: K,
-        mut f: F,
-    ) -> Result<Option<IVec>>
+    pub fn merge<K, V>(&self, key: K, value: V) -> Result<Option<IVec>>
     where
         K: AsRef<[u8]>,
-        F: FnMut(Option<&[u8]>) -> Option<V>,
-        V: Into<IVec>,
+        V: AsRef<[u8]>,
     {
-        let key_ref = key.as_ref();
-        let mut current = self.get(key_ref)?;
+        tracing_span!(
+            "tree.merge",
+            tree = self.tracing_name(),
+            key_len = key.as_ref().len(),
+        );
+        memory::wait_for_room(&self.context)?;
+        quota::check(self, Some((key.as_ref(), value.as_ref().len())))?;
+        let stored_key = self.order.encode(key.as_ref());
+        let _cc = concurrency_control::read();
+        let indexed = index::begin_write(self, &pin())?;
+        loop {
+            if let Ok(merge) =
+                self.merge_inner(key.as_ref(), &stored_key, value.as_ref())?
+            {
+                index::finish_write(indexed)?;
+                return Ok(merge);
+            }
+        }
+    }
+
+    /// Atomically adds `delta` to the counter at `key`, returning
+    /// its new value. A counter is stored as a big-endian `u64`
+    /// that starts at 0 when the key isn't set, and wraps around
+    /// on overflow like `AtomicU64::fetch_add`. Returns an error
+    /// if the value of `key` is not 8 bytes long.
+    ///
+    /// Unlike an `update_and_fetch` or merge operator that adds
+    /// to the value, concurrent increments of the same key never
+    /// retry after losing a race against each other. Instead they
+    /// are combined into a single write to the log, which makes
+    /// this the cheapest way to keep counters that are written
+    /// from many threads.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// assert_eq!(db.increment(b"visits", 1)?, 1);
+    /// assert_eq!(db.increment(b"visits", 41)?, 42);
+    /// assert_eq!(db.get(b"visits")?, Some(sled::IVec::from(&42_u64.to_be_bytes())));
+    ///
+    /// db.insert(b"name", b"sled")?;
+    /// assert!(db.increment(b"name", 1).is_err());
+    /// # Ok(()) }
+    /// ```
+    pub fn increment<K: AsRef<[u8]>>(&self, key: K, delta: u64) -> Result<u64> {
+        counter::increment(self, key.as_ref(), delta)
+    }
+
+    // the merge operator is passed `user_key`, which is `key`
+    // before it was encoded for a `KeyOrder`.
+    pub(crate) fn merge_inner(
+        &self,
+        user_key: &[u8],
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Conflictable<Option<IVec>>> {
+        trace!("merging key {:?}", key);
+        #[cfg(feature = "metrics")]
+        let _measure = Measure::new(&M.tree_merge);
+
+        let merge_operator_opt = self.merge_operator.read();
+
+        if merge_operator_opt.is_none() {
+            return Err(Error::Unsupported(
+                "must set a merge operator on this Tree \
+                 before calling merge by calling \
+                 Tree::set_merge_operator"
+                    .to_owned(),
+            ));
+        }
 
+        let merge_operator = &**merge_operator_opt.as_ref().unwrap();
+
+        self.merge_with(user_key, key, value, merge_operator)
+    }
+
+    // applies `merge` to the current value of `key` like a merge
+    // operator, which `Tree::increment` uses to combine the deltas
+    // of counters.
+    pub(crate) fn merge_with<F>(
+        &self,
+        user_key: &[u8],
+        key: &[u8],
+        value: &[u8],
+        merge: F,
+    ) -> Result<Conflictable<Option<IVec>>>
+    where
+        F: Fn(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>>,
+    {
         loop {
-            let tmp = current.as_ref().map(AsRef::as_ref);
-            let next = f(tmp).map(Into::into);
-            match self.compare_and_swap::<_, _, IVec>(
-                key_ref,
-                tmp,
-                next.clone(),
-            )? {
-                Ok(()) => return Ok(next),
-                Err(CompareAndSwapError { current: cur, .. }) => {
-                    current = cur;
-                }
+            let guard = pin();
+            let View { pid, node_view, .. } =
+                self.view_for_key(key.as_ref(), &guard)?;
+
+            let (encoded_key, raw_value) = node_view.node_kv_pair(key.as_ref());
+            let stored_value = value_log::load_opt(self, raw_value)?;
+            let mut current_value = stored_value.as_deref();
+            if current_value.is_some()
+                && expiration::is_expired(self, key.as_ref(), &guard)?
+            {
+                current_value = None;
             }
+            let new = merge(user_key, current_value, value).map(IVec::from);
+
+            if new.as_deref() == current_value {
+                // short-circuit no-op write
+                return Ok(Ok(new));
+            }
+
+            let mut subscriber_reservation =
+                self.subscribers.reserve(&key, new.as_ref().map(|v| v.len()));
+
+            let frag = if let Some(ref new) = new {
+                Link::Set(encoded_key, value_log::store(self, key, new)?)
+            } else {
+                Link::Del(encoded_key)
+            };
+            snapshot::record(self, key, || Ok(stored_value.clone()), &guard)?;
+            let linking =
+                new.as_ref().and_then(|_| bloom::write(self, pid, key));
+            let link =
+                self.context.pagecache.link(pid, node_view.0, frag, &guard)?;
+            drop(linking);
+
+            if let Ok(ref linked) = link {
+                let seq = history::seq(linked.last_lsn());
+                tracing_event!("tree.linked", pid = pid, lsn = linked.last_lsn());
+                self.count_write(stored_value.is_some(), new.is_some());
+                db_metrics::record_write(
+                    self,
+                    key.len(),
+                    new.as_ref().map(|v| v.len()),
+                );
+
+                index::update(
+                    self,
+                    key,
+                    stored_value.as_deref(),
+                    new.as_deref(),
+                )?;
+
+                versions::record(
+                    self,
+                    key,
+                    || Ok(current_value.map(IVec::from)),
+                    seq,
+                )?;
+                key_version::record(self, key, new.is_some(), seq)?;
+
+                history::publish(
+                    self,
+                    subscriber_reservation.take(),
+                    |with_previous| {
+                        let previous = if with_previous {
+                            Some(current_value.map(IVec::from))
+                        } else {
+                            None
+                        };
+                        Ok(Event::single_update(
+                            self.clone(),
+                            seq,
+                            key.as_ref().into(),
+                            new.clone(),
+                            previous,
+                        ))
+                    },
+                )?;
+
+                let _ = expiration::clear(self, key.as_ref())?;
+
+                return Ok(Ok(new));
+            }
+            #[cfg(feature = "metrics")]
+            M.tree_looped();
         }
     }
 
-    /// Fetch the value, apply a function to it and return the previous value.
+    /// Sets a merge operator for use with the `merge` function.
+    /// The operator is only set for this process, see
+    /// `Tree::use_merge_operator` for operators that are
+    /// persisted by name.
+    ///
+    /// Merge state directly into a given key's value using the
+    /// configured merge operator. This allows state to be written
+    /// into a value directly, without any read-modify-write steps.
+    /// Merge operators can be used to implement arbitrary data
+    /// structures.
     ///
-    /// # Note
+    /// # Panics
     ///
-    /// This may call the function multiple times if the value has been
-    /// changed from other threads in the meantime.
+    /// Calling `merge` will panic if no merge operator has been
+    /// configured.
     ///
     /// # Examples
     ///
     /// ```
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
-    /// use sled::{Config, Error, IVec};
-    /// use std::convert::TryInto;
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// use sled::IVec;
     ///
-    /// let config = Config::new().temporary(true);
-    /// let db = config.open()?;
+    /// fn concatenate_merge(
+    ///   _key: &[u8],               // the key being merged
+    ///   old_value: Option<&[u8]>,  // the previous value, if one existed
+    ///   merged_bytes: &[u8]        // the new bytes being merged in
+    /// ) -> Option<Vec<u8>> {       // set the new value, return None to delete
+    ///   let mut ret = old_value
+    ///     .map(|ov| ov.to_vec())
+    ///     .unwrap_or_else(|| vec![]);
     ///
-    /// fn u64_to_ivec(number: u64) -> IVec {
-    ///     IVec::from(number.to_be_bytes().to_vec())
+    ///   ret.extend_from_slice(merged_bytes);
+    ///
+    ///   Some(ret)
     /// }
     ///
-    /// let zero = u64_to_ivec(0);
-    /// let one = u64_to_ivec(1);
-    /// let two = u64_to_ivec(2);
+    /// db.set_merge_operator(concatenate_merge);
     ///
-    /// fn increment(old: Option<&[u8]>) -> Option<Vec<u8>> {
-    ///     let number = match old {
-    ///         Some(bytes) => {
-    ///             let array: [u8; 8] = bytes.try_into().unwrap();
-    ///             let number = u64::from_be_bytes(array);
-    ///             number + 1
-    ///         }
-    ///         None => 0,
-    ///     };
+    /// let k = b"k1";
     ///
-    ///     Some(number.to_be_bytes().to_vec())
-    /// }
+    /// db.insert(k, vec![0]);
+    /// db.merge(k, vec![1]);
+    /// db.merge(k, vec![2]);
+    /// assert_eq!(db.get(k), Ok(Some(IVec::from(vec![0, 1, 2]))));
     ///
-    /// assert_eq!(db.fetch_and_update("counter", increment), Ok(None));
-    /// assert_eq!(db.fetch_and_update("counter", increment), Ok(Some(zero)));
-    /// assert_eq!(db.fetch_and_update("counter", increment), Ok(Some(one)));
-    /// assert_eq!(db.fetch_and_update("counter", increment), Ok(Some(two)));
+    /// // Replace previously merged data. The merge function will not be called.
+    /// db.insert(k, vec![3]);
+    /// assert_eq!(db.get(k), Ok(Some(IVec::from(vec![3]))));
+    ///
+    /// // Merges on non-present values will cause the merge function to be called
+    /// // with `old_value == None`. If the merge function returns something (which it
+    /// // does, in this case) a new value will be inserted.
+    /// db.remove(k);
+    /// db.merge(k, vec![4]);
+    /// assert_eq!(db.get(k), Ok(Some(IVec::from(vec![4]))));
     /// # Ok(()) }
     /// ```
-    pub fn fetch_and_update<K, V, F>(
+    pub fn set_merge_operator(
         &self,
-        key: K,
-        mut f: F,
-    ) -> Result<Option<IVec>>
-    where
-        K: AsRef<[u8]>,
-        F: FnMut(Option<&[u8]>) -> Option<V>,
-        V: Into<IVec>,
-    {
-        let key_ref = key.as_ref();
-        let mut current = self.get(key_ref)?;
-
-        loop {
-            let tmp = current.as_ref().map(AsRef::as_ref);
-            let next = f(tmp);
-            match self.compare_and_swap(key_ref, tmp, next)? {
-                Ok(()) => return Ok(current),
-                Err(CompareAndSwapError { current: cur, .. }) => {
-                    current = cur;
-                }
-            }
-        }
+        merge_operator: impl MergeOperator + 'static,
+    ) {
+        let mut mo_write = self.merge_operator.write();
+        *mo_write = Some(Arc::new(merge_operator));
     }
 
-    /// Subscribe to `Event`s that happen to keys that have
-    /// the specified prefix. Events for particular keys are
-    /// guaranteed to be witnessed in the same order by all
-    /// threads, but threads may witness different interleavings
-    /// of `Event`s across different keys. If subscribers don't
-    /// keep up with new writes, they will cause new writes
-    /// to block. There is a buffer of 1024 items per
-    /// `Subscriber`. This can be used to build reactive
-    /// and replicated systems.
+    /// Sets the merge operator registered under `name` with
+    /// `Db::register_merge_operator` for use with the `merge`
+    /// function, and persists the name for this `Tree`. Once it
+    /// has been persisted, the `Tree` can only be opened after the
+    /// operator has been registered again, and it is set whenever
+    /// the `Tree` is opened.
     ///
-    /// `Subscriber` implements both `Iterator<Item = Event>`
-    /// and `Future<Output=Option<Event>>`
+    /// Returns `Error::Unsupported` if no operator is registered
+    /// under `name`, or if the `Tree` already uses an operator
+    /// with a different name.
     ///
     /// # Examples
     ///
-    /// Synchronous, blocking subscriber:
     /// ```
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// # let config = sled::Config::new().temporary(true);
     /// # let db = config.open()?;
-    /// // watch all events by subscribing to the empty prefix
-    /// let mut subscriber = db.watch_prefix(vec![]);
-    ///
-    /// let tree_2 = db.clone();
-    /// let thread = std::thread::spawn(move || {
-    ///     db.insert(vec![0], vec![1])
+    /// db.register_merge_operator("concatenate", |_k, old, merged| {
+    ///     let mut ret = old.map_or_else(Vec::new, <[u8]>::to_vec);
+    ///     ret.extend_from_slice(merged);
+    ///     Some(ret)
     /// });
     ///
-    /// // `Subscription` implements `Iterator<Item=Event>`
-    /// for event in subscriber.take(1) {
-    ///     // Events occur due to single key operations,
-    ///     // batches, or transactions. The tree is included
-    ///     // so that you may perform a new transaction or
-    ///     // operation in response to the event.
-    ///     for (tree, key, value_opt) in &event {
-    ///         if let Some(value) = value_opt {
-    ///             // key `key` was set to value `value`
-    ///         } else {
-    ///             // key `key` was removed
-    ///         }
-    ///     }
-    /// }
+    /// let log = db.open_tree("log")?;
+    /// log.use_merge_operator("concatenate")?;
     ///
-    /// # thread.join().unwrap();
+    /// log.merge("k", "a")?;
+    /// log.merge("k", "b")?;
+    /// assert_eq!(log.get("k")?, Some(sled::IVec::from("ab")));
     /// # Ok(()) }
     /// ```
-    /// Asynchronous, non-blocking subscriber:
-    ///
-    /// `Subscription` implements `Future<Output=Option<Event>>`.
-    ///
-    /// ```
-    /// # async fn foo() {
-    /// # let config = sled::Config::new().temporary(true);
-    /// # let db = config.open().unwrap();
-    /// # let mut subscriber = db.watch_prefix(vec![]);
-    /// while let Some(event) = (&mut subscriber).await {
-    ///     /* use it */
-    /// }
-    /// # }
-    /// ```
-    pub fn watch_prefix<P: AsRef<[u8]>>(&self, prefix: P) -> Subscriber {
-        self.subscribers.register(prefix.as_ref())
-    }
-
-    /// Synchronously flushes all dirty IO buffers and calls
-    /// fsync. If this succeeds, it is guaranteed that all
-    /// previous writes will be recovered if the system
-    /// crashes. Returns the number of bytes flushed during
-    /// this call.
-    ///
-    /// Flushing can take quite a lot of time, and you should
-    /// measure the performance impact of using it on
-    /// realistic sustained workloads running on realistic
-    /// hardware.
-    pub fn flush(&self) -> Result<usize> {
-        self.context.pagecache.flush()
+    pub fn use_merge_operator(&self, name: &str) -> Result<()> {
+        merge_operators::use_operator(self, name)
     }
 
-    /// Asynchronously flushes all dirty IO buffers
-    /// and calls fsync. If this succeeds, it is
-    /// guaranteed that all previous writes will
-    /// be recovered if the system crashes. Returns
-    /// the number of bytes flushed during this call.
+    /// Sets limits on the size of this `Tree` and on the rate
+    /// that it is written at, or removes them if `quota` is
+    /// `None`, so that one tenant of a `Db` can't take all of
+    /// its space or write throughput.
     ///
-    /// Flushing can take quite a lot of time, and you
-    /// should measure the performance impact of
-    /// using it on realistic sustained workloads
-    /// running on realistic hardware.
-    // this clippy check is mis-firing on async code.
-    #[allow(clippy::used_underscore_binding)]
-    pub async fn flush_async(&self) -> Result<usize> {
-        let pagecache = self.context.pagecache.clone();
-        if let Some(result) =
-            threadpool::spawn(move || pagecache.flush()).await
-        {
-            result
-        } else {
-            Err(Error::ReportableBug(
-                "threadpool failed to complete \
-                action before shutdown"
-                    .to_string(),
-            ))
-        }
-    }
-
-    /// Returns `true` if the `Tree` contains a value for
-    /// the specified key.
+    /// Inserts, merges, compare and swaps and batches are checked
+    /// against the quota before they are made, while removals,
+    /// transactions and bulk loads are not. Quotas are not
+    /// persisted, so they need to be set again every time the
+    /// `Db` is opened.
     ///
     /// # Examples
     ///
     /// ```
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use sled::{Error, Quota, QuotaAction, QuotaLimit};
+    ///
     /// # let config = sled::Config::new().temporary(true);
     /// # let db = config.open()?;
-    /// db.insert(&[0], vec![0])?;
-    /// assert!(db.contains_key(&[0])?);
-    /// assert!(!db.contains_key(&[1])?);
+    /// let tenant = db.open_tree("tenant")?;
+    /// tenant.set_quota(Some(Quota {
+    ///     max_keys: Some(2),
+    ///     action: QuotaAction::Error,
+    ///     ..Quota::default()
+    /// }))?;
+    ///
+    /// tenant.insert("a", "1")?;
+    /// tenant.insert("b", "2")?;
+    /// tenant.insert("b", "3")?;
+    /// match tenant.insert("c", "4") {
+    ///     Err(Error::QuotaExceeded { limit: QuotaLimit::Keys, .. }) => {}
+    ///     other => panic!("expected the quota to be exceeded: {:?}", other),
+    /// }
     /// # Ok(()) }
     /// ```
-    pub fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
-        self.get(key).map(|v| v.is_some())
+    pub fn set_quota(&self, quota: Option<Quota>) -> Result<()> {
+        quota::set(self, quota)
     }
 
-    /// Retrieve the key and value before the provided key,
-    /// if one exists.
-    ///
-    /// # Note
-    /// The order follows the Ord implementation for `Vec<u8>`:
-    ///
-    /// `[] < [0] < [255] < [255, 0] < [255, 255] ...`
-    ///
-    /// To retain the ordering of numerical types use big endian reprensentation
+    /// Creates a secondary index named `name` over this `Tree`,
+    /// which maps the key that `index_function` returns for each
+    /// record to the record's key. Records for which it returns
+    /// `None` are not indexed.
+    ///
+    /// The index is built from the existing records, and is then
+    /// updated atomically with every write to this `Tree`,
+    /// including writes in batches and transactions. Index
+    /// functions are not persisted, so an index needs to be
+    /// created again every time the `Db` is opened, which rebuilds
+    /// it. Until then, writes to this `Tree` return
+    /// `Error::Unsupported`. Creating an index with the name of an
+    /// existing one replaces its function. Writes to other trees
+    /// go on while an index is built, but writes to this `Tree`
+    /// wait for it.
+    ///
+    /// Writes to a `Tree` with indexes are serialized with each
+    /// other, and are recovered atomically with their index
+    /// updates, which makes them slower than writes to a `Tree`
+    /// without indexes.
     ///
     /// # Examples
     ///
     /// ```
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
-    /// use sled::IVec;
     /// # let config = sled::Config::new().temporary(true);
     /// # let db = config.open()?;
-    /// for i in 0..10 {
-    ///     db.insert(&[i], vec![i])
-    ///         .expect("should write successfully");
-    /// }
+    /// let users = db.open_tree("users")?;
     ///
-    /// assert_eq!(db.get_lt(&[]), Ok(None));
-    /// assert_eq!(db.get_lt(&[0]), Ok(None));
-    /// assert_eq!(
-    ///     db.get_lt(&[1]),
-    ///     Ok(Some((IVec::from(&[0]), IVec::from(&[0]))))
-    /// );
-    /// assert_eq!(
-    ///     db.get_lt(&[9]),
-    ///     Ok(Some((IVec::from(&[8]), IVec::from(&[8]))))
-    /// );
-    /// assert_eq!(
-    ///     db.get_lt(&[10]),
-    ///     Ok(Some((IVec::from(&[9]), IVec::from(&[9]))))
-    /// );
-    /// assert_eq!(
-    ///     db.get_lt(&[255]),
-    ///     Ok(Some((IVec::from(&[9]), IVec::from(&[9]))))
-    /// );
+    /// // values are "name,email"
+    /// let by_email = users.create_index("by_email", |_k, v| {
+    ///     let email = v.splitn(2, |b| *b == b',').nth(1)?;
+    ///     Some(email.to_vec())
+    /// })?;
+    ///
+    /// users.insert("1", "ada,ada@example.com")?;
+    /// users.insert("2", "bob,bob@example.com")?;
+    ///
+    /// let (_email, id) = by_email.get("bob@example.com").next().unwrap()?;
+    /// assert_eq!(id, "2");
+    ///
+    /// // the index follows updates and removals
+    /// users.insert("2", "bob,robert@example.com")?;
+    /// assert!(by_email.get("bob@example.com").next().is_none());
+    /// users.remove("1")?;
+    /// assert_eq!(by_email.len(), 1);
     /// # Ok(()) }
     /// ```
-    pub fn get_lt<K>(&self, key: K) -> Result<Option<(IVec, IVec)>>
+    pub fn create_index<N, F>(
+        &self,
+        name: N,
+        index_function: F,
+    ) -> Result<Index>
     where
-        K: AsRef<[u8]>,
+        N: AsRef<[u8]>,
+        F: IndexFunction + 'static,
     {
-        #[cfg(feature = "metrics")]
-        let _measure = Measure::new(&M.tree_get);
-        self.range(..key).next_back().transpose()
+        index::create(self, name.as_ref(), Box::new(index_function))
     }
 
-    /// Retrieve the next key and value from the `Tree` after the
-    /// provided key.
-    ///
-    /// # Note
-    /// The order follows the Ord implementation for `Vec<u8>`:
-    ///
-    /// `[] < [0] < [255] < [255, 0] < [255, 255] ...`
-    ///
-    /// To retain the ordering of numerical types use big endian reprensentation
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
-    /// use sled::IVec;
-    /// # let config = sled::Config::new().temporary(true);
-    /// # let db = config.open()?;
-    /// for i in 0..10 {
-    ///     db.insert(&[i], vec![i])?;
-    /// }
+    /// Create a double-ended iterator over the tuples of keys and
+    /// values in this tree.
     ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// use sled::IVec;
+    /// db.insert(&[1], vec![10]);
+    /// db.insert(&[2], vec![20]);
+    /// db.insert(&[3], vec![30]);
+    /// let mut iter = db.iter();
     /// assert_eq!(
-    ///     db.get_gt(&[]),
-    ///     Ok(Some((IVec::from(&[0]), IVec::from(&[0]))))
-    /// );
-    /// assert_eq!(
-    ///     db.get_gt(&[0]),
-    ///     Ok(Some((IVec::from(&[1]), IVec::from(&[1]))))
-    /// );
-    /// assert_eq!(
-    ///     db.get_gt(&[1]),
-    ///     Ok(Some((IVec::from(&[2]), IVec::from(&[2]))))
+    ///     iter.next().unwrap(),
+    ///     Ok((IVec::from(&[1]), IVec::from(&[10])))
     /// );
     /// assert_eq!(
-    ///     db.get_gt(&[8]),
-    ///     Ok(Some((IVec::from(&[9]), IVec::from(&[9]))))
+    ///     iter.next().unwrap(),
+    ///     Ok((IVec::from(&[2]), IVec::from(&[20])))
     /// );
-    /// assert_eq!(db.get_gt(&[9]), Ok(None));
-    ///
-    /// db.insert(500u16.to_be_bytes(), vec![10]);
     /// assert_eq!(
-    ///     db.get_gt(&499u16.to_be_bytes()),
-    ///     Ok(Some((IVec::from(&500u16.to_be_bytes()), IVec::from(&[10]))))
+    ///     iter.next().unwrap(),
+    ///     Ok((IVec::from(&[3]), IVec::from(&[30])))
     /// );
+    /// assert_eq!(iter.next(), None);
     /// # Ok(()) }
     /// ```
-    pub fn get_gt<K>(&self, key: K) -> Result<Option<(IVec, IVec)>>
-    where
-        K: AsRef<[u8]>,
-    {
-        #[cfg(feature = "metrics")]
-        let _measure = Measure::new(&M.tree_get);
-        self.range((ops::Bound::Excluded(key), ops::Bound::Unbounded))
-            .next()
-            .transpose()
+    pub fn iter(&self) -> Iter {
+        self.range::<Vec<u8>, _>(..)
     }
 
-    /// Merge state directly into a given key's value using the
-    /// configured merge operator. This allows state to be written
-    /// into a value directly, without any read-modify-write steps.
-    /// Merge operators can be used to implement arbitrary data
-    /// structures.
-    ///
-    /// Calling `merge` will return an `Unsupported` error if it
-    /// is called without first setting a merge operator function.
-    ///
-    /// Merge operators are shared by all instances of a particular
-    /// `Tree`. Different merge operators may be set on different
-    /// `Tree`s.
+    /// Create a double-ended iterator over tuples of keys and values,
+    /// where the keys fall within the specified range. The bounds
+    /// are interpreted in the `KeyOrder` of the `Tree`.
     ///
     /// # Examples
     ///
@@ -1077,132 +3141,307 @@ impl Tree {
     /// # let config = sled::Config::new().temporary(true);
     /// # let db = config.open()?;
     /// use sled::IVec;
+    /// db.insert(&[0], vec![0])?;
+    /// db.insert(&[1], vec![10])?;
+    /// db.insert(&[2], vec![20])?;
+    /// db.insert(&[3], vec![30])?;
+    /// db.insert(&[4], vec![40])?;
+    /// db.insert(&[5], vec![50])?;
     ///
-    /// fn concatenate_merge(
-    ///   _key: &[u8],               // the key being merged
-    ///   old_value: Option<&[u8]>,  // the previous value, if one existed
-    ///   merged_bytes: &[u8]        // the new bytes being merged in
-    /// ) -> Option<Vec<u8>> {       // set the new value, return None to delete
-    ///   let mut ret = old_value
-    ///     .map(|ov| ov.to_vec())
-    ///     .unwrap_or_else(|| vec![]);
-    ///
-    ///   ret.extend_from_slice(merged_bytes);
+    /// let start: &[u8] = &[2];
+    /// let end: &[u8] = &[4];
+    /// let mut r = db.range(start..end);
+    /// assert_eq!(r.next().unwrap(), Ok((IVec::from(&[2]), IVec::from(&[20]))));
+    /// assert_eq!(r.next().unwrap(), Ok((IVec::from(&[3]), IVec::from(&[30]))));
+    /// assert_eq!(r.next(), None);
     ///
-    ///   Some(ret)
-    /// }
+    /// let mut r = db.range(start..end).rev();
+    /// assert_eq!(r.next().unwrap(), Ok((IVec::from(&[3]), IVec::from(&[30]))));
+    /// assert_eq!(r.next().unwrap(), Ok((IVec::from(&[2]), IVec::from(&[20]))));
+    /// assert_eq!(r.next(), None);
+    /// # Ok(()) }
+    /// ```
+    pub fn range<K, R>(&self, range: R) -> Iter
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let (lo, hi) = self.encode_range(&range);
+        self.range_inner(lo, hi)
+    }
+
+    /// Retrieve the value that a key held at a point in the past,
+    /// which is either the sequence number of a write, as in
+    /// `Event::seq`, or a `SystemTime`. Requires
+    /// `Config::version_retention_ms`, and returns
+    /// `Error::Unsupported` if the point is older than the
+    /// retention, or than the tree's retained versions.
     ///
-    /// db.set_merge_operator(concatenate_merge);
+    /// # Examples
     ///
-    /// let k = b"k1";
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::time::SystemTime;
     ///
-    /// db.insert(k, vec![0]);
-    /// db.merge(k, vec![1]);
-    /// db.merge(k, vec![2]);
-    /// assert_eq!(db.get(k), Ok(Some(IVec::from(vec![0, 1, 2]))));
+    /// let config = sled::Config::new()
+    ///     .temporary(true)
+    ///     .version_retention_ms(Some(60 * 60 * 1000));
+    /// let db = config.open()?;
     ///
-    /// // Replace previously merged data. The merge function will not be called.
-    /// db.insert(k, vec![3]);
-    /// assert_eq!(db.get(k), Ok(Some(IVec::from(vec![3]))));
+    /// db.insert(b"k", vec![1])?;
+    /// let then = SystemTime::now();
+    /// # std::thread::sleep(std::time::Duration::from_millis(2));
+    /// db.insert(b"k", vec![2])?;
     ///
-    /// // Merges on non-present values will cause the merge function to be called
-    /// // with `old_value == None`. If the merge function returns something (which it
-    /// // does, in this case) a new value will be inserted.
-    /// db.remove(k);
-    /// db.merge(k, vec![4]);
-    /// assert_eq!(db.get(k), Ok(Some(IVec::from(vec![4]))));
+    /// assert_eq!(db.get_at(b"k", then)?, Some(sled::IVec::from(vec![1])));
+    /// assert_eq!(db.get(b"k")?, Some(sled::IVec::from(vec![2])));
     /// # Ok(()) }
     /// ```
-    pub fn merge<K, V>(&self, key: K, value: V) -> Result<Option<IVec>>
+    pub fn get_at<K, A>(&self, key: K, at: A) -> Result<Option<IVec>>
     where
         K: AsRef<[u8]>,
-        V: AsRef<[u8]>,
+        A: Into<VersionAt>,
     {
-        let _cc = concurrency_control::read();
-        loop {
-            if let Ok(merge) = self.merge_inner(key.as_ref(), value.as_ref())? {
-                return Ok(merge);
-            }
-        }
+        versions::get_at(self, key.as_ref(), at.into())
     }
 
-    pub(crate) fn merge_inner(
+    /// Create a forward iterator over a range of keys as they
+    /// were at a point in the past, see `Tree::get_at`.
+    pub fn range_at<K, R, A>(&self, range: R, at: A) -> Result<VersionIter>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+        A: Into<VersionAt>,
+    {
+        versions::range_at(self, &range, at.into())
+    }
+
+    // encodes the bounds of a range for the `KeyOrder` of the tree
+    pub(crate) fn encode_range<K, R>(
         &self,
-        key: &[u8],
-        value: &[u8],
-    ) -> Result<Conflictable<Option<IVec>>> {
-        trace!("merging key {:?}", key);
-        #[cfg(feature = "metrics")]
-        let _measure = Measure::new(&M.tree_merge);
+        range: &R,
+    ) -> (ops::Bound<IVec>, ops::Bound<IVec>)
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let encode = |key: &K| IVec::from(&*self.order.encode(key.as_ref()));
 
-        let merge_operator_opt = self.merge_operator.read();
+        let lo = match range.start_bound() {
+            ops::Bound::Included(start) => ops::Bound::Included(encode(start)),
+            ops::Bound::Excluded(start) => ops::Bound::Excluded(encode(start)),
+            ops::Bound::Unbounded => ops::Bound::Included(IVec::from(&[])),
+        };
 
-        if merge_operator_opt.is_none() {
-            return Err(Error::Unsupported(
-                "must set a merge operator on this Tree \
-                 before calling merge by calling \
-                 Tree::set_merge_operator"
-                    .to_owned(),
-            ));
-        }
+        let hi = match range.end_bound() {
+            ops::Bound::Included(end) => ops::Bound::Included(encode(end)),
+            ops::Bound::Excluded(end) => ops::Bound::Excluded(encode(end)),
+            ops::Bound::Unbounded => ops::Bound::Unbounded,
+        };
 
-        let merge_operator = merge_operator_opt.as_ref().unwrap();
+        (lo, hi)
+    }
 
-        loop {
-            let guard = pin();
-            let View { pid, node_view, .. } =
-                self.view_for_key(key.as_ref(), &guard)?;
+    /// Iterates over the stored keys between `lo` and `hi`,
+    /// which are not encoded for the `KeyOrder` of the tree.
+    pub(crate) fn range_inner(
+        &self,
+        lo: ops::Bound<IVec>,
+        hi: ops::Bound<IVec>,
+    ) -> Iter {
+        Iter {
+            tree: self.clone(),
+            start: lo.clone(),
+            end: hi.clone(),
+            hi,
+            lo,
+            cached_node: None,
+            going_forward: true,
+            parts: iter::Parts::KeysAndValues,
+        }
+    }
 
-            let (encoded_key, current_value) =
-                node_view.node_kv_pair(key.as_ref());
-            let tmp = current_value.as_ref().map(AsRef::as_ref);
-            let new = merge_operator(key, tmp, value).map(IVec::from);
+    /// Create an iterator over tuples of keys and values,
+    /// where the all the keys starts with the given prefix.
+    /// In a `Tree` with `KeyOrder::CaseInsensitive`, ASCII
+    /// letters in the prefix match regardless of case.
+    ///
+    /// Because keys are stored in order, the keys that start
+    /// with a prefix are next to each other, and the scan only
+    /// reads the nodes on the path to the first of them, the
+    /// leaves that hold them, and at most one leaf after them
+    /// to find where they end. Subtrees without matching keys
+    /// are never visited, so a scan of a sparse prefix, or of
+    /// one without any keys, costs about as much as a `get`.
+    /// Delimiting the prefixes of composite keys, for example
+    /// `tenant_id/`, keeps a scan for one tenant from also
+    /// matching the tenants whose ids it is a prefix of.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// use sled::IVec;
+    /// db.insert(&[0, 0, 0], vec![0, 0, 0])?;
+    /// db.insert(&[0, 0, 1], vec![0, 0, 1])?;
+    /// db.insert(&[0, 0, 2], vec![0, 0, 2])?;
+    /// db.insert(&[0, 0, 3], vec![0, 0, 3])?;
+    /// db.insert(&[0, 1, 0], vec![0, 1, 0])?;
+    /// db.insert(&[0, 1, 1], vec![0, 1, 1])?;
+    ///
+    /// let prefix: &[u8] = &[0, 0];
+    /// let mut r = db.scan_prefix(prefix);
+    /// assert_eq!(
+    ///     r.next(),
+    ///     Some(Ok((IVec::from(&[0, 0, 0]), IVec::from(&[0, 0, 0]))))
+    /// );
+    /// assert_eq!(
+    ///     r.next(),
+    ///     Some(Ok((IVec::from(&[0, 0, 1]), IVec::from(&[0, 0, 1]))))
+    /// );
+    /// assert_eq!(
+    ///     r.next(),
+    ///     Some(Ok((IVec::from(&[0, 0, 2]), IVec::from(&[0, 0, 2]))))
+    /// );
+    /// assert_eq!(
+    ///     r.next(),
+    ///     Some(Ok((IVec::from(&[0, 0, 3]), IVec::from(&[0, 0, 3]))))
+    /// );
+    /// assert_eq!(r.next(), None);
+    /// # Ok(()) }
+    /// ```
+    pub fn scan_prefix<P>(&self, prefix: P) -> Iter
+    where
+        P: AsRef<[u8]>,
+    {
+        let prefix_ref = self.order.encode_prefix(prefix.as_ref());
+        let lo = ops::Bound::Included(IVec::from(&*prefix_ref));
+        let mut upper = prefix_ref.to_vec();
 
-            if new.as_ref().map(AsRef::as_ref) == current_value {
-                // short-circuit no-op write
-                return Ok(Ok(new));
+        while let Some(last) = upper.pop() {
+            if last < u8::max_value() {
+                upper.push(last + 1);
+                return self.range_inner(lo, ops::Bound::Excluded(upper.into()));
             }
+        }
 
-            let mut subscriber_reservation = self.subscribers.reserve(&key);
+        self.range_inner(lo, ops::Bound::Unbounded)
+    }
 
-            let frag = if let Some(ref new) = new {
-                Link::Set(encoded_key, new.clone())
-            } else {
-                Link::Del(encoded_key)
-            };
-            let link =
-                self.context.pagecache.link(pid, node_view.0, frag, &guard)?;
+    /// Returns the first key and value in the `Tree`, or
+    /// `None` if the `Tree` is empty.
+    pub fn first(&self) -> Result<Option<(IVec, IVec)>> {
+        self.iter().next().transpose()
+    }
 
-            if link.is_ok() {
-                if let Some(res) = subscriber_reservation.take() {
-                    let event = subscriber::Event::single_update(
-                        self.clone(),
-                        key.as_ref().into(),
-                        new.clone(),
-                    );
+    /// Returns the last key and value in the `Tree`, or
+    /// `None` if the `Tree` is empty.
+    pub fn last(&self) -> Result<Option<(IVec, IVec)>> {
+        self.iter().next_back().transpose()
+    }
 
-                    res.complete(&event);
+    /// Atomically removes the maximum item in the `Tree` instance.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// db.insert(&[0], vec![0])?;
+    /// db.insert(&[1], vec![10])?;
+    /// db.insert(&[2], vec![20])?;
+    /// db.insert(&[3], vec![30])?;
+    /// db.insert(&[4], vec![40])?;
+    /// db.insert(&[5], vec![50])?;
+    ///
+    /// assert_eq!(&db.pop_max()?.unwrap().0, &[5]);
+    /// assert_eq!(&db.pop_max()?.unwrap().0, &[4]);
+    /// assert_eq!(&db.pop_max()?.unwrap().0, &[3]);
+    /// assert_eq!(&db.pop_max()?.unwrap().0, &[2]);
+    /// assert_eq!(&db.pop_max()?.unwrap().0, &[1]);
+    /// assert_eq!(&db.pop_max()?.unwrap().0, &[0]);
+    /// assert_eq!(db.pop_max()?, None);
+    /// # Ok(()) }
+    /// ```
+    pub fn pop_max(&self) -> Result<Option<(IVec, IVec)>> {
+        loop {
+            if let Some(first_res) = self.iter().next_back() {
+                let first = first_res?;
+                if self
+                    .compare_and_swap::<_, _, &[u8]>(
+                        &first.0,
+                        Some(&first.1),
+                        None,
+                    )?
+                    .is_ok()
+                {
+                    trace!("pop_max removed item {:?}", first);
+                    return Ok(Some(first));
                 }
-
-                return Ok(Ok(new));
+            // try again
+            } else {
+                trace!("pop_max removed nothing from empty tree");
+                return Ok(None);
             }
-            #[cfg(feature = "metrics")]
-            M.tree_looped();
         }
     }
 
-    /// Sets a merge operator for use with the `merge` function.
+    /// Atomically removes the minimum item in the `Tree` instance.
     ///
-    /// Merge state directly into a given key's value using the
-    /// configured merge operator. This allows state to be written
-    /// into a value directly, without any read-modify-write steps.
-    /// Merge operators can be used to implement arbitrary data
-    /// structures.
+    /// # Examples
     ///
-    /// # Panics
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// db.insert(&[0], vec![0])?;
+    /// db.insert(&[1], vec![10])?;
+    /// db.insert(&[2], vec![20])?;
+    /// db.insert(&[3], vec![30])?;
+    /// db.insert(&[4], vec![40])?;
+    /// db.insert(&[5], vec![50])?;
     ///
-    /// Calling `merge` will panic if no merge operator has been
-    /// configured.
+    /// assert_eq!(&db.pop_min()?.unwrap().0, &[0]);
+    /// assert_eq!(&db.pop_min()?.unwrap().0, &[1]);
+    /// assert_eq!(&db.pop_min()?.unwrap().0, &[2]);
+    /// assert_eq!(&db.pop_min()?.unwrap().0, &[3]);
+    /// assert_eq!(&db.pop_min()?.unwrap().0, &[4]);
+    /// assert_eq!(&db.pop_min()?.unwrap().0, &[5]);
+    /// assert_eq!(db.pop_min()?, None);
+    /// # Ok(()) }
+    /// ```
+    pub fn pop_min(&self) -> Result<Option<(IVec, IVec)>> {
+        loop {
+            if let Some(first_res) = self.iter().next() {
+                let first = first_res?;
+                if self
+                    .compare_and_swap::<_, _, &[u8]>(
+                        &first.0,
+                        Some(&first.1),
+                        None,
+                    )?
+                    .is_ok()
+                {
+                    trace!("pop_min removed item {:?}", first);
+                    return Ok(Some(first));
+                }
+            // try again
+            } else {
+                trace!("pop_min removed nothing from empty tree");
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Atomically removes up to `n` of the smallest items in the
+    /// `Tree`, returning them in order. Unlike calling `pop_min`
+    /// `n` times, the items are found in a single traversal and
+    /// removed as a single batch, so that consumers of a queue
+    /// don't contend for its head one item at a time. Writes to
+    /// every `Tree` wait while the items are collected.
     ///
     /// # Examples
     ///
@@ -1210,53 +3449,171 @@ impl Tree {
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// # let config = sled::Config::new().temporary(true);
     /// # let db = config.open()?;
-    /// use sled::IVec;
+    /// for i in 0..5_u8 {
+    ///     db.insert(&[i], vec![i * 10])?;
+    /// }
     ///
-    /// fn concatenate_merge(
-    ///   _key: &[u8],               // the key being merged
-    ///   old_value: Option<&[u8]>,  // the previous value, if one existed
-    ///   merged_bytes: &[u8]        // the new bytes being merged in
-    /// ) -> Option<Vec<u8>> {       // set the new value, return None to delete
-    ///   let mut ret = old_value
-    ///     .map(|ov| ov.to_vec())
-    ///     .unwrap_or_else(|| vec![]);
+    /// let popped = db.pop_n_min(3)?;
+    /// let keys: Vec<_> = popped.iter().map(|(k, _v)| k.to_vec()).collect();
+    /// assert_eq!(keys, vec![vec![0], vec![1], vec![2]]);
+    /// assert_eq!(db.pop_n_min(3)?.len(), 2);
+    /// assert!(db.pop_n_min(3)?.is_empty());
+    /// # Ok(()) }
+    /// ```
+    pub fn pop_n_min(&self, n: usize) -> Result<Vec<(IVec, IVec)>> {
+        self.pop_n(n, true)
+    }
+
+    /// Atomically removes up to `n` of the largest items in the
+    /// `Tree`, returning them from the largest down, see
+    /// `pop_n_min`.
     ///
-    ///   ret.extend_from_slice(merged_bytes);
+    /// # Examples
     ///
-    ///   Some(ret)
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// for i in 0..5_u8 {
+    ///     db.insert(&[i], vec![i * 10])?;
     /// }
     ///
-    /// db.set_merge_operator(concatenate_merge);
+    /// let popped = db.pop_n_max(2)?;
+    /// let keys: Vec<_> = popped.iter().map(|(k, _v)| k.to_vec()).collect();
+    /// assert_eq!(keys, vec![vec![4], vec![3]]);
+    /// assert_eq!(db.len(), 3);
+    /// # Ok(()) }
+    /// ```
+    pub fn pop_n_max(&self, n: usize) -> Result<Vec<(IVec, IVec)>> {
+        self.pop_n(n, false)
+    }
+
+    fn pop_n(&self, n: usize, smallest: bool) -> Result<Vec<(IVec, IVec)>> {
+        let _cc = concurrency_control::write();
+
+        let mut batch = Batch::default();
+        let mut popped = vec![];
+        let mut iter = self.iter();
+
+        // we hold the write lock for the entire scan, so the
+        // items can't be removed by anyone else before the
+        // batch is applied, and we call `next_inner` directly
+        // as `remove_range` does.
+        while popped.len() < n {
+            let next = if smallest {
+                iter.next_inner()
+            } else {
+                iter.next_back_inner()
+            };
+            let (k, v) = if let Some(res) = next { res? } else { break };
+            if expiration::is_expired(self, &k, &pin())? {
+                continue;
+            }
+            batch.remove(k.clone());
+            popped.push((self.order.decode(k), v));
+        }
+
+        if !popped.is_empty() {
+            trace!("pop_n removed {} items", popped.len());
+            let mut guard = pin();
+            self.apply_batch_inner(batch, &mut guard)?;
+        }
+
+        Ok(popped)
+    }
+
+    /// Atomically removes the minimum item in the `Tree` like
+    /// `pop_min`, blocking until there is one if the `Tree` is
+    /// empty, so that it can serve as a work queue without
+    /// polling. Returns `None` if nothing was inserted within the
+    /// `timeout`, or if the `Tree` is dropped first.
     ///
-    /// let k = b"k1";
+    /// # Examples
     ///
-    /// db.insert(k, vec![0]);
-    /// db.merge(k, vec![1]);
-    /// db.merge(k, vec![2]);
-    /// assert_eq!(db.get(k), Ok(Some(IVec::from(vec![0, 1, 2]))));
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::time::Duration;
     ///
-    /// // Replace previously merged data. The merge function will not be called.
-    /// db.insert(k, vec![3]);
-    /// assert_eq!(db.get(k), Ok(Some(IVec::from(vec![3]))));
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// let producer = db.clone();
+    /// let thread = std::thread::spawn(move || {
+    ///     producer.insert(&[1], b"job").unwrap();
+    /// });
     ///
-    /// // Merges on non-present values will cause the merge function to be called
-    /// // with `old_value == None`. If the merge function returns something (which it
-    /// // does, in this case) a new value will be inserted.
-    /// db.remove(k);
-    /// db.merge(k, vec![4]);
-    /// assert_eq!(db.get(k), Ok(Some(IVec::from(vec![4]))));
+    /// let (key, _job) = db.pop_min_wait(Duration::from_secs(5))?.unwrap();
+    /// assert_eq!(&key, &[1]);
+    /// assert_eq!(db.pop_min_wait(Duration::from_millis(10))?, None);
+    /// # thread.join().unwrap();
     /// # Ok(()) }
     /// ```
-    pub fn set_merge_operator(
+    pub fn pop_min_wait(
         &self,
-        merge_operator: impl MergeOperator + 'static,
-    ) {
-        let mut mo_write = self.merge_operator.write();
-        *mo_write = Some(Box::new(merge_operator));
+        timeout: Duration,
+    ) -> Result<Option<(IVec, IVec)>> {
+        // the subscriber is registered before the tree is found to
+        // be empty, so that no insertion is missed in between
+        let subscriber = self.watch_prefix_with(
+            vec![],
+            EventFilter::new().kinds(EventKinds::INSERT),
+        );
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(popped) = self.pop_min()? {
+                return Ok(Some(popped));
+            }
+            let remaining = deadline
+                .checked_duration_since(Instant::now())
+                .unwrap_or_else(|| Duration::from_nanos(0));
+            if subscriber.next_timeout(remaining).is_err() {
+                return Ok(None);
+            }
+        }
     }
 
-    /// Create a double-ended iterator over the tuples of keys and
-    /// values in this tree.
+    /// Atomically removes the minimum item in the `Tree` like
+    /// `pop_min_wait`, without blocking the calling task while
+    /// the `Tree` is empty. It waits for as long as it takes, and
+    /// can be given up on by dropping the future. Returns `None`
+    /// if the `Tree` is dropped first.
+    // this clippy check is mis-firing on async code.
+    #[allow(clippy::used_underscore_binding)]
+    pub async fn pop_min_wait_async(&self) -> Result<Option<(IVec, IVec)>> {
+        let mut subscriber = self.watch_prefix_with(
+            vec![],
+            EventFilter::new().kinds(EventKinds::INSERT),
+        );
+        loop {
+            let tree = self.clone();
+            match threadpool::spawn(move || tree.pop_min()).await {
+                Some(Ok(None)) => {}
+                Some(popped) => return popped,
+                None => {
+                    return Err(Error::ReportableBug(
+                        "threadpool failed to complete \
+                        action before shutdown"
+                            .to_string(),
+                    ))
+                }
+            }
+            if (&mut subscriber).await.is_none() {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Returns the number of elements in this tree.
+    ///
+    /// The count is kept up to date by every write, so this is
+    /// O(1). It's persisted when the `Tree` is dropped, and if
+    /// the `Db` wasn't closed cleanly, the keys of every `Tree`
+    /// are counted with a full O(n) scan while it's opened again.
+    /// A `Db` that is opened read-only counts the keys of a
+    /// `Tree` the first time that this is called, since another
+    /// process may write to them. Keys written with
+    /// `Tree::insert_with_ttl` stop being counted as soon as they
+    /// expire, so the deadlines that passed since the last sweep
+    /// are scanned and subtracted from the count.
     ///
     /// # Examples
     ///
@@ -1264,32 +3621,130 @@ impl Tree {
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// # let config = sled::Config::new().temporary(true);
     /// # let db = config.open()?;
-    /// use sled::IVec;
-    /// db.insert(&[1], vec![10]);
-    /// db.insert(&[2], vec![20]);
-    /// db.insert(&[3], vec![30]);
-    /// let mut iter = db.iter();
-    /// assert_eq!(
-    ///     iter.next().unwrap(),
-    ///     Ok((IVec::from(&[1]), IVec::from(&[10])))
-    /// );
-    /// assert_eq!(
-    ///     iter.next().unwrap(),
-    ///     Ok((IVec::from(&[2]), IVec::from(&[20])))
-    /// );
-    /// assert_eq!(
-    ///     iter.next().unwrap(),
-    ///     Ok((IVec::from(&[3]), IVec::from(&[30])))
-    /// );
-    /// assert_eq!(iter.next(), None);
+    /// db.insert(b"a", vec![0]);
+    /// db.insert(b"b", vec![1]);
+    /// assert_eq!(db.len(), 2);
     /// # Ok(()) }
     /// ```
-    pub fn iter(&self) -> Iter {
-        self.range::<Vec<u8>, _>(..)
+    pub fn len(&self) -> usize {
+        let expired = expiration::expired_len(self).unwrap_or(0);
+        self.counted_len().saturating_sub(expired)
     }
 
-    /// Create a double-ended iterator over tuples of keys and values,
-    /// where the keys fall within the specified range.
+    // the number of keys that are stored in the tree, if they have
+    // been counted since the tree was loaded
+    pub(crate) fn known_len(&self) -> Option<usize> {
+        let count = self.item_count.load(Acquire);
+        if count == UNCOUNTED {
+            None
+        } else {
+            Some(usize::try_from(count).unwrap())
+        }
+    }
+
+    // the number of keys that are stored in the tree, including
+    // keys that expired but have not been swept
+    pub(crate) fn counted_len(&self) -> usize {
+        let count = self.item_count.load(Acquire);
+        if count != UNCOUNTED {
+            return usize::try_from(count).unwrap();
+        }
+
+        // nothing writes to a read-only `Db`, so the count is kept
+        // once the keys were scanned. writes may race with a scan
+        // of any other tree, so the keys of a tree that couldn't
+        // be counted when it was loaded are scanned every time
+        let (count, complete) = self.scan_len();
+        if complete && self.context.read_only {
+            self.item_count.store(count, Release);
+        }
+        usize::try_from(count).unwrap()
+    }
+
+    // counts the keys of the tree with a full scan, returning
+    // whether all of them could be read
+    fn scan_len(&self) -> (u64, bool) {
+        let mut iter = self.iter();
+        iter.parts = iter::Parts::Keys;
+        let mut count = 0;
+        while let Some(res) = iter.next_inner() {
+            if res.is_err() {
+                return (count, false);
+            }
+            count += 1;
+        }
+        (count, true)
+    }
+
+    // counts the keys of a tree that the caller keeps from being
+    // written to, unless they are counted already
+    pub(crate) fn recount_len(&self) {
+        if self.known_len().is_none() {
+            let (count, complete) = self.scan_len();
+            if complete {
+                self.item_count.store(count, Release);
+            }
+        }
+    }
+
+    // sets the number of keys of a tree that is loaded while the
+    // `Db` is opened, before anything can write to it, to the one
+    // that was persisted when it was last dropped, or counts them
+    // if it wasn't. the persisted count is removed, since any
+    // write would make it stale.
+    pub(crate) fn recover_len(&self, guard: &Guard) -> Result<()> {
+        if self.context.read_only {
+            return Ok(());
+        }
+
+        let persisted =
+            tree_stats::persisted_len(&self.context, &self.tree_id, guard);
+        if let Some(count) = persisted {
+            tree_stats::record_len(&self.context, &self.tree_id, None, guard)?;
+            self.item_count.store(count, Release);
+        } else {
+            // keys that can't be read are left to be counted by
+            // `Tree::len`
+            self.recount_len();
+        }
+        Ok(())
+    }
+
+    // keeps the count of `Tree::len` up to date after a write
+    // of a key is linked
+    fn count_write(&self, existed: bool, exists: bool) {
+        // keys are only counted while no writes are linked, so
+        // the count can't become known during this check
+        let uncounted = self.item_count.load(Acquire) == UNCOUNTED;
+        if existed == exists || uncounted {
+            return;
+        }
+        if exists {
+            let _ = self.item_count.fetch_add(1, Release);
+        } else {
+            let _ = self.item_count.fetch_sub(1, Release);
+        }
+    }
+
+    /// Returns `true` if the `Tree` contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Returns the approximate number of bytes and keys that
+    /// are stored within a range, without scanning it.
+    ///
+    /// The leaves at either end of the range are read and
+    /// counted exactly. The subtrees in between are estimated
+    /// from the fanout of the index nodes on a single path down
+    /// each level of them, so the number of nodes that are read
+    /// grows with the depth of the tree rather than with the
+    /// size of the range. Estimates of large ranges are usually
+    /// within a factor of two of the truth, which is enough to
+    /// balance work across shards. Bytes count keys and values
+    /// as stored, including the value log entries of separated
+    /// values, but not the overhead of nodes, and keys that
+    /// have expired but have not been swept are still counted.
     ///
     /// # Examples
     ///
@@ -1297,63 +3752,35 @@ impl Tree {
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// # let config = sled::Config::new().temporary(true);
     /// # let db = config.open()?;
-    /// use sled::IVec;
     /// db.insert(&[0], vec![0])?;
     /// db.insert(&[1], vec![10])?;
-    /// db.insert(&[2], vec![20])?;
+    /// db.insert(&[2], vec![20, 20])?;
     /// db.insert(&[3], vec![30])?;
-    /// db.insert(&[4], vec![40])?;
-    /// db.insert(&[5], vec![50])?;
-    ///
-    /// let start: &[u8] = &[2];
-    /// let end: &[u8] = &[4];
-    /// let mut r = db.range(start..end);
-    /// assert_eq!(r.next().unwrap(), Ok((IVec::from(&[2]), IVec::from(&[20]))));
-    /// assert_eq!(r.next().unwrap(), Ok((IVec::from(&[3]), IVec::from(&[30]))));
-    /// assert_eq!(r.next(), None);
     ///
-    /// let mut r = db.range(start..end).rev();
-    /// assert_eq!(r.next().unwrap(), Ok((IVec::from(&[3]), IVec::from(&[30]))));
-    /// assert_eq!(r.next().unwrap(), Ok((IVec::from(&[2]), IVec::from(&[20]))));
-    /// assert_eq!(r.next(), None);
+    /// let start: &[u8] = &[1];
+    /// let end: &[u8] = &[3];
+    /// assert_eq!(db.size_of_range(start..end)?, (5, 2));
     /// # Ok(()) }
     /// ```
-    pub fn range<K, R>(&self, range: R) -> Iter
+    pub fn size_of_range<K, R>(&self, range: R) -> Result<(u64, u64)>
     where
         K: AsRef<[u8]>,
         R: RangeBounds<K>,
     {
-        let lo = match range.start_bound() {
-            ops::Bound::Included(start) => {
-                ops::Bound::Included(IVec::from(start.as_ref()))
-            }
-            ops::Bound::Excluded(start) => {
-                ops::Bound::Excluded(IVec::from(start.as_ref()))
-            }
-            ops::Bound::Unbounded => ops::Bound::Included(IVec::from(&[])),
-        };
-
-        let hi = match range.end_bound() {
-            ops::Bound::Included(end) => {
-                ops::Bound::Included(IVec::from(end.as_ref()))
-            }
-            ops::Bound::Excluded(end) => {
-                ops::Bound::Excluded(IVec::from(end.as_ref()))
-            }
-            ops::Bound::Unbounded => ops::Bound::Unbounded,
-        };
-
-        Iter {
-            tree: self.clone(),
-            hi,
-            lo,
-            cached_node: None,
-            going_forward: true,
-        }
+        let (lo, hi) = self.encode_range(&range);
+        range_size::estimate(self, &lo, &hi)
     }
 
-    /// Create an iterator over tuples of keys and values,
-    /// where the all the keys starts with the given prefix.
+    /// Reads every node that holds keys within a range into the
+    /// page cache, so that the reads and scans of the range that
+    /// follow don't have to wait on storage, returning how many
+    /// nodes had to be read from storage.
+    ///
+    /// The nodes are read even if they don't all fit in the
+    /// cache, in which case the ones that were read first are
+    /// evicted again, so a range that is larger than the cache
+    /// should be prefetched in parts as it's used. Values that
+    /// are stored in the value log are not read.
     ///
     /// # Examples
     ///
@@ -1361,112 +3788,124 @@ impl Tree {
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// # let config = sled::Config::new().temporary(true);
     /// # let db = config.open()?;
-    /// use sled::IVec;
-    /// db.insert(&[0, 0, 0], vec![0, 0, 0])?;
-    /// db.insert(&[0, 0, 1], vec![0, 0, 1])?;
-    /// db.insert(&[0, 0, 2], vec![0, 0, 2])?;
-    /// db.insert(&[0, 0, 3], vec![0, 0, 3])?;
-    /// db.insert(&[0, 1, 0], vec![0, 1, 0])?;
-    /// db.insert(&[0, 1, 1], vec![0, 1, 1])?;
+    /// for i in 0..100_u8 {
+    ///     db.insert(&[i], vec![i])?;
+    /// }
     ///
-    /// let prefix: &[u8] = &[0, 0];
-    /// let mut r = db.scan_prefix(prefix);
-    /// assert_eq!(
-    ///     r.next(),
-    ///     Some(Ok((IVec::from(&[0, 0, 0]), IVec::from(&[0, 0, 0]))))
-    /// );
-    /// assert_eq!(
-    ///     r.next(),
-    ///     Some(Ok((IVec::from(&[0, 0, 1]), IVec::from(&[0, 0, 1]))))
-    /// );
-    /// assert_eq!(
-    ///     r.next(),
-    ///     Some(Ok((IVec::from(&[0, 0, 2]), IVec::from(&[0, 0, 2]))))
-    /// );
-    /// assert_eq!(
-    ///     r.next(),
-    ///     Some(Ok((IVec::from(&[0, 0, 3]), IVec::from(&[0, 0, 3]))))
-    /// );
-    /// assert_eq!(r.next(), None);
+    /// let start: &[u8] = &[10];
+    /// let end: &[u8] = &[20];
+    /// db.prefetch_range(start..end)?;
     /// # Ok(()) }
     /// ```
-    pub fn scan_prefix<P>(&self, prefix: P) -> Iter
+    pub fn prefetch_range<K, R>(&self, range: R) -> Result<usize>
     where
-        P: AsRef<[u8]>,
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
     {
-        let prefix_ref = prefix.as_ref();
-        let mut upper = prefix_ref.to_vec();
-
-        while let Some(last) = upper.pop() {
-            if last < u8::max_value() {
-                upper.push(last + 1);
-                return self.range(prefix_ref..&upper);
-            }
-        }
-
-        self.range(prefix..)
-    }
-
-    /// Returns the first key and value in the `Tree`, or
-    /// `None` if the `Tree` is empty.
-    pub fn first(&self) -> Result<Option<(IVec, IVec)>> {
-        self.iter().next().transpose()
-    }
-
-    /// Returns the last key and value in the `Tree`, or
-    /// `None` if the `Tree` is empty.
-    pub fn last(&self) -> Result<Option<(IVec, IVec)>> {
-        self.iter().next_back().transpose()
+        let (lo, hi) = self.encode_range(&range);
+        warmup::prefetch(self, &lo, &hi)
     }
 
-    /// Atomically removes the maximum item in the `Tree` instance.
+    /// Changes how long the pages of this `Tree` are kept in the
+    /// cache, relative to the pages of the other trees, replacing
+    /// the `TreeConfig::cache_priority` that it was created with.
+    ///
+    /// With `CachePriority::Pinned`, the pages of the `Tree` are
+    /// never evicted once they have been read, so that a scan of a
+    /// large tree can't evict a small one that every request
+    /// depends on. Every node of the `Tree` is rewritten with the
+    /// new priority, which reads the ones that aren't cached, so
+    /// a pinned `Tree` is entirely cached when this returns. The
+    /// priority is persisted, but after a restart the pages of a
+    /// pinned `Tree` are only cached as they're read, which
+    /// `Tree::prefetch_range` can do up front.
     ///
     /// # Examples
     ///
     /// ```
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
-    /// # let config = sled::Config::new().temporary(true);
-    /// # let db = config.open()?;
-    /// db.insert(&[0], vec![0])?;
-    /// db.insert(&[1], vec![10])?;
-    /// db.insert(&[2], vec![20])?;
-    /// db.insert(&[3], vec![30])?;
-    /// db.insert(&[4], vec![40])?;
-    /// db.insert(&[5], vec![50])?;
+    /// use sled::CachePriority;
     ///
-    /// assert_eq!(&db.pop_max()?.unwrap().0, &[5]);
-    /// assert_eq!(&db.pop_max()?.unwrap().0, &[4]);
-    /// assert_eq!(&db.pop_max()?.unwrap().0, &[3]);
-    /// assert_eq!(&db.pop_max()?.unwrap().0, &[2]);
-    /// assert_eq!(&db.pop_max()?.unwrap().0, &[1]);
-    /// assert_eq!(&db.pop_max()?.unwrap().0, &[0]);
-    /// assert_eq!(db.pop_max()?, None);
+    /// # let db = sled::Config::new().temporary(true).open()?;
+    /// let routes = db.open_tree("routes")?;
+    /// routes.insert("eu", "10.0.0.1")?;
+    /// routes.set_cache_priority(CachePriority::Pinned)?;
     /// # Ok(()) }
     /// ```
-    pub fn pop_max(&self) -> Result<Option<(IVec, IVec)>> {
+    pub fn set_cache_priority(&self, priority: CachePriority) -> Result<()> {
+        // a root that is hoisted while the levels are walked may
+        // have been copied from the old root before it was rewritten
         loop {
-            if let Some(first_res) = self.iter().next_back() {
-                let first = first_res?;
-                if self
-                    .compare_and_swap::<_, _, &[u8]>(
-                        &first.0,
-                        Some(&first.1),
-                        None,
-                    )?
-                    .is_ok()
-                {
-                    trace!("pop_max removed item {:?}", first);
-                    return Ok(Some(first));
+            let guard = pin();
+            let root = self.root.load(Acquire);
+            let mut leftmost_chain = vec![root];
+            let mut cursor = root;
+            while let Some(view) = self.view_for_pid(cursor, &guard)? {
+                if !view.is_index {
+                    break;
                 }
-            // try again
+                cursor = view.iter_index_pids().next().unwrap();
+                leftmost_chain.push(cursor);
+            }
+
+            // each level is walked through the links between its
+            // nodes, which also lead to the nodes that split off
+            // of the ones that haven't been rewritten yet
+            for leftmost in leftmost_chain {
+                let mut next = Some(leftmost);
+                while let Some(pid) = next {
+                    next = self.set_node_cache_priority(pid, priority)?;
+                }
+            }
+
+            if self.root.load(Acquire) == root {
+                return Ok(());
+            }
+        }
+    }
+
+    // rewrites a node with another cache priority, returning the
+    // node that follows it on its level
+    fn set_node_cache_priority(
+        &self,
+        pid: PageId,
+        priority: CachePriority,
+    ) -> Result<Option<PageId>> {
+        let guard = pin();
+        loop {
+            let view = if let Some(view) = self.view_for_pid(pid, &guard)? {
+                view
             } else {
-                trace!("pop_max removed nothing from empty tree");
                 return Ok(None);
+            };
+            if view.cache_priority() != priority {
+                let mut node = view.deref().clone();
+                node.set_tree_hints(priority, view.expected_value_size());
+                let replaced = self.context.pagecache.replace(
+                    pid,
+                    view.node_view.0,
+                    &node,
+                    &guard,
+                )?;
+                if replaced.is_err() {
+                    continue;
+                }
+                // accessed again so that the cache tracks the page
+                // under its new priority
+                let _ = self.context.pagecache.get(pid, &guard)?;
             }
+            return Ok(view.next.map(NonZeroU64::get));
         }
     }
 
-    /// Atomically removes the minimum item in the `Tree` instance.
+    /// Returns `n` keys that are chosen at random, each about
+    /// equally likely, by descending from the root to a random
+    /// leaf for each of them rather than scanning the tree.
+    ///
+    /// Keys are sampled with replacement, so they may repeat,
+    /// and are returned in no particular order. Fewer than `n`
+    /// keys are returned only if the tree is empty or almost
+    /// all of its keys have expired.
     ///
     /// # Examples
     ///
@@ -1474,48 +3913,29 @@ impl Tree {
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// # let config = sled::Config::new().temporary(true);
     /// # let db = config.open()?;
-    /// db.insert(&[0], vec![0])?;
-    /// db.insert(&[1], vec![10])?;
-    /// db.insert(&[2], vec![20])?;
-    /// db.insert(&[3], vec![30])?;
-    /// db.insert(&[4], vec![40])?;
-    /// db.insert(&[5], vec![50])?;
+    /// for i in 0..100_u8 {
+    ///     db.insert(&[i], vec![])?;
+    /// }
     ///
-    /// assert_eq!(&db.pop_min()?.unwrap().0, &[0]);
-    /// assert_eq!(&db.pop_min()?.unwrap().0, &[1]);
-    /// assert_eq!(&db.pop_min()?.unwrap().0, &[2]);
-    /// assert_eq!(&db.pop_min()?.unwrap().0, &[3]);
-    /// assert_eq!(&db.pop_min()?.unwrap().0, &[4]);
-    /// assert_eq!(&db.pop_min()?.unwrap().0, &[5]);
-    /// assert_eq!(db.pop_min()?, None);
+    /// let sample = db.sample_keys(10)?;
+    /// assert_eq!(sample.len(), 10);
+    /// assert!(sample.iter().all(|key| key[0] < 100));
     /// # Ok(()) }
     /// ```
-    pub fn pop_min(&self) -> Result<Option<(IVec, IVec)>> {
-        loop {
-            if let Some(first_res) = self.iter().next() {
-                let first = first_res?;
-                if self
-                    .compare_and_swap::<_, _, &[u8]>(
-                        &first.0,
-                        Some(&first.1),
-                        None,
-                    )?
-                    .is_ok()
-                {
-                    trace!("pop_min removed item {:?}", first);
-                    return Ok(Some(first));
-                }
-            // try again
-            } else {
-                trace!("pop_min removed nothing from empty tree");
-                return Ok(None);
-            }
-        }
+    pub fn sample_keys(&self, n: usize) -> Result<Vec<IVec>> {
+        sample::sample(self, n)
     }
 
-    /// Returns the number of elements in this tree.
+    /// Removes all keys that fall within the specified range,
+    /// returning the number of keys that were removed.
     ///
-    /// Beware: performs a full O(n) scan under the hood.
+    /// This is not atomic: the keys are removed in chunks of at
+    /// most 1024 keys, each of which is applied like a `Batch`,
+    /// while other writes go on. Concurrent readers may see a part
+    /// of the range removed, a key that is inserted into the range
+    /// meanwhile may or may not be removed, and after a crash only
+    /// the chunks that were applied before it are recovered.
+    /// Subscribers receive each chunk as an `Event` of its own.
     ///
     /// # Examples
     ///
@@ -1523,18 +3943,52 @@ impl Tree {
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// # let config = sled::Config::new().temporary(true);
     /// # let db = config.open()?;
-    /// db.insert(b"a", vec![0]);
-    /// db.insert(b"b", vec![1]);
+    /// db.insert(&[0], vec![0])?;
+    /// db.insert(&[1], vec![10])?;
+    /// db.insert(&[2], vec![20])?;
+    /// db.insert(&[3], vec![30])?;
+    ///
+    /// let start: &[u8] = &[1];
+    /// let end: &[u8] = &[3];
+    /// assert_eq!(db.remove_range(start..end)?, 2);
+    ///
     /// assert_eq!(db.len(), 2);
+    /// assert!(db.contains_key(&[0])?);
+    /// assert!(db.contains_key(&[3])?);
     /// # Ok(()) }
     /// ```
-    pub fn len(&self) -> usize {
-        self.iter().count()
-    }
+    pub fn remove_range<K, R>(&self, range: R) -> Result<usize>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let mut iter = self.range(range);
+        iter.parts = iter::Parts::Keys;
 
-    /// Returns `true` if the `Tree` contains no elements.
-    pub fn is_empty(&self) -> bool {
-        self.iter().next().is_none()
+        let mut removed = 0;
+        loop {
+            // the read lock and the log are only held for one chunk
+            // at a time, so that neither batches nor flushes wait
+            // for the whole range. we call `next_inner` directly
+            // rather than going through the `Iterator` impl which
+            // would try to acquire the read lock again
+            let cc = concurrency_control::read();
+            let mut batch = Batch::default();
+            while batch.writes.len() < REMOVE_RANGE_CHUNK {
+                match iter.next_inner() {
+                    Some(res) => batch.remove(res?.0),
+                    None => break,
+                }
+            }
+            if batch.writes.is_empty() {
+                return Ok(removed);
+            }
+
+            removed += batch.writes.len();
+            let mut guard = pin();
+            self.apply_batch_inner(batch, &mut guard)?;
+            drop(cc);
+        }
     }
 
     /// Clears the `Tree`, removing all values.
@@ -1569,6 +4023,64 @@ impl Tree {
         Ok(hasher.finalize())
     }
 
+    /// Trains a zstd dictionary from a sample of the values in
+    /// this `Tree`, which is used to compress it from then on.
+    /// This helps the most when values are small and similar to
+    /// each other, because zstd has little to work with when it
+    /// compresses them without one. Pages that were written
+    /// earlier are compressed with the dictionary as they are
+    /// rewritten over time. Training again replaces the
+    /// dictionary with one that reflects the current values.
+    ///
+    /// The `Tree` must have been created with `Codec::Zstd` by
+    /// `Db::open_tree_with`. This reads every value in the
+    /// `Tree` and is fairly slow.
+    pub fn train_dictionary(&self) -> Result<()> {
+        let (codec, dictionary) = self.codec()?;
+        if dictionary == 0 || codec.is_none() {
+            return Err(Error::Unsupported(
+                "dictionaries can only be trained for trees \
+                 created with Codec::Zstd"
+                    .into(),
+            ));
+        }
+
+        #[cfg(feature = "compression")]
+        {
+            const DICTIONARY_SIZE: usize = 16 * 1024;
+            // zstd suggests training with around 100
+            // times as much data as the dictionary holds.
+            const SAMPLE_SIZE: usize = 100 * DICTIONARY_SIZE;
+
+            let mut values_size = 0;
+            for value in self.iter().values() {
+                values_size += value?.len();
+            }
+            let stride = (values_size / SAMPLE_SIZE).max(1);
+
+            let samples = self
+                .iter()
+                .values()
+                .step_by(stride)
+                .collect::<Result<Vec<_>>>()?;
+
+            let data = zstd::dict::from_samples(&samples, DICTIONARY_SIZE)
+                .map_err(|e| {
+                    Error::Unsupported(format!(
+                        "failed to train a dictionary: {}",
+                        e
+                    ))
+                })?;
+
+            self.context.dictionaries.add(dictionary, data)
+        }
+
+        #[cfg(not(feature = "compression"))]
+        Err(Error::Unsupported(
+            "zstd compression requires the compression feature".into(),
+        ))
+    }
+
     fn split_node<'g>(
         &self,
         view: &View<'g>,
@@ -1586,12 +4098,14 @@ impl Tree {
 
         // replace node, pointing next to installed right
         lhs.set_next(Some(NonZeroU64::new(rhs_pid).unwrap()));
+        let linking = bloom::remove(self, view.pid);
         let replace = self.context.pagecache.replace(
             view.pid,
             view.node_view.0,
             &lhs,
             guard,
         )?;
+        drop(linking);
         #[cfg(feature = "metrics")]
         M.tree_child_split_attempt();
         if replace.is_err() {
@@ -1643,7 +4157,7 @@ impl Tree {
                 // failed.
             }
         } else {
-            let _ = self.root_hoist(root_pid, rhs_pid, &rhs_lo, guard)?;
+            let _ = self.root_hoist(root_pid, rhs_pid, &rhs_lo, view, guard)?;
         }
 
         Ok(())
@@ -1654,13 +4168,18 @@ impl Tree {
         from: PageId,
         to: PageId,
         at: &[u8],
+        root: &Node,
         guard: &Guard,
     ) -> Result<bool> {
         #[cfg(feature = "metrics")]
         M.tree_root_split_attempt();
         // hoist new root, pointing to lhs & rhs
 
-        let new_root = Node::new_hoisted_root(from, at, to);
+        let mut new_root = Node::new_hoisted_root(from, at, to);
+        new_root.set_codec(root.codec(), root.dictionary());
+        new_root.set_key_order(root.key_order());
+        new_root
+            .set_tree_hints(root.cache_priority(), root.expected_value_size());
 
         let (new_root_pid, new_root_ptr) =
             self.context.pagecache.allocate(new_root, guard)?;
@@ -1709,6 +4228,96 @@ impl Tree {
         }
     }
 
+    /// The codec that was chosen for this tree when it was
+    /// created, or `None` if it uses the one configured for
+    /// the `Db`, along with the slot of its dictionaries.
+    pub(crate) fn codec(&self) -> Result<(Option<Codec>, u32)> {
+        let guard = pin();
+        loop {
+            let root_pid = self.root.load(Acquire);
+            if let Some(view) = self.view_for_pid(root_pid, &guard)? {
+                return Ok((view.codec(), view.dictionary()));
+            }
+        }
+    }
+
+    /// The cache priority and expected value size chosen for this
+    /// tree, see `TreeConfig`.
+    pub(crate) fn tree_hints(
+        &self,
+    ) -> Result<(CachePriority, Option<usize>)> {
+        let guard = pin();
+        loop {
+            let root_pid = self.root.load(Acquire);
+            if let Some(view) = self.view_for_pid(root_pid, &guard)? {
+                return Ok((view.cache_priority(), view.expected_value_size()));
+            }
+        }
+    }
+
+    /// The options that this tree was created with, or `None` if
+    /// it was created without a `TreeConfig`.
+    pub(crate) fn tree_config(&self) -> Result<Option<TreeConfig>> {
+        let (cache_priority, expected_value_size) = self.tree_hints()?;
+        Ok(self.codec()?.0.map(|compression| TreeConfig {
+            compression,
+            order: self.order,
+            cache_priority,
+            expected_value_size,
+            merge_operator: None,
+        }))
+    }
+
+    // Remove all pages for this tree from the underlying
+    // PageCache. This will leave orphans behind if
+    // the tree crashes during gc.
+    pub(crate) fn gc_pages(
+        &self,
+        mut leftmost_chain: Vec<PageId>,
+    ) -> Result<()> {
+        let mut guard = pin();
+
+        let mut ops = 0;
+        while let Some(mut pid) = leftmost_chain.pop() {
+            loop {
+                ops += 1;
+                if ops % 64 == 0 {
+                    // we re-pin here to avoid memory blow-ups during
+                    // long-running tree removals.
+                    guard = pin();
+                }
+                let cursor_view =
+                    if let Some(view) = self.view_for_pid(pid, &guard)? {
+                        view
+                    } else {
+                        trace!(
+                            "encountered Free node pid {} while GC'ing tree",
+                            pid
+                        );
+                        break;
+                    };
+
+                let ret = self.context.pagecache.free(
+                    pid,
+                    cursor_view.node_view.0,
+                    &guard,
+                )?;
+
+                if ret.is_ok() {
+                    let next_pid = if let Some(next_pid) = cursor_view.next {
+                        next_pid
+                    } else {
+                        break;
+                    };
+                    assert_ne!(pid, next_pid.get());
+                    pid = next_pid.get();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn view_for_pid<'g>(
         &self,
         pid: PageId,
@@ -1735,6 +4344,35 @@ impl Tree {
         }
     }
 
+    // Returns the key ranges of the leaves that have been
+    // written after the provided `Lsn`. Leaves are also
+    // rewritten when they are split, merged, or relocated
+    // by the segment cleaner, so some of the returned
+    // ranges may not contain any logical changes.
+    pub(crate) fn changed_ranges(
+        &self,
+        since: Lsn,
+    ) -> Result<Vec<(IVec, Option<IVec>)>> {
+        let guard = pin();
+        let mut ret = vec![];
+        let mut key = IVec::default();
+
+        loop {
+            let view = self.view_for_key(&key, &guard)?;
+            let hi = view.hi().map(IVec::from);
+
+            if view.node_view.0.last_lsn() > since {
+                ret.push((IVec::from(view.lo()), hi.clone()));
+            }
+
+            if let Some(hi) = hi {
+                key = hi;
+            } else {
+                return Ok(ret);
+            }
+        }
+    }
+
     // Returns the traversal path, completing any observed
     // partially complete splits or merges along the way.
     //
@@ -1766,8 +4404,13 @@ impl Tree {
         let mut unsplit_parent = None;
         let mut took_leftmost_branch = false;
 
+        // nodes are never split or merged in read-only mode, and
+        // incomplete splits and merges are read around instead
+        // of being completed.
+        let read_only = self.context.read_only;
+
         // only merge or split nodes a few times
-        let mut smo_budget = 3_u8;
+        let mut smo_budget = if read_only { 0 } else { 3_u8 };
 
         #[cfg(feature = "testing")]
         let mut path = vec![];
@@ -1814,7 +4457,10 @@ impl Tree {
             path.push((cursor, view.clone()));
 
             // When we encounter a merge intention, we collaboratively help out
-            if view.merging_child.is_some() {
+            if read_only {
+                // a child that is being merged still
+                // contains all of its items
+            } else if view.merging_child.is_some() {
                 self.merge_node(
                     &view,
                     view.merging_child.unwrap().get(),
@@ -1858,7 +4504,10 @@ impl Tree {
                 cursor = right_sibling;
                 if unsplit_parent.is_none() && parent_view.is_some() {
                     unsplit_parent = parent_view.clone();
-                } else if parent_view.is_none() && view.lo().is_empty() {
+                } else if !read_only
+                    && parent_view.is_none()
+                    && view.lo().is_empty()
+                {
                     assert!(unsplit_parent.is_none());
                     assert_eq!(view.pid, root_pid);
                     // we have found a partially-split root
@@ -1866,6 +4515,7 @@ impl Tree {
                         root_pid,
                         view.next.unwrap().get(),
                         view.hi().unwrap(),
+                        &view,
                         guard,
                     )? {
                         #[cfg(feature = "metrics")]
@@ -1875,7 +4525,9 @@ impl Tree {
                 }
 
                 continue;
-            } else if let Some(unsplit_parent) = unsplit_parent.take() {
+            } else if let (Some(unsplit_parent), false) =
+                (unsplit_parent.take(), read_only)
+            {
                 // we have found the proper page for
                 // our cooperative parent split
                 trace!(
@@ -2004,12 +4656,14 @@ impl Tree {
                 return Ok(Some(child_view));
             }
 
+            let linking = bloom::remove(self, child_pid);
             let install_frag = self.context.pagecache.link(
                 child_pid,
                 child_view.node_view.0,
                 Link::ChildMergeCap,
                 guard,
             )?;
+            drop(linking);
             match install_frag {
                 Ok(new_ptr) => {
                     trace!("child pid {} merge capped", child_pid);
@@ -2490,3 +5144,42 @@ impl fmt::Display for CompareAndSwapError {
 }
 
 impl std::error::Error for CompareAndSwapError {}
+
+/// The error of `Tree::compare_and_swap_many`, for the first swap
+/// whose old value didn't match. No swap was applied.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CompareAndSwapManyError {
+    /// The position of the swap that failed in the slice that was
+    /// passed in.
+    pub index: usize,
+    /// The current value which caused your CAS to fail.
+    pub current: Option<IVec>,
+    /// Returned value that was proposed unsuccessfully.
+    pub proposed: Option<IVec>,
+}
+
+impl fmt::Display for CompareAndSwapManyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Compare and swap conflict at swap {}", self.index)
+    }
+}
+
+impl std::error::Error for CompareAndSwapManyError {}
+
+/// The error of `Tree::insert_if_version`, when the key no longer
+/// has the expected version.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VersionMismatchError {
+    /// The current value of the key and its version, if it is set.
+    pub current: Option<(IVec, Version)>,
+    /// Returned value that was proposed unsuccessfully.
+    pub proposed: IVec,
+}
+
+impl fmt::Display for VersionMismatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Version mismatch")
+    }
+}
+
+impl std::error::Error for VersionMismatchError {}
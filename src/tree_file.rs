@@ -0,0 +1,211 @@
+//! The file format written by `Tree::export_to_file` and read by
+//! `Tree::import_from_file`.
+//!
+//! A file begins with `MAGIC`, a version byte, and a byte for
+//! the `KeyOrder` of the exported tree. It is followed by the
+//! pairs of the tree in that order, each written as
+//! `varint(key.len() + 1) ++ key ++ varint(value.len()) ++ value`,
+//! and a zero byte that ends them. The file ends with the number
+//! of pairs and a crc32 of every byte that precedes it, both
+//! little-endian.
+//!
+//! Keys are written as they are passed to and returned by the
+//! `Tree` API, rather than in the form that trees with other key
+//! orders store them in, so files don't depend on how a version
+//! of sled encodes keys.
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use crate::*;
+
+const MAGIC: &[u8; 8] = b"sledtree";
+const VERSION: u8 = 1;
+
+fn order_byte(order: KeyOrder) -> u8 {
+    match order {
+        KeyOrder::Lexicographic => 0,
+        KeyOrder::Reverse => 1,
+        KeyOrder::CaseInsensitive => 2,
+    }
+}
+
+pub(crate) struct TreeFileWriter {
+    writer: BufWriter<File>,
+    hasher: crc32fast::Hasher,
+    pairs: u64,
+}
+
+impl TreeFileWriter {
+    pub(crate) fn create(
+        path: &Path,
+        order: KeyOrder,
+    ) -> Result<TreeFileWriter> {
+        let mut ret = TreeFileWriter {
+            writer: BufWriter::new(File::create(path)?),
+            hasher: crc32fast::Hasher::new(),
+            pairs: 0,
+        };
+        ret.write(MAGIC)?;
+        ret.write(&[VERSION, order_byte(order)])?;
+        Ok(ret)
+    }
+
+    pub(crate) fn pair(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.write_varint(u64::try_from(key.len()).unwrap() + 1)?;
+        self.write(key)?;
+        self.write_varint(u64::try_from(value.len()).unwrap())?;
+        self.write(value)?;
+        self.pairs += 1;
+        Ok(())
+    }
+
+    /// Ends the file and makes it durable, returning the number
+    /// of pairs that were written.
+    pub(crate) fn finish(mut self) -> Result<u64> {
+        self.write(&[0])?;
+        let pairs = self.pairs;
+        self.write(&pairs.to_le_bytes())?;
+        let crc = self.hasher.clone().finalize();
+        self.writer.write_all(&crc.to_le_bytes())?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(pairs)
+    }
+
+    fn write_varint(&mut self, int: u64) -> Result<()> {
+        let mut buf = [0; 9];
+        let len = varint::serialize_into(int, &mut buf);
+        self.write(&buf[..len])
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.hasher.update(bytes);
+        self.writer.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+/// Reads the pairs of a file, verifying its checksum once they
+/// have all been read.
+pub(crate) struct TreeFileReader {
+    reader: BufReader<File>,
+    hasher: crc32fast::Hasher,
+    pairs: u64,
+    done: bool,
+}
+
+impl TreeFileReader {
+    pub(crate) fn open(
+        path: &Path,
+        order: KeyOrder,
+    ) -> Result<TreeFileReader> {
+        let mut ret = TreeFileReader {
+            reader: BufReader::new(File::open(path)?),
+            hasher: crc32fast::Hasher::new(),
+            pairs: 0,
+            done: false,
+        };
+
+        let mut prelude = [0; 10];
+        ret.read(&mut prelude)?;
+        if &prelude[..8] != MAGIC {
+            return Err(Error::Unsupported(
+                "file is not an exported sled tree".into(),
+            ));
+        }
+        if prelude[8] != VERSION {
+            return Err(Error::Unsupported(format!(
+                "unsupported tree file version {}",
+                prelude[8]
+            )));
+        }
+        if prelude[9] != order_byte(order) {
+            return Err(Error::Unsupported(
+                "file was exported from a tree with another KeyOrder".into(),
+            ));
+        }
+
+        Ok(ret)
+    }
+
+    fn next_pair(&mut self) -> Result<Option<(IVec, IVec)>> {
+        let key_len = match self.read_varint()? {
+            0 => {
+                self.finish()?;
+                return Ok(None);
+            }
+            len => len - 1,
+        };
+        let key = self.read_bytes(key_len)?;
+        let value_len = self.read_varint()?;
+        let value = self.read_bytes(value_len)?;
+        self.pairs += 1;
+        Ok(Some((key, value)))
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let mut pairs = [0; 8];
+        self.read(&mut pairs)?;
+        let expected = self.hasher.clone().finalize();
+        let mut crc = [0; 4];
+        self.reader.read_exact(&mut crc)?;
+        if u32::from_le_bytes(crc) != expected
+            || u64::from_le_bytes(pairs) != self.pairs
+        {
+            return Err(Error::corruption(None));
+        }
+        Ok(())
+    }
+
+    fn read_varint(&mut self) -> Result<u64> {
+        let mut buf = [0; 9];
+        self.read(&mut buf[..1])?;
+        let len = match buf[0] {
+            0..=240 => 1,
+            241..=248 => 2,
+            249 => 3,
+            other => usize::from(other) - 247 + 1,
+        };
+        self.read(&mut buf[1..len])?;
+        Ok(varint::deserialize(&buf[..len])?.0)
+    }
+
+    fn read_bytes(&mut self, len: u64) -> Result<IVec> {
+        // read through `take` rather than allocating `len`
+        // bytes up front, so a corrupt length can't cause
+        // a huge allocation.
+        let mut buf = vec![];
+        let read = (&mut self.reader).take(len).read_to_end(&mut buf)?;
+        if u64::try_from(read).unwrap() != len {
+            return Err(Error::corruption(None));
+        }
+        self.hasher.update(&buf);
+        Ok(buf.into())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.reader.read_exact(buf)?;
+        self.hasher.update(buf);
+        Ok(())
+    }
+}
+
+impl Iterator for TreeFileReader {
+    type Item = Result<(IVec, IVec)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let ret = self.next_pair().transpose();
+        match ret {
+            Some(Ok(_)) => {}
+            // stop after the end or the first error
+            _ => self.done = true,
+        }
+        ret
+    }
+}
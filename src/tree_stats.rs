@@ -0,0 +1,251 @@
+//! The statistics of every `Tree`, see `Db::tree_stats`.
+//!
+//! The time that a `Tree` was created at is persisted in the
+//! `Meta` page next to the roots of the trees, as a key made of a
+//! prefix and the name of the `Tree`, whose value is the number
+//! of milliseconds since the unix epoch rather than a root. It is
+//! written when the `Tree` is created, removed when it's dropped
+//! and moved along with it when it's renamed.
+//!
+//! The number of keys that `Tree::len` returns is persisted in
+//! the same way when the last handle of a `Tree` is dropped, and
+//! is read and removed when the `Db` is opened again, so that a
+//! count that a later write would make stale is never recovered.
+//! A `Tree` whose count isn't found, because the `Db` wasn't
+//! closed cleanly, has its keys counted while the `Db` is opened.
+//!
+//! The nodes of a `Tree` are walked one level at a time from its
+//! root, reading the index nodes that aren't cached but only
+//! checking whether the leaves are, so that the stats don't page
+//! in the whole `Tree` and change the residency that they report.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::*;
+
+const CREATED_PREFIX: &[u8] = b"__sled__created__";
+const LEN_PREFIX: &[u8] = b"__sled__len__";
+
+/// The statistics of a `Tree`, returned by `Db::tree_stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeStats {
+    /// The name of the tree.
+    pub tree: IVec,
+    /// The number of keys, like `Tree::len` once the keys have
+    /// been counted, and estimated like with `Tree::size_of_range`
+    /// until then, which is only the case for a `Db` that was
+    /// opened read-only.
+    pub len: usize,
+    /// The bytes of the keys and values, estimated like with
+    /// `Tree::size_of_range`.
+    pub live_bytes: u64,
+    /// The bytes that the nodes take in the log and the heap
+    /// files beyond the live bytes, which are the overhead of
+    /// the nodes and the updates that later ones replaced, until
+    /// the nodes are rewritten.
+    pub dead_bytes: u64,
+    /// The number of nodes of the tree.
+    pub nodes: u64,
+    /// The number of nodes that are in the page cache.
+    pub cached_nodes: u64,
+    /// When the tree was created, or `None` if it was created by
+    /// a version of sled that didn't record it.
+    pub created: Option<SystemTime>,
+}
+
+impl TreeStats {
+    /// Returns the share of the nodes of the tree that are in
+    /// the page cache, between 0 and 1.
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::float_arithmetic)]
+    pub fn cache_residency(&self) -> f64 {
+        if self.nodes == 0 {
+            return 0.;
+        }
+        self.cached_nodes as f64 / self.nodes as f64
+    }
+}
+
+pub(crate) fn is_meta_key(name: &[u8]) -> bool {
+    name.starts_with(CREATED_PREFIX) || name.starts_with(LEN_PREFIX)
+}
+
+/// Returns the name of the tree whose creation time is persisted
+/// under a `Meta` key.
+pub(crate) fn created_tree_name(key: &[u8]) -> Option<&[u8]> {
+    if key.starts_with(CREATED_PREFIX) {
+        Some(&key[CREATED_PREFIX.len()..])
+    } else {
+        None
+    }
+}
+
+/// Returns the name of the tree whose number of keys is persisted
+/// under a `Meta` key.
+pub(crate) fn len_tree_name(key: &[u8]) -> Option<&[u8]> {
+    if key.starts_with(LEN_PREFIX) {
+        Some(&key[LEN_PREFIX.len()..])
+    } else {
+        None
+    }
+}
+
+/// Returns the `Meta` key that persists the creation time of a
+/// tree.
+pub(crate) fn created_meta_key(tree_id: &[u8]) -> IVec {
+    let mut ret = CREATED_PREFIX.to_vec();
+    ret.extend_from_slice(tree_id);
+    ret.into()
+}
+
+/// Returns the `Meta` key that persists the number of keys of a
+/// tree.
+pub(crate) fn len_meta_key(tree_id: &[u8]) -> IVec {
+    let mut ret = LEN_PREFIX.to_vec();
+    ret.extend_from_slice(tree_id);
+    ret.into()
+}
+
+/// Returns the number of keys of a tree that was persisted when
+/// it was last dropped.
+pub(crate) fn persisted_len(
+    context: &Context,
+    tree_id: &[u8],
+    guard: &Guard,
+) -> Option<u64> {
+    context.pagecache.get_meta(guard).get_root(&len_meta_key(tree_id))
+}
+
+/// Persists the number of keys of a tree, or removes it if `len`
+/// is `None`.
+pub(crate) fn record_len(
+    context: &Context,
+    tree_id: &[u8],
+    len: Option<u64>,
+    guard: &Guard,
+) -> Result<()> {
+    let key = len_meta_key(tree_id);
+    let mut old = context.pagecache.get_meta(guard).get_root(&key);
+    while old != len {
+        match context.pagecache.cas_root_in_meta(&key, old, len, guard)? {
+            Ok(()) => break,
+            Err(actual) => old = actual,
+        }
+    }
+    Ok(())
+}
+
+/// Records that a tree has just been created.
+pub(crate) fn record_created(
+    context: &Context,
+    tree_id: &[u8],
+    guard: &Guard,
+) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let millis = u64::try_from(now.as_millis()).unwrap_or(u64::max_value());
+    let key = created_meta_key(tree_id);
+    let mut old = context.pagecache.get_meta(guard).get_root(&key);
+    while let Err(actual) =
+        context.pagecache.cas_root_in_meta(&key, old, Some(millis), guard)?
+    {
+        old = actual;
+    }
+    Ok(())
+}
+
+/// Removes the creation time of a tree that is dropped.
+pub(crate) fn forget_created(
+    context: &Context,
+    tree_id: &[u8],
+    guard: &Guard,
+) -> Result<()> {
+    let key = created_meta_key(tree_id);
+    let mut old = context.pagecache.get_meta(guard).get_root(&key);
+    while old.is_some() {
+        match context.pagecache.cas_root_in_meta(&key, old, None, guard)? {
+            Ok(()) => break,
+            Err(actual) => old = actual,
+        }
+    }
+    Ok(())
+}
+
+fn created(tree: &Tree, guard: &Guard) -> Option<SystemTime> {
+    let key = created_meta_key(&tree.tree_id);
+    let millis = tree.context.pagecache.get_meta(guard).get_root(&key)?;
+    Some(UNIX_EPOCH + Duration::from_millis(millis))
+}
+
+/// Gathers the statistics of a tree.
+pub(crate) fn tree_stats(
+    name: &IVec,
+    tree: &Tree,
+    guard: &Guard,
+) -> Result<TreeStats> {
+    let pagecache = &tree.context.pagecache;
+    let (live_bytes, estimated_len) = tree.size_of_range::<&[u8], _>(..)?;
+    let mut ret = TreeStats {
+        tree: name.clone(),
+        len: tree.known_len().unwrap_or_else(|| {
+            usize::try_from(estimated_len).unwrap_or(usize::max_value())
+        }),
+        live_bytes,
+        dead_bytes: 0,
+        nodes: 0,
+        cached_nodes: 0,
+        created: created(tree, guard),
+    };
+
+    let mut stored_bytes = 0;
+    let mut visited = FastSet8::default();
+    let mut level = vec![tree.root.load(Acquire)];
+    while !level.is_empty() {
+        // every node of a level is a leaf if one of them is
+        let mut is_leaf_level = None;
+        let mut next_level = vec![];
+        let mut i = 0;
+        while let Some(&pid) = level.get(i) {
+            i += 1;
+            if !visited.insert(pid) {
+                continue;
+            }
+            let cached = if let Some(cached) = pagecache.get_cached(pid, guard)
+            {
+                cached
+            } else {
+                continue;
+            };
+
+            let (log_bytes, heap_bytes) = pagecache.page_space(pid, guard);
+            stored_bytes += log_bytes + heap_bytes;
+            ret.nodes += 1;
+
+            let read = if cached.is_some() {
+                ret.cached_nodes += 1;
+                cached
+            } else if is_leaf_level == Some(true) {
+                // leaves that aren't cached are left unread, along
+                // with any sibling that hasn't reached its parent
+                continue;
+            } else {
+                pagecache.get(pid, guard)?
+            };
+            let node_view = if let Some(node_view) = read {
+                node_view
+            } else {
+                continue;
+            };
+
+            let _ = is_leaf_level.get_or_insert(!node_view.is_index);
+            if node_view.is_index {
+                next_level.extend(node_view.iter_index_pids());
+            }
+            if let Some(next) = node_view.next {
+                level.push(next.get());
+            }
+        }
+        level = next_level;
+    }
+
+    ret.dead_bytes = stored_bytes.saturating_sub(live_bytes);
+    Ok(ret)
+}
@@ -0,0 +1,719 @@
+//! An order-preserving binary encoding for serde types.
+//!
+//! Values are encoded such that comparing the encoded bytes
+//! lexicographically gives the same result as comparing the
+//! values by their fields in declaration order:
+//!
+//! * integers are big-endian, with the sign bit of signed
+//!   integers flipped so that negative numbers sort first.
+//! * floats have their sign bit flipped if they are positive,
+//!   and all of their bits flipped if they are negative.
+//! * strings and byte strings are escaped and terminated like
+//!   the keys of a tree with a `KeyOrder` other than
+//!   `Lexicographic`, so that they sort before any longer
+//!   string that they are a prefix of.
+//! * options, and each element of sequences and maps, are
+//!   preceded by a 1, and sequences and maps end with a 0.
+//! * enum variants are preceded by their index as a `u32`.
+//! * structs and tuples are their fields in order.
+//!
+//! The encoding is not self-describing, so types that need
+//! `deserialize_any`, such as those using `#[serde(flatten)]`
+//! or untagged enums, are not supported.
+use std::{convert::TryInto, fmt::Display};
+
+use serde::{
+    de::{self, DeserializeSeed, IntoDeserializer, Visitor},
+    ser, Deserialize, Serialize,
+};
+
+use crate::{
+    key_order::{escape, unescape, TERMINATOR},
+    *,
+};
+
+const NONE: u8 = 0;
+const SOME: u8 = 1;
+const END: u8 = 0;
+const ELEMENT: u8 = 1;
+
+pub(crate) fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    let mut serializer = Serializer { out: vec![] };
+    value.serialize(&mut serializer)?;
+    Ok(serializer.out)
+}
+
+pub(crate) fn from_slice<'de, T>(buf: &'de [u8]) -> Result<T>
+where
+    T: Deserialize<'de>,
+{
+    let mut deserializer = Deserializer { buf };
+    let value = T::deserialize(&mut deserializer)?;
+    if deserializer.buf.is_empty() {
+        Ok(value)
+    } else {
+        Err(Error::corruption(None))
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Error {
+        Error::Unsupported(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Error {
+        Error::Unsupported(msg.to_string())
+    }
+}
+
+fn not_self_describing<T>() -> Result<T> {
+    Err(Error::Unsupported(
+        "typed trees can only store types that can be \
+         deserialized without deserialize_any"
+            .into(),
+    ))
+}
+
+struct Serializer {
+    out: Vec<u8>,
+}
+
+impl Serializer {
+    fn write_escaped(&mut self, buf: &[u8]) {
+        self.out.extend_from_slice(&escape(buf));
+        self.out.extend_from_slice(&TERMINATOR);
+    }
+}
+
+macro_rules! serialize_unsigned {
+    ($method:ident, $ty:ty) => {
+        fn $method(self, v: $ty) -> Result<()> {
+            self.out.extend_from_slice(&v.to_be_bytes());
+            Ok(())
+        }
+    };
+}
+
+macro_rules! serialize_signed {
+    ($method:ident, $ty:ty) => {
+        fn $method(self, v: $ty) -> Result<()> {
+            let flipped = v ^ <$ty>::min_value();
+            self.out.extend_from_slice(&flipped.to_be_bytes());
+            Ok(())
+        }
+    };
+}
+
+impl ser::Serializer for &mut Serializer {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    serialize_unsigned!(serialize_u8, u8);
+    serialize_unsigned!(serialize_u16, u16);
+    serialize_unsigned!(serialize_u32, u32);
+    serialize_unsigned!(serialize_u64, u64);
+    serialize_unsigned!(serialize_u128, u128);
+    serialize_signed!(serialize_i8, i8);
+    serialize_signed!(serialize_i16, i16);
+    serialize_signed!(serialize_i32, i32);
+    serialize_signed!(serialize_i64, i64);
+    serialize_signed!(serialize_i128, i128);
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.serialize_u8(u8::from(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        let bits = v.to_bits();
+        let sign = 1 << 31;
+        let flipped = if bits & sign == 0 { bits ^ sign } else { !bits };
+        self.serialize_u32(flipped)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        let bits = v.to_bits();
+        let sign = 1 << 63;
+        let flipped = if bits & sign == 0 { bits ^ sign } else { !bits };
+        self.serialize_u64(flipped)
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.serialize_u32(u32::from(v))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.write_escaped(v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        self.write_escaped(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.out.push(NONE);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        self.out.push(SOME);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<()> {
+        self.serialize_u32(variant_index)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.serialize_u32(variant_index)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+}
+
+impl ser::SerializeSeq for &mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<()> {
+        self.out.push(ELEMENT);
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        self.out.push(END);
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.out.push(ELEMENT);
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        self.out.push(END);
+        Ok(())
+    }
+}
+
+macro_rules! serialize_fields {
+    ($trait:ident, $method:ident $(, $key:ident)?) => {
+        impl ser::$trait for &mut Serializer {
+            type Ok = ();
+            type Error = Error;
+
+            fn $method<T: Serialize + ?Sized>(
+                &mut self,
+                $($key: &'static str,)?
+                value: &T,
+            ) -> Result<()> {
+                value.serialize(&mut **self)
+            }
+
+            fn end(self) -> Result<()> {
+                Ok(())
+            }
+        }
+    };
+}
+
+serialize_fields!(SerializeTuple, serialize_element);
+serialize_fields!(SerializeTupleStruct, serialize_field);
+serialize_fields!(SerializeTupleVariant, serialize_field);
+serialize_fields!(SerializeStruct, serialize_field, _key);
+serialize_fields!(SerializeStructVariant, serialize_field, _key);
+
+struct Deserializer<'de> {
+    buf: &'de [u8],
+}
+
+impl<'de> Deserializer<'de> {
+    fn take(&mut self, len: usize) -> Result<&'de [u8]> {
+        if self.buf.len() < len {
+            return Err(Error::corruption(None));
+        }
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    fn take_escaped(&mut self) -> Result<Vec<u8>> {
+        let (unescaped, len) = unescape(self.buf);
+        let taken = self.take(len)?;
+        if !taken.ends_with(&TERMINATOR) {
+            return Err(Error::corruption(None));
+        }
+        Ok(unescaped)
+    }
+
+    fn take_u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn take_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn take_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn take_string(&mut self) -> Result<String> {
+        String::from_utf8(self.take_escaped()?)
+            .map_err(|_| Error::corruption(None))
+    }
+
+    // reads the marker that precedes each element of a
+    // sequence or map, returning false at its end.
+    fn has_element(&mut self) -> Result<bool> {
+        match self.take_u8()? {
+            END => Ok(false),
+            ELEMENT => Ok(true),
+            _ => Err(Error::corruption(None)),
+        }
+    }
+}
+
+macro_rules! deserialize_unsigned {
+    ($method:ident, $visit:ident, $ty:ty) => {
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+            const LEN: usize = std::mem::size_of::<$ty>();
+            let bytes = self.take(LEN)?.try_into().unwrap();
+            visitor.$visit(<$ty>::from_be_bytes(bytes))
+        }
+    };
+}
+
+macro_rules! deserialize_signed {
+    ($method:ident, $visit:ident, $ty:ty) => {
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+            const LEN: usize = std::mem::size_of::<$ty>();
+            let bytes = self.take(LEN)?.try_into().unwrap();
+            let flipped = <$ty>::from_be_bytes(bytes);
+            visitor.$visit(flipped ^ <$ty>::min_value())
+        }
+    };
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    deserialize_unsigned!(deserialize_u8, visit_u8, u8);
+    deserialize_unsigned!(deserialize_u16, visit_u16, u16);
+    deserialize_unsigned!(deserialize_u32, visit_u32, u32);
+    deserialize_unsigned!(deserialize_u64, visit_u64, u64);
+    deserialize_unsigned!(deserialize_u128, visit_u128, u128);
+    deserialize_signed!(deserialize_i8, visit_i8, i8);
+    deserialize_signed!(deserialize_i16, visit_i16, i16);
+    deserialize_signed!(deserialize_i32, visit_i32, i32);
+    deserialize_signed!(deserialize_i64, visit_i64, i64);
+    deserialize_signed!(deserialize_i128, visit_i128, i128);
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
+        not_self_describing()
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(
+        self,
+        _visitor: V,
+    ) -> Result<V::Value> {
+        not_self_describing()
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.take_u8()? {
+            0 => visitor.visit_bool(false),
+            1 => visitor.visit_bool(true),
+            _ => Err(Error::corruption(None)),
+        }
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let sign = 1 << 31;
+        let bits = self.take_u32()?;
+        let unflipped = if bits & sign == 0 { !bits } else { bits ^ sign };
+        visitor.visit_f32(f32::from_bits(unflipped))
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let sign = 1 << 63;
+        let bits = self.take_u64()?;
+        let unflipped = if bits & sign == 0 { !bits } else { bits ^ sign };
+        visitor.visit_f64(f64::from_bits(unflipped))
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let c = std::char::from_u32(self.take_u32()?)
+            .ok_or_else(|| Error::corruption(None))?;
+        visitor.visit_char(c)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_string(self.take_string()?)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_string(self.take_string()?)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_byte_buf(self.take_escaped()?)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_byte_buf(self.take_escaped()?)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.take_u8()? {
+            NONE => visitor.visit_none(),
+            SOME => visitor.visit_some(self),
+            _ => Err(Error::corruption(None)),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(Elements { de: self })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_seq(Fields { de: self, remaining: len })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_seq(Fields { de: self, remaining: len })
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_map(Elements { de: self })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_seq(Fields { de: self, remaining: fields.len() })
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_u32(visitor)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+// the elements of sequences and maps, which are
+// each preceded by a marker.
+struct Elements<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'de> de::SeqAccess<'de> for Elements<'_, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>> {
+        if self.de.has_element()? {
+            seed.deserialize(&mut *self.de).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Elements<'_, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>> {
+        if self.de.has_element()? {
+            seed.deserialize(&mut *self.de).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value> {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+// the fields of tuples and structs, the number of
+// which is known from their type.
+struct Fields<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    remaining: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for Fields<'_, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self)> {
+        let variant_index = self.take_u32()?;
+        let variant: de::value::U32Deserializer<Error> =
+            variant_index.into_deserializer();
+        Ok((seed.deserialize(variant)?, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_seq(Fields { de: self, remaining: len })
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_seq(Fields { de: self, remaining: fields.len() })
+    }
+}
+
+#[test]
+fn encodings_sort_like_values() {
+    #[derive(Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
+    enum Kind {
+        Small(i8),
+        Named { name: String, tags: Vec<Option<u16>> },
+    }
+
+    fn check<T>(mut values: Vec<T>)
+    where
+        T: std::fmt::Debug + PartialOrd + Serialize + for<'de> Deserialize<'de>,
+    {
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let mut encoded: Vec<Vec<u8>> =
+            values.iter().map(|value| to_vec(value).unwrap()).collect();
+        encoded.sort();
+        let decoded: Vec<T> =
+            encoded.iter().map(|buf| from_slice(buf).unwrap()).collect();
+        assert_eq!(decoded, values);
+    }
+
+    check(vec![i64::min_value(), -300, -1, 0, 1, 255, i64::max_value()]);
+    check(vec![std::f64::NEG_INFINITY, -2.5, -0.0, 0.5, 1e300]);
+    check(
+        vec!["", "\0", "\0\0", "a", "a\0b", "ab", "b"]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+    );
+    check(vec![(1_u8, "b".to_string()), (1, "ba".into()), (2, "a".into())]);
+    check(vec![
+        Kind::Small(-1),
+        Kind::Small(7),
+        Kind::Named { name: "a".into(), tags: vec![] },
+        Kind::Named { name: "a".into(), tags: vec![None, Some(1)] },
+        Kind::Named { name: "a".into(), tags: vec![Some(0)] },
+        Kind::Named { name: "b".into(), tags: vec![] },
+    ]);
+
+    assert!(from_slice::<u32>(&[0, 0, 1]).is_err());
+    assert!(from_slice::<u8>(&[0, 0]).is_err());
+}
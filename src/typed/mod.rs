@@ -0,0 +1,216 @@
+//! `Tree`s of serde types, see `TypedTree`.
+use std::{
+    fmt::{self, Debug},
+    marker::PhantomData,
+    ops::{self, RangeBounds},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::*;
+
+mod codec;
+
+/// A `Tree` that stores keys and values of types that implement
+/// serde's `Serialize` and `Deserialize`. Requires the `serde`
+/// feature.
+///
+/// Keys and values are encoded such that the keys of the `Tree`
+/// are sorted in the same order as the values that they were
+/// encoded from, comparing their fields in the order that they
+/// are declared in, so integers, signed or not, and tuples sort
+/// as expected. The encoding is not self-describing, so types
+/// that serde can only deserialize with `deserialize_any`, such
+/// as those using `#[serde(flatten)]`, are not supported. The
+/// names of fields and variants are not encoded, but the types
+/// and order of fields, and the order of variants must not
+/// change once they have been written.
+///
+/// # Examples
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use serde::{Deserialize, Serialize};
+/// use sled::TypedTree;
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct UserId(i64);
+///
+/// #[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// struct User {
+///     name: String,
+///     admin: bool,
+/// }
+///
+/// let db = sled::Config::new().temporary(true).open()?;
+/// let users: TypedTree<UserId, User> =
+///     TypedTree::new(db.open_tree("users")?);
+///
+/// users.insert(&UserId(42), &User { name: "ada".into(), admin: true })?;
+/// users.insert(&UserId(-7), &User { name: "bob".into(), admin: false })?;
+///
+/// assert_eq!(users.get(&UserId(42))?.unwrap().name, "ada");
+///
+/// // negative ids sort first
+/// let (UserId(first), _) = users.first()?.unwrap();
+/// assert_eq!(first, -7);
+/// # Ok(()) }
+/// ```
+pub struct TypedTree<K, V> {
+    tree: Tree,
+    marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> Clone for TypedTree<K, V> {
+    fn clone(&self) -> TypedTree<K, V> {
+        TypedTree { tree: self.tree.clone(), marker: PhantomData }
+    }
+}
+
+impl<K, V> Debug for TypedTree<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypedTree").field("tree", &self.tree).finish()
+    }
+}
+
+impl<K, V> TypedTree<K, V>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    /// Wraps a `Tree`, which should only contain keys and values
+    /// that were written by a `TypedTree` of the same types.
+    pub fn new(tree: Tree) -> TypedTree<K, V> {
+        TypedTree { tree, marker: PhantomData }
+    }
+
+    /// Returns the underlying `Tree`.
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    /// Insert a key to a new value, returning the last value if it
+    /// was set.
+    pub fn insert(&self, key: &K, value: &V) -> Result<Option<V>> {
+        let encoded_value = codec::to_vec(value)?;
+        let last = self.tree.insert(codec::to_vec(key)?, encoded_value)?;
+        last.map(|buf| codec::from_slice(&buf)).transpose()
+    }
+
+    /// Retrieve a value from the `TypedTree` if it exists.
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        let value = self.tree.get(codec::to_vec(key)?)?;
+        value.map(|buf| codec::from_slice(&buf)).transpose()
+    }
+
+    /// Returns `true` if the `TypedTree` contains a value for
+    /// the specified key.
+    pub fn contains_key(&self, key: &K) -> Result<bool> {
+        self.tree.contains_key(codec::to_vec(key)?)
+    }
+
+    /// Delete a value, returning the old value if it existed.
+    pub fn remove(&self, key: &K) -> Result<Option<V>> {
+        let last = self.tree.remove(codec::to_vec(key)?)?;
+        last.map(|buf| codec::from_slice(&buf)).transpose()
+    }
+
+    /// Create a double-ended iterator over the keys and values
+    /// of the `TypedTree`, in the order of the keys.
+    pub fn iter(&self) -> TypedIter<K, V> {
+        TypedIter { iter: self.tree.iter(), marker: PhantomData }
+    }
+
+    /// Create a double-ended iterator over the keys and values,
+    /// where the keys fall within the specified range.
+    pub fn range<R>(&self, range: R) -> Result<TypedIter<K, V>>
+    where
+        R: RangeBounds<K>,
+    {
+        let encode = |bound: ops::Bound<&K>| {
+            Ok::<_, Error>(match bound {
+                ops::Bound::Included(key) => {
+                    ops::Bound::Included(codec::to_vec(key)?)
+                }
+                ops::Bound::Excluded(key) => {
+                    ops::Bound::Excluded(codec::to_vec(key)?)
+                }
+                ops::Bound::Unbounded => ops::Bound::Unbounded,
+            })
+        };
+
+        let lo = encode(range.start_bound())?;
+        let hi = encode(range.end_bound())?;
+
+        let iter = self.tree.range::<Vec<u8>, _>((lo, hi));
+        Ok(TypedIter { iter, marker: PhantomData })
+    }
+
+    /// Returns the first key and value in the `TypedTree`, or
+    /// `None` if it is empty.
+    pub fn first(&self) -> Result<Option<(K, V)>> {
+        self.iter().next().transpose()
+    }
+
+    /// Returns the last key and value in the `TypedTree`, or
+    /// `None` if it is empty.
+    pub fn last(&self) -> Result<Option<(K, V)>> {
+        self.iter().next_back().transpose()
+    }
+
+    /// Returns the number of elements in this `TypedTree`, see
+    /// `Tree::len`.
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Returns `true` if the `TypedTree` contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Clears the `TypedTree`, removing all values.
+    ///
+    /// Note that this is not atomic.
+    pub fn clear(&self) -> Result<()> {
+        self.tree.clear()
+    }
+}
+
+/// An iterator over the keys and values of a `TypedTree`.
+pub struct TypedIter<K, V> {
+    iter: Iter,
+    marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> TypedIter<K, V>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+{
+    fn decode((k, v): (IVec, IVec)) -> Result<(K, V)> {
+        Ok((codec::from_slice(&k)?, codec::from_slice(&v)?))
+    }
+}
+
+impl<K, V> Iterator for TypedIter<K, V>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|res| res.and_then(Self::decode))
+    }
+}
+
+impl<K, V> DoubleEndedIterator for TypedIter<K, V>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back().map(|res| res.and_then(Self::decode))
+    }
+}
@@ -0,0 +1,105 @@
+//! Values that are read without being copied, see `Tree::get_ref`.
+//!
+//! A node that is replaced in the page cache is only freed once
+//! every thread that was pinned to the epoch when it was replaced
+//! has unpinned, so a value that is stored in a node stays valid
+//! for as long as the `Guard` that was pinned to read the node is
+//! held. A `ValueGuard` keeps that `Guard` along with a pointer to
+//! the value, which is either in the flat buffer of the node or in
+//! an `IVec` of its overlay. A value that is stored in the value
+//! log is read into an `IVec` instead, since only its pointer is
+//! in the node.
+#![allow(unsafe_code)]
+
+use std::ops::Deref;
+
+use crate::{tree::View, *};
+
+enum Value {
+    // borrowed from a node that the guard keeps from being freed
+    Pinned(*const u8, usize),
+    // read from the value log
+    Owned(IVec),
+}
+
+/// A value of a `Tree` that is borrowed from the page cache
+/// instead of being copied, returned by `Tree::get_ref`.
+///
+/// The guard keeps the thread pinned to the epoch that it was read
+/// in, which keeps every node that is replaced meanwhile, by any
+/// thread, from being freed until it's dropped, so it should be
+/// dropped as soon as the value has been used. It can't be sent to
+/// another thread.
+pub struct ValueGuard {
+    value: Value,
+    _guard: Guard,
+}
+
+impl Deref for ValueGuard {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.value {
+            Value::Pinned(ptr, len) => unsafe {
+                std::slice::from_raw_parts(*ptr, *len)
+            },
+            Value::Owned(value) => value,
+        }
+    }
+}
+
+impl AsRef<[u8]> for ValueGuard {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl PartialEq<[u8]> for ValueGuard {
+    fn eq(&self, other: &[u8]) -> bool {
+        **self == *other
+    }
+}
+
+impl Debug for ValueGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ValueGuard").field(&&**self).finish()
+    }
+}
+
+pub(crate) fn get(
+    tree: &Tree,
+    key: &[u8],
+    guard: Guard,
+) -> Result<Option<ValueGuard>> {
+    #[cfg(feature = "metrics")]
+    let _measure = Measure::new(&M.tree_get);
+
+    trace!("getting key {:?} without copying its value", key);
+
+    if bloom::excludes(tree, key, &guard)? {
+        return Ok(None);
+    }
+
+    let value = {
+        let View { node_view, pid, .. } = tree.view_for_key(key, &guard)?;
+        bloom::build(tree, pid, &guard)?;
+
+        let stored = if let (_, Some(stored)) = node_view.node_kv_pair(key) {
+            stored
+        } else {
+            return Ok(None);
+        };
+
+        if let Some(inline) = value_log::inline(tree, stored)? {
+            Value::Pinned(inline.as_ptr(), inline.len())
+        } else {
+            Value::Owned(value_log::load(tree, stored)?)
+        }
+    };
+
+    if expiration::is_expired(tree, key, &guard)? {
+        return Ok(None);
+    }
+
+    Ok(Some(ValueGuard { value, _guard: guard }))
+}
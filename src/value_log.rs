@@ -0,0 +1,918 @@
+//! Key-value separation for large values, see
+//! `Config::value_log_threshold`.
+//!
+//! When it is enabled, every value that a `Tree` stores starts
+//! with a tag. Values that are smaller than the threshold are
+//! stored in the tree after the tag, and larger values are
+//! appended to the files in the `values` directory, with only a
+//! pointer to them stored in the tree, so that they are not
+//! rewritten every time their node is. The hidden trees that
+//! hold expirations and indexes never separate their values.
+//!
+//! Value log files are append-only and are numbered in the
+//! order that they are written. Every value is durably written
+//! before its pointer is written to the tree, so no pointer is
+//! recovered without its value. Each entry records the name of
+//! the tree and the key that it was written for, so that
+//! `Db::collect_value_log_garbage` can tell whether the tree
+//! still points to it. The entries that are still referenced
+//! by a file that is mostly garbage are appended again and
+//! their pointers updated, and once that is durable the file is
+//! removed after every thread that may be reading it has moved
+//! on. Iterators that cached a node pointing to a removed file
+//! read it again.
+//!
+//! Values that are inserted with `Tree::insert_reader` are
+//! written to a file of their own in the `streams` directory,
+//! which is moved into the `values` directory once the value is
+//! durable, so that they are never seen half-written. Such a
+//! file is only collected after its pointer was written to the
+//! tree.
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    encryption::Encryption,
+    pagecache::{pread_exact, pwrite_all},
+    tree::View,
+    *,
+};
+
+const INLINE: u8 = 0;
+const BLOB: u8 = 1;
+
+// the size after which a new value log file is started
+#[cfg(not(feature = "testing"))]
+const FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+#[cfg(feature = "testing")]
+const FILE_SIZE: u64 = 64 * 1024;
+
+// the crc and the lengths of the tree name, the key and the
+// value of an entry
+const ENTRY_HEADER_LEN: usize = 4 + 3 * 8;
+
+// the size of the chunks in which values are streamed
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// The value log files of a database.
+pub(crate) struct ValueLog {
+    dir: PathBuf,
+    streams_dir: PathBuf,
+    read_only: bool,
+    encryption: Option<Encryption>,
+    active: Mutex<Active>,
+    files: RwLock<BTreeMap<u64, Arc<File>>>,
+}
+
+// the file that values are appended to, which is created when
+// the first value is written to it, and the id of the next file
+struct Active {
+    id: u64,
+    file: Option<Arc<File>>,
+    len: u64,
+    next_id: u64,
+}
+
+impl Debug for ValueLog {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> std::result::Result<(), fmt::Error> {
+        f.debug_struct("ValueLog")
+            .field("dir", &self.dir)
+            .field("files", &self.files.read().len())
+            .finish()
+    }
+}
+
+/// The location of an entry in the value log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Pointer {
+    file: u64,
+    offset: u64,
+    len: u64,
+}
+
+impl Pointer {
+    fn encode(self) -> IVec {
+        let mut ret = Vec::with_capacity(1 + 3 * 8);
+        ret.push(BLOB);
+        ret.extend_from_slice(&self.file.to_be_bytes());
+        ret.extend_from_slice(&self.offset.to_be_bytes());
+        ret.extend_from_slice(&self.len.to_be_bytes());
+        ret.into()
+    }
+
+    fn decode(buf: &[u8]) -> Result<Pointer> {
+        if buf.len() != 3 * 8 {
+            return Err(Error::corruption(None));
+        }
+        let field = |i: usize| {
+            u64::from_be_bytes(buf[i * 8..(i + 1) * 8].try_into().unwrap())
+        };
+        Ok(Pointer { file: field(0), offset: field(1), len: field(2) })
+    }
+}
+
+/// An entry of the value log.
+struct Entry {
+    tree_id: IVec,
+    key: IVec,
+    value: IVec,
+}
+
+impl ValueLog {
+    pub(crate) fn start(
+        path: &Path,
+        read_only: bool,
+        encryption: Option<Encryption>,
+    ) -> Result<ValueLog> {
+        let dir = path.join("values");
+        let streams_dir = path.join("streams");
+        let mut files = BTreeMap::new();
+
+        // values that were being streamed when the last process
+        // stopped were never pointed to
+        if !read_only {
+            match fs::remove_dir_all(&streams_dir) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => Some(entries),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        for entry in entries.into_iter().flatten() {
+            let file_path = entry?.path();
+            let id: u64 = file_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse().ok())
+                .ok_or_else(|| Error::corruption(None))?;
+            let file = OpenOptions::new()
+                .read(true)
+                .write(!read_only)
+                .open(&file_path)?;
+            files.insert(id, Arc::new(file));
+        }
+
+        // values are never appended to the files of an earlier
+        // process, which may end with an entry that was torn
+        // by a crash.
+        let next_id = files.keys().next_back().map_or(0, |id| id + 1);
+
+        Ok(ValueLog {
+            dir,
+            streams_dir,
+            read_only,
+            encryption,
+            active: Mutex::new(Active {
+                id: next_id,
+                file: None,
+                len: 0,
+                next_id,
+            }),
+            files: RwLock::new(files),
+        })
+    }
+
+    /// Returns the total size of the value log files.
+    pub(crate) fn size_on_disk(&self) -> Result<u64> {
+        let mut size = 0;
+        for file in self.files.read().values() {
+            size += file.metadata()?.len();
+        }
+        Ok(size)
+    }
+
+    /// Durably appends an entry, returning a pointer to it.
+    fn append(
+        &self,
+        io_barrier: &RwLock<()>,
+        tree_id: &[u8],
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Pointer> {
+        if self.read_only {
+            return Err(Error::Unsupported(
+                "the database was opened in read-only mode".into(),
+            ));
+        }
+
+        let sealed = if let Some(encryption) = &self.encryption {
+            encryption.encrypt(value)?
+        } else {
+            value.to_vec()
+        };
+
+        let parts = [tree_id, key, &sealed];
+        let mut entry = vec![0; 4];
+        for part in &parts {
+            let len = u64::try_from(part.len()).unwrap();
+            entry.extend_from_slice(&len.to_be_bytes());
+        }
+        for part in &parts {
+            entry.extend_from_slice(part);
+        }
+        let crc = crc32(&entry[4..]);
+        entry[..4].copy_from_slice(&crc.to_be_bytes());
+
+        let mut active = self.active.lock();
+        if active.file.is_none() || active.len >= FILE_SIZE {
+            self.rotate(&mut active)?;
+        }
+
+        let pointer = Pointer {
+            file: active.id,
+            offset: active.len,
+            len: u64::try_from(entry.len()).unwrap(),
+        };
+
+        let file = active.file.as_ref().unwrap();
+        let _barrier = io_barrier.read();
+        pwrite_all(file, &entry, pointer.offset)?;
+        file.sync_data()?;
+
+        active.len += pointer.len;
+
+        Ok(pointer)
+    }
+
+    fn rotate(&self, active: &mut Active) -> Result<()> {
+        active.id = active.next_id;
+        active.next_id += 1;
+
+        let file = Arc::new(create(&self.dir, active.id)?);
+        maybe_fsync_directory(&self.dir)?;
+
+        self.files.write().insert(active.id, file.clone());
+        active.file = Some(file);
+        active.len = 0;
+
+        Ok(())
+    }
+
+    /// Durably writes an entry whose value is `first` followed
+    /// by the rest of `reader` to a new file, returning a pointer
+    /// to it and the length of the value. The file is only added
+    /// to the files that may be collected when the returned
+    /// `Stream` is dropped.
+    fn append_reader(
+        &self,
+        io_barrier: &RwLock<()>,
+        tree_id: &[u8],
+        key: &[u8],
+        first: &[u8],
+        reader: &mut dyn Read,
+    ) -> Result<Stream<'_>> {
+        if self.read_only {
+            return Err(Error::Unsupported(
+                "the database was opened in read-only mode".into(),
+            ));
+        }
+
+        let id = {
+            let mut active = self.active.lock();
+            active.next_id += 1;
+            active.next_id - 1
+        };
+
+        let file = create(&self.streams_dir, id)?;
+
+        // the crc covers the lengths, which are only known once
+        // the value has been read, so the crc of the rest of the
+        // entry is combined with theirs at the end
+        let mut hasher = crc32fast::Hasher::new();
+        let mut offset = u64::try_from(ENTRY_HEADER_LEN).unwrap();
+        for part in &[tree_id, key, first] {
+            hasher.update(part);
+            pwrite_all(&file, part, offset)?;
+            offset += u64::try_from(part.len()).unwrap();
+        }
+
+        let mut value_len = u64::try_from(first.len()).unwrap();
+        let mut chunk = vec![0; CHUNK_LEN];
+        loop {
+            let read = match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            };
+            hasher.update(&chunk[..read]);
+            pwrite_all(&file, &chunk[..read], offset)?;
+            offset += u64::try_from(read).unwrap();
+            value_len += u64::try_from(read).unwrap();
+        }
+
+        let mut header = [0; ENTRY_HEADER_LEN];
+        let lens = [
+            u64::try_from(tree_id.len()).unwrap(),
+            u64::try_from(key.len()).unwrap(),
+            value_len,
+        ];
+        for (i, len) in lens.iter().enumerate() {
+            header[4 + i * 8..][..8].copy_from_slice(&len.to_be_bytes());
+        }
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&header[4..]);
+        crc.combine(&hasher);
+        header[..4].copy_from_slice(&crc.finalize().to_be_bytes());
+        pwrite_all(&file, &header, 0)?;
+        file.sync_data()?;
+
+        let _barrier = io_barrier.read();
+        fs::create_dir_all(&self.dir)?;
+        fs::rename(
+            self.streams_dir.join(id.to_string()),
+            self.dir.join(id.to_string()),
+        )?;
+        maybe_fsync_directory(&self.dir)?;
+
+        let pointer = Pointer { file: id, offset: 0, len: offset };
+
+        Ok(Stream {
+            value_log: self,
+            file: Arc::new(file),
+            pointer,
+            len: value_len,
+        })
+    }
+
+    /// Returns a file, or `None` if it has been removed.
+    fn file(&self, id: u64) -> Result<Option<Arc<File>>> {
+        if let Some(file) = self.files.read().get(&id) {
+            return Ok(Some(file.clone()));
+        }
+
+        // read-only databases open the files that another
+        // process has written since they were opened, and the
+        // files that were just collected may not be removed yet.
+        match File::open(self.dir.join(id.to_string())) {
+            Ok(opened) => {
+                let file = Arc::new(opened);
+                if self.read_only {
+                    self.files.write().insert(id, file.clone());
+                }
+                Ok(Some(file))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Reads the entry that a pointer points to, or `None` if
+    /// its file has been removed.
+    fn read(&self, pointer: Pointer) -> Result<Option<Entry>> {
+        let file = if let Some(file) = self.file(pointer.file)? {
+            file
+        } else {
+            return Ok(None);
+        };
+
+        let len = usize::try_from(pointer.len)
+            .map_err(|_| Error::corruption(None))?;
+        let mut buf = vec![0; len];
+        pread_exact(&file, &mut buf, pointer.offset)?;
+
+        self.decode_entry(&buf).map(Some)
+    }
+
+    /// Returns a reader for the value of the entry that a
+    /// pointer points to, which checks the crc of the entry once
+    /// the whole value was read from it.
+    fn reader(&self, pointer: Pointer) -> Result<Reader> {
+        if self.encryption.is_some() {
+            // the value can only be authenticated as a whole
+            let entry =
+                self.read(pointer)?.ok_or_else(|| Error::corruption(None))?;
+            return Ok(Reader::Buffered(io::Cursor::new(entry.value)));
+        }
+
+        let file =
+            self.file(pointer.file)?.ok_or_else(|| Error::corruption(None))?;
+
+        let mut header = [0; ENTRY_HEADER_LEN];
+        pread_exact(&file, &mut header, pointer.offset)?;
+        let (lens, tree_id_len, key_len, value_len) = entry_lens(&header)?;
+        if pointer.len != u64::try_from(ENTRY_HEADER_LEN + lens).unwrap() {
+            return Err(Error::corruption(None));
+        }
+
+        let mut prefix = vec![0; tree_id_len + key_len];
+        let prefix_offset =
+            pointer.offset + u64::try_from(ENTRY_HEADER_LEN).unwrap();
+        pread_exact(&file, &mut prefix, prefix_offset)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[4..]);
+        hasher.update(&prefix);
+
+        Ok(Reader::Blob(BlobReader {
+            file,
+            offset: prefix_offset + u64::try_from(prefix.len()).unwrap(),
+            remaining: u64::try_from(value_len).unwrap(),
+            hasher,
+            crc: u32::from_be_bytes(header[..4].try_into().unwrap()),
+        }))
+    }
+
+    fn decode_entry(&self, buf: &[u8]) -> Result<Entry> {
+        let (lens, tree_id_len, key_len, value_len) = entry_lens(buf)?;
+        if buf.len() != ENTRY_HEADER_LEN + lens {
+            return Err(Error::corruption(None));
+        }
+
+        let crc = u32::from_be_bytes(buf[..4].try_into().unwrap());
+        if crc != crc32(&buf[4..]) {
+            return Err(Error::corruption(None));
+        }
+
+        let tree_id = &buf[ENTRY_HEADER_LEN..ENTRY_HEADER_LEN + tree_id_len];
+        let key = &buf[ENTRY_HEADER_LEN + tree_id_len..][..key_len];
+        let sealed = &buf[buf.len() - value_len..];
+
+        let value = if let Some(encryption) = &self.encryption {
+            encryption.decrypt(sealed)?.into()
+        } else {
+            sealed.into()
+        };
+
+        Ok(Entry { tree_id: tree_id.into(), key: key.into(), value })
+    }
+
+    /// Returns the pointers to the entries of a sealed file, or
+    /// `None` if any of them can't be read. Only the active file
+    /// may end with an entry that is still being written, so an
+    /// entry that was torn or corrupted means that the entries
+    /// after it can't be found.
+    fn scan(
+        &self,
+        id: u64,
+        file: &File,
+    ) -> Result<Option<Vec<(Pointer, Entry)>>> {
+        let len = file.metadata()?.len();
+        let mut ret = vec![];
+        let mut offset = 0;
+
+        let mut header = [0; ENTRY_HEADER_LEN];
+        while offset < len {
+            if offset + ENTRY_HEADER_LEN as u64 > len {
+                return Ok(None);
+            }
+            pread_exact(file, &mut header, offset)?;
+            let entry_len = if let Ok((lens, ..)) = entry_lens(&header) {
+                ENTRY_HEADER_LEN + lens
+            } else {
+                return Ok(None);
+            };
+            let pointer = Pointer {
+                file: id,
+                offset,
+                len: u64::try_from(entry_len).unwrap(),
+            };
+            if offset + pointer.len > len {
+                return Ok(None);
+            }
+            match self.read(pointer) {
+                Ok(Some(entry)) => ret.push((pointer, entry)),
+                Ok(None) | Err(Error::Corruption { .. }) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            offset += pointer.len;
+        }
+
+        Ok(Some(ret))
+    }
+}
+
+/// A value that was written by `append_reader`, whose file is
+/// added to the ones that may be collected when it is dropped,
+/// after its pointer was written to the tree.
+pub(crate) struct Stream<'a> {
+    value_log: &'a ValueLog,
+    file: Arc<File>,
+    pointer: Pointer,
+    pub(crate) len: u64,
+}
+
+impl Stream<'_> {
+    /// Returns the form in which the value is stored in a tree.
+    pub(crate) fn stored(&self) -> IVec {
+        self.pointer.encode()
+    }
+}
+
+impl Drop for Stream<'_> {
+    fn drop(&mut self) {
+        let _ = self
+            .value_log
+            .files
+            .write()
+            .insert(self.pointer.file, self.file.clone());
+    }
+}
+
+/// A value that is read with `Tree::get_reader`.
+pub(crate) enum Reader {
+    Buffered(io::Cursor<IVec>),
+    Blob(BlobReader),
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Reader::Buffered(cursor) => cursor.read(buf),
+            Reader::Blob(blob) => blob.read(buf),
+        }
+    }
+}
+
+/// Reads a value from its value log file.
+pub(crate) struct BlobReader {
+    file: Arc<File>,
+    offset: u64,
+    remaining: u64,
+    hasher: crc32fast::Hasher,
+    crc: u32,
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            if self.remaining == 0 && self.hasher.clone().finalize() != self.crc
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the value failed its crc check",
+                ));
+            }
+            return Ok(0);
+        }
+
+        let len = usize::try_from(self.remaining)
+            .unwrap_or(buf.len())
+            .min(buf.len());
+        pread_exact(&self.file, &mut buf[..len], self.offset)?;
+        self.hasher.update(&buf[..len]);
+        self.offset += u64::try_from(len).unwrap();
+        self.remaining -= u64::try_from(len).unwrap();
+
+        Ok(len)
+    }
+}
+
+fn create(dir: &Path, id: u64) -> Result<File> {
+    fs::create_dir_all(dir)?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(dir.join(id.to_string()))?;
+    Ok(file)
+}
+
+// returns the sum of the lengths of the tree name, the key and
+// the value of an entry, and each of them
+fn entry_lens(buf: &[u8]) -> Result<(usize, usize, usize, usize)> {
+    if buf.len() < ENTRY_HEADER_LEN {
+        return Err(Error::corruption(None));
+    }
+    let field = |i: usize| {
+        let raw = u64::from_be_bytes(buf[4 + i * 8..][..8].try_into().unwrap());
+        usize::try_from(raw).map_err(|_| Error::corruption(None))
+    };
+    let (tree_id_len, key_len, value_len) = (field(0)?, field(1)?, field(2)?);
+    let lens = tree_id_len
+        .checked_add(key_len)
+        .and_then(|lens| lens.checked_add(value_len))
+        .ok_or_else(|| Error::corruption(None))?;
+    Ok((lens, tree_id_len, key_len, value_len))
+}
+
+/// Returns `true` if the values of a tree are stored with
+/// a tag, and separated when they are large.
+pub(crate) fn separates_values(context: &Context, tree_id: &[u8]) -> bool {
+    context.value_log_threshold.is_some()
+        && !expiration::is_expiration_tree_name(tree_id)
+        && !index::is_index_tree_name(tree_id)
+        && !history::is_history_tree_name(tree_id)
+        && !versions::is_versions_tree_name(tree_id)
+        && !key_version::is_key_versions_tree_name(tree_id)
+}
+
+/// Returns the form in which a value is stored in a tree,
+/// appending it to the value log if it is large.
+pub(crate) fn store(tree: &Tree, key: &[u8], value: &IVec) -> Result<IVec> {
+    if !tree.separates_values {
+        return Ok(value.clone());
+    }
+
+    if value.len() < tree.context.value_log_threshold.unwrap() {
+        let mut ret = Vec::with_capacity(1 + value.len());
+        ret.push(INLINE);
+        ret.extend_from_slice(value);
+        return Ok(ret.into());
+    }
+
+    let pointer = tree.context.value_log.append(
+        &tree.context.io_barrier,
+        &tree.tree_id,
+        key,
+        value,
+    )?;
+
+    Ok(pointer.encode())
+}
+
+/// A value that is read for `Tree::insert_reader`.
+pub(crate) enum Streamed<'a> {
+    /// The value was read into memory, because it is small
+    /// or the tree doesn't separate it.
+    Buffered(IVec),
+    /// The value was written to the value log as it was read.
+    Appended(Stream<'a>),
+}
+
+/// Reads a value for `Tree::insert_reader`, writing it to the
+/// value log in chunks if it isn't smaller than the threshold
+/// and isn't encrypted.
+pub(crate) fn stream<'a>(
+    tree: &'a Tree,
+    key: &[u8],
+    reader: &mut dyn Read,
+) -> Result<Streamed<'a>> {
+    let mut buf = vec![];
+    let value_log = &tree.context.value_log;
+    let streams = tree.separates_values && value_log.encryption.is_none();
+    if !streams {
+        let _ = reader.read_to_end(&mut buf)?;
+        return Ok(Streamed::Buffered(buf.into()));
+    }
+
+    let threshold = tree.context.value_log_threshold.unwrap();
+    let _ = reader
+        .take(u64::try_from(threshold).unwrap())
+        .read_to_end(&mut buf)?;
+    if buf.len() < threshold {
+        return Ok(Streamed::Buffered(buf.into()));
+    }
+
+    let stream = value_log.append_reader(
+        &tree.context.io_barrier,
+        &tree.tree_id,
+        key,
+        &buf,
+        reader,
+    )?;
+
+    Ok(Streamed::Appended(stream))
+}
+
+/// Returns the value that is stored in a tree in the form
+/// returned by `store` without copying it, or `None` if only a
+/// pointer to it is stored in the tree.
+pub(crate) fn inline<'a>(
+    tree: &Tree,
+    stored: &'a [u8],
+) -> Result<Option<&'a [u8]>> {
+    if !tree.separates_values {
+        return Ok(Some(stored));
+    }
+
+    match stored.split_first() {
+        Some((&INLINE, value)) => Ok(Some(value)),
+        Some((&BLOB, _)) => Ok(None),
+        _ => Err(Error::corruption(None)),
+    }
+}
+
+/// Returns a reader for a value that is stored in a tree in
+/// the form returned by `store`.
+pub(crate) fn reader(tree: &Tree, stored: &[u8]) -> Result<Reader> {
+    if !tree.separates_values {
+        return Ok(Reader::Buffered(io::Cursor::new(stored.into())));
+    }
+
+    match stored.split_first() {
+        Some((&INLINE, value)) => {
+            Ok(Reader::Buffered(io::Cursor::new(value.into())))
+        }
+        Some((&BLOB, pointer)) => {
+            tree.context.value_log.reader(Pointer::decode(pointer)?)
+        }
+        _ => Err(Error::corruption(None)),
+    }
+}
+
+/// Returns the value that is stored in a tree in the form
+/// returned by `store`.
+pub(crate) fn load(tree: &Tree, stored: &[u8]) -> Result<IVec> {
+    read(tree, stored)?.ok_or_else(|| Error::corruption(None))
+}
+
+pub(crate) fn load_opt(
+    tree: &Tree,
+    stored: Option<&[u8]>,
+) -> Result<Option<IVec>> {
+    stored.map(|buf| load(tree, buf)).transpose()
+}
+
+/// Like `load`, but returns `None` if the value was moved to
+/// another value log file which the stored pointer predates,
+/// and the file that it points to has been removed.
+pub(crate) fn read(tree: &Tree, stored: &[u8]) -> Result<Option<IVec>> {
+    if !tree.separates_values {
+        return Ok(Some(stored.into()));
+    }
+
+    match stored.split_first() {
+        Some((&INLINE, value)) => Ok(Some(value.into())),
+        Some((&BLOB, pointer)) => {
+            let entry =
+                tree.context.value_log.read(Pointer::decode(pointer)?)?;
+            Ok(entry.map(|found| found.value))
+        }
+        _ => Err(Error::corruption(None)),
+    }
+}
+
+/// Returns about how many bytes a value that is stored in a tree
+/// in the form returned by `store` takes, counting the whole value
+/// log entry that it may point to.
+pub(crate) fn stored_len(tree: &Tree, stored: &[u8]) -> u64 {
+    let len = match stored.split_first() {
+        _ if !tree.separates_values => stored.len(),
+        Some((&INLINE, value)) => value.len(),
+        Some((&BLOB, pointer)) => match Pointer::decode(pointer) {
+            Ok(decoded) => return decoded.len,
+            Err(_) => stored.len(),
+        },
+        _ => stored.len(),
+    };
+    u64::try_from(len).unwrap()
+}
+
+/// Returns the length of the value log entry that a value that
+/// is stored in a tree points to, or 0 if it isn't separated.
+pub(crate) fn separated_len(tree: &Tree, stored: &[u8]) -> u64 {
+    match stored.split_first() {
+        Some((&BLOB, pointer)) if tree.separates_values => {
+            Pointer::decode(pointer).map(|decoded| decoded.len).unwrap_or(0)
+        }
+        _ => 0,
+    }
+}
+
+/// Moves the entries that are still pointed to out of the value
+/// log files that are at least half garbage, and removes those
+/// files, returning the number of bytes that were reclaimed,
+/// see `Db::collect_value_log_garbage`.
+pub(crate) fn collect_garbage(
+    context: &Context,
+    tenants: &FastMap8<IVec, Tree>,
+) -> Result<u64> {
+    let value_log = &context.value_log;
+    if value_log.read_only {
+        return Err(Error::Unsupported(
+            "the database was opened in read-only mode".into(),
+        ));
+    }
+
+    // values are stored and their pointers linked under the read
+    // lock, so once the writes that are in flight are done, no
+    // pointer to a file that is sealed by then is about to be
+    // linked, and the later writes only store values in the
+    // active file or in newer ones.
+    let sealed: Vec<(u64, Arc<File>)> = {
+        let _cc = concurrency_control::write();
+        let active = value_log.active.lock();
+        let active_id = active.file.as_ref().map(|_| active.id);
+        value_log
+            .files
+            .read()
+            .iter()
+            .filter(|(id, _)| Some(**id) != active_id)
+            .map(|(id, file)| (*id, file.clone()))
+            .collect()
+    };
+
+    let mut reclaimed = 0;
+    let mut collected = vec![];
+
+    for (id, file) in sealed {
+        // keeps transactions, which may have read the values that
+        // are moved, from committing while a file is collected.
+        // other writes go on, and the pointers that they replace
+        // meanwhile are not moved.
+        let _cc = concurrency_control::read();
+        let guard = pin();
+
+        let file_len = file.metadata()?.len();
+        let entries = if let Some(entries) = value_log.scan(id, &file)? {
+            entries
+        } else {
+            warn!("not collecting value log file {} that can't be read", id);
+            continue;
+        };
+
+        let mut live = vec![];
+        let mut live_len = 0;
+
+        for (pointer, entry) in entries {
+            let tree = if let Some(tree) = tenants.get(&entry.tree_id) {
+                tree
+            } else {
+                continue;
+            };
+            let view = tree.view_for_key(&entry.key, &guard)?;
+            let stored = view.node_kv_pair(&entry.key).1;
+            if stored == Some(&*pointer.encode()) {
+                live_len += pointer.len;
+                live.push((tree, pointer, entry));
+            }
+        }
+
+        if live_len * 2 > file_len {
+            continue;
+        }
+
+        for (tree, old, entry) in live {
+            let new = value_log.append(
+                &context.io_barrier,
+                &entry.tree_id,
+                &entry.key,
+                &entry.value,
+            )?;
+            let _ = relink(tree, &entry.key, old, new, &guard)?;
+        }
+
+        reclaimed += file_len - live_len;
+        collected.push(id);
+    }
+
+    if collected.is_empty() {
+        return Ok(0);
+    }
+
+    // the moved pointers must be durable before the entries
+    // that they replaced are removed.
+    let _ = context.pagecache.flush()?;
+
+    let guard = pin();
+    for id in collected {
+        let _ = value_log.files.write().remove(&id);
+        let path = value_log.dir.join(id.to_string());
+        guard.defer(move || match fs::remove_file(&path) {
+            // the directory of a temporary database may be
+            // removed first
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                error!("failed to remove value log file {:?}: {:?}", path, e)
+            }
+        });
+    }
+    guard.flush();
+
+    Ok(reclaimed)
+}
+
+// replaces the pointer to a value that was moved without touching
+// the indexes, subscribers or expiration of the key, which don't
+// change when its value is moved. returns false if the key was
+// written since it was moved, leaving the moved entry as garbage.
+fn relink(
+    tree: &Tree,
+    key: &[u8],
+    old: Pointer,
+    new: Pointer,
+    guard: &Guard,
+) -> Result<bool> {
+    let (old, new) = (old.encode(), new.encode());
+    loop {
+        let View { pid, node_view, .. } = tree.view_for_key(key, guard)?;
+        let (encoded_key, stored) = node_view.node_kv_pair(key);
+        if stored != Some(&*old) {
+            return Ok(false);
+        }
+        let frag = Link::Set(encoded_key, new.clone());
+        let link = tree.context.pagecache.link(pid, node_view.0, frag, guard)?;
+        if link.is_ok() {
+            return Ok(true);
+        }
+    }
+}
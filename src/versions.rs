@@ -0,0 +1,488 @@
+//! The values that the keys of a `Tree` held before they were
+//! overwritten or removed, which are retained for
+//! `Config::version_retention_ms` so that `Tree::get_at` and
+//! `Tree::range_at` can read the tree as of a point in the past.
+//!
+//! The versions of a `Tree` are stored in a hidden companion
+//! `Tree` that is attached to it when it is opened:
+//!
+//! * `[BY_KEY] ++ escaped key ++ big-endian sequence number` ->
+//!   the big-endian time in milliseconds at which the write with
+//!   the sequence number replaced the version, a byte that is
+//!   1 if the key was set, and the value that it was set to
+//! * `[BY_SEQ] ++ big-endian sequence number ++ key` -> the
+//!   big-endian time, used to trim versions in the order that
+//!   they were replaced
+//! * `[TRIMMED]` -> the big-endian sequence number and time of
+//!   the newest version that was trimmed, or that was replaced
+//!   before the versions were retained, before which the tree
+//!   can't be read
+//!
+//! The stored keys are escaped, writing each 0 byte as `[0, 1]`
+//! and ending with `[0, 0]`, so that the versions of a key sort
+//! together, in the order of the keys and then of their
+//! sequence numbers.
+//!
+//! A version is recorded by the write that replaces it, once the
+//! write is linked and while it still holds the concurrency
+//! control, so reads take a `Snapshot` to see a tree and its
+//! versions as of the same moment. The value of a key as of a
+//! point is the version that was replaced by the first write
+//! after it, or the current value if there was no such write.
+//! Versions that were replaced longer ago than the retention are
+//! trimmed by the writes that record new ones, and the space that
+//! they used is reclaimed by the usual segment cleaning.
+use std::{
+    convert::{TryFrom, TryInto},
+    ops::{Bound, RangeBounds},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{snapshot::Snapshot, snapshot::SnapshotIter, *};
+
+const VERSIONS_TREE_PREFIX: &[u8] = b"__sled__versions__";
+
+const BY_KEY: u8 = 0;
+const BY_SEQ: u8 = 1;
+const TRIMMED: u8 = 2;
+
+/// The most versions that a write trims.
+const TRIM_CHUNK: usize = 16;
+
+/// A point in the past that `Tree::get_at` and `Tree::range_at`
+/// read a `Tree` as of. A `u64` or a `SystemTime` can be passed
+/// in its place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionAt {
+    /// Right after the write with this sequence number, see
+    /// `Event::seq`, so that a reader on another machine can read
+    /// exactly what a subscriber was sent.
+    Seq(u64),
+    /// As of this time, as measured by the clock of the writers.
+    Time(SystemTime),
+}
+
+impl From<u64> for VersionAt {
+    fn from(seq: u64) -> VersionAt {
+        VersionAt::Seq(seq)
+    }
+}
+
+impl From<SystemTime> for VersionAt {
+    fn from(time: SystemTime) -> VersionAt {
+        VersionAt::Time(time)
+    }
+}
+
+impl VersionAt {
+    // returns `true` if a version that was replaced by the write
+    // with `seq` at `time` was still current at this point
+    fn precedes(self, seq: u64, time: u64) -> bool {
+        match self {
+            VersionAt::Seq(at) => seq > at,
+            VersionAt::Time(at) => time > millis(at),
+        }
+    }
+}
+
+fn millis(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    u64::try_from(since_epoch.as_millis()).unwrap_or(u64::max_value())
+}
+
+pub(crate) fn is_versions_tree_name(name: &[u8]) -> bool {
+    name.starts_with(VERSIONS_TREE_PREFIX)
+}
+
+pub(crate) fn parent_tree_name(name: &[u8]) -> Option<&[u8]> {
+    if is_versions_tree_name(name) {
+        Some(&name[VERSIONS_TREE_PREFIX.len()..])
+    } else {
+        None
+    }
+}
+
+pub(crate) fn versions_tree_name(tree_id: &[u8]) -> IVec {
+    let mut name = VERSIONS_TREE_PREFIX.to_vec();
+    name.extend_from_slice(tree_id);
+    name.into()
+}
+
+fn escaped_key(key: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(key.len() + 11);
+    ret.push(BY_KEY);
+    for byte in key {
+        ret.push(*byte);
+        if *byte == 0 {
+            ret.push(1);
+        }
+    }
+    ret
+}
+
+fn by_key(key: &[u8], seq: u64) -> Vec<u8> {
+    let mut ret = escaped_key(key);
+    ret.extend_from_slice(&[0, 0]);
+    ret.extend_from_slice(&seq.to_be_bytes());
+    ret
+}
+
+// returns the stored key and the sequence number of a version
+fn decode_by_key(raw: &[u8]) -> Result<(IVec, u64)> {
+    let mut key = Vec::with_capacity(raw.len());
+    let mut i = 1;
+    loop {
+        match (raw.get(i), raw.get(i + 1)) {
+            (Some(0), Some(0)) => break,
+            (Some(0), Some(1)) => {
+                key.push(0);
+                i += 2;
+            }
+            (Some(byte), _) if *byte != 0 => {
+                key.push(*byte);
+                i += 1;
+            }
+            _ => return Err(Error::corruption(None)),
+        }
+    }
+    Ok((key.into(), decode_u64(&raw[i + 2..])?))
+}
+
+fn by_seq(seq: u64, key: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(9 + key.len());
+    ret.push(BY_SEQ);
+    ret.extend_from_slice(&seq.to_be_bytes());
+    ret.extend_from_slice(key);
+    ret
+}
+
+fn decode_u64(raw: &[u8]) -> Result<u64> {
+    let bytes = raw.try_into().map_err(|_| Error::corruption(None))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+// returns the time at which a version was replaced, and the
+// version itself
+fn decode_version(raw: &[u8]) -> Result<(u64, Option<IVec>)> {
+    if raw.len() < 9 {
+        return Err(Error::corruption(None));
+    }
+    let time = decode_u64(&raw[..8])?;
+    let version = match raw[8] {
+        0 => None,
+        1 => Some(IVec::from(&raw[9..])),
+        _ => return Err(Error::corruption(None)),
+    };
+    Ok((time, version))
+}
+
+/// Attaches a companion `Tree` that was loaded during startup to
+/// its parent.
+pub(crate) fn attach(tree: &Tree, versions: Tree) {
+    *tree.versions.write() = Some(versions);
+}
+
+/// Creates the companion `Tree` of a tree that doesn't have one
+/// yet.
+pub(crate) fn open(tree: &Tree) -> Result<()> {
+    if tree.versions.read().is_some() || tree.context.read_only {
+        return Ok(());
+    }
+
+    let mut versions = tree.versions.write();
+    if versions.is_some() {
+        return Ok(());
+    }
+
+    let guard = pin();
+    let companion = meta::open_tree(
+        &tree.context,
+        versions_tree_name(&tree.tree_id),
+        None,
+        &guard,
+    )?;
+
+    // the versions that the keys of the tree replaced before were
+    // never recorded
+    if !tree.is_empty() {
+        let log = &tree.context.pagecache.log;
+        let last = history::seq(log.iobufs.max_reserved_lsn.load(Acquire));
+        let mut marker = last.to_be_bytes().to_vec();
+        marker.extend_from_slice(&expiration::now_millis().to_be_bytes());
+        let _ = companion.insert([TRIMMED], marker)?;
+    }
+
+    *versions = Some(companion);
+    Ok(())
+}
+
+/// Records the version of a stored key that the write with `seq`
+/// replaced, which `previous` returns, if the tree retains its
+/// versions. Must be called once the write is linked.
+pub(crate) fn record<F>(
+    tree: &Tree,
+    key: &[u8],
+    previous: F,
+    seq: u64,
+) -> Result<()>
+where
+    F: FnOnce() -> Result<Option<IVec>>,
+{
+    let versions = if let Some(versions) = tree.versions.read().clone() {
+        versions
+    } else {
+        return Ok(());
+    };
+
+    let now = expiration::now_millis();
+    let mut version = now.to_be_bytes().to_vec();
+    if let Some(value) = previous()? {
+        version.push(1);
+        version.extend_from_slice(&value);
+    } else {
+        version.push(0);
+    }
+
+    let mut guard = pin();
+    let time = IVec::from(&now.to_be_bytes());
+    let version = Some(IVec::from(version));
+    let _ = versions.set_inner(&by_key(key, seq), version, &mut guard)?;
+    let _ = versions.set_inner(&by_seq(seq, key), Some(time), &mut guard)?;
+
+    trim(tree, &versions, now, &mut guard)
+}
+
+// removes some of the versions that were replaced longer ago than
+// the retention
+fn trim(
+    tree: &Tree,
+    versions: &Tree,
+    now: u64,
+    guard: &mut Guard,
+) -> Result<()> {
+    let retention = tree.context.version_retention_ms.unwrap_or(0);
+    let oldest_retained = now.saturating_sub(retention);
+
+    for _ in 0..TRIM_CHUNK {
+        let mut iter = versions.range(vec![BY_SEQ]..vec![TRIMMED]);
+        let (k, v) = if let Some(res) = iter.next_inner() {
+            res?
+        } else {
+            return Ok(());
+        };
+        drop(iter);
+
+        let time = decode_u64(&v)?;
+        if time >= oldest_retained {
+            return Ok(());
+        }
+        if k.len() < 9 {
+            return Err(Error::corruption(None));
+        }
+        let seq = decode_u64(&k[1..9])?;
+
+        let _ = versions.set_inner(&k, None, guard)?;
+        let _ = versions.set_inner(&by_key(&k[9..], seq), None, guard)?;
+        let mut marker = k[1..9].to_vec();
+        marker.extend_from_slice(&v);
+        let _ = versions.set_inner(&[TRIMMED], Some(marker.into()), guard)?;
+    }
+
+    Ok(())
+}
+
+// returns the companion tree, and a snapshot of it and its parent
+// once it is checked that the point is still retained
+fn retained(tree: &Tree, at: VersionAt) -> Result<(Tree, Snapshot)> {
+    let versions = if let Some(versions) = tree.versions.read().clone() {
+        versions
+    } else {
+        return Err(Error::Unsupported(
+            "the tree retains no versions, see Config::version_retention_ms"
+                .into(),
+        ));
+    };
+
+    if let VersionAt::Time(time) = at {
+        let retention = tree.context.version_retention_ms.unwrap_or(0);
+        let oldest_retained =
+            SystemTime::now().checked_sub(Duration::from_millis(retention));
+        if oldest_retained.map(|oldest| time < oldest) == Some(true) {
+            return Err(Error::Unsupported(format!(
+                "the versions of {:?} are no longer retained",
+                time
+            )));
+        }
+    }
+
+    let snapshot = Snapshot::new(&tree.context);
+    if let Some(marker) = snapshot.get(&versions, [TRIMMED])? {
+        if marker.len() != 16 {
+            return Err(Error::corruption(None));
+        }
+        let seq = decode_u64(&marker[..8])?;
+        let time = decode_u64(&marker[8..])?;
+        let trimmed = match at {
+            VersionAt::Seq(point) => point < seq,
+            VersionAt::Time(point) => millis(point) < time,
+        };
+        if trimmed {
+            return Err(Error::Unsupported(format!(
+                "the versions before {:?} are no longer retained",
+                VersionAt::Seq(seq)
+            )));
+        }
+    }
+
+    Ok((versions, snapshot))
+}
+
+/// Reads a key of a tree as of a point, see `Tree::get_at`.
+pub(crate) fn get_at(
+    tree: &Tree,
+    key: &[u8],
+    at: VersionAt,
+) -> Result<Option<IVec>> {
+    let (versions, snapshot) = retained(tree, at)?;
+
+    let stored_key = tree.order.encode(key);
+    let mut hi = escaped_key(&stored_key);
+    hi.extend_from_slice(&[0, 1]);
+    let lo = match at {
+        VersionAt::Seq(seq) => by_key(&stored_key, seq.saturating_add(1)),
+        VersionAt::Time(_) => by_key(&stored_key, 0),
+    };
+
+    for res in snapshot.range(&versions, lo..hi) {
+        let (k, v) = res?;
+        let (_, seq) = decode_by_key(&k)?;
+        let (time, version) = decode_version(&v)?;
+        if at.precedes(seq, time) {
+            return Ok(version);
+        }
+    }
+
+    snapshot.get(tree, key)
+}
+
+/// Iterates over a range of a tree as of a point, see
+/// `Tree::range_at`.
+pub(crate) fn range_at<K, R>(
+    tree: &Tree,
+    range: &R,
+    at: VersionAt,
+) -> Result<VersionIter>
+where
+    K: AsRef<[u8]>,
+    R: RangeBounds<K>,
+{
+    let (versions, snapshot) = retained(tree, at)?;
+    let (lo, hi) = tree.encode_range(range);
+
+    let versions_lo = match lo {
+        Bound::Included(ref start) => Bound::Included(escaped_key(start)),
+        Bound::Excluded(ref start) => {
+            let mut after = escaped_key(start);
+            after.extend_from_slice(&[0, 1]);
+            Bound::Included(after)
+        }
+        Bound::Unbounded => Bound::Included(vec![BY_KEY]),
+    };
+    let versions_hi = match hi {
+        Bound::Included(ref end) => {
+            let mut after = escaped_key(end);
+            after.extend_from_slice(&[0, 1]);
+            Bound::Excluded(after)
+        }
+        Bound::Excluded(ref end) => Bound::Excluded(escaped_key(end)),
+        Bound::Unbounded => Bound::Excluded(vec![BY_SEQ]),
+    };
+
+    Ok(VersionIter {
+        tree: tree.clone(),
+        at,
+        current: snapshot.range_inner(tree, lo, hi),
+        replaced: snapshot.range(&versions, (versions_lo, versions_hi)),
+        next_current: None,
+        next_replaced: None,
+    })
+}
+
+/// An iterator over the keys and values of a range of a `Tree` as
+/// of a point in the past, see `Tree::range_at`.
+pub struct VersionIter {
+    tree: Tree,
+    at: VersionAt,
+    current: SnapshotIter,
+    replaced: SnapshotIter,
+    // the next stored key of the tree and its value
+    next_current: Option<(IVec, IVec)>,
+    // the next stored key that has versions, the sequence number
+    // and time of the write that replaced the version, and the
+    // version
+    next_replaced: Option<(IVec, u64, u64, Option<IVec>)>,
+}
+
+impl VersionIter {
+    fn fill(&mut self) -> Result<()> {
+        if self.next_current.is_none() {
+            if let Some(res) = self.current.next() {
+                let (k, v) = res?;
+                let stored_key = IVec::from(&*self.tree.order.encode(&k));
+                self.next_current = Some((stored_key, v));
+            }
+        }
+        if self.next_replaced.is_none() {
+            if let Some(res) = self.replaced.next() {
+                let (k, v) = res?;
+                let (stored_key, seq) = decode_by_key(&k)?;
+                let (time, version) = decode_version(&v)?;
+                self.next_replaced = Some((stored_key, seq, time, version));
+            }
+        }
+        Ok(())
+    }
+
+    fn next_inner(&mut self) -> Result<Option<(IVec, IVec)>> {
+        loop {
+            self.fill()?;
+            let key = match (&self.next_current, &self.next_replaced) {
+                (Some((a, _)), Some((b, ..))) => std::cmp::min(a, b).clone(),
+                (Some((a, _)), None) => a.clone(),
+                (None, Some((b, ..))) => b.clone(),
+                (None, None) => return Ok(None),
+            };
+
+            // the first version that was replaced after the point
+            let mut found = None;
+            while self.next_replaced.as_ref().map(|(k, ..)| k) == Some(&key) {
+                let (_, seq, time, version) =
+                    self.next_replaced.take().unwrap();
+                if found.is_none() && self.at.precedes(seq, time) {
+                    found = Some(version);
+                }
+                self.fill()?;
+            }
+
+            let current = if self.next_current.as_ref().map(|(k, _)| k)
+                == Some(&key)
+            {
+                self.next_current.take().map(|(_, v)| v)
+            } else {
+                None
+            };
+
+            if let Some(value) = found.unwrap_or(current) {
+                return Ok(Some((self.tree.order.decode(key), value)));
+            }
+        }
+    }
+}
+
+impl Iterator for VersionIter {
+    type Item = Result<(IVec, IVec)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_inner().transpose()
+    }
+}
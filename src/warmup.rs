@@ -0,0 +1,127 @@
+//! Reading pages into the page cache before they are needed, see
+//! `Tree::prefetch_range` and `Db::warm_cache_from`.
+//!
+//! A cache manifest lists the pages that were cached when it was
+//! written, so that a process that opens the same database later
+//! can read them again before it takes traffic. It's written as
+//! `MAGIC` ++ version ++ count ++ pids ++ crc32, where the crc32
+//! covers every byte that precedes it. All integers are
+//! little-endian.
+//!
+//! Page IDs are only hints: a page may have been freed, or given
+//! to another node, since the manifest was written, in which case
+//! reading it just costs a read.
+use std::{
+    convert::TryInto,
+    fs,
+    ops::Bound,
+    path::{Path, PathBuf},
+};
+
+use crate::*;
+
+const MAGIC: &[u8; 8] = b"sledwarm";
+const VERSION: u8 = 1;
+
+/// Reads the nodes of a tree that hold keys between the encoded
+/// bounds of a range, returning how many of them were read from
+/// storage.
+pub(crate) fn prefetch(
+    tree: &Tree,
+    lo: &Bound<IVec>,
+    hi: &Bound<IVec>,
+) -> Result<usize> {
+    let pages_read_before = db_metrics::pages_read_by_thread();
+    let mut stack = vec![tree.root.load(Acquire)];
+    while let Some(pid) = stack.pop() {
+        let guard = pin();
+        let node_view =
+            if let Some(node_view) = tree.context.pagecache.get(pid, &guard)? {
+                node_view
+            } else {
+                continue;
+            };
+
+        if !node_view.is_index {
+            continue;
+        }
+
+        let children: Vec<(IVec, PageId)> =
+            node_view.decoded_keys().zip(node_view.iter_index_pids()).collect();
+        for (idx, (child_lo, child)) in children.iter().enumerate().rev() {
+            let child_hi = match children.get(idx + 1) {
+                Some((next_lo, _)) => Some(&**next_lo),
+                None => node_view.hi(),
+            };
+            if range_size::overlaps(lo, hi, child_lo, child_hi) {
+                stack.push(*child);
+            }
+        }
+    }
+    let pages_read = db_metrics::pages_read_by_thread() - pages_read_before;
+    Ok(usize::try_from(pages_read).unwrap())
+}
+
+/// Writes the pages that are cached to a manifest at `path`,
+/// returning how many there were.
+pub(crate) fn write_manifest(db: &Db, path: &Path) -> Result<usize> {
+    let pids = db.context.pagecache.cached_pids();
+
+    let mut buf = Vec::with_capacity(MAGIC.len() + 1 + 8 * (pids.len() + 1));
+    buf.extend_from_slice(MAGIC);
+    buf.push(VERSION);
+    buf.extend_from_slice(&u64::try_from(pids.len()).unwrap().to_le_bytes());
+    for pid in &pids {
+        buf.extend_from_slice(&pid.to_le_bytes());
+    }
+    let crc = crc32(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+
+    // the manifest is replaced at once, so that a crash while it's
+    // written leaves the previous one behind
+    let mut temp_path = PathBuf::from(path);
+    let _ = temp_path.set_extension("tmp");
+    let mut f = fs::File::create(&temp_path)?;
+    f.write_all(&buf)?;
+    f.sync_all()?;
+    fs::rename(temp_path, path)?;
+
+    Ok(pids.len())
+}
+
+/// Reads the pages that a manifest at `path` lists into the cache,
+/// returning how many were read from storage.
+pub(crate) fn warm_from(db: &Db, path: &Path) -> Result<usize> {
+    let buf = fs::read(path)?;
+    let header_len = MAGIC.len() + 1 + 8;
+    if buf.len() < header_len + 4 || &buf[..MAGIC.len()] != MAGIC {
+        return Err(Error::Unsupported(format!(
+            "{:?} is not a sled cache manifest",
+            path
+        )));
+    }
+    if buf[MAGIC.len()] != VERSION {
+        return Err(Error::Unsupported(format!(
+            "unsupported cache manifest version {}",
+            buf[MAGIC.len()]
+        )));
+    }
+
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    if u32::from_le_bytes(crc.try_into().unwrap()) != crc32(body) {
+        return Err(Error::corruption(None));
+    }
+
+    let count = u64::from_le_bytes(
+        body[MAGIC.len() + 1..header_len].try_into().unwrap(),
+    );
+    let pids: Vec<PageId> = body[header_len..]
+        .chunks_exact(8)
+        .map(|chunk| PageId::from_le_bytes(chunk.try_into().unwrap()))
+        .collect();
+    if u64::try_from(pids.len()).unwrap() != count {
+        return Err(Error::corruption(None));
+    }
+
+    db.context.pagecache.warm(&pids)
+}
@@ -0,0 +1,45 @@
+//! Durability that is chosen for a single write, see
+//! `Tree::insert_with` and `Tree::apply_batch_with`.
+//!
+//! An immediate write syncs the log up to its last message
+//! before it returns, like `SyncPolicy::EveryWrite` does for
+//! every write, rather than flushing everything that other
+//! threads have written since, like `Tree::flush` does.
+
+use crate::pagecache::durably;
+
+/// When a write is made durable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// The write is synced to the storage device before it
+    /// returns.
+    Immediate,
+    /// The write is synced according to the `SyncPolicy` of the
+    /// database, like every other write.
+    Lazy,
+}
+
+/// Options for a single write, see `Tree::insert_with` and
+/// `Tree::apply_batch_with`. The default options make a lazy
+/// write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOptions {
+    /// When the write is made durable.
+    pub flush: Durability,
+}
+
+impl Default for WriteOptions {
+    fn default() -> WriteOptions {
+        WriteOptions { flush: Durability::Lazy }
+    }
+}
+
+impl WriteOptions {
+    // runs the write with the durability of the options
+    pub(crate) fn apply<T, F: FnOnce() -> T>(self, write: F) -> T {
+        match self.flush {
+            Durability::Immediate => durably(write),
+            Durability::Lazy => write(),
+        }
+    }
+}
@@ -6,7 +6,7 @@ use std::{
         atomic::{AtomicUsize, Ordering::SeqCst},
         Arc, Barrier,
     },
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
 #[allow(unused_imports)]
@@ -1007,6 +1007,5789 @@ fn tree_range() {
     assert_eq!(r.next(), None);
 }
 
+#[test]
+fn tree_remove_range() -> Result<()> {
+    common::setup_logger();
+
+    let config = Config::new().temporary(true).flush_every_ms(None);
+    let t = config.open()?;
+
+    for i in 0..N_PER_THREAD {
+        let k = kv(i);
+        t.insert(&k, k.clone())?;
+    }
+
+    let subscriber = t.watch_prefix(vec![]);
+
+    let start = kv(10);
+    let end = kv(20);
+    assert_eq!(t.remove_range(start.clone()..end.clone())?, 10);
+    assert_eq!(t.len(), N_PER_THREAD - 10);
+    assert_eq!(t.range(start.clone()..end.clone()).next(), None);
+    assert!(t.contains_key(&end)?);
+
+    match subscriber.next_timeout(Duration::from_secs(1)) {
+        Ok(event) => assert_eq!(event.iter().count(), 10),
+        Err(e) => panic!("expected a single batch event: {:?}", e),
+    }
+
+    // removing an already-empty range is a no-op
+    assert_eq!(t.remove_range(start..end)?, 0);
+
+    assert_eq!(t.remove_range::<Vec<u8>, _>(..)?, N_PER_THREAD - 10);
+    assert!(t.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn tree_remove_range_in_chunks() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_remove_range_in_chunks";
+    let _ = std::fs::remove_dir_all(path);
+    let config = Config::new().path(path).flush_every_ms(None);
+
+    {
+        let db = config.open()?;
+        for i in 0..3000_u32 {
+            db.insert(i.to_be_bytes(), vec![0; 16])?;
+        }
+        let subscriber = db.watch_prefix(vec![0, 0]);
+
+        // other writes go on while the range is removed
+        let writer = {
+            let db = db.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 1_000_000..1_001_000_u32 {
+                    db.insert(i.to_be_bytes(), vec![1; 16])?;
+                }
+                Ok(())
+            })
+        };
+        let end = 3000_u32.to_be_bytes();
+        assert_eq!(db.remove_range(..&end[..])?, 3000);
+        writer.join().unwrap()?;
+
+        // every chunk was published before `remove_range` returned,
+        // so the events are already waiting
+        let mut removed = 0;
+        let mut seqs = vec![];
+        for event in subscriber.take(3) {
+            removed += event.iter().count();
+            seqs.push(event.seq());
+        }
+        assert_eq!(removed, 3000);
+        assert!(seqs.windows(2).all(|w| w[0] < w[1]));
+        db.flush()?;
+    }
+
+    let db = config.open()?;
+    assert_eq!(db.len(), 1000);
+    assert_eq!(db.first()?.unwrap().0, 1_000_000_u32.to_be_bytes());
+    drop(db);
+
+    let _ = std::fs::remove_dir_all(path);
+    Ok(())
+}
+
+#[test]
+fn tree_get_many() -> Result<()> {
+    common::setup_logger();
+
+    let config = Config::new().temporary(true).flush_every_ms(None);
+    let t = config.open()?;
+
+    for i in (0..N).step_by(2) {
+        let k = kv(i);
+        t.insert(&k, k.clone())?;
+    }
+
+    let keys: Vec<Vec<u8>> = (0..N).rev().map(kv).collect();
+    let values = t.get_many(&keys)?;
+    assert_eq!(values.len(), N);
+
+    for (k, v) in keys.iter().zip(values) {
+        assert_eq!(v, t.get(k)?);
+    }
+
+    assert_eq!(t.get_many::<&[u8], _>(vec![])?, vec![]);
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn tree_checkpoint() -> Result<()> {
+    common::setup_logger();
+
+    let path = std::env::temp_dir().join("test_tree_checkpoint");
+    let _ = std::fs::remove_dir_all(&path);
+
+    let config = Config::new().temporary(true).flush_every_ms(Some(1));
+    let db = config.open()?;
+
+    // large enough to be stored in the heap files
+    let big = vec![7; 64 * 1024];
+    db.insert(b"big", big.clone())?;
+    for i in 0..N {
+        db.insert(i.to_be_bytes(), &i.to_be_bytes())?;
+    }
+
+    // the heap is written to while it is copied
+    let bigs = db.open_tree("bigs")?;
+    let big_value = |i: usize| vec![(i % 251) as u8; 64 * 1024];
+
+    let written = Arc::new(AtomicUsize::new(N));
+    let writer = {
+        let db = db.clone();
+        let written = written.clone();
+        std::thread::spawn(move || -> Result<()> {
+            for i in N..N * 10 {
+                if i % 10 == 0 {
+                    bigs.insert(i.to_be_bytes(), big_value(i))?;
+                }
+                db.insert(i.to_be_bytes(), &i.to_be_bytes())?;
+                written.store(i + 1, SeqCst);
+            }
+            Ok(())
+        })
+    };
+
+    while written.load(SeqCst) < N * 2 {
+        std::thread::yield_now();
+    }
+    let before = written.load(SeqCst);
+    db.checkpoint(&path)?;
+    writer.join().unwrap()?;
+
+    match db.checkpoint(&path) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+
+    let copy = Config::new().path(&path).open()?;
+    assert_eq!(copy.get(b"big")?, Some(IVec::from(big)));
+
+    // writes are applied in order, so the copy must hold
+    // an unbroken prefix that includes everything written
+    // before `checkpoint` was called.
+    let copied = copy.len() - 1;
+    assert!(copied >= before, "{} < {}", copied, before);
+    for i in 0..copied {
+        let expected = IVec::from(&i.to_be_bytes());
+        assert_eq!(copy.get(i.to_be_bytes())?, Some(expected));
+    }
+    let copied_bigs = copy.open_tree("bigs")?;
+    for i in (N..copied).filter(|i| i % 10 == 0) {
+        let expected = IVec::from(big_value(i));
+        assert_eq!(copied_bigs.get(i.to_be_bytes())?, Some(expected));
+    }
+
+    drop(copy);
+    let _ = std::fs::remove_dir_all(&path);
+
+    Ok(())
+}
+
+#[test]
+fn tree_backup_restore() -> Result<()> {
+    common::setup_logger();
+
+    let config = Config::new().temporary(true).flush_every_ms(None);
+    let db = config.open()?;
+
+    let tree = db.open_tree("tree")?;
+    for i in 0..N {
+        db.insert(kv(i), kv(i))?;
+        tree.insert(kv(i), vec![7; i])?;
+    }
+    let _ = db.open_tree("empty")?;
+
+    let mut backup = vec![];
+    db.backup_to(&mut backup)?;
+
+    let restored = Config::new().temporary(true).open()?;
+    restored.restore_from(&*backup)?;
+    assert_eq!(db.checksum()?, restored.checksum()?);
+    assert_eq!(restored.tree_names().len(), 3);
+
+    match restored.restore_from(&*backup) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+
+    // flip a bit in the last value
+    let flipped = backup.len() - 6;
+    backup[flipped] ^= 1;
+    let corrupted = Config::new().temporary(true).open()?;
+    match corrupted.restore_from(&*backup) {
+        Err(Error::Corruption { .. }) => {}
+        other => panic!("expected Corruption, got {:?}", other),
+    }
+    assert_eq!(corrupted.tree_names().len(), 1);
+    assert!(corrupted.is_empty());
+
+    // the lsn of the header follows the magic, version, flag and since
+    let mut negative = backup.clone();
+    negative[25] = 0x80;
+    match corrupted.restore_to(vec![&*negative], 0) {
+        Err(Error::Corruption { .. }) => {}
+        other => panic!("expected Corruption, got {:?}", other),
+    }
+
+    let empty = Config::new().temporary(true).open()?;
+    match empty.restore_from(&b"not a backup"[..]) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn tree_incremental_backup() -> Result<()> {
+    common::setup_logger();
+
+    let config = Config::new().temporary(true).flush_every_ms(None);
+    let db = config.open()?;
+
+    let dropped = db.open_tree("dropped")?;
+    for i in 0..N {
+        db.insert(kv(i), kv(i))?;
+        dropped.insert(kv(i), kv(i))?;
+    }
+
+    let mut full = vec![];
+    let full_lsn = db.backup_to(&mut full)?;
+
+    for i in (0..N).step_by(3) {
+        db.remove(kv(i))?;
+    }
+    db.insert(kv(N / 2), vec![1; 100])?;
+    db.open_tree("created")?.insert(b"k", b"v")?;
+    db.drop_tree("dropped")?;
+
+    let mut first = vec![];
+    let first_lsn = db.backup_since(full_lsn, &mut first)?;
+    assert!(first_lsn > full_lsn);
+
+    db.insert(kv(1), vec![2; 100])?;
+
+    let mut second = vec![];
+    let second_lsn = db.backup_since(first_lsn, &mut second)?;
+    assert!(second_lsn > first_lsn);
+
+    // only the leaf that changed is included
+    assert!(second.len() < full.len());
+
+    let restored = Config::new().temporary(true).open()?;
+    assert_eq!(restored.restore_from(&*full)?, full_lsn);
+    assert_eq!(restored.restore_from(&*first)?, first_lsn);
+    assert_eq!(restored.restore_from(&*second)?, second_lsn);
+
+    assert_eq!(db.checksum()?, restored.checksum()?);
+    assert_eq!(db.tree_names(), restored.tree_names());
+
+    match db.backup_since(second_lsn * 2, &mut vec![]) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn tree_backup_during_writes() -> Result<()> {
+    common::setup_logger();
+
+    // writes to the db every time that the backup is written out
+    struct Writing<'a> {
+        db: &'a Db,
+        written: Vec<u8>,
+        writes: usize,
+    }
+
+    impl std::io::Write for Writing<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.db.insert(kv(self.writes), vec![1; 100]).unwrap();
+            self.db.remove(kv(N - 1 - self.writes)).unwrap();
+            self.writes += 1;
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let config = Config::new().temporary(true).flush_every_ms(None);
+    let db = config.open()?;
+    for i in 0..N {
+        db.insert(kv(i), kv(i))?;
+    }
+    let before = db.checksum()?;
+
+    let mut writing = Writing { db: &db, written: vec![], writes: 0 };
+    let lsn = db.backup_to(&mut writing)?;
+    assert!(writing.writes > 1);
+    assert_ne!(db.checksum()?, before);
+
+    let restored = Config::new().temporary(true).open()?;
+    assert_eq!(restored.restore_from(&*writing.written)?, lsn);
+    assert_eq!(restored.checksum()?, before);
+
+    // the writes that were made during the full backup are in
+    // the next incremental one
+    let mut incremental = vec![];
+    let _ = db.backup_since(lsn, &mut incremental)?;
+    let _ = restored.restore_from(&*incremental)?;
+    assert_eq!(restored.checksum()?, db.checksum()?);
+
+    Ok(())
+}
+
+#[test]
+fn tree_point_in_time_restore() -> Result<()> {
+    common::setup_logger();
+
+    let config = Config::new().temporary(true).flush_every_ms(None);
+    let db = config.open()?;
+
+    let mut backups = vec![];
+    let mut lsns = vec![];
+    let mut checksums = vec![];
+
+    for round in 0..4 {
+        db.insert(kv(round), kv(round))?;
+
+        let mut backup = vec![];
+        let lsn = if let Some(last) = lsns.last() {
+            db.backup_since(*last, &mut backup)?
+        } else {
+            db.backup_to(&mut backup)?
+        };
+        backups.push(backup);
+        lsns.push(lsn);
+        checksums.push(db.checksum()?);
+    }
+
+    for (idx, lsn) in lsns.iter().enumerate() {
+        let restored = Config::new().temporary(true).open()?;
+        let streams = backups.iter().map(|b| &**b);
+        assert_eq!(restored.restore_to(streams, *lsn + 1)?, *lsn);
+        assert_eq!(restored.checksum()?, checksums[idx]);
+    }
+
+    let restored = Config::new().temporary(true).open()?;
+    let streams = backups.iter().map(|b| &**b);
+    let now = std::time::SystemTime::now();
+    assert_eq!(restored.restore_to_time(streams, now)?, lsns[3]);
+
+    // out of order
+    let restored = Config::new().temporary(true).open()?;
+    let streams = vec![&*backups[0], &*backups[2]];
+    match restored.restore_to(streams, lsns[3]) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+
+    // before the full backup
+    let restored = Config::new().temporary(true).open()?;
+    let streams = backups.iter().map(|b| &**b);
+    match restored.restore_to(streams, lsns[0] - 1) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn tree_read_only() -> Result<()> {
+    common::setup_logger();
+
+    let path = std::env::temp_dir().join("test_tree_read_only");
+    let _ = std::fs::remove_dir_all(&path);
+
+    // background snapshots may still be written after the
+    // writer is dropped, which would race with the comparison
+    // of the files below.
+    let config =
+        Config::new().path(&path).snapshot_after_ops(u64::max_value());
+    {
+        let db = config.open()?;
+        let tree = db.open_tree("tree")?;
+        for i in 0..N {
+            db.insert(kv(i), kv(i))?;
+            tree.insert(kv(i), vec![7; 64 * 1024 * (i % 2)])?;
+        }
+        db.flush()?;
+    }
+
+    let read_dir = |path: &std::path::Path| {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(path).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_file() {
+                let contents = std::fs::read(entry.path()).unwrap();
+                files.push((entry.path(), contents));
+            }
+        }
+        files.sort();
+        files
+    };
+    let before = read_dir(&path);
+    let heap_before = read_dir(&path.join("heap"));
+
+    let config = Config::new().path(&path).read_only(true);
+    let db = config.open()?;
+    let other = Config::new().path(&path).read_only(true).open()?;
+
+    let tree = db.open_tree("tree")?;
+    assert_eq!(db.len(), N);
+    assert_eq!(other.len(), N);
+    assert_eq!(tree.iter().count(), N);
+    assert_eq!(db.get(kv(3))?, Some(IVec::from(kv(3))));
+    assert_eq!(tree.get(kv(3))?.unwrap().len(), 64 * 1024);
+
+    fn assert_unsupported<T: std::fmt::Debug>(res: Result<T>) {
+        match res {
+            Err(Error::Unsupported(_)) => {}
+            other => panic!("expected Unsupported, got {:?}", other),
+        }
+    }
+
+    assert_unsupported(db.insert(b"k", b"v"));
+    assert_unsupported(tree.remove(kv(3)));
+    assert_unsupported(db.open_tree("new"));
+    assert_unsupported(db.generate_id());
+    assert_eq!(db.get(b"k")?, None);
+    assert_eq!(db.flush()?, 0);
+
+    drop(tree);
+    drop(db);
+    drop(other);
+
+    assert!(before == read_dir(&path));
+    assert!(heap_before == read_dir(&path.join("heap")));
+
+    let missing = std::env::temp_dir().join("test_tree_read_only_missing");
+    let _ = std::fs::remove_dir_all(&missing);
+    assert_unsupported(Config::new().path(&missing).read_only(true).open());
+    assert!(!missing.join("db").exists());
+
+    let _ = std::fs::remove_dir_all(&path);
+
+    Ok(())
+}
+
+#[test]
+fn tree_concurrent_readers() -> Result<()> {
+    common::setup_logger();
+
+    let path = std::env::temp_dir().join("test_tree_concurrent_readers");
+    let _ = std::fs::remove_dir_all(&path);
+
+    let config = || Config::new().path(&path).segment_size(4096);
+    let writer = config().open()?;
+    let value = |round: usize, i: usize| vec![round as u8; i % 64];
+    for i in 0..N {
+        writer.insert(kv(i), value(0, i))?;
+    }
+    writer.flush()?;
+
+    let reader = config().read_only(true).open()?;
+
+    // overwrite everything a few times, so that the writer
+    // frees segments and heap slots that the reader's
+    // snapshot refers to.
+    for round in 1..4 {
+        for i in 0..N {
+            writer.insert(kv(i), value(round, i))?;
+        }
+        writer.flush()?;
+    }
+
+    for i in 0..N {
+        assert_eq!(reader.get(kv(i))?, Some(IVec::from(value(0, i))));
+    }
+
+    let refreshed = reader.refresh()?;
+    drop(reader);
+    for i in 0..N {
+        assert_eq!(refreshed.get(kv(i))?, Some(IVec::from(value(3, i))));
+    }
+
+    match writer.refresh() {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other.map(drop)),
+    }
+
+    drop(refreshed);
+    assert_eq!(std::fs::read_dir(path.join("readers"))?.count(), 0);
+
+    drop(writer);
+    let _ = std::fs::remove_dir_all(&path);
+
+    Ok(())
+}
+
+// a toy cipher for exercising `Config::encryption`,
+// which provides no security at all
+#[derive(Clone)]
+struct XorKeys {
+    current: Arc<AtomicUsize>,
+    known: Vec<u32>,
+}
+
+impl XorKeys {
+    fn xor(key_id: u32, buf: &[u8]) -> Vec<u8> {
+        buf.iter().map(|b| b ^ (key_id as u8 | 0x80)).collect()
+    }
+
+    fn tag(key_id: u32, plaintext: &[u8]) -> [u8; 4] {
+        let tag = plaintext
+            .iter()
+            .fold(key_id, |a, b| a.wrapping_mul(31) ^ u32::from(*b));
+        tag.to_le_bytes()
+    }
+}
+
+impl KeyProvider for XorKeys {
+    fn current_key_id(&self) -> u32 {
+        self.current.load(SeqCst) as u32
+    }
+
+    fn encrypt(&self, key_id: u32, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut ret = XorKeys::xor(key_id, plaintext);
+        ret.extend_from_slice(&XorKeys::tag(key_id, plaintext));
+        Ok(ret)
+    }
+
+    fn decrypt(&self, key_id: u32, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        if !self.known.contains(&key_id) || ciphertext.len() < 4 {
+            return Err(Error::Unsupported(format!("unknown key {}", key_id)));
+        }
+        let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - 4);
+        let plaintext = XorKeys::xor(key_id, ciphertext);
+        if tag != XorKeys::tag(key_id, &plaintext) {
+            return Err(Error::Unsupported("authentication failed".into()));
+        }
+        Ok(plaintext)
+    }
+}
+
+fn files_contain(path: &std::path::Path, pattern: &[u8]) -> bool {
+    std::fs::read_dir(path).unwrap().any(|entry| {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files_contain(&path, pattern)
+        } else {
+            // snapshots may be replaced in the background
+            let contents = std::fs::read(path).unwrap_or_default();
+            contents.windows(pattern.len()).any(|w| w == pattern)
+        }
+    })
+}
+
+#[test]
+fn tree_encryption() -> Result<()> {
+    common::setup_logger();
+
+    let path = std::env::temp_dir().join("test_tree_encryption");
+    let _ = std::fs::remove_dir_all(&path);
+
+    let keys =
+        XorKeys { current: Arc::new(AtomicUsize::new(1)), known: vec![1] };
+    let value = |i: usize| {
+        let mut value = b"plaintext marker ".repeat(1 + 4096 * (i % 2));
+        value.extend_from_slice(&i.to_be_bytes());
+        value
+    };
+
+    {
+        let db = Config::new().path(&path).encryption(keys.clone()).open()?;
+        for i in 0..N {
+            db.insert(kv(i), value(i))?;
+        }
+        db.flush()?;
+    }
+
+    assert!(!files_contain(&path, b"plaintext marker "));
+
+    // rotate to a new key, keeping the old one for reads
+    keys.current.store(2, SeqCst);
+    let rotated = XorKeys { known: vec![1, 2], ..keys.clone() };
+    {
+        let db = Config::new().path(&path).encryption(rotated.clone()).open()?;
+        for i in 0..N {
+            assert_eq!(db.get(kv(i))?, Some(IVec::from(value(i))));
+            db.insert(kv(i), value(i + 1))?;
+        }
+        db.flush()?;
+    }
+
+    {
+        let db = Config::new().path(&path).encryption(rotated).open()?;
+        for i in 0..N {
+            assert_eq!(db.get(kv(i))?, Some(IVec::from(value(i + 1))));
+        }
+    }
+
+    match Config::new().path(&path).open() {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other.map(drop)),
+    }
+
+    let _ = std::fs::remove_dir_all(&path);
+
+    let plain = Config::new().path(&path).open()?;
+    drop(plain);
+    match Config::new().path(&path).encryption(keys).open() {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other.map(drop)),
+    }
+
+    let _ = std::fs::remove_dir_all(&path);
+
+    Ok(())
+}
+
+#[test]
+fn tree_compression_per_tree() -> Result<()> {
+    common::setup_logger();
+
+    let path = std::env::temp_dir().join("test_tree_compression_per_tree");
+    let _ = std::fs::remove_dir_all(&path);
+
+    let hot_config =
+        TreeConfig { compression: Codec::None, ..TreeConfig::default() };
+    let cold_config =
+        TreeConfig { compression: Codec::Zstd(19), ..TreeConfig::default() };
+    let value = |marker: &[u8], i: usize| {
+        let mut value = marker.repeat(4);
+        value.extend_from_slice(&i.to_be_bytes());
+        value
+    };
+
+    {
+        let db = Config::new().path(&path).use_compression(true).open()?;
+        let hot = db.open_tree_with("hot", hot_config)?;
+        let cold = db.open_tree_with("cold", cold_config)?;
+        for i in 0..N {
+            hot.insert(kv(i), value(b"hot marker ", i))?;
+            cold.insert(kv(i), value(b"cold marker ", i))?;
+        }
+        db.flush()?;
+    }
+
+    assert!(files_contain(&path, b"hot marker hot marker "));
+    assert!(!files_contain(&path, b"cold marker cold marker "));
+
+    let db = Config::new().path(&path).use_compression(true).open()?;
+    let hot = db.open_tree_with("hot", hot_config)?;
+    let cold = db.open_tree_with("cold", cold_config)?;
+    for i in 0..N {
+        assert_eq!(hot.get(kv(i))?, Some(IVec::from(value(b"hot marker ", i))));
+        assert_eq!(
+            cold.get(kv(i))?,
+            Some(IVec::from(value(b"cold marker ", i)))
+        );
+    }
+
+    // the codec is fixed once the tree exists
+    assert_eq!(db.open_tree("cold")?.len(), N);
+    match db.open_tree_with("cold", hot_config) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+    let invalid =
+        TreeConfig { compression: Codec::Zstd(23), ..TreeConfig::default() };
+    match db.open_tree_with("lukewarm", invalid) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+
+    drop((hot, cold, db));
+    let _ = std::fs::remove_dir_all(&path);
+
+    Ok(())
+}
+
+#[test]
+fn tree_config_overrides() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_config_overrides";
+    let _ = std::fs::remove_dir_all(path);
+    let config = Config::new().path(path);
+    let concatenate = |_k: &[u8], old: Option<&[u8]>, merged: &[u8]| {
+        let mut ret = old.map_or_else(Vec::new, |old| old.to_vec());
+        ret.extend_from_slice(merged);
+        Some(ret)
+    };
+    let hot_config = TreeConfig {
+        cache_priority: CachePriority::High,
+        merge_operator: Some("concatenate"),
+        ..TreeConfig::default()
+    };
+    let cold_config = TreeConfig {
+        cache_priority: CachePriority::Low,
+        expected_value_size: Some(10_000),
+        ..TreeConfig::default()
+    };
+    let blob = |i: u32| vec![i as u8; 10_000];
+
+    {
+        let db = config.open()?;
+        assert!(db.open_tree_with("hot", hot_config).is_err());
+        db.register_merge_operator("concatenate", concatenate);
+        let hot = db.open_tree_with("hot", hot_config)?;
+        let cold = db.open_tree_with("cold", cold_config)?;
+        hot.merge(b"k", b"a")?;
+        hot.merge(b"k", b"b")?;
+        cold.bulk_load((0..100_u32).map(|i| (i.to_be_bytes(), blob(i))))?;
+        db.flush()?;
+    }
+
+    {
+        let db = config.open()?;
+        db.register_merge_operator("concatenate", concatenate);
+
+        // the merge operator and the options were persisted
+        let hot = db.open_tree("hot")?;
+        hot.merge(b"k", b"c")?;
+        assert_eq!(hot.get(b"k")?, Some(IVec::from(b"abc")));
+        match db.open_tree_with("hot", cold_config) {
+            Err(Error::Unsupported(_)) => {}
+            other => panic!("expected Unsupported, got {:?}", other),
+        }
+        let rounded =
+            TreeConfig { expected_value_size: Some(16_000), ..cold_config };
+        let cold = db.open_tree_with("cold", rounded)?;
+        assert_eq!(cold.get(7_u32.to_be_bytes())?, Some(IVec::from(blob(7))));
+
+        // a copy has the same options
+        assert!(db.copy_tree("cold", "copy")?);
+        assert!(db.open_tree_with("copy", cold_config).is_ok());
+    }
+
+    // leaves of trees with large expected values hold several
+    // values, instead of one
+    let nodes = |tree_config: TreeConfig| -> Result<u64> {
+        let db = Config::new().temporary(true).open()?;
+        let tree = db.open_tree_with("blobs", tree_config)?;
+        tree.bulk_load((0..100_u32).map(|i| (i.to_be_bytes(), blob(i))))?;
+        Ok(db.verify_integrity()?.nodes)
+    };
+    assert!(nodes(cold_config)? * 4 < nodes(TreeConfig::default())?);
+
+    let _ = std::fs::remove_dir_all(path);
+    Ok(())
+}
+
+#[test]
+fn tree_trained_dictionary() -> Result<()> {
+    common::setup_logger();
+
+    let path = std::env::temp_dir().join("test_tree_trained_dictionary");
+    let _ = std::fs::remove_dir_all(&path);
+
+    let tree_config =
+        TreeConfig { compression: Codec::Zstd(3), ..TreeConfig::default() };
+    let value = |i: usize| {
+        format!(
+            r#"{{"id":{},"email":"user{}@example.com","active":true}}"#,
+            i, i
+        )
+    };
+
+    {
+        let db = Config::new().path(&path).open()?;
+        let tree = db.open_tree_with("users", tree_config)?;
+        for i in 0..N {
+            tree.insert(kv(i), value(i).as_bytes())?;
+        }
+
+        match db.train_dictionary() {
+            Err(Error::Unsupported(_)) => {}
+            other => panic!("expected Unsupported, got {:?}", other),
+        }
+
+        tree.train_dictionary()?;
+        for i in 0..N {
+            tree.insert(kv(i), value(i + N).as_bytes())?;
+        }
+        db.flush()?;
+    }
+
+    let dictionary = path.join("dictionaries").join("1-1");
+    assert!(dictionary.exists());
+
+    {
+        let db = Config::new().path(&path).open()?;
+        let tree = db.open_tree_with("users", tree_config)?;
+        for i in 0..N {
+            let expected = IVec::from(value(i + N).as_bytes());
+            assert_eq!(tree.get(kv(i))?, Some(expected));
+        }
+    }
+
+    // pages written after training can't be read without it
+    std::fs::remove_file(&dictionary)?;
+    let res = Config::new().path(&path).open().and_then(|db| {
+        let tree = db.open_tree_with("users", tree_config)?;
+        for i in 0..N {
+            tree.get(kv(i))?;
+        }
+        Ok(())
+    });
+    assert!(res.is_err());
+
+    let _ = std::fs::remove_dir_all(&path);
+
+    Ok(())
+}
+
+#[test]
+fn tree_key_order() -> Result<()> {
+    common::setup_logger();
+
+    let path = std::env::temp_dir().join("test_tree_key_order");
+    let _ = std::fs::remove_dir_all(&path);
+
+    let reverse =
+        TreeConfig { order: KeyOrder::Reverse, ..TreeConfig::default() };
+    let case_insensitive = TreeConfig {
+        compression: Codec::None,
+        order: KeyOrder::CaseInsensitive,
+        ..TreeConfig::default()
+    };
+
+    {
+        let db = Config::new().path(&path).open()?;
+        let tree = db.open_tree_with("reverse", reverse)?;
+        let subscriber = tree.watch_prefix(kv(1));
+        for i in 0..N {
+            tree.insert(kv(i), kv(i))?;
+        }
+        let event = subscriber.next_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(event.iter().next().unwrap().1, &IVec::from(kv(1)));
+
+        let words = db.open_tree_with("words", case_insensitive)?;
+        for word in &["banana", "Apple", "apple", "APPLE", "Cherry", "b"] {
+            words.insert(word, *word)?;
+        }
+        db.flush()?;
+    }
+
+    let db = Config::new().path(&path).open()?;
+    let tree = db.open_tree_with("reverse", reverse)?;
+
+    let keys: Vec<IVec> = tree.iter().keys().collect::<Result<_>>()?;
+    let expected: Vec<IVec> = (0..N).rev().map(|i| kv(i).into()).collect();
+    assert_eq!(keys, expected);
+
+    // bounds are given in the order of the tree
+    let keys: Vec<IVec> =
+        tree.range(kv(10)..kv(5)).keys().collect::<Result<_>>()?;
+    let expected: Vec<IVec> = (6..=10).rev().map(|i| kv(i).into()).collect();
+    assert_eq!(keys, expected);
+    assert_eq!(tree.get_lt(kv(5))?.unwrap().0, kv(6));
+    assert_eq!(tree.first()?.unwrap().0, kv(N - 1));
+    assert_eq!(tree.get(kv(7))?, Some(IVec::from(kv(7))));
+
+    tree.apply_batch({
+        let mut batch = Batch::default();
+        batch.remove(kv(N - 1));
+        batch
+    })?;
+    assert_eq!(tree.pop_min()?.unwrap().0, kv(N - 2));
+
+    let words = db.open_tree_with("words", case_insensitive)?;
+    let keys: Vec<IVec> = words.iter().keys().collect::<Result<_>>()?;
+    let expected: Vec<IVec> =
+        ["APPLE", "Apple", "apple", "b", "banana", "Cherry"]
+            .iter()
+            .map(|word| IVec::from(*word))
+            .collect();
+    assert_eq!(keys, expected);
+
+    let keys: Vec<IVec> =
+        words.scan_prefix("APP").keys().collect::<Result<_>>()?;
+    assert_eq!(keys, &expected[..3]);
+    assert_eq!(words.get("apple")?, Some(IVec::from("apple")));
+    assert_eq!(words.get("aPPle")?, None);
+
+    let res: TransactionResult<()> = words.transaction(|tx| {
+        tx.insert("aPPle", "aPPle")?;
+        Ok(())
+    });
+    res.unwrap();
+    assert_eq!(words.scan_prefix("apple").count(), 4);
+
+    // the order is fixed once the tree exists
+    match db.open_tree_with("words", reverse) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+
+    drop((tree, words, db));
+    let _ = std::fs::remove_dir_all(&path);
+
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn tree_typed() -> Result<()> {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+    struct UserId(i64);
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum Role {
+        Member,
+        Admin { since: u32 },
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        role: Role,
+        score: Option<f64>,
+    }
+
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let users: TypedTree<UserId, User> =
+        TypedTree::new(db.open_tree("users")?);
+
+    let user = |i: i64| User {
+        name: format!("user {}", i),
+        role: if i % 2 == 0 {
+            Role::Member
+        } else {
+            Role::Admin { since: 7 }
+        },
+        score: if i % 3 == 0 { None } else { Some(i as f64 / 2.) },
+    };
+
+    for i in (-300..300).rev() {
+        assert_eq!(users.insert(&UserId(i), &user(i))?, None);
+    }
+    assert_eq!(users.insert(&UserId(5), &user(6))?, Some(user(5)));
+    assert_eq!(users.insert(&UserId(5), &user(5))?, Some(user(6)));
+
+    assert_eq!(users.len(), 600);
+    assert_eq!(users.get(&UserId(-42))?, Some(user(-42)));
+    assert_eq!(users.get(&UserId(300))?, None);
+    assert!(users.contains_key(&UserId(299))?);
+
+    // integers are ordered by value, including negative ones
+    let ids: Vec<i64> = users
+        .iter()
+        .map(|res| res.map(|(UserId(id), _)| id))
+        .collect::<Result<_>>()?;
+    assert_eq!(ids, (-300..300).collect::<Vec<_>>());
+
+    let ids: Vec<i64> = users
+        .range(UserId(-2)..UserId(2))?
+        .rev()
+        .map(|res| res.map(|(UserId(id), _)| id))
+        .collect::<Result<_>>()?;
+    assert_eq!(ids, vec![1, 0, -1, -2]);
+
+    assert_eq!(users.first()?, Some((UserId(-300), user(-300))));
+    assert_eq!(users.last()?, Some((UserId(299), user(299))));
+    assert_eq!(users.remove(&UserId(299))?, Some(user(299)));
+    assert_eq!(users.last()?.unwrap().0, UserId(298));
+
+    // tuple keys sort by their fields in order
+    let scores: TypedTree<(String, u64), ()> =
+        TypedTree::new(db.open_tree("scores")?);
+    for (name, score) in &[("b", 2), ("a", 10), ("ab", 1), ("a", 9)] {
+        scores.insert(&(name.to_string(), *score), &())?;
+    }
+    let keys: Vec<(String, u64)> =
+        scores.iter().map(|res| res.map(|(k, ())| k)).collect::<Result<_>>()?;
+    let expected: Vec<(String, u64)> =
+        vec![("a", 9), ("a", 10), ("ab", 1), ("b", 2)]
+            .into_iter()
+            .map(|(name, score)| (name.into(), score))
+            .collect();
+    assert_eq!(keys, expected);
+
+    // values written by other types can't be read
+    db.open_tree("scores")?.insert("a", "b")?;
+    assert!(scores.iter().any(|res| res.is_err()));
+
+    Ok(())
+}
+
+#[test]
+fn tree_index() -> Result<()> {
+    common::setup_logger();
+
+    let path = std::env::temp_dir().join("test_tree_index");
+    let _ = std::fs::remove_dir_all(&path);
+
+    // values are a one byte group followed by a name
+    fn by_group(_k: &[u8], v: &[u8]) -> Option<Vec<u8>> {
+        v.get(..1).map(<[u8]>::to_vec)
+    }
+
+    fn members(index: &Index, group: u8) -> Result<Vec<IVec>> {
+        index.get([group]).map(|res| res.map(|(_, k)| k)).collect()
+    }
+
+    {
+        let db = Config::new().path(&path).open()?;
+        let people = db.open_tree("people")?;
+        people.insert("ada", &b"\x01ada"[..])?;
+        people.insert("bob", &b"\x02bob"[..])?;
+
+        // existing records are indexed when the index is created
+        let index = people.create_index("by_group", by_group)?;
+        assert_eq!(members(&index, 1)?, vec![IVec::from("ada")]);
+
+        people.insert("cyd", &b"\x01cyd"[..])?;
+        people.insert("bob", &b"\x01bob"[..])?;
+        assert_eq!(members(&index, 1)?.len(), 3);
+        assert!(members(&index, 2)?.is_empty());
+
+        let swapped = people.compare_and_swap(
+            "ada",
+            Some(&b"\x01ada"[..]),
+            Some("\x03"),
+        )?;
+        assert!(swapped.is_ok());
+        people.remove("cyd")?;
+        people.set_merge_operator(|_k, _old, new| Some(new.to_vec()));
+        people.merge("dan", &b"\x02dan"[..])?;
+
+        people.apply_batch({
+            let mut batch = Batch::default();
+            batch.insert("eve", &b"\x02eve"[..]);
+            batch.remove("bob");
+            batch
+        })?;
+
+        let res: TransactionResult<()> = people.transaction(|tx| {
+            tx.insert("fay", &b"\x03fay"[..])?;
+            Ok(())
+        });
+        res.unwrap();
+
+        let entries: Vec<(IVec, IVec)> = index.iter().collect::<Result<_>>()?;
+        let expected: Vec<(IVec, IVec)> =
+            vec![(2, "dan"), (2, "eve"), (3, "ada"), (3, "fay")]
+                .into_iter()
+                .map(|(group, k)| (IVec::from(vec![group]), IVec::from(k)))
+                .collect();
+        assert_eq!(entries, expected);
+
+        let groups: Vec<IVec> = index
+            .range(&[3][..]..)
+            .rev()
+            .map(|res| res.map(|(_, k)| k))
+            .collect::<Result<_>>()?;
+        assert_eq!(groups, vec![IVec::from("fay"), IVec::from("ada")]);
+
+        // index keys that are prefixes of each other stay apart
+        let names = people.create_index("by_name", |k, _v| Some(k.to_vec()))?;
+        assert_eq!(names.get("fa").count(), 0);
+        assert_eq!(names.range("e".."fb").count(), 2);
+        assert_eq!(names.range::<&str, _>(.."fay").count(), 3);
+        assert_eq!(names.range::<&str, _>(.."f").count(), 3);
+
+        db.flush()?;
+    }
+
+    {
+        // the tree can't be written to until all of its indexes
+        // are created again
+        let db = Config::new().path(&path).open()?;
+        assert_eq!(db.tree_names().len(), 2);
+        let people = db.open_tree("people")?;
+        match people.insert("gus", &b"\x02gus"[..]) {
+            Err(Error::Unsupported(_)) => {}
+            other => panic!("expected an unsupported write, got {:?}", other),
+        }
+        let index = people.create_index("by_group", by_group)?;
+        let mut batch = Batch::default();
+        batch.remove("dan");
+        match people.apply_batch(batch) {
+            Err(Error::Unsupported(_)) => {}
+            other => panic!("expected an unsupported batch, got {:?}", other),
+        }
+        assert_eq!(people.len(), 4);
+
+        let _names = people.create_index("by_name", |k, _v| Some(k.to_vec()))?;
+        people.insert("gus", &b"\x02gus"[..])?;
+        people.remove("dan")?;
+        let expected = vec![IVec::from("eve"), IVec::from("gus")];
+        assert_eq!(members(&index, 2)?, expected);
+        assert_eq!(index.len(), 4);
+
+        // writes to other trees go on while an index is rebuilt
+        let others = db.open_tree("others")?;
+        let writer = {
+            let others = others.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..1000_u32 {
+                    others.insert(i.to_be_bytes(), vec![])?;
+                }
+                Ok(())
+            })
+        };
+        let index = people.create_index("by_group", by_group)?;
+        writer.join().unwrap()?;
+        assert_eq!(index.len(), 4);
+        assert_eq!(others.len(), 1000);
+
+        // the indexes are dropped along with their tree
+        assert!(db.drop_tree("people")?);
+        let people = db.open_tree("people")?;
+        let index = people.create_index("by_group", by_group)?;
+        assert!(index.is_empty());
+    }
+
+    let _ = std::fs::remove_dir_all(&path);
+
+    Ok(())
+}
+
+#[test]
+fn tree_named_merge_operators() -> Result<()> {
+    common::setup_logger();
+
+    let path = std::env::temp_dir().join("test_tree_named_merge_operators");
+    let _ = std::fs::remove_dir_all(&path);
+
+    fn concatenate(
+        _k: &[u8],
+        old: Option<&[u8]>,
+        merged: &[u8],
+    ) -> Option<Vec<u8>> {
+        let mut ret = old.map_or_else(Vec::new, <[u8]>::to_vec);
+        ret.extend_from_slice(merged);
+        Some(ret)
+    }
+
+    {
+        let db = Config::new().path(&path).open()?;
+        let log = db.open_tree("log")?;
+
+        match log.use_merge_operator("concatenate") {
+            Err(Error::Unsupported(_)) => {}
+            other => panic!("expected Unsupported, got {:?}", other),
+        }
+
+        db.register_merge_operator("concatenate", concatenate);
+        db.register_merge_operator("replace", |_k, _old, merged| {
+            Some(merged.to_vec())
+        });
+        log.use_merge_operator("concatenate")?;
+        log.merge("k", "a")?;
+
+        // the operator of a tree can't be changed
+        match log.use_merge_operator("replace") {
+            Err(Error::Unsupported(_)) => {}
+            other => panic!("expected Unsupported, got {:?}", other),
+        }
+        log.use_merge_operator("concatenate")?;
+
+        db.flush()?;
+    }
+
+    let db = Config::new().path(&path).open()?;
+
+    // the tree refuses to open until its operator is registered
+    match db.open_tree("log") {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+    assert_eq!(db.tree_names().len(), 2);
+
+    db.register_merge_operator("concatenate", concatenate);
+    let log = db.open_tree("log")?;
+    log.merge("k", "b")?;
+    assert_eq!(log.get("k")?, Some(IVec::from("ab")));
+
+    // dropping the tree forgets its operator
+    assert!(db.drop_tree("log")?);
+    db.register_merge_operator("replace", |_k, _old, merged| {
+        Some(merged.to_vec())
+    });
+    db.open_tree("log")?.use_merge_operator("replace")?;
+
+    drop((log, db));
+    let _ = std::fs::remove_dir_all(&path);
+
+    Ok(())
+}
+
+#[test]
+fn tree_builtin_merge_operators() -> Result<()> {
+    use std::convert::TryFrom;
+
+    use sled::merge::{Counter, OrSet};
+
+    common::setup_logger();
+
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // transactional merges fail without a merge operator
+    let res: TransactionResult<_> =
+        db.transaction(|tx| Ok(tx.merge(b"k", Counter::delta(1))?));
+    match res {
+        Err(TransactionError::Storage(Error::Unsupported(_))) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+
+    db.set_merge_operator(Counter::merge);
+
+    let threads: Vec<_> = (0..N_THREADS)
+        .map(|_| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for _ in 0..N_PER_THREAD {
+                    db.merge(b"k", Counter::delta(1)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let n = i64::try_from(N).unwrap();
+    assert_eq!(Counter::decode(&db.get(b"k")?.unwrap()), Some(n));
+
+    // merges in a transaction see the writes before them, and
+    // are rolled back with it
+    let res: TransactionResult<(), ()> = db.transaction(|tx| {
+        tx.merge(b"k", Counter::delta(-n))?;
+        let merged = tx.merge(b"k", Counter::delta(5))?.unwrap();
+        assert_eq!(Counter::decode(&merged), Some(5));
+        abort(())
+    });
+    assert_eq!(res, Err(TransactionError::Abort(())));
+    assert_eq!(Counter::decode(&db.get(b"k")?.unwrap()), Some(n));
+
+    let res: TransactionResult<()> = db.transaction(|tx| {
+        tx.merge(b"k", Counter::delta(-n))?;
+        tx.merge(b"k", Counter::delta(5))?;
+        Ok(())
+    });
+    res.unwrap();
+    assert_eq!(Counter::decode(&db.get(b"k")?.unwrap()), Some(5));
+
+    let set = db.open_tree("set")?;
+    set.set_merge_operator(OrSet::merge);
+    set.merge(b"k", OrSet::add(b"a", db.generate_id()?))?;
+    set.merge(b"k", OrSet::add(b"b", db.generate_id()?))?;
+    let observed = set.get(b"k")?.unwrap();
+    let res: TransactionResult<()> = set.transaction(|tx| {
+        tx.merge(b"k", OrSet::remove(&observed, b"a"))?;
+        Ok(())
+    });
+    res.unwrap();
+    let elements = set.get(b"k")?.unwrap();
+    assert_eq!(OrSet::elements(&elements), Some(vec![&b"b"[..]]));
+
+    Ok(())
+}
+
+#[test]
+fn tree_value_log() -> Result<()> {
+    common::setup_logger();
+
+    let path = std::env::temp_dir().join("test_tree_value_log");
+    let checkpoint = std::env::temp_dir().join("test_tree_value_log_copy");
+    let _ = std::fs::remove_dir_all(&path);
+    let _ = std::fs::remove_dir_all(&checkpoint);
+
+    let config = Config::new().path(&path).value_log_threshold(Some(1024));
+    let db = config.open()?;
+
+    let large = |i: u8| IVec::from(vec![i; 16 * 1024]);
+
+    db.insert(b"small", b"v")?;
+    for i in 0..8 {
+        assert_eq!(db.insert([i], large(i))?, None);
+    }
+    assert_eq!(db.insert([0], large(100))?, Some(large(0)));
+    assert!(path.join("values").read_dir()?.next().is_some());
+
+    assert_eq!(db.get(b"small")?, Some(IVec::from(b"v")));
+    assert_eq!(db.get([0])?, Some(large(100)));
+    assert_eq!(db.get_zero_copy([1], |v| v == Some(&*large(1)))?, true);
+    assert_eq!(db.iter().values().next_back().unwrap()?, IVec::from(b"v"));
+    let values: Vec<IVec> = db.range([1]..[3]).values().collect::<Result<_>>()?;
+    assert_eq!(values, vec![large(1), large(2)]);
+
+    assert_eq!(
+        db.compare_and_swap([1], Some(large(1)), Some(large(101)))?,
+        Ok(())
+    );
+    let res: TransactionResult<()> = db.transaction(|tx| {
+        assert_eq!(tx.get([2])?, Some(large(2)));
+        tx.insert(&[2], large(102))?;
+        Ok(())
+    });
+    res.unwrap();
+    assert_eq!(db.get([2])?, Some(large(102)));
+
+    // overwrite everything a few times so that the earlier
+    // value log files are mostly garbage
+    for round in 0..4 {
+        for i in 0..8 {
+            db.insert([i], large(i + round * 8))?;
+        }
+    }
+
+    let size = db.size_on_disk()?;
+    assert!(db.collect_value_log_garbage()? > 0);
+    assert!(db.size_on_disk()? < size);
+    for i in 0..8 {
+        assert_eq!(db.get([i])?, Some(large(i + 24)));
+    }
+
+    db.checkpoint(&checkpoint)?;
+    drop(db);
+
+    // the value log can't be turned off
+    match Config::new().path(&path).open() {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+
+    for dir in &[&path, &checkpoint] {
+        let db = Config::new().path(dir).value_log_threshold(Some(64)).open()?;
+        let values: Vec<IVec> = db.iter().values().collect::<Result<_>>()?;
+        let mut expected: Vec<IVec> = (24..32).map(large).collect();
+        expected.push(IVec::from(b"v"));
+        assert_eq!(values, expected);
+    }
+
+    let _ = std::fs::remove_dir_all(&path);
+    let _ = std::fs::remove_dir_all(&checkpoint);
+
+    Ok(())
+}
+
+#[test]
+fn tree_value_log_corrupt_file() -> Result<()> {
+    common::setup_logger();
+
+    let path = std::env::temp_dir().join("test_tree_value_log_corrupt_file");
+    let _ = std::fs::remove_dir_all(&path);
+
+    let config = Config::new().path(&path).value_log_threshold(Some(1024));
+    let large = |i: u8| IVec::from(vec![i; 8 * 1024]);
+
+    let db = config.open()?;
+    for i in 0..4 {
+        db.insert([i], large(i))?;
+    }
+    for i in 0..3 {
+        db.insert([i], large(i + 10))?;
+    }
+    drop(db);
+
+    // the first entry of the file is garbage, but the live one
+    // after it can't be found once it's corrupted
+    let file = path.join("values").join("0");
+    let mut bytes = std::fs::read(&file)?;
+    bytes[100] ^= 0xFF;
+    std::fs::write(&file, bytes)?;
+
+    let db = config.open()?;
+    assert_eq!(db.collect_value_log_garbage()?, 0);
+    assert!(file.exists());
+    assert_eq!(db.get([3])?, Some(large(3)));
+    drop(db);
+
+    let _ = std::fs::remove_dir_all(&path);
+
+    Ok(())
+}
+
+#[test]
+fn tree_value_readers() -> Result<()> {
+    use std::io::Read;
+
+    common::setup_logger();
+
+    let path = std::env::temp_dir().join("test_tree_value_readers");
+    let _ = std::fs::remove_dir_all(&path);
+
+    let config = Config::new().path(&path).value_log_threshold(Some(1024));
+    let db = config.open()?;
+
+    let large = |i: u8| vec![i; 256 * 1024 + 7];
+    fn read_all(tree: &Tree, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut buf = vec![];
+        if let Some(mut reader) = tree.get_reader(key)? {
+            let _ = reader.read_to_end(&mut buf)?;
+            Ok(Some(buf))
+        } else {
+            Ok(None)
+        }
+    }
+
+    let mut subscriber = db.watch_prefix(b"big");
+
+    assert_eq!(db.insert_reader(b"small", &b"v"[..])?, 1);
+    assert_eq!(db.insert_reader(b"big", &large(1)[..])?, 256 * 1024 + 7);
+    assert_eq!(db.get(b"small")?, Some(IVec::from(b"v")));
+    assert_eq!(db.get(b"big")?, Some(IVec::from(large(1))));
+    assert_eq!(read_all(&db, b"small")?, Some(b"v".to_vec()));
+    assert_eq!(read_all(&db, b"big")?, Some(large(1)));
+    assert_eq!(read_all(&db, b"missing")?, None);
+
+    for (_, key, value) in &subscriber.next().unwrap() {
+        assert_eq!(key, b"big");
+        assert_eq!(value, &Some(IVec::from(large(1))));
+    }
+
+    // values inserted as a whole can be read in chunks
+    db.insert(b"whole", large(2))?;
+    assert_eq!(read_all(&db, b"whole")?, Some(large(2)));
+
+    // streamed values are collected like the others
+    for i in 3..8 {
+        assert_eq!(db.insert_reader(b"big", &large(i)[..])?, 256 * 1024 + 7);
+    }
+    assert!(db.collect_value_log_garbage()? > 0);
+    assert_eq!(read_all(&db, b"big")?, Some(large(7)));
+    assert_eq!(db.get(b"whole")?, Some(IVec::from(large(2))));
+    drop(subscriber);
+    drop(db);
+
+    // a value that was being streamed during a crash is discarded
+    std::fs::create_dir_all(path.join("streams"))?;
+    std::fs::write(path.join("streams").join("1000"), b"torn")?;
+
+    let db = config.open()?;
+    assert!(!path.join("streams").exists());
+    assert_eq!(read_all(&db, b"big")?, Some(large(7)));
+    assert_eq!(read_all(&db, b"small")?, Some(b"v".to_vec()));
+    drop(db);
+
+    let _ = std::fs::remove_dir_all(&path);
+
+    Ok(())
+}
+
+#[test]
+fn tree_bloom_filters() -> Result<()> {
+    common::setup_logger();
+
+    let config = Config::new()
+        .temporary(true)
+        .bloom_bits_per_key(Some(10))
+        .flush_every_ms(None);
+    let db = config.open()?;
+
+    let key = |i: u32| i.to_be_bytes();
+    for i in (0..4096).step_by(2) {
+        db.insert(key(i), vec![0; 64])?;
+    }
+
+    // reading builds the filters, which then answer the reads
+    // of missing keys, and learn about new ones
+    for _ in 0..2 {
+        for i in 0..4096 {
+            assert_eq!(db.get(key(i))?.is_some(), i % 2 == 0, "key {}", i);
+        }
+    }
+    for i in (1..4096).step_by(4) {
+        db.insert(key(i), vec![1; 64])?;
+    }
+    for i in 0..4096 {
+        assert_eq!(db.get(key(i))?.is_some(), i % 4 != 3, "key {}", i);
+    }
+
+    // splits and merges don't lose keys
+    for i in 4096..8192 {
+        db.insert(key(i), vec![2; 64])?;
+        assert!(db.contains_key(key(i))?);
+    }
+    for i in 0..6000 {
+        db.remove(key(i))?;
+    }
+    for i in 0..8192 {
+        assert_eq!(db.get(key(i))?.is_some(), i >= 6000, "key {}", i);
+    }
+
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let db = db.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in (t..4096).step_by(4) {
+                    db.insert(key(10_000 + i), vec![3; 64])?;
+                    assert!(db.get(key(10_000 + i))?.is_some());
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    for i in 0..4096 {
+        assert!(db.get(key(10_000 + i))?.is_some(), "key {}", i);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn tree_iter_seek() -> Result<()> {
+    common::setup_logger();
+
+    let config = Config::new().temporary(true).flush_every_ms(None);
+    let db = config.open()?;
+    let left = db.open_tree("left")?;
+    let right = db.open_tree("right")?;
+
+    let key = |i: u32| IVec::from(&i.to_be_bytes());
+    for i in 0..2000 {
+        if i % 3 == 0 {
+            left.insert(key(i), vec![])?;
+        }
+        if i % 5 == 0 {
+            right.insert(key(i), vec![])?;
+        }
+    }
+
+    // a merge join that seeks each side to the other
+    let mut joined = vec![];
+    let mut a = left.iter();
+    let mut b = right.iter();
+    let (mut x, mut y) = (a.next(), b.next());
+    while let (Some(Ok((ka, _))), Some(Ok((kb, _)))) = (&x, &y) {
+        if ka == kb {
+            joined.push(ka.clone());
+            x = a.next();
+            y = b.next();
+        } else if ka < kb {
+            a.seek(kb);
+            x = a.next();
+        } else {
+            b.seek(ka);
+            y = b.next();
+        }
+    }
+    let expected: Vec<IVec> = (0..2000).step_by(15).map(key).collect();
+    assert_eq!(joined, expected);
+
+    // seeks stay within the range of the iterator
+    let mut iter = left.range(key(300)..key(600));
+    iter.seek(key(0));
+    assert_eq!(iter.next().unwrap()?.0, key(300));
+    iter.seek(key(301));
+    assert_eq!(iter.next().unwrap()?.0, key(303));
+    iter.seek(key(600));
+    assert!(iter.next().is_none());
+    iter.seek(key(450));
+    assert_eq!(iter.next().unwrap()?.0, key(450));
+
+    iter.seek_for_prev(key(1000));
+    assert_eq!(iter.next_back().unwrap()?.0, key(597));
+    iter.seek_for_prev(key(500));
+    assert_eq!(iter.next_back().unwrap()?.0, key(498));
+
+    // the front and the back still meet
+    let rest: Vec<IVec> = iter.keys().collect::<Result<_>>()?;
+    let expected: Vec<IVec> = (453..498).step_by(3).map(key).collect();
+    assert_eq!(rest, expected);
+
+    let mut iter = left.range(key(300)..=key(600));
+    iter.seek_for_prev(key(2000));
+    assert_eq!(iter.next_back().unwrap()?.0, key(600));
+    iter.seek_for_prev(key(0));
+    assert!(iter.next_back().is_none());
+
+    Ok(())
+}
+
+#[test]
+fn tree_keys_and_values_only() -> Result<()> {
+    common::setup_logger();
+
+    let config = Config::new().temporary(true).value_log_threshold(Some(64));
+    let db = config.open()?;
+    let reverse =
+        TreeConfig { order: KeyOrder::Reverse, ..TreeConfig::default() };
+    let tree = db.open_tree_with("reverse", reverse)?;
+
+    let value = |i: u8| IVec::from(vec![i; 1024]);
+    for i in 0..100 {
+        tree.insert([i], value(i))?;
+    }
+
+    let keys: Vec<IVec> = tree.iter().keys().collect::<Result<_>>()?;
+    let expected: Vec<IVec> =
+        (0..100).rev().map(|i| IVec::from(&[i])).collect();
+    assert_eq!(keys, expected);
+
+    let keys: Vec<IVec> =
+        tree.range([20]..=[10]).keys().rev().collect::<Result<_>>()?;
+    let expected: Vec<IVec> = (10..=20).map(|i| IVec::from(&[i])).collect();
+    assert_eq!(keys, expected);
+
+    let values: Vec<IVec> = tree.iter().values().collect::<Result<_>>()?;
+    let expected: Vec<IVec> = (0..100).rev().map(value).collect();
+    assert_eq!(values, expected);
+
+    let values: Vec<IVec> =
+        tree.range([5]..).values().rev().collect::<Result<_>>()?;
+    let expected: Vec<IVec> = (0..=5).map(value).collect();
+    assert_eq!(values, expected);
+
+    Ok(())
+}
+
+#[test]
+fn tree_len_is_maintained() -> Result<()> {
+    common::setup_logger();
+
+    let config = Config::new().temporary(true).flush_every_ms(None);
+    let db = config.open()?;
+    let tree = db.open_tree("len")?;
+    tree.set_merge_operator(|_k: &[u8], _old: Option<&[u8]>, new: &[u8]| {
+        if new.is_empty() {
+            None
+        } else {
+            Some(new.to_vec())
+        }
+    });
+
+    for i in 0..100_u8 {
+        tree.insert([i], vec![i])?;
+    }
+    assert_eq!(tree.len(), 100);
+
+    // writes after the first count keep it up to date
+    tree.insert([0], vec![1])?;
+    tree.insert([100], vec![])?;
+    let _ = tree.remove([1])?;
+    let _ = tree.remove([1])?;
+    let none: Option<&[u8]> = None;
+    tree.compare_and_swap([2], Some(vec![2]), none)?.unwrap();
+    tree.compare_and_swap([101], none, Some(vec![]))?.unwrap();
+    let _ = tree.merge([3], vec![])?;
+    let _ = tree.merge([102], vec![1])?;
+
+    let mut batch = Batch::default();
+    batch.insert(&[103], vec![]);
+    batch.remove(&[4]);
+    tree.apply_batch(batch)?;
+
+    tree.transaction::<_, _, ()>(|tx| {
+        tx.insert(&[104], vec![])?;
+        tx.remove(&[5])?;
+        Ok(())
+    })
+    .unwrap();
+
+    assert_eq!(tree.iter().count(), 100);
+    assert_eq!(tree.len(), 100);
+
+    assert_eq!(tree.remove_range(..[50])?, 45);
+    assert_eq!(tree.len(), 55);
+    tree.clear()?;
+    assert_eq!(tree.len(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn tree_len_is_persisted() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_len_is_persisted";
+    let _ = std::fs::remove_dir_all(path);
+    let config = Config::new().path(path);
+
+    {
+        let db = config.open()?;
+        let tree = db.open_tree("len")?;
+        for i in 0..100_u8 {
+            tree.insert([i], vec![i])?;
+            db.insert([i], vec![i])?;
+        }
+        let _ = tree.remove([0])?;
+
+        // the default tree of the `Db` is the tenant of its name
+        let default = db.open_tree(b"__sled__default")?;
+        let _ = default.remove([0])?;
+        assert_eq!(db.len(), 99);
+    }
+
+    {
+        let db = config.open()?;
+        assert_eq!(db.len(), 99);
+        let tree = db.open_tree("len")?;
+        assert_eq!(tree.len(), 99);
+        tree.insert([200], vec![])?;
+
+        assert!(db.rename_tree("len", "renamed", false)?);
+        let renamed = db.open_tree("renamed")?;
+        assert_eq!(renamed.len(), 100);
+        renamed.insert([201], vec![])?;
+
+        let dropped = db.open_tree("dropped")?;
+        dropped.insert([0], vec![])?;
+        assert!(db.drop_tree("dropped")?);
+        drop(dropped);
+    }
+
+    let db = config.open()?;
+    assert_eq!(db.open_tree("renamed")?.len(), 101);
+    assert_eq!(db.open_tree("dropped")?.len(), 0);
+    assert_eq!(db.len(), 99);
+    drop(db);
+
+    let _ = std::fs::remove_dir_all(path);
+
+    Ok(())
+}
+
+#[test]
+fn tree_size_of_range() -> Result<()> {
+    common::setup_logger();
+
+    let config = Config::new().temporary(true).flush_every_ms(None);
+    let db = config.open()?;
+
+    let key = |i: u32| i.to_be_bytes();
+    const N: u32 = 50_000;
+    for i in 0..N {
+        db.insert(key(i), vec![0; 12])?;
+    }
+
+    assert_eq!(db.size_of_range(key(10)..key(20))?, (160, 10));
+    assert_eq!(db.size_of_range(key(20)..key(10))?, (0, 0));
+
+    let ranges = [(0, N), (0, N / 2), (N / 4, N - N / 4), (N / 10, N / 5)];
+    for &(lo, hi) in &ranges {
+        let (bytes, keys) = db.size_of_range(key(lo)..key(hi))?;
+        let expected = u64::from(hi - lo);
+        assert!(
+            keys > expected / 2 && keys < expected * 2,
+            "estimated {} keys in {}..{}",
+            keys,
+            lo,
+            hi
+        );
+        assert_eq!(bytes, keys * 16);
+    }
+
+    let (_, keys) = db.size_of_range::<&[u8], _>(..)?;
+    assert!(keys > u64::from(N) / 2 && keys < u64::from(N) * 2);
+
+    Ok(())
+}
+
+#[test]
+fn tree_sample_keys() -> Result<()> {
+    common::setup_logger();
+
+    let config = Config::new().temporary(true).flush_every_ms(None);
+    let db = config.open()?;
+    assert_eq!(db.sample_keys(10)?, Vec::<IVec>::new());
+
+    // keys are inserted in an order that leaves some
+    // leaves much fuller than others
+    const N: u32 = 20_000;
+    for i in 0..N {
+        db.insert((i * 7919 % N).to_be_bytes(), vec![])?;
+    }
+
+    let sample = db.sample_keys(4000)?;
+    assert_eq!(sample.len(), 4000);
+
+    let mut quarters = [0_usize; 4];
+    for key in &sample {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(key);
+        let i = u32::from_be_bytes(buf);
+        assert!(i < N);
+        quarters[(i / (N / 4)) as usize] += 1;
+    }
+    for &count in &quarters {
+        assert!(count > 800 && count < 1200, "unbalanced {:?}", quarters);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn tree_bulk_load() -> Result<()> {
+    common::setup_logger();
+
+    let path = std::env::temp_dir().join("test_tree_bulk_load");
+    let _ = std::fs::remove_dir_all(&path);
+
+    const N: u32 = 20_000;
+    let key = |i: u32| IVec::from(&(i * 2).to_be_bytes());
+    let value = |i: u32| IVec::from(vec![0; (i % 64) as usize]);
+
+    {
+        let db = Config::new().path(&path).flush_every_ms(None).open()?;
+        let tree = db.open_tree("loaded")?;
+
+        // keys that are out of order leave the tree empty
+        let unsorted = vec![(key(1), value(1)), (key(0), value(0))];
+        assert!(tree.bulk_load(unsorted).is_err());
+        assert!(tree.is_empty());
+
+        let pairs = (0..N).map(|i| (key(i), value(i)));
+        assert_eq!(tree.bulk_load(pairs)?, N as usize);
+        assert!(tree.bulk_load(vec![(key(N), value(N))]).is_err());
+
+        assert_eq!(tree.len(), N as usize);
+        assert_eq!(tree.get(key(777))?, Some(value(777)));
+        assert_eq!(tree.get(&(777_u32 * 2 + 1).to_be_bytes())?, None);
+        let keys: Vec<IVec> =
+            tree.iter().keys().rev().collect::<Result<_>>()?;
+        let expected: Vec<IVec> = (0..N).rev().map(key).collect();
+        assert_eq!(keys, expected);
+        assert_eq!(tree.range(key(10)..key(13)).count(), 3);
+
+        // the loaded nodes are split and merged by later writes
+        for i in 0..N / 2 {
+            tree.insert(&(i * 2 + 1).to_be_bytes(), vec![1; 100])?;
+            let _ = tree.remove(key(i + N / 2))?;
+        }
+        assert_eq!(tree.len(), N as usize);
+        tree.flush()?;
+    }
+
+    let db = Config::new().path(&path).open()?;
+    let tree = db.open_tree("loaded")?;
+    assert_eq!(tree.len(), N as usize);
+    assert_eq!(tree.get(key(777))?, Some(value(777)));
+    assert_eq!(tree.get(key(N - 1))?, None);
+    drop(tree);
+    assert!(db.drop_tree("loaded")?);
+
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+
+    Ok(())
+}
+
+#[test]
+fn tree_export_import_file() -> Result<()> {
+    common::setup_logger();
+
+    let path = std::env::temp_dir().join("test_tree_export_import_file");
+    let db = Config::new().temporary(true).open()?;
+    let other = Config::new().temporary(true).open()?;
+
+    let reverse =
+        TreeConfig { order: KeyOrder::Reverse, ..TreeConfig::default() };
+    let tree = db.open_tree_with("tree", reverse)?;
+    tree.insert(b"", b"empty")?;
+    for i in 0..1000_u32 {
+        tree.insert(i.to_be_bytes(), vec![1; (i % 100) as usize])?;
+    }
+    // writes go on during the export, which only holds the keys
+    // that the tree had when it started
+    let writer = {
+        let tree = tree.clone();
+        std::thread::spawn(move || -> Result<()> {
+            for i in 1000..2000_u32 {
+                tree.insert(i.to_be_bytes(), vec![2; 10])?;
+            }
+            Ok(())
+        })
+    };
+    let exported = tree.export_to_file(&path)?;
+    writer.join().unwrap()?;
+    assert!((1001..=2001).contains(&exported));
+    for i in 1000..2000_u32 {
+        let _ = tree.remove(i.to_be_bytes())?;
+    }
+    assert_eq!(tree.export_to_file(&path)?, 1001);
+
+    // the file can only be loaded into an empty tree that
+    // sorts its keys in the same order
+    let lexicographic = other.open_tree("lexicographic")?;
+    assert!(lexicographic.import_from_file(&path).is_err());
+    assert!(lexicographic.is_empty());
+    let imported = other.open_tree_with("imported", reverse)?;
+    imported.insert(b"a", b"b")?;
+    assert!(imported.import_from_file(&path).is_err());
+    let _ = imported.remove(b"a")?;
+
+    assert_eq!(imported.import_from_file(&path)?, 1001);
+    assert_eq!(imported.checksum()?, tree.checksum()?);
+    assert_eq!(imported.first()?, tree.first()?);
+    assert_eq!(imported.get(b"")?, Some(IVec::from(b"empty")));
+
+    // a corrupt file leaves the tree empty
+    let mut bytes = std::fs::read(&path)?;
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 1;
+    std::fs::write(&path, &bytes)?;
+    let corrupted = other.open_tree_with("corrupted", reverse)?;
+    assert!(corrupted.import_from_file(&path).is_err());
+    assert!(corrupted.is_empty());
+
+    std::fs::remove_file(&path)?;
+
+    Ok(())
+}
+
+#[test]
+fn tree_dump_and_load() -> Result<()> {
+    use sled::dump::{Dump, Encoding, Format};
+
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    db.insert(b"k", b"v")?;
+    let tree = db.open_tree("quoted, \"name\"\n")?;
+    for i in 0..100_u32 {
+        tree.insert(i.to_be_bytes(), vec![b'"'; i as usize % 7])?;
+    }
+    tree.insert(b"", b"line\nbreak,\r\t\\\x01\xe2\x98\x83")?;
+    tree.insert(b"\xff", b"")?;
+
+    let encodings = [Encoding::Hex, Encoding::Utf8, Encoding::Base64];
+    for &format in &[Format::Json, Format::Csv] {
+        for &values in &encodings {
+            let dump = Dump::new(format).values(values);
+            let mut out = vec![];
+            assert_eq!(dump.write(&db, &mut out)?, 103);
+
+            let copy = Config::new().temporary(true).open()?;
+            assert_eq!(dump.load(&copy, &out[..])?, 103);
+            assert_eq!(copy.checksum()?, db.checksum()?);
+        }
+    }
+
+    // keys that aren't valid UTF-8 can't be written as Utf8
+    let utf8 = Dump::new(Format::Json).keys(Encoding::Utf8);
+    assert!(utf8.write(&db, &mut vec![]).is_err());
+    db.drop_tree("quoted, \"name\"\n")?;
+    let mut out = vec![];
+    assert_eq!(utf8.write(&db, &mut out)?, 1);
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "{\"tree\":\"__sled__default\",\"key\":\"k\",\"value\":\"76\"}\n"
+    );
+
+    // records are inserted up to the first invalid one
+    let copy = Config::new().temporary(true).open()?;
+    let csv = "tree,key,value\n746f,6b31,7631\n746f,6b32\n746f,6b33,7633\n";
+    let dump = Dump::new(Format::Csv);
+    assert!(dump.load(&copy, csv.as_bytes()).is_err());
+    assert_eq!(copy.open_tree(b"to")?.len(), 1);
+    assert!(dump.load(&copy, &b"746f,6b31,7631\n"[..]).is_err());
+    let json = "\n { \"value\" : \"\", \"key\":\"\\u0030a\", \"tree\":\"\"}\n";
+    assert_eq!(Dump::new(Format::Json).load(&copy, json.as_bytes())?, 1);
+    assert_eq!(copy.open_tree(b"")?.get(b"\x0a")?, Some(IVec::default()));
+
+    Ok(())
+}
+
+#[test]
+fn tree_verify_integrity() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_verify_integrity";
+    let _ = std::fs::remove_dir_all(path);
+    let config = Config::new().path(path);
+
+    {
+        let db = config.open()?;
+        let tree = db.open_tree("numbers")?;
+        for i in 0..10_000_u32 {
+            tree.insert(i.to_be_bytes(), &i.to_le_bytes())?;
+        }
+        for i in (0..10_000_u32).filter(|i| i % 3 != 0) {
+            let _ = tree.remove(i.to_be_bytes())?;
+        }
+        db.insert(b"k", b"v")?;
+        let _ = db.open_tree("empty")?;
+
+        let report = db.verify_integrity()?;
+        assert!(report.is_healthy(), "{:?}", report.problems);
+        assert_eq!(report.trees, 3);
+        assert_eq!(report.keys, 3335);
+        assert!(report.nodes > 3);
+
+        // the hidden trees of indexes and expiring keys are
+        // checked as well, while writes go on
+        let parity = |k: &[u8], _v: &[u8]| Some(vec![k[3] % 2]);
+        let _parity = tree.create_index("parity", parity)?;
+        db.insert_with_ttl(b"expiring", b"v", Duration::from_secs(60))?;
+        let writer = {
+            let tree = tree.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 10_000..20_000_u32 {
+                    tree.insert(i.to_be_bytes(), &i.to_le_bytes())?;
+                }
+                Ok(())
+            })
+        };
+        let report = db.verify_integrity()?;
+        writer.join().unwrap()?;
+        assert!(report.is_healthy(), "{:?}", report.problems);
+        assert_eq!(report.trees, 5);
+        db.flush()?;
+    }
+
+    // the nodes are read back from the log after a restart
+    let db = config.open()?;
+    let report = db.verify_integrity()?;
+    assert!(report.is_healthy(), "{:?}", report.problems);
+    assert_eq!(report.trees, 5);
+    drop(db);
+
+    std::fs::remove_dir_all(path)?;
+
+    Ok(())
+}
+
+#[test]
+fn tree_space_usage() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new()
+        .temporary(true)
+        .segment_size(4096)
+        .value_log_threshold(Some(256))
+        .open()?;
+    let large = db.open_tree("large")?;
+    let small = db.open_tree("small")?;
+    for round in 0..4_u8 {
+        for i in 0..200_u32 {
+            large.insert(i.to_be_bytes(), vec![round; 1024])?;
+        }
+    }
+    small.insert(b"k", b"v")?;
+    db.flush()?;
+
+    let usage = db.space_usage()?;
+    assert_eq!(usage.live + usage.dead, usage.on_disk);
+    assert!(usage.dead > 0);
+    for segment in &usage.segments {
+        assert_eq!(segment.live + segment.dead, 4096, "{:?}", segment);
+    }
+    assert!(usage.segments.iter().any(|s| s.state == SegmentState::Active));
+    let log_live: u64 = usage.segments.iter().map(|s| s.live).sum();
+    assert!(log_live > 0);
+
+    let space = |name: &[u8]| {
+        usage.trees.iter().find(|t| &*t.tree == name).unwrap().clone()
+    };
+    let large_space = space(b"large");
+    assert!(large_space.value_log >= 200 * 1024, "{:?}", large_space);
+    assert!(large_space.nodes > 1);
+    let small_space = space(b"small");
+    // the root of a tree is an index node above its first leaf
+    assert_eq!(small_space.nodes, 2);
+    assert_eq!(small_space.value_log, 0);
+    assert!(small_space.live() < large_space.live());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn tree_shrink_to_fit() -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    common::setup_logger();
+
+    let path = "test_tree_shrink_to_fit";
+    let _ = std::fs::remove_dir_all(path);
+    // nothing is cleaned or written in the background, so the
+    // free segments don't change between the measurements
+    let config =
+        Config::new().path(path).segment_size(4096).flush_every_ms(None);
+    let allocated = || -> Result<u64> {
+        Ok(std::fs::metadata(format!("{}/db", path))?.blocks() * 512)
+    };
+
+    {
+        let db = config.open()?;
+        for round in 0..20_u8 {
+            for i in 0..500_u32 {
+                db.insert(i.to_be_bytes(), vec![round; 100])?;
+            }
+        }
+        for i in 50..500_u32 {
+            db.remove(i.to_be_bytes())?;
+        }
+        db.flush()?;
+
+        let before = allocated()?;
+        let shrunk = db.shrink_to_fit()?;
+        assert!(allocated()? + shrunk <= before);
+        assert_eq!(db.shrink_to_fit()?, 0);
+    }
+
+    // every segment that holds no live page is found to be free
+    // when the log is recovered, and is given back right away
+    let before = allocated()?;
+    let db = config.open()?;
+    assert!(allocated()? < before);
+    assert_eq!(db.shrink_to_fit()?, 0);
+
+    assert_eq!(db.len(), 50);
+    for i in 0..50_u32 {
+        assert_eq!(db.get(i.to_be_bytes())?, Some(vec![19; 100].into()));
+    }
+    drop(db);
+
+    std::fs::remove_dir_all(path)?;
+
+    Ok(())
+}
+
+#[test]
+fn tree_compact() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).segment_size(4096).open()?;
+    let tree = db.open_tree("compacted")?;
+    for round in 0..10_u8 {
+        for i in 0..200_u32 {
+            db.insert(i.to_be_bytes(), vec![round; 100])?;
+            tree.insert(i.to_be_bytes(), vec![round; 100])?;
+        }
+    }
+    db.flush()?;
+
+    assert!(db.compact(CompactOptions::new().range(..10_u32.to_be_bytes()))
+        .is_err());
+    assert!(db.compact(CompactOptions::new().tree("missing")).is_err());
+
+    let ranged = db.compact(
+        CompactOptions::new()
+            .tree("compacted")
+            .range(10_u32.to_be_bytes()..20_u32.to_be_bytes())
+            .live_threshold(100),
+    )?;
+    assert_eq!(ranged.remaining, 0);
+
+    let reports = Arc::new(AtomicUsize::new(0));
+    let reports2 = reports.clone();
+    let progress = db.compact(
+        CompactOptions::new().live_threshold(100).on_progress(move |_| {
+            reports2.fetch_add(1, SeqCst);
+        }),
+    )?;
+    assert_eq!(progress.remaining, 0);
+    assert!(reports.load(SeqCst) >= 2);
+
+    for i in 0..200_u32 {
+        assert_eq!(db.get(i.to_be_bytes())?, Some(vec![9; 100].into()));
+        assert_eq!(tree.get(i.to_be_bytes())?, Some(vec![9; 100].into()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn tree_background_limits() -> Result<()> {
+    common::setup_logger();
+
+    let config = Config::new().temporary(true).segment_size(4096);
+    assert!(config.clone().background_io_limit(Some(0)).open().is_err());
+    assert!(config.clone().background_cpu_limit(Some(0)).open().is_err());
+    assert!(config.clone().background_cpu_limit(Some(101)).open().is_err());
+
+    let db = config
+        .flush_every_ms(Some(1))
+        .background_io_limit(Some(64 * 1024))
+        .background_cpu_limit(Some(10))
+        .open()?;
+    for round in 0..10_u8 {
+        for i in 0..200_u32 {
+            db.insert(i.to_be_bytes(), vec![round; 100])?;
+        }
+    }
+    std::thread::sleep(Duration::from_millis(50));
+    db.flush()?;
+
+    for i in 0..200_u32 {
+        assert_eq!(db.get(i.to_be_bytes())?, Some(vec![9; 100].into()));
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn tree_background_threads() -> Result<()> {
+    common::setup_logger();
+
+    // the name, niceness and allowed cores of every thread, where
+    // names are cut off after 15 bytes
+    let threads = || -> Vec<(String, i64, String)> {
+        let tasks = std::fs::read_dir("/proc/self/task").unwrap();
+        tasks
+            .filter_map(|task| {
+                let task = task.ok()?.path();
+                let comm = std::fs::read_to_string(task.join("comm")).ok()?;
+                let stat = std::fs::read_to_string(task.join("stat")).ok()?;
+                let status =
+                    std::fs::read_to_string(task.join("status")).ok()?;
+                let after_comm = &stat[stat.rfind(')')? + 2..];
+                let nice = after_comm.split(' ').nth(16)?.parse().ok()?;
+                let cores = status
+                    .lines()
+                    .find(|line| line.starts_with("Cpus_allowed_list:"))?
+                    .split_whitespace()
+                    .nth(1)?
+                    .to_owned();
+                Some((comm.trim().to_owned(), nice, cores))
+            })
+            .collect()
+    };
+
+    let config = Config::new().temporary(true);
+    assert!(config.clone().background_threads(0).open().is_err());
+    assert!(config.clone().background_thread_nice(Some(20)).open().is_err());
+    assert!(config
+        .clone()
+        .background_thread_cores(Some(vec![]))
+        .open()
+        .is_err());
+
+    let db = config
+        .background_threads(3)
+        .background_thread_name("bgt".to_owned())
+        .background_thread_nice(Some(5))
+        .background_thread_cores(Some(vec![0]))
+        .open()?;
+    for i in 0..100_u32 {
+        db.insert(i.to_be_bytes(), vec![0; 100])?;
+    }
+    db.flush()?;
+
+    let started = threads();
+    for name in &["bgt-flusher", "bgt-cleaner-1", "bgt-cleaner-2"] {
+        let thread = started.iter().find(|thread| thread.0 == *name);
+        assert_eq!(
+            thread.map(|thread| (thread.1, thread.2.as_str())),
+            Some((5, "0")),
+            "{} is missing or not configured in {:?}",
+            name,
+            started
+        );
+    }
+    assert!(!started.iter().any(|thread| thread.0 == "bgt-cleaner-3"));
+
+    drop(db);
+    assert!(!threads().iter().any(|thread| thread.0.starts_with("bgt")));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn tree_direct_io() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_direct_io";
+    let _ = std::fs::remove_dir_all(path);
+    let config = Config::new()
+        .path(path)
+        .segment_size(64 * 1024)
+        .cache_capacity(16 * 1024)
+        .direct_io(true);
+
+    // values of odd lengths keep the buffers of the log from
+    // starting and ending on the boundaries of blocks
+    let value = |i: u32| vec![i as u8; 1 + i as usize % 777];
+    {
+        let db = config.open()?;
+        for i in 0..2000_u32 {
+            db.insert(i.to_be_bytes(), value(i))?;
+            if i % 100 == 0 {
+                db.flush()?;
+            }
+        }
+        for i in 0..2000_u32 {
+            assert_eq!(db.get(i.to_be_bytes())?, Some(value(i).into()));
+        }
+        db.flush()?;
+    }
+
+    // the log is written in the same format either way
+    for direct_io in &[true, false] {
+        let db = config.clone().direct_io(*direct_io).open()?;
+        for i in 0..2000_u32 {
+            assert_eq!(db.get(i.to_be_bytes())?, Some(value(i).into()));
+        }
+        db.insert(b"reopened", &[*direct_io as u8])?;
+        db.flush()?;
+    }
+
+    let db = config.open()?;
+    assert_eq!(db.get(b"reopened")?, Some(IVec::from(&[0])));
+    drop(db);
+
+    std::fs::remove_dir_all(path)?;
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn tree_mmap_reads() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_mmap_reads";
+    let _ = std::fs::remove_dir_all(path);
+    let config = Config::new()
+        .path(path)
+        .segment_size(64 * 1024)
+        .mmap_reads(true);
+    assert!(config.clone().direct_io(true).open().is_err());
+
+    // pages are read from the log the first time that they are
+    // needed after reopening, and rewriting every value lets the
+    // older segments be cleaned, reused and truncated while they
+    // are mapped
+    let value = |round: u8, i: u32| vec![round; 1 + i as usize % 777];
+    for round in 0..5_u8 {
+        let db = config.open()?;
+        for i in 0..2000_u32 {
+            if round > 0 {
+                let expected = value(round - 1, i);
+                assert_eq!(db.get(i.to_be_bytes())?, Some(expected.into()));
+            }
+            db.insert(i.to_be_bytes(), value(round, i))?;
+        }
+        db.flush()?;
+    }
+
+    let db = config.open()?;
+    for i in 0..2000_u32 {
+        assert_eq!(db.get(i.to_be_bytes())?, Some(value(4, i).into()));
+        db.remove(i.to_be_bytes())?;
+    }
+    db.flush()?;
+    db.shrink_to_fit()?;
+    assert!(db.is_empty());
+    drop(db);
+
+    std::fs::remove_dir_all(path)?;
+    Ok(())
+}
+
+#[test]
+fn tree_checksum() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_checksum";
+    let _ = std::fs::remove_dir_all(path);
+    let config = Config::new()
+        .path(path)
+        .segment_size(4096)
+        .checksum(sled::Checksum::Crc32c);
+
+    // the large values go to the heap files
+    let value = |i: u32| vec![i as u8; 1 + i as usize * 97 % 20_000];
+    {
+        let db = config.open()?;
+        for i in 0..200_u32 {
+            db.insert(i.to_be_bytes(), value(i))?;
+        }
+        db.flush()?;
+    }
+
+    let conf = std::fs::read(format!("{}/conf", path))?;
+    assert!(String::from_utf8_lossy(&conf).contains("checksum: crc32c"));
+    match config.clone().checksum(sled::Checksum::Crc32).open() {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+
+    let db = config.open()?;
+    for i in 0..200_u32 {
+        assert_eq!(db.get(i.to_be_bytes())?, Some(value(i).into()));
+    }
+    drop(db);
+
+    std::fs::remove_dir_all(path)?;
+    Ok(())
+}
+
+#[test]
+fn tree_group_commit() -> Result<()> {
+    common::setup_logger();
+
+    let config = Config::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .snapshot_after_ops(1_000_000)
+        .group_commit_bytes(Some(1024))
+        .group_commit_latency_us(Some(2000))
+        .max_concurrent_reservations(1);
+    let db = config.open()?;
+    let size_before = db.size_on_disk()?;
+
+    // the buffer is written once a batch is reserved in it,
+    // without waiting for a flush
+    for i in 0..50_u32 {
+        db.insert(i.to_be_bytes(), vec![i as u8; 200])?;
+    }
+    let mut waited = 0;
+    while db.size_on_disk()? == size_before {
+        assert!(waited < 1000, "the batch was never written");
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        waited += 1;
+    }
+
+    let threads: Vec<_> = (0..4_u32)
+        .map(|t| {
+            let db = db.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..50_u32 {
+                    let key = ((t + 1) * 1000 + i).to_be_bytes();
+                    db.insert(key, vec![i as u8; 10])?;
+                    db.flush()?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(db.len(), 250);
+
+    match config.max_concurrent_reservations(128).open() {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn tree_sync_policy() -> Result<()> {
+    use sled::SyncPolicy;
+
+    common::setup_logger();
+
+    let path = "test_tree_sync_policy";
+    let config = Config::new()
+        .path(path)
+        .temporary(true)
+        .snapshot_after_ops(1_000_000)
+        .sync_policy(SyncPolicy::OsManaged);
+    let db = config.open()?;
+    let log_len = || std::fs::metadata(format!("{}/db", path)).unwrap().len();
+
+    // nothing reaches the log until a flush
+    let size = log_len();
+    db.insert("os", "managed")?;
+    assert_eq!(log_len(), size);
+
+    db.set_sync_policy(SyncPolicy::EveryWrite)?;
+    db.insert("every", "write")?;
+    let synced = log_len();
+    assert!(synced > size);
+
+    db.set_sync_policy(SyncPolicy::EveryNBytes(1 << 20))?;
+    db.insert("every", "few bytes")?;
+    assert_eq!(log_len(), synced);
+    db.set_sync_policy(SyncPolicy::EveryNBytes(1))?;
+    db.insert("every", "byte")?;
+    assert!(log_len() > synced);
+
+    db.set_sync_policy(SyncPolicy::EveryNMillis(10))?;
+    let flushed = log_len();
+    db.insert("every", "few millis")?;
+    let mut waited = 0;
+    while log_len() == flushed {
+        assert!(waited < 1000, "the flush thread never flushed");
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        waited += 1;
+    }
+
+    match db.set_sync_policy(SyncPolicy::EveryNMillis(0)) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+    match config.sync_policy(SyncPolicy::EveryNBytes(0)).open() {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn tree_write_options() -> Result<()> {
+    use sled::{Durability, SyncPolicy, WriteOptions};
+
+    common::setup_logger();
+
+    let path = "test_tree_write_options";
+    let db = Config::new()
+        .path(path)
+        .temporary(true)
+        .snapshot_after_ops(1_000_000)
+        .sync_policy(SyncPolicy::OsManaged)
+        .open()?;
+    let immediate = WriteOptions { flush: Durability::Immediate };
+    let log_len = || std::fs::metadata(format!("{}/db", path)).unwrap().len();
+
+    let size = log_len();
+    db.insert_with("lazy", "write", WriteOptions::default())?;
+    assert_eq!(log_len(), size);
+
+    db.insert_with("immediate", "write", immediate)?;
+    let synced = log_len();
+    assert!(synced > size);
+
+    // batches write the buffer before them out on their own, so
+    // only the immediate one is checked
+    let mut batch = sled::Batch::default();
+    batch.insert("immediate", "batch");
+    batch.remove("lazy");
+    db.apply_batch_with(batch, immediate)?;
+    assert!(log_len() > synced);
+
+    assert_eq!(db.get("immediate")?, Some(IVec::from("batch")));
+    assert_eq!(db.get("lazy")?, None);
+    Ok(())
+}
+
+#[test]
+fn tree_ingest_session() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_ingest_session";
+    let _ = std::fs::remove_dir_all(path);
+    let config = Config::new().path(path).flush_every_ms(None);
+    let log_len = || std::fs::metadata(format!("{}/db", path)).unwrap().len();
+
+    {
+        let db = config.open()?;
+        let before = log_len();
+        let mut session = db.ingest_session();
+        for i in (0..2000_u32).rev() {
+            session.insert(i.to_be_bytes(), vec![i as u8; 100])?;
+        }
+        session.remove(0_u32.to_be_bytes())?;
+        assert_eq!(log_len(), before);
+        assert!(db.is_empty());
+        assert_eq!(session.finish()?, 2000);
+        assert_eq!(db.len(), 1999);
+
+        // a tree that isn't empty gets the session in batches
+        let mut session = db.ingest_session();
+        session.insert(0_u32.to_be_bytes(), vec![0; 100])?;
+        session.remove(1_u32.to_be_bytes())?;
+        assert_eq!(session.len(), 2);
+        assert_eq!(session.finish()?, 2);
+
+        // writes that are spilled to disk are merged, with the
+        // newest write of a key winning
+        let mut session = db.ingest_session();
+        for i in 2000..4000_u32 {
+            session.insert(i.to_be_bytes(), vec![0; 100])?;
+        }
+        for i in 2000..4000_u32 {
+            session.remove(i.to_be_bytes())?;
+        }
+        assert!(session.len() > 2000);
+        assert_eq!(session.finish()?, 2000);
+        assert_eq!(db.len(), 1999);
+        let leftover = std::fs::read_dir(path)?
+            .filter(|e| {
+                let name = e.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with("txn-spill-")
+            })
+            .count();
+        assert_eq!(leftover, 0);
+
+        // a session that is dropped writes nothing
+        let mut session = db.ingest_session();
+        session.insert("dropped", "")?;
+        drop(session);
+    }
+
+    let db = config.open()?;
+    assert_eq!(db.len(), 1999);
+    assert_eq!(db.get(1_u32.to_be_bytes())?, None);
+    assert_eq!(db.get("dropped")?, None);
+    for i in (0..2000_u32).filter(|i| *i != 1) {
+        let expected = vec![i as u8; 100];
+        assert_eq!(db.get(i.to_be_bytes())?, Some(expected.into()));
+    }
+    drop(db);
+
+    std::fs::remove_dir_all(path)?;
+    Ok(())
+}
+
+#[test]
+fn tree_wait_for_durability() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new()
+        .temporary(true)
+        .sync_policy(sled::SyncPolicy::OsManaged)
+        .open()?;
+
+    db.insert("first", "write")?;
+    let lsn = db.last_lsn();
+    assert!(db.stable_lsn() < lsn);
+    db.wait_for_durability(lsn)?;
+    assert!(db.stable_lsn() >= lsn);
+
+    // an lsn that is already durable returns right away
+    db.wait_for_durability(lsn)?;
+
+    db.insert("second", "write")?;
+    let lsn = db.last_lsn();
+    let async_db = sled::AsyncDb::from(db.clone());
+    block_on(async_db.wait_for_durability(lsn))?;
+    assert!(db.stable_lsn() >= lsn);
+
+    match db.wait_for_durability(db.last_lsn() + 1) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn tree_watch_prefix_since() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_watch_prefix_since";
+    let _ = std::fs::remove_dir_all(path);
+    let config = Config::new().path(path).event_history(Some(8));
+    let timeout = Duration::from_secs(1);
+    let first_key = |event: Event| event.iter().next().unwrap().1.clone();
+
+    let handled = {
+        let db = config.open()?;
+        let tree = db.open_tree("feed")?;
+        let subscriber = tree.watch_prefix(b"k");
+
+        tree.insert(b"k1", b"1")?;
+        let mut batch = Batch::default();
+        batch.insert(b"k2", b"2");
+        batch.remove(b"k1");
+        tree.apply_batch(batch)?;
+
+        let first = subscriber.next_timeout(timeout).unwrap();
+        let second = subscriber.next_timeout(timeout).unwrap();
+        assert!(first.seq() < second.seq());
+        assert_eq!(second.iter().count(), 2);
+
+        // the consumer stops here and misses the writes below
+        tree.insert(b"k3", b"3")?;
+        tree.insert(b"other", b"x")?;
+        let res: TransactionResult<()> = tree.transaction(|tx| {
+            tx.insert(b"k4", b"4")?;
+            Ok(())
+        });
+        res.unwrap();
+        db.flush()?;
+        second.seq()
+    };
+
+    let db = config.open()?;
+    let tree = db.open_tree("feed")?;
+    let subscriber = tree.watch_prefix_since(b"k", handled + 1)?;
+    tree.insert(b"k5", b"5")?;
+
+    let mut seqs = vec![];
+    let mut keys = vec![];
+    for _ in 0..3 {
+        let event = subscriber.next_timeout(timeout).unwrap();
+        seqs.push(event.seq());
+        keys.push(first_key(event));
+    }
+    assert_eq!(keys, vec![IVec::from(b"k3"), b"k4".into(), b"k5".into()]);
+    assert!(seqs.windows(2).all(|w| w[0] < w[1]));
+    assert!(subscriber.next_timeout(Duration::from_millis(100)).is_err());
+
+    // trees without a history can't be replayed
+    let no_history = Config::new().temporary(true).open()?;
+    match no_history.watch_prefix_since(b"k", 0) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other.is_ok()),
+    }
+
+    // older events are dropped once more than 8 are retained
+    for i in 0..8_u8 {
+        tree.insert(b"other", vec![i])?;
+    }
+    match tree.watch_prefix_since(b"k", handled + 1) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other.is_ok()),
+    }
+    let recent = tree.watch_prefix_since(b"other", seqs[2] + 1)?;
+    assert_eq!(recent.take(8).count(), 8);
+    drop(tree);
+    drop(db);
+
+    // the history is discarded when it is no longer retained,
+    // and the events before a new one is created are missing
+    drop(config.clone().event_history(None).open()?);
+    let db = config.open()?;
+    let tree = db.open_tree("feed")?;
+    assert!(tree.watch_prefix_since(b"k", 0).is_err());
+    let since = db.last_lsn() + 1;
+    tree.insert(b"k6", b"6")?;
+    let subscriber = tree.watch_prefix_since(b"k", since)?;
+    let event = subscriber.next_timeout(timeout).unwrap();
+    assert_eq!(first_key(event), IVec::from(b"k6"));
+
+    drop(tree);
+    drop(db);
+    let _ = std::fs::remove_dir_all(path);
+    Ok(())
+}
+
+#[test]
+fn tree_watch_prefix_with() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let config =
+        TreeConfig { order: KeyOrder::Reverse, ..TreeConfig::default() };
+    let tree = db.open_tree_with("filtered", config)?;
+    let timeout = Duration::from_secs(1);
+    let first_key = |event: Event| event.iter().next().unwrap().1.clone();
+
+    let removals = tree.watch_prefix_with(
+        b"k",
+        EventFilter::new().kinds(EventKinds::REMOVE),
+    );
+    let large = tree.watch_prefix_with(
+        b"k",
+        EventFilter::new()
+            .kinds(EventKinds::INSERT | EventKinds::REMOVE)
+            .min_value_len(3),
+    );
+    // the predicate sees the keys decoded for the order of the tree
+    let odd = tree.watch_prefix_with(
+        vec![],
+        EventFilter::new().key_predicate(|key| key.ends_with(b"1")),
+    );
+
+    tree.insert(b"k1", b"a")?;
+    tree.insert(b"k2", b"abc")?;
+    tree.remove(b"k1")?;
+    let mut batch = Batch::default();
+    batch.insert(b"k3", b"a");
+    batch.insert(b"x1", b"abcd");
+    tree.apply_batch(batch)?;
+    tree.compare_and_swap(b"k2", Some(b"abc"), Some(b"abcd"))?.unwrap();
+
+    let event = removals.next_timeout(timeout).unwrap();
+    assert_eq!(first_key(event), IVec::from(b"k1"));
+    assert!(removals.next_timeout(Duration::from_millis(100)).is_err());
+
+    let mut keys = vec![];
+    for _ in 0..3 {
+        keys.push(first_key(large.next_timeout(timeout).unwrap()));
+    }
+    // removals pass regardless of the length, and the batch is
+    // not received because its only watched value is too short
+    assert_eq!(keys, vec![IVec::from(b"k2"), b"k1".into(), b"k2".into()]);
+    assert!(large.next_timeout(Duration::from_millis(100)).is_err());
+
+    let mut events = vec![];
+    for _ in 0..3 {
+        events.push(odd.next_timeout(timeout).unwrap());
+    }
+    // the event of the batch still holds every write
+    assert_eq!(events[2].iter().count(), 2);
+    assert!(odd.next_timeout(Duration::from_millis(100)).is_err());
+
+    assert!(EventKinds::ALL.contains(EventKinds::INSERT));
+    assert!(!EventKinds::INSERT.contains(EventKinds::ALL));
+    Ok(())
+}
+
+#[test]
+fn tree_event_previous_values() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let timeout = Duration::from_secs(1);
+    let plain = db.watch_prefix(b"plain");
+    let subscriber = db.watch_prefix_with(
+        b"k",
+        EventFilter::new().previous_values(true),
+    );
+    let changes = |event: Event| -> Vec<_> {
+        event
+            .iter_with_previous()
+            .unwrap()
+            .map(|(_, k, previous, value)| {
+                (k.clone(), previous.clone(), value.clone())
+            })
+            .collect()
+    };
+    let v = |value: &[u8]| Some(IVec::from(value));
+
+    db.insert(b"plain", b"x")?;
+    let event = plain.next_timeout(timeout).unwrap();
+    assert!(event.iter_with_previous().is_none());
+
+    db.insert(b"k1", b"a")?;
+    db.insert(b"k1", b"b")?;
+    let mut batch = Batch::default();
+    batch.remove(b"k1");
+    batch.insert(b"k2", b"c");
+    db.apply_batch(batch)?;
+    let res: TransactionResult<()> = db.transaction(|tx| {
+        tx.insert(b"k2", b"d")?;
+        Ok(())
+    });
+    res.unwrap();
+    db.compare_and_swap(b"k2", Some(b"d"), Some(b"e"))?.unwrap();
+    db.insert_with_ttl(b"k3", b"f", Duration::from_millis(1))?;
+    std::thread::sleep(Duration::from_millis(10));
+    db.insert(b"k3", b"g")?;
+
+    let mut seen = vec![];
+    for _ in 0..7 {
+        seen.extend(changes(subscriber.next_timeout(timeout).unwrap()));
+    }
+    assert_eq!(
+        seen,
+        vec![
+            (IVec::from(b"k1"), None, v(b"a")),
+            (IVec::from(b"k1"), v(b"a"), v(b"b")),
+            (IVec::from(b"k1"), v(b"b"), None),
+            (IVec::from(b"k2"), None, v(b"c")),
+            (IVec::from(b"k2"), v(b"c"), v(b"d")),
+            (IVec::from(b"k2"), v(b"d"), v(b"e")),
+            (IVec::from(b"k3"), None, v(b"f")),
+            // an expired key held no value
+            (IVec::from(b"k3"), None, v(b"g")),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn tree_subscriber_overflow() -> Result<()> {
+    common::setup_logger();
+
+    let config = |capacity: usize, overflow: SubscriberOverflow| {
+        Config::new()
+            .temporary(true)
+            .subscriber_capacity(capacity)
+            .subscriber_overflow(overflow)
+    };
+    let first_key = |event: &Event| event.iter().next().unwrap().1.clone();
+    let keys = |n: u8| (1..=n).map(|i| vec![b'k', b'0' + i]);
+
+    // the oldest events are dropped and counted
+    let db = config(2, SubscriberOverflow::DropOldest).open()?;
+    let subscriber = db.watch_prefix(b"k");
+    for key in keys(5) {
+        db.insert(key, b"v")?;
+    }
+    let mut received = vec![];
+    let mut missed = 0;
+    let timeout = Duration::from_millis(100);
+    while let Ok(event) = subscriber.next_timeout(timeout) {
+        missed += event.missed();
+        received.push(first_key(&event));
+    }
+    // writes that are retried may reserve a place in the queue too
+    assert!(received.len() <= 2);
+    assert!(received.len() as u64 + missed >= 5);
+    assert_eq!(received.last(), Some(&IVec::from(b"k5")));
+    drop(db);
+
+    // the subscriber ends once it has received what was queued
+    let db = config(2, SubscriberOverflow::Disconnect).open()?;
+    let subscriber = db.watch_prefix(b"k");
+    for key in keys(3) {
+        db.insert(key, b"v")?;
+    }
+    let received: Vec<_> = subscriber.map(|event| first_key(&event)).collect();
+    assert!(received.len() <= 2);
+    assert!(!received.contains(&IVec::from(b"k3")));
+    drop(db);
+
+    // writers wait for the subscriber, or for it to be dropped
+    let db = config(1, SubscriberOverflow::Block).open()?;
+    let written = Arc::new(AtomicUsize::new(0));
+    let writer = |db: Db, written: Arc<AtomicUsize>| {
+        std::thread::spawn(move || {
+            for key in keys(3) {
+                db.insert(key, b"v").unwrap();
+                written.fetch_add(1, SeqCst);
+            }
+        })
+    };
+    let consumer = db.watch_prefix(b"k");
+    let thread = writer(db.clone(), written.clone());
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(written.load(SeqCst) <= 1);
+    for _ in 0..3 {
+        consumer.next_timeout(Duration::from_secs(1)).unwrap();
+    }
+    thread.join().unwrap();
+    drop(consumer);
+
+    let subscriber = db.watch_prefix(b"k");
+    let thread = writer(db.clone(), written.clone());
+    std::thread::sleep(Duration::from_millis(100));
+    drop(subscriber);
+    thread.join().unwrap();
+    assert_eq!(written.load(SeqCst), 6);
+
+    match config(0, SubscriberOverflow::Block).open() {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other.is_ok()),
+    }
+    Ok(())
+}
+
+#[test]
+fn tree_transaction_events_are_atomic() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let a = db.open_tree(b"a")?;
+    let b = db.open_tree(b"b")?;
+    let timeout = Duration::from_secs(5);
+    let subscriber = a.watch_prefix(b"");
+
+    const N: u64 = 50;
+    let reader = {
+        let b = b.clone();
+        std::thread::spawn(move || {
+            for _ in 0..N {
+                let event = subscriber.next_timeout(timeout).unwrap();
+                let trees: Vec<_> =
+                    event.iter().map(|(tree, _, _)| tree.name()).collect();
+                assert_eq!(trees, vec![IVec::from(b"a"), IVec::from(b"b")]);
+                for (_, k, v) in &event {
+                    // the write to the other tree is already visible
+                    assert_eq!(b.get(k).unwrap(), *v);
+                }
+            }
+        })
+    };
+
+    for i in 0..N {
+        let key = i.to_be_bytes();
+        let res: TransactionResult<()> = (&a, &b).transaction(|(a, b)| {
+            a.insert(&key, &key)?;
+            b.insert(&key, &key)?;
+            Ok(())
+        });
+        res.unwrap();
+    }
+    reader.join().unwrap();
+
+    let batch_subscriber = db.watch_prefix(b"");
+    let mut batch = Batch::default();
+    batch.insert(b"x", b"1");
+    batch.insert(b"y", b"2");
+    batch.remove(b"z");
+    db.apply_batch(batch)?;
+    let event = batch_subscriber.next_timeout(timeout).unwrap();
+    assert_eq!(event.iter().count(), 3);
+
+    Ok(())
+}
+
+#[test]
+fn tree_watch_key() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let reverse =
+        TreeConfig { order: KeyOrder::Reverse, ..TreeConfig::default() };
+    let other = db.open_tree_with("other", reverse)?;
+    let timeout = Duration::from_millis(100);
+
+    for tree in &[&db as &Tree, &other] {
+        tree.insert(b"k", b"0")?;
+        let watch = tree.watch_key(b"k");
+        tree.insert(b"k2", b"ignored")?;
+        tree.insert(b"", b"ignored")?;
+        assert!(watch.wait_timeout(timeout).is_err());
+
+        let watch = tree.watch_key(b"k");
+        tree.insert(b"k2", b"ignored")?;
+        tree.remove(b"k")?;
+        assert_eq!(watch.wait_timeout(timeout).unwrap(), None);
+
+        let watch = tree.watch_key(b"k");
+        tree.insert(b"k", b"1")?;
+        assert_eq!(block_on(watch), Some(Some(IVec::from(b"1"))));
+    }
+
+    // the same key in another tree of a transaction doesn't count
+    let watch = other.watch_key(b"k");
+    let main: &Tree = &db;
+    let res: TransactionResult<()> = (main, &other).transaction(|(a, b)| {
+        a.insert(b"k", b"a")?;
+        b.insert(b"j", b"b")?;
+        Ok(())
+    });
+    res.unwrap();
+    assert!(watch.wait_timeout(timeout).is_err());
+
+    let is_ready = |value: Option<&IVec>| value == Some(&IVec::from(b"3"));
+    assert_eq!(db.wait_for(b"k", is_ready, timeout)?, None);
+    db.insert(b"k", b"3")?;
+    assert_eq!(
+        db.wait_for(b"k", is_ready, timeout)?,
+        Some(Some(IVec::from(b"3")))
+    );
+    let writer = {
+        let db = db.clone();
+        std::thread::spawn(move || {
+            for i in 0..5_u8 {
+                std::thread::sleep(Duration::from_millis(5));
+                db.insert(b"k", vec![b'4' + i]).unwrap();
+            }
+        })
+    };
+    let value = db.wait_for(
+        b"k",
+        |value| value == Some(&IVec::from(b"6")),
+        Duration::from_secs(5),
+    )?;
+    assert_eq!(value, Some(Some(IVec::from(b"6"))));
+    writer.join().unwrap();
+    let absent = db.wait_for(b"absent", |value| value.is_none(), timeout)?;
+    assert_eq!(absent, Some(None));
+
+    Ok(())
+}
+
+#[test]
+fn tree_pop_min_wait() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let timeout = Duration::from_millis(50);
+
+    assert_eq!(db.pop_min_wait(timeout)?, None);
+    db.insert(b"b", b"2")?;
+    db.insert(b"a", b"1")?;
+    assert_eq!(db.pop_min_wait(timeout)?.unwrap().0, IVec::from(b"a"));
+
+    const N: u32 = 100;
+    let producer = {
+        let db = db.clone();
+        std::thread::spawn(move || {
+            for i in 0..N {
+                if i % 10 == 0 {
+                    std::thread::sleep(Duration::from_millis(2));
+                }
+                db.insert(i.to_be_bytes(), vec![]).unwrap();
+            }
+        })
+    };
+    let consumers: Vec<_> = (0..3)
+        .map(|_| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                let mut popped = vec![];
+                while let Some((k, _)) =
+                    db.pop_min_wait(Duration::from_millis(500)).unwrap()
+                {
+                    popped.push(k);
+                }
+                popped
+            })
+        })
+        .collect();
+    producer.join().unwrap();
+    let mut popped: Vec<IVec> =
+        consumers.into_iter().flat_map(|c| c.join().unwrap()).collect();
+    popped.sort();
+    let mut expected: Vec<IVec> =
+        (0..N).map(|i| IVec::from(&i.to_be_bytes())).collect();
+    expected.push(IVec::from(b"b"));
+    expected.sort();
+    assert_eq!(popped, expected);
+
+    let tree = AsyncTree::from(db.open_tree(b"async")?);
+    let producer = {
+        let tree = tree.blocking().clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            tree.insert(b"job", b"x").unwrap();
+        })
+    };
+    let popped = block_on(tree.pop_min_wait())?;
+    assert_eq!(popped, Some((IVec::from(b"job"), IVec::from(b"x"))));
+    producer.join().unwrap();
+
+    Ok(())
+}
+
+#[test]
+fn tree_pop_n() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let reverse =
+        TreeConfig { order: KeyOrder::Reverse, ..TreeConfig::default() };
+    let tree = db.open_tree_with("reverse", reverse)?;
+    for i in 0..10_u8 {
+        tree.insert(&[i], &[i])?;
+    }
+    tree.insert_with_ttl(&[20], &[20], Duration::from_millis(1))?;
+    std::thread::sleep(Duration::from_millis(10));
+
+    let subscriber = tree.watch_prefix(b"");
+    let keys = |popped: Vec<(IVec, IVec)>| -> Vec<Vec<u8>> {
+        popped.into_iter().map(|(k, _)| k.to_vec()).collect()
+    };
+    assert_eq!(keys(tree.pop_n_min(3)?), vec![vec![9], vec![8], vec![7]]);
+    let event = subscriber.next_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(event.iter().count(), 3);
+    assert_eq!(keys(tree.pop_n_max(2)?), vec![vec![0], vec![1]]);
+    assert!(tree.pop_n_min(0)?.is_empty());
+    assert_eq!(tree.pop_n_min(100)?.len(), 5);
+    assert!(tree.pop_n_max(100)?.is_empty());
+
+    const N: u32 = 1000;
+    for i in 0..N {
+        db.insert(i.to_be_bytes(), vec![])?;
+    }
+    let consumers: Vec<_> = (0..4)
+        .map(|_| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                let mut popped = vec![];
+                loop {
+                    let items = db.pop_n_min(7).unwrap();
+                    if items.is_empty() {
+                        return popped;
+                    }
+                    popped.extend(items.into_iter().map(|(k, _)| k));
+                }
+            })
+        })
+        .collect();
+    let mut popped: Vec<IVec> =
+        consumers.into_iter().flat_map(|c| c.join().unwrap()).collect();
+    popped.sort();
+    let expected: Vec<IVec> =
+        (0..N).map(|i| IVec::from(&i.to_be_bytes())).collect();
+    assert_eq!(popped, expected);
+    assert!(db.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn tree_compare_and_swap_many() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let none: &[(&[u8], Option<&[u8]>, Option<&[u8]>)] = &[];
+    assert_eq!(db.compare_and_swap_many(none)?, Ok(()));
+    let twice: &[(&[u8], Option<&[u8]>, Option<&[u8]>)] =
+        &[(b"k", None, Some(b"1")), (b"k", None, Some(b"2"))];
+    assert!(db.compare_and_swap_many(twice).is_err());
+    assert_eq!(db.get(b"k")?, None);
+
+    // units are moved between the accounts, which always add up
+    let accounts: Vec<IVec> =
+        (0_u8..4).map(|i| IVec::from(vec![b'a', i])).collect();
+    for account in &accounts {
+        db.insert(account, &100_u64.to_be_bytes())?;
+    }
+    let balance = |value: Option<IVec>| -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&value.unwrap());
+        u64::from_be_bytes(bytes)
+    };
+
+    let movers: Vec<_> = (0..4_usize)
+        .map(|t| {
+            let db = db.clone();
+            let accounts = accounts.clone();
+            std::thread::spawn(move || {
+                let mut conflicts = 0;
+                for i in 0..200 {
+                    let from = &accounts[(t + i) % 4];
+                    let to = &accounts[(t + i + 1) % 4];
+                    let from_old = db.get(from).unwrap();
+                    let to_old = db.get(to).unwrap();
+                    let from_new = balance(from_old.clone()).wrapping_sub(1);
+                    let to_new = balance(to_old.clone()).wrapping_add(1);
+                    let swaps = [
+                        (from, from_old, Some(from_new.to_be_bytes())),
+                        (to, to_old, Some(to_new.to_be_bytes())),
+                    ];
+                    if db.compare_and_swap_many(&swaps).unwrap().is_err() {
+                        conflicts += 1;
+                    }
+                }
+                conflicts
+            })
+        })
+        .collect();
+    for _ in 0..50 {
+        let res: TransactionResult<u64> = db.transaction(|tx| {
+            let mut total = 0_u64;
+            for account in &accounts {
+                total = total.wrapping_add(balance(tx.get(account)?));
+            }
+            Ok(total)
+        });
+        assert_eq!(res.unwrap(), 400);
+    }
+    for mover in movers {
+        let _conflicts = mover.join().unwrap();
+    }
+    let total = accounts
+        .iter()
+        .map(|account| balance(db.get(account).unwrap()))
+        .fold(0_u64, u64::wrapping_add);
+    assert_eq!(total, 400);
+
+    Ok(())
+}
+
+#[test]
+fn db_apply_batches() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let trees = (0..5)
+        .map(|i| db.open_tree(format!("tree{}", i)))
+        .collect::<Result<Vec<_>>>()?;
+    let subscriber = trees[0].watch_prefix(b"");
+    trees[3].insert(b"stale", b"x")?;
+    let _ = subscriber.next_timeout(Duration::from_millis(10));
+
+    let mut batches = vec![];
+    for tree in &trees {
+        let mut batch = Batch::default();
+        batch.insert(b"k", tree.name());
+        batch.remove(b"stale");
+        batches.push((tree, batch));
+    }
+    let mut again = Batch::default();
+    again.insert(b"k", b"overwritten");
+    batches.push((&trees[4], again));
+    db.apply_batches(batches)?;
+
+    for tree in &trees[..4] {
+        assert_eq!(tree.get(b"k")?, Some(tree.name()));
+        assert_eq!(tree.get(b"stale")?, None);
+    }
+    assert_eq!(trees[4].get(b"k")?, Some(IVec::from(b"overwritten")));
+
+    let event = subscriber.next_timeout(Duration::from_secs(1)).unwrap();
+    let written: std::collections::BTreeSet<IVec> =
+        event.iter().map(|(tree, _, _)| tree.name()).collect();
+    assert_eq!(written.len(), 5);
+    assert_eq!(event.iter().count(), 10);
+
+    db.apply_batches(Vec::new())?;
+
+    let other = Config::new().temporary(true).open()?;
+    let mut batch = Batch::default();
+    batch.insert(b"k", b"v");
+    let foreign = vec![(&trees[0], Batch::default()), (&*other, batch)];
+    assert!(db.apply_batches(foreign).is_err());
+    assert_eq!(other.get(b"k")?, None);
+
+    Ok(())
+}
+
+#[test]
+fn db_begin_transaction() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let a = db.open_tree(b"a")?;
+    let b = db.open_tree(b"b")?;
+    a.insert(b"k", b"1")?;
+
+    let mut txn = db.begin_transaction();
+    assert_eq!(txn.get(&a, b"k")?, Some(IVec::from(b"1")));
+    txn.insert(&b, b"k", b"2");
+    txn.remove(&a, b"k");
+    assert_eq!(txn.get(&b, b"k")?, Some(IVec::from(b"2")));
+    assert_eq!(txn.get(&a, b"k")?, None);
+    assert_eq!(b.get(b"k")?, None);
+    txn.commit().unwrap();
+    assert_eq!(a.get(b"k")?, None);
+    assert_eq!(b.get(b"k")?, Some(IVec::from(b"2")));
+
+    // a key that was read and then changed fails the commit, even
+    // when it was read as absent
+    let mut txn = db.begin_transaction();
+    assert_eq!(txn.get(&a, b"k")?, None);
+    txn.insert(&b, b"k", b"3");
+    a.insert(b"k", b"elsewhere")?;
+    assert_eq!(txn.commit(), Err(UnabortableTransactionError::Conflict));
+    assert_eq!(b.get(b"k")?, Some(IVec::from(b"2")));
+
+    // two transactions that read the same keys and each write a
+    // different one of them can't both commit, so an invariant
+    // across the keys holds without writing them all
+    a.insert(b"on call 1", b"")?;
+    a.insert(b"on call 2", b"")?;
+    let mut first = db.begin_transaction();
+    let mut second = db.begin_transaction();
+    for txn in &mut [&mut first, &mut second] {
+        assert!(txn.get(&a, b"on call 1")?.is_some());
+        assert!(txn.get(&a, b"on call 2")?.is_some());
+    }
+    first.remove(&a, b"on call 1");
+    second.remove(&a, b"on call 2");
+    first.commit().unwrap();
+    assert_eq!(second.commit(), Err(UnabortableTransactionError::Conflict));
+    assert!(a.get(b"on call 2")?.is_some());
+
+    let mut txn = db.begin_transaction();
+    txn.insert(&a, b"aborted", b"");
+    txn.abort();
+    assert_eq!(a.get(b"aborted")?, None);
+
+    let other = Config::new().temporary(true).open()?;
+    let mut txn = db.begin_transaction();
+    txn.insert(&other, b"k", b"");
+    assert!(txn.commit().is_err());
+
+    // concurrent increments are retried until they don't conflict
+    let counter = |value: Option<IVec>| -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&value.unwrap());
+        u64::from_be_bytes(bytes)
+    };
+    a.insert(b"counter", &0_u64.to_be_bytes())?;
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let db = db.clone();
+            let a = a.clone();
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    loop {
+                        let mut txn = db.begin_transaction();
+                        let value = txn.get(&a, b"counter").unwrap();
+                        let next = counter(value) + 1;
+                        txn.insert(&a, b"counter", &next.to_be_bytes());
+                        match txn.commit() {
+                            Ok(()) => break,
+                            Err(UnabortableTransactionError::Conflict) => {}
+                            Err(e) => panic!("{:?}", e),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(counter(a.get(b"counter")?), 200);
+
+    Ok(())
+}
+
+#[test]
+fn tree_transaction_async() -> Result<()> {
+    common::setup_logger();
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    // returns pending once, like a call to another system would
+    struct YieldOnce(bool);
+    impl std::future::Future for YieldOnce {
+        type Output = ();
+
+        fn poll(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<()> {
+            if self.0 {
+                std::task::Poll::Ready(())
+            } else {
+                self.0 = true;
+                std::task::Poll::Pending
+            }
+        }
+    }
+
+    let db = Config::new().temporary(true).open()?;
+    let counter = |value: Option<IVec>| -> u64 {
+        value.map_or(0, |v| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&v);
+            u64::from_be_bytes(bytes)
+        })
+    };
+
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let db = db.clone();
+            let attempts = attempts.clone();
+            std::thread::spawn(move || {
+                for _ in 0..25 {
+                    let future = db.transaction_async(|tx| {
+                        attempts.fetch_add(1, SeqCst);
+                        async move {
+                            let value = counter(tx.get(b"counter")?);
+                            YieldOnce(false).await;
+                            tx.insert(b"counter", &(value + 1).to_be_bytes());
+                            Ok::<_, ConflictableTransactionError<()>>(())
+                        }
+                    });
+                    assert_send(&future);
+                    block_on(future).unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(counter(db.get(b"counter")?), 100);
+    assert!(attempts.load(SeqCst) >= 100);
+
+    let res = block_on(db.transaction_async(|tx| async move {
+        tx.insert(b"aborted", b"");
+        Err::<(), _>(ConflictableTransactionError::Abort(7))
+    }));
+    assert_eq!(res, Err(TransactionError::Abort(7)));
+    assert_eq!(db.get(b"aborted")?, None);
+
+    Ok(())
+}
+
+#[test]
+fn transaction_retry_policy() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let conflicts = Arc::new(AtomicUsize::new(0));
+    let counted = conflicts.clone();
+    let retry = RetryPolicy::new()
+        .max_attempts(3)
+        .backoff(Duration::from_millis(1), Duration::from_millis(2))
+        .on_conflict(move |attempts| {
+            assert_eq!(attempts as usize, counted.fetch_add(1, SeqCst) + 1);
+        });
+
+    // a transaction that always conflicts is given up on
+    let runs = AtomicUsize::new(0);
+    let res: TransactionResult<()> = db.transaction_with_retry(&retry, |_| {
+        runs.fetch_add(1, SeqCst);
+        Err(ConflictableTransactionError::Conflict)
+    });
+    assert_eq!(res, Err(TransactionError::Conflict));
+    assert_eq!(runs.load(SeqCst), 3);
+    assert_eq!(conflicts.load(SeqCst), 3);
+
+    // as is one whose reads are always changed before it commits
+    conflicts.store(0, SeqCst);
+    let res = block_on(db.transaction_async_with_retry(&retry, |tx| {
+        let db = db.clone();
+        async move {
+            let value = tx.get(b"contended")?;
+            db.insert(b"contended", vec![value.map_or(0, |v| v[0] + 1)])?;
+            tx.insert(b"contended", b"lost");
+            Ok::<_, ConflictableTransactionError<()>>(())
+        }
+    }));
+    assert_eq!(res, Err(TransactionError::Conflict));
+    assert_eq!(conflicts.load(SeqCst), 3);
+    assert_eq!(db.get(b"contended")?, Some(IVec::from(&[2])));
+
+    // and a transaction that stops conflicting commits
+    conflicts.store(0, SeqCst);
+    let res = block_on(db.transaction_async_with_retry(&retry, |tx| {
+        let db = db.clone();
+        async move {
+            if tx.get(b"contended")?.map(|v| v[0]) == Some(2) {
+                db.insert(b"contended", vec![3])?;
+            }
+            tx.insert(b"contended", b"won");
+            Ok::<_, ConflictableTransactionError<()>>(())
+        }
+    }));
+    assert_eq!(res, Ok(()));
+    assert_eq!(conflicts.load(SeqCst), 1);
+    assert_eq!(db.get(b"contended")?, Some(IVec::from(b"won")));
+
+    Ok(())
+}
+
+#[test]
+fn db_begin_locked_transaction() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let a = db.open_tree(b"a")?;
+    let b = db.open_tree(b"b")?;
+    let counter = |value: Option<IVec>| -> u64 {
+        value.map_or(0, |v| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&v);
+            u64::from_be_bytes(bytes)
+        })
+    };
+
+    // the keys are locked in different orders, without deadlocking,
+    // and the locked transactions never conflict
+    let threads: Vec<_> = (0..6)
+        .map(|t| {
+            let db = db.clone();
+            let a = a.clone();
+            let b = b.clone();
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    let keys = if t % 2 == 0 {
+                        vec![(&a, "hot"), (&b, "hot")]
+                    } else {
+                        vec![(&b, "hot"), (&a, "hot"), (&a, "hot")]
+                    };
+                    let mut txn = db.begin_locked_transaction(keys);
+                    for tree in &[&a, &b] {
+                        let next = counter(txn.get(tree, "hot").unwrap()) + 1;
+                        txn.insert(tree, "hot", &next.to_be_bytes());
+                    }
+                    txn.commit().unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(counter(a.get("hot")?), 300);
+    assert_eq!(counter(b.get("hot")?), 300);
+
+    // dropping a transaction releases its locks
+    let txn = db.begin_locked_transaction(vec![(&a, "k")]);
+    txn.abort();
+    let txn = db.begin_locked_transaction(vec![(&a, "k")]);
+    let waiter = {
+        let db = db.clone();
+        let a = a.clone();
+        std::thread::spawn(move || {
+            let mut txn = db.begin_locked_transaction(vec![(&a, "k")]);
+            let value = txn.get(&a, "k").unwrap();
+            txn.commit().unwrap();
+            value
+        })
+    };
+    std::thread::sleep(Duration::from_millis(20));
+    let mut txn = txn;
+    txn.insert(&a, "k", "first");
+    txn.commit().unwrap();
+    assert_eq!(waiter.join().unwrap(), Some(IVec::from("first")));
+
+    // writes that don't lock the key still make it conflict
+    let mut txn = db.begin_locked_transaction(vec![(&a, "k")]);
+    let _ = txn.get(&a, "k")?;
+    a.insert("k", "outside")?;
+    txn.insert(&a, "k", "inside");
+    assert_eq!(txn.commit(), Err(UnabortableTransactionError::Conflict));
+
+    Ok(())
+}
+
+#[test]
+fn db_begin_transaction_with() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let a = db.open_tree(b"a")?;
+    let timeout = Duration::from_millis(20);
+
+    // without locks it is an optimistic transaction
+    let mut txn = db.begin_transaction_with(TxnOptions::new()).unwrap();
+    txn.insert(&a, "k", "v");
+    txn.commit().unwrap();
+    assert_eq!(a.get("k")?, Some(IVec::from("v")));
+
+    // a thread that locks a key that it holds itself times out,
+    // without holding the keys that it did lock
+    let held =
+        db.begin_transaction_with(TxnOptions::new().lock(&a, "k")).unwrap();
+    let options =
+        TxnOptions::new().lock(&a, "a").lock(&a, "k").timeout(timeout);
+    let res = db.begin_transaction_with(options.clone());
+    assert_eq!(res.unwrap_err(), TransactionError::Timeout);
+    let other = db.begin_locked_transaction(vec![(&a, "a")]);
+    drop(other);
+
+    // another thread gets the locks once they are released
+    let waiter = {
+        let db = db.clone();
+        let a = a.clone();
+        let options = options.timeout(Duration::from_secs(10));
+        std::thread::spawn(move || {
+            let mut txn = db.begin_transaction_with(options).unwrap();
+            let value = txn.get(&a, "k").unwrap();
+            txn.commit().unwrap();
+            value
+        })
+    };
+    std::thread::sleep(timeout);
+    let mut held = held;
+    held.insert(&a, "k", "first");
+    held.commit().unwrap();
+    assert_eq!(waiter.join().unwrap(), Some(IVec::from("first")));
+
+    Ok(())
+}
+
+#[test]
+fn db_transaction_spill() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_db_transaction_spill";
+    let _ = std::fs::remove_dir_all(path);
+    let db = Config::new().path(path).open()?;
+    let a = db.open_tree(b"a")?;
+    let b = db.open_tree(b"b")?;
+    let spill_files = || {
+        std::fs::read_dir(path)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with("txn-spill")
+            })
+            .count()
+    };
+    let key = |i: u32| IVec::from(&i.to_be_bytes());
+    const N: u32 = 4000;
+
+    a.insert(key(N), b"removed")?;
+    let subscriber = a.watch_prefix(vec![]);
+    let options = TxnOptions::new().spill_after(4096);
+    let mut txn = db.begin_transaction_with(options.clone()).unwrap();
+    for i in 0..N {
+        txn.insert(&a, key(i), vec![1; 16]);
+        txn.insert(&b, key(i), vec![2; 16]);
+    }
+    assert!(spill_files() > 1);
+
+    // reads see the newest write of each key, spilled or not
+    txn.insert(&a, key(7), vec![3]);
+    txn.remove(&a, key(N));
+    assert_eq!(txn.get(&a, key(7))?, Some(IVec::from(vec![3])));
+    assert_eq!(txn.get(&a, key(8))?, Some(IVec::from(vec![1; 16])));
+    assert_eq!(txn.get(&b, key(N - 1))?, Some(IVec::from(vec![2; 16])));
+    assert_eq!(txn.get(&a, key(N))?, None);
+    assert_eq!(txn.get(&a, key(N + 1))?, None);
+
+    // rolling back drops the writes that were spilled since
+    let savepoint = txn.savepoint();
+    for i in 0..N {
+        txn.insert(&b, key(i), vec![4; 16]);
+    }
+    txn.rollback_to(&savepoint);
+    assert_eq!(txn.get(&b, key(0))?, Some(IVec::from(vec![2; 16])));
+
+    assert_eq!(a.get(key(0))?, None);
+    txn.commit().unwrap();
+    assert_eq!(spill_files(), 0);
+    assert_eq!(a.len(), N as usize);
+    assert_eq!(b.len(), N as usize);
+    assert_eq!(a.get(key(7))?, Some(IVec::from(vec![3])));
+    assert_eq!(a.get(key(8))?, Some(IVec::from(vec![1; 16])));
+    assert_eq!(a.get(key(N))?, None);
+    assert_eq!(b.get(key(0))?, Some(IVec::from(vec![2; 16])));
+
+    // subscribers receive the chunks as events with the same seq
+    let timeout = Duration::from_secs(1);
+    let of_a = |event: &Event| {
+        event.iter().filter(|(tree, ..)| tree.name() == a.name()).count()
+    };
+    let first = subscriber.next_timeout(timeout).unwrap();
+    let mut written = of_a(&first);
+    while written < N as usize + 1 {
+        let event = subscriber.next_timeout(timeout).unwrap();
+        assert_eq!(event.seq(), first.seq());
+        written += of_a(&event);
+    }
+    assert_eq!(written, N as usize + 1);
+
+    // conflicts and dropped transactions remove their files too
+    let mut txn = db.begin_transaction_with(options.clone()).unwrap();
+    let _ = txn.get(&a, key(0))?;
+    for i in 0..N {
+        txn.insert(&a, key(i), vec![5; 16]);
+    }
+    a.insert(key(0), b"elsewhere")?;
+    assert_eq!(txn.commit(), Err(UnabortableTransactionError::Conflict));
+    assert_eq!(spill_files(), 0);
+    assert_eq!(a.get(key(1))?, Some(IVec::from(vec![1; 16])));
+
+    let mut txn = db.begin_transaction_with(options).unwrap();
+    for i in 0..N {
+        txn.insert(&a, key(i), vec![6; 16]);
+    }
+    drop(txn);
+    assert_eq!(spill_files(), 0);
+
+    // the files of an encrypted database would hold its writes in
+    // plaintext
+    let keys =
+        XorKeys { current: Arc::new(AtomicUsize::new(1)), known: vec![1] };
+    let encrypted = Config::new().temporary(true).encryption(keys).open()?;
+    let options = TxnOptions::new().spill_after(4096);
+    match encrypted.begin_transaction_with(options) {
+        Err(TransactionError::Storage(Error::Unsupported(_))) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+
+    drop(db);
+    let _ = std::fs::remove_dir_all(path);
+    Ok(())
+}
+
+#[test]
+fn transaction_savepoints() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let a = db.open_tree(b"a")?;
+    let b = db.open_tree(b"b")?;
+    a.insert("kept", "before")?;
+
+    // only the writes after the savepoint are undone, and the
+    // savepoint can be rolled back to again
+    let mut txn = db.begin_transaction();
+    txn.insert(&a, "step 1", "done");
+    let savepoint = txn.savepoint();
+    txn.insert(&a, "step 2", "done");
+    txn.remove(&a, "kept");
+    txn.insert(&b, "step 3", "failed");
+    txn.rollback_to(&savepoint);
+    assert_eq!(txn.get(&a, "kept")?, Some(IVec::from("before")));
+    assert_eq!(txn.get(&b, "step 3")?, None);
+    txn.insert(&a, "step 2", "retried");
+    txn.rollback_to(&savepoint);
+    txn.insert(&b, "step 2", "elsewhere");
+    txn.commit().unwrap();
+    assert_eq!(a.get("step 1")?, Some(IVec::from("done")));
+    assert_eq!(a.get("step 2")?, None);
+    assert_eq!(a.get("kept")?, Some(IVec::from("before")));
+    assert_eq!(b.get("step 2")?, Some(IVec::from("elsewhere")));
+    assert_eq!(b.get("step 3")?, None);
+
+    // closure transactions roll back each tree on its own
+    (&a, &b)
+        .transaction(|(a, b)| {
+            a.insert("x", "1")?;
+            let a_savepoint = a.savepoint();
+            let b_savepoint = b.savepoint();
+            a.insert("y", "2")?;
+            b.insert("y", "2")?;
+            a.rollback_to(&a_savepoint);
+            assert_eq!(a.get("y")?, None);
+            assert_eq!(b.get("y")?, Some(IVec::from("2")));
+            b.rollback_to(&b_savepoint);
+            Ok::<_, ConflictableTransactionError<()>>(())
+        })
+        .unwrap();
+    assert_eq!(a.get("x")?, Some(IVec::from("1")));
+    assert_eq!(a.get("y")?, None);
+    assert_eq!(b.get("y")?, None);
+
+    Ok(())
+}
+
+#[test]
+fn db_snapshot() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let tree = db.open_tree(b"accounts")?;
+    let key = |i: u32| IVec::from(&i.to_be_bytes());
+    let balance = |v: &IVec| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(v);
+        u64::from_be_bytes(bytes)
+    };
+    const N: u32 = 500;
+    for i in 0..N {
+        tree.insert(key(i), &100_u64.to_be_bytes())?;
+    }
+
+    // a snapshot sees none of the writes that happen after it
+    let snapshot = db.snapshot();
+    tree.remove(key(3))?;
+    tree.insert(key(N), &1_u64.to_be_bytes())?;
+    tree.insert(key(4), &7_u64.to_be_bytes())?;
+    assert_eq!(snapshot.get(&tree, key(3))?.map(|v| balance(&v)), Some(100));
+    assert_eq!(snapshot.get(&tree, key(4))?.map(|v| balance(&v)), Some(100));
+    assert_eq!(snapshot.get(&tree, key(N))?, None);
+    let keys: Vec<IVec> = snapshot
+        .iter(&tree)
+        .map(|res| res.map(|(k, _v)| k))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, (0..N).map(key).collect::<Vec<_>>());
+    let back: Vec<IVec> = snapshot
+        .range(&tree, key(2)..=key(5))
+        .rev()
+        .map(|res| res.map(|(k, _v)| k))
+        .collect::<Result<_>>()?;
+    assert_eq!(back, vec![key(5), key(4), key(3), key(2)]);
+    drop(snapshot);
+    tree.remove(key(N))?;
+    tree.insert(key(3), &100_u64.to_be_bytes())?;
+    tree.insert(key(4), &100_u64.to_be_bytes())?;
+
+    // scans see a consistent total while transfers run, without
+    // missing or repeating keys, from both ends at once
+    let done = Arc::new(AtomicUsize::new(0));
+    let transfers = {
+        let tree = tree.clone();
+        let done = done.clone();
+        std::thread::spawn(move || {
+            let mut i = 0_u32;
+            while done.load(SeqCst) == 0 {
+                let (from, to) = (key(i % N), key((i * 7 + 1) % N));
+                i += 1;
+                if from == to {
+                    continue;
+                }
+                tree.transaction(|tx| {
+                    let a = balance(&tx.get(&from)?.unwrap());
+                    let b = balance(&tx.get(&to)?.unwrap());
+                    if a > 0 {
+                        tx.insert(&from, &(a - 1).to_be_bytes())?;
+                        tx.insert(&to, &(b + 1).to_be_bytes())?;
+                    }
+                    Ok::<_, ConflictableTransactionError<()>>(())
+                })
+                .unwrap();
+                if i % 50 == 0 {
+                    // a key that exists only briefly
+                    let moved = key(N + i);
+                    tree.insert(&moved, &0_u64.to_be_bytes())?;
+                    tree.remove(&moved)?;
+                }
+            }
+            Ok::<_, Error>(())
+        })
+    };
+    for _ in 0..20 {
+        let snapshot = db.snapshot();
+        let mut iter = snapshot.iter(&tree);
+        let mut keys = vec![];
+        let mut total = 0;
+        loop {
+            let front = iter.next();
+            let back = iter.next_back();
+            if front.is_none() && back.is_none() {
+                break;
+            }
+            for (k, v) in front.into_iter().chain(back).map(Result::unwrap) {
+                keys.push(k);
+                total += balance(&v);
+            }
+        }
+        // a key that was moved may have existed when it was taken
+        keys.sort();
+        let moved = keys.iter().filter(|k| **k >= key(N)).count();
+        assert!(moved <= 1);
+        keys.truncate(keys.len() - moved);
+        assert!(keys == (0..N).map(key).collect::<Vec<_>>());
+        assert_eq!(total, u64::from(N) * 100);
+    }
+    done.store(1, SeqCst);
+    transfers.join().unwrap()?;
+
+    // a tree that is bulk loaded after the snapshot stays empty
+    let loaded = db.open_tree(b"loaded")?;
+    let snapshot = db.snapshot();
+    loaded.bulk_load(vec![(key(1), key(1)), (key(2), key(2))])?;
+    loaded.insert(key(1), key(3))?;
+    assert_eq!(snapshot.iter(&loaded).count(), 0);
+    assert_eq!(snapshot.get(&loaded, key(1))?, None);
+    assert_eq!(db.snapshot().iter(&loaded).count(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn tree_get_at() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_get_at";
+    let _ = std::fs::remove_dir_all(path);
+    let config =
+        Config::new().path(path).version_retention_ms(Some(60 * 60 * 1000));
+    let timeout = Duration::from_secs(1);
+    let one = IVec::from(b"1");
+    let two = IVec::from(b"2");
+
+    let before = {
+        let db = config.open()?;
+        let tree = db.open_tree("rows")?;
+        let subscriber = tree.watch_prefix(vec![]);
+
+        let mut batch = Batch::default();
+        batch.insert(b"a", b"1");
+        batch.insert(&[b'b', 0], b"1");
+        batch.insert(b"b", b"1");
+        tree.apply_batch(batch)?;
+        let before = subscriber.next_timeout(timeout).unwrap().seq();
+
+        tree.insert(b"a", b"2")?;
+        tree.remove(&[b'b', 0])?;
+        tree.insert(b"c", b"1")?;
+        let res: TransactionResult<()> = tree.transaction(|tx| {
+            tx.insert(b"b", b"2")?;
+            Ok(())
+        });
+        res.unwrap();
+        tree.compare_and_swap(b"c", Some(b"1"), Some(b"2"))?.unwrap();
+
+        assert_eq!(tree.get_at(b"a", before)?, Some(one.clone()));
+        assert_eq!(tree.get_at(&[b'b', 0], before)?, Some(one.clone()));
+        assert_eq!(tree.get_at(b"c", before)?, None);
+        assert_eq!(tree.get_at(b"c", u64::max_value())?, Some(two.clone()));
+
+        let expected = vec![
+            (IVec::from(b"a"), one.clone()),
+            (IVec::from(b"b"), one.clone()),
+            (IVec::from(&[b'b', 0]), one.clone()),
+        ];
+        let rows = tree.range_at::<&[u8], _, _>(.., before)?;
+        assert_eq!(rows.collect::<Result<Vec<_>>>()?, expected);
+        let rows = tree.range_at(&b"a"[..]..&b"b"[..], before)?;
+        assert_eq!(rows.collect::<Result<Vec<_>>>()?, expected[..1].to_vec());
+        let rows = tree.range_at(&b"b"[..]..=&b"c"[..], before)?;
+        assert_eq!(rows.collect::<Result<Vec<_>>>()?, expected[1..].to_vec());
+
+        // a point can also be a time
+        std::thread::sleep(Duration::from_millis(5));
+        let then = SystemTime::now();
+        std::thread::sleep(Duration::from_millis(5));
+        tree.insert(b"a", b"3")?;
+        assert_eq!(tree.get_at(b"a", then)?, Some(two.clone()));
+        assert_eq!(tree.get_at(b"c", then)?, Some(two.clone()));
+
+        db.flush()?;
+        before
+    };
+
+    // the versions are recovered, and discarded once they are no
+    // longer retained
+    {
+        let db = config.open()?;
+        let tree = db.open_tree("rows")?;
+        assert_eq!(tree.get_at(b"a", before)?, Some(one.clone()));
+    }
+    {
+        let db = config.clone().version_retention_ms(None).open()?;
+        let tree = db.open_tree("rows")?;
+        assert!(tree.get_at(b"a", before).is_err());
+    }
+    {
+        let db = config.open()?;
+        let tree = db.open_tree("rows")?;
+        assert!(tree.get_at(b"a", before).is_err());
+        assert_eq!(tree.get_at(b"a", u64::max_value())?, Some("3".into()));
+    }
+
+    // versions that are older than the retention are trimmed
+    {
+        let db = config.clone().version_retention_ms(Some(20)).open()?;
+        let tree = db.open_tree("fleeting")?;
+        let subscriber = tree.watch_prefix(vec![]);
+        tree.insert(b"k", b"1")?;
+        let first = subscriber.next_timeout(timeout).unwrap().seq();
+        tree.insert(b"k", b"2")?;
+        assert_eq!(tree.get_at(b"k", first)?, Some(one.clone()));
+
+        std::thread::sleep(Duration::from_millis(50));
+        let ago = SystemTime::now() - Duration::from_millis(40);
+        assert!(tree.get_at(b"k", ago).is_err());
+        tree.insert(b"k", b"3")?;
+        assert!(tree.get_at(b"k", first).is_err());
+    }
+
+    let _ = std::fs::remove_dir_all(path);
+    Ok(())
+}
+
+#[test]
+fn tree_get_versioned() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_get_versioned";
+    let _ = std::fs::remove_dir_all(path);
+    let config = Config::new().path(path);
+    let one = IVec::from(b"1");
+    let two = IVec::from(b"2");
+
+    let kept = {
+        let db = config.open()?;
+        let tree = db.open_tree("rows")?;
+        tree.insert(b"old", b"1")?;
+
+        // keys that were written before the versions were tracked
+        // share a version
+        let (value, old) = tree.get_versioned(b"old")?.unwrap();
+        assert_eq!(value, one);
+        assert_eq!(tree.get_versioned(b"missing")?, None);
+
+        // every kind of write gives a key a new version
+        tree.insert(b"k", b"1")?;
+        let (_, inserted) = tree.get_versioned(b"k")?.unwrap();
+        assert!(inserted > old);
+        let mut batch = Batch::default();
+        batch.insert(b"k", b"2");
+        tree.apply_batch(batch)?;
+        let (_, batched) = tree.get_versioned(b"k")?.unwrap();
+        assert!(batched > inserted);
+        let res: TransactionResult<()> = tree.transaction(|tx| {
+            tx.insert(b"k", b"1")?;
+            Ok(())
+        });
+        res.unwrap();
+        let (_, transacted) = tree.get_versioned(b"k")?.unwrap();
+        assert!(transacted > batched);
+        tree.compare_and_swap(b"k", Some(b"1"), Some(b"2"))?.unwrap();
+        let (value, swapped) = tree.get_versioned(b"k")?.unwrap();
+        assert!(swapped > transacted);
+        assert_eq!(value, two);
+
+        // a stale version fails even if the value is the same
+        tree.insert(b"k", b"1")?;
+        tree.insert(b"k", b"2")?;
+        let err = tree.insert_if_version(b"k", b"3", swapped)?.unwrap_err();
+        let (value, current) = err.current.unwrap();
+        assert_eq!(value, two);
+        assert!(current > swapped);
+        assert_eq!(tree.get(b"k")?, Some(two.clone()));
+
+        let written = tree.insert_if_version(b"k", b"3", current)?.unwrap();
+        assert_eq!(tree.get_versioned(b"k")?, Some(("3".into(), written)));
+
+        // removed keys have no version to insert with
+        tree.remove(b"k")?;
+        let err = tree.insert_if_version(b"k", b"4", written)?.unwrap_err();
+        assert_eq!(err.current, None);
+        assert_eq!(tree.get(b"k")?, None);
+
+        tree.insert(b"kept", b"1")?;
+        let (_, kept) = tree.get_versioned(b"kept")?.unwrap();
+        db.flush()?;
+        kept
+    };
+
+    // the versions are still tracked, and renewed, after a restart
+    {
+        let db = config.open()?;
+        let tree = db.open_tree("rows")?;
+        let (value, renewed) = tree.get_versioned(b"kept")?.unwrap();
+        assert_eq!(value, one);
+        assert!(renewed > kept);
+        assert!(tree.insert_if_version(b"kept", b"2", kept)?.is_err());
+        tree.insert(b"kept", b"2")?;
+        let (_, written) = tree.get_versioned(b"kept")?.unwrap();
+        assert!(written > renewed);
+
+        assert!(db.drop_tree("rows")?);
+        let tree = db.open_tree("rows")?;
+        assert_eq!(tree.get_versioned(b"kept")?, None);
+    }
+
+    let _ = std::fs::remove_dir_all(path);
+    Ok(())
+}
+
+#[test]
+fn tree_salvage_corrupt_snapshot() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_salvage_corrupt_snapshot";
+    let _ = std::fs::remove_dir_all(path);
+    // snapshots are only written when the database is recovered,
+    // so that none is written while it is being damaged
+    let config =
+        Config::new().path(path).snapshot_after_ops(u64::max_value());
+    {
+        let db = config.open()?;
+        for i in 0..1000_u32 {
+            db.insert(i.to_be_bytes(), &i.to_le_bytes())?;
+        }
+        db.flush()?;
+    }
+    drop(config.open()?);
+
+    let mut snapshots = vec![];
+    for entry in std::fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        let name = entry_path.file_name().unwrap().to_string_lossy();
+        if name.starts_with("snap.") {
+            snapshots.push(entry_path.clone());
+        }
+    }
+    assert!(!snapshots.is_empty());
+    for snapshot in snapshots {
+        let mut data = std::fs::read(&snapshot)?;
+        data[0] ^= 0xff;
+        std::fs::write(&snapshot, data)?;
+    }
+
+    let faults = Arc::new(std::sync::Mutex::new(vec![]));
+    let config = config.on_fault({
+        let faults = faults.clone();
+        move |fault| faults.lock().unwrap().push(fault.clone())
+    });
+    match config.open() {
+        Err(Error::Corruption { .. }) => {}
+        other => panic!("expected a corrupt snapshot, got {:?}", other),
+    }
+    {
+        let faults = faults.lock().unwrap();
+        assert_eq!(faults.len(), 1, "{:?}", faults);
+        assert!(faults[0].fatal && faults[0].pid.is_none());
+    }
+    assert!(config
+        .clone()
+        .read_only(true)
+        .recovery_mode(RecoveryMode::Salvage)
+        .open()
+        .is_err());
+
+    // the snapshot is rebuilt from the log
+    let db = config.recovery_mode(RecoveryMode::Salvage).open()?;
+    assert!(db.lost_ranges().is_empty());
+    {
+        let faults = faults.lock().unwrap();
+        assert_eq!(faults.len(), 2, "{:?}", faults);
+        assert!(!faults[1].fatal);
+    }
+    assert_eq!(db.len(), 1000);
+    for i in 0..1000_u32 {
+        let expected = IVec::from(&i.to_le_bytes());
+        assert_eq!(db.get(i.to_be_bytes())?, Some(expected));
+    }
+    drop(db);
+
+    std::fs::remove_dir_all(path)?;
+
+    Ok(())
+}
+
+// a minimal executor, so that the async api can be
+// tested without pulling in a runtime
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    fn noop_raw_waker() -> RawWaker {
+        fn clone(_: *const ()) -> RawWaker {
+            noop_raw_waker()
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable =
+            RawWakerVTable::new(clone, noop, noop, noop);
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+
+    #[allow(unsafe_code)]
+    let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn tree_async_api() -> Result<()> {
+    common::setup_logger();
+
+    let config = Config::new().temporary(true).flush_every_ms(None);
+
+    block_on(async {
+        let db = config.open_async().await?;
+        let tree = db.open_tree("async").await?;
+
+        assert_eq!(tree.insert(b"a", b"1").await?, None);
+        assert_eq!(tree.insert(b"b", b"2").await?, None);
+        assert_eq!(tree.get(b"a").await?, Some(IVec::from(b"1")));
+        assert!(tree.contains_key(b"b").await?);
+
+        assert_eq!(
+            tree.compare_and_swap(b"a", Some(b"1"), Some(b"3")).await?,
+            Ok(())
+        );
+        assert_eq!(
+            tree.get_many(vec![b"b", b"a", b"c"]).await?,
+            vec![Some(IVec::from(b"2")), Some(IVec::from(b"3")), None]
+        );
+
+        assert_eq!(tree.remove(b"b").await?, Some(IVec::from(b"2")));
+        tree.flush().await?;
+
+        assert_eq!(tree.blocking().len(), 1);
+        assert_eq!(db.tree_names().len(), 2);
+        assert!(db.drop_tree("async").await?);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn tree_expiration() -> Result<()> {
+    common::setup_logger();
+
+    let config = Config::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .expiration_sweep_every_ms(None);
+    let db = config.open()?;
+    let t = db.open_tree("expiring")?;
+
+    let long = Duration::from_secs(60 * 60);
+    let short = Duration::from_millis(10);
+
+    t.insert_with_ttl(b"a", b"a", short)?;
+    t.insert_with_ttl(b"b", b"b", long)?;
+    t.insert_with_ttl(b"c", b"c", short)?;
+    t.insert_with_ttl(b"d", b"d", short)?;
+    t.insert(b"e", b"e")?;
+
+    // a plain write clears the expiration
+    t.insert(b"d", b"d")?;
+
+    std::thread::sleep(Duration::from_millis(50));
+
+    assert_eq!(t.get(b"a")?, None);
+    assert_eq!(t.get(b"b")?, Some(IVec::from(b"b")));
+    assert!(!t.contains_key(b"c")?);
+    assert_eq!(t.get(b"d")?, Some(IVec::from(b"d")));
+
+    let keys: Vec<_> = t.iter().keys().collect::<Result<_>>()?;
+    assert_eq!(keys, [IVec::from(b"b"), IVec::from(b"d"), IVec::from(b"e")]);
+    let keys: Vec<_> = t.iter().keys().rev().collect::<Result<_>>()?;
+    assert_eq!(keys, [IVec::from(b"e"), IVec::from(b"d"), IVec::from(b"b")]);
+
+    // an expired value is treated as absent by cas
+    assert_eq!(
+        t.compare_and_swap(b"c", None as Option<&[u8]>, Some(b"c2")),
+        Ok(Ok(()))
+    );
+    assert_eq!(t.get(b"c")?, Some(IVec::from(b"c2")));
+
+    // expired keys stop being counted before they are swept
+    assert_eq!(t.len(), 4);
+    assert_eq!(t.sweep_expired()?, 1);
+    assert_eq!(t.sweep_expired()?, 0);
+    assert_eq!(t.len(), 4);
+
+    // a key that was rewritten before it's swept is kept, and the
+    // sweep goes on past it, across several chunks
+    for i in 0..1000_u32 {
+        t.insert_with_ttl(i.to_be_bytes(), b"v", short)?;
+    }
+    t.insert(500_u32.to_be_bytes(), b"kept")?;
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(t.len(), 5);
+    assert_eq!(t.sweep_expired()?, 999);
+    assert_eq!(t.remove(500_u32.to_be_bytes())?, Some(IVec::from(b"kept")));
+    assert_eq!(t.len(), 4);
+
+    // deadlines survive restarts, and stay hidden from tree_names
+    t.insert_with_ttl(b"f", b"f", short)?;
+    drop(t);
+    drop(db);
+
+    std::thread::sleep(Duration::from_millis(50));
+
+    let db = config.open()?;
+    assert_eq!(db.tree_names().len(), 2);
+    let t = db.open_tree("expiring")?;
+    assert_eq!(t.get(b"f")?, None);
+    assert_eq!(t.sweep_expired()?, 1);
+    assert_eq!(t.len(), 4);
+
+    assert!(db.drop_tree("expiring")?);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn tree_expiration_sweeper() -> Result<()> {
+    common::setup_logger();
+
+    let sweepers = || {
+        let tasks = std::fs::read_dir("/proc/self/task").unwrap();
+        tasks
+            .filter_map(|task| {
+                let comm = task.ok()?.path().join("comm");
+                std::fs::read_to_string(comm).ok()
+            })
+            .filter(|comm| comm.trim() == "ttl-expiration-")
+            .count()
+    };
+
+    let db = Config::new()
+        .temporary(true)
+        .background_thread_name("ttl".to_owned())
+        .expiration_sweep_every_ms(Some(1))
+        .open()?;
+    assert_eq!(sweepers(), 0);
+
+    let trees: Vec<Tree> = (0..3_u8)
+        .map(|i| db.open_tree([i]))
+        .collect::<Result<_>>()?;
+    for tree in &trees {
+        tree.insert_with_ttl(b"k", b"v", Duration::from_millis(1))?;
+    }
+
+    // every tree is swept by the same thread
+    assert_eq!(sweepers(), 1);
+    for _ in 0..1000 {
+        if trees.iter().all(|tree| tree.len() == 0) {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    for tree in &trees {
+        assert_eq!(tree.len(), 0);
+    }
+
+    drop(trees);
+    drop(db);
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(sweepers(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn tree_rename() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_rename";
+    let _ = std::fs::remove_dir_all(path);
+    let config = Config::new().path(path).event_history(Some(16));
+    let concatenate = |_k: &[u8], old: Option<&[u8]>, merged: &[u8]| {
+        let mut ret = old.map_or_else(Vec::new, |old| old.to_vec());
+        ret.extend_from_slice(merged);
+        Some(ret)
+    };
+
+    {
+        let db = config.open()?;
+        db.register_merge_operator("concatenate", concatenate);
+        let staging = db.open_tree("staging")?;
+        staging.use_merge_operator("concatenate")?;
+        for i in 0..1000_u32 {
+            staging.insert(i.to_be_bytes(), &i.to_le_bytes())?;
+        }
+        staging.insert_with_ttl(b"ttl", b"v", Duration::from_secs(3600))?;
+        let _ = staging.get_versioned(b"ttl")?;
+        let subscriber = staging.watch_prefix(vec![]);
+        staging.insert(b"last", b"v")?;
+        let last = subscriber.next_timeout(Duration::from_secs(1)).unwrap();
+        let live = db.open_tree("live")?;
+        live.insert(b"old", b"v")?;
+
+        assert_eq!(db.rename_tree("missing", "other", false), Ok(false));
+        assert!(db.rename_tree("staging", "live", false).is_err());
+        assert!(db.rename_tree("staging", "__sled__default", true).is_err());
+        assert_eq!(live.get(b"old")?, Some(IVec::from(b"v")));
+
+        assert_eq!(db.rename_tree("staging", "live", true), Ok(true));
+        assert!(staging.get(b"ttl").is_err());
+        assert!(live.get(b"old").is_err());
+
+        let mut names = db.tree_names();
+        names.sort();
+        let expected = vec![IVec::from("__sled__default"), IVec::from("live")];
+        assert_eq!(names, expected);
+
+        let live = db.open_tree("live")?;
+        assert_eq!(live.len(), 1002);
+        assert_eq!(live.get(b"old")?, None);
+        let seven = IVec::from(&7_u32.to_le_bytes());
+        assert_eq!(live.get(7_u32.to_be_bytes())?, Some(seven));
+        live.merge(b"ttl", b"2")?;
+        assert_eq!(live.get(b"ttl")?, Some(IVec::from(b"v2")));
+        assert!(live.get_versioned(b"ttl")?.is_some());
+        let replayed = live.watch_prefix_since(vec![], last.seq())?;
+        let event = replayed.next_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(event.seq(), last.seq());
+
+        // the old name can be used for a new tree
+        let staging = db.open_tree("staging")?;
+        assert!(staging.is_empty());
+        db.flush()?;
+    }
+
+    // the rename is recovered along with the companions of the tree
+    {
+        let db = config.open()?;
+        db.register_merge_operator("concatenate", concatenate);
+        let live = db.open_tree("live")?;
+        assert_eq!(live.len(), 1002);
+        live.merge(b"ttl", b"3")?;
+        assert_eq!(live.get(b"ttl")?, Some(IVec::from(b"v23")));
+        assert!(db.drop_tree("live")?);
+    }
+
+    let _ = std::fs::remove_dir_all(path);
+    Ok(())
+}
+
+#[test]
+fn tree_reserved_names() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let foo = db.open_tree("foo")?;
+    foo.insert_with_ttl(b"k", b"v", Duration::from_secs(3600))?;
+    db.register_merge_operator("replace", |_k, _old, merged| {
+        Some(merged.to_vec())
+    });
+    foo.use_merge_operator("replace")?;
+
+    // the names of the entries of the meta that aren't trees, and
+    // of the trees that sled uses itself, can't be used by others
+    for name in &[
+        "__sled__created__foo",
+        "__sled__len__foo",
+        "__sled__merge_operator__foo",
+        "__sled__expirations__foo",
+        "__sled__temporary__foo",
+        "__sled__other",
+    ] {
+        match db.open_tree(name) {
+            Err(Error::Unsupported(_)) => {}
+            other => panic!("expected Unsupported, got {:?}", other),
+        }
+        assert!(db.open_tree_with(name, TreeConfig::default()).is_err());
+        assert!(db.rename_tree("foo", name, true).is_err());
+        assert!(db.rename_tree(name, "bar", true).is_err());
+        assert!(db.copy_tree("foo", name).is_err());
+        if !name.starts_with("__sled__temporary__") {
+            assert!(db.drop_tree(name).is_err());
+        }
+    }
+    assert_eq!(db.tree_names().len(), 2);
+    assert_eq!(foo.get(b"k")?, Some(IVec::from(b"v")));
+
+    // while the trees that exist can still be opened
+    let default = db.open_tree("__sled__default")?;
+    assert_eq!(default.name(), db.name());
+    let scratch = db.open_temporary_tree("scratch")?;
+    assert_eq!(db.open_tree(scratch.name())?.name(), scratch.name());
+    assert!(db.drop_tree(scratch.name())?);
+
+    Ok(())
+}
+
+#[test]
+fn tree_reserved_names_survive_reopen() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_reserved_names_survive_reopen";
+    let _ = std::fs::remove_dir_all(path);
+    let config = Config::new().path(path);
+
+    // no tree can be mistaken for the hidden tree of another, or
+    // for a temporary tree, when the `Db` is opened again
+    {
+        let db = config.open()?;
+        let foo = db.open_tree("foo")?;
+        foo.insert(b"k", b"v")?;
+        for prefix in &["expirations", "history", "versions", "temporary"] {
+            let name = format!("__sled__{}__foo", prefix);
+            assert!(db.open_tree(&name).is_err());
+            assert!(db.copy_tree("foo", &name).is_err());
+        }
+        db.flush()?;
+    }
+
+    let db = config.open()?;
+    let mut names = db.tree_names();
+    names.sort();
+    assert_eq!(names, vec![IVec::from("__sled__default"), IVec::from("foo")]);
+    let foo = db.open_tree("foo")?;
+    assert_eq!(foo.get(b"k")?, Some(IVec::from(b"v")));
+    assert_eq!(foo.sweep_expired()?, 0);
+    drop(db);
+
+    let _ = std::fs::remove_dir_all(path);
+    Ok(())
+}
+
+#[test]
+fn tree_copy() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_copy";
+    let _ = std::fs::remove_dir_all(path);
+    let config = Config::new().path(path).event_history(Some(16));
+
+    {
+        let db = config.open()?;
+        let base = db.open_tree_with(
+            "base",
+            TreeConfig { compression: Codec::None, ..TreeConfig::default() },
+        )?;
+        for i in 0..1000_u32 {
+            base.insert(i.to_be_bytes(), &i.to_le_bytes())?;
+        }
+        db.open_tree("taken")?;
+
+        assert_eq!(db.copy_tree("missing", "other"), Ok(false));
+        assert!(db.copy_tree("base", "taken").is_err());
+        assert_eq!(db.copy_tree("base", "sandbox"), Ok(true));
+
+        let sandbox = db.open_tree("sandbox")?;
+        assert_eq!(sandbox.len(), 1000);
+        assert!(sandbox.iter().eq(base.iter()));
+
+        // the copy is independent of its source
+        sandbox.remove(7_u32.to_be_bytes())?;
+        base.insert(b"new", b"v")?;
+        assert_eq!(sandbox.len(), 999);
+        assert_eq!(sandbox.get(b"new")?, None);
+        assert!(base.get(7_u32.to_be_bytes())?.is_some());
+
+        // the copy retains its history like any other tree
+        let subscriber = sandbox.watch_prefix(vec![]);
+        sandbox.insert(b"last", b"v")?;
+        let last = subscriber.next_timeout(Duration::from_secs(1)).unwrap();
+        let replayed = sandbox.watch_prefix_since(vec![], last.seq())?;
+        assert!(replayed.next_timeout(Duration::from_secs(1)).is_ok());
+        db.flush()?;
+    }
+
+    {
+        let db = config.open()?;
+        let sandbox = db.open_tree("sandbox")?;
+        assert_eq!(sandbox.len(), 1000);
+        let eight = IVec::from(&8_u32.to_le_bytes());
+        assert_eq!(sandbox.get(8_u32.to_be_bytes())?, Some(eight));
+    }
+
+    let _ = std::fs::remove_dir_all(path);
+    Ok(())
+}
+
+#[test]
+fn tree_stats() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_stats";
+    let _ = std::fs::remove_dir_all(path);
+    let config = Config::new().path(path);
+
+    let before = SystemTime::now() - Duration::from_secs(1);
+    let created = {
+        let db = config.open()?;
+        let big = db.open_tree("big")?;
+        for i in 0..10_000_u32 {
+            big.insert(i.to_be_bytes(), vec![0; 100])?;
+        }
+        assert_eq!(big.len(), 10_000);
+        let small = db.open_tree("small")?;
+        small.insert(b"k", b"v")?;
+
+        let stats = db.tree_stats()?;
+        let names: Vec<&[u8]> = stats.iter().map(|s| &*s.tree).collect();
+        assert_eq!(names, vec![&b"__sled__default"[..], b"big", b"small"]);
+
+        let big_stats = &stats[1];
+        assert_eq!(big_stats.len, 10_000);
+        assert!(big_stats.live_bytes > 100 * 10_000 / 2);
+        assert!(big_stats.nodes > 100);
+        assert_eq!(big_stats.cache_residency(), 1.0);
+        let small_stats = &stats[2];
+        assert_eq!(small_stats.len, 1);
+        assert_eq!(small_stats.nodes, 2);
+        assert_eq!(small_stats.cache_residency(), 1.0);
+
+        let created = big_stats.created.unwrap();
+        assert!(created > before && created <= SystemTime::now());
+
+        // the creation time moves along with a renamed tree, and
+        // goes away with a dropped one
+        assert_eq!(db.rename_tree("big", "renamed", false), Ok(true));
+        assert!(db.drop_tree("small")?);
+        let stats = db.tree_stats()?;
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[1].created, Some(created));
+        db.flush()?;
+        created
+    };
+
+    {
+        let db = config.open()?;
+        let stats = db.tree_stats()?;
+        assert_eq!(&*stats[1].tree, b"renamed");
+        assert_eq!(stats[1].created, Some(created));
+
+        // the keys are estimated until they have been counted
+        assert!(stats[1].len > 5_000 && stats[1].len < 20_000);
+        let renamed = db.open_tree("renamed")?;
+        assert_eq!(renamed.len(), 10_000);
+        let stats = db.tree_stats()?;
+        assert_eq!(stats[1].len, 10_000);
+        assert_eq!(stats[1].cache_residency(), 1.0);
+        let small = db.open_tree("small")?;
+        assert!(db.tree_stats()?[2].created.unwrap() > created);
+        assert!(small.is_empty());
+    }
+
+    let _ = std::fs::remove_dir_all(path);
+    Ok(())
+}
+
+#[test]
+fn tree_temporary() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_temporary";
+    let _ = std::fs::remove_dir_all(path);
+    let config = Config::new().path(path).event_history(Some(16));
+
+    {
+        let db = config.open()?;
+        let scratch = db.open_temporary_tree("scratch")?;
+        for i in 0..1000_u32 {
+            scratch.insert_with_ttl(
+                i.to_be_bytes(),
+                b"v",
+                Duration::from_secs(60),
+            )?;
+        }
+        let regular = db.open_tree("scratch")?;
+        regular.insert(b"kept", b"v")?;
+
+        assert_ne!(scratch.name(), regular.name());
+        assert_eq!(scratch.len(), 1000);
+        assert_eq!(db.open_temporary_tree("scratch")?.len(), 1000);
+        assert!(db.tree_names().contains(&scratch.name()));
+        db.flush()?;
+    }
+
+    {
+        let db = config.clone().read_only(true).open()?;
+        assert_eq!(db.tree_names().len(), 2);
+        assert!(db.open_temporary_tree("scratch").is_err());
+    }
+
+    {
+        let db = config.open()?;
+        assert_eq!(db.tree_names().len(), 2);
+        assert!(db.open_temporary_tree("scratch")?.is_empty());
+        assert_eq!(db.open_tree("scratch")?.len(), 1);
+    }
+
+    let _ = std::fs::remove_dir_all(path);
+    Ok(())
+}
+
+#[test]
+fn tree_namespaces() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let tenant = db.open_namespace("tenant_42")?;
+    let other = db.open_namespace("tenant_420")?;
+    assert!(db.open_namespace("").is_err());
+
+    tenant.open_tree("orders")?.insert(b"k", b"v")?;
+    tenant.open_tree("users")?;
+    tenant.open_namespace("eu")?.open_tree("orders")?;
+    other.open_tree("orders")?;
+    db.open_tree("tenant_42")?;
+
+    assert_eq!(tenant.name(), b"tenant_42");
+    let orders = tenant.open_tree("orders")?;
+    assert_eq!(orders.get(b"k")?, Some(IVec::from(b"v")));
+    assert_eq!(tenant.tree_names(), vec!["eu/orders", "orders", "users"]);
+    assert_eq!(other.tree_names(), vec!["orders"]);
+
+    assert!(tenant.drop_tree("users")?);
+    assert!(!tenant.drop_tree("users")?);
+    assert_eq!(tenant.drop_namespace("eu")?, 1);
+    assert_eq!(tenant.tree_names(), vec!["orders"]);
+
+    // a namespace only covers the trees behind its separator
+    assert_eq!(db.drop_namespace("tenant_42")?, 1);
+    assert!(tenant.tree_names().is_empty());
+    assert_eq!(other.tree_names(), vec!["orders"]);
+    assert!(db.tree_names().contains(&IVec::from("tenant_42")));
+
+    Ok(())
+}
+
+#[test]
+fn tree_sequences() -> Result<()> {
+    common::setup_logger();
+
+    let path = "test_tree_sequences";
+    let _ = std::fs::remove_dir_all(path);
+    let config = Config::new().path(path);
+
+    {
+        let db = config.open()?;
+        let orders = db.sequence("order_id")?;
+        let invoices = db.sequence("invoice_id")?;
+        assert!(orders.set_block_size(0).is_err());
+        orders.set_block_size(10)?;
+
+        for expected in 0..15 {
+            assert_eq!(orders.next_id()?, expected);
+        }
+        assert_eq!(orders.current(), 15);
+        assert_eq!(db.sequence("order_id")?.next_id()?, 15);
+        assert_eq!(invoices.next_id()?, 0);
+        assert_eq!(orders.name(), IVec::from("order_id"));
+        assert_eq!(db.tree_names().len(), 1);
+    }
+
+    {
+        // the IDs that were reserved but not handed out are skipped
+        let db = config.open()?;
+        let orders = db.sequence("order_id")?;
+        assert_eq!(orders.current(), 20);
+        assert_eq!(orders.next_id()?, 20);
+
+        orders.set(1000)?;
+        assert_eq!(orders.next_id()?, 1000);
+        orders.set(5)?;
+    }
+
+    {
+        let db = config.read_only(true).open()?;
+        let orders = db.sequence("order_id")?;
+        assert_eq!(orders.current(), 5);
+        assert!(orders.next_id().is_err());
+        assert_eq!(db.sequence("invoice_id")?.current(), 1_000_000);
+    }
+
+    let _ = std::fs::remove_dir_all(path);
+    Ok(())
+}
+
+#[test]
+fn tree_counters() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let tree = db.open_tree("counters")?;
+
+    assert_eq!(tree.increment(b"c", 0)?, 0);
+    assert_eq!(tree.increment(b"c", 5)?, 5);
+    assert_eq!(tree.increment(b"c", u64::max_value())?, 4);
+    tree.insert(b"name", b"sled")?;
+    assert!(tree.increment(b"name", 1).is_err());
+    assert_eq!(tree.get(b"name")?, Some(IVec::from(b"sled")));
+
+    // every increment sees the value that its own delta produced,
+    // even when concurrent increments are written together
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let tree = tree.clone();
+            std::thread::spawn(move || -> Result<Vec<u64>> {
+                (0..1000).map(|_| tree.increment(b"hot", 1)).collect()
+            })
+        })
+        .collect();
+    let mut seen = std::collections::HashSet::new();
+    for thread in threads {
+        for value in thread.join().unwrap()? {
+            assert!(seen.insert(value));
+        }
+    }
+    assert_eq!(seen.len(), 8000);
+    assert!(seen.iter().all(|value| (1..=8000).contains(value)));
+    assert_eq!(tree.get(b"hot")?, Some(IVec::from(&8000_u64.to_be_bytes())));
+
+    Ok(())
+}
+
+#[test]
+fn tree_metrics() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let users = db.open_tree("users")?;
+    let orders = db.open_tree("orders")?;
+
+    for i in 0..100_u32 {
+        users.insert(i.to_be_bytes(), vec![0; 96])?;
+    }
+    users.remove(0_u32.to_be_bytes())?;
+    orders
+        .compare_and_swap(b"o", None as Option<&[u8]>, Some(b"v"))?
+        .unwrap();
+    for i in 0..10_u32 {
+        users.get(i.to_be_bytes())?;
+    }
+    let keys: Vec<_> = (0..5_u32).map(u32::to_be_bytes).collect();
+    assert_eq!(users.get_many(&keys)?.len(), 5);
+    db.flush()?;
+
+    let metrics = db.metrics()?;
+    let names: Vec<_> = metrics.trees.iter().map(|t| &*t.tree).collect();
+    assert_eq!(names, [&b"__sled__default"[..], b"orders", b"users"]);
+
+    let user_metrics = &metrics.trees[2];
+    assert_eq!(user_metrics.writes, 101);
+    assert_eq!(user_metrics.user_bytes_written, 100 * 100 + 4);
+    assert_eq!(user_metrics.gets, 15);
+    assert_eq!(metrics.trees[1].writes, 1);
+    assert_eq!(metrics.trees[1].user_bytes_written, 2);
+    assert_eq!(metrics.user_bytes_written, 100 * 100 + 4 + 2);
+    assert_eq!(metrics.gets, 15);
+
+    // every write reaches the storage files along with the
+    // headers and nodes around it, and the gets only hit the cache
+    assert!(metrics.write_amplification() > 1.0);
+    assert!(metrics.log_bytes_written > 0);
+    assert_eq!(metrics.read_amplification(), 0.0);
+    assert!(metrics.cache_hits >= 10);
+    assert!(metrics.cache_hit_rate() > 0.0);
+    assert!(metrics.live_bytes > 0);
+    assert!(metrics.space_amplification() > 0.0);
+
+    Ok(())
+}
+
+#[test]
+fn tree_latency_histograms() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let tree = db.open_tree("latency")?;
+
+    for i in 0..100_u32 {
+        tree.insert(i.to_be_bytes(), vec![0; 16])?;
+    }
+    for i in 0..50_u32 {
+        tree.get(i.to_be_bytes())?;
+    }
+    tree.remove(0_u32.to_be_bytes())?;
+    db.flush()?;
+
+    // the transaction is timed from its first attempt, so the
+    // latency of its body is included
+    tree.transaction(|tx| {
+        std::thread::sleep(Duration::from_millis(20));
+        tx.insert(b"tx", b"v")?;
+        Ok::<_, ConflictableTransactionError<()>>(())
+    })
+    .unwrap();
+
+    let histograms = db.latency_histograms();
+    assert_eq!(histograms.insert.count(), 100);
+    assert_eq!(histograms.get.count(), 50);
+    assert_eq!(histograms.remove.count(), 1);
+    assert_eq!(histograms.flush.count(), 1);
+    assert_eq!(histograms.transaction.count(), 1);
+    assert!(histograms.transaction.min() >= Duration::from_millis(20));
+
+    let inserts = &histograms.insert;
+    assert!(inserts.min() <= inserts.percentile(50.));
+    assert!(inserts.percentile(50.) <= inserts.percentile(99.));
+    assert!(inserts.percentile(99.) <= inserts.max());
+    assert_eq!(inserts.percentile(100.), inserts.max());
+    assert!(inserts.mean() >= inserts.min());
+    assert!(inserts.mean() <= inserts.max());
+    let bucketed: u64 = inserts.buckets().map(|(_, count)| count).sum();
+    assert_eq!(bucketed, 100);
+
+    db.reset_latency_histograms();
+    let histograms = db.latency_histograms();
+    assert_eq!(histograms.insert.count(), 0);
+    assert_eq!(histograms.insert.percentile(99.), Duration::from_nanos(0));
+    assert_eq!(histograms.transaction.max(), Duration::from_nanos(0));
+
+    tree.get(b"tx")?;
+    assert_eq!(db.latency_histograms().get.count(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn tree_slow_op_hook() -> Result<()> {
+    common::setup_logger();
+
+    let slow_ops = Arc::new(std::sync::Mutex::new(vec![]));
+    let reported = slow_ops.clone();
+    let db = Config::new()
+        .temporary(true)
+        .on_slow_op(Duration::from_millis(10), move |slow_op| {
+            reported.lock().unwrap().push(slow_op.clone());
+        })
+        .open()?;
+    let tree = db.open_tree("slow")?;
+
+    tree.insert(b"fast", b"v")?;
+    tree.get(b"fast")?;
+    assert!(slow_ops.lock().unwrap().is_empty());
+
+    tree.transaction(|tx| {
+        std::thread::sleep(Duration::from_millis(20));
+        tx.insert(b"slow", b"v")?;
+        Ok::<_, ConflictableTransactionError<()>>(())
+    })
+    .unwrap();
+
+    let slow_ops = slow_ops.lock().unwrap();
+    assert_eq!(slow_ops.len(), 1);
+    let slow_op = &slow_ops[0];
+    assert_eq!(slow_op.op, Operation::Transaction);
+    assert_eq!(slow_op.tree, "slow");
+    assert_eq!(slow_op.key_len, None);
+    assert!(slow_op.latency >= Duration::from_millis(20));
+    let phases: Vec<_> = slow_op.phases.iter().map(|p| p.0).collect();
+    assert_eq!(phases, ["lock", "body", "commit", "flush"]);
+    assert!(slow_op.phases[1].1 >= Duration::from_millis(20));
+    let spent: Duration = slow_op.phases.iter().map(|p| p.1).sum();
+    assert!(spent <= slow_op.latency);
+    assert!(slow_op.to_string().starts_with("slow transaction on tree"));
+
+    // every operation is slower than a threshold of 0
+    let slow_ops = Arc::new(std::sync::Mutex::new(vec![]));
+    let reported = slow_ops.clone();
+    let db = Config::new()
+        .temporary(true)
+        .on_slow_op(Duration::from_nanos(0), move |slow_op| {
+            reported.lock().unwrap().push(slow_op.clone());
+        })
+        .open()?;
+    db.insert(b"key", b"value")?;
+    db.remove(b"key")?;
+
+    let slow_ops = slow_ops.lock().unwrap();
+    let ops: Vec<_> = slow_ops.iter().map(|slow_op| slow_op.op).collect();
+    assert_eq!(ops, [Operation::Insert, Operation::Remove]);
+    assert_eq!(slow_ops[0].key_len, Some(3));
+    let phases: Vec<_> = slow_ops[0].phases.iter().map(|p| p.0).collect();
+    assert_eq!(phases, ["quota", "concurrency_control", "index", "write"]);
+
+    Ok(())
+}
+
+#[test]
+fn tree_set_cache_capacity() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let tree = db.open_tree("cached")?;
+    for i in 0..20_000_u32 {
+        tree.insert(i.to_be_bytes(), vec![0; 100])?;
+    }
+    db.flush()?;
+
+    let cached = |db: &Db| -> Result<(u64, u64)> {
+        let stats = db.tree_stats()?;
+        let stats = stats.iter().find(|s| s.tree == "cached").unwrap();
+        Ok((stats.cached_nodes, stats.nodes))
+    };
+    let (cached_before, nodes) = cached(&db)?;
+    assert_eq!(cached_before, nodes);
+
+    // every shard of the cache keeps at most one page
+    db.set_cache_capacity(256)?;
+    assert_eq!(db.cache_capacity(), 256);
+    let (cached_after, _) = cached(&db)?;
+    assert!(cached_after < cached_before);
+
+    for i in 0..20_000_u32 {
+        assert_eq!(tree.get(i.to_be_bytes())?.unwrap().len(), 100);
+    }
+
+    db.set_cache_capacity(1024 * 1024 * 1024)?;
+    for i in 0..20_000_u32 {
+        tree.get(i.to_be_bytes())?;
+    }
+    assert_eq!(cached(&db)?.0, nodes);
+
+    match db.set_cache_capacity(255) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn tree_tiny_lfu_cache_policy() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new()
+        .temporary(true)
+        .cache_capacity(64 * 1024)
+        .cache_policy(CachePolicy::TinyLfu)
+        .open()?;
+    let tree = db.open_tree("scanned")?;
+    for i in 0..20_000_u32 {
+        tree.insert(i.to_be_bytes(), vec![0; 100])?;
+    }
+    db.flush()?;
+
+    // pages that are rejected by the cache are still read
+    for _ in 0..10 {
+        for i in 0..100_u32 {
+            assert_eq!(tree.get(i.to_be_bytes())?.unwrap().len(), 100);
+        }
+    }
+    assert_eq!(tree.iter().count(), 20_000);
+    for (i, kv) in tree.range(10_000_u32.to_be_bytes()..).enumerate() {
+        let (k, v) = kv?;
+        assert_eq!(&*k, &(10_000 + i as u32).to_be_bytes());
+        assert_eq!(v.len(), 100);
+    }
+
+    let stats = db.tree_stats()?;
+    let stats = stats.iter().find(|s| s.tree == "scanned").unwrap();
+    assert!(stats.cached_nodes < stats.nodes);
+
+    Ok(())
+}
+
+#[test]
+fn tree_shared_cache() -> Result<()> {
+    common::setup_logger();
+
+    let cache = SharedCache::new(64 * 1024)?;
+    let open = |priority| {
+        Config::new()
+            .temporary(true)
+            .shared_cache(cache.clone())
+            .shared_cache_priority(priority)
+            .open()
+    };
+    let cached = |db: &Db| -> Result<(u64, u64)> {
+        let stats = db.tree_stats()?;
+        let stats = stats.iter().find(|s| s.tree == "tenant").unwrap();
+        Ok((stats.cached_nodes, stats.nodes))
+    };
+
+    let busy = open(CachePriority::High)?;
+    let idle = open(CachePriority::Low)?;
+    assert_eq!(cache.databases(), 2);
+    assert_eq!(busy.cache_capacity(), 64 * 1024);
+
+    let busy_tree = busy.open_tree("tenant")?;
+    for i in 0..1_000_u32 {
+        busy_tree.insert(i.to_be_bytes(), vec![1; 100])?;
+    }
+
+    // the pages of the idle tenant are evicted before those of
+    // the busy one, so it doesn't grow past the cache
+    let idle_tree = idle.open_tree("tenant")?;
+    for i in 0..40_000_u32 {
+        idle_tree.insert(i.to_be_bytes(), vec![2; 100])?;
+    }
+    for i in 0..40_000_u32 {
+        assert_eq!(idle_tree.get(i.to_be_bytes())?.unwrap(), vec![2; 100]);
+    }
+    let (idle_cached, idle_nodes) = cached(&idle)?;
+    assert!(idle_cached < idle_nodes);
+    let (busy_cached, busy_nodes) = cached(&busy)?;
+    assert_eq!(busy_cached, busy_nodes);
+
+    for i in 0..1_000_u32 {
+        assert_eq!(busy_tree.get(i.to_be_bytes())?.unwrap(), vec![1; 100]);
+    }
+
+    match busy.set_cache_capacity(2 * 1024 * 1024) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+
+    // the slot of a closed database is reused
+    drop(idle_tree);
+    drop(idle);
+    assert_eq!(cache.databases(), 1);
+    let next = open(CachePriority::Normal)?;
+    assert_eq!(cache.databases(), 2);
+    let next_tree = next.open_tree("tenant")?;
+    for i in 0..10_000_u32 {
+        next_tree.insert(i.to_be_bytes(), vec![3; 100])?;
+    }
+    for i in 0..10_000_u32 {
+        assert_eq!(next_tree.get(i.to_be_bytes())?.unwrap(), vec![3; 100]);
+    }
+    for i in 0..1_000_u32 {
+        assert_eq!(busy_tree.get(i.to_be_bytes())?.unwrap(), vec![1; 100]);
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "io_uring")]
+fn tree_io_uring_round_trip() -> Result<()> {
+    common::setup_logger();
+
+    // the log is written and read through io_uring where the
+    // kernel supports it, and through pwrite and pread otherwise
+    let path = "test_tree_io_uring_round_trip";
+    let _ = std::fs::remove_dir_all(path);
+    let config = Config::new()
+        .path(path)
+        .cache_capacity(64 * 1024)
+        .flush_every_ms(None);
+    let value = |i: u32| vec![i as u8; (i % 1000) as usize];
+
+    {
+        let db = config.open()?;
+        for i in 0..10_000_u32 {
+            db.insert(i.to_be_bytes(), value(i))?;
+        }
+        db.flush()?;
+
+        // most pages were evicted from the small cache, so they
+        // are read back from the log
+        for i in 0..10_000_u32 {
+            assert_eq!(db.get(i.to_be_bytes())?.unwrap(), value(i));
+        }
+    }
+
+    let db = config.open()?;
+    assert_eq!(db.len(), 10_000);
+    for (i, kv_res) in db.iter().enumerate() {
+        let (k, v) = kv_res?;
+        assert_eq!(k, (i as u32).to_be_bytes());
+        assert_eq!(v, value(i as u32));
+    }
+    drop(db);
+
+    std::fs::remove_dir_all(path)?;
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "compression")]
+fn tree_compressed_cache() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new()
+        .temporary(true)
+        .cache_capacity(256 * 1024)
+        .compressed_cache_fraction(Some(0.75))
+        .open()?;
+    assert_eq!(db.cache_capacity(), 256 * 1024);
+
+    let tree = db.open_tree("compressible")?;
+    for i in 0..20_000_u32 {
+        tree.insert(i.to_be_bytes(), vec![7; 100])?;
+    }
+    db.flush()?;
+
+    for _ in 0..2 {
+        for i in 0..20_000_u32 {
+            assert_eq!(tree.get(i.to_be_bytes())?.unwrap(), vec![7; 100]);
+        }
+    }
+    let metrics = db.metrics()?;
+    assert!(metrics.compressed_cache_hits > 0);
+    assert!(metrics.cache_hits >= metrics.compressed_cache_hits);
+
+    // pages that changed since they were compressed are read again
+    for i in 0..20_000_u32 {
+        tree.insert(i.to_be_bytes(), vec![8; 100])?;
+    }
+    for i in 0..20_000_u32 {
+        assert_eq!(tree.get(i.to_be_bytes())?.unwrap(), vec![8; 100]);
+    }
+
+    db.set_cache_capacity(128 * 1024)?;
+    assert_eq!(db.cache_capacity(), 128 * 1024);
+    assert_eq!(tree.iter().count(), 20_000);
+    drop(tree);
+    drop(db);
+
+    for fraction in &[0., 1.] {
+        let res = Config::new()
+            .temporary(true)
+            .compressed_cache_fraction(Some(*fraction))
+            .open();
+        match res {
+            Err(Error::Unsupported(_)) => {}
+            other => panic!("expected Unsupported, got {:?}", other),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn tree_secondary_cache() -> Result<()> {
+    common::setup_logger();
+
+    let path = std::path::PathBuf::from("test_tree_secondary_cache.cache");
+    let config = || {
+        Config::new()
+            .temporary(true)
+            .cache_capacity(64 * 1024)
+            .secondary_cache_path(Some(path.clone()))
+            .secondary_cache_capacity(512 * 1024)
+    };
+    let db = config().open()?;
+    assert!(path.exists());
+
+    // the cache file can't be used by two databases
+    match config().open() {
+        Err(Error::Io(_)) => {}
+        other => panic!("expected Io, got {:?}", other),
+    }
+
+    let tree = db.open_tree("remote")?;
+    for i in 0..20_000_u32 {
+        tree.insert(i.to_be_bytes(), vec![1; 100])?;
+    }
+    db.flush()?;
+
+    for _ in 0..2 {
+        for i in 0..20_000_u32 {
+            assert_eq!(tree.get(i.to_be_bytes())?.unwrap(), vec![1; 100]);
+        }
+    }
+    let metrics = db.metrics()?;
+    assert!(metrics.secondary_cache_hits > 0);
+    assert!(metrics.cache_hits >= metrics.secondary_cache_hits);
+
+    // pages that changed since they were written to the cache file
+    // are read from the storage files
+    for i in 0..20_000_u32 {
+        tree.insert(i.to_be_bytes(), vec![2; 100])?;
+    }
+    for _ in 0..2 {
+        for i in 0..20_000_u32 {
+            assert_eq!(tree.get(i.to_be_bytes())?.unwrap(), vec![2; 100]);
+        }
+    }
+    assert!(std::fs::metadata(&path)?.len() <= 512 * 1024);
+
+    drop(tree);
+    drop(db);
+    assert!(!path.exists());
+
+    Ok(())
+}
+
+#[test]
+fn tree_secondary_cache_encryption() -> Result<()> {
+    common::setup_logger();
+
+    let path =
+        std::path::PathBuf::from("test_tree_secondary_cache_encryption.cache");
+    let keys =
+        XorKeys { current: Arc::new(AtomicUsize::new(1)), known: vec![1] };
+    let db = Config::new()
+        .temporary(true)
+        .cache_capacity(64 * 1024)
+        .encryption(keys)
+        .secondary_cache_path(Some(path.clone()))
+        .secondary_cache_capacity(512 * 1024)
+        .open()?;
+
+    let value = b"plaintext marker ".repeat(4);
+    for i in 0..20_000_u32 {
+        db.insert(i.to_be_bytes(), value.clone())?;
+    }
+    db.flush()?;
+
+    for _ in 0..2 {
+        for i in 0..20_000_u32 {
+            assert_eq!(db.get(i.to_be_bytes())?.unwrap(), value);
+        }
+    }
+    assert!(db.metrics()?.secondary_cache_hits > 0);
+
+    let cached = std::fs::read(&path)?;
+    assert!(!cached.is_empty());
+    assert!(!cached.windows(17).any(|w| w == b"plaintext marker "));
+
+    drop(db);
+    assert!(!path.exists());
+
+    Ok(())
+}
+
+#[test]
+fn tree_cache_warmup() -> Result<()> {
+    common::setup_logger();
+
+    let path = std::path::PathBuf::from("test_tree_cache_warmup");
+    let manifest = std::path::PathBuf::from("test_tree_cache_warmup.hot");
+    let _ = std::fs::remove_dir_all(&path);
+    let config = || Config::new().path(&path).cache_capacity(64 * 1024);
+
+    // the pages that gets read, which unlike `DbMetrics::pages_read`
+    // leaves out those that the background threads read
+    let gets_pages_read = |db: &Db| -> Result<u64> {
+        let metrics = db.metrics()?;
+        let hot = metrics.trees.iter().find(|t| &*t.tree == b"hot").unwrap();
+        Ok(hot.pages_read)
+    };
+
+    // the integrity checks of the testing feature read every page
+    // as the database is opened, so it's started with an empty cache
+    let open_cold = || -> Result<Db> {
+        let db = config().open()?;
+        db.set_cache_capacity(256)?;
+        db.set_cache_capacity(64 * 1024)?;
+        Ok(db)
+    };
+
+    let db = config().open()?;
+    let tree = db.open_tree("hot")?;
+    for i in 0..20_000_u32 {
+        tree.insert(i.to_be_bytes(), vec![1; 100])?;
+    }
+    db.flush()?;
+    drop(tree);
+    drop(db);
+
+    let db = open_cold()?;
+    let tree = db.open_tree("hot")?;
+    for i in 0..100_u32 {
+        assert!(tree.get(i.to_be_bytes())?.is_some());
+    }
+    assert!(gets_pages_read(&db)? > 0);
+    assert!(db.write_cache_manifest(&manifest)? > 0);
+    drop(tree);
+    drop(db);
+
+    // the hot keys are read without touching storage once the
+    // cache has been warmed
+    let db = open_cold()?;
+    let tree = db.open_tree("hot")?;
+    assert!(db.warm_cache_from(&manifest)? > 0);
+    assert_eq!(db.warm_cache_from(&manifest)?, 0);
+    for i in 0..100_u32 {
+        assert!(tree.get(i.to_be_bytes())?.is_some());
+    }
+    assert_eq!(gets_pages_read(&db)?, 0);
+
+    // and so is a range that was prefetched
+    let start = 10_000_u32.to_be_bytes();
+    let end = 10_500_u32.to_be_bytes();
+    assert!(tree.prefetch_range(start..end)? > 0);
+    assert_eq!(tree.prefetch_range(start..end)?, 0);
+    for i in 10_000..10_500_u32 {
+        assert!(tree.get(i.to_be_bytes())?.is_some());
+    }
+    assert_eq!(gets_pages_read(&db)?, 0);
+
+    std::fs::write(&manifest, b"not a manifest")?;
+    match db.warm_cache_from(&manifest) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+
+    drop(tree);
+    drop(db);
+    std::fs::remove_file(&manifest)?;
+    std::fs::remove_dir_all(&path)?;
+
+    Ok(())
+}
+
+#[test]
+fn tree_pinned_cache_priority() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).cache_capacity(64 * 1024).open()?;
+
+    // the pages that the gets of the routes read
+    let routes_pages_read = |db: &Db| -> Result<u64> {
+        let metrics = db.metrics()?;
+        let routes =
+            metrics.trees.iter().find(|t| &*t.tree == b"routes").unwrap();
+        Ok(routes.pages_read)
+    };
+
+    let routes = db.open_tree("routes")?;
+    for i in 0..200_u32 {
+        routes.insert(i.to_be_bytes(), vec![2; 10])?;
+    }
+    routes.set_cache_priority(CachePriority::Pinned)?;
+
+    // scans of a tree that doesn't fit in the cache don't evict
+    // the pinned pages
+    let bulk = db.open_tree("bulk")?;
+    for i in 0..20_000_u32 {
+        bulk.insert(i.to_be_bytes(), vec![1; 100])?;
+    }
+    for _ in 0..2 {
+        assert_eq!(bulk.iter().count(), 20_000);
+    }
+    for i in 0..200_u32 {
+        assert_eq!(routes.get(i.to_be_bytes())?.unwrap(), vec![2; 10]);
+    }
+    assert_eq!(routes_pages_read(&db)?, 0);
+
+    // until they're unpinned
+    routes.set_cache_priority(CachePriority::Normal)?;
+    for _ in 0..2 {
+        assert_eq!(bulk.iter().count(), 20_000);
+    }
+    for i in 0..200_u32 {
+        assert_eq!(routes.get(i.to_be_bytes())?.unwrap(), vec![2; 10]);
+    }
+    assert!(routes_pages_read(&db)? > 0);
+
+    match Config::new()
+        .temporary(true)
+        .shared_cache_priority(CachePriority::Pinned)
+        .open()
+    {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn tree_memory_limit() -> Result<()> {
+    common::setup_logger();
+
+    let config = |limit| {
+        Config::new()
+            .temporary(true)
+            .cache_capacity(64 * 1024)
+            .segment_size(64 * 1024)
+            .memory_limit(Some(limit))
+    };
+    match config(64 * 1024).open() {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+
+    let db = config(512 * 1024).open()?;
+    let tree = db.open_tree("tree")?;
+    tree.insert(b"k", b"v")?;
+    let usage = db.memory_usage();
+    assert!(usage.io_buffers >= 64 * 1024);
+    assert_eq!(usage.limit, Some(512 * 1024));
+    assert!(usage.total() <= 512 * 1024);
+
+    // the writes of a transaction are counted until it's dropped
+    let mut txn = db.begin_transaction();
+    for i in 0..10_u32 {
+        txn.insert(&tree, i.to_be_bytes(), vec![0; 1000]);
+    }
+    assert_eq!(db.memory_usage().transactions, 10 * 1004);
+    drop(txn);
+    assert_eq!(db.memory_usage().transactions, 0);
+
+    // and the events of a subscriber until it receives them, or
+    // is dropped. writes that are retried queue an event that's
+    // never sent, so there may be more of them.
+    let mut subscriber = tree.watch_prefix(vec![]);
+    for i in 0..3_u32 {
+        tree.insert(i.to_be_bytes(), vec![0; 100])?;
+    }
+    assert!(db.memory_usage().subscriber_queues >= 3 * 104);
+    assert!(subscriber.next().is_some());
+    drop(subscriber);
+    assert_eq!(db.memory_usage().subscriber_queues, 0);
+
+    // writes wait while a transaction holds more than the limit
+    let mut txn = db.begin_transaction();
+    for i in 0..500_u32 {
+        txn.insert(&tree, i.to_be_bytes(), vec![0; 1000]);
+    }
+    let holder = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(200));
+        drop(txn);
+    });
+    let start = std::time::Instant::now();
+    tree.insert(b"k", b"v2")?;
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    holder.join().unwrap();
+    assert!(db.memory_usage().total() <= 512 * 1024);
+    Ok(())
+}
+
+#[test]
+fn tree_get_ref() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let tree = db.open_tree("tree")?;
+    for i in 0..1000_u32 {
+        tree.insert(i.to_be_bytes(), vec![i as u8; 100 + i as usize])?;
+    }
+    for i in 0..1000_u32 {
+        let value = tree.get_ref(i.to_be_bytes())?.unwrap();
+        assert_eq!(&*value, &*tree.get(i.to_be_bytes())?.unwrap());
+    }
+    assert!(tree.get_ref(1000_u32.to_be_bytes())?.is_none());
+
+    // the value stays valid while its node is replaced
+    let value = tree.get_ref(7_u32.to_be_bytes())?.unwrap();
+    tree.insert(7_u32.to_be_bytes(), b"replaced")?;
+    tree.remove(7_u32.to_be_bytes())?;
+    for i in 1000..2000_u32 {
+        tree.insert(i.to_be_bytes(), vec![0; 100])?;
+    }
+    assert_eq!(&*value, &[7; 107][..]);
+    drop(value);
+    assert!(tree.get_ref(7_u32.to_be_bytes())?.is_none());
+
+    tree.insert_with_ttl(b"expiring", b"v", Duration::from_millis(1))?;
+    std::thread::sleep(Duration::from_millis(10));
+    assert!(tree.get_ref(b"expiring")?.is_none());
+
+    // values in the value log are read into memory of their own
+    let db = Config::new()
+        .temporary(true)
+        .value_log_threshold(Some(64))
+        .open()?;
+    db.insert(b"small", b"v")?;
+    db.insert(b"large", vec![1; 1000])?;
+    assert_eq!(&*db.get_ref(b"small")?.unwrap(), b"v");
+    assert_eq!(&*db.get_ref(b"large")?.unwrap(), &[1; 1000][..]);
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "metrics-prometheus")]
+fn tree_prometheus_metrics() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let tree = db.open_tree("quote\"d\\tree")?;
+    tree.insert(b"k", b"v")?;
+    tree.get(b"k")?;
+    db.flush()?;
+
+    let exposition = db.prometheus_metrics()?;
+    for line in exposition.lines() {
+        assert!(
+            line.starts_with("# HELP sled_")
+                || line.starts_with("# TYPE sled_")
+                || line.starts_with("sled_"),
+            "unexpected line {:?}",
+            line
+        );
+    }
+    assert!(exposition.contains("# TYPE sled_flush_seconds summary\n"));
+    assert!(exposition.contains("sled_flush_seconds{quantile=\"0.99\"} "));
+    assert!(exposition.contains("sled_gets_total 1\n"));
+    assert!(exposition
+        .contains("sled_tree_writes_total{tree=\"quote\\\"d\\\\tree\"} 1\n"));
+    assert!(exposition
+        .contains("sled_tree_keys{tree=\"__sled__default\"} 0\n"));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "tracing")]
+fn tree_tracing_spans() -> Result<()> {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    };
+    use tracing::{span, Event, Metadata, Subscriber};
+
+    #[derive(Default)]
+    struct Recorder {
+        spans: Mutex<Vec<&'static str>>,
+        events: AtomicU64,
+        ids: AtomicU64,
+    }
+
+    impl Subscriber for &'static Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, attributes: &span::Attributes<'_>) -> span::Id {
+            self.spans.lock().unwrap().push(attributes.metadata().name());
+            span::Id::from_u64(self.ids.fetch_add(1, Ordering::Relaxed) + 1)
+        }
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+        fn event(&self, _: &Event<'_>) {
+            self.events.fetch_add(1, Ordering::Relaxed);
+        }
+        fn enter(&self, _: &span::Id) {}
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    common::setup_logger();
+
+    let recorder: &'static Recorder = Box::leak(Box::default());
+    let db = Config::new().temporary(true).open()?;
+    tracing::subscriber::with_default(recorder, || -> Result<()> {
+        db.insert(b"k", b"v")?;
+        db.get(b"k")?;
+        db.transaction(|tx| -> ConflictableTransactionResult<()> {
+            tx.remove(b"k")?;
+            Ok(())
+        })
+        .unwrap();
+        db.flush()?;
+        Ok(())
+    })?;
+
+    let spans = recorder.spans.lock().unwrap();
+    for expected in &[
+        "tree.insert",
+        "tree.get",
+        "transaction",
+        "transaction.commit",
+        "tree.flush",
+        "log.make_stable",
+    ] {
+        assert!(
+            spans.contains(expected),
+            "missing {} in {:?}",
+            expected,
+            spans
+        );
+    }
+    assert!(recorder.events.load(Ordering::Relaxed) > 0);
+
+    Ok(())
+}
+
+#[test]
+fn tree_quota() -> Result<()> {
+    common::setup_logger();
+
+    let db = Config::new().temporary(true).open()?;
+    let tree = db.open_tree("quota")?;
+
+    // writes that would add keys above the limit fail, but
+    // overwrites and removals don't
+    tree.set_quota(Some(Quota { max_keys: Some(3), ..Quota::default() }))?;
+    for i in 0..3_u32 {
+        tree.insert(i.to_be_bytes(), b"v")?;
+    }
+    match tree.insert(b"new", b"v") {
+        Err(Error::QuotaExceeded { limit: QuotaLimit::Keys, .. }) => {}
+        other => panic!("expected the key limit, got {:?}", other),
+    }
+    tree.insert(0_u32.to_be_bytes(), b"overwritten")?;
+    tree.remove(2_u32.to_be_bytes())?;
+    tree.insert(b"new", b"v")?;
+    assert_eq!(tree.len(), 3);
+
+    // the oldest keys make room for new ones
+    tree.clear()?;
+    tree.set_quota(Some(Quota {
+        max_keys: Some(10),
+        action: QuotaAction::EvictOldest,
+        ..Quota::default()
+    }))?;
+    for i in 0..25_u32 {
+        tree.insert(i.to_be_bytes(), b"v")?;
+    }
+    assert_eq!(tree.len(), 10);
+    assert_eq!(tree.first()?.unwrap().0, 15_u32.to_be_bytes());
+
+    // the size limit errs on the side of the writes
+    tree.clear()?;
+    tree.set_quota(Some(Quota { max_bytes: Some(4096), ..Quota::default() }))?;
+    let mut written = 0_u32;
+    let err = loop {
+        match tree.insert(written.to_be_bytes(), vec![0; 100]) {
+            Ok(_) => written += 1,
+            Err(err) => break err,
+        }
+    };
+    match err {
+        Error::QuotaExceeded { limit: QuotaLimit::Bytes, .. } => {}
+        other => panic!("expected the byte limit, got {:?}", other),
+    }
+    assert!(written > 0 && written < 80);
+
+    // the callback decides whether the write is made
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counted = calls.clone();
+    let callback: QuotaCallback = Arc::new(move |_tree, limit| {
+        assert_eq!(limit, QuotaLimit::Keys);
+        if counted.fetch_add(1, SeqCst) == 0 {
+            Ok(())
+        } else {
+            Err(Error::Unsupported("full".into()))
+        }
+    });
+    tree.clear()?;
+    tree.set_quota(Some(Quota {
+        max_keys: Some(1),
+        action: QuotaAction::Callback(callback),
+        ..Quota::default()
+    }))?;
+    tree.insert(b"a", b"v")?;
+    tree.insert(b"b", b"v")?;
+    assert!(tree.insert(b"c", b"v").is_err());
+    assert_eq!(calls.load(SeqCst), 2);
+    assert_eq!(tree.len(), 2);
+
+    // writes above the rate wait for their turn
+    tree.set_quota(Some(Quota {
+        max_writes_per_sec: Some(100),
+        ..Quota::default()
+    }))?;
+    let before = std::time::Instant::now();
+    for i in 0..20_u32 {
+        tree.insert(i.to_be_bytes(), b"v")?;
+    }
+    assert!(before.elapsed() >= Duration::from_millis(150));
+    assert!(tree
+        .set_quota(Some(Quota {
+            max_writes_per_sec: Some(0),
+            ..Quota::default()
+        }))
+        .is_err());
+
+    tree.set_quota(None)?;
+    for i in 0..1000_u32 {
+        tree.insert(i.to_be_bytes(), vec![0; 100])?;
+    }
+    assert_eq!(tree.len(), 1002);
+
+    Ok(())
+}
+
 #[test]
 #[cfg_attr(miri, ignore)]
 fn recover_tree() {
@@ -2736,3 +8519,4 @@ fn tree_bug_49() {
         0
     ))
 }
+